@@ -57,6 +57,10 @@ impl GlobalLibTable {
         })
     }
 
+    pub fn lib_by_handle(&self, handle: LibraryHandle) -> &LibraryInfo {
+        &self.all_libs[handle.0]
+    }
+
     pub fn get_lib(&self, index: GlobalLibIndex) -> Option<&LibraryInfo> {
         let handle = self.used_libs.get(index.0)?;
         self.all_libs.get(handle.0)
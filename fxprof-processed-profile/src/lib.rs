@@ -44,6 +44,7 @@ mod global_lib_table;
 mod graph_color;
 mod lib_mappings;
 mod library_info;
+mod marker_schema_validation;
 mod marker_table;
 mod markers;
 mod native_symbols;
@@ -68,6 +69,7 @@ pub use global_lib_table::{LibraryHandle, UsedLibraryAddressesIterator};
 pub use graph_color::GraphColor;
 pub use lib_mappings::LibMappings;
 pub use library_info::{LibraryInfo, Symbol, SymbolTable};
+pub use marker_schema_validation::{validate_marker_schema, MarkerSchemaIssue};
 pub use markers::{
     Marker, MarkerFieldFormat, MarkerFieldFormatKind, MarkerFieldSchema, MarkerGraph,
     MarkerGraphType, MarkerHandle, MarkerLocation, MarkerSchema, MarkerStaticField, MarkerTiming,
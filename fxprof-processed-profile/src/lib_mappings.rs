@@ -99,12 +99,44 @@ impl<T> LibMappings<T> {
         self.map.clear();
     }
 
+    /// Iterate over all mappings in the order of their start address, as
+    /// `(start_avma, end_avma, value)`.
+    pub fn iter(&self) -> impl Iterator<Item = (u64, u64, &T)> {
+        self.map
+            .values()
+            .map(|m| (m.start_avma, m.end_avma, &m.value))
+    }
+
     /// Look up the mapping which covers the given address and return
     /// the stored value.
     pub fn lookup(&self, avma: u64) -> Option<&T> {
         self.lookup_impl(avma).map(|m| &m.value)
     }
 
+    /// Look up all mappings which cover the given address if each mapping is
+    /// widened by `tolerance` bytes on both sides, and return their start
+    /// addresses and stored values, in the order of their start address.
+    ///
+    /// This is for addresses which were recorded with an ASLR slide that
+    /// differs slightly from the one the mappings were recorded with. The
+    /// result is only a set of candidates: with a large tolerance, several
+    /// neighboring mappings can match, and an address which is attributed to
+    /// a mapping it isn't actually in will be symbolicated as the wrong
+    /// function. Keep the tolerance below the typical gap between mappings.
+    pub fn fuzzy_lookup(&self, avma: u64, tolerance: u64) -> Vec<(u64, &T)> {
+        // The mappings don't overlap, so their end addresses are sorted just
+        // like their start addresses.
+        let mut matches: Vec<(u64, &T)> = self
+            .map
+            .range(..=avma.saturating_add(tolerance))
+            .rev()
+            .take_while(|(_, mapping)| mapping.end_avma.saturating_add(tolerance) > avma)
+            .map(|(start_avma, mapping)| (*start_avma, &mapping.value))
+            .collect();
+        matches.reverse();
+        matches
+    }
+
     /// Look up the mapping which covers the given address and return
     /// its `Mapping<T>``.
     fn lookup_impl(&self, avma: u64) -> Option<&Mapping<T>> {
@@ -157,5 +189,24 @@ mod test {
         assert_eq!(m.lookup(149), Some(&"100..150"));
         assert_eq!(m.lookup(200), Some(&"180..220"));
         assert_eq!(m.lookup(260), Some(&"255..270"));
+        let starts: Vec<u64> = m.iter().map(|(start_avma, _, _)| start_avma).collect();
+        assert_eq!(starts, [100, 180, 225, 255]);
+    }
+
+    #[test]
+    fn test_fuzzy_lookup() {
+        let mut m = LibMappings::new();
+        m.add_mapping(0x1000, 0x2000, 0, "a");
+        m.add_mapping(0x2080, 0x3000, 0, "b");
+        assert_eq!(m.fuzzy_lookup(0x1800, 0), [(0x1000, &"a")]);
+        assert_eq!(m.fuzzy_lookup(0x2040, 0), []);
+        assert_eq!(
+            m.fuzzy_lookup(0x2040, 0x100),
+            [(0x1000, &"a"), (0x2080, &"b")]
+        );
+        assert_eq!(m.fuzzy_lookup(0x30ff, 0x100), [(0x2080, &"b")]);
+        assert_eq!(m.fuzzy_lookup(0x3100, 0x100), []);
+        assert_eq!(m.fuzzy_lookup(0xf00, 0x100), [(0x1000, &"a")]);
+        assert_eq!(m.fuzzy_lookup(0xeff, 0x100), []);
     }
 }
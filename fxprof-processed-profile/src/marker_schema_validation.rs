@@ -0,0 +1,355 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::markers::InternalMarkerSchema;
+use crate::{MarkerFieldFormat, MarkerFieldFormatKind, MarkerSchema};
+
+/// Field keys which the Firefox Profiler front-end uses for its own purposes
+/// in the marker data, and which marker fields therefore can't use.
+const RESERVED_FIELD_KEYS: &[&str] = &["type", "cause"];
+
+/// A way in which a [`MarkerSchema`] breaks the rules of the marker schema
+/// format, as understood by the Firefox Profiler front-end at the processed
+/// profile version which this crate writes. Markers with such a schema are
+/// displayed incompletely or not at all.
+///
+/// Returned from [`validate_marker_schema`] and
+/// [`Profile::validate_marker_schemas`](crate::Profile::validate_marker_schemas).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MarkerSchemaIssue {
+    /// The schema's `type_name` is empty.
+    EmptyTypeName,
+    /// Another schema with the same `type_name` was registered before.
+    DuplicateTypeName { type_name: String },
+    /// A field uses a key which is reserved by the front-end.
+    ReservedFieldKey { type_name: String, key: String },
+    /// Two fields of the schema have the same key.
+    DuplicateFieldKey { type_name: String, key: String },
+    /// A graph refers to a key which isn't one of the schema's fields.
+    GraphKeyNotAField { type_name: String, key: String },
+    /// A graph refers to a string field, but graphs can only plot numbers.
+    GraphOnStringField {
+        type_name: String,
+        key: String,
+        format: MarkerFieldFormat,
+    },
+    /// A graph refers to a number field whose format the front-end can't
+    /// plot, such as a duration or a timestamp.
+    GraphOnUnsupportedFormat {
+        type_name: String,
+        key: String,
+        format: MarkerFieldFormat,
+    },
+    /// A label template refers to `{marker.data.<key>}` for a key which isn't
+    /// one of the schema's fields. The front-end displays it as empty.
+    LabelKeyNotAField {
+        type_name: String,
+        label: String,
+        key: String,
+    },
+}
+
+impl fmt::Display for MarkerSchemaIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::EmptyTypeName => write!(f, "a marker schema has an empty type name"),
+            Self::DuplicateTypeName { type_name } => {
+                write!(f, "marker schema {type_name:?} is registered more than once")
+            }
+            Self::ReservedFieldKey { type_name, key } => write!(
+                f,
+                "marker schema {type_name:?} has a field with the reserved key {key:?}"
+            ),
+            Self::DuplicateFieldKey { type_name, key } => write!(
+                f,
+                "marker schema {type_name:?} has more than one field with the key {key:?}"
+            ),
+            Self::GraphKeyNotAField { type_name, key } => write!(
+                f,
+                "marker schema {type_name:?} has a graph of {key:?}, which isn't one of its fields"
+            ),
+            Self::GraphOnStringField {
+                type_name,
+                key,
+                format,
+            }
+            | Self::GraphOnUnsupportedFormat {
+                type_name,
+                key,
+                format,
+            } => write!(
+                f,
+                "marker schema {type_name:?} has a graph of {key:?}, whose format {format:?} can't be graphed"
+            ),
+            Self::LabelKeyNotAField {
+                type_name,
+                label,
+                key,
+            } => write!(
+                f,
+                "marker schema {type_name:?} has the label {label:?}, but {key:?} isn't one of its fields"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MarkerSchemaIssue {}
+
+/// Whether the front-end can plot the values of a number field with this
+/// format in a marker graph.
+fn is_graphable_format(format: &MarkerFieldFormat) -> bool {
+    !matches!(
+        format,
+        MarkerFieldFormat::Duration | MarkerFieldFormat::Time
+    )
+}
+
+/// The `<key>`s of the `{marker.data.<key>}` references in a label template.
+fn label_data_keys(label: &str) -> impl Iterator<Item = &str> {
+    label
+        .split('{')
+        .skip(1)
+        .filter_map(|part| part.split_once('}'))
+        .filter_map(|(reference, _)| reference.trim().strip_prefix("marker.data."))
+}
+
+/// Checks a marker schema against the rules of the marker schema format, and
+/// returns the ways in which it breaks them. An empty result means that the
+/// schema is valid.
+///
+/// This only looks at the schema itself. Use
+/// [`Profile::validate_marker_schemas`](crate::Profile::validate_marker_schemas)
+/// to also check that the type names of a profile's schemas are unique.
+pub fn validate_marker_schema(schema: &MarkerSchema) -> Vec<MarkerSchemaIssue> {
+    validate_internal_marker_schema(&schema.clone().into())
+}
+
+pub(crate) fn validate_internal_marker_schema(
+    schema: &InternalMarkerSchema,
+) -> Vec<MarkerSchemaIssue> {
+    let type_name = schema.type_name();
+    let mut issues = Vec::new();
+    if type_name.is_empty() {
+        issues.push(MarkerSchemaIssue::EmptyTypeName);
+    }
+
+    let mut keys = HashSet::new();
+    for field in schema.fields() {
+        if RESERVED_FIELD_KEYS.contains(&field.key.as_str()) {
+            issues.push(MarkerSchemaIssue::ReservedFieldKey {
+                type_name: type_name.to_owned(),
+                key: field.key.clone(),
+            });
+        }
+        if !keys.insert(field.key.as_str()) {
+            issues.push(MarkerSchemaIssue::DuplicateFieldKey {
+                type_name: type_name.to_owned(),
+                key: field.key.clone(),
+            });
+        }
+    }
+
+    for graph in schema.graphs() {
+        let key = graph.key.clone();
+        let Some(field) = schema.fields().iter().find(|field| field.key == graph.key) else {
+            issues.push(MarkerSchemaIssue::GraphKeyNotAField {
+                type_name: type_name.to_owned(),
+                key,
+            });
+            continue;
+        };
+        let format = field.format.clone();
+        if format.kind() == MarkerFieldFormatKind::String {
+            issues.push(MarkerSchemaIssue::GraphOnStringField {
+                type_name: type_name.to_owned(),
+                key,
+                format,
+            });
+        } else if !is_graphable_format(&format) {
+            issues.push(MarkerSchemaIssue::GraphOnUnsupportedFormat {
+                type_name: type_name.to_owned(),
+                key,
+                format,
+            });
+        }
+    }
+
+    for label in schema.labels() {
+        for key in label_data_keys(label) {
+            if !keys.contains(key) {
+                issues.push(MarkerSchemaIssue::LabelKeyNotAField {
+                    type_name: type_name.to_owned(),
+                    label: label.to_owned(),
+                    key: key.to_owned(),
+                });
+            }
+        }
+    }
+
+    issues
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        GraphColor, MarkerFieldSchema, MarkerGraph, MarkerGraphType, MarkerLocation,
+        MarkerStaticField, ReferenceTimestamp, SamplingInterval,
+    };
+
+    fn field(key: &str, format: MarkerFieldFormat) -> MarkerFieldSchema {
+        MarkerFieldSchema {
+            key: key.into(),
+            label: key.into(),
+            format,
+            searchable: false,
+        }
+    }
+
+    fn graph(key: &str, graph_type: MarkerGraphType) -> MarkerGraph {
+        MarkerGraph {
+            key: key.into(),
+            graph_type,
+            color: Some(GraphColor::Blue),
+        }
+    }
+
+    fn schema(fields: Vec<MarkerFieldSchema>, graphs: Vec<MarkerGraph>) -> MarkerSchema {
+        MarkerSchema {
+            type_name: "Custom".into(),
+            locations: vec![MarkerLocation::MarkerChart, MarkerLocation::MarkerTable],
+            chart_label: Some("{marker.data.name}".into()),
+            tooltip_label: None,
+            table_label: Some("{marker.name} - { marker.data.name }".into()),
+            fields,
+            static_fields: vec![MarkerStaticField {
+                label: "Description".into(),
+                value: "A marker for testing".into(),
+            }],
+            graphs,
+        }
+    }
+
+    #[test]
+    fn valid_schemas_have_no_issues() {
+        let fields = vec![
+            field("name", MarkerFieldFormat::String),
+            field("size", MarkerFieldFormat::Bytes),
+            field("load", MarkerFieldFormat::Percentage),
+            field("count", MarkerFieldFormat::Integer),
+            field("elapsed", MarkerFieldFormat::Milliseconds),
+            field("latency", MarkerFieldFormat::Duration),
+        ];
+        let graphs = vec![
+            graph("size", MarkerGraphType::Line),
+            graph("load", MarkerGraphType::LineFilled),
+            graph("count", MarkerGraphType::Bar),
+            graph("elapsed", MarkerGraphType::Bar),
+        ];
+        assert_eq!(validate_marker_schema(&schema(fields, graphs)), vec![]);
+        let no_graphs = schema(vec![field("name", MarkerFieldFormat::Url)], vec![]);
+        assert_eq!(validate_marker_schema(&no_graphs), vec![]);
+    }
+
+    #[test]
+    fn invalid_graphs_are_reported() {
+        let fields = vec![
+            field("name", MarkerFieldFormat::String),
+            field("latency", MarkerFieldFormat::Duration),
+            field("start", MarkerFieldFormat::Time),
+        ];
+        let graphs = vec![
+            graph("latency", MarkerGraphType::Line),
+            graph("start", MarkerGraphType::Bar),
+            graph("name", MarkerGraphType::LineFilled),
+            graph("temperature", MarkerGraphType::Line),
+        ];
+        let issues = validate_marker_schema(&schema(fields, graphs));
+        let type_name = "Custom".to_string();
+        assert_eq!(
+            issues,
+            vec![
+                MarkerSchemaIssue::GraphOnUnsupportedFormat {
+                    type_name: type_name.clone(),
+                    key: "latency".into(),
+                    format: MarkerFieldFormat::Duration,
+                },
+                MarkerSchemaIssue::GraphOnUnsupportedFormat {
+                    type_name: type_name.clone(),
+                    key: "start".into(),
+                    format: MarkerFieldFormat::Time,
+                },
+                MarkerSchemaIssue::GraphOnStringField {
+                    type_name: type_name.clone(),
+                    key: "name".into(),
+                    format: MarkerFieldFormat::String,
+                },
+                MarkerSchemaIssue::GraphKeyNotAField {
+                    type_name,
+                    key: "temperature".into(),
+                },
+            ]
+        );
+        assert_eq!(
+            issues[0].to_string(),
+            r#"marker schema "Custom" has a graph of "latency", whose format Duration can't be graphed"#
+        );
+    }
+
+    #[test]
+    fn invalid_fields_and_labels_are_reported() {
+        let mut invalid = schema(
+            vec![
+                field("type", MarkerFieldFormat::String),
+                field("count", MarkerFieldFormat::Integer),
+                field("count", MarkerFieldFormat::Decimal),
+            ],
+            vec![],
+        );
+        invalid.type_name = String::new();
+        let issues = validate_marker_schema(&invalid);
+        assert_eq!(
+            issues,
+            vec![
+                MarkerSchemaIssue::EmptyTypeName,
+                MarkerSchemaIssue::ReservedFieldKey {
+                    type_name: String::new(),
+                    key: "type".into(),
+                },
+                MarkerSchemaIssue::DuplicateFieldKey {
+                    type_name: String::new(),
+                    key: "count".into(),
+                },
+                MarkerSchemaIssue::LabelKeyNotAField {
+                    type_name: String::new(),
+                    label: "{marker.data.name}".into(),
+                    key: "name".into(),
+                },
+                MarkerSchemaIssue::LabelKeyNotAField {
+                    type_name: String::new(),
+                    label: "{marker.name} - { marker.data.name }".into(),
+                    key: "name".into(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn profiles_report_duplicate_type_names() {
+        let mut profile = crate::Profile::new(
+            "test",
+            ReferenceTimestamp::from_millis_since_unix_epoch(0.0),
+            SamplingInterval::from_millis(1),
+        );
+        let valid = schema(vec![field("name", MarkerFieldFormat::String)], vec![]);
+        profile.register_marker_type(valid.clone());
+        assert_eq!(profile.validate_marker_schemas(), vec![]);
+        profile.register_marker_type(valid);
+        assert_eq!(
+            profile.validate_marker_schemas(),
+            vec![MarkerSchemaIssue::DuplicateTypeName {
+                type_name: "Custom".into()
+            }]
+        );
+    }
+}
@@ -424,6 +424,14 @@ impl InternalMarkerSchema {
     pub fn number_field_count(&self) -> usize {
         self.number_field_count
     }
+    pub(crate) fn graphs(&self) -> &[MarkerGraph] {
+        &self.graphs
+    }
+    pub(crate) fn labels(&self) -> impl Iterator<Item = &str> {
+        [&self.chart_label, &self.tooltip_label, &self.table_label]
+            .into_iter()
+            .filter_map(|label| label.as_deref())
+    }
     fn serialize_self<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: serde::Serializer,
@@ -1,4 +1,5 @@
 use std::collections::hash_map::Entry;
+use std::collections::BTreeMap;
 use std::sync::Arc;
 use std::time::Duration;
 
@@ -15,6 +16,7 @@ use crate::frame_table::{InternalFrame, InternalFrameLocation};
 use crate::global_lib_table::{GlobalLibTable, LibraryHandle, UsedLibraryAddressesIterator};
 use crate::lib_mappings::LibMappings;
 use crate::library_info::{LibraryInfo, SymbolTable};
+use crate::marker_schema_validation::{validate_internal_marker_schema, MarkerSchemaIssue};
 use crate::markers::{
     InternalMarkerSchema, Marker, MarkerHandle, MarkerSchema, MarkerTiming, MarkerTypeHandle,
     StaticSchemaMarker,
@@ -121,6 +123,7 @@ pub struct Profile {
     static_schema_marker_types: FastHashMap<&'static str, MarkerTypeHandle>,
     used_pids: FastHashMap<u32, u32>,
     used_tids: FastHashMap<u32, u32>,
+    extra_meta_fields: BTreeMap<String, serde_json::Value>,
 }
 
 impl Profile {
@@ -155,6 +158,7 @@ impl Profile {
             used_pids: FastHashMap::default(),
             used_tids: FastHashMap::default(),
             counters: Vec::new(),
+            extra_meta_fields: BTreeMap::new(),
         }
     }
 
@@ -178,6 +182,22 @@ impl Profile {
         self.os_name = Some(os_name.to_string());
     }
 
+    /// Set an additional property on the profile's `meta` object.
+    ///
+    /// The front-end ignores properties it doesn't know about, so this can be
+    /// used to store information for other tooling. Setting the same key twice
+    /// replaces the previous value. Keys which clash with the built-in `meta`
+    /// properties are written after them, and end up overriding them in most
+    /// JSON parsers, so pick distinct names.
+    pub fn set_extra_meta_field(&mut self, key: &str, value: serde_json::Value) {
+        self.extra_meta_fields.insert(key.to_string(), value);
+    }
+
+    /// Get a property previously set with [`Profile::set_extra_meta_field`].
+    pub fn extra_meta_field(&self, key: &str) -> Option<&serde_json::Value> {
+        self.extra_meta_fields.get(key)
+    }
+
     /// Add a category and return its handle.
     ///
     /// Categories are used for stack frames and markers, as part of a "category pair".
@@ -292,6 +312,11 @@ impl Profile {
         self.global_libs.handle_for_lib(library)
     }
 
+    /// Get the [`LibraryInfo`] which was given to [`Profile::add_lib`] for `library`.
+    pub fn library_info(&self, library: LibraryHandle) -> &LibraryInfo {
+        self.global_libs.lib_by_handle(library)
+    }
+
     /// Set the symbol table for a library.
     ///
     /// This symbol table can also be specified in the [`LibraryInfo`] which is given to
@@ -396,6 +421,11 @@ impl Profile {
         self.threads[thread.0].set_end_time(end_time);
     }
 
+    /// The process which the thread was added to.
+    pub fn thread_process(&self, thread: ThreadHandle) -> ProcessHandle {
+        self.threads[thread.0].process()
+    }
+
     /// Set the tid (thread ID) of a thread.
     pub fn set_thread_tid(&mut self, thread: ThreadHandle, tid: u32) {
         let tid = self.make_unique_tid(tid);
@@ -535,6 +565,24 @@ impl Profile {
         handle
     }
 
+    /// Checks the schemas of all registered marker types against the rules of the
+    /// marker schema format, see [`validate_marker_schema`](crate::validate_marker_schema),
+    /// and checks that no type name is used twice. An empty result means that all
+    /// schemas are valid.
+    pub fn validate_marker_schemas(&self) -> Vec<MarkerSchemaIssue> {
+        let mut type_names = std::collections::HashSet::new();
+        let mut issues = Vec::new();
+        for schema in &self.marker_schemas {
+            if !type_names.insert(schema.type_name()) {
+                issues.push(MarkerSchemaIssue::DuplicateTypeName {
+                    type_name: schema.type_name().to_owned(),
+                });
+            }
+            issues.extend(validate_internal_marker_schema(schema));
+        }
+        issues
+    }
+
     /// Returns the marker type handle for a type that implements [`StaticSchemaMarker`].
     ///
     /// You usually don't need to call this, ever. It is called by the blanket impl
@@ -864,6 +912,10 @@ impl Serialize for SerializableProfileMeta<'_> {
         marker_schemas.sort_by(|a, b| a.type_name().cmp(b.type_name()));
         map.serialize_entry("markerSchema", &marker_schemas)?;
 
+        for (key, value) in &self.0.extra_meta_fields {
+            map.serialize_entry(key, value)?;
+        }
+
         map.end()
     }
 }
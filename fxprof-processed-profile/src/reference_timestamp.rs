@@ -25,6 +25,11 @@ impl ReferenceTimestamp {
         }
     }
 
+    /// The reference timestamp in milliseconds since the UNIX epoch.
+    pub fn as_millis_since_unix_epoch(&self) -> f64 {
+        self.ms_since_unix_epoch
+    }
+
     /// Create a reference timestamp from a [`SystemTime`].
     pub fn from_system_time(system_time: SystemTime) -> Self {
         Self::from_duration_since_unix_epoch(system_time.duration_since(UNIX_EPOCH).unwrap())
@@ -0,0 +1,51 @@
+//! Compares the address lookups of `SymbolPrecog` with the linear search
+//! through the known addresses which it replaced, at 1M addresses.
+//! Run with `cargo bench -p samply --bench precog_lookup`.
+
+use std::hint::black_box;
+use std::time::Instant;
+
+#[allow(dead_code, unused_imports)]
+#[path = "../src/shared/symbol_precog.rs"]
+mod symbol_precog;
+
+use symbol_precog::SymbolPrecog;
+
+const ADDRESS_COUNT: u32 = 1_000_000;
+
+/// The linear search is too slow to look up every address, so it only
+/// looks up this many, spread over the whole range.
+const LINEAR_LOOKUP_COUNT: u32 = 1_000;
+
+fn report(name: &str, count: u32, per: &str, start: Instant) {
+    let ns_per_item = start.elapsed().as_nanos() as f64 / f64::from(count);
+    println!("{name:<24} {ns_per_item:>12.1} ns per {per}");
+}
+
+fn main() {
+    let known_addresses: Vec<(u32, usize)> =
+        (0..ADDRESS_COUNT).map(|i| (i * 16, i as usize)).collect();
+
+    let start = Instant::now();
+    let step = ADDRESS_COUNT / LINEAR_LOOKUP_COUNT;
+    for i in (0..ADDRESS_COUNT).step_by(step as usize) {
+        let rva = black_box(i * 16);
+        let found = known_addresses
+            .iter()
+            .find(|(known_rva, _)| *known_rva == rva)
+            .map(|(_, index)| *index);
+        assert_eq!(found, Some(i as usize));
+    }
+    report("Linear search", LINEAR_LOOKUP_COUNT, "lookup", start);
+
+    let start = Instant::now();
+    let precog = SymbolPrecog::from_known_addresses(&known_addresses);
+    report("SymbolPrecog index", ADDRESS_COUNT, "address", start);
+
+    let start = Instant::now();
+    for i in 0..ADDRESS_COUNT {
+        let rva = black_box(i * 16);
+        assert_eq!(precog.lookup(rva), Some(i as usize));
+    }
+    report("SymbolPrecog", ADDRESS_COUNT, "lookup", start);
+}
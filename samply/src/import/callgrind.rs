@@ -0,0 +1,518 @@
+//! Importing the `callgrind.out.<pid>` files of Valgrind's Callgrind tool,
+//! for `samply import-valgrind`.
+//!
+//! Callgrind doesn't record stacks: it records the exclusive cost of each
+//! function, and the inclusive cost of each call from one function to
+//! another. The stacks are reconstructed by walking the call graph from the
+//! functions which aren't called by anyone, and by splitting up the cost of
+//! a function between its callers in proportion to the cost of their calls.
+//! This is exact for functions with a single caller, and an estimate for the
+//! others.
+//!
+//! Each function of an object file becomes a symbol of a synthetic library
+//! for that object, so that the profile can be grouped by library like a
+//! recorded one.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::BufRead;
+
+use fxprof_processed_profile::{
+    CategoryColor, CategoryPairHandle, CpuDelta, Profile, ReferenceTimestamp, SamplingInterval,
+    Timestamp,
+};
+
+use crate::shared::lib_mappings::{
+    LibMappingAdd, LibMappingInfo, LibMappingOp, LibMappingOpQueue, LibMappingsHierarchy,
+};
+use crate::shared::stack_converter::StackConverter;
+use crate::shared::synthetic_jit_library::SyntheticJitLibrary;
+use crate::shared::types::{StackFrame, StackMode};
+use crate::shared::unresolved_samples::{UnresolvedStackHandle, UnresolvedStacks};
+
+/// The number of instructions, or whatever the first event counts, per unit
+/// of sample weight.
+pub const COST_PER_WEIGHT: u64 = 1_000_000;
+
+/// The distance between the addresses of two object files' synthetic
+/// libraries.
+const OBJECT_ADDRESS_SPACING: u64 = 1 << 32;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("I/O Error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Line {line}: {message}")]
+    Parse { line: usize, message: String },
+}
+
+/// A function of a Callgrind file, with the costs of the first event.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CallgrindFunction {
+    pub name: String,
+    pub object: String,
+    /// The cost of the function itself.
+    pub self_cost: u64,
+    /// The callee's index and the inclusive cost of the calls to it.
+    pub calls: Vec<(usize, u64)>,
+}
+
+impl CallgrindFunction {
+    pub fn inclusive_cost(&self) -> u64 {
+        self.self_cost + self.calls.iter().map(|(_, cost)| cost).sum::<u64>()
+    }
+}
+
+/// The parsed contents of a Callgrind file.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CallgrindData {
+    pub cmd: Option<String>,
+    pub pid: Option<u32>,
+    /// The event names from the `events:` line. The costs are the ones of
+    /// the first event, which is `Ir`, the executed instructions, unless
+    /// Callgrind was told to collect something else.
+    pub events: Vec<String>,
+    pub functions: Vec<CallgrindFunction>,
+}
+
+/// The names of one kind of compressed string, e.g. of the `fn=` and `cfn=`
+/// lines. `fn=(12) name` defines id 12, and `fn=(12)` refers to it.
+#[derive(Debug, Default)]
+struct CompressedNames(HashMap<u32, String>);
+
+impl CompressedNames {
+    fn resolve(&mut self, value: &str) -> Result<String, String> {
+        let Some(rest) = value.strip_prefix('(') else {
+            return Ok(value.to_string());
+        };
+        let (id, name) = rest
+            .split_once(')')
+            .ok_or_else(|| format!("Unterminated name id in {value:?}"))?;
+        let id: u32 = id
+            .parse()
+            .map_err(|_| format!("Invalid name id in {value:?}"))?;
+        let name = name.trim_start();
+        if name.is_empty() {
+            self.0
+                .get(&id)
+                .cloned()
+                .ok_or_else(|| format!("Undefined name id {id}"))
+        } else {
+            self.0.insert(id, name.to_string());
+            Ok(name.to_string())
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Parser {
+    data: CallgrindData,
+    function_indexes: HashMap<(String, String), usize>,
+    objects: CompressedNames,
+    functions: CompressedNames,
+    position_count: usize,
+    current_object: String,
+    current_function: Option<usize>,
+    callee_object: Option<String>,
+    callee: Option<String>,
+    /// Set after a `calls=` line, whose following cost line is the
+    /// inclusive cost of the call.
+    pending_call: Option<usize>,
+}
+
+impl Parser {
+    fn function_index(&mut self, object: String, name: String) -> usize {
+        let functions = &mut self.data.functions;
+        *self
+            .function_indexes
+            .entry((object.clone(), name.clone()))
+            .or_insert_with(|| {
+                functions.push(CallgrindFunction {
+                    name,
+                    object,
+                    ..Default::default()
+                });
+                functions.len() - 1
+            })
+    }
+
+    fn process_line(&mut self, line: &str) -> Result<(), String> {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(());
+        }
+        if line.starts_with(|c: char| c.is_ascii_digit() || matches!(c, '+' | '-' | '*')) {
+            return self.process_cost_line(line);
+        }
+        if let Some((key, value)) = split_key(line, '=') {
+            let value = value.trim();
+            match key {
+                "ob" => self.current_object = self.objects.resolve(value)?,
+                "cob" => self.callee_object = Some(self.objects.resolve(value)?),
+                "fn" => {
+                    let name = self.functions.resolve(value)?;
+                    let object = self.current_object.clone();
+                    self.current_function = Some(self.function_index(object, name));
+                }
+                "cfn" => self.callee = Some(self.functions.resolve(value)?),
+                "calls" => {
+                    let callee = self
+                        .callee
+                        .clone()
+                        .ok_or("calls= line without a preceding cfn= line")?;
+                    let object = self
+                        .callee_object
+                        .take()
+                        .unwrap_or_else(|| self.current_object.clone());
+                    self.pending_call = Some(self.function_index(object, callee));
+                }
+                // File names, and jumps, which don't have a cost line.
+                _ => {}
+            }
+            return Ok(());
+        }
+        if let Some((key, value)) = split_key(line, ':') {
+            let value = value.trim();
+            match key {
+                "cmd" => self.data.cmd = Some(value.to_string()),
+                "pid" => self.data.pid = value.parse().ok(),
+                "events" => {
+                    self.data.events = value.split_whitespace().map(str::to_string).collect()
+                }
+                "positions" => self.position_count = value.split_whitespace().count(),
+                _ => {}
+            }
+            return Ok(());
+        }
+        Err(format!("Unrecognized line {line:?}"))
+    }
+
+    fn process_cost_line(&mut self, line: &str) -> Result<(), String> {
+        let cost = match line.split_whitespace().nth(self.position_count.max(1)) {
+            Some(cost) => cost
+                .parse::<u64>()
+                .map_err(|_| format!("Invalid cost in {line:?}"))?,
+            None => 0,
+        };
+        let caller = self
+            .current_function
+            .ok_or("Cost line without a preceding fn= line")?;
+        let function = &mut self.data.functions[caller];
+        match self.pending_call.take() {
+            Some(callee) => function.calls.push((callee, cost)),
+            None => function.self_cost += cost,
+        }
+        Ok(())
+    }
+}
+
+/// Splits `key<separator>value` lines, for keys which are a single word.
+fn split_key(line: &str, separator: char) -> Option<(&str, &str)> {
+    line.split_once(separator)
+        .filter(|(key, _)| key.chars().all(|c| c.is_ascii_alphanumeric()))
+}
+
+/// Parses a Callgrind file.
+pub fn parse_callgrind(reader: impl BufRead) -> Result<CallgrindData, Error> {
+    let mut parser = Parser {
+        position_count: 1,
+        ..Default::default()
+    };
+    for (index, line) in reader.lines().enumerate() {
+        parser
+            .process_line(&line?)
+            .map_err(|message| Error::Parse {
+                line: index + 1,
+                message,
+            })?;
+    }
+    Ok(parser.data)
+}
+
+/// The reconstructed stacks, as function indexes from the root caller to the
+/// callee, with their exclusive costs.
+pub fn reconstruct_stacks(data: &CallgrindData) -> BTreeMap<Vec<usize>, f64> {
+    let inclusive_costs: Vec<u64> = data
+        .functions
+        .iter()
+        .map(CallgrindFunction::inclusive_cost)
+        .collect();
+    let mut incoming_costs = vec![0; data.functions.len()];
+    for function in &data.functions {
+        for (callee, cost) in &function.calls {
+            incoming_costs[*callee] += cost;
+        }
+    }
+
+    let mut stacks = BTreeMap::new();
+    let mut path = Vec::new();
+    for (index, inclusive_cost) in inclusive_costs.iter().enumerate() {
+        // The cost which isn't explained by calls from other functions, e.g.
+        // all of it for `main`'s caller.
+        let root_cost = inclusive_cost.saturating_sub(incoming_costs[index]);
+        if root_cost > 0 {
+            path.push(index);
+            add_function_stacks(
+                data,
+                &inclusive_costs,
+                root_cost as f64,
+                &mut path,
+                &mut stacks,
+            );
+            path.pop();
+        }
+    }
+    stacks
+}
+
+/// Adds the stacks of the last function of `path`, for `cost` of its
+/// inclusive cost, to `stacks`.
+fn add_function_stacks(
+    data: &CallgrindData,
+    inclusive_costs: &[u64],
+    cost: f64,
+    path: &mut Vec<usize>,
+    stacks: &mut BTreeMap<Vec<usize>, f64>,
+) {
+    let index = *path.last().unwrap();
+    let function = &data.functions[index];
+    let inclusive_cost = inclusive_costs[index] as f64;
+    // Subtrees which are too small to get any weight are added to the
+    // function itself, which keeps the total and the number of stacks down.
+    if cost < COST_PER_WEIGHT as f64 / 2.0 || inclusive_cost == 0.0 {
+        *stacks.entry(path.clone()).or_default() += cost;
+        return;
+    }
+    let fraction = cost / inclusive_cost;
+    let mut self_cost = function.self_cost as f64 * fraction;
+    for (callee, call_cost) in &function.calls {
+        let call_cost = *call_cost as f64 * fraction;
+        if path.contains(callee) {
+            // Recursion is folded into the outermost call.
+            self_cost += call_cost;
+        } else {
+            path.push(*callee);
+            add_function_stacks(data, inclusive_costs, call_cost, path, stacks);
+            path.pop();
+        }
+    }
+    if self_cost > 0.0 {
+        *stacks.entry(path.clone()).or_default() += self_cost;
+    }
+}
+
+/// The profile, and the total cost and the number of samples in it.
+pub struct ImportedCallgrind {
+    pub profile: Profile,
+    pub total_cost: u64,
+    pub sample_count: usize,
+}
+
+/// Makes a profile with one sample per reconstructed stack, weighted by its
+/// cost in units of `COST_PER_WEIGHT`. Stacks with less than half a unit are
+/// left out. The samples are 1ms apart.
+pub fn convert(data: &CallgrindData, reference_timestamp: ReferenceTimestamp) -> ImportedCallgrind {
+    let name = data.cmd.clone().unwrap_or_else(|| "callgrind".to_string());
+    let mut profile = Profile::new(&name, reference_timestamp, SamplingInterval::from_millis(1));
+    let user_category: CategoryPairHandle =
+        profile.add_category("User", CategoryColor::Yellow).into();
+    let kernel_category: CategoryPairHandle =
+        profile.add_category("Kernel", CategoryColor::Orange).into();
+    let start = Timestamp::from_nanos_since_reference(0);
+    let pid = data.pid.unwrap_or(0);
+    let process = profile.add_process(&name, pid, start);
+    let thread = profile.add_thread(process, pid, start, true);
+
+    // A synthetic library per object, with a one-byte symbol per function.
+    let mut libs: Vec<(String, SyntheticJitLibrary)> = Vec::new();
+    let mut addresses = Vec::with_capacity(data.functions.len());
+    for function in &data.functions {
+        let lib_index = match libs
+            .iter()
+            .position(|(object, _)| *object == function.object)
+        {
+            Some(lib_index) => lib_index,
+            None => {
+                let lib_name = if function.object.is_empty() {
+                    "???".to_string()
+                } else {
+                    function.object.clone()
+                };
+                let lib = SyntheticJitLibrary::new(lib_name, user_category, &mut profile, false);
+                libs.push((function.object.clone(), lib));
+                libs.len() - 1
+            }
+        };
+        let relative_address = libs[lib_index].1.add_function(function.name.clone(), 1);
+        let base = (lib_index as u64 + 1) * OBJECT_ADDRESS_SPACING;
+        addresses.push(base + u64::from(relative_address));
+    }
+    let mut lib_mapping_ops = LibMappingOpQueue::default();
+    for (lib_index, (_, lib)) in libs.iter().enumerate() {
+        let start_avma = (lib_index as u64 + 1) * OBJECT_ADDRESS_SPACING;
+        lib_mapping_ops.push(
+            0,
+            LibMappingOp::Add(LibMappingAdd {
+                start_avma,
+                end_avma: start_avma + OBJECT_ADDRESS_SPACING,
+                relative_address_at_start: 0,
+                info: LibMappingInfo::new_lib(lib.lib_handle()),
+            }),
+        );
+    }
+    let mut lib_mappings = LibMappingsHierarchy::new(lib_mapping_ops);
+    lib_mappings.process_ops(0);
+    for (_, lib) in libs {
+        lib.finish_and_set_symbol_table(&mut profile);
+    }
+
+    let mut stacks = UnresolvedStacks::default();
+    let mut stack_converter = StackConverter::new(user_category, kernel_category);
+    let mut frames = Vec::new();
+    let mut sample_count = 0;
+    let mut total_cost = 0.0;
+    for (path, cost) in reconstruct_stacks(data) {
+        total_cost += cost;
+        let weight = (cost / COST_PER_WEIGHT as f64).round() as i32;
+        if weight == 0 {
+            continue;
+        }
+        let stack: UnresolvedStackHandle = stacks.convert(
+            path.iter()
+                .map(|index| StackFrame::InstructionPointer(addresses[*index], StackMode::User)),
+        );
+        frames.clear();
+        stacks.convert_back(stack, &mut frames);
+        let frame_infos = stack_converter.convert_stack(&frames, &lib_mappings, None, None);
+        let timestamp = Timestamp::from_millis_since_reference(sample_count as f64);
+        profile.add_sample(thread, timestamp, frame_infos, CpuDelta::ZERO, weight);
+        sample_count += 1;
+    }
+
+    ImportedCallgrind {
+        profile,
+        total_cost: total_cost.round() as u64,
+        sample_count,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CALLGRIND_OUT: &str = "\
+# callgrind format
+version: 1
+creator: callgrind-3.22.0
+pid: 4242
+cmd:  ./app --mode=fast
+part: 1
+
+positions: line
+events: Ir
+summary: 10000000
+
+ob=(1) /usr/bin/app
+fl=(1) app.c
+fn=(1) main
+5 1000000
+cfn=(2) work
+calls=2 10
+6 7000000
+cob=(2) /usr/lib/libc.so.6
+cfi=(2) ???
+cfn=(3) memcpy
+calls=1 0
+7 2000000
+
+fn=(2)
+10 3000000
+cob=(2)
+cfn=(3)
+calls=4 0
++1 4000000
+
+ob=(2)
+fl=(2)
+fn=(3)
+0 6000000
+
+totals: 10000000
+";
+
+    #[test]
+    fn callgrind_files_are_parsed() {
+        let data = parse_callgrind(CALLGRIND_OUT.as_bytes()).unwrap();
+        assert_eq!(data.cmd.as_deref(), Some("./app --mode=fast"));
+        assert_eq!(data.pid, Some(4242));
+        assert_eq!(data.events, ["Ir"]);
+        let functions: Vec<_> = data
+            .functions
+            .iter()
+            .map(|f| {
+                (
+                    f.object.as_str(),
+                    f.name.as_str(),
+                    f.self_cost,
+                    f.calls.clone(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            functions,
+            [
+                (
+                    "/usr/bin/app",
+                    "main",
+                    1_000_000,
+                    vec![(1, 7_000_000), (2, 2_000_000)]
+                ),
+                ("/usr/bin/app", "work", 3_000_000, vec![(2, 4_000_000)]),
+                ("/usr/lib/libc.so.6", "memcpy", 6_000_000, vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn the_cost_of_shared_callees_is_split_between_callers() {
+        let data = parse_callgrind(CALLGRIND_OUT.as_bytes()).unwrap();
+        let stacks: Vec<(Vec<usize>, u64)> = reconstruct_stacks(&data)
+            .into_iter()
+            .map(|(path, cost)| (path, cost.round() as u64))
+            .collect();
+        assert_eq!(
+            stacks,
+            [
+                (vec![0], 1_000_000),
+                (vec![0, 1], 3_000_000),
+                (vec![0, 1, 2], 4_000_000),
+                (vec![0, 2], 2_000_000),
+            ]
+        );
+
+        let imported = convert(&data, ReferenceTimestamp::from_millis_since_unix_epoch(0.0));
+        assert_eq!(imported.total_cost, 10_000_000);
+        assert_eq!(imported.sample_count, 4);
+    }
+
+    #[test]
+    fn recursion_is_folded() {
+        let data = parse_callgrind(
+            "events: Ir\nfn=(1) main\n0 1000000\ncfn=(2) fib\ncalls=1 0\n0 9000000\n\
+             fn=(2)\n0 3000000\ncfn=(2)\ncalls=10 0\n0 6000000\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        let stacks: Vec<(Vec<usize>, u64)> = reconstruct_stacks(&data)
+            .into_iter()
+            .map(|(path, cost)| (path, cost.round() as u64))
+            .collect();
+        assert_eq!(stacks, [(vec![0], 1_000_000), (vec![0, 1], 9_000_000)]);
+    }
+
+    #[test]
+    fn undefined_name_ids_are_errors() {
+        let err = parse_callgrind("events: Ir\nfn=(3)\n".as_bytes()).unwrap_err();
+        assert_eq!(err.to_string(), "Line 2: Undefined name id 3");
+    }
+}
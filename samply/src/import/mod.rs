@@ -1 +1,2 @@
+pub mod callgrind;
 pub mod perf;
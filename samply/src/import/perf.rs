@@ -14,6 +14,7 @@ use crate::linux_shared::{
     MmapRangeOrVec,
 };
 use crate::shared::recording_props::ProfileCreationProps;
+use crate::shared::time_report::TimeReport;
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
@@ -30,12 +31,12 @@ pub fn convert<C: Read + Seek>(
     binary_lookup_dirs: Vec<PathBuf>,
     aux_file_lookup_dirs: Vec<PathBuf>,
     profile_creation_props: ProfileCreationProps,
-) -> Result<Profile, Error> {
+) -> Result<(Profile, TimeReport), Error> {
     let perf_file = PerfFileReader::parse_file(cursor)?;
 
     let arch = perf_file.perf_file.arch().ok().flatten();
 
-    let profile = match arch {
+    let result = match arch {
         Some("aarch64") => {
             let cache = framehop::aarch64::CacheAarch64::new();
             convert_impl::<framehop::aarch64::UnwinderAarch64<MmapRangeOrVec>, ConvertRegsAarch64, _>(
@@ -65,7 +66,7 @@ pub fn convert<C: Read + Seek>(
             )
         }
     };
-    Ok(profile)
+    Ok(result)
 }
 
 fn convert_impl<U, C, R>(
@@ -75,7 +76,7 @@ fn convert_impl<U, C, R>(
     aux_file_lookup_dirs: Vec<PathBuf>,
     cache: U::Cache,
     profile_creation_props: ProfileCreationProps,
-) -> Profile
+) -> (Profile, TimeReport)
 where
     U: Unwinder<Module = Module<MmapRangeOrVec>> + Default,
     C: ConvertRegs<UnwindRegs = U::UnwindRegs>,
@@ -216,6 +217,9 @@ where
 
                 match interpretation.known_event_indices.get(&attr_index) {
                     Some(KnownEvent::RssStat) => converter.handle_rss_stat_sample::<C>(&e),
+                    Some(KnownEvent::SchedWaking) => {
+                        converter.handle_sched_waking_sample::<C>(&e, attr_index)
+                    }
                     _ => {
                         // the main event and sched_switch are already covered by regular samples so don't add other event markers
                         if !(attr_index == interpretation.main_event_attr_index
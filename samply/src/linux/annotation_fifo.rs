@@ -0,0 +1,161 @@
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{ErrorKind, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use framehop::{Module, Unwinder};
+
+use crate::linux_shared::{Converter, MmapRangeOrVec};
+
+/// A named pipe which turns every line written to it during recording into
+/// an "Annotation" marker, for `--annotation-fifo`.
+///
+/// Writers can open and close the FIFO as often as they like, e.g. with
+/// `echo "warmup done" > /tmp/samply-1234.marks`. The FIFO is removed when
+/// this is dropped.
+pub struct AnnotationFifo {
+    path: PathBuf,
+    file: File,
+    /// The start of a line whose newline hasn't been read yet.
+    pending: Vec<u8>,
+}
+
+impl AnnotationFifo {
+    /// The FIFO path for this samply process, `/tmp/samply-<pid>.marks`.
+    pub fn default_path() -> PathBuf {
+        std::env::temp_dir().join(format!("samply-{}.marks", std::process::id()))
+    }
+
+    /// Creates the FIFO at `path`, replacing any stale file at that path.
+    pub fn create(path: &Path) -> std::io::Result<Self> {
+        let _ = std::fs::remove_file(path);
+        let c_path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|e| std::io::Error::new(ErrorKind::InvalidInput, e))?;
+        if unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        // Opening the read end without O_NONBLOCK would block until the
+        // first writer shows up.
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(path)?;
+        Ok(Self {
+            path: path.to_owned(),
+            file,
+            pending: Vec::new(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the complete lines which were written since the last call,
+    /// without blocking. Empty lines are skipped.
+    ///
+    /// A read of zero bytes means that all writers have closed the FIFO; the
+    /// rest of the last writer's output counts as a line then, even if it
+    /// didn't end in a newline. The FIFO stays open for the next writer.
+    pub fn read_lines(&mut self) -> Vec<String> {
+        let mut lines = Vec::new();
+        let mut buf = [0; 4096];
+        loop {
+            match self.file.read(&mut buf) {
+                Ok(0) => {
+                    let rest = std::mem::take(&mut self.pending);
+                    push_line(&mut lines, &rest);
+                    break;
+                }
+                Ok(len) => {
+                    self.pending.extend_from_slice(&buf[..len]);
+                    while let Some(newline) = self.pending.iter().position(|&b| b == b'\n') {
+                        let line: Vec<u8> = self.pending.drain(..=newline).collect();
+                        push_line(&mut lines, &line[..newline]);
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    eprintln!("Couldn't read from {}: {e}", self.path.display());
+                    break;
+                }
+            }
+        }
+        lines
+    }
+
+    /// Adds an annotation marker for each new line, at `timestamp_mono`.
+    pub fn poll<U>(&mut self, timestamp_mono: u64, converter: &mut Converter<U>)
+    where
+        U: Unwinder<Module = Module<MmapRangeOrVec>> + Default,
+    {
+        for line in self.read_lines() {
+            converter.handle_annotation(timestamp_mono, &line);
+        }
+    }
+}
+
+impl Drop for AnnotationFifo {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+fn push_line(lines: &mut Vec<String>, line: &[u8]) {
+    let line = String::from_utf8_lossy(line);
+    let line = line.trim_end_matches('\r');
+    if !line.trim().is_empty() {
+        lines.push(line.to_string());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use fxprof_processed_profile::{MarkerTiming, Timestamp};
+
+    use super::*;
+    use crate::shared::process_sample_data::AnnotationMarker;
+    use crate::shared::test_support::new_profile;
+
+    fn write_to_fifo(path: &Path, text: &str) {
+        let mut writer = std::fs::OpenOptions::new().write(true).open(path).unwrap();
+        writer.write_all(text.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn every_writer_adds_markers() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("samply.marks");
+        let mut fifo = AnnotationFifo::create(&path).unwrap();
+        assert!(fifo.read_lines().is_empty());
+
+        write_to_fifo(&path, "warmup done\n");
+        let first = fifo.read_lines();
+        write_to_fifo(&path, "\nrequest sent");
+        let second = fifo.read_lines();
+        assert_eq!(first, ["warmup done"]);
+        assert_eq!(second, ["request sent"]);
+
+        let mut profile = new_profile();
+        let start = Timestamp::from_millis_since_reference(0.0);
+        let process = profile.add_process("test", 10, start);
+        let thread = profile.add_thread(process, 10, start, true);
+        for (time, line) in first.iter().chain(&second).enumerate() {
+            let message = profile.intern_string(line);
+            let timing = MarkerTiming::Instant(Timestamp::from_millis_since_reference(time as f64));
+            profile.add_marker(thread, timing, AnnotationMarker(message));
+        }
+        let json = serde_json::to_value(&profile).unwrap();
+        let markers = &json["threads"][0]["markers"];
+        assert_eq!(markers["length"], 2);
+        assert_eq!(markers["data"][1]["type"], "Annotation");
+
+        drop(fifo);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,185 @@
+use std::io::ErrorKind;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+
+use framehop::{Module, Unwinder};
+
+use crate::linux_shared::{Converter, MmapRangeOrVec};
+
+/// The largest datagram we accept. Longer marker lines are truncated by the
+/// kernel, and then dropped as invalid.
+const MAX_DATAGRAM_SIZE: usize = 64 * 1024;
+
+/// A Unix datagram socket which the profiled processes can send marker
+/// lines to, for `--marker-socket`, instead of writing them to marker files.
+///
+/// Each datagram is one line in the marker file format, `id[,tid] {json}`.
+/// The pid isn't part of the line: the kernel attaches the credentials of
+/// the sending process to every datagram, because `SO_PEERCRED` only works
+/// for connected sockets. The socket file is removed when this is dropped.
+pub struct MarkerSocket {
+    path: PathBuf,
+    socket: UnixDatagram,
+}
+
+impl MarkerSocket {
+    /// Binds the socket at `path`, replacing any stale socket file there.
+    pub fn bind(path: &Path) -> std::io::Result<Self> {
+        let _ = std::fs::remove_file(path);
+        let socket = UnixDatagram::bind(path)?;
+        socket.set_nonblocking(true)?;
+        let enable: libc::c_int = 1;
+        let result = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_PASSCRED,
+                &enable as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of_val(&enable) as libc::socklen_t,
+            )
+        };
+        if result != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(Self {
+            path: path.to_owned(),
+            socket,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the datagrams which arrived since the last call, without
+    /// blocking, as `(pid, line)` pairs. Datagrams without credentials, and
+    /// datagrams which aren't UTF-8, are dropped.
+    pub fn receive(&mut self) -> Vec<(i32, String)> {
+        let mut lines = Vec::new();
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+        loop {
+            match self.receive_one(&mut buf) {
+                Ok(Some((Some(pid), len))) => {
+                    if let Ok(line) = std::str::from_utf8(&buf[..len]) {
+                        let line = line.trim_end_matches(['\n', '\r']);
+                        lines.push((pid, line.to_string()));
+                    }
+                }
+                Ok(Some((None, _))) => {}
+                Ok(None) => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => {
+                    eprintln!("Couldn't receive from {}: {e}", self.path.display());
+                    break;
+                }
+            }
+        }
+        lines
+    }
+
+    /// Receives one datagram into `buf`, and returns the sender's pid and
+    /// the datagram's length, or `None` if no datagram is waiting.
+    fn receive_one(&self, buf: &mut [u8]) -> std::io::Result<Option<(Option<i32>, usize)>> {
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        // u64 for the alignment of the cmsghdr.
+        let mut control = [0u64; 8];
+        let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = std::mem::size_of_val(&control) as _;
+
+        let len = unsafe { libc::recvmsg(self.socket.as_raw_fd(), &mut msg, 0) };
+        if len < 0 {
+            let e = std::io::Error::last_os_error();
+            if e.kind() == ErrorKind::WouldBlock {
+                return Ok(None);
+            }
+            return Err(e);
+        }
+
+        let mut pid = None;
+        let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+        while !cmsg.is_null() {
+            let header = unsafe { &*cmsg };
+            if header.cmsg_level == libc::SOL_SOCKET && header.cmsg_type == libc::SCM_CREDENTIALS {
+                let credentials: libc::ucred = unsafe {
+                    std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::ucred)
+                };
+                pid = Some(credentials.pid);
+            }
+            cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+        }
+        Ok(Some((pid, len as usize)))
+    }
+
+    /// Hands every received line to the converter.
+    pub fn poll<U>(&mut self, converter: &mut Converter<U>)
+    where
+        U: Unwinder<Module = Module<MmapRangeOrVec>> + Default,
+    {
+        for (pid, line) in self.receive() {
+            converter.handle_marker_socket_line(pid, &line);
+        }
+    }
+}
+
+impl Drop for MarkerSocket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use fxprof_processed_profile::Timestamp;
+
+    use super::*;
+    use crate::shared::marker_file::{MarkerData, MarkerLineProcessor};
+    use crate::shared::test_support::identity_converter;
+
+    #[test]
+    fn received_lines_become_markers() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("markers.sock");
+        let mut socket = MarkerSocket::bind(&path).unwrap();
+        assert!(socket.receive().is_empty());
+
+        let client = UnixDatagram::unbound().unwrap();
+        for line in [
+            r#"0 {"timestamp":"1000000","target":"app","fields":{"message":"started"}}"#,
+            r#"7 {"timestamp":"2000000","target":"app","span":{"name":"load"},"fields":{"message":"new"}}"#,
+            r#"7 {"timestamp":"5000000","target":"app","span":{"name":"load"},"fields":{"message":"close"}}"#,
+        ] {
+            client.send_to(line.as_bytes(), &path).unwrap();
+        }
+
+        let received = socket.receive();
+        assert_eq!(received.len(), 3);
+        let pid = std::process::id() as i32;
+        assert!(received.iter().all(|(sender, _)| *sender == pid));
+
+        let mut processor = MarkerLineProcessor::new(identity_converter());
+        for (_, line) in &received {
+            processor.process_line(line).unwrap();
+        }
+        let markers = processor.into_markers();
+        let messages: Vec<_> = markers.iter().map(|m| m.message.as_str()).collect();
+        assert_eq!(messages, ["started", "load"]);
+        assert!(matches!(markers[0].marker_data, MarkerData::Event));
+        match &markers[1].marker_data {
+            MarkerData::Span(span) => assert_eq!(
+                span.end_time,
+                Timestamp::from_nanos_since_reference(5_000_000)
+            ),
+            other => panic!("expected a span, got {other:?}"),
+        }
+
+        drop(socket);
+        assert!(!path.exists());
+    }
+}
@@ -1,7 +1,11 @@
+mod annotation_fifo;
+mod marker_socket;
 mod perf_event;
 mod perf_group;
+mod proc_io;
 mod proc_maps;
 mod process;
 pub mod profiler;
 mod sorter;
+mod stderr_markers;
 mod sys;
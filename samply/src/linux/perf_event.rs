@@ -9,9 +9,12 @@ use std::{cmp, fmt, io, mem, ptr, slice};
 
 use libc::{self, c_void, pid_t};
 use linux_perf_data::linux_perf_event_reader;
-use linux_perf_event_reader::{Endianness, RawData, RawEventRecord, RecordParseInfo, RecordType};
+use linux_perf_event_reader::{
+    Endianness, RawData, RawEventRecord, ReadFormat, RecordParseInfo, RecordType,
+};
 
 use super::sys::*;
+use crate::shared::recording_props::HwCounter;
 
 #[derive(Debug)]
 #[repr(C)]
@@ -86,11 +89,18 @@ pub struct Perf {
     fd: RawFd,
     position: u64,
     parse_info: RecordParseInfo,
+    event_source: EventSource,
+    /// The `--read-counters` events in this event's group, which have no
+    /// ring buffers of their own.
+    group_member_fds: Vec<RawFd>,
 }
 
 impl Drop for Perf {
     fn drop(&mut self) {
         unsafe {
+            for &fd in &self.group_member_fds {
+                libc::close(fd);
+            }
             libc::close(self.fd);
         }
     }
@@ -148,6 +158,30 @@ fn next_raw_event(
 pub enum EventSource {
     HwCpuCycles,
     SwCpuClock,
+    /// A `--hw-counter` event. It is sampled every `sample_period()` events,
+    /// without stacks, and doesn't report mmaps, comms or tasks, because the
+    /// CPU sampling events already do.
+    HwCounter(HwCounter),
+}
+
+impl EventSource {
+    /// The name of the event's counter, as in `--hw-counter`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventSource::HwCpuCycles => "cycles",
+            EventSource::SwCpuClock => "cpu-clock",
+            EventSource::HwCounter(counter) => counter.as_str(),
+        }
+    }
+}
+
+fn hw_counter_config(counter: HwCounter) -> u64 {
+    match counter {
+        HwCounter::CacheMisses => PERF_COUNT_HW_CACHE_MISSES,
+        HwCounter::BranchMisses => PERF_COUNT_HW_BRANCH_MISSES,
+        HwCounter::Instructions => PERF_COUNT_HW_INSTRUCTIONS,
+        HwCounter::Cycles => PERF_COUNT_HW_CPU_CYCLES,
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -163,6 +197,7 @@ pub struct PerfBuilder {
     enable_on_exec: bool,
     exclude_kernel: bool,
     gather_context_switches: bool,
+    read_counters: Vec<HwCounter>,
 }
 
 impl PerfBuilder {
@@ -227,6 +262,13 @@ impl PerfBuilder {
         self
     }
 
+    /// Opens `counters` in the group of this event, so that each sample
+    /// carries the values of this event and of `counters`, see `GroupRead`.
+    pub fn read_counters(mut self, counters: Vec<HwCounter>) -> Self {
+        self.read_counters = counters;
+        self
+    }
+
     pub fn open(self) -> io::Result<Perf> {
         let pid = self.pid;
         let cpu = self.cpu.map(|cpu| cpu as i32).unwrap_or(-1);
@@ -295,6 +337,10 @@ impl PerfBuilder {
                 attr.kind = PERF_TYPE_SOFTWARE;
                 attr.config = PERF_COUNT_SW_CPU_CLOCK;
             }
+            EventSource::HwCounter(counter) => {
+                attr.kind = PERF_TYPE_HARDWARE;
+                attr.config = hw_counter_config(counter);
+            }
         }
 
         attr.sample_type = PERF_SAMPLE_IP
@@ -311,6 +357,18 @@ impl PerfBuilder {
             attr.sample_type |= PERF_SAMPLE_STACK_USER;
         }
 
+        // The read values are scaled by the enabled and running times when
+        // the PMU multiplexes the group with other events. The leader's ID
+        // tells the reads of the per-CPU events of a thread apart.
+        let read_format = PERF_FORMAT_GROUP
+            | PERF_FORMAT_TOTAL_TIME_ENABLED
+            | PERF_FORMAT_TOTAL_TIME_RUNNING
+            | PERF_FORMAT_ID;
+        if !self.read_counters.is_empty() {
+            attr.sample_type |= PERF_SAMPLE_READ;
+            attr.read_format = read_format;
+        }
+
         attr.sample_regs_user = reg_mask;
         attr.sample_stack_user = stack_size;
         attr.sample_period_or_freq = frequency;
@@ -326,6 +384,12 @@ impl PerfBuilder {
             | PERF_ATTR_FLAG_SAMPLE_ID_ALL
             | PERF_ATTR_FLAG_USE_CLOCKID;
 
+        if let EventSource::HwCounter(counter) = event_source {
+            attr.sample_period_or_freq = counter.sample_period();
+            attr.flags =
+                PERF_ATTR_FLAG_DISABLED | PERF_ATTR_FLAG_SAMPLE_ID_ALL | PERF_ATTR_FLAG_USE_CLOCKID;
+        }
+
         if self.enable_on_exec {
             attr.flags |= PERF_ATTR_FLAG_ENABLE_ON_EXEC;
         }
@@ -358,6 +422,40 @@ impl PerfBuilder {
             return Err(err);
         }
 
+        let mut group_member_fds = Vec::new();
+        for &counter in &self.read_counters {
+            let mut member_attr: PerfEventAttr = unsafe { mem::zeroed() };
+            member_attr.size = mem::size_of::<PerfEventAttr>() as u32;
+            member_attr.kind = PERF_TYPE_HARDWARE;
+            member_attr.config = hw_counter_config(counter);
+            member_attr.read_format = read_format;
+            // Members count whenever the leader does, so they aren't
+            // disabled themselves.
+            if exclude_kernel {
+                member_attr.flags |= PERF_ATTR_FLAG_EXCLUDE_KERNEL;
+            }
+            if inherit {
+                member_attr.flags |= PERF_ATTR_FLAG_INHERIT;
+            }
+            let member_fd = sys_perf_event_open(
+                &member_attr,
+                pid as pid_t,
+                cpu as _,
+                fd,
+                PERF_FLAG_FD_CLOEXEC,
+            );
+            if member_fd < 0 {
+                unsafe {
+                    for &fd in &group_member_fds {
+                        libc::close(fd);
+                    }
+                    libc::close(fd);
+                }
+                return Err(io::Error::from_raw_os_error(-member_fd));
+            }
+            group_member_fds.push(member_fd);
+        }
+
         const STACK_COUNT_PER_BUFFER: u32 = 32;
         let required_space = max(stack_size, 4096) * STACK_COUNT_PER_BUFFER;
         let page_size = 4096;
@@ -383,6 +481,9 @@ impl PerfBuilder {
                 0,
             );
             if buffer == libc::MAP_FAILED {
+                for &fd in &group_member_fds {
+                    libc::close(fd);
+                }
                 libc::close(fd);
                 return Err(io::Error::new(io::ErrorKind::Other, "mmap failed"));
             }
@@ -397,7 +498,14 @@ impl PerfBuilder {
         let (attr2, _size) =
             linux_perf_event_reader::PerfEventAttr::parse::<_, byteorder::NativeEndian>(attr_bytes)
                 .unwrap();
-        let parse_info = RecordParseInfo::new(&attr2, Endianness::NATIVE);
+        let mut parse_info = RecordParseInfo::new(&attr2, Endianness::NATIVE);
+        // linux-perf-event-reader skips the read values of group reads as if
+        // they were the values of a single event, and vice versa. Group reads
+        // are the only reads we ask for, so flip the flag for the records to
+        // parse correctly. `GroupRead` parses the values themselves.
+        if !self.read_counters.is_empty() {
+            parse_info.read_format.toggle(ReadFormat::GROUP);
+        }
 
         // debug!("Perf events open with fd={}", fd);
         let mut perf = Perf {
@@ -407,6 +515,8 @@ impl PerfBuilder {
             fd,
             position: 0,
             parse_info,
+            event_source,
+            group_member_fds,
         };
 
         if !start_disabled {
@@ -436,6 +546,7 @@ impl Perf {
             enable_on_exec: false,
             exclude_kernel: true,
             gather_context_switches: false,
+            read_counters: Vec::new(),
         }
     }
 
@@ -524,6 +635,7 @@ pub struct EventRef {
     prev_position: u64,
     position: u64,
     parse_info: RecordParseInfo,
+    event_source: EventSource,
 }
 
 impl fmt::Debug for EventRef {
@@ -551,6 +663,11 @@ impl EventRef {
 
         self.event_location.get(buffer, self.parse_info)
     }
+
+    /// The source of the perf event which this record came from.
+    pub fn event_source(&self) -> EventSource {
+        self.event_source
+    }
 }
 
 pub struct EventIter<'a> {
@@ -580,6 +697,7 @@ impl Iterator for EventIter<'_> {
             prev_position,
             position: perf.position,
             parse_info: self.perf.parse_info,
+            event_source: self.perf.event_source,
         })
     }
 }
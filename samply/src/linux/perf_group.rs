@@ -10,8 +10,9 @@ use linux_perf_data::linux_perf_event_reader::get_record_timestamp;
 use mio::unix::SourceFd;
 use mio::{Events, Interest, Poll, Token};
 
-use super::perf_event::{EventRef, EventSource, Perf};
+use super::perf_event::{EventRef, EventSource, Perf, PerfBuilder};
 use super::sorter::EventSorter;
+use crate::shared::recording_props::HwCounter;
 
 struct StoppedProcess(u32);
 
@@ -72,6 +73,14 @@ pub struct PerfGroup {
     stack_size: u32,
     regs_mask: u64,
     event_source: EventSource,
+    /// Whether the events of a process are inherited by the processes and
+    /// threads it creates after the events were opened.
+    inherit: bool,
+    /// The `--hw-counter` events which are opened next to the sampling events.
+    hw_counters: Vec<HwCounter>,
+    /// The `--read-counters` events which are read with each sample of the
+    /// sampling events.
+    read_counters: Vec<HwCounter>,
     stopped_processes: Vec<StoppedProcess>,
 }
 
@@ -98,7 +107,15 @@ pub enum AttachMode {
 }
 
 impl PerfGroup {
-    pub fn new(frequency: u32, stack_size: u32, regs_mask: u64, event_source: EventSource) -> Self {
+    pub fn new(
+        frequency: u32,
+        stack_size: u32,
+        regs_mask: u64,
+        event_source: EventSource,
+        inherit: bool,
+        hw_counters: Vec<HwCounter>,
+        read_counters: Vec<HwCounter>,
+    ) -> Self {
         PerfGroup {
             event_sorter: EventSorter::new(),
             members: Default::default(),
@@ -108,19 +125,34 @@ impl PerfGroup {
             stack_size,
             event_source,
             regs_mask,
+            inherit,
+            hw_counters,
+            read_counters,
             stopped_processes: Vec::new(),
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn open(
         pid: u32,
         frequency: u32,
         stack_size: u32,
         event_source: EventSource,
         regs_mask: u64,
+        inherit: bool,
+        hw_counters: Vec<HwCounter>,
+        read_counters: Vec<HwCounter>,
         attach_mode: AttachMode,
     ) -> Result<Self, io::Error> {
-        let mut group = PerfGroup::new(frequency, stack_size, regs_mask, event_source);
+        let mut group = PerfGroup::new(
+            frequency,
+            stack_size,
+            regs_mask,
+            event_source,
+            inherit,
+            hw_counters,
+            read_counters,
+        );
         group.open_process(pid, attach_mode)?;
         Ok(group)
     }
@@ -143,16 +175,19 @@ impl PerfGroup {
                 .sample_kernel()
                 .gather_context_switches()
                 .event_source(self.event_source)
-                .inherit_to_children()
                 .start_disabled();
 
+            if self.inherit {
+                builder = builder.inherit_to_children();
+            }
             if attach_mode == AttachMode::AttachWithEnableOnExec {
                 builder = builder.enable_on_exec();
             }
 
-            let perf = builder.open()?;
+            let perf = self.open_sampling_event(builder)?;
 
             perf_events.push((Some(cpu), perf));
+            self.open_hw_counters(pid, Some(cpu), attach_mode, &mut perf_events);
         }
 
         if cpu_count * (threads.len() + 1) >= 1000 {
@@ -169,9 +204,10 @@ impl PerfGroup {
                 if attach_mode == AttachMode::AttachWithEnableOnExec {
                     builder = builder.enable_on_exec();
                 }
-                let perf = builder.open()?;
+                let perf = self.open_sampling_event(builder)?;
 
                 perf_events.push((None, perf));
+                self.open_hw_counters(tid, None, attach_mode, &mut perf_events);
             }
         } else {
             for cpu in 0..cpu_count as u32 {
@@ -185,14 +221,17 @@ impl PerfGroup {
                         .sample_kernel()
                         .gather_context_switches()
                         .event_source(self.event_source)
-                        .inherit_to_children()
                         .start_disabled();
+                    if self.inherit {
+                        builder = builder.inherit_to_children();
+                    }
                     if attach_mode == AttachMode::AttachWithEnableOnExec {
                         builder = builder.enable_on_exec();
                     }
-                    let perf = builder.open()?;
+                    let perf = self.open_sampling_event(builder)?;
 
                     perf_events.push((Some(cpu), perf));
+                    self.open_hw_counters(tid, Some(cpu), attach_mode, &mut perf_events);
                 }
             }
         }
@@ -210,6 +249,81 @@ impl PerfGroup {
         Ok(())
     }
 
+    /// Opens a sampling event together with the `--read-counters` events.
+    /// If they can't be opened, e.g. because the kernel can't read the
+    /// counters of inherited events before Linux 6.12, the recording goes on
+    /// without them, with a warning.
+    fn open_sampling_event(&mut self, builder: PerfBuilder) -> Result<Perf, io::Error> {
+        if self.read_counters.is_empty() {
+            return builder.open();
+        }
+        match builder
+            .clone()
+            .read_counters(self.read_counters.clone())
+            .open()
+        {
+            Ok(perf) => Ok(perf),
+            Err(e) => {
+                let names: Vec<&str> = self.read_counters.iter().map(|c| c.as_str()).collect();
+                eprintln!(
+                    "Couldn't read the {} counters with the samples, recording without them: {e}",
+                    names.join(",")
+                );
+                self.read_counters.clear();
+                builder.open()
+            }
+        }
+    }
+
+    /// The `--read-counters` events which the sampling events were opened
+    /// with.
+    pub fn read_counters(&self) -> &[HwCounter] {
+        &self.read_counters
+    }
+
+    /// Opens the hardware counter events for `pid` on `cpu`, or on any CPU.
+    /// A counter which can't be opened, e.g. in a VM without a PMU, is
+    /// dropped with a warning, and the recording goes on without it.
+    fn open_hw_counters(
+        &mut self,
+        pid: u32,
+        cpu: Option<u32>,
+        attach_mode: AttachMode,
+        perf_events: &mut Vec<(Option<u32>, Perf)>,
+    ) {
+        let inherit = self.inherit;
+        self.hw_counters.retain(|&counter| {
+            let mut builder = Perf::build()
+                .pid(pid)
+                .sample_kernel()
+                .event_source(EventSource::HwCounter(counter))
+                .start_disabled();
+            builder = match cpu {
+                Some(cpu) => builder.only_cpu(cpu),
+                None => builder.any_cpu(),
+            };
+            if inherit && cpu.is_some() {
+                builder = builder.inherit_to_children();
+            }
+            if attach_mode == AttachMode::AttachWithEnableOnExec {
+                builder = builder.enable_on_exec();
+            }
+            match builder.open() {
+                Ok(perf) => {
+                    perf_events.push((cpu, perf));
+                    true
+                }
+                Err(e) => {
+                    eprintln!(
+                        "Couldn't open the {} hardware counter, recording without it: {e}",
+                        counter.as_str()
+                    );
+                    false
+                }
+            }
+        });
+    }
+
     pub fn is_empty(&self) -> bool {
         self.members.is_empty()
     }
@@ -233,7 +347,10 @@ impl PerfGroup {
             .poll
             .poll(&mut self.poll_events, Some(Duration::from_millis(100)));
         if let Err(err) = result {
-            eprintln!("poll failed: {}", err);
+            // E.g. SIGUSR2, see `run_profiler`.
+            if err.kind() != std::io::ErrorKind::Interrupted {
+                eprintln!("poll failed: {}", err);
+            }
             return;
         }
 
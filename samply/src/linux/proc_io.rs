@@ -0,0 +1,54 @@
+use std::time::{Duration, Instant};
+
+use framehop::{Module, Unwinder};
+
+use crate::linux_shared::{Converter, MmapRangeOrVec, ProcIo};
+
+/// How often `/proc/<pid>/io` is read.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Reads `/proc/<pid>/io` of the profiled processes at a fixed interval, for
+/// the "Disk reads" and "Disk writes" counters.
+pub struct ProcIoPoller {
+    pids: Vec<u32>,
+    last_poll: Option<Instant>,
+}
+
+impl ProcIoPoller {
+    pub fn new() -> Self {
+        Self {
+            pids: Vec::new(),
+            last_poll: None,
+        }
+    }
+
+    pub fn add_pid(&mut self, pid: u32) {
+        if !self.pids.contains(&pid) {
+            self.pids.push(pid);
+        }
+    }
+
+    pub fn is_due(&self) -> bool {
+        self.last_poll
+            .map_or(true, |last_poll| last_poll.elapsed() >= POLL_INTERVAL)
+    }
+
+    /// Reads the counters of all processes and hands them to the converter.
+    /// Processes whose `io` file can't be read anymore have exited, and are
+    /// not polled again.
+    pub fn poll<U>(&mut self, timestamp_mono: u64, converter: &mut Converter<U>)
+    where
+        U: Unwinder<Module = Module<MmapRangeOrVec>> + Default,
+    {
+        self.last_poll = Some(Instant::now());
+        self.pids.retain(|&pid| {
+            let Ok(contents) = std::fs::read_to_string(format!("/proc/{pid}/io")) else {
+                return false;
+            };
+            if let Some(io) = ProcIo::parse(&contents) {
+                converter.handle_proc_io(pid as i32, timestamp_mono, io);
+            }
+            true
+        });
+    }
+}
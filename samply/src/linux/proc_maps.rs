@@ -49,6 +49,12 @@ fn skip_whitespace(p: &mut &str) {
     }
 }
 
+/// Reads the current memory mappings of the process `pid` from `/proc/<pid>/maps`.
+pub fn read(pid: u32) -> std::io::Result<Vec<Region>> {
+    let maps = std::fs::read(format!("/proc/{pid}/maps"))?;
+    Ok(parse(&String::from_utf8_lossy(&maps)))
+}
+
 pub fn parse(maps: &str) -> Vec<Region> {
     if maps.is_empty() {
         return Vec::new();
@@ -173,3 +179,19 @@ fn test_parse() {
 fn test_empty_maps() {
     assert_eq!(parse(""), vec![]);
 }
+
+#[test]
+fn test_parse_unusual_names() {
+    let maps = "7f0000000000-7f0000001000 r-xp 00002000 fd:01 42                         /tmp/my lib.so (deleted)\n";
+    let regions = parse(maps);
+    assert_eq!(regions.len(), 1);
+    assert_eq!(regions[0].file_offset, 0x2000);
+    assert_eq!(regions[0].name, "/tmp/my lib.so (deleted)");
+}
+
+#[test]
+fn test_read_own_maps() {
+    let regions = read(std::process::id()).unwrap();
+    assert!(regions.iter().any(|region| region.is_executable));
+    assert!(read(u32::MAX).is_err());
+}
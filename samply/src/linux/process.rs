@@ -20,6 +20,7 @@ impl SuspendedLaunchedProcess {
         command_name: &OsStr,
         command_args: &[OsString],
         env_vars: &[(OsString, OsString)],
+        stderr: Option<OwnedFd>,
     ) -> std::io::Result<Self> {
         let argv: Vec<CString> = std::iter::once(command_name)
             .chain(command_args.iter().map(|s| s.as_os_str()))
@@ -57,11 +58,19 @@ impl SuspendedLaunchedProcess {
                 // std::panic::always_abort();
                 nix::unistd::close(resume_sp.into_raw_fd()).unwrap();
                 nix::unistd::close(execerr_rp.into_raw_fd()).unwrap();
+                if let Some(stderr) = stderr {
+                    // The duplicate doesn't inherit O_CLOEXEC, so the command
+                    // keeps it across exec.
+                    unsafe { libc::dup2(stderr.as_raw_fd(), libc::STDERR_FILENO) };
+                }
                 Self::run_child(resume_rp, execerr_sp, &argv, envp)
             }
             nix::unistd::ForkResult::Parent { child } => {
                 nix::unistd::close(resume_rp.into_raw_fd())?;
                 nix::unistd::close(execerr_sp.into_raw_fd())?;
+                // Only the child writes to the stderr pipe, so that the
+                // reader sees the end of it once the child is gone.
+                drop(stderr);
                 Ok(Self {
                     pid: child,
                     send_end_of_resume_pipe: resume_sp,
@@ -1,9 +1,12 @@
 use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
 use std::fs::File;
 use std::ops::Deref;
 use std::os::unix::process::ExitStatusExt;
 use std::path::Path;
 use std::process::ExitStatus;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::thread;
 use std::time::{Duration, SystemTime};
 
@@ -15,20 +18,30 @@ use linux_perf_data::linux_perf_event_reader::{
 use nix::sys::wait::WaitStatus;
 use tokio::sync::oneshot;
 
+use super::annotation_fifo::AnnotationFifo;
+use super::marker_socket::MarkerSocket;
 use super::perf_event::EventSource;
 use super::perf_group::{AttachMode, PerfGroup};
+use super::proc_io::ProcIoPoller;
 use super::proc_maps;
 use super::process::SuspendedLaunchedProcess;
+use super::stderr_markers::StderrMarkers;
 use crate::linux_shared::vdso::VdsoObject;
 use crate::linux_shared::{
     ConvertRegs, Converter, EventInterpretation, MmapRangeOrVec, OffCpuIndicator,
 };
 use crate::server::{start_server_main, ServerProps};
 use crate::shared::ctrl_c::CtrlC;
+use crate::shared::memory_usage::MemoryUsageMonitor;
+use crate::shared::per_cpu::CpuTopology;
 use crate::shared::recording_props::{
-    ProcessLaunchProps, ProfileCreationProps, RecordingMode, RecordingProps,
+    ClockSource, HwCounter, ProcessLaunchProps, ProfileCreationProps, RecordingMode,
+    RecordingProps, SampleWeightUnit, SchemaValidation,
+};
+use crate::shared::save_profile::{
+    append_profile_to_file, check_marker_schemas, embed_memory_report, embed_sample_weight_unit,
+    save_profile_to_file,
 };
-use crate::shared::save_profile::save_profile_to_file;
 use crate::shared::symbol_props::SymbolProps;
 
 #[cfg(target_arch = "x86_64")]
@@ -40,7 +53,7 @@ pub type ConvertRegsNative = crate::linux_shared::ConvertRegsAarch64;
 pub fn start_recording(
     recording_mode: RecordingMode,
     recording_props: RecordingProps,
-    profile_creation_props: ProfileCreationProps,
+    mut profile_creation_props: ProfileCreationProps,
     symbol_props: SymbolProps,
     server_props: Option<ServerProps>,
 ) -> Result<ExitStatus, ()> {
@@ -86,11 +99,21 @@ pub fn start_recording(
     // to all processes in the foreground process group).
     let mut ctrl_c_receiver = CtrlC::observe_oneshot();
 
+    // With --stderr-markers, the stderr lines of every iteration of the
+    // command become markers when its process is finished.
+    let stderr_markers = recording_props
+        .stderr_markers
+        .then(|| Arc::new(StderrMarkers::default()));
+    if let Some(stderr_markers) = &stderr_markers {
+        profile_creation_props
+            .custom_marker_sources
+            .0
+            .push(stderr_markers.clone());
+    }
+
     // Start a new process for the launched command and get its pid.
     // The command will not start running until we tell it to.
-    let process =
-        SuspendedLaunchedProcess::launch_in_suspended_state(&command_name, &args, &env_vars)
-            .unwrap();
+    let process = launch_suspended(&command_name, &args, &env_vars, stderr_markers.as_deref());
     let pid = process.pid();
 
     // Create a channel for the observer thread to notify the main thread once
@@ -104,6 +127,27 @@ pub fn start_recording(
     let output_file_copy = recording_props.output_file.clone();
     let interval = recording_props.interval;
     let time_limit = recording_props.time_limit;
+    let sample_stacks = recording_props.sample_stacks;
+    let inherit = recording_props.inherit;
+    let hw_counters = recording_props.hw_counters;
+    let read_counters = recording_props.read_counters;
+    let cpu_clock_source = recording_props.cpu_clock_source;
+    let io_counters = recording_props.io_counters;
+    let annotation_fifo = recording_props
+        .annotation_fifo
+        .then(create_annotation_fifo)
+        .flatten();
+    let marker_socket = recording_props
+        .marker_socket
+        .as_deref()
+        .and_then(bind_marker_socket);
+    let sample_callback = recording_props.sample_callback;
+    let spill_dir = recording_props.spill_dir;
+    let log_markers = recording_props.log_markers;
+    let record_only_during_span = recording_props.record_only_during_span;
+    let span_lookback = recording_props.span_lookback;
+    let reuse_profile = recording_props.reuse_profile;
+    let sample_weight_unit = recording_props.sample_weight_unit;
     let initial_exec_name = command_name.to_string_lossy().to_string();
     let initial_cmdline: Vec<String> = std::iter::once(initial_exec_name.clone())
         .chain(args.iter().map(|arg| arg.to_string_lossy().to_string()))
@@ -115,7 +159,13 @@ pub fn start_recording(
     let initial_exec_name_and_cmdline = (initial_exec_name, initial_cmdline);
     let observer_thread = thread::spawn(move || {
         let unstable_presymbolicate = profile_creation_props.unstable_presymbolicate;
+        let time_report = profile_creation_props.time_report;
+        let validate_schemas = profile_creation_props.validate_schemas;
         let mut converter = make_converter(interval, profile_creation_props);
+        converter.set_sample_callback(sample_callback);
+        converter.set_spill_dir(spill_dir);
+        converter.set_log_markers(log_markers);
+        converter.set_record_only_during_span(record_only_during_span, span_lookback);
 
         // Wait for the initial pid to profile.
         let SamplerRequest::StartProfilingAnotherProcess(pid, attach_mode) =
@@ -125,7 +175,22 @@ pub fn start_recording(
         };
 
         // Create the perf events, setting ENABLE_ON_EXEC.
-        let perf_group = init_profiler(interval, pid, attach_mode, &mut converter);
+        let perf_group = init_profiler(
+            interval,
+            sample_stacks,
+            inherit,
+            hw_counters,
+            read_counters,
+            cpu_clock_source,
+            pid,
+            attach_mode,
+            &mut converter,
+        );
+        let io_poller = io_counters.then(|| {
+            let mut io_poller = ProcIoPoller::new();
+            io_poller.add_pid(pid);
+            io_poller
+        });
 
         // Tell the main thread to tell the child process to begin executing.
         profile_another_pid_reply_sender.send(true).unwrap();
@@ -142,11 +207,18 @@ pub fn start_recording(
             perf_group,
             converter,
             &output_file_copy,
+            reuse_profile,
+            sample_weight_unit,
             time_limit,
             profile_another_pid_request_receiver,
             profile_another_pid_reply_sender,
             stop_receiver,
             unstable_presymbolicate,
+            time_report,
+            validate_schemas,
+            io_poller,
+            annotation_fifo,
+            marker_socket,
             Some(initial_exec_name_and_cmdline),
         );
     });
@@ -154,13 +226,20 @@ pub fn start_recording(
     // We're on the main thread here and the observer thread has just been launched.
 
     // Request profiling of our process and wait for profiler initialization.
-    profile_another_pid_request_sender
-        .send(SamplerRequest::StartProfilingAnotherProcess(
-            pid,
-            AttachMode::AttachWithEnableOnExec,
-        ))
-        .unwrap();
-    let _ = profile_another_pid_reply_receiver.recv().unwrap();
+    // With --stdin-trigger, the process is launched unprofiled and attached
+    // to like in --pid mode once the trigger fires.
+    let attach_initial_process = |attach_mode| {
+        profile_another_pid_request_sender
+            .send(SamplerRequest::StartProfilingAnotherProcess(
+                pid,
+                attach_mode,
+            ))
+            .unwrap();
+        let _ = profile_another_pid_reply_receiver.recv().unwrap();
+    };
+    if !recording_props.stdin_trigger {
+        attach_initial_process(AttachMode::AttachWithEnableOnExec);
+    }
 
     // Now tell the child process to start executing.
     let process = match process.unsuspend_and_run() {
@@ -180,6 +259,11 @@ pub fn start_recording(
         }
     };
 
+    if recording_props.stdin_trigger {
+        wait_for_stdin_trigger();
+        attach_initial_process(AttachMode::StopAttachEnableResume);
+    }
+
     // Phew, we're profiling!
 
     // Wait for the child process to quit.
@@ -198,9 +282,7 @@ pub fn start_recording(
             break;
         }
         eprintln!("Running iteration {i} of {iteration_count}...");
-        let process =
-            SuspendedLaunchedProcess::launch_in_suspended_state(&command_name, &args, &env_vars)
-                .unwrap();
+        let process = launch_suspended(&command_name, &args, &env_vars, stderr_markers.as_deref());
         let pid = process.pid();
 
         // Tell the sampler to start profiling another pid, and wait for it to signal us to go ahead.
@@ -282,18 +364,51 @@ fn start_profiling_pid(
         crossbeam_channel::bounded(2);
 
     let output_file = recording_props.output_file.clone();
+    let stdin_trigger = recording_props.stdin_trigger;
+    let annotation_fifo = recording_props
+        .annotation_fifo
+        .then(create_annotation_fifo)
+        .flatten();
+    let marker_socket = recording_props
+        .marker_socket
+        .as_deref()
+        .and_then(bind_marker_socket);
     let observer_thread = thread::spawn({
         move || {
             let interval = recording_props.interval;
             let time_limit = recording_props.time_limit;
             let unstable_presymbolicate = profile_creation_props.unstable_presymbolicate;
+            let time_report = profile_creation_props.time_report;
+            let validate_schemas = profile_creation_props.validate_schemas;
             let mut converter = make_converter(interval, profile_creation_props);
+            converter.set_sample_callback(recording_props.sample_callback.clone());
+            converter.set_spill_dir(recording_props.spill_dir.clone());
+            converter.set_log_markers(recording_props.log_markers);
+            converter.set_record_only_during_span(
+                recording_props.record_only_during_span.clone(),
+                recording_props.span_lookback,
+            );
             let SamplerRequest::StartProfilingAnotherProcess(pid, attach_mode) =
                 profile_another_pid_request_receiver.recv().unwrap()
             else {
                 panic!("The first message should be a StartProfilingAnotherProcess")
             };
-            let perf_group = init_profiler(interval, pid, attach_mode, &mut converter);
+            let perf_group = init_profiler(
+                interval,
+                recording_props.sample_stacks,
+                recording_props.inherit,
+                recording_props.hw_counters.clone(),
+                recording_props.read_counters.clone(),
+                recording_props.cpu_clock_source,
+                pid,
+                attach_mode,
+                &mut converter,
+            );
+            let io_poller = recording_props.io_counters.then(|| {
+                let mut io_poller = ProcIoPoller::new();
+                io_poller.add_pid(pid);
+                io_poller
+            });
 
             // Tell the main thread that we are now executing.
             profile_another_pid_reply_sender.send(true).unwrap();
@@ -303,11 +418,18 @@ fn start_profiling_pid(
                 perf_group,
                 converter,
                 &output_file,
+                recording_props.reuse_profile,
+                recording_props.sample_weight_unit,
                 time_limit,
                 profile_another_pid_request_receiver,
                 profile_another_pid_reply_sender,
                 ctrl_c_receiver,
                 unstable_presymbolicate,
+                time_report,
+                validate_schemas,
+                io_poller,
+                annotation_fifo,
+                marker_socket,
                 None,
             )
         }
@@ -315,6 +437,10 @@ fn start_profiling_pid(
 
     // We're on the main thread here and the observer thread has just been launched.
 
+    if stdin_trigger {
+        wait_for_stdin_trigger();
+    }
+
     // Request profiling of our process and wait for profiler initialization.
     profile_another_pid_request_sender
         .send(SamplerRequest::StartProfilingAnotherProcess(
@@ -352,6 +478,79 @@ fn start_profiling_pid(
     }
 }
 
+/// Launches the command in a suspended state, with its stderr going to
+/// `stderr_markers` if set.
+fn launch_suspended(
+    command_name: &OsStr,
+    args: &[OsString],
+    env_vars: &[(OsString, OsString)],
+    stderr_markers: Option<&StderrMarkers>,
+) -> SuspendedLaunchedProcess {
+    let stderr_pipe = stderr_markers.and_then(|_| match StderrMarkers::pipe() {
+        Ok(pipe) => Some(pipe),
+        Err(e) => {
+            eprintln!("Couldn't create the pipe for --stderr-markers: {e}");
+            None
+        }
+    });
+    let (read_end, write_end) = stderr_pipe.unzip();
+    let process = SuspendedLaunchedProcess::launch_in_suspended_state(
+        command_name,
+        args,
+        env_vars,
+        write_end,
+    )
+    .unwrap();
+    if let (Some(stderr_markers), Some(read_end)) = (stderr_markers, read_end) {
+        stderr_markers.capture(process.pid(), read_end);
+    }
+    process
+}
+
+/// Creates the FIFO for `--annotation-fifo` and tells the user where it is.
+fn create_annotation_fifo() -> Option<AnnotationFifo> {
+    let path = AnnotationFifo::default_path();
+    match AnnotationFifo::create(&path) {
+        Ok(fifo) => {
+            eprintln!(
+                "Lines written to {} will be added as annotation markers.",
+                fifo.path().display()
+            );
+            Some(fifo)
+        }
+        Err(e) => {
+            eprintln!(
+                "Couldn't create the annotation FIFO at {}: {e}",
+                path.display()
+            );
+            None
+        }
+    }
+}
+
+/// Binds the socket for `--marker-socket`.
+fn bind_marker_socket(path: &Path) -> Option<MarkerSocket> {
+    match MarkerSocket::bind(path) {
+        Ok(socket) => {
+            eprintln!("Reading markers from {}.", socket.path().display());
+            Some(socket)
+        }
+        Err(e) => {
+            eprintln!("Couldn't bind the marker socket at {}: {e}", path.display());
+            None
+        }
+    }
+}
+
+/// Blocks until a line is read from stdin, or stdin is closed.
+fn wait_for_stdin_trigger() {
+    eprintln!("Press Enter to start recording...");
+    let mut line = String::new();
+    if let Err(e) = std::io::stdin().read_line(&mut line) {
+        eprintln!("Couldn't read from stdin, starting to record now: {e}");
+    }
+}
+
 fn paranoia_level() -> Option<u32> {
     let level = read_string_lossy("/proc/sys/kernel/perf_event_paranoid").ok()?;
     let level = level.trim().parse::<u32>().ok()?;
@@ -406,11 +605,61 @@ fn make_converter(
     if let Ok(os_release) = os_release::OsRelease::new() {
         converter.set_os_name(&os_release.pretty_name);
     }
+    if let Some(offset) = realtime_to_monotonic_offset_ns() {
+        converter.register_realtime_offset(offset);
+    }
+    match CpuTopology::from_sysfs() {
+        Ok(topology) => converter.set_cpu_topology(topology),
+        Err(err) => log::warn!("Could not read the CPU topology: {err}"),
+    }
     converter
 }
 
+pub(super) fn clock_ns(clock_id: libc::clockid_t) -> Option<i64> {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    let result = unsafe { libc::clock_gettime(clock_id, &mut ts) };
+    if result != 0 {
+        return None;
+    }
+    Some(Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32).as_nanos() as i64)
+}
+
+/// Set by SIGUSR2, which asks for the memory usage of samply's buffers to be
+/// printed.
+static MEMORY_REPORT_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_memory_report(_signal: libc::c_int) {
+    MEMORY_REPORT_REQUESTED.store(true, Ordering::Relaxed);
+}
+
+fn install_memory_report_signal_handler() {
+    unsafe {
+        libc::signal(
+            libc::SIGUSR2,
+            request_memory_report as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        );
+    }
+}
+
+/// Sample both clocks back-to-back to find the offset that maps CLOCK_REALTIME
+/// timestamps onto the CLOCK_MONOTONIC timeline used by perf events.
+fn realtime_to_monotonic_offset_ns() -> Option<i64> {
+    let realtime = clock_ns(libc::CLOCK_REALTIME)?;
+    let monotonic = clock_ns(libc::CLOCK_MONOTONIC)?;
+    Some(monotonic - realtime)
+}
+
+#[allow(clippy::too_many_arguments)]
 fn init_profiler(
     interval: Duration,
+    sample_stacks: bool,
+    inherit: bool,
+    hw_counters: Vec<HwCounter>,
+    read_counters: Vec<HwCounter>,
+    cpu_clock_source: Option<ClockSource>,
     pid: u32,
     attach_mode: AttachMode,
     converter: &mut Converter<
@@ -424,15 +673,27 @@ fn init_profiler(
     };
 
     let frequency = (1_000_000_000 / interval_nanos) as u32;
-    let stack_size = 32000;
-    let regs_mask = ConvertRegsNative::regs_mask();
+    // Without the user stack and registers there is nothing to unwind, and
+    // samples end up with empty stacks.
+    let (stack_size, regs_mask) = if sample_stacks {
+        (32000, ConvertRegsNative::regs_mask())
+    } else {
+        (0, 0)
+    };
 
+    let event_source = match cpu_clock_source {
+        Some(ClockSource::SoftwareClock) => EventSource::SwCpuClock,
+        Some(ClockSource::HardwareCycles) | None => EventSource::HwCpuCycles,
+    };
     let perf = PerfGroup::open(
         pid,
         frequency,
         stack_size,
-        EventSource::HwCpuCycles,
+        event_source,
         regs_mask,
+        inherit,
+        hw_counters.clone(),
+        read_counters.clone(),
         attach_mode,
     );
 
@@ -457,6 +718,10 @@ fn init_profiler(
 
     let mut perf = match perf {
         Ok(perf) => perf,
+        Err(error) if event_source == EventSource::SwCpuClock => {
+            eprintln!("Failed to start profiling: {error}");
+            std::process::exit(1);
+        }
         Err(_) => {
             // We've already checked for permission denied due to paranoia
             // level, and exited with a warning in that case.
@@ -464,12 +729,20 @@ fn init_profiler(
             // Another reason for the error could be the type of perf event:
             // The "Hardware CPU cycles" event is not supported in some contexts, for example in VMs.
             // Try a different event type.
+            if cpu_clock_source == Some(ClockSource::HardwareCycles) {
+                eprintln!(
+                    "Warning: Hardware CPU cycles aren't available, sampling with the software CPU clock instead."
+                );
+            }
             let perf = PerfGroup::open(
                 pid,
                 frequency,
                 stack_size,
                 EventSource::SwCpuClock,
                 regs_mask,
+                inherit,
+                hw_counters,
+                read_counters,
                 attach_mode,
             );
             match perf {
@@ -482,6 +755,8 @@ fn init_profiler(
         }
     };
 
+    converter.set_read_counters(perf.read_counters().to_vec());
+
     let (exe_name, cmdline) = get_process_cmdline(pid).expect("Couldn't read process cmdline");
     let comm_data = std::fs::read(format!("/proc/{pid}/comm")).expect("Couldn't read process comm");
     let length = memchr::memchr(b'\0', &comm_data).unwrap_or(comm_data.len());
@@ -504,8 +779,16 @@ fn init_profiler(
         }
     }
 
-    let maps = read_string_lossy(format!("/proc/{pid}/maps")).expect("couldn't read proc maps");
-    let maps = proc_maps::parse(&maps);
+    // Seed the mappings which exist at attach time, before any PERF_RECORD_MMAP
+    // events arrive, so that addresses in libraries which were loaded earlier
+    // can be resolved.
+    let maps = proc_maps::read(pid).unwrap_or_else(|e| {
+        eprintln!(
+            "Warning: Could not read the memory mappings of process {pid}: {e}. \
+             Libraries which were loaded before profiling started will be missing."
+        );
+        Vec::new()
+    });
 
     let vdso_file_id = VdsoObject::shared_instance_for_this_process()
         .map(|vdso| Mmap2FileId::BuildId(vdso.build_id().to_owned()));
@@ -579,15 +862,24 @@ fn run_profiler(
         framehop::UnwinderNative<MmapRangeOrVec, framehop::MayAllocateDuringUnwind>,
     >,
     output_filename: &Path,
+    reuse_profile: bool,
+    sample_weight_unit: SampleWeightUnit,
     _time_limit: Option<Duration>,
     more_processes_request_receiver: Receiver<SamplerRequest>,
     more_processes_reply_sender: Sender<bool>,
     mut stop_receiver: oneshot::Receiver<()>,
     unstable_presymbolicate: bool,
+    print_time_report: bool,
+    validate_schemas: Option<SchemaValidation>,
+    mut io_poller: Option<ProcIoPoller>,
+    mut annotation_fifo: Option<AnnotationFifo>,
+    mut marker_socket: Option<MarkerSocket>,
     mut initial_exec_name_and_cmdline: Option<(String, Vec<String>)>,
 ) {
     // eprintln!("Running...");
 
+    install_memory_report_signal_handler();
+    let mut memory_usage_monitor = MemoryUsageMonitor::new();
     let mut should_stop_profiling_once_perf_events_exhausted = false;
     let mut pending_lost_events = 0;
     let mut total_lost_events = 0;
@@ -601,6 +893,9 @@ fn run_profiler(
             Ok(SamplerRequest::StartProfilingAnotherProcess(another_pid, attach_mode)) => {
                 match perf.open_process(another_pid, attach_mode) {
                     Ok(_) => {
+                        if let Some(io_poller) = &mut io_poller {
+                            io_poller.add_pid(another_pid);
+                        }
                         more_processes_reply_sender.send(true).unwrap();
                     }
                     Err(error) => {
@@ -622,6 +917,9 @@ fn run_profiler(
                 Ok(SamplerRequest::StartProfilingAnotherProcess(another_pid, attach_mode)) => {
                     match perf.open_process(another_pid, attach_mode) {
                         Ok(_) => {
+                            if let Some(io_poller) = &mut io_poller {
+                                io_poller.add_pid(another_pid);
+                            }
                             more_processes_reply_sender.send(true).unwrap();
                         }
                         Err(error) => {
@@ -659,7 +957,17 @@ fn run_profiler(
 
             match parsed_record {
                 EventRecord::Sample(e) => {
-                    converter.handle_main_event_sample::<ConvertRegsNative>(&e);
+                    if let EventSource::HwCounter(counter) = event_ref.event_source() {
+                        converter.handle_hw_counter_sample(counter, &e);
+                    } else {
+                        converter.handle_main_event_sample::<ConvertRegsNative>(&e);
+                        converter.handle_group_read(
+                            event_ref.event_source().name(),
+                            &e,
+                            record.data,
+                            &record.parse_info,
+                        );
+                    }
                     /*
                     } else if interpretation.sched_switch_attr_index == Some(attr_index) {
                         converter.handle_sched_switch_sample::<C>(e);
@@ -721,6 +1029,38 @@ fn run_profiler(
             }
         });
 
+        if let Some(io_poller) = &mut io_poller {
+            if io_poller.is_due() {
+                if let Some(now) = clock_ns(libc::CLOCK_MONOTONIC) {
+                    io_poller.poll(now as u64, &mut converter);
+                }
+            }
+        }
+
+        if let Some(annotation_fifo) = &mut annotation_fifo {
+            if let Some(now) = clock_ns(libc::CLOCK_MONOTONIC) {
+                annotation_fifo.poll(now as u64, &mut converter);
+            }
+        }
+
+        if let Some(marker_socket) = &mut marker_socket {
+            marker_socket.poll(&mut converter);
+        }
+
+        if let Some(now) = clock_ns(libc::CLOCK_MONOTONIC) {
+            converter.apply_span_gate(now as u64);
+        }
+
+        converter.checkpoint_spill_manifest();
+
+        let memory_report_requested = MEMORY_REPORT_REQUESTED.swap(false, Ordering::Relaxed);
+        if memory_report_requested || memory_usage_monitor.is_due() {
+            memory_usage_monitor.record(converter.memory_report());
+        }
+        if memory_report_requested {
+            eprintln!("{}", memory_usage_monitor.latest());
+        }
+
         perf.wait();
     }
 
@@ -728,9 +1068,27 @@ fn run_profiler(
         eprintln!("Lost {total_lost_events} events.");
     }
 
-    let profile = converter.finish();
+    memory_usage_monitor.record(converter.memory_report());
+    let (mut profile, mut time_report) = converter.finish();
+    embed_sample_weight_unit(&mut profile, sample_weight_unit);
+    embed_memory_report(&mut profile, memory_usage_monitor.peak());
+    if let Some(validation) = validate_schemas {
+        if !check_marker_schemas(&profile, validation) {
+            std::process::exit(1);
+        }
+    }
 
-    save_profile_to_file(&profile, output_filename).expect("Couldn't write JSON");
+    time_report.time("Saving profile", || {
+        if reuse_profile {
+            append_profile_to_file(&profile, output_filename).expect("Couldn't write JSON")
+        } else {
+            save_profile_to_file(&profile, output_filename).expect("Couldn't write JSON")
+        }
+    });
+    if print_time_report {
+        time_report.print();
+        eprintln!("Peak {}", memory_usage_monitor.peak());
+    }
 
     if unstable_presymbolicate {
         crate::shared::symbol_precog::presymbolicate(
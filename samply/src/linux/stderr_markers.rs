@@ -0,0 +1,192 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::os::fd::OwnedFd;
+use std::sync::{Arc, Mutex};
+
+use fxprof_processed_profile::{
+    CategoryHandle, MarkerFieldFormat, MarkerFieldSchema, MarkerLocation, MarkerSchema,
+    MarkerTiming, Profile, StaticSchemaMarker, StringHandle,
+};
+
+use super::profiler::clock_ns;
+use crate::shared::custom_markers::{CustomMarkerProcess, CustomMarkerSource};
+use crate::shared::timestamp_converter::{ClockDomain, TimestampConverter};
+
+/// A line which a launched command wrote to stderr.
+struct StderrLine {
+    pid: i32,
+    timestamp_mono: u64,
+    line: String,
+}
+
+/// Turns the lines which the launched command writes to stderr into "Stderr"
+/// markers on its main thread, for `--stderr-markers`.
+///
+/// The command's stderr is redirected to a pipe, and a thread passes each
+/// line on to samply's own stderr and notes when it arrived. The markers are
+/// added when the process is finished; lines which arrive after that are
+/// still printed, but don't become markers.
+#[derive(Default)]
+pub struct StderrMarkers {
+    lines: Arc<Mutex<Vec<StderrLine>>>,
+}
+
+impl StderrMarkers {
+    /// Creates a pipe for the stderr of a command which is about to be
+    /// launched, and returns its read end and its write end.
+    pub fn pipe() -> std::io::Result<(OwnedFd, OwnedFd)> {
+        Ok(nix::unistd::pipe2(nix::fcntl::OFlag::O_CLOEXEC)?)
+    }
+
+    /// Reads the lines of process `pid` from `read_end` until all writers
+    /// have closed the pipe, in a thread of its own.
+    pub fn capture(&self, pid: u32, read_end: OwnedFd) {
+        let lines = self.lines.clone();
+        std::thread::spawn(move || {
+            let mut reader = BufReader::new(File::from(read_end));
+            let mut buf = Vec::new();
+            loop {
+                buf.clear();
+                match reader.read_until(b'\n', &mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => {}
+                }
+                let _ = std::io::stderr().write_all(&buf);
+                let Some(now) = clock_ns(libc::CLOCK_MONOTONIC) else {
+                    continue;
+                };
+                let line = String::from_utf8_lossy(&buf);
+                let line = line.trim_end_matches(['\n', '\r']);
+                if line.is_empty() {
+                    continue;
+                }
+                lines.lock().unwrap().push(StderrLine {
+                    pid: pid as i32,
+                    timestamp_mono: now as u64,
+                    line: line.to_string(),
+                });
+            }
+        });
+    }
+}
+
+impl CustomMarkerSource for StderrMarkers {
+    fn add_markers(
+        &self,
+        profile: &mut Profile,
+        process: &CustomMarkerProcess,
+        timestamp_converter: &TimestampConverter,
+    ) {
+        let mut lines = self.lines.lock().unwrap();
+        let (own_lines, other_lines): (Vec<_>, Vec<_>) = std::mem::take(&mut *lines)
+            .into_iter()
+            .partition(|line| line.pid == process.pid);
+        *lines = other_lines;
+        drop(lines);
+        for stderr_line in own_lines {
+            let timestamp = timestamp_converter
+                .convert_time_from_domain(stderr_line.timestamp_mono, ClockDomain::Monotonic);
+            let message = profile.intern_string(&stderr_line.line);
+            profile.add_marker(
+                process.main_thread,
+                MarkerTiming::Instant(timestamp),
+                StderrLineMarker(message),
+            );
+        }
+    }
+}
+
+/// A line which the launched command wrote to stderr, see `--stderr-markers`.
+#[derive(Debug, Clone)]
+pub struct StderrLineMarker(pub StringHandle);
+
+impl StaticSchemaMarker for StderrLineMarker {
+    const UNIQUE_MARKER_TYPE_NAME: &'static str = "Stderr";
+
+    fn schema() -> MarkerSchema {
+        MarkerSchema {
+            type_name: Self::UNIQUE_MARKER_TYPE_NAME.into(),
+            locations: vec![MarkerLocation::MarkerChart, MarkerLocation::MarkerTable],
+            chart_label: Some("{marker.data.message}".into()),
+            tooltip_label: Some("{marker.data.message}".into()),
+            table_label: Some("{marker.data.message}".into()),
+            fields: vec![MarkerFieldSchema {
+                key: "message".into(),
+                label: "Message".into(),
+                format: MarkerFieldFormat::String,
+                searchable: true,
+            }],
+            static_fields: vec![],
+            graphs: vec![],
+        }
+    }
+
+    fn name(&self, profile: &mut Profile) -> StringHandle {
+        profile.intern_string("Stderr")
+    }
+
+    fn category(&self, _profile: &mut Profile) -> CategoryHandle {
+        CategoryHandle::OTHER
+    }
+
+    fn string_field_value(&self, _field_index: u32) -> StringHandle {
+        self.0
+    }
+
+    fn number_field_value(&self, _field_index: u32) -> f64 {
+        unreachable!()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use fxprof_processed_profile::Timestamp;
+
+    use super::*;
+    use crate::shared::test_support::{identity_converter, new_profile};
+
+    #[test]
+    fn stderr_lines_go_on_the_main_thread_of_their_process() {
+        let mut profile = new_profile();
+        let start = Timestamp::from_millis_since_reference(0.0);
+        let process = profile.add_process("test", 10, start);
+        let main_thread = profile.add_thread(process, 10, start, true);
+        let other_thread = profile.add_thread(process, 11, start, false);
+        let custom_marker_process = CustomMarkerProcess {
+            pid: 10,
+            process,
+            main_thread,
+            threads: vec![(10, main_thread), (11, other_thread)],
+        };
+        let stderr_markers = StderrMarkers::default();
+        stderr_markers.lines.lock().unwrap().extend([
+            StderrLine {
+                pid: 10,
+                timestamp_mono: 1_000_000,
+                line: "warning: cache miss".to_string(),
+            },
+            StderrLine {
+                pid: 20,
+                timestamp_mono: 2_000_000,
+                line: "from the next iteration".to_string(),
+            },
+        ]);
+        stderr_markers.add_markers(&mut profile, &custom_marker_process, &identity_converter());
+
+        // The line of the other process stays around for that process.
+        assert_eq!(stderr_markers.lines.lock().unwrap().len(), 1);
+        let json = serde_json::to_value(&profile).unwrap();
+        let threads = json["threads"].as_array().unwrap();
+        let (main, other): (Vec<_>, Vec<_>) = threads
+            .iter()
+            .partition(|thread| thread["isMainThread"] == true);
+        assert_eq!(other[0]["markers"]["length"], 0);
+        let markers = &main[0]["markers"];
+        assert_eq!(markers["length"], 1);
+        assert_eq!(markers["startTime"][0], 1.0);
+        assert_eq!(
+            markers["data"][0]["type"],
+            StderrLineMarker::UNIQUE_MARKER_TYPE_NAME
+        );
+    }
+}
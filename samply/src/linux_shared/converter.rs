@@ -2,6 +2,7 @@ use std::collections::HashMap;
 use std::ops::Range;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use byteorder::LittleEndian;
 use debugid::DebugId;
@@ -20,8 +21,8 @@ use linux_perf_data::{
 };
 use linux_perf_event_reader::constants::PERF_CONTEXT_MAX;
 use linux_perf_event_reader::{
-    CommOrExecRecord, CommonData, ContextSwitchRecord, ForkOrExitRecord, Mmap2FileId, Mmap2Record,
-    MmapRecord, RawDataU64, SampleRecord,
+    CommOrExecRecord, CommonData, ContextSwitchRecord, CpuMode, ForkOrExitRecord, Mmap2FileId,
+    Mmap2Record, MmapRecord, RawData, RawDataU64, RecordParseInfo, SampleRecord,
 };
 use memmap2::Mmap;
 use object::{CompressedFileRange, CompressionFormat, Object, ObjectSection};
@@ -32,29 +33,37 @@ use wholesym::{samply_symbols, CodeId, ElfBuildId};
 use super::avma_range::AvmaRange;
 use super::convert_regs::ConvertRegs;
 use super::event_interpretation::{EventInterpretation, OffCpuIndicator};
+use super::group_read::{GroupRead, GroupReadCounters};
 use super::injected_jit_object::{correct_bad_perf_jit_so_file, jit_function_name};
 use super::kernel_symbols::{kernel_module_build_id, KernelSymbols};
 use super::mmap_range_or_vec::MmapRangeOrVec;
 use super::pe_mappings::{PeMappings, SuspectedPeMapping};
+use super::proc_io::{rate_mb_per_s, ProcIo};
 use super::processes::Processes;
 use super::rss_stat::{RssStat, MM_ANONPAGES, MM_FILEPAGES, MM_SHMEMPAGES, MM_SWAPENTS};
+use super::sched_switch::{SchedSwitch, SchedWaking};
 use super::svma_file_range::compute_vma_bias;
 use super::vdso::VdsoObject;
 use crate::shared::context_switch::{ContextSwitchHandler, OffCpuSampleGroup};
 use crate::shared::jit_category_manager::JitCategoryManager;
 use crate::shared::lib_mappings::{AndroidArtInfo, LibMappingInfo};
-use crate::shared::per_cpu::Cpus;
+use crate::shared::log_markers;
+use crate::shared::memory_usage::{MemoryReport, MemoryUsage};
+use crate::shared::per_cpu::{CpuTopology, Cpus, InterruptTracker};
 use crate::shared::process_name::make_process_name;
 use crate::shared::process_sample_data::{
-    OtherEventMarker, RssStatMarker, RssStatMember, SchedSwitchMarkerOnCpuTrack,
-    SchedSwitchMarkerOnThreadTrack,
+    AnnotationMarker, CrossProcessEdge, HwCounterMarker, OtherEventMarker, RssStatMarker,
+    RssStatMember, SchedSwitchDetails, SchedSwitchMarkerOnCpuTrack, SchedSwitchMarkerOnThreadTrack,
 };
-use crate::shared::recording_props::ProfileCreationProps;
+use crate::shared::recording_props::{HwCounter, ProfileCreationProps};
+use crate::shared::span_recording_gate::SpanRecordingGate;
+use crate::shared::spill_recovery::SpillManifest;
 use crate::shared::synthetic_jit_library::SyntheticJitLibrary;
-use crate::shared::timestamp_converter::TimestampConverter;
+use crate::shared::time_report::TimeReport;
+use crate::shared::timestamp_converter::{ClockDomain, TimestampConverter};
 use crate::shared::types::{StackFrame, StackMode};
 use crate::shared::unresolved_samples::{
-    UnresolvedSamples, UnresolvedStackHandle, UnresolvedStacks,
+    SampleCallback, UnresolvedSamples, UnresolvedStackHandle, UnresolvedStacks,
 };
 use crate::shared::utils::open_file_with_fallback;
 
@@ -88,6 +97,9 @@ where
     jit_category_manager: JitCategoryManager,
     arg_count_to_include_in_process_name: usize,
     cpus: Option<Cpus>,
+    /// Created once the kernel image has a symbol table, if there are
+    /// per-CPU tracks.
+    interrupt_tracker: Option<InterruptTracker>,
 
     /// Whether repeated frames at the base of the stack should be folded
     /// into one frame.
@@ -111,11 +123,32 @@ where
     /// Whether to emit context switch markers.
     should_emit_cswitch_markers: bool,
 
+    /// Whether wakeups of threads in other processes become cross-process
+    /// edges, see `handle_sched_waking_sample`.
+    ipc_edges: bool,
+
+    /// Whether to emit one marker per run of a thread on the per-CPU tracks,
+    /// instead of the context switch markers.
+    should_emit_cpu_run_markers: bool,
+
     // Whether to emit mmap markers.
     should_emit_mmap_markers: bool,
 
     // Whether to attach markers to the profiled thread rather than the main thread.
     should_attach_markers_to_profiled_thread: bool,
+
+    /// When the converter was created, i.e. when event processing started.
+    created_at: Instant,
+
+    /// The profile's reference timestamp and interval, for the spill manifest.
+    reference_timestamp: ReferenceTimestamp,
+    interval: SamplingInterval,
+
+    /// Describes the spill files for `samply recover`, see `spill_recovery`.
+    spill_manifest: Option<SpillManifest>,
+
+    /// The `--read-counters` events which the samples carry the values of.
+    read_counters: Vec<HwCounter>,
 }
 
 const DEFAULT_OFF_CPU_SAMPLING_INTERVAL_NS: u64 = 1_000_000; // 1ms
@@ -144,6 +177,9 @@ where
             Some(nanos) => SamplingInterval::from_nanos(nanos),
             None => SamplingInterval::from_millis(1),
         };
+        let reference_realtime_ns =
+            (reference_timestamp.as_millis_since_unix_epoch() * 1_000_000.0) as u64;
+        let reference_timestamp = profile_creation_props.reference_timestamp(reference_timestamp);
         let mut profile = Profile::new(profile_name, reference_timestamp, interval);
         if let Some(linux_version) = linux_version {
             profile.set_os_name(&format!("Linux {linux_version}"));
@@ -234,14 +270,22 @@ where
             }
         }
 
-        let timestamp_converter = TimestampConverter {
+        let mut timestamp_converter = TimestampConverter {
             reference_raw: first_sample_time,
             raw_to_ns_factor: 1,
+            domain: ClockDomain::Monotonic,
+            realtime_to_monotonic_offset_ns: None,
         };
+        timestamp_converter.register_realtime_reference(reference_realtime_ns);
 
         let cpus = if profile_creation_props.create_per_cpu_threads {
             let start_timestamp = timestamp_converter.convert_time(first_sample_time);
-            Some(Cpus::new(start_timestamp, &mut profile))
+            let mut cpus = Cpus::new(start_timestamp, &mut profile);
+            cpus.set_track_naming(profile_creation_props.synthetic_track_naming.clone());
+            if let Some(min_run_duration) = profile_creation_props.cpu_run_intervals {
+                cpus.set_min_run_duration(min_run_duration.as_nanos() as u64);
+            }
+            Some(cpus)
         } else {
             None
         };
@@ -249,11 +293,7 @@ where
         Self {
             profile,
             cache,
-            processes: Processes::new(
-                profile_creation_props.reuse_threads,
-                profile_creation_props.unlink_aux_files,
-                profile_creation_props.should_emit_jit_markers,
-            ),
+            processes: Processes::new(profile_creation_props),
             timestamp_converter,
             current_sample_time: first_sample_time,
             build_ids,
@@ -274,31 +314,62 @@ where
             simpleperf_symbol_tables_kernel_modules,
             simpleperf_jit_app_cache_library,
             pe_mappings: PeMappings::new(),
-            jit_category_manager: JitCategoryManager::new(),
+            jit_category_manager: {
+                let mut jit_category_manager = JitCategoryManager::new();
+                jit_category_manager.add_v8_tiered_categories();
+                jit_category_manager
+            },
             fold_recursive_prefix: profile_creation_props.fold_recursive_prefix,
             arg_count_to_include_in_process_name: profile_creation_props
                 .arg_count_to_include_in_process_name,
             cpus,
+            interrupt_tracker: None,
             call_chain_return_addresses_are_preadjusted,
             should_emit_jit_markers: profile_creation_props.should_emit_jit_markers,
             should_emit_cswitch_markers: profile_creation_props.should_emit_cswitch_markers,
+            ipc_edges: profile_creation_props.ipc_edges,
+            should_emit_cpu_run_markers: profile_creation_props.cpu_run_intervals.is_some(),
             should_emit_mmap_markers: profile_creation_props.should_emit_mmap_markers,
             should_attach_markers_to_profiled_thread: profile_creation_props
                 .attach_markers_to_profiled_thread,
+            created_at: Instant::now(),
+            reference_timestamp,
+            interval,
+            spill_manifest: None,
+            read_counters: Vec::new(),
         }
     }
 
-    pub fn finish(mut self) -> Profile {
+    /// Finish the profile, and return it together with the time spent in
+    /// each phase of the conversion so far.
+    pub fn finish(mut self) -> (Profile, TimeReport) {
+        let mut time_report = TimeReport::new();
+        time_report.record("Processing events", self.created_at.elapsed());
         let mut profile = self.profile;
+        if let Some(cpus) = &mut self.cpus {
+            cpus.finish_runs(&self.timestamp_converter, &mut profile);
+            if let Some(interrupt_tracker) = &self.interrupt_tracker {
+                interrupt_tracker.embed_sample_counts(cpus.cpu_count(), &mut profile);
+            }
+        }
         self.simpleperf_jit_app_cache_library
             .finish_and_set_symbol_table(&mut profile);
+        let cpu_threads = self
+            .cpus
+            .as_ref()
+            .map(Cpus::thread_handles)
+            .unwrap_or_default();
+        self.processes
+            .add_log_markers(log_markers::take_markers(), &mut profile);
         self.processes.finish(
             &mut profile,
+            &cpu_threads,
             &self.unresolved_stacks,
             &mut self.jit_category_manager,
             &self.timestamp_converter,
+            &mut time_report,
         );
-        profile
+        (profile, time_report)
     }
 
     pub fn set_profile_name(&mut self, profile_name: &str) {
@@ -309,6 +380,164 @@ where
         self.profile.set_os_name(os_name);
     }
 
+    /// Call `sample_callback` for every sample as it is recorded, see
+    /// `RecordingProps::sample_callback`.
+    pub fn set_sample_callback(&mut self, sample_callback: Option<SampleCallback>) {
+        self.processes.set_sample_callback(sample_callback);
+    }
+
+    /// Spill the oldest samples of processes with many samples to files in
+    /// `spill_dir`, see `RecordingProps::spill_dir`.
+    pub fn set_spill_dir(&mut self, spill_dir: Option<PathBuf>) {
+        self.spill_manifest = spill_dir.as_deref().and_then(|dir| {
+            SpillManifest::create(dir, self.reference_timestamp, self.interval)
+                .map_err(|e| eprintln!("Couldn't create a manifest in {}: {e}", dir.display()))
+                .ok()
+        });
+        self.processes.set_spill_dir(spill_dir);
+    }
+
+    /// Keep the warnings which samply logs from now on, and add them as
+    /// markers on a "samply" track when the profile is finished.
+    pub fn set_log_markers(&mut self, log_markers: bool) {
+        if log_markers {
+            log_markers::start_collecting(self.timestamp_converter);
+        }
+    }
+
+    /// The approximate sizes of the buffers which grow during a recording.
+    pub fn memory_report(&self) -> MemoryReport {
+        let mut report = MemoryReport::default();
+        report.add("Unresolved stacks", self.unresolved_stacks.heap_size());
+        self.processes.add_memory_usage(&mut report);
+        report
+    }
+
+    /// Writes what `samply recover` needs in order to read the spill files
+    /// to the manifest in the spill directory, if a checkpoint is due.
+    pub fn checkpoint_spill_manifest(&mut self) {
+        let Some(manifest) = &mut self.spill_manifest else {
+            return;
+        };
+        if !manifest.is_due() {
+            return;
+        }
+        let records = self
+            .processes
+            .iter_mut()
+            .flat_map(|process| process.new_manifest_records(&self.profile))
+            .collect();
+        if let Err(e) = manifest.checkpoint(records, &self.unresolved_stacks) {
+            eprintln!("Couldn't write the spill manifest, samply recover won't work: {e}");
+            self.spill_manifest = None;
+        }
+    }
+
+    /// Name the per-CPU tracks after the physical cores in `topology`.
+    pub fn set_cpu_topology(&mut self, topology: CpuTopology) {
+        if let Some(cpus) = &mut self.cpus {
+            cpus.set_topology(topology);
+        }
+    }
+
+    /// Register the offset between CLOCK_REALTIME and CLOCK_MONOTONIC, so that
+    /// marker and counter files with realtime timestamps line up with the samples.
+    pub fn register_realtime_offset(&mut self, realtime_to_monotonic_offset_ns: i64) {
+        self.timestamp_converter
+            .register_realtime_offset(realtime_to_monotonic_offset_ns);
+    }
+
+    /// Adds a sample of a `--hw-counter` event to the process's counter
+    /// track for it. The sample has no stack of its own; it gets a marker
+    /// with the stack of the thread's most recent CPU sample.
+    pub fn handle_hw_counter_sample(&mut self, counter: HwCounter, e: &SampleRecord) {
+        let (Some(pid), Some(tid), Some(timestamp_mono)) = (e.pid, e.tid, e.timestamp) else {
+            return;
+        };
+        if tid == 0 {
+            return;
+        }
+        let count = e.period.unwrap_or_else(|| counter.sample_period());
+        let timestamp = self.timestamp_converter.convert_time(timestamp_mono);
+        let process = self.processes.get_by_pid(pid, &mut self.profile);
+        let counter_handle = process.get_or_make_hw_counter(counter, &mut self.profile);
+        self.profile
+            .add_counter_sample(counter_handle, timestamp, count as f64, 1);
+
+        let thread_handle = process
+            .threads
+            .get_thread_by_tid(tid, &mut self.profile)
+            .profile_thread;
+        let Some(stack) = process.unresolved_samples.last_sample_stack(thread_handle) else {
+            return;
+        };
+        let name = self.profile.intern_string(counter.as_str());
+        let marker_handle = self.profile.add_marker(
+            thread_handle,
+            MarkerTiming::Instant(timestamp),
+            HwCounterMarker { name, count },
+        );
+        process.unresolved_samples.attach_stack_to_marker(
+            thread_handle,
+            timestamp,
+            timestamp_mono,
+            stack,
+            marker_handle,
+        );
+    }
+
+    /// Sets the `--read-counters` events which were opened in the group of
+    /// each sampling event, see `handle_group_read`.
+    pub fn set_read_counters(&mut self, read_counters: Vec<HwCounter>) {
+        self.read_counters = read_counters;
+    }
+
+    /// Adds the counter values which a sample of the sampling event `leader`
+    /// carries to the per-thread `--read-counters` tracks. `data` is the raw
+    /// sample record.
+    pub fn handle_group_read(
+        &mut self,
+        leader: &'static str,
+        e: &SampleRecord,
+        data: RawData,
+        parse_info: &RecordParseInfo,
+    ) {
+        if self.read_counters.is_empty() {
+            return;
+        }
+        let (Some(pid), Some(tid), Some(timestamp_mono)) = (e.pid, e.tid, e.timestamp) else {
+            return;
+        };
+        if tid == 0 {
+            return;
+        }
+        let read = match GroupRead::parse(
+            data,
+            parse_info.sample_format,
+            parse_info.read_format,
+            parse_info.endian,
+        ) {
+            Ok(Some(read)) => read,
+            Ok(None) | Err(_) => return,
+        };
+        let timestamp = self.timestamp_converter.convert_time(timestamp_mono);
+        let process = self.processes.get_by_pid(pid, &mut self.profile);
+        let thread_handle = process
+            .threads
+            .get_thread_by_tid(tid, &mut self.profile)
+            .profile_thread;
+        let read_counters = &self.read_counters;
+        process
+            .group_read_counters
+            .get_or_insert_with(|| {
+                let names = std::iter::once(leader)
+                    .chain(read_counters.iter().map(|counter| counter.as_str()))
+                    .collect();
+                GroupReadCounters::new(names)
+            })
+            .add_read(tid, thread_handle, timestamp, read);
+    }
+
     pub fn handle_main_event_sample<C: ConvertRegs<UnwindRegs = U::UnwindRegs>>(
         &mut self,
         e: &SampleRecord,
@@ -436,6 +665,18 @@ where
                 1,
                 Some(thread.thread_label_frame.clone()),
             );
+
+            if let (CpuMode::Kernel, Some(interrupt_tracker)) =
+                (e.cpu_mode, &mut self.interrupt_tracker)
+            {
+                interrupt_tracker.handle_kernel_sample(
+                    cpu_index,
+                    &stack,
+                    profile_timestamp,
+                    cpus,
+                    &mut self.profile,
+                );
+            }
         }
     }
 
@@ -483,22 +724,41 @@ where
             let stack_index = self.unresolved_stacks.convert(stack.iter().rev().cloned());
             let cpu = cpus.get_mut(cpu_index as usize, &mut self.profile);
             let timestamp = self.timestamp_converter.convert_time(timestamp_mono);
-            let marker_handle = self.profile.add_marker(
-                cpu.thread_handle,
-                MarkerTiming::Instant(timestamp),
-                SchedSwitchMarkerOnCpuTrack,
-            );
-            process.unresolved_samples.attach_stack_to_marker(
-                cpu.thread_handle,
-                timestamp,
-                timestamp_mono,
-                stack_index,
-                marker_handle,
-            );
+            let sched_switch = e
+                .raw
+                .and_then(|raw| SchedSwitch::parse(raw, self.endian).ok());
+            let cpu_thread_handle = cpu.thread_handle;
+            let details = SchedSwitchDetails {
+                reason: self.profile.intern_string(
+                    sched_switch
+                        .as_ref()
+                        .map_or("unknown", SchedSwitch::prev_state_reason),
+                ),
+                prev_prio: sched_switch.as_ref().map(|s| s.prev_prio),
+                next_prio: sched_switch.as_ref().map(|s| s.next_prio),
+            };
+            // With CPU run markers, the CPU tracks only show the runs.
+            if !self.should_emit_cpu_run_markers {
+                let marker_handle = self.profile.add_marker(
+                    cpu_thread_handle,
+                    MarkerTiming::Instant(timestamp),
+                    SchedSwitchMarkerOnCpuTrack(details.clone()),
+                );
+                process.unresolved_samples.attach_stack_to_marker(
+                    cpu_thread_handle,
+                    timestamp,
+                    timestamp_mono,
+                    stack_index,
+                    marker_handle,
+                );
+            }
             let marker_handle = self.profile.add_marker(
                 thread.profile_thread,
                 MarkerTiming::Instant(timestamp),
-                SchedSwitchMarkerOnThreadTrack { cpu: cpu_index },
+                SchedSwitchMarkerOnThreadTrack {
+                    cpu: cpu_index,
+                    details,
+                },
             );
             process.unresolved_samples.attach_stack_to_marker(
                 thread.profile_thread,
@@ -510,6 +770,65 @@ where
         }
     }
 
+    /// With `--ipc-edges`, a sched_waking sample of a thread which wakes up
+    /// a thread of another process becomes a cross-process edge from the
+    /// waker's stack to the stack which the woken thread went to sleep in,
+    /// i.e. its last sched_switch stack. Other sched_waking samples are
+    /// event markers like any other event.
+    pub fn handle_sched_waking_sample<C: ConvertRegs<UnwindRegs = U::UnwindRegs>>(
+        &mut self,
+        e: &SampleRecord,
+        attr_index: usize,
+    ) {
+        let woken = e
+            .raw
+            .filter(|_| self.ipc_edges)
+            .and_then(|raw| SchedWaking::parse(raw, self.endian).ok())
+            .and_then(|waking| self.processes.find_thread_by_tid(waking.tid));
+        let pid = e.pid.expect("Can't handle samples without pids");
+        let (to_pid, to_thread, to_stack) = match woken {
+            Some((to_pid, to_thread, Some(to_stack))) if to_pid != pid => {
+                (to_pid, to_thread, to_stack)
+            }
+            _ => {
+                self.handle_other_event_sample::<C>(e, attr_index);
+                return;
+            }
+        };
+        let tid = e.tid.expect("Can't handle samples without tids");
+        let timestamp_mono = e
+            .timestamp
+            .expect("Can't handle samples without timestamps");
+        let process = self.processes.get_by_pid(pid, &mut self.profile);
+        let mut stack = Vec::new();
+        Self::get_sample_stack::<C>(
+            e,
+            &process.unwinder,
+            &mut self.cache,
+            &mut stack,
+            self.fold_recursive_prefix,
+            self.call_chain_return_addresses_are_preadjusted,
+        );
+        let from_stack = self.unresolved_stacks.convert(stack.into_iter().rev());
+        let from_thread = process
+            .threads
+            .get_thread_by_tid(tid, &mut self.profile)
+            .profile_thread;
+        let edge = CrossProcessEdge {
+            from_pid: pid as u32,
+            from_thread,
+            from_stack,
+            to_pid: to_pid as u32,
+            to_thread,
+            to_stack,
+            timestamp: self.timestamp_converter.convert_time(timestamp_mono),
+        };
+        process.add_cross_process_edge(edge.clone());
+        if let Some(to_process) = self.processes.get_existing_by_pid(to_pid) {
+            to_process.add_cross_process_edge(edge);
+        }
+    }
+
     pub fn handle_rss_stat_sample<C: ConvertRegs<UnwindRegs = U::UnwindRegs>>(
         &mut self,
         e: &SampleRecord,
@@ -597,6 +916,76 @@ where
         );
     }
 
+    /// Adds a sample to the process's "Disk reads" and "Disk writes" counters,
+    /// from a reading of `/proc/<pid>/io` taken at `timestamp_mono`. The first
+    /// reading for a process only sets the baseline for the rates.
+    ///
+    /// Readings for processes which haven't been seen in any event yet, or
+    /// which have already exited, are ignored.
+    #[allow(unused)]
+    pub fn handle_proc_io(&mut self, pid: i32, timestamp_mono: u64, io: ProcIo) {
+        let Some(process) = self.processes.get_existing_by_pid(pid) else {
+            return;
+        };
+        let Some((prev_timestamp_mono, prev_io)) = process.prev_io.replace((timestamp_mono, io))
+        else {
+            return;
+        };
+        let interval_ns = timestamp_mono.saturating_sub(prev_timestamp_mono);
+        let (reads_counter, writes_counter) = process.get_or_make_io_counters(&mut self.profile);
+        let timestamp = self.timestamp_converter.convert_time(timestamp_mono);
+        let read_rate = rate_mb_per_s(prev_io.read_bytes, io.read_bytes, interval_ns);
+        let write_rate = rate_mb_per_s(prev_io.write_bytes, io.write_bytes, interval_ns);
+        self.profile
+            .add_counter_sample(reads_counter, timestamp, read_rate, 1);
+        self.profile
+            .add_counter_sample(writes_counter, timestamp, write_rate, 1);
+    }
+
+    /// Adds an annotation marker with `message` to the main thread of every
+    /// process, for a line read from the annotation FIFO.
+    pub fn handle_annotation(&mut self, timestamp_mono: u64, message: &str) {
+        let timestamp = self.timestamp_converter.convert_time(timestamp_mono);
+        let message = self.profile.intern_string(message);
+        for thread in self.processes.main_thread_handles() {
+            self.profile.add_marker(
+                thread,
+                MarkerTiming::Instant(timestamp),
+                AnnotationMarker(message),
+            );
+        }
+    }
+
+    /// Processes a line which process `pid` sent to the marker socket. Lines
+    /// from processes which aren't profiled are ignored.
+    pub fn handle_marker_socket_line(&mut self, pid: i32, line: &str) {
+        let raw_marker_lines = self.processes.raw_marker_lines();
+        if let Some(process) = self.processes.get_existing_by_pid(pid) {
+            process.add_marker_socket_line(line, &self.timestamp_converter, raw_marker_lines);
+            self.processes
+                .process_span_gate_line(pid, line, &self.timestamp_converter);
+        }
+    }
+
+    /// Only keep the samples taken while a span called `span_name` was open,
+    /// holding samples back for `lookback` in case the span's marker lines
+    /// arrive late. See `RecordingProps::record_only_during_span`.
+    pub fn set_record_only_during_span(&mut self, span_name: Option<String>, lookback: Duration) {
+        self.processes
+            .set_span_gate(span_name.map(|span_name| SpanRecordingGate::new(span_name, lookback)));
+    }
+
+    /// Drops the samples which are known to be outside of the span of
+    /// `set_record_only_during_span`, as of the raw time `now`.
+    pub fn apply_span_gate(&mut self, now: u64) {
+        self.processes.apply_span_gate(now);
+        // The spill manifest only appends new stacks, so the stacks can't
+        // be renumbered once it has been written.
+        if self.spill_manifest.is_none() {
+            self.processes.gc_stacks_if_due(&mut self.unresolved_stacks);
+        }
+    }
+
     pub fn handle_other_event_sample<C: ConvertRegs<UnwindRegs = U::UnwindRegs>>(
         &mut self,
         e: &SampleRecord,
@@ -904,7 +1293,9 @@ where
             return true;
         }
 
-        if filename.starts_with("counter-") && filename.ends_with(".json") {
+        if filename.starts_with("counter-")
+            && (filename.ends_with(".json") || filename.ends_with(".ndjson"))
+        {
             let counter_file_path = Path::new(path);
             let process = self.processes.get_by_pid(pid, &mut self.profile);
             let thread = process.threads.get_thread_by_tid(tid, &mut self.profile);
@@ -998,7 +1389,17 @@ where
                             Some(idle_frame_label),
                         );
                     }
-                    if self.should_emit_cswitch_markers {
+                    if self.should_emit_cpu_run_markers {
+                        cpu.notify_switch_in_for_run(
+                            pid,
+                            tid,
+                            thread.thread_label(),
+                            timestamp,
+                            &self.timestamp_converter,
+                            &[cpu.thread_handle, combined_thread],
+                            &mut self.profile,
+                        );
+                    } else if self.should_emit_cswitch_markers {
                         cpu.notify_switch_in_for_marker(
                             tid,
                             thread.thread_label(),
@@ -1018,7 +1419,15 @@ where
                     let cpu = cpus.get_mut(cpu_index as usize, &mut self.profile);
                     self.context_switch_handler
                         .handle_switch_out(timestamp, &mut cpu.context_switch_data);
-                    if self.should_emit_cswitch_markers {
+                    if self.should_emit_cpu_run_markers {
+                        cpu.notify_switch_out_for_run(
+                            tid,
+                            timestamp,
+                            &self.timestamp_converter,
+                            &[cpu.thread_handle, combined_thread],
+                            &mut self.profile,
+                        );
+                    } else if self.should_emit_cswitch_markers {
                         cpu.notify_switch_out_for_marker(
                             tid,
                             timestamp,
@@ -1294,6 +1703,12 @@ where
                 .map(|s| s.symbol_table.clone())
         };
 
+        if let (DsoKey::Kernel, Some(_), Some(symbol_table)) = (&dso_key, &self.cpus, &symbol_table)
+        {
+            self.interrupt_tracker =
+                Some(InterruptTracker::new(base_address, symbol_table.clone()));
+        }
+
         let lib_handle = self.profile.add_lib(LibraryInfo {
             debug_id: debug_id.unwrap_or_default(),
             path,
@@ -11,6 +11,7 @@ pub enum KnownEvent {
     MmapExit,
     MprotectEnter,
     PageFault,
+    SchedWaking,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -79,6 +80,7 @@ impl EventInterpretation {
             ("syscalls:sys_enter_mprotect", KnownEvent::MprotectEnter),
             ("syscalls:sys_enter_mmap", KnownEvent::MmapEnter),
             ("syscalls:sys_exit_mmap", KnownEvent::MmapExit),
+            ("sched:sched_waking", KnownEvent::SchedWaking),
         ];
 
         for (event_name, event) in known_events {
@@ -0,0 +1,372 @@
+use std::collections::{BTreeMap, HashMap};
+
+use byteorder::ByteOrder;
+use fxprof_processed_profile::{MarkerGraphType, ThreadHandle, Timestamp};
+use linux_perf_data::{linux_perf_event_reader, Endianness};
+use linux_perf_event_reader::{RawData, ReadFormat, SampleFormat};
+
+use crate::shared::counter_file::{Counter, CounterCategory, CounterSample};
+use crate::shared::process_sample_data::CounterOnThread;
+
+/// The values of a sampling event and of the `--read-counters` events in its
+/// group, which each sample carries with `PERF_SAMPLE_READ` and
+/// `PERF_FORMAT_GROUP`:
+///
+/// ```text
+/// struct read_format {
+///     u64 nr;
+///     u64 time_enabled;  /* if PERF_FORMAT_TOTAL_TIME_ENABLED */
+///     u64 time_running;  /* if PERF_FORMAT_TOTAL_TIME_RUNNING */
+///     struct {
+///         u64 value;
+///         u64 id;        /* if PERF_FORMAT_ID */
+///     } values[nr];
+/// };
+/// ```
+///
+/// The values are running totals, the leader's first, then the other
+/// members in the order in which they were opened.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GroupRead {
+    pub time_enabled: Option<u64>,
+    pub time_running: Option<u64>,
+    /// The ID of the group leader, if the read format has IDs.
+    pub leader_id: Option<u64>,
+    pub values: Vec<u64>,
+}
+
+impl GroupRead {
+    /// Parses the read values of the sample record `data`, or returns `None`
+    /// if the sample has none. The reads are taken to be group reads, whether
+    /// or not `read_format` has `ReadFormat::GROUP`, see
+    /// `PerfBuilder::read_counters`.
+    pub fn parse(
+        data: RawData,
+        sample_format: SampleFormat,
+        read_format: ReadFormat,
+        endian: Endianness,
+    ) -> Result<Option<Self>, std::io::Error> {
+        match endian {
+            Endianness::LittleEndian => {
+                Self::parse_impl::<byteorder::LittleEndian>(data, sample_format, read_format)
+            }
+            Endianness::BigEndian => {
+                Self::parse_impl::<byteorder::BigEndian>(data, sample_format, read_format)
+            }
+        }
+    }
+
+    pub fn parse_impl<O: ByteOrder>(
+        mut data: RawData,
+        sample_format: SampleFormat,
+        read_format: ReadFormat,
+    ) -> Result<Option<Self>, std::io::Error> {
+        if !sample_format.contains(SampleFormat::READ) {
+            return Ok(None);
+        }
+        // The u64 fields which come before the read values.
+        let preceding = SampleFormat::IDENTIFIER
+            | SampleFormat::IP
+            | SampleFormat::TID
+            | SampleFormat::TIME
+            | SampleFormat::ADDR
+            | SampleFormat::ID
+            | SampleFormat::STREAM_ID
+            | SampleFormat::CPU
+            | SampleFormat::PERIOD;
+        let preceding_count = (sample_format & preceding).bits().count_ones() as usize;
+        data.skip(preceding_count * 8)?;
+
+        let nr = data.read_u64::<O>()?;
+        let time_enabled = if read_format.contains(ReadFormat::TOTAL_TIME_ENABLED) {
+            Some(data.read_u64::<O>()?)
+        } else {
+            None
+        };
+        let time_running = if read_format.contains(ReadFormat::TOTAL_TIME_RUNNING) {
+            Some(data.read_u64::<O>()?)
+        } else {
+            None
+        };
+        let mut leader_id = None;
+        let mut values = Vec::new();
+        for i in 0..nr {
+            values.push(data.read_u64::<O>()?);
+            if read_format.contains(ReadFormat::ID) {
+                let id = data.read_u64::<O>()?;
+                if i == 0 {
+                    leader_id = Some(id);
+                }
+            }
+        }
+        Ok(Some(GroupRead {
+            time_enabled,
+            time_running,
+            leader_id,
+            values,
+        }))
+    }
+
+    /// How much each value grew since `previous`, an earlier read of the
+    /// same event, or since the event was opened. When the PMU multiplexed
+    /// the group with other events, the values only grew while the group was
+    /// running, so they're scaled up by the time the group was enabled over
+    /// the time it was running. Returns `None` if the group didn't run.
+    pub fn scaled_deltas(&self, previous: Option<&GroupRead>) -> Option<Vec<f64>> {
+        let elapsed = |now: Option<u64>, before: Option<u64>| {
+            now.map(|now| now.saturating_sub(before.unwrap_or(0)))
+        };
+        let enabled = elapsed(self.time_enabled, previous.and_then(|p| p.time_enabled));
+        let running = elapsed(self.time_running, previous.and_then(|p| p.time_running));
+        let scale = match (enabled, running) {
+            (_, Some(0)) => return None,
+            (Some(enabled), Some(running)) => enabled as f64 / running as f64,
+            _ => 1.0,
+        };
+        let previous_values = previous.map_or(&[][..], |p| &p.values[..]);
+        let deltas = self
+            .values
+            .iter()
+            .enumerate()
+            .map(|(i, value)| {
+                let previous_value = previous_values.get(i).copied().unwrap_or(0);
+                value.saturating_sub(previous_value) as f64 * scale
+            })
+            .collect();
+        Some(deltas)
+    }
+}
+
+#[derive(Debug)]
+struct ThreadGroupReads {
+    thread_handle: ThreadHandle,
+    /// The previous read of each of the thread's events, by leader ID, as
+    /// the thread has an event per CPU.
+    previous: HashMap<Option<u64>, GroupRead>,
+    /// The scaled deltas of each read.
+    deltas: Vec<(Timestamp, Vec<f64>)>,
+}
+
+/// The `--read-counters` values of a process's samples, turned into per-sample
+/// deltas for each thread.
+#[derive(Debug)]
+pub struct GroupReadCounters {
+    /// The names of the group members, the sampling event's first.
+    names: Vec<&'static str>,
+    threads: BTreeMap<i32, ThreadGroupReads>,
+}
+
+impl GroupReadCounters {
+    pub fn new(names: Vec<&'static str>) -> Self {
+        GroupReadCounters {
+            names,
+            threads: BTreeMap::new(),
+        }
+    }
+
+    pub fn add_read(
+        &mut self,
+        tid: i32,
+        thread_handle: ThreadHandle,
+        timestamp: Timestamp,
+        read: GroupRead,
+    ) {
+        let thread = self.threads.entry(tid).or_insert_with(|| ThreadGroupReads {
+            thread_handle,
+            previous: HashMap::new(),
+            deltas: Vec::new(),
+        });
+        let previous = thread.previous.get(&read.leader_id);
+        if let Some(deltas) = read.scaled_deltas(previous) {
+            thread.deltas.push((timestamp, deltas));
+        }
+        thread.previous.insert(read.leader_id, read);
+    }
+
+    /// A counter per thread and group member, plus an "IPC" counter with the
+    /// instructions per cycle if the group has both.
+    pub fn into_counters(self) -> Vec<CounterOnThread> {
+        let position = |name| self.names.iter().position(|n| *n == name);
+        let ipc_positions = position("instructions").zip(position("cycles"));
+        let mut counters = Vec::new();
+        for (tid, thread) in self.threads {
+            for (i, name) in self.names.iter().enumerate() {
+                let samples = thread
+                    .deltas
+                    .iter()
+                    .filter_map(|(timestamp, deltas)| {
+                        Some(CounterSample {
+                            timestamp: *timestamp,
+                            value: *deltas.get(i)?,
+                            modification_count: 1,
+                        })
+                    })
+                    .collect();
+                counters.push(CounterOnThread {
+                    thread_handle: thread.thread_handle,
+                    counter: Counter {
+                        name: format!("{name} (tid {tid})"),
+                        category: CounterCategory::Cpu,
+                        description: format!("Hardware {name} events of thread {tid}"),
+                        color: None,
+                        graph_type: MarkerGraphType::Line,
+                        is_cumulative: false,
+                        samples,
+                    },
+                });
+            }
+            let Some((instructions, cycles)) = ipc_positions else {
+                continue;
+            };
+            // The ratio isn't a count, so it's drawn as a graph of the values
+            // on the thread's track.
+            let samples = thread
+                .deltas
+                .iter()
+                .filter_map(|(timestamp, deltas)| {
+                    let cycles = *deltas.get(cycles)?;
+                    (cycles > 0.0).then_some(CounterSample {
+                        timestamp: *timestamp,
+                        value: *deltas.get(instructions)? / cycles,
+                        modification_count: 1,
+                    })
+                })
+                .collect();
+            counters.push(CounterOnThread {
+                thread_handle: thread.thread_handle,
+                counter: Counter {
+                    name: format!("IPC (tid {tid})"),
+                    category: CounterCategory::Custom,
+                    description: format!("Instructions per cycle of thread {tid}"),
+                    color: None,
+                    graph_type: MarkerGraphType::Line,
+                    is_cumulative: false,
+                    samples,
+                },
+            });
+        }
+        counters
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SAMPLE_FORMAT: SampleFormat = SampleFormat::IP
+        .union(SampleFormat::TID)
+        .union(SampleFormat::TIME)
+        .union(SampleFormat::CPU)
+        .union(SampleFormat::PERIOD)
+        .union(SampleFormat::READ)
+        .union(SampleFormat::REGS_USER);
+
+    const READ_FORMAT: ReadFormat = ReadFormat::TOTAL_TIME_ENABLED
+        .union(ReadFormat::TOTAL_TIME_RUNNING)
+        .union(ReadFormat::ID);
+
+    /// Builds a little-endian sample with `SAMPLE_FORMAT` and a group read
+    /// with `READ_FORMAT` of `values`, whose IDs count up from `leader_id`.
+    fn sample(enabled: u64, running: u64, leader_id: u64, values: &[u64]) -> Vec<u8> {
+        let mut words = vec![0x5555_0000, (7 << 32) | 7, 1_000_000, 3, 100_000];
+        words.extend([values.len() as u64, enabled, running]);
+        for (id, value) in (leader_id..).zip(values) {
+            words.extend([*value, id]);
+        }
+        // The user registers' ABI, which follows the read values.
+        words.push(0);
+        words.iter().flat_map(|word| word.to_le_bytes()).collect()
+    }
+
+    fn parse(bytes: &[u8]) -> GroupRead {
+        GroupRead::parse(
+            RawData::from(bytes),
+            SAMPLE_FORMAT,
+            READ_FORMAT,
+            Endianness::LittleEndian,
+        )
+        .unwrap()
+        .unwrap()
+    }
+
+    #[test]
+    fn parse_group_read() {
+        let bytes = sample(2000, 1000, 40, &[100_000, 250_000, 17]);
+        assert_eq!(
+            parse(&bytes),
+            GroupRead {
+                time_enabled: Some(2000),
+                time_running: Some(1000),
+                leader_id: Some(40),
+                values: vec![100_000, 250_000, 17],
+            }
+        );
+        let without_read = SAMPLE_FORMAT - SampleFormat::READ;
+        let read = GroupRead::parse(
+            RawData::from(&bytes[..]),
+            without_read,
+            READ_FORMAT,
+            Endianness::LittleEndian,
+        );
+        assert_eq!(read.unwrap(), None);
+    }
+
+    #[test]
+    fn multiplexed_deltas_are_scaled() {
+        let first = parse(&sample(1000, 1000, 40, &[100, 200]));
+        assert_eq!(first.scaled_deltas(None), Some(vec![100.0, 200.0]));
+        // The group ran for 500 of the 2000 enabled nanoseconds since the
+        // first read, so the deltas are scaled up by 4.
+        let second = parse(&sample(3000, 1500, 40, &[150, 260]));
+        assert_eq!(second.scaled_deltas(Some(&first)), Some(vec![200.0, 240.0]));
+        // Without running, there's nothing to scale.
+        let third = parse(&sample(4000, 1500, 40, &[150, 260]));
+        assert_eq!(third.scaled_deltas(Some(&second)), None);
+    }
+
+    #[test]
+    fn counters_have_per_thread_deltas_and_ipc() {
+        let mut profile = crate::shared::test_support::new_profile();
+        let (_, threads) = crate::shared::test_support::add_process_with_threads(&mut profile, 1);
+        let thread = threads[0];
+        let mut counters = GroupReadCounters::new(vec!["cycles", "instructions"]);
+        let at = Timestamp::from_millis_since_reference;
+        counters.add_read(7, thread, at(1.0), parse(&sample(10, 10, 40, &[1000, 500])));
+        // Another CPU's event of the same thread has its own running totals.
+        counters.add_read(
+            7,
+            thread,
+            at(2.0),
+            parse(&sample(10, 10, 50, &[1000, 3000])),
+        );
+        counters.add_read(
+            7,
+            thread,
+            at(3.0),
+            parse(&sample(30, 20, 40, &[2000, 2500])),
+        );
+
+        let counters = counters.into_counters();
+        let names: Vec<&str> = counters.iter().map(|c| c.counter.name.as_str()).collect();
+        assert_eq!(
+            names,
+            ["cycles (tid 7)", "instructions (tid 7)", "IPC (tid 7)"]
+        );
+        let values = |i: usize| -> Vec<f64> {
+            counters[i]
+                .counter
+                .samples
+                .iter()
+                .map(|s| s.value)
+                .collect()
+        };
+        // The last read ran for half of the time it was enabled.
+        assert_eq!(values(0), [1000.0, 1000.0, 2000.0]);
+        assert_eq!(values(1), [500.0, 3000.0, 4000.0]);
+        assert_eq!(values(2), [0.5, 3.0, 2.0]);
+        assert!(matches!(
+            counters[2].counter.category,
+            CounterCategory::Custom
+        ));
+    }
+}
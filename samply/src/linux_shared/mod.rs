@@ -2,15 +2,18 @@ mod avma_range;
 mod convert_regs;
 mod converter;
 mod event_interpretation;
+mod group_read;
 mod injected_jit_object;
 mod kernel_symbols;
 mod mmap_range_or_vec;
 mod object_rewriter;
 mod pe_mappings;
+mod proc_io;
 mod process;
 mod process_threads;
 mod processes;
 mod rss_stat;
+mod sched_switch;
 mod svma_file_range;
 mod thread;
 #[allow(unused)]
@@ -21,3 +24,5 @@ pub use converter::Converter;
 #[allow(unused)]
 pub use event_interpretation::{EventInterpretation, KnownEvent, OffCpuIndicator};
 pub use mmap_range_or_vec::MmapRangeOrVec;
+#[allow(unused)]
+pub use proc_io::ProcIo;
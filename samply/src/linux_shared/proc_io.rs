@@ -0,0 +1,83 @@
+/// The byte counters from `/proc/<pid>/io`.
+///
+/// ```text
+/// # cat /proc/self/io
+/// rchar: 4292
+/// wchar: 0
+/// syscr: 13
+/// syscw: 0
+/// read_bytes: 0
+/// write_bytes: 0
+/// cancelled_write_bytes: 0
+/// ```
+///
+/// `read_bytes` and `write_bytes` count the bytes which actually went to or
+/// came from the storage layer, unlike `rchar` and `wchar`, which include
+/// reads served from the page cache.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ProcIo {
+    pub read_bytes: u64,
+    pub write_bytes: u64,
+}
+
+impl ProcIo {
+    /// Returns `None` if either of the two fields is missing.
+    pub fn parse(contents: &str) -> Option<Self> {
+        let mut read_bytes = None;
+        let mut write_bytes = None;
+        for line in contents.lines() {
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+            let field = match key {
+                "read_bytes" => &mut read_bytes,
+                "write_bytes" => &mut write_bytes,
+                _ => continue,
+            };
+            *field = value.trim().parse().ok();
+        }
+        Some(ProcIo {
+            read_bytes: read_bytes?,
+            write_bytes: write_bytes?,
+        })
+    }
+}
+
+/// The throughput in MB/s between two readings of a byte counter which are
+/// `interval_ns` apart.
+pub fn rate_mb_per_s(prev_bytes: u64, bytes: u64, interval_ns: u64) -> f64 {
+    if interval_ns == 0 {
+        return 0.0;
+    }
+    let mb = bytes.saturating_sub(prev_bytes) as f64 / 1_000_000.0;
+    mb / (interval_ns as f64 / 1_000_000_000.0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_proc_io() {
+        let contents = "rchar: 323934931\nwchar: 323929600\nsyscr: 632687\nsyscw: 632675\n\
+                        read_bytes: 4096\nwrite_bytes: 323932160\ncancelled_write_bytes: 0\n";
+        assert_eq!(
+            ProcIo::parse(contents),
+            Some(ProcIo {
+                read_bytes: 4096,
+                write_bytes: 323932160
+            })
+        );
+        assert_eq!(ProcIo::parse("rchar: 1\nread_bytes: 2\n"), None);
+        assert_eq!(ProcIo::parse("read_bytes: x\nwrite_bytes: 0\n"), None);
+    }
+
+    #[test]
+    fn rate() {
+        // 5 MB in 500 ms.
+        assert_eq!(rate_mb_per_s(1_000_000, 6_000_000, 500_000_000), 10.0);
+        assert_eq!(rate_mb_per_s(0, 1_000, 0), 0.0);
+        // Counters never go backwards, but don't report a negative rate if they do.
+        assert_eq!(rate_mb_per_s(2_000, 1_000, 1_000_000), 0.0);
+    }
+}
@@ -2,25 +2,39 @@ use std::path::{Path, PathBuf};
 
 use framehop::Unwinder;
 use fxprof_processed_profile::{
-    CounterHandle, FrameInfo, LibraryHandle, MarkerTiming, ProcessHandle, Profile, ThreadHandle,
-    Timestamp,
+    CounterHandle, FrameInfo, GraphColor, LibMappings, LibraryHandle, MarkerTiming, ProcessHandle,
+    Profile, ThreadHandle, Timestamp,
 };
 
+use super::group_read::GroupReadCounters;
+use super::proc_io::ProcIo;
 use super::process_threads::ProcessThreads;
 use super::thread::Thread;
-use crate::shared::counter_file::get_counter;
+use crate::shared::counter_file::{get_counter, CounterCategory};
+use crate::shared::custom_markers::CustomMarkerSources;
 use crate::shared::jit_category_manager::JitCategoryManager;
 use crate::shared::jit_function_add_marker::JitFunctionAddMarker;
 use crate::shared::jit_function_recycler::JitFunctionRecycler;
 use crate::shared::jitdump_manager::JitDumpManager;
-use crate::shared::lib_mappings::{LibMappingAdd, LibMappingInfo, LibMappingOp, LibMappingOpQueue};
-use crate::shared::marker_file::get_markers;
+use crate::shared::lib_mappings::{
+    find_by_name, LibMappingAdd, LibMappingInfo, LibMappingOp, LibMappingOpQueue,
+};
+use crate::shared::marker_file::{
+    get_markers, EventOrSpanMarker, MarkerFileContents, MarkerFileOptions, MarkerLineProcessor,
+};
+use crate::shared::memory_usage::{MemoryReport, MemoryUsage};
 use crate::shared::perf_map::try_load_perf_map;
-use crate::shared::process_sample_data::{CounterOnThread, MarkerOnThread, ProcessSampleData};
+use crate::shared::process_sample_data::{
+    CounterOnThread, CrossProcessEdge, MarkerOnThread, ProcessSampleData,
+};
+use crate::shared::recording_props::HwCounter;
 use crate::shared::recycling::{ProcessRecyclingData, ThreadRecycler};
+use crate::shared::sample_spill::{SampleSpill, DEFAULT_MAX_IN_MEMORY_SAMPLES};
+use crate::shared::save_profile::embed_marker_file_report;
+use crate::shared::spill_recovery::{ManifestProgress, ManifestRecord};
 use crate::shared::synthetic_jit_library::SyntheticJitLibrary;
 use crate::shared::timestamp_converter::TimestampConverter;
-use crate::shared::unresolved_samples::UnresolvedSamples;
+use crate::shared::unresolved_samples::{SampleCallback, UnresolvedSamples, UnresolvedStackHandle};
 
 pub struct Process<U> {
     pub profile_process: ProcessHandle,
@@ -35,11 +49,25 @@ pub struct Process<U> {
     pub jit_function_recycler: Option<JitFunctionRecycler>,
     marker_file_paths: Vec<(ThreadHandle, PathBuf, Vec<PathBuf>)>,
     counter_file_paths: Vec<(ThreadHandle, PathBuf, Vec<PathBuf>)>,
+    /// The marker lines which this process sent to the marker socket.
+    marker_socket_lines: Option<MarkerLineProcessor>,
     pub prev_mm_filepages_size: i64,
     pub prev_mm_anonpages_size: i64,
     pub prev_mm_swapents_size: i64,
     pub prev_mm_shmempages_size: i64,
     pub mem_counter: Option<CounterHandle>,
+    /// The last `/proc/<pid>/io` reading, with its raw timestamp.
+    pub prev_io: Option<(u64, ProcIo)>,
+    /// The "Disk reads" and "Disk writes" counters.
+    pub io_counters: Option<(CounterHandle, CounterHandle)>,
+    /// The tracks of the `--hw-counter` events.
+    pub hw_counters: Vec<(HwCounter, CounterHandle)>,
+    /// The `--read-counters` values of this process's samples.
+    pub group_read_counters: Option<GroupReadCounters>,
+    /// What `new_manifest_records` has returned so far.
+    manifest_progress: ManifestProgress,
+    /// The `--ipc-edges` edges with an end in this process.
+    cross_process_edges: Vec<CrossProcessEdge>,
 }
 
 pub struct ProcessForkData<U> {
@@ -62,7 +90,20 @@ where
         jit_function_recycler: Option<JitFunctionRecycler>,
         unlink_aux_files: bool,
         should_emit_jit_markers: bool,
+        sample_callback: Option<SampleCallback>,
+        spill_dir: Option<&Path>,
     ) -> Self {
+        let mut unresolved_samples = UnresolvedSamples::default();
+        unresolved_samples.set_sample_callback(sample_callback);
+        if let Some(spill_dir) = spill_dir {
+            match SampleSpill::create(spill_dir, pid, DEFAULT_MAX_IN_MEMORY_SAMPLES) {
+                Ok(spill) => unresolved_samples.set_spill(Some(spill)),
+                Err(e) => eprintln!(
+                    "Couldn't create a spill file in {}: {e}",
+                    spill_dir.display()
+                ),
+            }
+        }
         Self {
             profile_process: process_handle,
             unwinder: U::default(),
@@ -78,16 +119,23 @@ where
                 name,
                 thread_recycler,
             ),
-            unresolved_samples: Default::default(),
+            unresolved_samples,
             jit_app_cache_mapping_ops: LibMappingOpQueue::default(),
             jit_function_recycler,
             marker_file_paths: Vec::new(),
             counter_file_paths: Vec::new(),
+            marker_socket_lines: None,
             prev_mm_filepages_size: 0,
             prev_mm_anonpages_size: 0,
             prev_mm_swapents_size: 0,
             prev_mm_shmempages_size: 0,
             mem_counter: None,
+            prev_io: None,
+            io_counters: None,
+            hw_counters: Vec::new(),
+            group_read_counters: None,
+            manifest_progress: ManifestProgress::default(),
+            cross_process_edges: Vec::new(),
         }
     }
 
@@ -103,6 +151,61 @@ where
     pub fn adopt_fork_data_from_parent(&mut self, fork_data: ProcessForkData<U>) {
         self.unwinder = fork_data.unwinder;
         self.lib_mapping_ops = fork_data.lib_mapping_ops;
+        self.manifest_progress.lib_op_count = 0;
+    }
+
+    /// The spill manifest records about this process's spill file which
+    /// weren't returned by an earlier call, see `spill_recovery`.
+    pub fn new_manifest_records(&mut self, profile: &Profile) -> Vec<ManifestRecord> {
+        let Some(spill) = self.unresolved_samples.spill() else {
+            return Vec::new();
+        };
+        let progress = &mut self.manifest_progress;
+        let mut records = Vec::new();
+        if !progress.process_written {
+            let spill_file = spill.path().file_name().unwrap_or_default();
+            records.push(ManifestRecord::Process {
+                pid: self.pid,
+                name: self.name.clone(),
+                spill_file: spill_file.to_string_lossy().into_owned(),
+            });
+            progress.process_written = true;
+        }
+
+        let new_thread_handles = &spill.thread_handles()[progress.thread_count..];
+        for (index, thread_handle) in (progress.thread_count..).zip(new_thread_handles) {
+            if let Some(tid) = self.threads.tid_for_thread_handle(*thread_handle) {
+                records.push(ManifestRecord::Thread {
+                    pid: self.pid,
+                    index: index as u32,
+                    tid,
+                });
+            }
+        }
+        progress.thread_count += new_thread_handles.len();
+
+        let ops = self.lib_mapping_ops.ops();
+        for (_, op) in ops.get(progress.lib_op_count..).unwrap_or_default() {
+            let LibMappingOp::Add(add) = op else {
+                continue;
+            };
+            let lib = profile.library_info(add.info.lib_handle);
+            records.push(ManifestRecord::Lib {
+                pid: self.pid,
+                start_avma: add.start_avma,
+                end_avma: add.end_avma,
+                relative_address_at_start: add.relative_address_at_start,
+                name: lib.name.clone(),
+                debug_name: lib.debug_name.clone(),
+                path: lib.path.clone(),
+                debug_path: lib.debug_path.clone(),
+                debug_id: lib.debug_id.to_string(),
+                code_id: lib.code_id.clone(),
+                arch: lib.arch.clone(),
+            });
+        }
+        progress.lib_op_count = ops.len();
+        records
     }
 
     pub fn rename_with_recycling(
@@ -197,6 +300,70 @@ where
             .push((thread, path.to_owned(), lookup_dirs));
     }
 
+    /// Processes a marker line which this process sent to the marker socket.
+    /// The markers are added to the main thread, or, for running spans, to
+    /// the thread in the line's prefix, like the markers from marker files.
+    pub fn add_marker_socket_line(
+        &mut self,
+        line: &str,
+        timestamp_converter: &TimestampConverter,
+        raw_marker_lines: bool,
+    ) {
+        let processor = self.marker_socket_lines.get_or_insert_with(|| {
+            MarkerLineProcessor::new(*timestamp_converter).with_raw_lines(raw_marker_lines)
+        });
+        if let Err(reason) = processor.process_line(line) {
+            log::warn!(
+                "Invalid line on the marker socket from process {}: {reason}",
+                self.pid
+            );
+        }
+    }
+
+    /// The samples of this process, the off-CPU stacks of its threads and
+    /// the stacks of its cross-process edges, which are all the stacks it
+    /// refers to, see `UnresolvedStacks::gc`.
+    pub fn stack_refs_mut(
+        &mut self,
+    ) -> (
+        &mut UnresolvedSamples,
+        impl Iterator<Item = &mut UnresolvedStackHandle>,
+    ) {
+        let off_cpu_stacks = std::iter::once(&mut self.threads.main_thread)
+            .chain(self.threads.threads_by_tid.values_mut())
+            .filter_map(|thread| thread.off_cpu_stack.as_mut());
+        let edge_stacks = self
+            .cross_process_edges
+            .iter_mut()
+            .flat_map(|edge| [&mut edge.from_stack, &mut edge.to_stack]);
+        (
+            &mut self.unresolved_samples,
+            off_cpu_stacks.chain(edge_stacks),
+        )
+    }
+
+    /// Add an edge which has at least one end in this process, see
+    /// `ProcessSampleData::add_cross_process_edge`.
+    pub fn add_cross_process_edge(&mut self, edge: CrossProcessEdge) {
+        self.cross_process_edges.push(edge);
+    }
+
+    /// Adds the approximate sizes of this process's buffers to `report`.
+    pub fn add_memory_usage(&self, report: &mut MemoryReport) {
+        report.add("Unresolved samples", self.unresolved_samples.heap_size());
+        report.add(
+            "Lib mapping ops",
+            self.lib_mapping_ops.heap_size() + self.jit_app_cache_mapping_ops.heap_size(),
+        );
+        report.add(
+            "Markers",
+            self.marker_socket_lines
+                .as_ref()
+                .map_or(0, MarkerLineProcessor::heap_size),
+        );
+        report.add("Jitdump", self.jitdump_manager.heap_size());
+    }
+
     pub fn notify_dead(&mut self, end_time: Timestamp, profile: &mut Profile) {
         self.threads.notify_process_dead(end_time, profile);
         profile.set_process_end_time(self.profile_process, end_time);
@@ -207,6 +374,8 @@ where
         profile: &mut Profile,
         jit_category_manager: &mut JitCategoryManager,
         timestamp_converter: &TimestampConverter,
+        marker_file_options: MarkerFileOptions,
+        custom_marker_sources: &CustomMarkerSources,
     ) -> (ProcessSampleData, Option<(String, ProcessRecyclingData)>) {
         self.unwinder = U::default();
 
@@ -233,35 +402,103 @@ where
             jitdump_ops.insert(0, self.jit_app_cache_mapping_ops);
         }
 
-        let mut markers = Vec::new();
-        for (thread_handle, marker_file_path, lookup_dirs) in self.marker_file_paths {
-            if let Ok(markers_from_this_file) =
-                get_markers(&marker_file_path, &lookup_dirs, *timestamp_converter)
+        if perf_map_mappings.is_none()
+            && jitdump_ops.is_empty()
+            && !self.unresolved_samples.is_empty()
+        {
+            if let Some((lib_name, hint)) =
+                jit_runtime_without_names(&self.lib_mapping_ops, profile)
             {
-                markers.extend(
-                    markers_from_this_file
-                        .into_iter()
-                        .map(|marker| MarkerOnThread {
-                            thread_handle,
-                            event_or_span: marker,
-                        }),
+                eprintln!(
+                    "Process {} uses {lib_name}, but it didn't write a perf map or jitdump, so its JIT frames won't have names. To get them, {hint}.",
+                    self.pid
                 );
             }
         }
 
+        let mut source_files = Vec::new();
+        let mut markers = Vec::new();
         let mut counters = Vec::new();
+        let threads = &self.threads;
+        let marker_on_thread = |marker: EventOrSpanMarker, thread_handle| {
+            // Running spans go on the thread they ran on.
+            let thread_handle = marker
+                .tid
+                .and_then(|tid| threads.thread_handle_for_tid(tid))
+                .unwrap_or(thread_handle);
+            MarkerOnThread {
+                thread_handle,
+                event_or_span: marker,
+            }
+        };
+        for (thread_handle, marker_file_path, lookup_dirs) in self.marker_file_paths {
+            match get_markers(
+                &marker_file_path,
+                &lookup_dirs,
+                *timestamp_converter,
+                marker_file_options,
+            ) {
+                Ok(MarkerFileContents {
+                    markers: markers_from_this_file,
+                    true_path,
+                    report,
+                    counters: counters_from_this_file,
+                }) => {
+                    println!("Marker file {}: {report}", true_path.display());
+                    embed_marker_file_report(profile, &true_path, &report);
+                    source_files.push(true_path);
+                    markers.extend(
+                        markers_from_this_file
+                            .into_iter()
+                            .map(|marker| marker_on_thread(marker, thread_handle)),
+                    );
+                    counters.extend(counters_from_this_file.into_iter().map(|counter| {
+                        CounterOnThread {
+                            thread_handle,
+                            counter,
+                        }
+                    }));
+                }
+                Err(e) if marker_file_options.strict && e.is_parse_error() => {
+                    eprintln!("{e}");
+                    std::process::exit(1);
+                }
+                Err(e) => e.report(),
+            }
+        }
+        if let Some(marker_socket_lines) = self.marker_socket_lines.take() {
+            let main_thread = self.threads.main_thread.profile_thread;
+            markers.extend(
+                marker_socket_lines
+                    .into_markers()
+                    .into_iter()
+                    .map(|marker| marker_on_thread(marker, main_thread)),
+            );
+        }
+
+        custom_marker_sources.add_markers(
+            profile,
+            &self.threads.custom_marker_process(),
+            timestamp_converter,
+        );
+
         for (thread_handle, counter_file_path, lookup_dirs) in self.counter_file_paths {
-            if let Ok(counter_from_this_file) =
-                get_counter(&counter_file_path, &lookup_dirs, *timestamp_converter)
-            {
-                counters.push(CounterOnThread {
-                    thread_handle,
-                    counter: counter_from_this_file,
-                });
+            match get_counter(&counter_file_path, &lookup_dirs, *timestamp_converter) {
+                Ok((counter_from_this_file, true_path)) => {
+                    source_files.push(true_path);
+                    counters.push(CounterOnThread {
+                        thread_handle,
+                        counter: counter_from_this_file,
+                    });
+                }
+                Err(e) => e.report(),
             }
         }
+        if let Some(group_read_counters) = self.group_read_counters {
+            counters.extend(group_read_counters.into_counters());
+        }
 
-        let process_sample_data = ProcessSampleData::new(
+        let mut process_sample_data = ProcessSampleData::new(
             std::mem::take(&mut self.unresolved_samples),
             std::mem::take(&mut self.lib_mapping_ops),
             jitdump_ops,
@@ -269,7 +506,12 @@ where
             markers,
             counters,
             self.profile_process,
+            source_files,
         );
+        process_sample_data.set_main_thread(self.threads.main_thread.profile_thread);
+        for edge in std::mem::take(&mut self.cross_process_edges) {
+            process_sample_data.add_cross_process_edge(edge);
+        }
 
         let thread_recycler = self.threads.finish();
 
@@ -340,7 +582,7 @@ where
         if let (Some(name), Some(recycler)) = (symbol_name, self.jit_function_recycler.as_mut()) {
             let code_size = (end_address - start_address) as u32;
             (lib_handle, relative_address_at_start) =
-                recycler.recycle(name, code_size, lib_handle, relative_address_at_start);
+                recycler.recycle(name, code_size, None, lib_handle, relative_address_at_start);
         }
 
         let (category, js_frame) =
@@ -383,10 +625,124 @@ where
             profile.add_counter(
                 self.profile_process,
                 "malloc",
-                "Memory",
+                CounterCategory::Memory.into(),
                 "Amount of allocated memory",
-                None,
+                CounterCategory::Memory.default_color(),
             )
         })
     }
+
+    pub fn get_or_make_io_counters(
+        &mut self,
+        profile: &mut Profile,
+    ) -> (CounterHandle, CounterHandle) {
+        *self.io_counters.get_or_insert_with(|| {
+            let reads = profile.add_counter(
+                self.profile_process,
+                "Disk reads",
+                CounterCategory::Io.into(),
+                "Bytes read from storage, in MB/s",
+                Some(GraphColor::Blue),
+            );
+            let writes = profile.add_counter(
+                self.profile_process,
+                "Disk writes",
+                CounterCategory::Io.into(),
+                "Bytes written to storage, in MB/s",
+                Some(GraphColor::Orange),
+            );
+            (reads, writes)
+        })
+    }
+
+    pub fn get_or_make_hw_counter(
+        &mut self,
+        counter: HwCounter,
+        profile: &mut Profile,
+    ) -> CounterHandle {
+        if let Some((_, handle)) = self.hw_counters.iter().find(|(c, _)| *c == counter) {
+            return *handle;
+        }
+        let handle = profile.add_counter(
+            self.profile_process,
+            counter.as_str(),
+            CounterCategory::Cpu.into(),
+            &format!("Hardware {} events", counter.as_str()),
+            CounterCategory::Cpu.default_color(),
+        );
+        self.hw_counters.push((counter, handle));
+        handle
+    }
+}
+
+/// JIT runtimes which only write a perf map or a jitdump for their JIT code
+/// when asked to, with how to ask them.
+const JIT_RUNTIME_HINTS: &[(&str, &str)] = &[
+    ("libcoreclr.so", "set DOTNET_PerfMapEnabled=1"),
+    (
+        "libjvm.so",
+        "run java with -XX:+UnlockDiagnosticVMOptions -XX:+DumpPerfMapAtExit",
+    ),
+];
+
+/// The first library of `JIT_RUNTIME_HINTS` which is mapped once all of
+/// `lib_mapping_ops` have been applied, with its hint.
+fn jit_runtime_without_names(
+    lib_mapping_ops: &LibMappingOpQueue,
+    profile: &Profile,
+) -> Option<(&'static str, &'static str)> {
+    let mut mappings = LibMappings::new();
+    for (_, op) in lib_mapping_ops.ops() {
+        op.clone().apply_to(&mut mappings);
+    }
+    JIT_RUNTIME_HINTS
+        .iter()
+        .copied()
+        .find(|(lib_name, _)| find_by_name(&mappings, profile, lib_name).is_some())
+}
+
+#[cfg(test)]
+mod test {
+    use debugid::DebugId;
+    use fxprof_processed_profile::LibraryInfo;
+
+    use super::*;
+    use crate::shared::test_support::{add_synthetic_lib, new_profile};
+
+    fn add(start_avma: u64, lib_handle: LibraryHandle) -> LibMappingOp {
+        LibMappingOp::Add(LibMappingAdd {
+            start_avma,
+            end_avma: start_avma + 0x1000,
+            relative_address_at_start: 0,
+            info: LibMappingInfo::new_lib(lib_handle),
+        })
+    }
+
+    #[test]
+    fn mapped_jit_runtime_is_found_until_exec() {
+        let mut profile = new_profile();
+        let app = add_synthetic_lib(&mut profile, 0);
+        let coreclr = profile.add_lib(LibraryInfo {
+            name: "libcoreclr.so".to_string(),
+            debug_name: "libcoreclr.so".to_string(),
+            path: "/usr/share/dotnet/libcoreclr.so".to_string(),
+            debug_path: "/usr/share/dotnet/libcoreclr.so".to_string(),
+            debug_id: DebugId::nil(),
+            code_id: None,
+            arch: None,
+            symbol_table: None,
+        });
+        let mut ops = LibMappingOpQueue::default();
+        ops.push(0, add(0x1000, app));
+        assert_eq!(jit_runtime_without_names(&ops, &profile), None);
+
+        ops.push(1, add(0x5000, coreclr));
+        let (lib_name, _) = jit_runtime_without_names(&ops, &profile).unwrap();
+        assert_eq!(lib_name, "libcoreclr.so");
+
+        // An exec clears the mappings.
+        ops.push(2, LibMappingOp::Clear);
+        ops.push(3, add(0x1000, app));
+        assert_eq!(jit_runtime_without_names(&ops, &profile), None);
+    }
 }
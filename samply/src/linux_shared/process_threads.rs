@@ -5,6 +5,7 @@ use fxprof_processed_profile::{
 };
 
 use super::thread::Thread;
+use crate::shared::custom_markers::CustomMarkerProcess;
 use crate::shared::recycling::ThreadRecycler;
 use crate::shared::types::FastHashMap;
 
@@ -14,6 +15,11 @@ pub struct ProcessThreads {
     pub main_thread: Thread,
     pub threads_by_tid: FastHashMap<i32, Thread>,
     pub thread_recycler: Option<ThreadRecycler>,
+    /// The profile threads of threads which have already exited.
+    exited_thread_handles_by_tid: FastHashMap<i32, ThreadHandle>,
+    /// The tid of the latest thread which used each profile thread, including
+    /// the main thread and threads which have exited.
+    tids_by_thread_handle: FastHashMap<ThreadHandle, i32>,
 }
 
 impl ProcessThreads {
@@ -25,12 +31,16 @@ impl ProcessThreads {
         name: Option<String>,
         thread_recycler: Option<ThreadRecycler>,
     ) -> Self {
+        let mut tids_by_thread_handle = FastHashMap::default();
+        tids_by_thread_handle.insert(main_thread_handle, pid);
         Self {
             pid,
             profile_process: process_handle,
             main_thread: Thread::new(main_thread_handle, main_thread_label_frame, name),
             threads_by_tid: Default::default(),
             thread_recycler,
+            exited_thread_handles_by_tid: Default::default(),
+            tids_by_thread_handle,
         }
     }
 
@@ -45,6 +55,8 @@ impl ProcessThreads {
         let (_old_name, old_main_thread_recycling_data) = self
             .main_thread
             .rename_with_recycling(name, main_thread_recycling_data);
+        self.tids_by_thread_handle
+            .insert(self.main_thread.profile_thread, self.pid);
         let old_thread_recycler =
             std::mem::replace(&mut self.thread_recycler, Some(thread_recycler));
         (
@@ -72,6 +84,7 @@ impl ProcessThreads {
                     {
                         let thread =
                             Thread::new(thread_handle, thread_label_frame, Some(name.clone()));
+                        self.tids_by_thread_handle.insert(thread_handle, tid);
                         return entry.insert(thread);
                     }
                 }
@@ -84,6 +97,7 @@ impl ProcessThreads {
                 let thread_label_frame =
                     make_thread_label_frame(profile, name.as_deref(), self.pid, tid);
                 let thread = Thread::new(thread_handle, thread_label_frame, name);
+                self.tids_by_thread_handle.insert(thread_handle, tid);
                 entry.insert(thread)
             }
             Entry::Occupied(entry) => {
@@ -134,6 +148,8 @@ impl ProcessThreads {
                         if let Some(old_name) = old_name {
                             thread_recycler.add_to_pool(&old_name, old_thread_recycling_data);
                         }
+                        self.tids_by_thread_handle
+                            .insert(thread.profile_thread, tid);
                     }
                 } else {
                     let thread_label_frame =
@@ -147,7 +163,9 @@ impl ProcessThreads {
     /// Called when a process has exited, before finish(). Not called if the process
     /// is still alive at the end of the profiling run.
     pub fn notify_process_dead(&mut self, end_time: Timestamp, profile: &mut Profile) {
-        for (_tid, mut thread) in self.threads_by_tid.drain() {
+        for (tid, mut thread) in self.threads_by_tid.drain() {
+            self.exited_thread_handles_by_tid
+                .insert(tid, thread.profile_thread);
             thread.notify_dead(end_time, profile);
 
             let (name, thread_recycling_data) = thread.finish();
@@ -178,6 +196,7 @@ impl ProcessThreads {
                 false,
             );
             let thread_label_frame = make_thread_label_frame(profile, None, self.pid, tid);
+            self.tids_by_thread_handle.insert(profile_thread, tid);
             Thread {
                 profile_thread,
                 context_switch_data: Default::default(),
@@ -189,10 +208,52 @@ impl ProcessThreads {
         })
     }
 
+    /// Returns the profile thread of the thread with this tid, if this
+    /// process has or had such a thread.
+    pub fn thread_handle_for_tid(&self, tid: i32) -> Option<ThreadHandle> {
+        if tid == self.pid {
+            return Some(self.main_thread.profile_thread);
+        }
+        match self.threads_by_tid.get(&tid) {
+            Some(thread) => Some(thread.profile_thread),
+            None => self.exited_thread_handles_by_tid.get(&tid).copied(),
+        }
+    }
+
+    /// The tid of the latest thread of this process which used this profile
+    /// thread, if any.
+    pub fn tid_for_thread_handle(&self, thread_handle: ThreadHandle) -> Option<i32> {
+        self.tids_by_thread_handle.get(&thread_handle).copied()
+    }
+
+    /// The process and its threads, for `CustomMarkerSource`s.
+    pub fn custom_marker_process(&self) -> CustomMarkerProcess {
+        let threads = std::iter::once((self.pid, self.main_thread.profile_thread))
+            .chain(
+                self.threads_by_tid
+                    .iter()
+                    .map(|(tid, thread)| (*tid, thread.profile_thread)),
+            )
+            .chain(
+                self.exited_thread_handles_by_tid
+                    .iter()
+                    .map(|(tid, h)| (*tid, *h)),
+            )
+            .collect();
+        CustomMarkerProcess {
+            pid: self.pid,
+            process: self.profile_process,
+            main_thread: self.main_thread.profile_thread,
+            threads,
+        }
+    }
+
     pub fn remove_non_main_thread(&mut self, tid: i32, time: Timestamp, profile: &mut Profile) {
         let Some(mut thread) = self.threads_by_tid.remove(&tid) else {
             return;
         };
+        self.exited_thread_handles_by_tid
+            .insert(tid, thread.profile_thread);
 
         thread.notify_dead(time, profile);
 
@@ -1,17 +1,31 @@
 use std::collections::hash_map::Entry;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
+use std::path::PathBuf;
 
 use framehop::Unwinder;
-use fxprof_processed_profile::{CategoryColor, Profile, Timestamp};
+use fxprof_processed_profile::{CategoryColor, ProcessHandle, Profile, ThreadHandle, Timestamp};
 
 use super::process::Process;
 use super::process_threads::make_thread_label_frame;
+use crate::shared::custom_markers::CustomMarkerSources;
 use crate::shared::jit_category_manager::JitCategoryManager;
 use crate::shared::jit_function_recycler::JitFunctionRecycler;
-use crate::shared::process_sample_data::ProcessSampleData;
+use crate::shared::lib_mappings::LibMappingOpQueue;
+use crate::shared::marker_file::{EventOrSpanMarker, MarkerFileOptions};
+use crate::shared::memory_usage::MemoryReport;
+use crate::shared::process_sample_data::{
+    add_global_phase_markers, collect_global_phases, warn_about_missing_categories, FlushOptions,
+    MarkerOnThread, ProcessProfileBuilder, ProcessSampleData,
+};
+use crate::shared::recording_props::ProfileCreationProps;
 use crate::shared::recycling::{ProcessRecycler, ProcessRecyclingData, ThreadRecycler};
+use crate::shared::save_profile::embed_source_files;
+use crate::shared::span_recording_gate::SpanRecordingGate;
+use crate::shared::time_report::TimeReport;
 use crate::shared::timestamp_converter::TimestampConverter;
-use crate::shared::unresolved_samples::UnresolvedStacks;
+use crate::shared::unresolved_samples::{
+    SampleCallback, UnresolvedSamples, UnresolvedStackHandle, UnresolvedStacks,
+};
 
 pub struct Processes<U>
 where
@@ -23,6 +37,9 @@ where
     /// thread of the same name.
     process_recycler: Option<ProcessRecycler>,
 
+    /// Whether reused processes only merge JIT functions with identical code.
+    exact_jit_function_reuse: bool,
+
     /// The sample data for all removed processes.
     process_sample_datas: Vec<ProcessSampleData>,
 
@@ -31,14 +48,54 @@ where
 
     /// Whether to emit JitFunctionAdd markers.
     should_emit_jit_markers: bool,
+
+    /// Whether to list the marker and counter files in the profile metadata.
+    embed_source_files: bool,
+
+    /// How the marker files of the processes are read.
+    marker_file_options: MarkerFileOptions,
+
+    /// How the data of the processes is turned into profile data.
+    flush_options: FlushOptions,
+
+    /// The builder of each profile process whose data has been flushed, so
+    /// that all data of a profile process goes through the same builder, and
+    /// its marker types, categories and counters are only created once.
+    profile_builders: HashMap<ProcessHandle, ProcessProfileBuilder>,
+
+    /// If set, the span category whose spans are shown on every track.
+    global_phase_category: Option<String>,
+
+    /// Add markers to each process when it is finished.
+    custom_marker_sources: CustomMarkerSources,
+
+    /// Called for every new sample, see `RecordingProps::sample_callback`.
+    sample_callback: Option<SampleCallback>,
+
+    /// Where processes spill their oldest samples, see
+    /// `RecordingProps::spill_dir`.
+    spill_dir: Option<PathBuf>,
+
+    /// Drops the samples outside of a span, see
+    /// `RecordingProps::record_only_during_span`.
+    span_gate: Option<SpanRecordingGate>,
+
+    /// The number of samples which the span gate dropped since the stacks
+    /// were last garbage collected, see `gc_stacks_if_due`.
+    gated_samples_since_gc: usize,
 }
 
+/// The number of samples the span gate drops before the stacks which only
+/// they used are dropped too, so that the stack table doesn't keep growing
+/// while recording outside of the span.
+const STACK_GC_GATED_SAMPLES: usize = 100_000;
+
 impl<U> Processes<U>
 where
     U: Unwinder + Default,
 {
-    pub fn new(allow_reuse: bool, unlink_aux_data: bool, should_emit_jit_markers: bool) -> Self {
-        let process_recycler = if allow_reuse {
+    pub fn new(profile_creation_props: &ProfileCreationProps) -> Self {
+        let process_recycler = if profile_creation_props.reuse_threads {
             Some(ProcessRecycler::new())
         } else {
             None
@@ -46,12 +103,33 @@ where
         Self {
             processes_by_pid: HashMap::new(),
             process_recycler,
+            exact_jit_function_reuse: profile_creation_props.exact_jit_function_reuse,
             process_sample_datas: Vec::new(),
-            unlink_aux_data,
-            should_emit_jit_markers,
+            unlink_aux_data: profile_creation_props.unlink_aux_files,
+            should_emit_jit_markers: profile_creation_props.should_emit_jit_markers,
+            embed_source_files: profile_creation_props.embed_source_files,
+            marker_file_options: profile_creation_props.marker_file_options(),
+            flush_options: profile_creation_props.flush_options(),
+            profile_builders: HashMap::new(),
+            global_phase_category: profile_creation_props.global_phase_category.clone(),
+            custom_marker_sources: profile_creation_props.custom_marker_sources.clone(),
+            sample_callback: None,
+            spill_dir: None,
+            span_gate: None,
+            gated_samples_since_gc: 0,
         }
     }
 
+    /// Applies to processes which are created after this call.
+    pub fn set_sample_callback(&mut self, sample_callback: Option<SampleCallback>) {
+        self.sample_callback = sample_callback;
+    }
+
+    /// Applies to processes which are created after this call.
+    pub fn set_spill_dir(&mut self, spill_dir: Option<PathBuf>) {
+        self.spill_dir = spill_dir;
+    }
+
     pub fn recycle_or_get_new(
         &mut self,
         pid: i32,
@@ -83,6 +161,8 @@ where
                             Some(jit_function_recycler),
                             self.unlink_aux_data,
                             self.should_emit_jit_markers,
+                            self.sample_callback.clone(),
+                            self.spill_dir.as_deref(),
                         );
                         return entry.insert(process);
                     }
@@ -104,7 +184,7 @@ where
                 let (thread_recycler, jit_function_recycler) = if self.process_recycler.is_some() {
                     (
                         Some(ThreadRecycler::new()),
-                        Some(JitFunctionRecycler::default()),
+                        Some(JitFunctionRecycler::new(self.exact_jit_function_reuse)),
                     )
                 } else {
                     (None, None)
@@ -119,6 +199,8 @@ where
                     jit_function_recycler,
                     self.unlink_aux_data,
                     self.should_emit_jit_markers,
+                    self.sample_callback.clone(),
+                    self.spill_dir.as_deref(),
                 );
                 entry.insert(process)
             }
@@ -156,7 +238,7 @@ where
             let (thread_recycler, jit_function_recycler) = if self.process_recycler.is_some() {
                 (
                     Some(ThreadRecycler::new()),
-                    Some(JitFunctionRecycler::default()),
+                    Some(JitFunctionRecycler::new(self.exact_jit_function_reuse)),
                 )
             } else {
                 (None, None)
@@ -171,10 +253,119 @@ where
                 jit_function_recycler,
                 self.unlink_aux_data,
                 self.should_emit_jit_markers,
+                self.sample_callback.clone(),
+                self.spill_dir.as_deref(),
             )
         })
     }
 
+    /// The pid, the profile thread and the off-CPU stack of the thread with
+    /// this tid, if it's a thread of a process which hasn't exited. This
+    /// looks at every process.
+    pub fn find_thread_by_tid(
+        &self,
+        tid: i32,
+    ) -> Option<(i32, ThreadHandle, Option<UnresolvedStackHandle>)> {
+        self.processes_by_pid.values().find_map(|process| {
+            let threads = &process.threads;
+            let thread = if tid == threads.pid {
+                Some(&threads.main_thread)
+            } else {
+                threads.threads_by_tid.get(&tid)
+            }?;
+            Some((process.pid, thread.profile_thread, thread.off_cpu_stack))
+        })
+    }
+
+    /// Like `get_by_pid`, but doesn't create a process for an unknown pid.
+    pub fn get_existing_by_pid(&mut self, pid: i32) -> Option<&mut Process<U>> {
+        self.processes_by_pid.get_mut(&pid)
+    }
+
+    /// The main threads of all processes which haven't exited.
+    pub fn set_span_gate(&mut self, span_gate: Option<SpanRecordingGate>) {
+        self.span_gate = span_gate;
+    }
+
+    /// Hands a live marker line from process `pid` to the span gate.
+    pub fn process_span_gate_line(
+        &mut self,
+        pid: i32,
+        line: &str,
+        timestamp_converter: &TimestampConverter,
+    ) {
+        if let Some(span_gate) = &mut self.span_gate {
+            span_gate.process_line(pid, line, timestamp_converter);
+        }
+    }
+
+    /// Drops the samples which the span gate knows to be outside of the span
+    /// as of the raw time `now`.
+    pub fn apply_span_gate(&mut self, now: u64) {
+        let Some(span_gate) = &mut self.span_gate else {
+            return;
+        };
+        span_gate.forget_old_spans(now);
+        for process in self.processes_by_pid.values_mut() {
+            self.gated_samples_since_gc += span_gate.apply(&mut process.unresolved_samples, now);
+        }
+    }
+
+    /// Drops the stacks which only the samples dropped by the span gate
+    /// used, once `STACK_GC_GATED_SAMPLES` samples have been dropped since
+    /// the last time, see `UnresolvedStacks::gc`. `stacks` must be the
+    /// stacks which all processes refer to.
+    pub fn gc_stacks_if_due(&mut self, stacks: &mut UnresolvedStacks) {
+        if self.gated_samples_since_gc < STACK_GC_GATED_SAMPLES {
+            return;
+        }
+        self.gated_samples_since_gc = 0;
+        let mut live_samples = Vec::new();
+        let mut live_handles = Vec::new();
+        for process in self.processes_by_pid.values_mut() {
+            let (samples, handles) = process.stack_refs_mut();
+            live_samples.push(samples);
+            live_handles.extend(handles);
+        }
+        for process_sample_data in &mut self.process_sample_datas {
+            let (samples, handles) = process_sample_data.stack_refs_mut();
+            live_samples.push(samples);
+            live_handles.extend(handles);
+        }
+        stacks.gc(live_samples, live_handles);
+    }
+
+    pub fn raw_marker_lines(&self) -> bool {
+        self.marker_file_options.raw_lines
+    }
+
+    /// Adds the approximate sizes of the buffers of the running processes,
+    /// and of the ones which have exited, to `report`. The data of the
+    /// exited processes is only held for the final flush, so it gets its own
+    /// line rather than being added to the buffers of the running ones.
+    pub fn add_memory_usage(&self, report: &mut MemoryReport) {
+        for process in self.processes_by_pid.values() {
+            process.add_memory_usage(report);
+        }
+        report.add(
+            "Exited processes",
+            self.process_sample_datas
+                .iter()
+                .map(ProcessSampleData::estimated_memory_bytes)
+                .sum(),
+        );
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Process<U>> {
+        self.processes_by_pid.values_mut()
+    }
+
+    pub fn main_thread_handles(&self) -> impl Iterator<Item = ThreadHandle> + '_ {
+        self.processes_by_pid
+            .values()
+            .map(|process| process.threads.main_thread.profile_thread)
+    }
+
     pub fn remove(
         &mut self,
         pid: i32,
@@ -188,13 +379,19 @@ where
         };
 
         process.notify_dead(time, profile);
-
-        let (process_sample_data, process_recycling_data) =
-            process.finish(profile, jit_category_manager, timestamp_converter);
-        if !process_sample_data.is_empty() {
-            self.process_sample_datas.push(process_sample_data);
+        if let Some(span_gate) = &self.span_gate {
+            span_gate.apply_final(&mut process.unresolved_samples);
         }
 
+        let (process_sample_data, process_recycling_data) = process.finish(
+            profile,
+            jit_category_manager,
+            timestamp_converter,
+            self.marker_file_options,
+            &self.custom_marker_sources,
+        );
+        add_process_sample_data(&mut self.process_sample_datas, process_sample_data);
+
         if let (Some((name, process_recycling_data)), Some(process_recycler)) =
             (process_recycling_data, self.process_recycler.as_mut())
         {
@@ -237,33 +434,158 @@ where
         }
     }
 
+    /// Adds `markers`, the warnings which samply logged about itself, to a
+    /// "samply" track.
+    pub fn add_log_markers(&mut self, markers: Vec<EventOrSpanMarker>, profile: &mut Profile) {
+        let Some(first_marker) = markers.first() else {
+            return;
+        };
+        let pid = std::process::id();
+        let process = profile.add_process("samply", pid, first_marker.start_time);
+        let thread = profile.add_thread(process, pid, first_marker.start_time, true);
+        profile.set_thread_name(thread, "samply");
+        let markers = markers
+            .into_iter()
+            .map(|event_or_span| MarkerOnThread {
+                thread_handle: thread,
+                event_or_span,
+            })
+            .collect();
+        let mut process_sample_data = ProcessSampleData::new(
+            UnresolvedSamples::default(),
+            LibMappingOpQueue::default(),
+            Vec::new(),
+            None,
+            markers,
+            Vec::new(),
+            process,
+            Vec::new(),
+        );
+        process_sample_data.set_main_thread(thread);
+        self.process_sample_datas.push(process_sample_data);
+    }
+
+    /// `cpu_threads` are the per-CPU tracks, which get the global phase
+    /// markers like the main threads of the processes.
+    #[allow(clippy::too_many_arguments)]
     pub fn finish(
         mut self,
         profile: &mut Profile,
+        cpu_threads: &[ThreadHandle],
         unresolved_stacks: &UnresolvedStacks,
         jit_category_manager: &mut JitCategoryManager,
         timestamp_converter: &TimestampConverter,
+        time_report: &mut TimeReport,
     ) {
         // Gather the ProcessSampleData from any processes which are still alive at the end of profiling.
-        for process in self.processes_by_pid.into_values() {
-            let (process_sample_data, _process_recycling_data) =
-                process.finish(profile, jit_category_manager, timestamp_converter);
-            if !process_sample_data.is_empty() {
-                self.process_sample_datas.push(process_sample_data);
+        time_report.time("Finishing processes", || {
+            for mut process in self.processes_by_pid.into_values() {
+                if let Some(span_gate) = &self.span_gate {
+                    span_gate.apply_final(&mut process.unresolved_samples);
+                }
+                let profile_process = process.profile_process;
+                let (process_sample_data, _process_recycling_data) = process.finish(
+                    profile,
+                    jit_category_manager,
+                    timestamp_converter,
+                    self.marker_file_options,
+                    &self.custom_marker_sources,
+                );
+                // The process didn't end while it was profiled, so its track
+                // ends with its last recorded data rather than at an exit.
+                if let Some((_, end_time)) = process_sample_data.time_bounds() {
+                    profile.set_process_end_time(profile_process, end_time);
+                }
+                add_process_sample_data(&mut self.process_sample_datas, process_sample_data);
             }
+        });
+
+        if self.embed_source_files {
+            let source_files: Vec<_> = self
+                .process_sample_datas
+                .iter()
+                .flat_map(|data| data.source_files().iter().cloned())
+                .collect();
+            embed_source_files(profile, &source_files);
+        }
+
+        let requested_categories: BTreeSet<&str> = self
+            .flush_options
+            .span_sample_categories
+            .iter()
+            .chain(self.flush_options.span_category_colors.keys())
+            .chain(&self.global_phase_category)
+            .map(String::as_str)
+            .collect();
+        warn_about_missing_categories(&self.process_sample_datas, requested_categories);
+
+        if let Some(category) = &self.global_phase_category {
+            let phases = collect_global_phases(&self.process_sample_datas, category);
+            let threads: Vec<ThreadHandle> = self
+                .process_sample_datas
+                .iter()
+                .filter_map(ProcessSampleData::main_thread)
+                .chain(cpu_threads.iter().copied())
+                .collect();
+            add_global_phase_markers(profile, &phases, &threads);
         }
 
         let user_category = profile.add_category("User", CategoryColor::Yellow).into();
         let kernel_category = profile.add_category("Kernel", CategoryColor::Orange).into();
         let mut stack_frame_scratch_buf = Vec::new();
-        for process_sample_data in self.process_sample_datas {
-            process_sample_data.flush_samples_to_profile(
-                profile,
-                user_category,
-                kernel_category,
-                &mut stack_frame_scratch_buf,
-                unresolved_stacks,
-            );
-        }
+        let flush_options = &self.flush_options;
+        let profile_builders = &mut self.profile_builders;
+        time_report.time("Flushing samples", || {
+            for process_sample_data in self.process_sample_datas {
+                // Counting the samples reads back the spilled ones.
+                if log::log_enabled!(log::Level::Info) {
+                    log::info!(
+                        "Flushing {} samples and {} markers of {:?}",
+                        process_sample_data.sample_count(),
+                        process_sample_data.marker_count(),
+                        process_sample_data.process()
+                    );
+                }
+                let process_sample_data = process_sample_data.prepare_for_flush(
+                    profile,
+                    unresolved_stacks,
+                    flush_options,
+                );
+                let builder = profile_builders
+                    .entry(process_sample_data.process())
+                    .or_insert_with(|| {
+                        ProcessProfileBuilder::new(
+                            process_sample_data.process(),
+                            user_category,
+                            kernel_category,
+                            flush_options,
+                        )
+                    });
+                builder.add_pending(process_sample_data);
+                builder.flush_pending(profile, unresolved_stacks, &mut stack_frame_scratch_buf);
+            }
+        });
+    }
+}
+
+/// Adds the data of a process which has finished to `process_sample_datas`.
+/// A process which was recycled from an earlier one with the same name has
+/// the profile process of that one, so its data is merged into the earlier
+/// data, and each profile process is flushed once.
+fn add_process_sample_data(
+    process_sample_datas: &mut Vec<ProcessSampleData>,
+    process_sample_data: ProcessSampleData,
+) {
+    if process_sample_data.is_empty() {
+        return;
+    }
+    match process_sample_datas
+        .iter_mut()
+        .find(|data| data.process() == process_sample_data.process())
+    {
+        Some(data) => data
+            .merge(process_sample_data)
+            .expect("the data is of the same process"),
+        None => process_sample_datas.push(process_sample_data),
     }
 }
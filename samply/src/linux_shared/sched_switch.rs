@@ -0,0 +1,214 @@
+use byteorder::ByteOrder;
+use linux_perf_data::{linux_perf_event_reader, Endianness};
+use linux_perf_event_reader::RawData;
+
+/// The task state bits which `prev_state` reports, from `TASK_REPORT` in
+/// `include/linux/sched.h`.
+const TASK_INTERRUPTIBLE: i64 = 0x01;
+const TASK_UNINTERRUPTIBLE: i64 = 0x02;
+const TASK_STOPPED: i64 = 0x04;
+const TASK_TRACED: i64 = 0x08;
+const EXIT_DEAD: i64 = 0x10;
+const EXIT_ZOMBIE: i64 = 0x20;
+const TASK_PARKED: i64 = 0x40;
+const TASK_REPORT_IDLE: i64 = 0x80;
+
+/// ```
+/// # cat /sys/kernel/tracing/events/sched/sched_switch/format
+/// name: sched_switch
+/// ID: 316
+/// format:
+///         field:unsigned short common_type;       offset:0;       size:2; signed:0;
+///         field:unsigned char common_flags;       offset:2;       size:1; signed:0;
+///         field:unsigned char common_preempt_count;       offset:3;       size:1; signed:0;
+///         field:int common_pid;   offset:4;       size:4; signed:1;
+///
+///         field:char prev_comm[16];       offset:8;       size:16;        signed:0;
+///         field:pid_t prev_pid;   offset:24;      size:4; signed:1;
+///         field:int prev_prio;    offset:28;      size:4; signed:1;
+///         field:long prev_state;  offset:32;      size:8; signed:1;
+///         field:char next_comm[16];       offset:40;      size:16;        signed:0;
+///         field:pid_t next_pid;   offset:56;      size:4; signed:1;
+///         field:int next_prio;    offset:60;      size:4; signed:1;
+///
+/// print fmt: "prev_comm=%s prev_pid=%d prev_prio=%d prev_state=%s%s ==> next_comm=%s next_pid=%d next_prio=%d", ...
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchedSwitch {
+    pub prev_pid: i32,
+    pub prev_prio: i32,
+    pub prev_state: i64,
+    pub next_pid: i32,
+    pub next_prio: i32,
+}
+
+impl SchedSwitch {
+    pub fn parse(data: RawData, endian: Endianness) -> Result<Self, std::io::Error> {
+        match endian {
+            Endianness::LittleEndian => Self::parse_impl::<byteorder::LittleEndian>(data),
+            Endianness::BigEndian => Self::parse_impl::<byteorder::BigEndian>(data),
+        }
+    }
+
+    pub fn parse_impl<O: ByteOrder>(mut data: RawData) -> Result<Self, std::io::Error> {
+        // common_type, common_flags, common_preempt_count, common_pid, prev_comm
+        data.skip(8 + 16)?;
+        let prev_pid = data.read_i32::<O>()?;
+        let prev_prio = data.read_i32::<O>()?;
+        let prev_state = data.read_u64::<O>()? as i64;
+        // next_comm
+        data.skip(16)?;
+        let next_pid = data.read_i32::<O>()?;
+        let next_prio = data.read_i32::<O>()?;
+        Ok(SchedSwitch {
+            prev_pid,
+            prev_prio,
+            prev_state,
+            next_pid,
+            next_prio,
+        })
+    }
+
+    /// Why the previous task was switched out, based on its state.
+    ///
+    /// A task which is still runnable was preempted. Otherwise it went to
+    /// sleep: "sleeping" tasks can be woken by signals, "uninterruptible" ones
+    /// are typically waiting for I/O.
+    pub fn prev_state_reason(&self) -> &'static str {
+        // The kernel sets a bit above the reported states (TASK_REPORT_MAX)
+        // for preempted tasks; only the state bits below it matter here.
+        let state = self.prev_state & (TASK_REPORT_IDLE | (TASK_REPORT_IDLE - 1));
+        if state == 0 {
+            "preempted"
+        } else if state & TASK_UNINTERRUPTIBLE != 0 {
+            "uninterruptible"
+        } else if state & (TASK_INTERRUPTIBLE | TASK_PARKED | TASK_REPORT_IDLE) != 0 {
+            "sleeping"
+        } else if state & (TASK_STOPPED | TASK_TRACED) != 0 {
+            "stopped"
+        } else if state & (EXIT_DEAD | EXIT_ZOMBIE) != 0 {
+            "exited"
+        } else {
+            "unknown"
+        }
+    }
+}
+
+/// ```
+/// # cat /sys/kernel/tracing/events/sched/sched_waking/format
+/// name: sched_waking
+/// ID: 320
+/// format:
+///         field:unsigned short common_type;       offset:0;       size:2; signed:0;
+///         field:unsigned char common_flags;       offset:2;       size:1; signed:0;
+///         field:unsigned char common_preempt_count;       offset:3;       size:1; signed:0;
+///         field:int common_pid;   offset:4;       size:4; signed:1;
+///
+///         field:char comm[16];    offset:8;       size:16;        signed:0;
+///         field:pid_t pid;        offset:24;      size:4; signed:1;
+///         field:int prio; offset:28;      size:4; signed:1;
+///         field:int target_cpu;   offset:32;      size:4; signed:1;
+///
+/// print fmt: "comm=%s pid=%d prio=%d target_cpu=%03d", ...
+/// ```
+///
+/// The event is emitted by the waking thread, so the sample's stack is the
+/// waker's. Only the woken thread's tid is read; the fields after it moved
+/// between kernel versions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchedWaking {
+    pub tid: i32,
+}
+
+impl SchedWaking {
+    pub fn parse(data: RawData, endian: Endianness) -> Result<Self, std::io::Error> {
+        match endian {
+            Endianness::LittleEndian => Self::parse_impl::<byteorder::LittleEndian>(data),
+            Endianness::BigEndian => Self::parse_impl::<byteorder::BigEndian>(data),
+        }
+    }
+
+    pub fn parse_impl<O: ByteOrder>(mut data: RawData) -> Result<Self, std::io::Error> {
+        // common_type, common_flags, common_preempt_count, common_pid, comm
+        data.skip(8 + 16)?;
+        let tid = data.read_i32::<O>()?;
+        Ok(SchedWaking { tid })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Builds a little-endian sched_switch payload in the layout above.
+    fn payload(prev_prio: i32, prev_state: i64, next_prio: i32) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&316u16.to_le_bytes());
+        bytes.extend_from_slice(&[0x1, 0x0]);
+        bytes.extend_from_slice(&1234i32.to_le_bytes());
+        bytes.extend_from_slice(b"make\0\0\0\0\0\0\0\0\0\0\0\0");
+        bytes.extend_from_slice(&1234i32.to_le_bytes());
+        bytes.extend_from_slice(&prev_prio.to_le_bytes());
+        bytes.extend_from_slice(&prev_state.to_le_bytes());
+        bytes.extend_from_slice(b"swapper/3\0\0\0\0\0\0\0");
+        bytes.extend_from_slice(&0i32.to_le_bytes());
+        bytes.extend_from_slice(&next_prio.to_le_bytes());
+        bytes
+    }
+
+    fn reason(prev_state: i64) -> &'static str {
+        let bytes = payload(120, prev_state, 120);
+        SchedSwitch::parse(RawData::from(&bytes[..]), Endianness::LittleEndian)
+            .unwrap()
+            .prev_state_reason()
+    }
+
+    #[test]
+    fn parse_sched_switch() {
+        let bytes = payload(120, TASK_UNINTERRUPTIBLE, 98);
+        let switch = SchedSwitch::parse(RawData::from(&bytes[..]), Endianness::LittleEndian);
+        assert_eq!(
+            switch.unwrap(),
+            SchedSwitch {
+                prev_pid: 1234,
+                prev_prio: 120,
+                prev_state: TASK_UNINTERRUPTIBLE,
+                next_pid: 0,
+                next_prio: 98,
+            }
+        );
+        let truncated = RawData::from(&bytes[..40]);
+        assert!(SchedSwitch::parse(truncated, Endianness::LittleEndian).is_err());
+    }
+
+    #[test]
+    fn parse_sched_waking() {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&320u16.to_le_bytes());
+        bytes.extend_from_slice(&[0x1, 0x0]);
+        bytes.extend_from_slice(&1234i32.to_le_bytes());
+        bytes.extend_from_slice(b"server\0\0\0\0\0\0\0\0\0\0");
+        bytes.extend_from_slice(&5678i32.to_le_bytes());
+        bytes.extend_from_slice(&120i32.to_le_bytes());
+        bytes.extend_from_slice(&3i32.to_le_bytes());
+        let waking = SchedWaking::parse(RawData::from(&bytes[..]), Endianness::LittleEndian);
+        assert_eq!(waking.unwrap(), SchedWaking { tid: 5678 });
+        let truncated = RawData::from(&bytes[..26]);
+        assert!(SchedWaking::parse(truncated, Endianness::LittleEndian).is_err());
+    }
+
+    #[test]
+    fn prev_state_reasons() {
+        // "R" and "R+"
+        assert_eq!(reason(0), "preempted");
+        assert_eq!(reason(0x100), "preempted");
+        // "S", "D", "T", "t"
+        assert_eq!(reason(TASK_INTERRUPTIBLE), "sleeping");
+        assert_eq!(reason(TASK_UNINTERRUPTIBLE), "uninterruptible");
+        assert_eq!(reason(TASK_STOPPED), "stopped");
+        assert_eq!(reason(TASK_TRACED), "stopped");
+        // "I", the state of idle kernel threads.
+        assert_eq!(reason(TASK_REPORT_IDLE), "sleeping");
+        assert_eq!(reason(EXIT_ZOMBIE), "exited");
+    }
+}
@@ -17,8 +17,11 @@ use crate::server::{start_server_main, ServerProps};
 use crate::shared::recording_props::{
     ProcessLaunchProps, ProfileCreationProps, RecordingMode, RecordingProps,
 };
-use crate::shared::save_profile::save_profile_to_file;
+use crate::shared::save_profile::{
+    append_profile_to_file, check_marker_schemas, embed_sample_weight_unit, save_profile_to_file,
+};
 use crate::shared::symbol_props::SymbolProps;
+use crate::shared::time_report::TimeReport;
 
 pub fn start_recording(
     recording_mode: RecordingMode,
@@ -28,6 +31,8 @@ pub fn start_recording(
     server_props: Option<ServerProps>,
 ) -> Result<ExitStatus, MachError> {
     let output_file = recording_props.output_file.clone();
+    let reuse_profile = recording_props.reuse_profile;
+    let sample_weight_unit = recording_props.sample_weight_unit;
 
     let mut task_accepter = TaskAccepter::new()?;
 
@@ -80,6 +85,8 @@ pub fn start_recording(
     };
 
     let unstable_presymbolicate = profile_creation_props.unstable_presymbolicate;
+    let print_time_report = profile_creation_props.time_report;
+    let validate_schemas = profile_creation_props.validate_schemas;
 
     let (task_sender, task_receiver) = unbounded();
 
@@ -194,7 +201,7 @@ pub fn start_recording(
     // or until the time limit has elapsed.
     let profile_result = sampler_thread.join().expect("couldn't join sampler thread");
 
-    let profile = match profile_result {
+    let mut profile = match profile_result {
         Ok(profile) => profile,
         Err(SamplingError::CouldNotObtainRootTask) => {
             eprintln!("Profiling failed: Could not obtain the root task.");
@@ -210,7 +217,24 @@ pub fn start_recording(
         }
     };
 
-    save_profile_to_file(&profile, &output_file).expect("Couldn't write JSON");
+    embed_sample_weight_unit(&mut profile, sample_weight_unit);
+    if let Some(validation) = validate_schemas {
+        if !check_marker_schemas(&profile, validation) {
+            std::process::exit(1);
+        }
+    }
+
+    let mut time_report = TimeReport::new();
+    time_report.time("Saving profile", || {
+        if reuse_profile {
+            append_profile_to_file(&profile, &output_file).expect("Couldn't write JSON")
+        } else {
+            save_profile_to_file(&profile, &output_file).expect("Couldn't write JSON")
+        }
+    });
+    if print_time_report {
+        time_report.print();
+    }
 
     if unstable_presymbolicate {
         crate::shared::symbol_precog::presymbolicate(
@@ -4,15 +4,21 @@ use std::time::{Duration, SystemTime};
 use std::{mem, thread};
 
 use crossbeam_channel::Receiver;
-use fxprof_processed_profile::{CategoryColor, CategoryPairHandle, Profile, ReferenceTimestamp};
+use fxprof_processed_profile::{
+    CategoryColor, CategoryPairHandle, Profile, ReferenceTimestamp, ThreadHandle,
+};
 use mach::port::mach_port_t;
 
 use super::error::SamplingError;
 use super::task_profiler::TaskProfiler;
 use super::time::get_monotonic_timestamp;
+use crate::shared::process_sample_data::{
+    add_global_phase_markers, collect_global_phases, ProcessSampleData,
+};
 use crate::shared::recording_props::{ProfileCreationProps, RecordingProps};
 use crate::shared::recycling::ProcessRecycler;
-use crate::shared::timestamp_converter::TimestampConverter;
+use crate::shared::save_profile::embed_source_files;
+use crate::shared::timestamp_converter::{ClockDomain, TimestampConverter};
 use crate::shared::unresolved_samples::UnresolvedStacks;
 
 pub enum ProcessSpecificPath {
@@ -62,11 +68,14 @@ impl Sampler {
         let timestamp_converter = TimestampConverter {
             reference_raw: reference_mono,
             raw_to_ns_factor: 1,
+            domain: ClockDomain::Monotonic,
+            realtime_to_monotonic_offset_ns: None,
         };
 
         let mut profile = Profile::new(
             self.profile_creation_props.profile_name(),
-            ReferenceTimestamp::from_system_time(reference_system_time),
+            self.profile_creation_props
+                .reference_timestamp(ReferenceTimestamp::from_system_time(reference_system_time)),
             self.recording_props.interval.into(),
         );
         if let Some(macos_name_and_version) = get_macos_name_and_version() {
@@ -75,6 +84,7 @@ impl Sampler {
 
         let mut jit_category_manager =
             crate::shared::jit_category_manager::JitCategoryManager::new();
+        jit_category_manager.add_v8_tiered_categories();
 
         let default_category =
             CategoryPairHandle::from(profile.add_category("User", CategoryColor::Yellow));
@@ -221,7 +231,25 @@ impl Sampler {
             process_sample_datas.push(process_sample_data);
         }
 
+        if self.profile_creation_props.embed_source_files {
+            let source_files: Vec<_> = process_sample_datas
+                .iter()
+                .flat_map(|data| data.source_files().iter().cloned())
+                .collect();
+            embed_source_files(&mut profile, &source_files);
+        }
+
+        if let Some(category) = &self.profile_creation_props.global_phase_category {
+            let phases = collect_global_phases(&process_sample_datas, category);
+            let main_threads: Vec<ThreadHandle> = process_sample_datas
+                .iter()
+                .filter_map(ProcessSampleData::main_thread)
+                .collect();
+            add_global_phase_markers(&mut profile, &phases, &main_threads);
+        }
+
         let mut stack_frame_scratch_buf = Vec::new();
+        let flush_options = self.profile_creation_props.flush_options();
         for process_sample_data in process_sample_datas {
             process_sample_data.flush_samples_to_profile(
                 &mut profile,
@@ -229,6 +257,7 @@ impl Sampler {
                 default_category,
                 &mut stack_frame_scratch_buf,
                 &unresolved_stacks,
+                &flush_options,
             );
         }
 
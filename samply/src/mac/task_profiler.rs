@@ -41,12 +41,13 @@ use crate::shared::lib_mappings::{
     LibMappingAdd, LibMappingInfo, LibMappingOp, LibMappingOpQueue, LibMappingRemove,
 };
 use crate::shared::marker_file;
-use crate::shared::marker_file::get_markers;
+use crate::shared::marker_file::{get_markers, MarkerFileContents};
 use crate::shared::perf_map::try_load_perf_map;
 use crate::shared::process_name::make_process_name;
-use crate::shared::process_sample_data::{MarkerOnThread, ProcessSampleData};
+use crate::shared::process_sample_data::{CounterOnThread, MarkerOnThread, ProcessSampleData};
 use crate::shared::recording_props::ProfileCreationProps;
 use crate::shared::recycling::{ProcessRecycler, ProcessRecyclingData, ThreadRecycler};
+use crate::shared::save_profile::embed_marker_file_report;
 use crate::shared::timestamp_converter::TimestampConverter;
 use crate::shared::unresolved_samples::{UnresolvedSamples, UnresolvedStacks};
 
@@ -223,7 +224,9 @@ impl TaskProfiler {
                 let (thread_recycler, jit_function_recycler) = match process_recycler {
                     Some(_) => (
                         Some(ThreadRecycler::new()),
-                        Some(JitFunctionRecycler::default()),
+                        Some(JitFunctionRecycler::new(
+                            profile_creation_props.exact_jit_function_reuse,
+                        )),
                     ),
                     None => (None, None),
                 };
@@ -601,15 +604,16 @@ impl TaskProfiler {
                 }
                 ProcessSpecificPath::MarkerFile(marker_file_path) => {
                     // count the number of - characters in marker_file_path
-                    let marker_info = marker_file::parse_marker_file_path(&marker_file_path);
-                    let thread_handle = if marker_info.tid.is_some() {
-                        self.live_threads
+                    let marker_tid = marker_file::parse_marker_file_path(&marker_file_path)
+                        .and_then(|marker_info| marker_info.tid);
+                    let thread_handle = match marker_tid {
+                        Some(tid) => self
+                            .live_threads
                             .iter()
-                            .find(|(_, thread)| thread.tid == marker_info.tid.unwrap())
+                            .find(|(_, thread)| thread.tid == tid)
                             .map(|(_, thread)| thread.profile_thread)
-                            .unwrap_or(self.main_thread_handle)
-                    } else {
-                        self.main_thread_handle
+                            .unwrap_or(self.main_thread_handle),
+                        None => self.main_thread_handle,
                     };
                     self.marker_file_paths
                         .push((thread_handle, marker_file_path));
@@ -668,33 +672,59 @@ impl TaskProfiler {
             self.jit_function_recycler.as_mut(),
             &self.timestamp_converter,
         );
+        let mut source_files = Vec::new();
         let mut markers = Vec::new();
+        let mut counters = Vec::new();
         for (thread_handle, marker_file_path) in self.marker_file_paths {
-            if let Ok(markers_from_this_file) =
-                get_markers(&marker_file_path, &[], self.timestamp_converter)
-            {
-                markers.extend(
-                    markers_from_this_file
-                        .into_iter()
-                        .map(|marker| MarkerOnThread {
+            match get_markers(
+                &marker_file_path,
+                &[],
+                self.timestamp_converter,
+                self.profile_creation_props.marker_file_options(),
+            ) {
+                Ok(MarkerFileContents {
+                    markers: markers_from_this_file,
+                    true_path,
+                    report,
+                    counters: counters_from_this_file,
+                }) => {
+                    println!("Marker file {}: {report}", true_path.display());
+                    embed_marker_file_report(profile, &true_path, &report);
+                    source_files.push(true_path);
+                    markers.extend(markers_from_this_file.into_iter().map(|marker| {
+                        MarkerOnThread {
                             thread_handle,
                             event_or_span: marker,
-                        }),
-                );
-                if self.profile_creation_props.unlink_aux_files {
-                    std::fs::remove_file(marker_file_path).ok();
+                        }
+                    }));
+                    counters.extend(counters_from_this_file.into_iter().map(|counter| {
+                        CounterOnThread {
+                            thread_handle,
+                            counter,
+                        }
+                    }));
+                    if self.profile_creation_props.unlink_aux_files {
+                        std::fs::remove_file(marker_file_path).ok();
+                    }
+                }
+                Err(e) if self.profile_creation_props.strict_markers && e.is_parse_error() => {
+                    eprintln!("{e}");
+                    std::process::exit(1);
                 }
+                Err(e) => e.report(),
             }
         }
-        let process_sample_data = ProcessSampleData::new(
+        let mut process_sample_data = ProcessSampleData::new(
             self.unresolved_samples,
             self.lib_mapping_ops,
             jitdump_lib_ops,
             perf_map_mappings,
             markers,
-            vec![],
+            counters,
             self.profile_process,
+            source_files,
         );
+        process_sample_data.set_main_thread(self.main_thread_handle);
 
         let recycling_data = if let (Some(jit_function_recycler), Some(thread_recycler)) =
             (self.jit_function_recycler, self.thread_recycler)
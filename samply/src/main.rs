@@ -14,15 +14,18 @@ mod profile_json_preparse;
 mod server;
 mod shared;
 
+use std::collections::HashMap;
 use std::ffi::OsStr;
 use std::fs::File;
 use std::io::BufReader;
 use std::net::IpAddr;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
 use clap::{Args, Parser, Subcommand, ValueEnum};
+use fxprof_processed_profile::{CategoryColor, ReferenceTimestamp};
 #[cfg(any(target_os = "android", target_os = "linux"))]
 use linux::profiler;
 #[cfg(target_os = "macos")]
@@ -32,12 +35,23 @@ use mac::profiler;
 pub use mac::{kernel_error, thread_act, thread_info};
 use profile_json_preparse::parse_libinfo_map_from_profile_file;
 use server::{start_server_main, PortSelection, ServerProps};
+use shared::address_lookup::symbolicate_addresses;
+use shared::file_inspection::{
+    counter_to_json, inspect_counter_file, inspect_marker_file, marker_to_json,
+};
 use shared::included_processes::IncludedProcesses;
+use shared::log_markers;
+use shared::process_sample_data::{parse_span_category_colors, SpanTableLabel};
 use shared::recording_props::{
-    CoreClrProfileProps, ProcessLaunchProps, ProfileCreationProps, RecordingMode, RecordingProps,
+    ClockSource, CoreClrProfileProps, HwCounter, ProcessLaunchProps, ProfileCreationProps,
+    RecordingMode, RecordingProps, SampleWeightUnit, SchemaValidation, SyntheticTrackNaming,
+};
+use shared::save_profile::{
+    check_marker_schemas, diff_summaries, load_profile_json, save_profile_to_file,
 };
-use shared::save_profile::save_profile_to_file;
+use shared::spill_recovery::recover;
 use shared::symbol_props::SymbolProps;
+use shared::utils::{default_launch_profile_name, read_hex_address_list};
 #[cfg(target_os = "windows")]
 use windows::profiler;
 
@@ -88,6 +102,27 @@ enum Action {
     /// Import a perf.data file and display the profile.
     Import(ImportArgs),
 
+    /// Make a profile from the spill directory of a recording which was
+    /// killed, see `samply record --spill-dir`.
+    Recover(RecoverArgs),
+
+    /// Import a callgrind.out file of Valgrind's Callgrind tool.
+    ImportValgrind(ImportValgrindArgs),
+
+    /// Compare the self weight of each function in two saved profiles, and
+    /// print the functions which got heavier or lighter.
+    Diff(DiffArgs),
+
+    /// Parse a marker file without recording, and print a summary of it.
+    InspectMarkers(InspectArgs),
+
+    /// Parse a counter file without recording, and print a summary of it.
+    InspectCounter(InspectArgs),
+
+    /// Look up the functions of addresses in a binary, e.g. the addresses of a
+    /// crash report, and print one line per address.
+    Symbolicate(SymbolicateArgs),
+
     #[cfg(target_os = "windows")]
     #[clap(hide = true)]
     /// Used in the elevated helper process.
@@ -110,6 +145,62 @@ struct LoadArgs {
     symbol_args: SymbolArgs,
 }
 
+#[derive(Debug, Args)]
+struct RecoverArgs {
+    /// The spill directory of the recording.
+    spill_dir: PathBuf,
+
+    /// Output filename.
+    #[arg(short, long, default_value = "profile.json.gz")]
+    output: PathBuf,
+}
+
+#[derive(Debug, Args)]
+struct DiffArgs {
+    /// The baseline profile.
+    baseline: PathBuf,
+
+    /// The profile to compare against the baseline.
+    new: PathBuf,
+
+    /// The maximum number of regressions and of improvements to print.
+    #[arg(long, default_value = "10")]
+    limit: usize,
+}
+
+#[derive(Debug, Args)]
+struct InspectArgs {
+    /// Path to the file.
+    file: PathBuf,
+
+    /// Print the parsed markers or counter samples as JSON instead of the
+    /// summary, e.g. for diffing the output of two versions of a writer.
+    #[arg(long)]
+    dump_json: bool,
+}
+
+#[derive(Debug, Args)]
+struct SymbolicateArgs {
+    /// Path to the binary or library which the addresses are in.
+    binary: PathBuf,
+
+    /// A text file with one hex address per line, relative to the start of the
+    /// binary, e.g. from awk or perf script. The addresses may have a "0x"
+    /// prefix, and blank lines and lines starting with "#" are skipped.
+    #[arg(long, value_name = "FILE")]
+    addresses: PathBuf,
+}
+
+#[derive(Debug, Args)]
+struct ImportValgrindArgs {
+    /// Path to the callgrind.out file.
+    file: PathBuf,
+
+    /// Output filename.
+    #[arg(short, long, default_value = "profile.json.gz")]
+    output: PathBuf,
+}
+
 #[derive(Debug, Args)]
 struct ImportArgs {
     /// Path to the profile file that should be imported.
@@ -161,6 +252,14 @@ struct ImportArgs {
     time_range: Option<(std::time::Duration, std::time::Duration)>,
 }
 
+fn parse_cpu_track_name(arg: &str) -> Result<String, String> {
+    SyntheticTrackNaming::validate_format(arg, SyntheticTrackNaming::CPU_PLACEHOLDER)
+}
+
+fn parse_category_track_name(arg: &str) -> Result<String, String> {
+    SyntheticTrackNaming::validate_format(arg, SyntheticTrackNaming::CATEGORY_PLACEHOLDER)
+}
+
 #[allow(unused)]
 fn parse_time_range(
     arg: &str,
@@ -258,6 +357,123 @@ struct RecordArgs {
     #[cfg(target_os = "windows")]
     #[arg(long)]
     keep_etl: bool,
+
+    /// Sample call stacks. Use --sample-stacks-only=false to skip stack sampling and
+    /// unwinding, e.g. when only counters and markers are of interest (Linux only).
+    #[arg(long, default_value_t = true, num_args = 0..=1, require_equals = true, default_missing_value = "true", action = clap::ArgAction::Set)]
+    sample_stacks_only: bool,
+
+    /// Add "Disk reads" and "Disk writes" counters, in MB/s, by polling
+    /// /proc/<pid>/io of the profiled processes (Linux only).
+    #[arg(long)]
+    io_counters: bool,
+
+    /// Create a named pipe, /tmp/samply-<pid>.marks, and add an "Annotation"
+    /// marker for every line which is written to it during recording, e.g. with
+    /// `echo "warmup done" > /tmp/samply-1234.marks`. The markers are added to
+    /// the main thread of every profiled process (Linux only).
+    #[arg(long)]
+    annotation_fifo: bool,
+
+    /// Bind a Unix datagram socket at this path, and read markers from it while
+    /// recording. Each datagram is one line in the marker file format,
+    /// `id[,tid] {json}`, and the markers go to the process which sent it. This
+    /// avoids agreeing on marker file paths, and the buffering delays of files
+    /// (Linux only).
+    #[arg(long, value_name = "PATH")]
+    marker_socket: Option<PathBuf>,
+
+    /// Add the warnings which samply logs while recording, e.g. about files it
+    /// couldn't read, as markers on a "samply" track, whether or not RUST_LOG
+    /// shows them (Linux only).
+    #[arg(long)]
+    log_markers: bool,
+
+    /// Add each line which the launched command writes to stderr as a marker on
+    /// the main thread of its process. The lines are still printed (Linux only).
+    #[arg(long)]
+    stderr_markers: bool,
+
+    /// Only keep the samples taken while a span with this name was open in one
+    /// of the profiled processes, according to the lines sent to the marker
+    /// socket. This keeps the profile of a long-running service focused on the
+    /// work of interest (Linux only).
+    #[arg(long, value_name = "NAME", requires = "marker_socket")]
+    record_only_during_span: Option<String>,
+
+    /// How long samples are held back for --record-only-during-span before they
+    /// are dropped, in milliseconds. The marker lines arrive some time after the
+    /// samples they're about; a span whose first line arrives later than this
+    /// loses its beginning.
+    #[arg(long, value_name = "MS", default_value_t = 1000)]
+    span_lookback_ms: u64,
+
+    /// Also profile the processes and threads which the profiled process creates,
+    /// e.g. the compiler invocations of `make -j8`. Only the descendants of the
+    /// profiled process are followed, never unrelated processes. Use --inherit=false
+    /// to profile just the process itself: with inheritance, the kernel has to
+    /// copy the perf events on every fork and thread creation, and all
+    /// descendants write their samples into the profiled process's buffers,
+    /// which adds overhead to fork-heavy workloads (Linux only).
+    #[arg(long, default_value_t = true, num_args = 0..=1, require_equals = true, default_missing_value = "true", action = clap::ArgAction::Set)]
+    inherit: bool,
+
+    /// Don't start recording until Enter is pressed, e.g. to skip the warm-up of a
+    /// benchmark. The process runs unprofiled until then, and nothing it does
+    /// before the trigger ends up in the profile. The launched process shares
+    /// samply's stdin, so this doesn't mix with commands which read from stdin
+    /// (Linux only).
+    #[arg(long)]
+    stdin_trigger: bool,
+
+    /// Append the recording to the profile at the output path as a new run,
+    /// instead of overwriting it. Each run shows up as its own processes, with
+    /// the run index after the pid, and the runs are laid out one after the other
+    /// on the timeline.
+    #[arg(long)]
+    reuse_profile: bool,
+
+    /// What the sample weights measure: "samples", "nanoseconds" or "bytes".
+    /// This is saved in the profile so that the value axis is labeled
+    /// correctly.
+    #[arg(long, value_name = "UNIT", default_value = "samples")]
+    sample_weight_unit: SampleWeightUnit,
+
+    /// Write the oldest samples of each process to a temporary file in this
+    /// directory once the process has more than a million samples in memory,
+    /// and read them back when the process is added to the profile. This keeps
+    /// the memory use of multi-hour recordings in check (Linux only). If
+    /// samply is killed, `samply recover` makes a profile from the spilled
+    /// samples.
+    #[arg(long, value_name = "PATH")]
+    spill_dir: Option<PathBuf>,
+
+    /// Also sample a hardware performance counter: "cache-misses",
+    /// "branch-misses", "instructions" or "cycles". Can be given more than once.
+    /// Each counter gets a track per process, and each counter sample becomes a
+    /// marker with the stack of the thread's latest CPU sample. Hardware counters
+    /// need a CPU with a PMU which the kernel exposes, so they usually don't work
+    /// in VMs, and they need '/proc/sys/kernel/perf_event_paranoid' to be 1 or
+    /// lower, or CAP_PERFMON / CAP_SYS_ADMIN (Linux only).
+    #[arg(long = "hw-counter", value_name = "EVENT")]
+    hw_counters: Vec<HwCounter>,
+
+    /// Hardware performance counters to read with each CPU sample, separated
+    /// by commas, e.g. "instructions,cache-misses". Each thread gets a track
+    /// per counter with its growth between samples, and an "IPC" track with
+    /// the instructions per cycle if the samples are driven by CPU cycles.
+    /// Counts are scaled up when the CPU has to share its counters among
+    /// events. Before Linux 6.12, this needs --inherit=false (Linux only).
+    #[arg(long, value_name = "EVENTS", value_delimiter = ',')]
+    read_counters: Vec<HwCounter>,
+
+    /// The event which drives the CPU sampling: "hardware" or "cycles" for
+    /// CPU cycles, which is more precise on bare metal, or "software" or
+    /// "cpu-clock" for the CPU clock timer. By default, CPU cycles are used if
+    /// they're available, and the CPU clock otherwise. If CPU cycles aren't
+    /// available, samply warns and uses the CPU clock (Linux only).
+    #[arg(long, alias = "event", value_name = "SOURCE")]
+    cpu_clock_source: Option<ClockSource>,
 }
 
 #[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
@@ -331,6 +547,11 @@ struct SymbolArgs {
     /// Extra directory containing symbol files, with the directory structure used by simpleperf's scripts
     #[arg(long)]
     simpleperf_binary_cache: Option<PathBuf>,
+
+    /// A file with names for addresses which can't be symbolicated otherwise, one
+    /// "<library>+0x<offset> <name>" or "0x<address> <name>" entry per line
+    #[arg(long)]
+    symbol_override_file: Option<PathBuf>,
 }
 
 #[derive(Debug, Args, Clone)]
@@ -343,10 +564,17 @@ pub struct SetupArgs {
 #[derive(Debug, Args, Clone)]
 pub struct ProfileCreationArgs {
     /// Set a custom name for the recorded profile.
-    /// By default it is either the command that was run or the process pid.
+    /// By default it is either the command that was run, followed by the host
+    /// name and the `git describe` of the current directory, or the process pid.
     #[arg(long)]
     profile_name: Option<String>,
 
+    /// Show the profile as starting at this time, in UTC, like
+    /// "2024-05-01T12:00:00Z", instead of at the time it was recorded. This
+    /// only changes the displayed wall-clock time, not the timeline.
+    #[arg(long, value_name = "RFC3339", value_parser = humantime::parse_rfc3339)]
+    profile_start_time: Option<std::time::SystemTime>,
+
     /// Only include the main thread of each process in order to reduce profile size,
     /// only respected on Windows and macOS
     #[arg(long)]
@@ -356,6 +584,12 @@ pub struct ProfileCreationArgs {
     #[arg(long)]
     reuse_threads: bool,
 
+    /// With --reuse-threads, only merge the JIT functions of different runs if
+    /// their machine code is identical. By default, functions with the same
+    /// name, size and first bytes of code are merged.
+    #[arg(long, requires = "reuse_threads")]
+    exact_jit_function_reuse: bool,
+
     /// Fold repeated frames at the base of the stack.
     #[arg(long)]
     fold_recursive_prefix: bool,
@@ -379,6 +613,25 @@ pub struct ProfileCreationArgs {
     #[arg(long)]
     cswitch_markers: bool,
 
+    /// Link a thread which wakes up a thread of another process, e.g. by
+    /// writing to a socket or a pipe which the other thread waits on, to the
+    /// woken thread, with an "IPC send" marker at the waker's stack and an
+    /// "IPC receive" marker at the stack the woken thread waited in. Needs a
+    /// perf.data file with sched:sched_waking and sched:sched_switch samples
+    /// with call stacks (Linux perf.data import only).
+    #[arg(long)]
+    ipc_edges: bool,
+
+    /// On the per-CPU tracks (see --per-cpu-threads), emit one marker for each
+    /// run of a thread on the CPU, instead of a marker for every context switch.
+    #[arg(long)]
+    cpu_run_intervals: bool,
+
+    /// With --cpu-run-intervals, combine the runs shorter than this many
+    /// microseconds into one "(short runs)" marker per CPU and second.
+    #[arg(long, value_name = "MICROSECONDS", default_value = "100")]
+    min_cpu_run_us: u64,
+
     /// Emit markers for mmap events.
     #[arg(long)]
     mmap_markers: bool,
@@ -387,6 +640,164 @@ pub struct ProfileCreationArgs {
     #[arg(long)]
     markers_on_profiled_thread: bool,
 
+    /// Record the paths of the marker and counter files which fed the profile
+    /// in the profile metadata.
+    #[arg(long)]
+    embed_source_files: bool,
+
+    /// Give samples which fall inside a span of this category the span's category,
+    /// so that the time spent in such spans stands out in the timeline
+    /// (can be specified multiple times).
+    #[arg(long, value_name = "CATEGORY")]
+    categorize_samples_by_span: Vec<String>,
+
+    /// Emit all events from marker files as "Log" markers, with the extra
+    /// fields combined into one JSON string, instead of a separate marker type
+    /// for each combination of fields.
+    #[arg(long)]
+    simple_event_markers: bool,
+
+    /// Infer the types of the extra fields of each marker file from its first
+    /// 100 lines, and show integer and float fields as numbers instead of
+    /// strings, for better sorting and filtering.
+    #[arg(long)]
+    infer_marker_field_types: bool,
+
+    /// Keep the source line of each marker from a marker file, truncated to
+    /// 1024 bytes, in a non-searchable "__raw" field, for debugging markers
+    /// which look wrong.
+    #[arg(long)]
+    raw_marker_lines: bool,
+
+    /// Exit with an error at the first marker file line which would otherwise be
+    /// skipped or warned about, such as a line which isn't JSON or a "close" line
+    /// for a span which was never created. The error has the file, the line
+    /// number and the start of the line. Meant for validating the program which
+    /// writes the marker files, e.g. in CI.
+    #[arg(long)]
+    strict_markers: bool,
+
+    /// Warn about the spans in a marker file which were never closed or
+    /// exited only if there are more than this many. A few are normal for
+    /// spans which are still open when the profiled program is stopped.
+    #[arg(long, value_name = "COUNT", default_value = "10")]
+    marker_orphan_warning_threshold: usize,
+
+    /// Truncate string fields of marker file events and spans, such as long
+    /// messages, to this many bytes, so that they don't slow down the profiler
+    /// UI. The full values are kept in a separate, non-searchable field.
+    #[arg(long, value_name = "BYTES", default_value = "1024")]
+    max_marker_string_length: usize,
+
+    /// What the marker table shows for spans from marker files: "name" for just
+    /// the span's name, "timings" to add its busy and idle times, or a label
+    /// template like "{marker.data.name} ({marker.data.time_busy})".
+    #[arg(long, value_name = "LABEL", default_value = "name")]
+    span_table_label: SpanTableLabel,
+
+    /// A JSON file which maps span category names to colors, like
+    /// {"Compaction": "blue", "Scan": "lightred"}. Categories which aren't
+    /// listed get a color picked based on their name.
+    #[arg(long, value_name = "FILE")]
+    span_category_colors: Option<PathBuf>,
+
+    /// Put the markers from marker files on one track per category, instead of
+    /// on the thread which emitted them: spans by their category, events by
+    /// their target. Each process gets a "<category> markers" track for each
+    /// category.
+    #[arg(long)]
+    category_marker_tracks: bool,
+
+    /// Add a "Samples" counter with the number of samples per window of this
+    /// many milliseconds, to diagnose uneven sampling.
+    #[arg(long, value_name = "MILLISECONDS", num_args = 0..=1, require_equals = true, default_missing_value = "10")]
+    sample_rate_counter: Option<u64>,
+
+    /// Add a counter per span category of the marker files, with the busy
+    /// milliseconds of all the category's spans which have ended by each point in
+    /// time, to spot when the cost of a subsystem ramps up.
+    #[arg(long)]
+    span_busy_counters: bool,
+
+    /// Give the counters of each category only this many tracks per process,
+    /// including an "Other <category>" track with the sum of the counters
+    /// with the lowest peak values. Custom counters aren't capped. Counter
+    /// files with thousands of counters otherwise overwhelm the UI.
+    #[arg(long, value_name = "N")]
+    max_counter_tracks: Option<usize>,
+
+    /// Show the spans of this category from the marker files of one process,
+    /// the first one which has any, e.g. the GC or compaction phases of a
+    /// coordinator, as "Global phase" markers on the main thread of every
+    /// process and on the per-CPU tracks, so that they line up with everything
+    /// else.
+    #[arg(long, value_name = "CATEGORY")]
+    global_phase_category: Option<String>,
+
+    /// Attribute sample addresses which aren't in any library mapping to a
+    /// library mapped within this many bytes of them, for recordings where the
+    /// exact ASLR slide of a binary isn't in the recorded data. Such frames can
+    /// be symbolicated as the wrong function, so keep this smaller than the
+    /// gaps between libraries.
+    #[arg(long, value_name = "BYTES")]
+    aslr_tolerance: Option<u64>,
+
+    /// Print the span stats of the marker files for each window of this many
+    /// seconds, and start over, instead of printing them once for the whole
+    /// recording. This keeps the stats of long recordings readable.
+    #[arg(long, value_name = "SECONDS")]
+    stats_flush_interval_secs: Option<u64>,
+
+    /// Check the schemas of all marker types against the rules of the Firefox
+    /// Profiler's marker schema format before saving the profile, and print the
+    /// problems, such as a graph of a duration field, which the front-end can't
+    /// display. With "strict", a problem is an error and no profile is saved.
+    #[arg(long, value_name = "MODE", num_args = 0..=1, require_equals = true, default_missing_value = "warn")]
+    validate_schemas: Option<SchemaValidation>,
+
+    /// Put this in front of the names of the tracks which samply adds for
+    /// things other than threads, i.e. the per-CPU tracks and the marker
+    /// category tracks, so that they can't be mistaken for real threads.
+    #[arg(long, value_name = "PREFIX", default_value = "")]
+    synthetic_track_prefix: String,
+
+    /// The name of each per-CPU track, where {cpu} is the CPU number. Unused
+    /// if the CPU topology is known, then the tracks are named after cores.
+    #[arg(long, value_name = "FORMAT", default_value = "CPU {cpu}", value_parser = parse_cpu_track_name)]
+    cpu_track_name: String,
+
+    /// The name of each marker category track of --category-marker-tracks,
+    /// where {category} is the category.
+    #[arg(long, value_name = "FORMAT", default_value = "{category} markers", value_parser = parse_category_track_name)]
+    category_track_name: String,
+
+    /// Also write the samples' stacks to this file, in the folded format which
+    /// flamegraph tools such as inferno-flamegraph read: one line per distinct
+    /// stack of each process, with the frames from root to leaf separated by
+    /// ";", and the summed sample weight. The frames aren't symbolicated, they
+    /// are library-relative addresses like "libxul.so+0x1234".
+    #[arg(long, value_name = "FILE")]
+    folded_stacks: Option<PathBuf>,
+
+    /// Print the <N> functions with the most samples in which they are the
+    /// leaf frame, for each process, when the profile is created. Like with
+    /// --folded-stacks, functions are library-relative addresses.
+    #[arg(long, value_name = "N")]
+    top_functions: Option<usize>,
+
+    /// Keep only every <N>th sample of each thread, and count it N times, to
+    /// shrink the profiles of long recordings. The call tree keeps its shape,
+    /// but the timeline loses detail.
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    downsample: Option<u32>,
+
+    /// Print how long each phase of creating and saving the profile took, and
+    /// the peak memory usage of samply's buffers during the recording. On
+    /// Linux, sending SIGUSR2 to samply prints the current memory usage at any
+    /// time.
+    #[arg(long)]
+    time_report: bool,
+
     /// Include up to <INCLUDE_ARGS> command line arguments in the process name.
     /// This can help differentiate processes if the same executable is used
     /// for different types of programs. And in --reuse-threads mode it
@@ -418,7 +829,7 @@ struct RunElevatedHelperArgs {
 }
 
 fn main() {
-    env_logger::init();
+    log_markers::init();
 
     let opt = Opt::parse();
     match opt.action {
@@ -476,6 +887,138 @@ fn main() {
             }
         }
 
+        Action::Recover(recover_args) => {
+            let recovered = match recover(&recover_args.spill_dir) {
+                Ok(recovered) => recovered,
+                Err(err) => {
+                    eprintln!("{err}");
+                    std::process::exit(1)
+                }
+            };
+            if recovered.truncated {
+                eprintln!("Skipped the records which were cut off when samply was stopped.");
+            }
+            if recovered.dropped_sample_count > 0 {
+                eprintln!(
+                    "Dropped {} samples whose thread or stack wasn't in the manifest yet.",
+                    recovered.dropped_sample_count
+                );
+            }
+            if let Err(err) = save_profile_to_file(&recovered.profile, &recover_args.output) {
+                eprintln!("Couldn't write {:?}: {err}", recover_args.output);
+                std::process::exit(1)
+            }
+            eprintln!(
+                "Recovered {} samples into {:?}.",
+                recovered.sample_count, recover_args.output
+            );
+        }
+
+        Action::ImportValgrind(import_args) => {
+            let data = match File::open(&import_args.file) {
+                Ok(file) => import::callgrind::parse_callgrind(BufReader::new(file)),
+                Err(err) => {
+                    eprintln!("Could not open file {:?}: {}", import_args.file, err);
+                    std::process::exit(1)
+                }
+            };
+            let data = match data {
+                Ok(data) => data,
+                Err(err) => {
+                    eprintln!("Couldn't parse {:?}: {err}", import_args.file);
+                    std::process::exit(1)
+                }
+            };
+            let reference_timestamp = std::fs::metadata(&import_args.file)
+                .and_then(|metadata| metadata.modified())
+                .map(ReferenceTimestamp::from_system_time)
+                .unwrap_or_else(|_| ReferenceTimestamp::from_millis_since_unix_epoch(0.0));
+            let imported = import::callgrind::convert(&data, reference_timestamp);
+            if let Err(err) = save_profile_to_file(&imported.profile, &import_args.output) {
+                eprintln!("Couldn't write {:?}: {err}", import_args.output);
+                std::process::exit(1)
+            }
+            eprintln!(
+                "Imported {} {} into {} samples of {} each in {:?}.",
+                imported.total_cost,
+                data.events.first().map_or("instructions", String::as_str),
+                imported.sample_count,
+                import::callgrind::COST_PER_WEIGHT,
+                import_args.output
+            );
+        }
+
+        Action::Diff(diff_args) => {
+            let [baseline, new] =
+                [&diff_args.baseline, &diff_args.new].map(|path| match load_profile_json(path) {
+                    Ok(profile) => profile,
+                    Err(err) => {
+                        eprintln!("Couldn't read {path:?}: {err}");
+                        std::process::exit(1)
+                    }
+                });
+            let mut report = diff_summaries(&baseline, &new);
+            report.regressions.truncate(diff_args.limit);
+            report.improvements.truncate(diff_args.limit);
+            print!("{report}");
+        }
+
+        Action::InspectMarkers(inspect_args) => {
+            let (summary, markers) = match inspect_marker_file(&inspect_args.file) {
+                Ok(result) => result,
+                Err(err) => {
+                    eprintln!("{err}");
+                    std::process::exit(1)
+                }
+            };
+            if inspect_args.dump_json {
+                let markers: Vec<_> = markers.iter().map(marker_to_json).collect();
+                println!("{}", serde_json::to_string_pretty(&markers).unwrap());
+            } else {
+                print!("{summary}");
+            }
+        }
+
+        Action::Symbolicate(symbolicate_args) => {
+            let addresses = match read_hex_address_list(&symbolicate_args.addresses) {
+                Ok(addresses) => addresses,
+                Err(err) => {
+                    eprintln!("Couldn't read {:?}: {err}", symbolicate_args.addresses);
+                    std::process::exit(1)
+                }
+            };
+            match symbolicate_addresses(&symbolicate_args.binary, &addresses) {
+                Ok(symbolicated) => {
+                    for address in symbolicated {
+                        println!("{address}");
+                    }
+                }
+                Err(err) => {
+                    eprintln!(
+                        "Couldn't load the symbols of {:?}: {err}",
+                        symbolicate_args.binary
+                    );
+                    std::process::exit(1)
+                }
+            }
+        }
+
+        Action::InspectCounter(inspect_args) => {
+            let (summary, counter) = match inspect_counter_file(&inspect_args.file) {
+                Ok(result) => result,
+                Err(err) => {
+                    eprintln!("{err}");
+                    std::process::exit(1)
+                }
+            };
+            if inspect_args.dump_json {
+                let counter = counter_to_json(&counter);
+                println!("{}", serde_json::to_string_pretty(&counter).unwrap());
+            } else {
+                print!("{summary}");
+            }
+        }
+
         #[cfg(any(
             target_os = "android",
             target_os = "macos",
@@ -520,6 +1063,16 @@ fn main() {
     }
 }
 
+impl ProfileCreationArgs {
+    fn synthetic_track_naming(&self) -> SyntheticTrackNaming {
+        SyntheticTrackNaming {
+            prefix: self.synthetic_track_prefix.clone(),
+            cpu_format: self.cpu_track_name.clone(),
+            category_format: self.category_track_name.clone(),
+        }
+    }
+}
+
 impl LoadArgs {
     fn server_props(&self) -> ServerProps {
         self.server_args.server_props()
@@ -549,8 +1102,10 @@ impl ImportArgs {
         ProfileCreationProps {
             profile_name: self.profile_creation_args.profile_name.clone(),
             fallback_profile_name,
+            profile_start_time: self.profile_creation_args.profile_start_time,
             main_thread_only: self.profile_creation_args.main_thread_only,
             reuse_threads: self.profile_creation_args.reuse_threads,
+            exact_jit_function_reuse: self.profile_creation_args.exact_jit_function_reuse,
             fold_recursive_prefix: self.profile_creation_args.fold_recursive_prefix,
             unlink_aux_files: self.profile_creation_args.unlink_aux_files,
             create_per_cpu_threads: self.profile_creation_args.per_cpu_threads,
@@ -559,10 +1114,54 @@ impl ImportArgs {
             unstable_presymbolicate: self.profile_creation_args.unstable_presymbolicate,
             should_emit_jit_markers: self.profile_creation_args.jit_markers,
             should_emit_cswitch_markers: self.profile_creation_args.cswitch_markers,
+            ipc_edges: self.profile_creation_args.ipc_edges,
+            cpu_run_intervals: self
+                .profile_creation_args
+                .cpu_run_intervals
+                .then(|| Duration::from_micros(self.profile_creation_args.min_cpu_run_us)),
             should_emit_mmap_markers: self.profile_creation_args.mmap_markers,
             attach_markers_to_profiled_thread: self
                 .profile_creation_args
                 .markers_on_profiled_thread,
+            embed_source_files: self.profile_creation_args.embed_source_files,
+            time_report: self.profile_creation_args.time_report,
+            folded_stacks_file: create_folded_stacks_file(
+                self.profile_creation_args.folded_stacks.as_deref(),
+            ),
+            downsample_factor: self.profile_creation_args.downsample,
+            top_functions: self.profile_creation_args.top_functions,
+            categorize_samples_by_span: self
+                .profile_creation_args
+                .categorize_samples_by_span
+                .clone(),
+            simple_event_markers: self.profile_creation_args.simple_event_markers,
+            infer_marker_field_types: self.profile_creation_args.infer_marker_field_types,
+            raw_marker_lines: self.profile_creation_args.raw_marker_lines,
+            strict_markers: self.profile_creation_args.strict_markers,
+            marker_orphan_warning_threshold: self
+                .profile_creation_args
+                .marker_orphan_warning_threshold,
+            max_marker_string_len: self.profile_creation_args.max_marker_string_length,
+            span_table_label: self.profile_creation_args.span_table_label.clone(),
+            span_category_colors: load_span_category_colors(
+                self.profile_creation_args.span_category_colors.as_deref(),
+            ),
+            category_marker_tracks: self.profile_creation_args.category_marker_tracks,
+            sample_rate_counter_window: self
+                .profile_creation_args
+                .sample_rate_counter
+                .map(Duration::from_millis),
+            span_busy_counters: self.profile_creation_args.span_busy_counters,
+            max_counter_tracks: self.profile_creation_args.max_counter_tracks,
+            global_phase_category: self.profile_creation_args.global_phase_category.clone(),
+            aslr_tolerance: self.profile_creation_args.aslr_tolerance,
+            validate_schemas: self.profile_creation_args.validate_schemas,
+            synthetic_track_naming: self.profile_creation_args.synthetic_track_naming(),
+            stats_flush_interval: self
+                .profile_creation_args
+                .stats_flush_interval_secs
+                .map(Duration::from_secs),
+            custom_marker_sources: Default::default(),
             coreclr: to_coreclr_profile_props(&self.coreclr),
             #[cfg(target_os = "windows")]
             unknown_event_markers: self.profile_creation_args.unknown_event_markers,
@@ -627,6 +1226,23 @@ impl RecordArgs {
             keep_etl: self.keep_etl,
             #[cfg(not(target_os = "windows"))]
             keep_etl: false,
+            sample_stacks: self.sample_stacks_only,
+            io_counters: self.io_counters,
+            annotation_fifo: self.annotation_fifo,
+            marker_socket: self.marker_socket.clone(),
+            log_markers: self.log_markers,
+            stderr_markers: self.stderr_markers,
+            inherit: self.inherit,
+            sample_callback: None,
+            stdin_trigger: self.stdin_trigger,
+            reuse_profile: self.reuse_profile,
+            sample_weight_unit: self.sample_weight_unit,
+            spill_dir: self.spill_dir.clone(),
+            record_only_during_span: self.record_only_during_span.clone(),
+            span_lookback: Duration::from_millis(self.span_lookback_ms),
+            hw_counters: self.hw_counters.clone(),
+            read_counters: self.read_counters.clone(),
+            cpu_clock_source: self.cpu_clock_source,
         }
     }
 
@@ -665,21 +1281,27 @@ impl RecordArgs {
     }
 
     pub fn profile_creation_props(&self) -> ProfileCreationProps {
+        let mut profile_name = self.profile_creation_args.profile_name.clone();
         let fallback_profile_name = match self.recording_mode() {
             RecordingMode::All => "All processes".to_string(),
             RecordingMode::Pid(pid) => format!("PID {pid}"),
             RecordingMode::Launch(launch_props) => {
-                let filename = Path::new(&launch_props.command_name)
+                let filename: String = Path::new(&launch_props.command_name)
                     .file_name()
-                    .unwrap_or(launch_props.command_name.as_os_str());
-                filename.to_string_lossy().into()
+                    .unwrap_or(launch_props.command_name.as_os_str())
+                    .to_string_lossy()
+                    .into();
+                profile_name.get_or_insert_with(|| default_launch_profile_name(&filename));
+                filename
             }
         };
         ProfileCreationProps {
-            profile_name: self.profile_creation_args.profile_name.clone(),
+            profile_name,
             fallback_profile_name,
+            profile_start_time: self.profile_creation_args.profile_start_time,
             main_thread_only: self.profile_creation_args.main_thread_only,
             reuse_threads: self.profile_creation_args.reuse_threads,
+            exact_jit_function_reuse: self.profile_creation_args.exact_jit_function_reuse,
             fold_recursive_prefix: self.profile_creation_args.fold_recursive_prefix,
             unlink_aux_files: self.profile_creation_args.unlink_aux_files,
             create_per_cpu_threads: self.profile_creation_args.per_cpu_threads,
@@ -688,10 +1310,54 @@ impl RecordArgs {
             unstable_presymbolicate: self.profile_creation_args.unstable_presymbolicate,
             should_emit_jit_markers: self.profile_creation_args.jit_markers,
             should_emit_cswitch_markers: self.profile_creation_args.cswitch_markers,
+            ipc_edges: self.profile_creation_args.ipc_edges,
+            cpu_run_intervals: self
+                .profile_creation_args
+                .cpu_run_intervals
+                .then(|| Duration::from_micros(self.profile_creation_args.min_cpu_run_us)),
             should_emit_mmap_markers: self.profile_creation_args.mmap_markers,
             attach_markers_to_profiled_thread: self
                 .profile_creation_args
                 .markers_on_profiled_thread,
+            embed_source_files: self.profile_creation_args.embed_source_files,
+            time_report: self.profile_creation_args.time_report,
+            folded_stacks_file: create_folded_stacks_file(
+                self.profile_creation_args.folded_stacks.as_deref(),
+            ),
+            downsample_factor: self.profile_creation_args.downsample,
+            top_functions: self.profile_creation_args.top_functions,
+            categorize_samples_by_span: self
+                .profile_creation_args
+                .categorize_samples_by_span
+                .clone(),
+            simple_event_markers: self.profile_creation_args.simple_event_markers,
+            infer_marker_field_types: self.profile_creation_args.infer_marker_field_types,
+            raw_marker_lines: self.profile_creation_args.raw_marker_lines,
+            strict_markers: self.profile_creation_args.strict_markers,
+            marker_orphan_warning_threshold: self
+                .profile_creation_args
+                .marker_orphan_warning_threshold,
+            max_marker_string_len: self.profile_creation_args.max_marker_string_length,
+            span_table_label: self.profile_creation_args.span_table_label.clone(),
+            span_category_colors: load_span_category_colors(
+                self.profile_creation_args.span_category_colors.as_deref(),
+            ),
+            category_marker_tracks: self.profile_creation_args.category_marker_tracks,
+            sample_rate_counter_window: self
+                .profile_creation_args
+                .sample_rate_counter
+                .map(Duration::from_millis),
+            span_busy_counters: self.profile_creation_args.span_busy_counters,
+            max_counter_tracks: self.profile_creation_args.max_counter_tracks,
+            global_phase_category: self.profile_creation_args.global_phase_category.clone(),
+            aslr_tolerance: self.profile_creation_args.aslr_tolerance,
+            validate_schemas: self.profile_creation_args.validate_schemas,
+            synthetic_track_naming: self.profile_creation_args.synthetic_track_naming(),
+            stats_flush_interval: self
+                .profile_creation_args
+                .stats_flush_interval_secs
+                .map(Duration::from_secs),
+            custom_marker_sources: Default::default(),
             coreclr: to_coreclr_profile_props(&self.coreclr),
             #[cfg(target_os = "windows")]
             unknown_event_markers: self.profile_creation_args.unknown_event_markers,
@@ -747,6 +1413,40 @@ impl SymbolArgs {
             breakpad_symbol_dir: self.breakpad_symbol_dir.clone(),
             breakpad_symbol_cache: self.breakpad_symbol_cache.clone(),
             simpleperf_binary_cache: self.simpleperf_binary_cache.clone(),
+            symbol_override_file: self.symbol_override_file.clone(),
+        }
+    }
+}
+
+fn load_span_category_colors(path: Option<&Path>) -> HashMap<String, CategoryColor> {
+    let Some(path) = path else {
+        return HashMap::new();
+    };
+    let colors = std::fs::read_to_string(path)
+        .map_err(|e| e.to_string())
+        .and_then(|json| parse_span_category_colors(&json));
+    match colors {
+        Ok(colors) => colors,
+        Err(e) => {
+            eprintln!(
+                "Error: could not read span category colors from {}: {e}",
+                path.display()
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
+fn create_folded_stacks_file(path: Option<&Path>) -> Option<Arc<Mutex<File>>> {
+    let path = path?;
+    match File::create(path) {
+        Ok(file) => Some(Arc::new(Mutex::new(file))),
+        Err(e) => {
+            eprintln!(
+                "Error: could not create the folded stacks file {}: {e}",
+                path.display()
+            );
+            std::process::exit(1);
         }
     }
 }
@@ -831,21 +1531,33 @@ fn convert_perf_data_file_to_profile(input_file: &File, import_args: &ImportArgs
         binary_lookup_dirs.push(parent_dir.into());
         aux_file_lookup_dirs.push(parent_dir.into());
     }
+    let time_report_enabled = profile_creation_props.time_report;
+    let validate_schemas = profile_creation_props.validate_schemas;
     let reader = BufReader::new(input_file);
-    let profile = match import::perf::convert(
+    let (profile, mut time_report) = match import::perf::convert(
         reader,
         file_mod_time,
         binary_lookup_dirs,
         aux_file_lookup_dirs,
         profile_creation_props,
     ) {
-        Ok(profile) => profile,
+        Ok(result) => result,
         Err(error) => {
             eprintln!("Error importing perf.data file: {:?}", error);
             std::process::exit(1);
         }
     };
-    save_profile_to_file(&profile, &import_args.output).expect("Couldn't write JSON");
+    if let Some(validation) = validate_schemas {
+        if !check_marker_schemas(&profile, validation) {
+            std::process::exit(1);
+        }
+    }
+    time_report.time("Saving profile", || {
+        save_profile_to_file(&profile, &import_args.output).expect("Couldn't write JSON")
+    });
+    if time_report_enabled {
+        time_report.print();
+    }
 }
 
 #[cfg(test)]
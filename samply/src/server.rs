@@ -27,6 +27,7 @@ use crate::name::SAMPLY_NAME;
 use crate::shared;
 use crate::shared::ctrl_c::CtrlC;
 use crate::shared::symbol_manager_observer::SamplySymbolManagerObserver;
+use crate::shared::symbol_overrides::SymbolOverrides;
 use crate::shared::symbol_props::SymbolProps;
 
 #[derive(Clone, Debug)]
@@ -194,6 +195,15 @@ async fn start_server(
 
     let template_values = Arc::new(template_values);
 
+    let symbol_overrides = match &symbol_props.symbol_override_file {
+        Some(path) => SymbolOverrides::load(path).unwrap_or_else(|e| {
+            eprintln!("Ignoring the symbol override file: {e}");
+            SymbolOverrides::default()
+        }),
+        None => SymbolOverrides::default(),
+    };
+    let symbol_overrides = Arc::new(symbol_overrides);
+
     let (config, quota_manager) = create_symbol_manager_config_and_quota_manager(symbol_props);
     let mut symbol_manager = SymbolManager::with_config(config);
     let notifiers = match &quota_manager {
@@ -241,6 +251,7 @@ async fn start_server(
     let server = tokio::task::spawn(run_server(
         listener,
         symbol_manager,
+        symbol_overrides,
         profile_filename.map(PathBuf::from),
         template_values,
         path_prefix,
@@ -346,6 +357,7 @@ const TEMPLATE_WITHOUT_PROFILE: &str = r#"
 async fn run_server(
     listener: TcpListener,
     symbol_manager: Arc<SymbolManager>,
+    symbol_overrides: Arc<SymbolOverrides>,
     profile_filename: Option<PathBuf>,
     template_values: Arc<HashMap<&'static str, String>>,
     path_prefix: String,
@@ -366,6 +378,7 @@ async fn run_server(
         let io = TokioIo::new(stream);
 
         let symbol_manager = symbol_manager.clone();
+        let symbol_overrides = symbol_overrides.clone();
         let profile_filename = profile_filename.clone();
         let template_values = template_values.clone();
         let path_prefix = path_prefix.clone();
@@ -382,6 +395,7 @@ async fn run_server(
                             req,
                             template_values.clone(),
                             symbol_manager.clone(),
+                            symbol_overrides.clone(),
                             profile_filename.clone(),
                             path_prefix.clone(),
                         )
@@ -399,6 +413,7 @@ async fn symbolication_service(
     req: Request<hyper::body::Incoming>,
     template_values: Arc<HashMap<&'static str, String>>,
     symbol_manager: Arc<SymbolManager>,
+    symbol_overrides: Arc<SymbolOverrides>,
     profile_filename: Option<PathBuf>,
     path_prefix: String,
 ) -> Result<Response<Either<String, BoxBody<Bytes, std::io::Error>>>, hyper::Error> {
@@ -506,7 +521,10 @@ async fn symbolication_service(
             // Convert the `Collected<Bytes>` into a `String`.
             let full_body =
                 String::from_utf8(full_body.to_bytes().to_vec()).expect("invalid utf-8");
-            let response_json = symbol_manager.query_json_api(&path, &full_body).await;
+            let mut response_json = symbol_manager.query_json_api(&path, &full_body).await;
+            if path == "/symbolicate/v5" {
+                response_json = symbol_overrides.apply_to_symbolication_response(response_json);
+            }
 
             *response.body_mut() = Either::Left(response_json);
         }
@@ -0,0 +1,102 @@
+use std::fmt;
+use std::path::Path;
+
+use wholesym::{AddressInfo, LookupAddress, SymbolManager, SymbolManagerConfig};
+
+/// An address of `samply symbolicate`, with the symbol it was found in.
+#[derive(Debug, Clone)]
+pub struct SymbolicatedAddress {
+    pub address: u64,
+    pub info: Option<AddressInfo>,
+}
+
+impl fmt::Display for SymbolicatedAddress {
+    /// `0x<address> <function>+0x<offset>`, followed by the file and line of
+    /// the innermost frame if the binary has debug info, or `??` if no
+    /// symbol contains the address.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#x} ", self.address)?;
+        let Some(info) = &self.info else {
+            return write!(f, "??");
+        };
+        let offset = self.address - u64::from(info.symbol.address);
+        write!(f, "{}+{offset:#x}", info.symbol.name)?;
+        let innermost_frame = info.frames.as_ref().and_then(|frames| frames.first());
+        if let Some(frame) = innermost_frame {
+            if let (Some(file_path), Some(line)) = (&frame.file_path, frame.line_number) {
+                write!(f, " ({}:{line})", file_path.display_path())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Looks up `addresses`, which are relative to the start of the binary at
+/// `binary_path`, in its symbols and debug info.
+pub fn symbolicate_addresses(
+    binary_path: &Path,
+    addresses: &[u64],
+) -> Result<Vec<SymbolicatedAddress>, wholesym::Error> {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let symbol_manager = SymbolManager::with_config(SymbolManagerConfig::new());
+    rt.block_on(async {
+        let symbol_map = symbol_manager
+            .load_symbol_map_for_binary_at_path(binary_path, None)
+            .await?;
+        let mut symbolicated = Vec::new();
+        for &address in addresses {
+            // Relative addresses beyond 4GB can't be in any symbol.
+            let info = match u32::try_from(address) {
+                Ok(rva) => symbol_map.lookup(LookupAddress::Relative(rva)).await,
+                Err(_) => None,
+            };
+            symbolicated.push(SymbolicatedAddress { address, info });
+        }
+        Ok(symbolicated)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use wholesym::{FrameDebugInfo, SourceFilePath, SymbolInfo};
+
+    use super::*;
+
+    #[test]
+    fn symbolicated_addresses_show_the_offset_and_the_source_line() {
+        let symbol = SymbolInfo {
+            address: 0x1000,
+            size: Some(0x40),
+            name: "parse_args".to_string(),
+        };
+        let frame = FrameDebugInfo {
+            function: Some("parse_args".to_string()),
+            file_path: Some(SourceFilePath::new("src/main.rs".to_string(), None)),
+            line_number: Some(12),
+        };
+        let with_debug_info = SymbolicatedAddress {
+            address: 0x1010,
+            info: Some(AddressInfo {
+                symbol: symbol.clone(),
+                frames: Some(vec![frame]),
+            }),
+        };
+        assert_eq!(
+            with_debug_info.to_string(),
+            "0x1010 parse_args+0x10 (src/main.rs:12)"
+        );
+        let without_debug_info = SymbolicatedAddress {
+            address: 0x1000,
+            info: Some(AddressInfo {
+                symbol,
+                frames: None,
+            }),
+        };
+        assert_eq!(without_debug_info.to_string(), "0x1000 parse_args+0x0");
+        let unknown = SymbolicatedAddress {
+            address: 0x2a,
+            info: None,
+        };
+        assert_eq!(unknown.to_string(), "0x2a ??");
+    }
+}
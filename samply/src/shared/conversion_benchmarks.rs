@@ -0,0 +1,183 @@
+//! Timing tests for the phases of the conversion pipeline, on synthetic data
+//! sized like large real-world recordings.
+//!
+//! These are ignored by default. Run them with
+//! `cargo test --release -p samply conversion_benchmarks -- --ignored --nocapture`.
+//! They print the time each phase takes rather than asserting on it, because
+//! wall-clock times depend on the machine.
+
+use std::path::Path;
+use std::time::Instant;
+
+use fxprof_processed_profile::CategoryColor;
+
+use super::lib_mappings::LibMappingsHierarchy;
+use super::marker_file::MarkerFile;
+use super::process_sample_data::{FlushOptions, ProcessSampleData};
+use super::stack_converter::StackConverter;
+use super::symbol_precog::SymbolPrecog;
+use super::test_support::*;
+use super::unresolved_samples::UnresolvedStacks;
+
+fn run_timed(name: &str, f: impl FnOnce()) {
+    let start = Instant::now();
+    f();
+    eprintln!("{name}: {:.3?}", start.elapsed());
+}
+
+#[test]
+#[ignore]
+fn flush_one_million_samples_across_eight_threads() {
+    let mut profile = new_profile();
+    let (process, threads) = add_process_with_threads(&mut profile, 8);
+    let lib = add_synthetic_lib(&mut profile, 0);
+    let mut stacks = UnresolvedStacks::default();
+    let stack_handles = synthetic_stacks(&mut stacks, 10_000, 32);
+    let samples = synthetic_samples(&threads, &stack_handles, 1_000_000);
+    let markers = synthetic_markers(&threads, 1_000, 10);
+    let user_category = profile.add_category("User", CategoryColor::Yellow).into();
+    let kernel_category = profile.add_category("Kernel", CategoryColor::Orange).into();
+    let process_sample_data = ProcessSampleData::new(
+        samples,
+        synthetic_lib_mapping(lib),
+        Vec::new(),
+        None,
+        markers,
+        Vec::new(),
+        process,
+        Vec::new(),
+    );
+
+    run_timed("flush 1M samples", || {
+        process_sample_data.flush_samples_to_profile(
+            &mut profile,
+            user_category,
+            kernel_category,
+            &mut Vec::new(),
+            &stacks,
+            &FlushOptions::default(),
+        );
+    });
+}
+
+#[test]
+#[ignore]
+fn flush_100k_markers_with_10_field_combinations() {
+    let mut profile = new_profile();
+    let (process, threads) = add_process_with_threads(&mut profile, 8);
+    let markers = synthetic_markers(&threads, 100_000, 10);
+    let user_category = profile.add_category("User", CategoryColor::Yellow).into();
+    let kernel_category = profile.add_category("Kernel", CategoryColor::Orange).into();
+    let process_sample_data = ProcessSampleData::new(
+        Default::default(),
+        Default::default(),
+        Vec::new(),
+        None,
+        markers,
+        Vec::new(),
+        process,
+        Vec::new(),
+    );
+
+    run_timed("flush 100k markers", || {
+        process_sample_data.flush_samples_to_profile(
+            &mut profile,
+            user_category,
+            kernel_category,
+            &mut Vec::new(),
+            &UnresolvedStacks::default(),
+            &FlushOptions::default(),
+        );
+    });
+}
+
+#[test]
+#[ignore]
+fn convert_500k_unique_stacks() {
+    let mut profile = new_profile();
+    let lib = add_synthetic_lib(&mut profile, 0);
+    let user_category = profile.add_category("User", CategoryColor::Yellow).into();
+    let kernel_category = profile.add_category("Kernel", CategoryColor::Orange).into();
+    let mut stacks = UnresolvedStacks::default();
+    let lib_mappings = LibMappingsHierarchy::new(synthetic_lib_mapping(lib));
+
+    run_timed("convert 500k unique stacks", || {
+        let stack_handles = synthetic_stacks(&mut stacks, 500_000, 24);
+        let mut stack_converter = StackConverter::new(user_category, kernel_category);
+        let mut frames = Vec::new();
+        let mut frame_count = 0;
+        for stack in stack_handles {
+            frames.clear();
+            stacks.convert_back(stack, &mut frames);
+            frame_count += stack_converter
+                .convert_stack(&frames, &lib_mappings, None, None)
+                .count();
+        }
+        assert!(frame_count > 0);
+    });
+}
+
+#[test]
+#[ignore]
+fn replay_large_lib_mapping_op_queue() {
+    let mut profile = new_profile();
+    let libs: Vec<_> = (0..100)
+        .map(|i| add_synthetic_lib(&mut profile, i))
+        .collect();
+    let ops = synthetic_lib_mapping_ops(&libs, 1_000_000);
+    let mut lib_mappings = LibMappingsHierarchy::new(ops);
+
+    run_timed("replay 1M lib mapping ops", || {
+        for timestamp in (0..1_000_000).step_by(100) {
+            lib_mappings.process_ops(timestamp);
+            lib_mappings.convert_address(LIB_START_AVMA + 0x10);
+        }
+    });
+}
+
+#[test]
+#[ignore]
+fn parse_marker_file_with_100k_spans() {
+    let contents = synthetic_marker_file(100_000);
+    let timestamp_converter = identity_converter();
+
+    run_timed("parse 100k marker spans", || {
+        let marker_file = MarkerFile::parse(
+            contents.as_bytes(),
+            Path::new("synthetic.txt"),
+            timestamp_converter,
+        );
+        let markers = marker_file.collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(markers.len(), 200_000);
+    });
+}
+
+#[test]
+#[ignore]
+fn look_up_1m_presymbolicated_addresses() {
+    let known_addresses: Vec<(u32, usize)> = (0..1_000_000u32)
+        .map(|i| (i * 16, i as usize / 4))
+        .collect();
+    let lookups: Vec<u32> = (0..1_000_000u32)
+        .map(|i| i.wrapping_mul(2_654_435_761) % 16_000_000)
+        .collect();
+
+    // The linear search which lookups used before, on a thousandth of the
+    // lookups.
+    run_timed("1k linear lookups", || {
+        let found = lookups[..1000]
+            .iter()
+            .filter(|&&rva| known_addresses.iter().any(|(known, _)| *known == rva))
+            .count();
+        assert!(found > 0);
+    });
+
+    let precog = SymbolPrecog::from_known_addresses(&known_addresses);
+    run_timed("1M indexed lookups", || {
+        let found = lookups
+            .iter()
+            .filter(|&&rva| precog.lookup(rva).is_some())
+            .count();
+        assert_eq!(found, lookups.iter().filter(|&&rva| rva % 16 == 0).count());
+    });
+}
@@ -1,27 +1,39 @@
-use std::fs::File;
+use std::fmt::Display;
+use std::io::{BufRead, BufReader, Cursor, Read};
 use std::path::{Path, PathBuf};
 
-use fxprof_processed_profile::{GraphColor, Timestamp};
+use fxprof_processed_profile::{GraphColor, MarkerGraphType, Timestamp};
 
-use super::timestamp_converter::TimestampConverter;
+use super::error::Error;
+use super::memory_usage::{vec_heap_size, MemoryUsage};
+use super::timestamp_converter::{ClockDomain, TimestampConverter};
 use super::utils::open_file_with_fallback;
 
+/// In counter files without a `clock` field, sample timestamps from this one
+/// on are taken to be nanoseconds since the UNIX epoch rather than since
+/// boot. It's in September 2001, and no machine has been up for that long.
+const REALTIME_DETECTION_THRESHOLD_NS: u64 = 1_000_000_000_000_000_000;
+
 #[derive(Debug, Clone)]
 pub enum CounterCategory {
     Memory,
     Bandwidth,
     Cpu,
+    Io,
     Custom,
 }
 
-impl From<&str> for CounterCategory {
-    fn from(value: &str) -> Self {
+impl TryFrom<&str> for CounterCategory {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
         match value {
-            "Memory" => CounterCategory::Memory,
-            "Bandwidth" => CounterCategory::Bandwidth,
-            "CPU" => CounterCategory::Cpu,
-            "Custom" => CounterCategory::Custom,
-            _ => panic!("Invalid counter category: {}", value),
+            "Memory" => Ok(CounterCategory::Memory),
+            "Bandwidth" => Ok(CounterCategory::Bandwidth),
+            "CPU" => Ok(CounterCategory::Cpu),
+            "I/O" => Ok(CounterCategory::Io),
+            "Custom" => Ok(CounterCategory::Custom),
+            _ => Err(format!("invalid counter category: {value}")),
         }
     }
 }
@@ -32,11 +44,26 @@ impl From<CounterCategory> for &str {
             CounterCategory::Memory => "Memory",
             CounterCategory::Bandwidth => "Bandwidth",
             CounterCategory::Cpu => "CPU",
+            CounterCategory::Io => "I/O",
             CounterCategory::Custom => "Custom",
         }
     }
 }
 
+impl CounterCategory {
+    /// The track color of counters in this category, so that e.g. all memory
+    /// tracks look alike. Custom counters are left to the front-end's default.
+    pub fn default_color(&self) -> Option<GraphColor> {
+        match self {
+            CounterCategory::Memory => Some(GraphColor::Orange),
+            CounterCategory::Bandwidth => Some(GraphColor::Teal),
+            CounterCategory::Cpu => Some(GraphColor::Yellow),
+            CounterCategory::Io => Some(GraphColor::Blue),
+            CounterCategory::Custom => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct CounterSample {
     pub timestamp: Timestamp,
@@ -61,43 +88,785 @@ fn get_graph_color(color: &str) -> Option<GraphColor> {
     }
 }
 
+/// The name of `color` in counter files.
+fn graph_color_name(color: GraphColor) -> &'static str {
+    match color {
+        GraphColor::Blue => "blue",
+        GraphColor::Green => "green",
+        GraphColor::Grey => "grey",
+        GraphColor::Ink => "ink",
+        GraphColor::Magenta => "magenta",
+        GraphColor::Orange => "orange",
+        GraphColor::Purple => "purple",
+        GraphColor::Red => "red",
+        GraphColor::Teal => "teal",
+        GraphColor::Yellow => "yellow",
+    }
+}
+
+/// The colors which `distinguish_counter_colors` hands out, in order, so
+/// that neighbors in the list contrast with each other.
+const DISTINCT_COLOR_PALETTE: [GraphColor; 10] = [
+    GraphColor::Blue,
+    GraphColor::Orange,
+    GraphColor::Green,
+    GraphColor::Magenta,
+    GraphColor::Teal,
+    GraphColor::Red,
+    GraphColor::Purple,
+    GraphColor::Yellow,
+    GraphColor::Ink,
+    GraphColor::Grey,
+];
+
+/// A change of a counter's color by `distinguish_counter_colors`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CounterColorChange {
+    pub name: String,
+    /// The track color before the change, `None` for the front-end's default.
+    pub old_color: Option<GraphColor>,
+    pub new_color: GraphColor,
+}
+
+impl Display for CounterColorChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Counter \"{}\": {} -> {}",
+            self.name,
+            self.old_color.map_or("unspec", graph_color_name),
+            graph_color_name(self.new_color)
+        )
+    }
+}
+
+/// Makes the track colors of `counters` tell them apart, after each has its
+/// color from the counter file or its category. Counters with a color in
+/// the counter file keep it. Each other counter whose track color is
+/// unspecified, or the same as an earlier counter's, gets the first color of
+/// `DISTINCT_COLOR_PALETTE` which no counter has yet. Once all colors are
+/// taken, the remaining counters keep theirs. Returns the changes.
+pub fn distinguish_counter_colors<'a>(
+    counters: impl IntoIterator<Item = &'a mut Counter>,
+) -> Vec<CounterColorChange> {
+    let mut counters: Vec<&mut Counter> = counters.into_iter().collect();
+    let mut taken: Vec<GraphColor> = counters
+        .iter()
+        .filter_map(|counter| counter.color)
+        .collect();
+    let mut changes = Vec::new();
+    for counter in counters
+        .iter_mut()
+        .filter(|counter| counter.color.is_none())
+    {
+        let old_color = counter.track_color();
+        if let Some(color) = old_color.filter(|color| !taken.contains(color)) {
+            taken.push(color);
+            continue;
+        }
+        let Some(new_color) = DISTINCT_COLOR_PALETTE
+            .into_iter()
+            .find(|color| !taken.contains(color))
+        else {
+            continue;
+        };
+        taken.push(new_color);
+        counter.color = Some(new_color);
+        changes.push(CounterColorChange {
+            name: counter.name.clone(),
+            old_color,
+            new_color,
+        });
+    }
+    changes
+}
+
+fn get_graph_type(graph_type: &str) -> Option<MarkerGraphType> {
+    match graph_type {
+        "line" => Some(MarkerGraphType::Line),
+        "bar" => Some(MarkerGraphType::Bar),
+        _ => None,
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Counter {
     pub name: String,
     pub category: CounterCategory,
     pub description: String,
     pub color: Option<GraphColor>,
+    /// How `CounterCategory::Custom` counters are drawn. Defaults to a line.
+    pub graph_type: MarkerGraphType,
+    /// Whether the sample values are running totals which were summed up
+    /// from per-interval rates, see `to_cumulative`.
+    pub is_cumulative: bool,
     pub samples: Vec<CounterSample>,
 }
 
-fn parse_counter_file(file: File, timestamp_converter: TimestampConverter) -> Counter {
-    let json: serde_json::Value = serde_json::from_reader(&file).ok().unwrap();
+impl Counter {
+    /// The counter's own `color` if it has one, otherwise the default color
+    /// of its category.
+    pub fn track_color(&self) -> Option<GraphColor> {
+        self.color.or_else(|| self.category.default_color())
+    }
 
-    let mut samples = Vec::new();
+    /// Turns a counter whose samples are rates per sample interval, e.g. the
+    /// bytes sent since the previous sample, into one whose samples are the
+    /// running total up to and including each sample. The modification
+    /// counts are summed up the same way.
+    #[allow(dead_code, clippy::wrong_self_convention)]
+    pub fn to_cumulative(mut self) -> Counter {
+        if self.is_cumulative {
+            return self;
+        }
+        let mut value = 0.0;
+        let mut modification_count = 0u32;
+        for sample in &mut self.samples {
+            value += sample.value;
+            modification_count = modification_count.saturating_add(sample.modification_count);
+            sample.value = value;
+            sample.modification_count = modification_count;
+        }
+        self.is_cumulative = true;
+        self
+    }
 
-    for sample in json["samples"].as_array().unwrap() {
-        let sample = sample.as_array().unwrap();
-        samples.push(CounterSample {
-            timestamp: timestamp_converter.convert_time(sample[0].as_u64().unwrap()),
-            value: sample[1].as_f64().unwrap(),
-            modification_count: sample[2].as_u64().unwrap() as u32,
-        });
+    /// Adds up counters which measure parts of the same quantity, e.g. the
+    /// memory use of each process, into one counter. The result has a sample
+    /// at every timestamp of any of the counters. Where a counter has no
+    /// sample at such a timestamp, its value is interpolated linearly between
+    /// its neighboring samples; before its first and after its last sample, a
+    /// counter adds nothing. The modification counts of the samples at a
+    /// timestamp are summed up too, without interpolation.
+    ///
+    /// Everything but the samples is taken from the first counter. Returns
+    /// `None` if `counters` is empty.
+    pub fn sum(counters: &[Counter]) -> Option<Counter> {
+        let first = counters.first()?;
+        let sorted_samples: Vec<Vec<&CounterSample>> = counters
+            .iter()
+            .map(|counter| {
+                let mut samples: Vec<_> = counter.samples.iter().collect();
+                samples.sort_by_key(|sample| sample.timestamp);
+                samples
+            })
+            .collect();
+        let mut timestamps: Vec<Timestamp> = sorted_samples
+            .iter()
+            .flatten()
+            .map(|sample| sample.timestamp)
+            .collect();
+        timestamps.sort();
+        timestamps.dedup();
+
+        let samples = timestamps
+            .into_iter()
+            .map(|timestamp| {
+                let mut value = 0.0;
+                let mut modification_count = 0u32;
+                for samples in &sorted_samples {
+                    let index = samples.partition_point(|sample| sample.timestamp < timestamp);
+                    match samples.get(index) {
+                        Some(sample) if sample.timestamp == timestamp => {
+                            value += sample.value;
+                            modification_count =
+                                modification_count.saturating_add(sample.modification_count);
+                        }
+                        Some(next) if index > 0 => {
+                            let prev = samples[index - 1];
+                            let prev_ns = prev.timestamp.as_nanos_since_reference();
+                            let next_ns = next.timestamp.as_nanos_since_reference();
+                            let fraction = (timestamp.as_nanos_since_reference() - prev_ns) as f64
+                                / (next_ns - prev_ns) as f64;
+                            value += prev.value + (next.value - prev.value) * fraction;
+                        }
+                        _ => {}
+                    }
+                }
+                CounterSample {
+                    timestamp,
+                    value,
+                    modification_count,
+                }
+            })
+            .collect();
+
+        Some(Counter {
+            name: first.name.clone(),
+            category: first.category.clone(),
+            description: first.description.clone(),
+            color: first.color,
+            graph_type: first.graph_type.clone(),
+            is_cumulative: first.is_cumulative,
+            samples,
+        })
+    }
+}
+
+impl MemoryUsage for Counter {
+    fn heap_size(&self) -> usize {
+        self.name.heap_size() + self.description.heap_size() + vec_heap_size(&self.samples)
+    }
+}
+
+fn str_field<'a>(json: &'a serde_json::Value, field: &str) -> Result<&'a str, String> {
+    json[field]
+        .as_str()
+        .ok_or_else(|| format!("missing string field '{field}'"))
+}
+
+fn parse_sample(
+    sample: &serde_json::Value,
+    timestamp_converter: &TimestampConverter,
+    domain: ClockDomain,
+) -> Option<CounterSample> {
+    let sample = sample.as_array()?;
+    Some(CounterSample {
+        timestamp: timestamp_converter.convert_time_from_domain(sample.first()?.as_u64()?, domain),
+        value: sample.get(1)?.as_f64()?,
+        modification_count: sample.get(2)?.as_u64()? as u32,
+    })
+}
+
+/// Parses a sample line of an NDJSON counter file, of the form
+/// `{"t": timestamp, "v": value, "m": modification_count}` or
+/// `[timestamp, value, modification_count]`.
+fn parse_ndjson_sample(
+    sample: &serde_json::Value,
+    timestamp_converter: &TimestampConverter,
+    domain: ClockDomain,
+) -> Option<CounterSample> {
+    if sample.is_array() {
+        return parse_sample(sample, timestamp_converter, domain);
+    }
+    Some(CounterSample {
+        timestamp: timestamp_converter.convert_time_from_domain(sample["t"].as_u64()?, domain),
+        value: sample["v"].as_f64()?,
+        modification_count: sample["m"].as_u64()? as u32,
+    })
+}
+
+/// The timestamp of an NDJSON sample line in either form.
+fn ndjson_sample_timestamp(sample: &serde_json::Value) -> Option<u64> {
+    sample["t"].as_u64().or_else(|| sample[0].as_u64())
+}
+
+/// The clock domain of a counter file without a `clock` field, going by its
+/// first sample timestamp.
+fn detect_clock_domain(first_timestamp: Option<u64>) -> ClockDomain {
+    match first_timestamp {
+        Some(timestamp) if timestamp >= REALTIME_DETECTION_THRESHOLD_NS => ClockDomain::Realtime,
+        _ => ClockDomain::Monotonic,
+    }
+}
+
+/// Parses everything but the samples, and returns the counter together
+/// with the clock domain of its sample timestamps, if the `clock` field
+/// names one.
+fn parse_counter_header(
+    json: &serde_json::Value,
+) -> Result<(Counter, Option<ClockDomain>), String> {
+    let domain = json["clock"].as_str().and_then(ClockDomain::from_name);
+    let counter = Counter {
+        name: str_field(json, "name")?.into(),
+        category: str_field(json, "category")?.try_into()?,
+        description: str_field(json, "description")?.into(),
+        color: json["color"].as_str().and_then(get_graph_color),
+        graph_type: json["graph_type"]
+            .as_str()
+            .and_then(get_graph_type)
+            .unwrap_or(MarkerGraphType::Line),
+        is_cumulative: false,
+        samples: Vec::new(),
+    };
+    Ok((counter, domain))
+}
+
+fn parse_counter_json(
+    json: &serde_json::Value,
+    timestamp_converter: TimestampConverter,
+) -> Result<Counter, String> {
+    let (mut counter, domain) = parse_counter_header(json)?;
+
+    let json_samples = json["samples"]
+        .as_array()
+        .ok_or("missing array field 'samples'")?;
+    let domain = domain.unwrap_or_else(|| {
+        detect_clock_domain(json_samples.first().and_then(|sample| sample[0].as_u64()))
+    });
+    for (i, sample) in json_samples.iter().enumerate() {
+        let sample = parse_sample(sample, &timestamp_converter, domain).ok_or_else(|| {
+            format!("sample {i} is not a [timestamp, value, modification_count] array")
+        })?;
+        counter.samples.push(sample);
+    }
+
+    Ok(counter)
+}
+
+fn json_error(e: serde_json::Error, path: &Path, line: usize) -> Error {
+    if e.is_io() {
+        Error::Io {
+            path: path.to_owned(),
+            source: e.into(),
+        }
+    } else {
+        Error::Parse {
+            path: path.to_owned(),
+            line: Some(line),
+            reason: e.to_string(),
+        }
     }
+}
+
+/// Whether `first_line` is the header line of the NDJSON form, i.e. a JSON
+/// object of its own without `samples`. The first line of the JSON form is
+/// either the whole counter, with `samples`, or only part of it.
+fn is_ndjson_header(first_line: &[u8]) -> bool {
+    serde_json::from_slice::<serde_json::Value>(first_line)
+        .is_ok_and(|json| json.is_object() && json.get("samples").is_none())
+}
+
+/// Parses a counter file in the JSON form, or in the NDJSON form if its first
+/// line is an NDJSON header, see `parse_counter_ndjson`.
+fn parse_counter_file<R: Read>(
+    reader: R,
+    path: &Path,
+    timestamp_converter: TimestampConverter,
+) -> Result<Counter, Error> {
+    let mut reader = BufReader::new(reader);
+    let mut first_line = Vec::new();
+    reader
+        .read_until(b'\n', &mut first_line)
+        .map_err(|source| Error::Io {
+            path: path.to_owned(),
+            source,
+        })?;
+    let is_ndjson = is_ndjson_header(&first_line);
+    let reader = Cursor::new(first_line).chain(reader);
+    if is_ndjson {
+        return parse_counter_ndjson(reader, path, timestamp_converter);
+    }
+
+    let json: serde_json::Value = serde_json::from_reader(reader).map_err(|e| {
+        let line = e.line();
+        json_error(e, path, line)
+    })?;
+    parse_counter_json(&json, timestamp_converter).map_err(|reason| Error::Parse {
+        path: path.to_owned(),
+        line: None,
+        reason,
+    })
+}
 
-    Counter {
-        name: json["name"].as_str().unwrap().into(),
-        category: json["category"].as_str().unwrap().into(),
-        description: json["description"].as_str().unwrap().into(),
-        color: get_graph_color(json["color"].as_str().unwrap()),
-        samples,
+/// Parses the NDJSON form of a counter file, which can be appended to while
+/// the program is running. The first line has the same fields as the JSON
+/// form, minus `samples`. Every following line is one sample, either as an
+/// object or as an array like in the JSON form:
+///
+/// ```text
+/// {"name": "queue", "category": "Custom", "description": "Queue length"}
+/// {"t": 1000, "v": 3.0, "m": 1}
+/// [2000, 5.0, 1]
+/// ```
+///
+/// The lines are parsed one at a time, so the file is never loaded as a
+/// whole. Files with an `.ndjson` extension are always in this form, others
+/// are if their first line is a header.
+///
+/// Empty lines are skipped, and so is a malformed last line, because the
+/// program may have been stopped while writing it.
+fn parse_counter_ndjson<R: Read>(
+    reader: R,
+    path: &Path,
+    timestamp_converter: TimestampConverter,
+) -> Result<Counter, Error> {
+    let parse_error = |line: usize, reason: String| Error::Parse {
+        path: path.to_owned(),
+        line: Some(line),
+        reason,
+    };
+    let mut lines = BufReader::new(reader)
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line))
+        .filter(|(_, line)| !matches!(line, Ok(line) if line.trim().is_empty()))
+        .peekable();
+
+    let Some((header_line_number, header)) = lines.next() else {
+        return Err(parse_error(1, "missing header line".to_string()));
+    };
+    let header = header.map_err(|source| Error::Io {
+        path: path.to_owned(),
+        source,
+    })?;
+    let header: serde_json::Value =
+        serde_json::from_str(&header).map_err(|e| json_error(e, path, header_line_number))?;
+    let (mut counter, mut domain) =
+        parse_counter_header(&header).map_err(|reason| parse_error(header_line_number, reason))?;
+
+    while let Some((line_number, line)) = lines.next() {
+        let line = line.map_err(|source| Error::Io {
+            path: path.to_owned(),
+            source,
+        })?;
+        let is_last_line = lines.peek().is_none();
+        let sample = serde_json::from_str::<serde_json::Value>(&line)
+            .ok()
+            .and_then(|json| {
+                let domain = *domain
+                    .get_or_insert_with(|| detect_clock_domain(ndjson_sample_timestamp(&json)));
+                parse_ndjson_sample(&json, &timestamp_converter, domain)
+            });
+        match sample {
+            Some(sample) => counter.samples.push(sample),
+            None if is_last_line => {}
+            None => {
+                return Err(parse_error(
+                    line_number,
+                    "sample is not a {\"t\", \"v\", \"m\"} object or a [timestamp, value, modification_count] array"
+                        .to_string(),
+                ))
+            }
+        }
     }
+    Ok(counter)
 }
 
+/// Whether the counter file at `path` is in the NDJSON form, based on its
+/// `.ndjson` extension.
+fn is_ndjson_counter_file(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension == "ndjson")
+}
+
+/// Reads the counter file, and returns the counter together with the path
+/// the file was actually found at.
 pub fn get_counter(
     counter_file: &Path,
     lookup_dirs: &[PathBuf],
     timestamp_converter: TimestampConverter,
-) -> Result<Counter, std::io::Error> {
-    let (f, _true_path) = open_file_with_fallback(counter_file, lookup_dirs)?;
-    Ok(parse_counter_file(f, timestamp_converter))
+) -> Result<(Counter, PathBuf), Error> {
+    let (f, true_path) = open_file_with_fallback(counter_file, lookup_dirs)?;
+    let counter = if is_ndjson_counter_file(&true_path) {
+        parse_counter_ndjson(f, &true_path, timestamp_converter)?
+    } else {
+        parse_counter_file(f, &true_path, timestamp_converter)?
+    };
+    Ok((counter, true_path))
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::shared::test_support::identity_converter;
+
+    fn try_parse_counter(contents: &str) -> Result<Counter, Error> {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(contents.as_bytes()).unwrap();
+        let (counter, _) = get_counter(file.path(), &[], identity_converter())?;
+        Ok(counter)
+    }
+
+    fn json_scalar() -> impl Strategy<Value = serde_json::Value> {
+        prop_oneof![
+            Just(serde_json::Value::Null),
+            any::<u64>().prop_map(serde_json::Value::from),
+            any::<i64>().prop_map(serde_json::Value::from),
+            any::<f64>().prop_map(serde_json::Value::from),
+            "\\PC{0,8}".prop_map(serde_json::Value::from),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn arbitrary_counter_file_never_panics(contents in prop::collection::vec(any::<u8>(), 0..256)) {
+            let _ = parse_counter_file(&contents[..], Path::new("fuzz"), identity_converter());
+            let _ = parse_counter_ndjson(&contents[..], Path::new("fuzz"), identity_converter());
+        }
+
+        #[test]
+        fn arbitrary_counter_json_never_panics(
+            name in json_scalar(),
+            category in prop_oneof![Just("CPU".into()), json_scalar()],
+            color in json_scalar(),
+            graph_type in json_scalar(),
+            clock in json_scalar(),
+            samples in prop::collection::vec(prop::collection::vec(json_scalar(), 0..4), 0..8),
+        ) {
+            let json = serde_json::json!({
+                "name": name,
+                "category": category,
+                "description": "",
+                "color": color,
+                "graph_type": graph_type,
+                "clock": clock,
+                "samples": samples,
+            });
+            let _ = parse_counter_json(&json, identity_converter());
+        }
+    }
+
+    fn try_parse_ndjson_counter(contents: &str) -> Result<Counter, Error> {
+        let mut file = tempfile::Builder::new()
+            .prefix("counter-")
+            .suffix(".ndjson")
+            .tempfile()
+            .unwrap();
+        file.write_all(contents.as_bytes()).unwrap();
+        let (counter, _) = get_counter(file.path(), &[], identity_converter())?;
+        Ok(counter)
+    }
+
+    #[test]
+    fn ndjson_counter() {
+        let counter = try_parse_ndjson_counter(concat!(
+            r#"{"name": "queue", "category": "Custom", "description": "Queue length"}"#,
+            "\n",
+            r#"{"t": 1000, "v": 3.0, "m": 1}"#,
+            "\n\n",
+            r#"{"t": 2000, "v": 5.0, "m": 1}"#,
+            "\n",
+            r#"{"t": 3000, "v": 4.5, "m": 2}"#,
+            "\n",
+        ))
+        .unwrap();
+        assert_eq!(counter.name, "queue");
+        let samples: Vec<_> = counter
+            .samples
+            .iter()
+            .map(|sample| (sample.timestamp, sample.value, sample.modification_count))
+            .collect();
+        assert_eq!(
+            samples,
+            [
+                (Timestamp::from_nanos_since_reference(1000), 3.0, 1),
+                (Timestamp::from_nanos_since_reference(2000), 5.0, 1),
+                (Timestamp::from_nanos_since_reference(3000), 4.5, 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn epoch_timestamps_are_detected() {
+        // The profile starts at 5s since boot, which was at this UNIX time.
+        let reference_realtime_ns = 1_700_000_000_000_000_000;
+        let mut converter = TimestampConverter {
+            reference_raw: 5_000_000_000,
+            ..identity_converter()
+        };
+        converter.register_realtime_reference(reference_realtime_ns);
+        let parse = |contents: &str| {
+            let mut file = tempfile::NamedTempFile::new().unwrap();
+            file.write_all(contents.as_bytes()).unwrap();
+            let (counter, _) = get_counter(file.path(), &[], converter).unwrap();
+            let timestamps: Vec<_> = counter
+                .samples
+                .iter()
+                .map(|sample| sample.timestamp.as_nanos_since_reference())
+                .collect();
+            timestamps
+        };
+
+        let json = format!(
+            r#"{{"name": "queue", "category": "Custom", "description": "Queue length",
+                "samples": [[{}, 1.0, 1], [{}, 2.0, 1]]}}"#,
+            reference_realtime_ns + 250_000_000,
+            reference_realtime_ns + 500_000_000
+        );
+        assert_eq!(parse(&json), [250_000_000, 500_000_000]);
+
+        // An explicit clock wins over the detection.
+        let json = json.replace(r#""samples""#, r#""clock": "monotonic", "samples""#);
+        assert_eq!(
+            parse(&json),
+            [
+                reference_realtime_ns + 250_000_000 - 5_000_000_000,
+                reference_realtime_ns + 500_000_000 - 5_000_000_000
+            ]
+        );
+
+        // Timestamps since boot are left alone.
+        let json = r#"{"name": "queue", "category": "Custom", "description": "Queue length",
+            "samples": [[5250000000, 1.0, 1]]}"#;
+        assert_eq!(parse(json), [250_000_000]);
+    }
+
+    #[test]
+    fn ndjson_counter_with_truncated_last_line() {
+        let header = r#"{"name": "c", "category": "CPU", "description": ""}"#;
+        let counter = try_parse_ndjson_counter(&format!(
+            "{header}\n{{\"t\": 1, \"v\": 2, \"m\": 1}}\n{{\"t\": 2, \"v"
+        ))
+        .unwrap();
+        assert_eq!(counter.samples.len(), 1);
+
+        let err = try_parse_ndjson_counter(&format!(
+            "{header}\n{{\"t\": 1}}\n{{\"t\": 2, \"v\": 2, \"m\": 1}}\n"
+        ))
+        .unwrap_err();
+        assert!(matches!(err, Error::Parse { line: Some(2), .. }), "{err:?}");
+    }
+
+    #[test]
+    fn streamed_counter_is_detected_by_its_header_line() {
+        let header = r#"{"name": "queue", "category": "Custom", "description": "Queue length"}"#;
+        let counter = try_parse_counter(&format!(
+            "{header}\n[1000, 3.0, 1]\n{{\"t\": 2000, \"v\": 5.0, \"m\": 1}}\n"
+        ))
+        .unwrap();
+        assert_eq!(counter.name, "queue");
+        let samples: Vec<_> = counter
+            .samples
+            .iter()
+            .map(|sample| (sample.timestamp, sample.value))
+            .collect();
+        assert_eq!(
+            samples,
+            [
+                (Timestamp::from_nanos_since_reference(1000), 3.0),
+                (Timestamp::from_nanos_since_reference(2000), 5.0),
+            ]
+        );
+
+        // Truncated in the middle of the last sample.
+        let counter = try_parse_counter(&format!("{header}\n[1000, 3.0, 1]\n[2000, 5")).unwrap();
+        assert_eq!(counter.samples.len(), 1);
+        let err =
+            try_parse_counter(&format!("{header}\n[1000, 3.0]\n[2000, 5.0, 1]\n")).unwrap_err();
+        assert!(matches!(err, Error::Parse { line: Some(2), .. }), "{err:?}");
+
+        // The JSON form on a single line, or over several lines, is unaffected.
+        let counter = try_parse_counter(&format!(
+            "{}, \"samples\": [[1000, 3.0, 1]]}}",
+            header.trim_end_matches('}')
+        ))
+        .unwrap();
+        assert_eq!(counter.samples.len(), 1);
+        let counter = try_parse_counter(&format!(
+            "{{\n{},\n\"samples\": [[1000, 3.0, 1]]}}",
+            header.trim_matches(['{', '}'])
+        ))
+        .unwrap();
+        assert_eq!(counter.samples.len(), 1);
+    }
+
+    #[test]
+    fn valid_counter() {
+        let counter = try_parse_counter(
+            r#"{"name": "c", "category": "Custom", "description": "", "color": "red", "samples": [[1, 2.0, 1]]}"#,
+        )
+        .unwrap();
+        assert_eq!(counter.samples.len(), 1);
+        assert!(matches!(counter.category, CounterCategory::Custom));
+    }
+
+    #[test]
+    fn malformed_json_is_parse_error_with_line() {
+        let err =
+            try_parse_counter("{\n\"name\": \"c\",\n\"samples\": [[1, 2.0, 1]\n").unwrap_err();
+        assert!(matches!(err, Error::Parse { line: Some(4), .. }), "{err:?}");
+    }
+
+    #[test]
+    fn invalid_category_is_parse_error() {
+        let err = try_parse_counter(
+            r#"{"name": "c", "category": "Disk", "description": "", "samples": []}"#,
+        )
+        .unwrap_err();
+        match err {
+            Error::Parse { line, reason, .. } => {
+                assert_eq!(line, None);
+                assert!(reason.contains("Disk"), "{reason}");
+            }
+            err => panic!("expected a parse error, got {err:?}"),
+        }
+    }
+
+    #[test]
+    fn bad_sample_is_parse_error() {
+        let err = try_parse_counter(
+            r#"{"name": "c", "category": "CPU", "description": "", "samples": [[1, 2.0, 1], [2, "x"]]}"#,
+        )
+        .unwrap_err();
+        assert!(err.is_parse_error(), "{err:?}");
+        assert!(err.to_string().contains("sample 1"), "{err}");
+    }
+
+    #[test]
+    fn cumulative_counter_sums_up_rates() {
+        let counter = try_parse_counter(
+            r#"{"name": "bytes_sent", "category": "Bandwidth", "description": "", "samples": [[1, 2.0, 1], [2, 0.5, 1], [3, 4.0, 2]]}"#,
+        )
+        .unwrap();
+        let rate_sum: f64 = counter.samples.iter().map(|sample| sample.value).sum();
+        assert!(!counter.is_cumulative);
+
+        let cumulative = counter.to_cumulative();
+        assert!(cumulative.is_cumulative);
+        let values: Vec<_> = cumulative
+            .samples
+            .iter()
+            .map(|sample| (sample.value, sample.modification_count))
+            .collect();
+        assert_eq!(values, [(2.0, 1), (2.5, 2), (6.5, 4)]);
+        assert_eq!(cumulative.samples.last().unwrap().value, rate_sum);
+
+        // Converting again doesn't sum up the totals.
+        let again = cumulative.to_cumulative();
+        assert_eq!(again.samples.last().unwrap().value, rate_sum);
+    }
+
+    #[test]
+    fn summed_counters_interpolate_between_samples() {
+        let a = try_parse_counter(
+            r#"{"name": "rss", "category": "Memory", "description": "", "samples": [[0, 1.0, 1], [10, 2.0, 1], [20, 3.0, 1]]}"#,
+        )
+        .unwrap();
+        let b = try_parse_counter(
+            r#"{"name": "rss", "category": "Memory", "description": "", "samples": [[5, 10.0, 2], [15, 20.0, 2], [25, 30.0, 2]]}"#,
+        )
+        .unwrap();
+
+        assert!(Counter::sum(&[]).is_none());
+        let sum = Counter::sum(&[a, b]).unwrap();
+        assert_eq!(sum.name, "rss");
+        let samples: Vec<_> = sum
+            .samples
+            .iter()
+            .map(|sample| {
+                (
+                    sample.timestamp.as_nanos_since_reference(),
+                    sample.value,
+                    sample.modification_count,
+                )
+            })
+            .collect();
+        assert_eq!(
+            samples,
+            [
+                (0, 1.0, 1),
+                (5, 11.5, 2),
+                (10, 17.0, 1),
+                (15, 22.5, 2),
+                (20, 28.0, 1),
+                (25, 30.0, 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn missing_counter_file_is_not_found() {
+        let err = get_counter(
+            Path::new("/nonexistent/counter-1.json"),
+            &[],
+            identity_converter(),
+        )
+        .unwrap_err();
+        assert!(matches!(err, Error::NotFound(_)), "{err:?}");
+    }
 }
@@ -0,0 +1,170 @@
+use std::fmt;
+use std::sync::Arc;
+
+use fxprof_processed_profile::{ProcessHandle, Profile, ThreadHandle};
+
+use super::timestamp_converter::TimestampConverter;
+
+/// The process which a `CustomMarkerSource` adds markers to.
+#[derive(Debug, Clone)]
+pub struct CustomMarkerProcess {
+    #[allow(dead_code)]
+    pub pid: i32,
+    #[allow(dead_code)]
+    pub process: ProcessHandle,
+    #[allow(dead_code)]
+    pub main_thread: ThreadHandle,
+    /// The profile thread of each thread the process had, including the main
+    /// thread and threads which have exited, by tid.
+    #[allow(dead_code)]
+    pub threads: Vec<(i32, ThreadHandle)>,
+}
+
+impl CustomMarkerProcess {
+    #[allow(dead_code)]
+    pub fn thread_handle_for_tid(&self, tid: i32) -> Option<ThreadHandle> {
+        self.threads
+            .iter()
+            .find(|(thread_tid, _)| *thread_tid == tid)
+            .map(|(_, thread_handle)| *thread_handle)
+    }
+}
+
+/// Adds markers of its own types to a process when the process is finished,
+/// after the markers from marker files have been read.
+///
+/// Implementations are free to register their own `StaticSchemaMarker` or
+/// runtime schema marker types on the profile. Raw timestamps, e.g. from a
+/// log with `CLOCK_MONOTONIC` times, can be turned into profile timestamps
+/// with `timestamp_converter`. The markers of `--stderr-markers` are added
+/// by one of these, see `StderrMarkers`.
+///
+/// ```ignore
+/// struct PhaseMarkers(Vec<(i32, u64, u64, String)>);
+///
+/// impl CustomMarkerSource for PhaseMarkers {
+///     fn add_markers(
+///         &self,
+///         profile: &mut Profile,
+///         process: &CustomMarkerProcess,
+///         timestamp_converter: &TimestampConverter,
+///     ) {
+///         for (tid, start, end, name) in &self.0 {
+///             let Some(thread) = process.thread_handle_for_tid(*tid) else {
+///                 continue;
+///             };
+///             let timing = MarkerTiming::Interval(
+///                 timestamp_converter.convert_time(*start),
+///                 timestamp_converter.convert_time(*end),
+///             );
+///             let name = profile.intern_string(name);
+///             profile.add_marker(thread, timing, PhaseMarker(name));
+///         }
+///     }
+/// }
+/// ```
+pub trait CustomMarkerSource: Send + Sync {
+    fn add_markers(
+        &self,
+        profile: &mut Profile,
+        process: &CustomMarkerProcess,
+        timestamp_converter: &TimestampConverter,
+    );
+}
+
+/// The custom marker sources, see `ProfileCreationProps::custom_marker_sources`.
+#[derive(Clone, Default)]
+pub struct CustomMarkerSources(pub Vec<Arc<dyn CustomMarkerSource>>);
+
+impl fmt::Debug for CustomMarkerSources {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "CustomMarkerSources({})", self.0.len())
+    }
+}
+
+impl CustomMarkerSources {
+    pub fn add_markers(
+        &self,
+        profile: &mut Profile,
+        process: &CustomMarkerProcess,
+        timestamp_converter: &TimestampConverter,
+    ) {
+        for source in &self.0 {
+            source.add_markers(profile, process, timestamp_converter);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use fxprof_processed_profile::{MarkerTiming, StaticSchemaMarker, Timestamp};
+
+    use super::*;
+    use crate::shared::process_sample_data::OtherEventMarker;
+    use crate::shared::test_support::new_profile;
+    use crate::shared::timestamp_converter::ClockDomain;
+
+    /// Adds one "Other event" marker on the thread with `tid`, at raw time
+    /// `time`.
+    struct OneMarker {
+        tid: i32,
+        time: u64,
+    }
+
+    impl CustomMarkerSource for OneMarker {
+        fn add_markers(
+            &self,
+            profile: &mut Profile,
+            process: &CustomMarkerProcess,
+            timestamp_converter: &TimestampConverter,
+        ) {
+            let thread = process.thread_handle_for_tid(self.tid).unwrap();
+            let name = profile.intern_string("custom");
+            profile.add_marker(
+                thread,
+                MarkerTiming::Instant(timestamp_converter.convert_time(self.time)),
+                OtherEventMarker(name),
+            );
+        }
+    }
+
+    #[test]
+    fn custom_markers_go_on_the_requested_thread() {
+        let mut profile = new_profile();
+        let start = Timestamp::from_millis_since_reference(0.0);
+        let process = profile.add_process("test", 10, start);
+        let main_thread = profile.add_thread(process, 10, start, true);
+        let other_thread = profile.add_thread(process, 11, start, false);
+        let custom_marker_process = CustomMarkerProcess {
+            pid: 10,
+            process,
+            main_thread,
+            threads: vec![(10, main_thread), (11, other_thread)],
+        };
+        let timestamp_converter = TimestampConverter {
+            reference_raw: 1_000_000,
+            raw_to_ns_factor: 1,
+            domain: ClockDomain::Monotonic,
+            realtime_to_monotonic_offset_ns: None,
+        };
+        let sources = CustomMarkerSources(vec![Arc::new(OneMarker {
+            tid: 11,
+            time: 3_000_000,
+        })]);
+        sources.add_markers(&mut profile, &custom_marker_process, &timestamp_converter);
+
+        let json = serde_json::to_value(&profile).unwrap();
+        let threads = json["threads"].as_array().unwrap();
+        let (main, other): (Vec<_>, Vec<_>) = threads
+            .iter()
+            .partition(|thread| thread["isMainThread"] == true);
+        assert_eq!(main[0]["markers"]["length"], 0);
+        let markers = &other[0]["markers"];
+        assert_eq!(markers["length"], 1);
+        assert_eq!(markers["startTime"][0], 2.0);
+        assert_eq!(
+            markers["data"][0]["type"],
+            OtherEventMarker::UNIQUE_MARKER_TYPE_NAME
+        );
+    }
+}
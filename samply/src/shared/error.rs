@@ -0,0 +1,55 @@
+use std::path::PathBuf;
+
+use super::utils::OpenFileWithFallbackError;
+
+/// Errors from ingesting the marker, counter and jitdump files which
+/// profiled programs hand to us.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    NotFound(#[from] OpenFileWithFallbackError),
+
+    #[error("Could not read {path:?}: {source}")]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("Could not parse {path:?}{}: {reason}", line.map_or(String::new(), |line| format!(" line {line}")))]
+    Parse {
+        path: PathBuf,
+        /// The 1-based line number, if the error can be attributed to a line.
+        line: Option<usize>,
+        reason: String,
+    },
+}
+
+impl Error {
+    /// Whether the file was found and read, but its contents were invalid.
+    /// Such errors only affect the file itself, so callers can log them and
+    /// carry on with other files.
+    pub fn is_parse_error(&self) -> bool {
+        matches!(self, Error::Parse { .. })
+    }
+
+    /// Report this error for a file that the profiled program asked us to
+    /// read: parse errors are logged, errors accessing the file are printed.
+    pub fn report(&self) {
+        if self.is_parse_error() {
+            log::warn!("{self}");
+        } else {
+            eprintln!("{self}");
+        }
+    }
+}
+
+/// Errors from `MarkerFile::auto_detect`.
+#[derive(Debug, thiserror::Error)]
+pub enum FormatError {
+    #[error("Could not read the marker file: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Unrecognized marker file format, the first line is {0:?}")]
+    Unrecognized(String),
+}
@@ -0,0 +1,351 @@
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use super::counter_file::{get_counter, Counter};
+use super::error::Error;
+use super::marker_file::{EventOrSpanMarker, MarkerData, MarkerFile, MarkerFileReport, SpanType};
+use super::timestamp_converter::{ClockDomain, TimestampConverter};
+
+/// Inspected files aren't part of a recording, so their timestamps are
+/// taken as they are, in nanoseconds.
+fn identity_converter() -> TimestampConverter {
+    TimestampConverter {
+        reference_raw: 0,
+        raw_to_ns_factor: 1,
+        domain: ClockDomain::Monotonic,
+        realtime_to_monotonic_offset_ns: None,
+    }
+}
+
+/// The earliest and latest timestamp of a file, in nanoseconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeRange {
+    pub start_ns: u64,
+    pub end_ns: u64,
+}
+
+impl TimeRange {
+    fn extend(range: &mut Option<TimeRange>, start_ns: u64, end_ns: u64) {
+        let range = range.get_or_insert(TimeRange { start_ns, end_ns });
+        range.start_ns = range.start_ns.min(start_ns);
+        range.end_ns = range.end_ns.max(end_ns);
+    }
+}
+
+impl fmt::Display for TimeRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} to {} ({} ns)",
+            self.start_ns,
+            self.end_ns,
+            self.end_ns - self.start_ns
+        )
+    }
+}
+
+fn write_time_range(f: &mut fmt::Formatter<'_>, range: &Option<TimeRange>) -> fmt::Result {
+    match range {
+        Some(range) => writeln!(f, "Time range: {range}"),
+        None => writeln!(f, "Time range: none"),
+    }
+}
+
+/// What `samply inspect-markers` prints about a marker file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MarkerFileSummary {
+    pub report: MarkerFileReport,
+    pub events: usize,
+    pub total_spans: usize,
+    pub running_spans: usize,
+    /// The new/close and enter/exit spans which never ended.
+    pub unended_spans: (usize, usize),
+    pub targets: BTreeSet<String>,
+    /// The distinct sets of extra field names, each sorted.
+    pub field_sets: BTreeSet<Vec<String>>,
+    pub time_range: Option<TimeRange>,
+}
+
+impl fmt::Display for MarkerFileSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let report = &self.report;
+        writeln!(f, "Lines: {}", report.lines_read)?;
+        writeln!(f, "Lines with markers: {}", report.lines_with_markers)?;
+        writeln!(f, "Dropped lines:")?;
+        writeln!(f, "  invalid JSON: {}", report.invalid_json)?;
+        writeln!(f, "  keyword mismatch: {}", report.dropped_keyword_mismatch)?;
+        writeln!(f, "Events: {}", self.events)?;
+        writeln!(f, "Total spans: {}", self.total_spans)?;
+        writeln!(f, "Running spans: {}", self.running_spans)?;
+        writeln!(
+            f,
+            "Unended spans: {} new/close, {} enter/exit",
+            self.unended_spans.0, self.unended_spans.1
+        )?;
+        writeln!(f, "Targets ({}):", self.targets.len())?;
+        for target in &self.targets {
+            writeln!(f, "  {target}")?;
+        }
+        writeln!(f, "Extra field sets ({}):", self.field_sets.len())?;
+        for fields in &self.field_sets {
+            writeln!(f, "  [{}]", fields.join(", "))?;
+        }
+        write_time_range(f, &self.time_range)
+    }
+}
+
+/// Parses the marker file like a recording would, and returns a summary and
+/// the markers, in the order in which they were completed.
+pub fn inspect_marker_file(
+    path: &Path,
+) -> Result<(MarkerFileSummary, Vec<EventOrSpanMarker>), Error> {
+    let file = File::open(path).map_err(|source| Error::Io {
+        path: path.to_owned(),
+        source,
+    })?;
+    let mut marker_file = MarkerFile::parse(BufReader::new(file), path, identity_converter());
+    let markers = marker_file
+        .by_ref()
+        .map(|marker| marker.map(|(marker, _parent_id)| marker))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut summary = MarkerFileSummary {
+        report: marker_file.report(),
+        unended_spans: marker_file.orphan_stats(),
+        ..Default::default()
+    };
+    for marker in &markers {
+        let start_ns = marker.start_time.as_nanos_since_reference();
+        let end_ns = match &marker.marker_data {
+            MarkerData::Event => {
+                summary.events += 1;
+                start_ns
+            }
+            MarkerData::Span(span) => {
+                match span.span_type {
+                    SpanType::Total => summary.total_spans += 1,
+                    SpanType::Running => summary.running_spans += 1,
+                }
+                span.end_time.as_nanos_since_reference()
+            }
+        };
+        TimeRange::extend(&mut summary.time_range, start_ns, end_ns);
+        summary.targets.insert(marker.target.clone());
+        let mut fields: Vec<String> = marker.extra_fields.keys().cloned().collect();
+        fields.sort();
+        summary.field_sets.insert(fields);
+    }
+    Ok((summary, markers))
+}
+
+/// A marker as `samply inspect-markers --dump-json` prints it.
+pub fn marker_to_json(marker: &EventOrSpanMarker) -> serde_json::Value {
+    let mut json = serde_json::json!({
+        "name": marker.message,
+        "target": marker.target,
+        "start": marker.start_time.as_nanos_since_reference(),
+        "fields": marker.extra_fields.iter().collect::<std::collections::BTreeMap<_, _>>(),
+    });
+    if let Some(level) = marker.level {
+        json["level"] = level.as_str().into();
+    }
+    if let Some(tid) = marker.tid {
+        json["tid"] = tid.into();
+    }
+    if let Some(trace_context) = &marker.trace_context {
+        json["traceId"] = trace_context.trace_id_hex().into();
+    }
+    match &marker.marker_data {
+        MarkerData::Event => json["type"] = "event".into(),
+        MarkerData::Span(span) => {
+            json["type"] = span.span_type.to_string().into();
+            json["id"] = span.id.into();
+            json["end"] = span.end_time.as_nanos_since_reference().into();
+            json["category"] = span.category.clone().into();
+            json["busy"] = (span.timings.time_busy.as_nanos() as u64).into();
+            json["idle"] = (span.timings.time_idle.as_nanos() as u64).into();
+        }
+    }
+    json
+}
+
+/// What `samply inspect-counter` prints about a counter file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CounterFileSummary {
+    pub name: String,
+    pub category: &'static str,
+    pub description: String,
+    pub samples: usize,
+    pub time_range: Option<TimeRange>,
+    /// The smallest and largest sample value.
+    pub value_range: Option<(f64, f64)>,
+}
+
+impl fmt::Display for CounterFileSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Name: {}", self.name)?;
+        writeln!(f, "Category: {}", self.category)?;
+        writeln!(f, "Description: {}", self.description)?;
+        writeln!(f, "Samples: {}", self.samples)?;
+        match self.value_range {
+            Some((min, max)) => writeln!(f, "Values: {min} to {max}")?,
+            None => writeln!(f, "Values: none")?,
+        }
+        write_time_range(f, &self.time_range)
+    }
+}
+
+/// Parses the counter file, in its JSON or NDJSON form, like a recording
+/// would, and returns a summary and the counter.
+pub fn inspect_counter_file(path: &Path) -> Result<(CounterFileSummary, Counter), Error> {
+    let (counter, _) = get_counter(path, &[], identity_converter())?;
+    let mut summary = CounterFileSummary {
+        name: counter.name.clone(),
+        category: counter.category.clone().into(),
+        description: counter.description.clone(),
+        samples: counter.samples.len(),
+        time_range: None,
+        value_range: None,
+    };
+    for sample in &counter.samples {
+        let timestamp_ns = sample.timestamp.as_nanos_since_reference();
+        TimeRange::extend(&mut summary.time_range, timestamp_ns, timestamp_ns);
+        let (min, max) = summary
+            .value_range
+            .get_or_insert((sample.value, sample.value));
+        *min = min.min(sample.value);
+        *max = max.max(sample.value);
+    }
+    Ok((summary, counter))
+}
+
+/// A counter as `samply inspect-counter --dump-json` prints it, with its
+/// samples as `[timestamp, value, modification count]`.
+pub fn counter_to_json(counter: &Counter) -> serde_json::Value {
+    let samples: Vec<_> = counter
+        .samples
+        .iter()
+        .map(|sample| {
+            serde_json::json!([
+                sample.timestamp.as_nanos_since_reference(),
+                sample.value,
+                sample.modification_count
+            ])
+        })
+        .collect();
+    serde_json::json!({
+        "name": counter.name,
+        "category": <&str>::from(counter.category.clone()),
+        "description": counter.description,
+        "isCumulative": counter.is_cumulative,
+        "samples": samples,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use super::*;
+
+    fn fixture(name: &str, contents: &str) -> (tempfile::TempDir, std::path::PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(name);
+        File::create(&path)
+            .unwrap()
+            .write_all(contents.as_bytes())
+            .unwrap();
+        (dir, path)
+    }
+
+    const MARKER_FIXTURE: &str = r#"1 {"timestamp": "100", "target": "db", "fields": {"message": "new"}, "span": {"name": "Compaction", "action": "Compaction"}}
+0 {"timestamp": "150", "level": "WARN", "target": "db::io", "fields": {"message": "slow read", "bytes": "4096"}}
+not a marker line
+1,42 {"timestamp": "160", "target": "db", "fields": {"message": "enter"}, "span": {"name": "Compaction", "action": "Compaction"}}
+1,42 {"timestamp": "170", "target": "db", "fields": {"message": "exit"}, "span": {"name": "Compaction", "action": "Compaction"}}
+2 {"timestamp": "180", "target": "db", "fields": {"message": "close"}, "span": {"name": "Flush", "action": "Flush"}}
+1 {"timestamp": "300", "target": "db", "fields": {"message": "close", "time.busy": "10ns", "time.idle": "190ns"}, "span": {"name": "Compaction", "action": "Compaction"}}
+3 {"timestamp": "400", "target": "db", "fields": {"message": "new"}, "span": {"name": "Scan", "action": "Scan"}}
+"#;
+
+    #[test]
+    fn marker_file_summary() {
+        let (_dir, path) = fixture("marker-1-1.txt", MARKER_FIXTURE);
+        let (summary, markers) = inspect_marker_file(&path).unwrap();
+        assert_eq!(markers.len(), 3);
+        assert_eq!(
+            summary.to_string(),
+            "\
+Lines: 8
+Lines with markers: 3
+Dropped lines:
+  invalid JSON: 1
+  keyword mismatch: 1
+Events: 1
+Total spans: 1
+Running spans: 1
+Unended spans: 1 new/close, 0 enter/exit
+Targets (2):
+  db
+  db::io
+Extra field sets (3):
+  [action]
+  [action, tid]
+  [bytes]
+Time range: 100 to 300 (200 ns)
+"
+        );
+
+        let json: Vec<_> = markers.iter().map(marker_to_json).collect();
+        assert_eq!(
+            json[0],
+            serde_json::json!({
+                "type": "event",
+                "name": "slow read",
+                "target": "db::io",
+                "level": "WARN",
+                "start": 150,
+                "fields": {"bytes": "4096"},
+            })
+        );
+        assert_eq!(json[1]["type"], "Running");
+        assert_eq!(json[1]["tid"], 42);
+        assert_eq!(json[2]["type"], "Total");
+        assert_eq!(json[2]["end"], 300);
+        assert_eq!(json[2]["busy"], 10);
+    }
+
+    #[test]
+    fn counter_file_summary() {
+        let (_dir, path) = fixture(
+            "counter-1.ndjson",
+            r#"{"name": "queue", "category": "Custom", "description": "Queue length"}
+{"t": 1000, "v": 3.0, "m": 1}
+{"t": 2000, "v": 5.5, "m": 2}
+{"t": 3000, "v": 1.0, "m": 1}
+"#,
+        );
+        let (summary, counter) = inspect_counter_file(&path).unwrap();
+        assert_eq!(
+            summary.to_string(),
+            "\
+Name: queue
+Category: Custom
+Description: Queue length
+Samples: 3
+Values: 1 to 5.5
+Time range: 1000 to 3000 (2000 ns)
+"
+        );
+        assert_eq!(
+            counter_to_json(&counter)["samples"],
+            serde_json::json!([[1000, 3.0, 1], [2000, 5.5, 2], [3000, 1.0, 1]])
+        );
+
+        let (_dir, path) = fixture("counter-2.json", "{}");
+        assert!(inspect_counter_file(&path).is_err());
+    }
+}
@@ -20,6 +20,7 @@ pub enum JsName {
 #[derive(Debug, Clone)]
 pub struct JitCategoryManager {
     categories: Vec<LazilyCreatedCategory>,
+    v8_tiered_categories: Vec<LazilyCreatedCategory>,
     baseline_interpreter_category: LazilyCreatedCategory,
     ion_ic_category: LazilyCreatedCategory,
     wasm_liftoff_category: LazilyCreatedCategory,
@@ -82,12 +83,23 @@ impl JitCategoryManager {
         ("LLInt: ", "LLInt", CategoryColor::Red, true),
     ];
 
+    /// (prefix, name, color) of the V8 execution tiers, as marked in the
+    /// perf map symbol names. Only used after `add_v8_tiered_categories`.
+    const V8_TIERED_CATEGORIES: &'static [(&'static str, &'static str, CategoryColor)] = &[
+        ("JS:~", "Ignition", CategoryColor::Magenta),
+        ("Script:~", "Ignition", CategoryColor::Magenta),
+        ("JS:^", "Sparkplug", CategoryColor::Blue),
+        ("JS:+", "Maglev", CategoryColor::Yellow),
+        ("JS:*", "TurboFan", CategoryColor::Green),
+    ];
+
     pub fn new() -> Self {
         Self {
             categories: Self::CATEGORIES
                 .iter()
                 .map(|(_prefix, name, color, _is_js)| LazilyCreatedCategory::new(name, *color))
                 .collect(),
+            v8_tiered_categories: Vec::new(),
             baseline_interpreter_category: LazilyCreatedCategory::new(
                 "BaselineInterpreter",
                 CategoryColor::Magenta,
@@ -105,6 +117,22 @@ impl JitCategoryManager {
         }
     }
 
+    /// Gives each V8 execution tier its own category: Ignition (interpreter),
+    /// Sparkplug (baseline compiler), Maglev and TurboFan. Without this, the
+    /// tiers share the engine-agnostic "Interpreter", "Baseline" etc.
+    /// categories, and Maglev and TurboFan code have the same color, which
+    /// makes tier-up transitions hard to see in the flame chart.
+    pub fn add_v8_tiered_categories(&mut self) {
+        self.v8_tiered_categories.clear();
+        for &(_prefix, name, color) in Self::V8_TIERED_CATEGORIES {
+            // Prefixes of the same tier share one category.
+            if !self.v8_tiered_categories.iter().any(|c| c.name == name) {
+                self.v8_tiered_categories
+                    .push(LazilyCreatedCategory::new(name, color));
+            }
+        }
+    }
+
     #[allow(dead_code)]
     pub fn default_category(&mut self, profile: &mut Profile) -> CategoryHandle {
         self.generic_jit_category.get(profile)
@@ -154,6 +182,24 @@ impl JitCategoryManager {
             return (category.into(), None);
         }
 
+        for &(prefix, category_name, _color) in Self::V8_TIERED_CATEGORIES {
+            let Some(lazy_category_handle) = self
+                .v8_tiered_categories
+                .iter_mut()
+                .find(|c| c.name == category_name)
+            else {
+                break;
+            };
+            if let Some(name_without_prefix) = name.strip_prefix(prefix) {
+                let category = lazy_category_handle.get(profile);
+                let js_name = JsFrame::RegularInAdditionToNativeFrame(Self::intern_js_name(
+                    profile,
+                    name_without_prefix,
+                ));
+                return (category.into(), Some(js_name));
+            }
+        }
+
         for (&(prefix, _category_name, _color, is_js), lazy_category_handle) in
             Self::CATEGORIES.iter().zip(self.categories.iter_mut())
         {
@@ -281,4 +327,64 @@ mod test {
             _ => panic!(),
         }
     }
+
+    fn js_frame_name(profile: &Profile, js_frame: Option<JsFrame>) -> &str {
+        match js_frame {
+            Some(JsFrame::RegularInAdditionToNativeFrame(JsName::NonSelfHosted(s))) => {
+                profile.get_string(s)
+            }
+            other => panic!("unexpected JS frame {other:?}"),
+        }
+    }
+
+    #[test]
+    fn v8_tiers() {
+        // Lines from a `node --perf-basic-prof` perf map, one per tier.
+        let perf_map = "\
+            3b1c0e042a0 3c Script:~ /app/index.js:1:1\n\
+            3b1c0e04340 7c JS:~render /app/index.js:10:16\n\
+            3b1c0e04420 1b4 JS:^render /app/index.js:10:16\n\
+            3b1c0e04600 2e8 JS:+render /app/index.js:10:16\n\
+            3b1c0e04900 5a0 JS:*render /app/index.js:10:16\n";
+        let mut profile = Profile::new(
+            "",
+            ReferenceTimestamp::from_millis_since_unix_epoch(0.0),
+            SamplingInterval::from_millis(1),
+        );
+
+        let mut manager = JitCategoryManager::new();
+        let untiered: Vec<_> = perf_map
+            .lines()
+            .map(|line| {
+                let symbol_name = line.splitn(3, ' ').nth(2).unwrap();
+                manager.classify_jit_symbol(symbol_name, &mut profile).0
+            })
+            .collect();
+
+        let mut manager = JitCategoryManager::new();
+        manager.add_v8_tiered_categories();
+        let tiered: Vec<_> = perf_map
+            .lines()
+            .map(|line| {
+                let symbol_name = line.splitn(3, ' ').nth(2).unwrap();
+                manager.classify_jit_symbol(symbol_name, &mut profile)
+            })
+            .collect();
+        // Top-level scripts and functions run by Ignition share a category.
+        assert_eq!(tiered[0].0, tiered[1].0);
+        for i in 1..tiered.len() {
+            for j in (i + 1)..tiered.len() {
+                assert_ne!(tiered[i].0, tiered[j].0, "tiers {i} and {j}");
+            }
+            assert_eq!(
+                js_frame_name(&profile, tiered[i].1),
+                "render /app/index.js:10:16"
+            );
+            assert!(!untiered.contains(&tiered[i].0));
+        }
+
+        // Other JIT symbols still get their usual categories.
+        let (category, _) = manager.classify_jit_symbol("Builtin: ArrayMap", &mut profile);
+        assert!(!tiered.iter().any(|(c, _)| *c == category));
+    }
 }
@@ -46,3 +46,74 @@ impl StaticSchemaMarker for JitFunctionAddMarker {
         unreachable!()
     }
 }
+
+/// Emitted for a JIT_CODE_MOVE record, when the JIT moves a function's code
+/// to a different address, e.g. during code GC. The old address is invalid
+/// from then on.
+#[derive(Debug, Clone)]
+pub struct JitFunctionMoveMarker {
+    pub old_addr: u64,
+    pub new_addr: u64,
+    pub size: u64,
+}
+
+impl StaticSchemaMarker for JitFunctionMoveMarker {
+    const UNIQUE_MARKER_TYPE_NAME: &'static str = "JitFunctionMove";
+
+    fn schema() -> MarkerSchema {
+        MarkerSchema {
+            type_name: Self::UNIQUE_MARKER_TYPE_NAME.into(),
+            locations: vec![MarkerLocation::MarkerChart, MarkerLocation::MarkerTable],
+            chart_label: None,
+            tooltip_label: Some("JIT function moved ({marker.data.size})".into()),
+            table_label: Some("{marker.data.old_addr} → {marker.data.new_addr}".into()),
+            fields: vec![
+                MarkerFieldSchema {
+                    key: "old_addr".into(),
+                    label: "Old address".into(),
+                    format: MarkerFieldFormat::Integer,
+                    searchable: false,
+                },
+                MarkerFieldSchema {
+                    key: "new_addr".into(),
+                    label: "New address".into(),
+                    format: MarkerFieldFormat::Integer,
+                    searchable: false,
+                },
+                MarkerFieldSchema {
+                    key: "size".into(),
+                    label: "Size".into(),
+                    format: MarkerFieldFormat::Bytes,
+                    searchable: false,
+                },
+            ],
+            static_fields: vec![MarkerStaticField {
+                label: "Description".into(),
+                value: "Emitted when the JIT moves a function's code to a different address."
+                    .into(),
+            }],
+            graphs: vec![],
+        }
+    }
+
+    fn name(&self, profile: &mut Profile) -> StringHandle {
+        profile.intern_string("JitFunctionMove")
+    }
+
+    fn category(&self, _profile: &mut Profile) -> CategoryHandle {
+        CategoryHandle::OTHER
+    }
+
+    fn string_field_value(&self, _field_index: u32) -> StringHandle {
+        unreachable!()
+    }
+
+    fn number_field_value(&self, field_index: u32) -> f64 {
+        match field_index {
+            0 => self.old_addr as f64,
+            1 => self.new_addr as f64,
+            2 => self.size as f64,
+            _ => unreachable!(),
+        }
+    }
+}
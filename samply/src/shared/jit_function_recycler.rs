@@ -1,28 +1,136 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
 use fxprof_processed_profile::LibraryHandle;
 
 use super::types::FastHashMap;
 
+/// How many bytes at the start of a function's machine code go into its key,
+/// unless the whole code has to match.
+const CODE_HASH_PREFIX_LEN: usize = 64;
+
 /// When running with --reuse-threads, and we run a process multiple times, and each
 /// of that invocations creates similar JIT functions, we want to collapse those "similar"
 /// JIT functions into the same JIT function so that the assembly view shows more hits.
 ///
-/// We define "similar" functions as functions which have the same name and code size (in bytes).
+/// We define "similar" functions as functions which have the same name and code size (in bytes),
+/// and, if we know their machine code, the same first `CODE_HASH_PREFIX_LEN` bytes of code.
+/// Generic names like `Function` can easily collide on name and size alone.
+///
+/// With `require_exact_code`, the whole machine code has to match, and functions whose
+/// code we don't know are never collapsed.
 #[derive(Debug, Clone, Default)]
 pub struct JitFunctionRecycler {
-    jit_functions_for_reuse_by_name_and_size: FastHashMap<(String, u32), (LibraryHandle, u32)>,
+    require_exact_code: bool,
+    jit_functions_for_reuse_by_key: FastHashMap<JitFunctionKey, (LibraryHandle, u32)>,
 }
 
+/// The name, the code size and, if known, a hash of the machine code.
+type JitFunctionKey = (String, u32, Option<u64>);
+
 impl JitFunctionRecycler {
+    pub fn new(require_exact_code: bool) -> Self {
+        Self {
+            require_exact_code,
+            ..Default::default()
+        }
+    }
+
     pub fn recycle(
         &mut self,
         name: &str,
         code_size: u32,
+        code_bytes: Option<&[u8]>,
         lib_handle: LibraryHandle,
         relative_address: u32,
     ) -> (LibraryHandle, u32) {
+        let code_hash = match code_bytes {
+            Some(code_bytes) if self.require_exact_code => Some(hash_code(code_bytes)),
+            Some(code_bytes) => Some(hash_code(
+                &code_bytes[..code_bytes.len().min(CODE_HASH_PREFIX_LEN)],
+            )),
+            None if self.require_exact_code => return (lib_handle, relative_address),
+            None => None,
+        };
         *self
-            .jit_functions_for_reuse_by_name_and_size
-            .entry((name.to_owned(), code_size))
+            .jit_functions_for_reuse_by_key
+            .entry((name.to_owned(), code_size, code_hash))
             .or_insert((lib_handle, relative_address))
     }
 }
+
+fn hash_code(code_bytes: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    code_bytes.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::shared::test_support::{add_synthetic_lib, new_profile};
+
+    #[test]
+    fn functions_with_different_code_are_not_merged() {
+        let mut profile = new_profile();
+        let first = add_synthetic_lib(&mut profile, 0);
+        let second = add_synthetic_lib(&mut profile, 1);
+        let third = add_synthetic_lib(&mut profile, 2);
+        let code_a = [0x55, 0x48, 0x89, 0xe5, 0xc3];
+        let code_b = [0x55, 0x48, 0x89, 0xe5, 0x90];
+
+        let mut recycler = JitFunctionRecycler::default();
+        assert_eq!(
+            recycler.recycle("Function", 5, Some(&code_a), first, 0),
+            (first, 0)
+        );
+        // Same name and size, but different code: a collision, not a recompile.
+        assert_eq!(
+            recycler.recycle("Function", 5, Some(&code_b), second, 16),
+            (second, 16)
+        );
+        assert_eq!(
+            recycler.recycle("Function", 5, Some(&code_a), third, 32),
+            (first, 0)
+        );
+        // Without code bytes, name and size decide, as before.
+        assert_eq!(
+            recycler.recycle("Function", 5, None, first, 48),
+            (first, 48)
+        );
+        assert_eq!(
+            recycler.recycle("Function", 5, None, third, 64),
+            (first, 48)
+        );
+    }
+
+    #[test]
+    fn exact_code_matching() {
+        let mut profile = new_profile();
+        let first = add_synthetic_lib(&mut profile, 0);
+        let second = add_synthetic_lib(&mut profile, 1);
+        // The functions only differ after the hashed prefix.
+        let mut code_a = vec![0x90; CODE_HASH_PREFIX_LEN + 1];
+        let code_b = code_a.clone();
+        *code_a.last_mut().unwrap() = 0xc3;
+
+        let mut recycler = JitFunctionRecycler::default();
+        recycler.recycle("Function", code_a.len() as u32, Some(&code_a), first, 0);
+        assert_eq!(
+            recycler.recycle("Function", code_b.len() as u32, Some(&code_b), second, 80),
+            (first, 0)
+        );
+
+        let mut recycler = JitFunctionRecycler::new(true);
+        recycler.recycle("Function", code_a.len() as u32, Some(&code_a), first, 0);
+        assert_eq!(
+            recycler.recycle("Function", code_b.len() as u32, Some(&code_b), second, 80),
+            (second, 80)
+        );
+        recycler.recycle("Function", 5, None, first, 160);
+        assert_eq!(
+            recycler.recycle("Function", 5, None, second, 240),
+            (second, 240)
+        );
+    }
+}
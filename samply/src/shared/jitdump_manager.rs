@@ -1,28 +1,43 @@
+use std::fs::File;
+use std::io::{Cursor, Read, Seek, SeekFrom};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use fxprof_processed_profile::{
     LibraryHandle, MarkerTiming, Profile, Symbol, SymbolTable, ThreadHandle,
 };
-use linux_perf_data::jitdump::{JitDumpReader, JitDumpRecord, JitDumpRecordType};
+use linux_perf_data::jitdump::{JitDumpError, JitDumpReader, JitDumpRecord, JitDumpRecordType};
 
+use super::error::Error;
 use super::jit_category_manager::JitCategoryManager;
-use super::jit_function_add_marker::JitFunctionAddMarker;
+use super::jit_function_add_marker::{JitFunctionAddMarker, JitFunctionMoveMarker};
 use super::jit_function_recycler::JitFunctionRecycler;
 use super::lib_mappings::{
     LibMappingAdd, LibMappingInfo, LibMappingMove, LibMappingOp, LibMappingOpQueue,
 };
+use super::memory_usage::{vec_heap_size, MemoryUsage};
 use super::timestamp_converter::TimestampConverter;
 use super::utils::open_file_with_fallback;
 
 #[derive(Debug)]
 pub struct JitDumpManager {
-    pending_jitdump_paths: Vec<(ThreadHandle, PathBuf, Vec<PathBuf>)>,
+    pending_jitdump_paths: Vec<PendingJitDump>,
     processors: Vec<SingleJitDumpProcessor>,
     unlink_after_open: bool,
     should_emit_jit_markers: bool,
 }
 
+/// A jitdump file which couldn't be opened yet, e.g. because the JIT hasn't
+/// created it yet.
+#[derive(Debug)]
+struct PendingJitDump {
+    thread: ThreadHandle,
+    path: PathBuf,
+    lookup_dirs: Vec<PathBuf>,
+    /// Why the last attempt to open it failed, to report if it never opens.
+    last_error: Option<Error>,
+}
+
 impl JitDumpManager {
     pub fn new(unlink_after_open: bool, should_emit_jit_markers: bool) -> Self {
         JitDumpManager {
@@ -39,8 +54,12 @@ impl JitDumpManager {
         path: impl Into<PathBuf>,
         lookup_dirs: Vec<PathBuf>,
     ) {
-        self.pending_jitdump_paths
-            .push((thread, path.into(), lookup_dirs));
+        self.pending_jitdump_paths.push(PendingJitDump {
+            thread,
+            path: path.into(),
+            lookup_dirs,
+            last_error: None,
+        });
     }
 
     pub fn process_pending_records(
@@ -50,34 +69,30 @@ impl JitDumpManager {
         mut recycler: Option<&mut JitFunctionRecycler>,
         timestamp_converter: &TimestampConverter,
     ) {
-        self.pending_jitdump_paths
-            .retain_mut(|(thread, path, lookup_dirs)| {
-                fn jitdump_reader_for_path(
-                    path: &Path,
-                    lookup_dirs: &[PathBuf],
-                    unlink_after_open: bool,
-                ) -> Option<(JitDumpReader<std::fs::File>, PathBuf)> {
-                    let (file, path) = open_file_with_fallback(path, lookup_dirs).ok()?;
-                    let reader = JitDumpReader::new(file).ok()?;
-                    if unlink_after_open {
-                        std::fs::remove_file(&path).ok()?;
-                    }
-                    Some((reader, path))
-                }
-                let Some((reader, actual_path)) =
-                    jitdump_reader_for_path(path, lookup_dirs, self.unlink_after_open)
-                else {
+        self.pending_jitdump_paths.retain_mut(|pending| {
+            let (reader, actual_path) = match jitdump_reader_for_path(
+                &pending.path,
+                &pending.lookup_dirs,
+                self.unlink_after_open,
+            ) {
+                Ok(reader_and_path) => reader_and_path,
+                Err(e) => {
+                    pending.last_error = Some(e);
                     return true;
-                };
-                let lib_handle = crate::shared::utils::lib_handle_for_jitdump(
-                    &actual_path,
-                    reader.header(),
-                    profile,
-                );
-                self.processors
-                    .push(SingleJitDumpProcessor::new(reader, lib_handle, *thread));
-                false // "Do not retain", i.e. remove from pending_jitdump_paths
-            });
+                }
+            };
+            let lib_handle = crate::shared::utils::lib_handle_for_jitdump(
+                &actual_path,
+                reader.header(),
+                profile,
+            );
+            self.processors.push(SingleJitDumpProcessor::new(
+                reader,
+                lib_handle,
+                pending.thread,
+            ));
+            false // "Do not retain", i.e. remove from pending_jitdump_paths
+        });
 
         for jitdump in &mut self.processors {
             jitdump.process_pending_records(
@@ -98,6 +113,11 @@ impl JitDumpManager {
         timestamp_converter: &TimestampConverter,
     ) -> Vec<LibMappingOpQueue> {
         self.process_pending_records(jit_category_manager, profile, recycler, timestamp_converter);
+        for pending in &self.pending_jitdump_paths {
+            if let Some(e) = &pending.last_error {
+                e.report();
+            }
+        }
         self.processors
             .into_iter()
             .map(|processor| processor.finish(profile))
@@ -105,10 +125,118 @@ impl JitDumpManager {
     }
 }
 
+/// The magic number at the start of a zstd frame.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// A jitdump file. Jitdumps which were compressed with zstd are decompressed
+/// into memory, because the reader needs to seek.
+#[derive(Debug)]
+enum JitDumpFile {
+    Plain(File),
+    Decompressed(Cursor<Vec<u8>>),
+}
+
+impl JitDumpFile {
+    /// Checks whether the file is compressed, by its `.zst` extension or by
+    /// the zstd magic number, and decompresses it if it is.
+    fn new(mut file: File, path: &Path) -> std::io::Result<Self> {
+        let mut magic = [0; 4];
+        // A jitdump which is still being written can be shorter than that.
+        let has_zstd_magic = file.read_exact(&mut magic).is_ok() && magic == ZSTD_MAGIC;
+        file.seek(SeekFrom::Start(0))?;
+        let has_zst_extension = path.extension().is_some_and(|ext| ext == "zst");
+        if !has_zstd_magic && !has_zst_extension {
+            return Ok(JitDumpFile::Plain(file));
+        }
+        let mut decoder = ruzstd::StreamingDecoder::new(file)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))?;
+        let mut data = Vec::new();
+        decoder.read_to_end(&mut data)?;
+        Ok(JitDumpFile::Decompressed(Cursor::new(data)))
+    }
+}
+
+impl Read for JitDumpFile {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            JitDumpFile::Plain(file) => file.read(buf),
+            JitDumpFile::Decompressed(data) => data.read(buf),
+        }
+    }
+}
+
+impl Seek for JitDumpFile {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        match self {
+            JitDumpFile::Plain(file) => file.seek(pos),
+            JitDumpFile::Decompressed(data) => data.seek(pos),
+        }
+    }
+}
+
+/// Opens the jitdump file at `path`, or with the same name in one of
+/// `lookup_dirs`, and reads its header. If there is no such file, a
+/// zstd-compressed `<path>.zst` is used instead, e.g. for a recording which
+/// was copied from another machine with its jitdumps compressed.
+fn jitdump_reader_for_path(
+    path: &Path,
+    lookup_dirs: &[PathBuf],
+    unlink_after_open: bool,
+) -> Result<(JitDumpReader<JitDumpFile>, PathBuf), Error> {
+    let (file, path) = open_file_with_fallback(path, lookup_dirs).or_else(|e| {
+        let mut compressed_path = path.as_os_str().to_owned();
+        compressed_path.push(".zst");
+        open_file_with_fallback(Path::new(&compressed_path), lookup_dirs).map_err(|_| e)
+    })?;
+    let file = JitDumpFile::new(file, &path).map_err(|source| Error::Io {
+        path: path.clone(),
+        source,
+    })?;
+    let reader = JitDumpReader::new(file).map_err(|e| match e {
+        JitDumpError::Io(source) => Error::Io {
+            path: path.clone(),
+            source,
+        },
+        e => Error::Parse {
+            path: path.clone(),
+            line: None,
+            reason: e.to_string(),
+        },
+    })?;
+    if unlink_after_open {
+        std::fs::remove_file(&path).map_err(|source| Error::Io {
+            path: path.clone(),
+            source,
+        })?;
+    }
+    Ok((reader, path))
+}
+
+impl MemoryUsage for JitDumpManager {
+    /// The lib mapping ops and symbols of the jitdump files. The readers'
+    /// buffers aren't included.
+    fn heap_size(&self) -> usize {
+        vec_heap_size(&self.processors)
+            + self
+                .processors
+                .iter()
+                .map(|processor| {
+                    processor.lib_mapping_ops.heap_size()
+                        + vec_heap_size(&processor.symbols)
+                        + processor
+                            .symbols
+                            .iter()
+                            .map(|symbol| symbol.name.heap_size())
+                            .sum::<usize>()
+                })
+                .sum::<usize>()
+    }
+}
+
 #[derive(Debug)]
 struct SingleJitDumpProcessor {
     /// Some() until a JIT_CODE_CLOSE record is encountered.
-    reader: Option<JitDumpReader<std::fs::File>>,
+    reader: Option<JitDumpReader<JitDumpFile>>,
     lib_handle: LibraryHandle,
     lib_mapping_ops: LibMappingOpQueue,
     symbols: Vec<Symbol>,
@@ -126,7 +254,7 @@ struct SingleJitDumpProcessor {
 
 impl SingleJitDumpProcessor {
     pub fn new(
-        reader: JitDumpReader<std::fs::File>,
+        reader: JitDumpReader<JitDumpFile>,
         lib_handle: LibraryHandle,
         thread_handle: ThreadHandle,
     ) -> Self {
@@ -176,7 +304,8 @@ impl SingleJitDumpProcessor {
             match raw_jitdump_record.parse() {
                 Ok(JitDumpRecord::CodeLoad(record)) => {
                     let start_avma = record.code_addr;
-                    let code_size = record.code_bytes.len() as u32;
+                    let code_bytes = record.code_bytes.as_slice();
+                    let code_size = code_bytes.len() as u32;
                     let end_avma = start_avma + u64::from(code_size);
 
                     let relative_address_at_start = self.cumulative_address;
@@ -206,6 +335,7 @@ impl SingleJitDumpProcessor {
                             recycler.recycle(
                                 symbol_name,
                                 code_size,
+                                Some(&code_bytes),
                                 self.lib_handle,
                                 relative_address_at_start,
                             )
@@ -227,6 +357,19 @@ impl SingleJitDumpProcessor {
                     // TODO: Add to unwinder so that it can use the code bytes for prologue / epilogue detection
                 }
                 Ok(JitDumpRecord::CodeMove(record)) => {
+                    if should_add_marker {
+                        let timestamp =
+                            timestamp_converter.convert_time(raw_jitdump_record.timestamp);
+                        profile.add_marker(
+                            self.thread_handle,
+                            MarkerTiming::Instant(timestamp),
+                            JitFunctionMoveMarker {
+                                old_addr: record.old_code_addr,
+                                new_addr: record.new_code_addr,
+                                size: record.code_size,
+                            },
+                        );
+                    }
                     self.lib_mapping_ops.push(
                         raw_jitdump_record.timestamp,
                         LibMappingOp::Move(LibMappingMove {
@@ -267,3 +410,177 @@ impl SingleJitDumpProcessor {
         self.lib_mapping_ops
     }
 }
+
+#[cfg(test)]
+mod test {
+    use fxprof_processed_profile::{LibraryInfo, Timestamp};
+
+    use super::*;
+    use crate::shared::lib_mappings::LibMappingsHierarchy;
+    use crate::shared::test_support::identity_converter;
+
+    fn fixture_dir() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("../fixtures/other/jitdump")
+    }
+
+    /// The type, timestamp and raw body of every record in the jitdump.
+    fn read_records(path: &Path, lookup_dirs: &[PathBuf]) -> Vec<(u32, u64, Vec<u8>)> {
+        let (mut reader, _) = jitdump_reader_for_path(path, lookup_dirs, false).unwrap();
+        let mut records = Vec::new();
+        while let Some(record) = reader.next_record().unwrap() {
+            records.push((
+                record.record_type.0,
+                record.timestamp,
+                record.body.as_slice().to_vec(),
+            ));
+        }
+        records
+    }
+
+    #[test]
+    fn compressed_jitdump_reads_like_the_plain_one() {
+        let plain = read_records(&fixture_dir().join("jit-4242.dump"), &[]);
+        assert_eq!(plain.len(), 4);
+        assert_eq!(plain[3].0, JitDumpRecordType::JIT_CODE_CLOSE.0);
+        let compressed = read_records(&fixture_dir().join("jit-4242.dump.zst"), &[]);
+        assert_eq!(compressed, plain);
+
+        // The compressed file stands in for a missing plain one.
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::copy(
+            fixture_dir().join("jit-4242.dump.zst"),
+            dir.path().join("jit-4242.dump.zst"),
+        )
+        .unwrap();
+        let lookup_dirs = [dir.path().to_owned()];
+        let from_fallback = read_records(Path::new("/nonexistent/jit-4242.dump"), &lookup_dirs);
+        assert_eq!(from_fallback, plain);
+    }
+
+    #[test]
+    fn pending_jitdump_keeps_the_last_open_error() {
+        let mut profile = crate::shared::test_support::new_profile();
+        let start = Timestamp::from_millis_since_reference(0.0);
+        let process = profile.add_process("jit", 4242, start);
+        let thread = profile.add_thread(process, 4243, start, true);
+        let timestamp_converter = identity_converter();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("jit-4242.dump");
+        let mut manager = JitDumpManager::new(false, false);
+        manager.add_jitdump_path(thread, &path, Vec::new());
+
+        manager.process_pending_records(
+            &mut JitCategoryManager::new(),
+            &mut profile,
+            None,
+            &timestamp_converter,
+        );
+        assert_eq!(manager.pending_jitdump_paths.len(), 1);
+        assert!(matches!(
+            manager.pending_jitdump_paths[0].last_error,
+            Some(Error::NotFound(_))
+        ));
+
+        // Once the file appears, it is opened and nothing is left to report.
+        std::fs::write(&path, jitdump_with_move()).unwrap();
+        manager.process_pending_records(
+            &mut JitCategoryManager::new(),
+            &mut profile,
+            None,
+            &timestamp_converter,
+        );
+        assert!(manager.pending_jitdump_paths.is_empty());
+    }
+
+    /// A jitdump record with the given type, timestamp and body.
+    fn jitdump_record(record_type: u32, timestamp: u64, body: &[u8]) -> Vec<u8> {
+        let mut record = Vec::new();
+        record.extend_from_slice(&record_type.to_le_bytes());
+        record.extend_from_slice(&(16 + body.len() as u32).to_le_bytes());
+        record.extend_from_slice(&timestamp.to_le_bytes());
+        record.extend_from_slice(body);
+        record
+    }
+
+    /// A jitdump which loads `f` at 0x1000, moves it to 0x8000, and closes.
+    fn jitdump_with_move() -> Vec<u8> {
+        let mut dump = Vec::new();
+        for field in [0x4A695444u32, 1, 40, 62, 0, 4242] {
+            dump.extend_from_slice(&field.to_le_bytes());
+        }
+        dump.extend_from_slice(&0u64.to_le_bytes()); // timestamp
+        dump.extend_from_slice(&0u64.to_le_bytes()); // flags
+
+        let mut load = Vec::new();
+        load.extend_from_slice(&4242u32.to_le_bytes()); // pid
+        load.extend_from_slice(&4243u32.to_le_bytes()); // tid
+        for field in [0x1000u64, 0x1000, 0x20, 0] {
+            // vma, code_addr, code_size, code_index
+            load.extend_from_slice(&field.to_le_bytes());
+        }
+        load.extend_from_slice(b"f\0");
+        load.extend_from_slice(&[0xc3; 0x20]);
+        dump.extend(jitdump_record(0, 1_000, &load));
+
+        let mut code_move = Vec::new();
+        code_move.extend_from_slice(&4242u32.to_le_bytes());
+        code_move.extend_from_slice(&4243u32.to_le_bytes());
+        for field in [0x8000u64, 0x1000, 0x8000, 0x20, 0] {
+            // vma, old_code_addr, new_code_addr, code_size, code_index
+            code_move.extend_from_slice(&field.to_le_bytes());
+        }
+        dump.extend(jitdump_record(1, 2_000, &code_move));
+        dump.extend(jitdump_record(3, 3_000, &[]));
+        dump
+    }
+
+    #[test]
+    fn code_move_adds_marker_and_moves_the_mapping() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("jit-4242.dump");
+        std::fs::write(&path, jitdump_with_move()).unwrap();
+
+        let mut profile = crate::shared::test_support::new_profile();
+        let start = Timestamp::from_millis_since_reference(0.0);
+        let process = profile.add_process("jit", 4242, start);
+        let thread = profile.add_thread(process, 4243, start, true);
+        let lib_handle = profile.add_lib(LibraryInfo {
+            name: "jit-4242.dump".into(),
+            debug_name: "jit-4242.dump".into(),
+            path: path.to_string_lossy().into(),
+            debug_path: path.to_string_lossy().into(),
+            debug_id: Default::default(),
+            code_id: None,
+            arch: None,
+            symbol_table: None,
+        });
+        let timestamp_converter = identity_converter();
+        let (reader, _) = jitdump_reader_for_path(&path, &[], false).unwrap();
+        let mut processor = SingleJitDumpProcessor::new(reader, lib_handle, thread);
+        processor.process_pending_records(
+            &mut JitCategoryManager::new(),
+            &mut profile,
+            None,
+            &timestamp_converter,
+            true,
+        );
+        let ops = processor.finish(&mut profile);
+
+        let json = serde_json::to_value(&profile).unwrap();
+        let markers = &json["threads"][0]["markers"];
+        assert_eq!(markers["length"], 2);
+        let move_marker = &markers["data"][1];
+        assert_eq!(move_marker["type"], "JitFunctionMove");
+        assert_eq!(move_marker["old_addr"], 4096.0);
+        assert_eq!(move_marker["new_addr"], 32768.0);
+        assert_eq!(move_marker["size"], 32.0);
+
+        let mut mappings = LibMappingsHierarchy::new(LibMappingOpQueue::default());
+        mappings.add_jitdump_lib_mappings_ops(ops);
+        mappings.process_ops(1_500);
+        assert_eq!(mappings.convert_address(0x1004).map(|(a, _)| a), Some(4));
+        mappings.process_ops(2_500);
+        assert!(mappings.convert_address(0x1004).is_none());
+        assert_eq!(mappings.convert_address(0x8004).map(|(a, _)| a), Some(4));
+    }
+}
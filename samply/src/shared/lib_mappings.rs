@@ -1,8 +1,9 @@
 use std::iter::Peekable;
 
-use fxprof_processed_profile::{CategoryPairHandle, LibMappings, LibraryHandle};
+use fxprof_processed_profile::{CategoryPairHandle, LibMappings, LibraryHandle, Profile};
 
 use super::jit_category_manager::JsFrame;
+use super::memory_usage::{vec_heap_size, MemoryUsage};
 
 #[derive(Debug, Clone)]
 pub struct LibMappingInfo {
@@ -82,6 +83,7 @@ pub struct LibMappingsHierarchy {
     regular_libs: (LibMappings<LibMappingInfo>, LibMappingOpQueueIter),
     jitdumps: Vec<(LibMappings<LibMappingInfo>, LibMappingOpQueueIter)>,
     perf_map: Option<LibMappings<LibMappingInfo>>,
+    aslr_tolerance: Option<u64>,
 }
 
 impl LibMappingsHierarchy {
@@ -90,14 +92,39 @@ impl LibMappingsHierarchy {
             regular_libs: (LibMappings::default(), regular_lib_mappings_ops.into_iter()),
             jitdumps: Vec::new(),
             perf_map: None,
+            aslr_tolerance: None,
         }
     }
 
+    /// Attribute addresses which aren't in any lib mapping to a regular lib
+    /// mapping within `tolerance` bytes, for recordings where the
+    /// ASLR slide of the mappings doesn't quite match the one of the
+    /// samples. See `LibMappings::fuzzy_lookup` for the precision tradeoffs.
+    pub fn set_aslr_tolerance(&mut self, tolerance: Option<u64>) {
+        self.aslr_tolerance = tolerance;
+    }
+
     pub fn add_jitdump_lib_mappings_ops(&mut self, lib_mappings_ops: LibMappingOpQueue) {
         self.jitdumps
             .push((LibMappings::default(), lib_mappings_ops.into_iter()));
     }
 
+    /// Append ops which come after the ones added so far. The i-th queue in
+    /// `jitdump_lib_mappings_ops` continues the i-th jitdump's ops.
+    pub fn extend_ops(
+        &mut self,
+        regular_lib_mappings_ops: LibMappingOpQueue,
+        jitdump_lib_mappings_ops: Vec<LibMappingOpQueue>,
+    ) {
+        self.regular_libs.1.extend(regular_lib_mappings_ops);
+        for (i, lib_mappings_ops) in jitdump_lib_mappings_ops.into_iter().enumerate() {
+            match self.jitdumps.get_mut(i) {
+                Some((_mappings, ops)) => ops.extend(lib_mappings_ops),
+                None => self.add_jitdump_lib_mappings_ops(lib_mappings_ops),
+            }
+        }
+    }
+
     pub fn add_perf_map_mappings(&mut self, mappings: LibMappings<LibMappingInfo>) {
         self.perf_map = Some(mappings);
     }
@@ -127,7 +154,32 @@ impl LibMappingsHierarchy {
                 return Some(x);
             }
         }
-        None
+        let tolerance = self.aslr_tolerance?;
+        Self::convert_address_fuzzy(&self.regular_libs.0, address, tolerance)
+    }
+
+    /// Converts `address` relative to a mapping within `tolerance` bytes of
+    /// it. An address between two such mappings is attributed to the one
+    /// below it, whose end it has run past. The relative address can be
+    /// outside of the mapping.
+    fn convert_address_fuzzy(
+        mappings: &LibMappings<LibMappingInfo>,
+        address: u64,
+        tolerance: u64,
+    ) -> Option<(u32, &LibMappingInfo)> {
+        let candidates = mappings.fuzzy_lookup(address, tolerance);
+        let below = candidates
+            .iter()
+            .rev()
+            .find(|(start_avma, _)| *start_avma <= address);
+        let (start_avma, _) = below.or_else(|| candidates.first())?;
+        let (relative_address_at_start, info) = mappings.convert_address(*start_avma)?;
+        let relative_address = if address >= *start_avma {
+            relative_address_at_start.checked_add(u32::try_from(address - start_avma).ok()?)?
+        } else {
+            relative_address_at_start.checked_sub(u32::try_from(start_avma - address).ok()?)?
+        };
+        Some((relative_address, info))
     }
 }
 
@@ -143,15 +195,42 @@ impl LibMappingOpQueue {
         self.0.is_empty()
     }
 
+    pub fn ops(&self) -> &[(u64, LibMappingOp)] {
+        &self.0
+    }
+
+    /// Adds the ops of `other`, so that all of them are in timestamp order.
+    /// Of two ops at the same timestamp, the one from `self` comes first.
+    pub fn merge(&mut self, other: LibMappingOpQueue) {
+        self.0.extend(other.0);
+        self.0.sort_by_key(|(timestamp, _)| *timestamp);
+    }
+
     pub fn into_iter(self) -> LibMappingOpQueueIter {
         LibMappingOpQueueIter(self.0.into_iter().peekable())
     }
 }
 
+impl MemoryUsage for LibMappingOpQueue {
+    fn heap_size(&self) -> usize {
+        vec_heap_size(&self.0)
+    }
+}
+
 #[derive(Debug)]
 pub struct LibMappingOpQueueIter(Peekable<std::vec::IntoIter<(u64, LibMappingOp)>>);
 
 impl LibMappingOpQueueIter {
+    /// Append the ops in `queue`, after the ops which haven't been consumed yet.
+    pub fn extend(&mut self, queue: LibMappingOpQueue) {
+        if queue.is_empty() {
+            return;
+        }
+        let mut ops: Vec<_> = self.0.by_ref().collect();
+        ops.extend(queue.0);
+        self.0 = ops.into_iter().peekable();
+    }
+
     pub fn next_op_if_at_or_before(&mut self, timestamp: u64) -> Option<LibMappingOp> {
         if self.0.peek()?.0 > timestamp {
             return None;
@@ -223,3 +302,88 @@ pub struct LibMappingMove {
 pub struct LibMappingRemove {
     pub start_avma: u64,
 }
+
+/// The first mapping, by start address, of the library called `name`, as
+/// `(start_avma, end_avma, info)`. `LibMappingInfo` only has the library
+/// handle, so the names are looked up in `profile`.
+pub fn find_by_name<'a>(
+    mappings: &'a LibMappings<LibMappingInfo>,
+    profile: &Profile,
+    name: &str,
+) -> Option<(u64, u64, &'a LibMappingInfo)> {
+    find_all_by_name(mappings, profile, name).next()
+}
+
+/// Every mapping of the library called `name`, for libraries which are
+/// mapped at multiple addresses, see `find_by_name`.
+pub fn find_all_by_name<'a, 'p>(
+    mappings: &'a LibMappings<LibMappingInfo>,
+    profile: &'p Profile,
+    name: &'p str,
+) -> impl Iterator<Item = (u64, u64, &'a LibMappingInfo)> + 'p
+where
+    'a: 'p,
+{
+    mappings
+        .iter()
+        .filter(move |(_, _, info)| profile.library_info(info.lib_handle).name == name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::shared::test_support::{add_synthetic_lib, new_profile};
+
+    #[test]
+    fn mappings_are_found_by_library_name() {
+        let mut profile = new_profile();
+        let lib0 = add_synthetic_lib(&mut profile, 0);
+        let lib1 = add_synthetic_lib(&mut profile, 1);
+        let mut mappings = LibMappings::new();
+        mappings.add_mapping(0x3000, 0x4000, 0x1000, LibMappingInfo::new_lib(lib0));
+        mappings.add_mapping(0x1000, 0x2000, 0, LibMappingInfo::new_lib(lib0));
+        mappings.add_mapping(0x2000, 0x3000, 0, LibMappingInfo::new_lib(lib1));
+
+        let (start, end, info) = find_by_name(&mappings, &profile, "libsynthetic0.so").unwrap();
+        assert_eq!((start, end, info.lib_handle), (0x1000, 0x2000, lib0));
+        let all: Vec<_> = find_all_by_name(&mappings, &profile, "libsynthetic0.so")
+            .map(|(start, end, _)| (start, end))
+            .collect();
+        assert_eq!(all, [(0x1000, 0x2000), (0x3000, 0x4000)]);
+        assert!(find_by_name(&mappings, &profile, "libc.so.6").is_none());
+    }
+
+    #[test]
+    fn aslr_tolerance_attributes_nearby_addresses() {
+        let mut profile = new_profile();
+        let lib0 = add_synthetic_lib(&mut profile, 0);
+        let lib1 = add_synthetic_lib(&mut profile, 1);
+        let mut ops = LibMappingOpQueue::default();
+        for (start_avma, end_avma, lib) in [(0x1000, 0x2000, lib0), (0x2080, 0x3000, lib1)] {
+            ops.push(
+                0,
+                LibMappingOp::Add(LibMappingAdd {
+                    start_avma,
+                    end_avma,
+                    relative_address_at_start: 0x100,
+                    info: LibMappingInfo::new_lib(lib),
+                }),
+            );
+        }
+        let mut hierarchy = LibMappingsHierarchy::new(ops);
+        hierarchy.process_ops(0);
+        let convert = |hierarchy: &LibMappingsHierarchy, address| {
+            hierarchy
+                .convert_address(address)
+                .map(|(relative_address, info)| (relative_address, info.lib_handle))
+        };
+        assert_eq!(convert(&hierarchy, 0x2040), None);
+
+        hierarchy.set_aslr_tolerance(Some(0x100));
+        assert_eq!(convert(&hierarchy, 0x1010), Some((0x110, lib0)));
+        assert_eq!(convert(&hierarchy, 0x2040), Some((0x1140, lib0)));
+        assert_eq!(convert(&hierarchy, 0xff0), Some((0xf0, lib0)));
+        assert_eq!(convert(&hierarchy, 0x3010), Some((0x1090, lib1)));
+        assert_eq!(convert(&hierarchy, 0x3200), None);
+    }
+}
@@ -0,0 +1,111 @@
+use std::sync::{Mutex, OnceLock};
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+use super::marker_file::EventOrSpanMarker;
+use super::timestamp_converter::TimestampConverter;
+
+static LOGGER: OnceLock<MarkerCollectingLogger> = OnceLock::new();
+
+/// A logger which passes the records on to env_logger, and which keeps the
+/// records of `Warn` and higher as markers once `start_collecting` has been
+/// called, so that the problems samply ran into during a recording show up
+/// in the profile when they happened, whether or not `RUST_LOG` shows them.
+struct MarkerCollectingLogger {
+    inner: env_logger::Logger,
+    converter: Mutex<Option<TimestampConverter>>,
+    markers: Mutex<Vec<EventOrSpanMarker>>,
+}
+
+impl Log for MarkerCollectingLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata) || metadata.level() <= Level::Warn
+    }
+
+    fn log(&self, record: &Record) {
+        if self.inner.matches(record) {
+            self.inner.log(record);
+        }
+        if record.level() > Level::Warn {
+            return;
+        }
+        if let Some(converter) = *self.converter.lock().unwrap() {
+            let marker = EventOrSpanMarker::from_log_record(record, &converter);
+            self.markers.lock().unwrap().push(marker);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+/// Installs the logger, instead of `env_logger::init()`.
+pub fn init() {
+    let logger = LOGGER.get_or_init(|| MarkerCollectingLogger {
+        inner: env_logger::Builder::from_default_env().build(),
+        converter: Mutex::new(None),
+        markers: Mutex::new(Vec::new()),
+    });
+    if log::set_logger(logger).is_ok() {
+        log::set_max_level(logger.inner.filter().max(LevelFilter::Warn));
+    }
+}
+
+/// Keep the warnings which are logged from now on as markers, with
+/// timestamps from `converter`.
+pub fn start_collecting(converter: TimestampConverter) {
+    if let Some(logger) = LOGGER.get() {
+        *logger.converter.lock().unwrap() = Some(converter);
+    }
+}
+
+/// The markers of the warnings which were logged since `start_collecting`.
+pub fn take_markers() -> Vec<EventOrSpanMarker> {
+    match LOGGER.get() {
+        Some(logger) => std::mem::take(&mut *logger.markers.lock().unwrap()),
+        None => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::shared::marker_file::EventLevel;
+    use crate::shared::test_support::identity_converter;
+
+    #[test]
+    fn only_warnings_are_collected_once_started() {
+        let logger = MarkerCollectingLogger {
+            inner: env_logger::Builder::new().build(),
+            converter: Mutex::new(None),
+            markers: Mutex::new(Vec::new()),
+        };
+        let record = |level, message| {
+            logger.log(
+                &Record::builder()
+                    .args(format_args!("{message}"))
+                    .level(level)
+                    .target("samply")
+                    .build(),
+            )
+        };
+        record(Level::Warn, "before");
+        *logger.converter.lock().unwrap() = Some(identity_converter());
+        record(Level::Info, "info");
+        record(Level::Warn, "warning");
+        record(Level::Error, "error");
+        let markers = logger.markers.into_inner().unwrap();
+        let messages: Vec<_> = markers
+            .iter()
+            .map(|marker| (marker.message.as_str(), marker.level))
+            .collect();
+        assert_eq!(
+            messages,
+            [
+                ("warning", Some(EventLevel::Warn)),
+                ("error", Some(EventLevel::Error)),
+            ]
+        );
+    }
+}
@@ -1,15 +1,22 @@
-use std::collections::HashMap;
+use std::cell::Cell;
+use std::collections::{HashMap, VecDeque};
 use std::fmt::Display;
 use std::fs::File;
-use std::io::{BufRead, BufReader, Lines};
+use std::io::{BufRead, BufReader, Cursor, Lines, Read};
 use std::ops::AddAssign;
 use std::path::{Path, PathBuf};
-use std::time::Duration;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
-use fxprof_processed_profile::Timestamp;
+use fxprof_processed_profile::{CategoryColor, MarkerGraphType, Timestamp};
 use log::warn;
+use serde_derive::Serialize;
 
-use super::timestamp_converter::TimestampConverter;
+use super::counter_file::{Counter, CounterCategory, CounterSample};
+use super::error::{Error, FormatError};
+use super::memory_usage::{hash_map_heap_size, vec_deque_heap_size, vec_heap_size, MemoryUsage};
+use super::timestamp_converter::{ClockDomain, TimestampConverter};
 use super::utils::open_file_with_fallback;
 
 #[derive(Debug, Default, Clone)]
@@ -37,15 +44,291 @@ pub struct EventOrSpanMarker {
     pub message: String,
     pub target: String,
     pub extra_fields: HashMap<String, String>,
+    /// The level of an event, if it has one. Always `None` for spans.
+    pub level: Option<EventLevel>,
+    /// The thread a Running span ran on, if the marker file recorded it.
+    pub tid: Option<i32>,
+    /// The W3C trace context from a `traceparent` field, which is then not
+    /// among the extra fields.
+    pub trace_context: Option<TraceContext>,
+    /// The types of the extra fields, if they were inferred for the whole
+    /// marker file. See `MarkerFile::with_type_inference`.
+    pub field_types: Option<Arc<HashMap<String, FieldType>>>,
     pub marker_data: MarkerData,
 }
 
+impl EventOrSpanMarker {
+    /// An event for a record of the `log` crate, at the current time. The
+    /// record's source location, if it has one, goes into the `file` and
+    /// `line` fields.
+    pub fn from_log_record(record: &log::Record, converter: &TimestampConverter) -> Self {
+        let now_ns = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |since_epoch| since_epoch.as_nanos() as u64);
+        let mut extra_fields = HashMap::new();
+        if let Some(file) = record.file() {
+            extra_fields.insert("file".to_string(), file.to_string());
+        }
+        if let Some(line) = record.line() {
+            extra_fields.insert("line".to_string(), line.to_string());
+        }
+        Self {
+            start_time: converter.convert_time_from_domain(now_ns, ClockDomain::Realtime),
+            message: record.args().to_string(),
+            target: record.target().to_string(),
+            extra_fields,
+            level: Some(record.level().into()),
+            tid: None,
+            trace_context: None,
+            field_types: None,
+            marker_data: MarkerData::Event,
+        }
+    }
+
+    /// Sets the thread which this marker happened on.
+    pub fn with_thread_id(mut self, tid: i32) -> Self {
+        self.tid = Some(tid);
+        self
+    }
+
+    /// Adds the extra field `key`, or replaces its value.
+    pub fn with_extra_field(mut self, key: &str, value: &str) -> Self {
+        self.extra_fields.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    /// The category color for this marker's level: red for errors, orange
+    /// for warnings, green for info, blue for debug and gray for trace
+    /// events. Events with a level we don't know get gray, the color of the
+    /// "Other" category, and markers without a level stay green.
+    pub fn severity_color(&self) -> CategoryColor {
+        match self.level {
+            Some(EventLevel::Error) => CategoryColor::Red,
+            Some(EventLevel::Warn) => CategoryColor::Orange,
+            Some(EventLevel::Info) => CategoryColor::Green,
+            Some(EventLevel::Debug) => CategoryColor::Blue,
+            Some(EventLevel::Trace) => CategoryColor::Gray,
+            None if self.extra_fields.contains_key("level") => CategoryColor::Gray,
+            None => CategoryColor::Green,
+        }
+    }
+
+    /// The category of this marker: the span category for spans, and the
+    /// target for events.
+    pub fn category(&self) -> &str {
+        match &self.marker_data {
+            MarkerData::Span(span) => &span.category,
+            MarkerData::Event => &self.target,
+        }
+    }
+
+    /// Whether this marker belongs to `category`. Spans have a category,
+    /// from their action. Events don't, so their target stands in for it: an
+    /// event matches the category of its target's module and of all parent
+    /// modules, e.g. "db::open" matches "db::open" and "db".
+    pub fn category_matches(&self, category: &str) -> bool {
+        match &self.marker_data {
+            MarkerData::Span(span) => span.category == category,
+            MarkerData::Event => {
+                self.target == category
+                    || self
+                        .target
+                        .strip_prefix(category)
+                        .is_some_and(|rest| rest.starts_with("::"))
+            }
+        }
+    }
+
+    /// The type of the extra field `name`. Without type inference, all
+    /// fields are strings.
+    pub fn field_type(&self, name: &str) -> FieldType {
+        self.field_types
+            .as_ref()
+            .and_then(|field_types| field_types.get(name))
+            .copied()
+            .unwrap_or(FieldType::String)
+    }
+}
+
+/// The type of an extra field, as inferred from its values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FieldType {
+    Integer,
+    Float,
+    Bool,
+    /// A duration with a unit, like the `time.busy` field, e.g. `1.2ms`.
+    Duration,
+    String,
+}
+
+impl FieldType {
+    /// The most specific type which `value` parses as.
+    pub fn of_value(value: &str) -> Self {
+        if value.parse::<i64>().is_ok() {
+            FieldType::Integer
+        } else if value.parse::<f64>().is_ok_and(f64::is_finite) {
+            FieldType::Float
+        } else if value == "true" || value == "false" {
+            FieldType::Bool
+        } else if parse_duration(value).is_ok() {
+            FieldType::Duration
+        } else {
+            FieldType::String
+        }
+    }
+
+    /// The value of a field of this type as a number, or `None` if it
+    /// doesn't parse as one. Durations are in milliseconds.
+    pub fn number_value(&self, value: &str) -> Option<f64> {
+        match self {
+            FieldType::Integer | FieldType::Float => value.parse().ok(),
+            FieldType::Duration => parse_duration(value)
+                .ok()
+                .map(|duration| duration.as_nanos() as f64 / 1_000_000.0),
+            FieldType::Bool | FieldType::String => None,
+        }
+    }
+
+    /// The most specific type which values of both types parse as.
+    pub fn merge(self, other: Self) -> Self {
+        match (self, other) {
+            (a, b) if a == b => a,
+            (FieldType::Integer, FieldType::Float) | (FieldType::Float, FieldType::Integer) => {
+                FieldType::Float
+            }
+            _ => FieldType::String,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FieldType::Integer => "integer",
+            FieldType::Float => "float",
+            FieldType::Bool => "bool",
+            FieldType::Duration => "duration",
+            FieldType::String => "string",
+        }
+    }
+}
+
+impl MemoryUsage for EventOrSpanMarker {
+    fn heap_size(&self) -> usize {
+        let fields_size: usize = self
+            .extra_fields
+            .iter()
+            .map(|(key, value)| key.heap_size() + value.heap_size())
+            .sum();
+        let span_size = match &self.marker_data {
+            MarkerData::Span(span) => {
+                span.category.heap_size()
+                    + vec_heap_size(&span.child_markers)
+                    + span
+                        .child_markers
+                        .iter()
+                        .map(EventOrSpanMarker::heap_size)
+                        .sum::<usize>()
+            }
+            MarkerData::Event => 0,
+        };
+        self.message.heap_size()
+            + self.target.heap_size()
+            + hash_map_heap_size(&self.extra_fields)
+            + fields_size
+            + span_size
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum MarkerData {
     Span(MarkerSpan),
     Event,
 }
 
+/// The level of a tracing event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum EventLevel {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl From<log::Level> for EventLevel {
+    fn from(level: log::Level) -> Self {
+        match level {
+            log::Level::Error => EventLevel::Error,
+            log::Level::Warn => EventLevel::Warn,
+            log::Level::Info => EventLevel::Info,
+            log::Level::Debug => EventLevel::Debug,
+            log::Level::Trace => EventLevel::Trace,
+        }
+    }
+}
+
+impl EventLevel {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_uppercase().as_str() {
+            "TRACE" => Some(EventLevel::Trace),
+            "DEBUG" => Some(EventLevel::Debug),
+            "INFO" => Some(EventLevel::Info),
+            "WARN" | "WARNING" => Some(EventLevel::Warn),
+            "ERROR" => Some(EventLevel::Error),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventLevel::Trace => "TRACE",
+            EventLevel::Debug => "DEBUG",
+            EventLevel::Info => "INFO",
+            EventLevel::Warn => "WARN",
+            EventLevel::Error => "ERROR",
+        }
+    }
+}
+
+/// A W3C trace context, from a `traceparent` header of the form
+/// `<version>-<trace-id>-<parent-id>-<flags>` in hex, e.g.
+/// `00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceContext {
+    pub trace_id: u128,
+    pub parent_id: u64,
+    pub flags: u8,
+}
+
+impl TraceContext {
+    /// Parses a `traceparent` header. Versions other than 00 may have more
+    /// parts, which are ignored. All-zero ids are invalid.
+    pub fn from_traceparent(traceparent: &str) -> Option<Self> {
+        fn hex<const LEN: usize>(part: Option<&str>) -> Option<&str> {
+            part.filter(|part| part.len() == LEN && part.bytes().all(|b| b.is_ascii_hexdigit()))
+        }
+        let mut parts = traceparent.trim().split('-');
+        let version = hex::<2>(parts.next())?;
+        let trace_id = u128::from_str_radix(hex::<32>(parts.next())?, 16).ok()?;
+        let parent_id = u64::from_str_radix(hex::<16>(parts.next())?, 16).ok()?;
+        let flags = u8::from_str_radix(hex::<2>(parts.next())?, 16).ok()?;
+        let is_valid = match version {
+            "00" => parts.next().is_none(),
+            "ff" | "FF" => false,
+            _ => true,
+        };
+        (is_valid && trace_id != 0 && parent_id != 0).then_some(Self {
+            trace_id,
+            parent_id,
+            flags,
+        })
+    }
+
+    /// The trace id as 32 lowercase hex digits, as in `traceparent`.
+    pub fn trace_id_hex(&self) -> String {
+        format!("{:032x}", self.trace_id)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum SpanType {
     Total,
@@ -63,32 +346,48 @@ impl Display for SpanType {
 
 #[derive(Debug, Clone)]
 pub struct MarkerSpan {
+    /// The tracing span id. Span ids can be reused once a span is closed.
+    pub id: u64,
     pub span_type: SpanType,
     pub end_time: Timestamp,
     pub timings: TracingTimings,
     pub category: String,
     pub profiler_label: Option<String>,
     pub stats_label: Option<String>,
+    /// Events and spans which were emitted inside this span, i.e. whose
+    /// `parent_id` is this span's id. Only `SpanType::Total` spans have children.
+    pub child_markers: Vec<EventOrSpanMarker>,
 }
 
+#[derive(Default)]
 pub struct MarkerStats {
     per_collection_map: HashMap<String, TracingTimings>,
+    migrated_span_count: usize,
 }
 
 impl MarkerStats {
     pub fn new() -> Self {
         Self {
             per_collection_map: HashMap::new(),
+            migrated_span_count: 0,
         }
     }
 
     pub fn is_empty(&self) -> bool {
-        self.per_collection_map.is_empty()
+        self.per_collection_map.is_empty() && self.migrated_span_count == 0
+    }
+
+    /// Returns the stats collected so far, and starts over with empty stats.
+    pub fn reset(&mut self) -> MarkerStats {
+        std::mem::take(self)
     }
 
     pub fn process_span(&mut self, marker: &EventOrSpanMarker) {
         if let MarkerData::Span(span) = &marker.marker_data {
             if span.span_type != SpanType::Total {
+                if marker.extra_fields.get("migrated").map(String::as_str) == Some("out") {
+                    self.migrated_span_count += 1;
+                }
                 return;
             }
             if let Some(label) = &span.stats_label {
@@ -100,7 +399,7 @@ impl MarkerStats {
     fn calc_per_type(&self) -> HashMap<String, TracingTimings> {
         let mut per_type = HashMap::new();
         for (collection, timings) in self.per_collection_map.iter() {
-            let (collection_type, _) = collection.split_once('-').unwrap();
+            let collection_type = collection.split_once('-').map_or(&**collection, |(t, _)| t);
             *per_type.entry(collection_type.to_string()).or_default() += timings;
         }
         per_type
@@ -135,6 +434,12 @@ impl MarkerStats {
         let per_type_map = self.calc_per_type();
         self.dump_stats_map("Per Type", &per_type_map);
         self.dump_stats_map("Per Collection", &self.per_collection_map);
+        if self.migrated_span_count > 0 {
+            println!(
+                "Running spans split across threads: {}",
+                self.migrated_span_count
+            );
+        }
     }
 }
 
@@ -142,6 +447,9 @@ struct SpanTracker {
     start_keyword: String,
     end_keyword: String,
     started_span_cache: HashMap<u64, serde_json::Value>,
+    /// The number of lines which were dropped because they had the wrong
+    /// keyword for the state of their span.
+    dropped_count: usize,
 }
 
 impl SpanTracker {
@@ -150,13 +458,18 @@ impl SpanTracker {
             start_keyword: start_keyword.to_string(),
             end_keyword: end_keyword.to_string(),
             started_span_cache: HashMap::new(),
+            dropped_count: 0,
         }
     }
 
+    /// Returns the start and end line once a span ends. Lines with the wrong
+    /// keyword for the state of their span are dropped with a warning in
+    /// `warnings`.
     fn process_line(
         &mut self,
         id: u64,
         json: serde_json::Value,
+        warnings: &mut Vec<String>,
     ) -> Option<(serde_json::Value, serde_json::Value)> {
         let message = json.get("fields")?.get("message")?.as_str()?.to_string();
         if message != self.start_keyword && message != self.end_keyword {
@@ -171,10 +484,10 @@ impl SpanTracker {
         };
 
         if &message != expected_keyword {
-            warn!(
-                "Dropping span - expected '{}', got '{}' for span {:?}",
-                expected_keyword, message, json
-            );
+            warnings.push(format!(
+                "Dropping span - expected '{expected_keyword}', got '{message}' for span {id}"
+            ));
+            self.dropped_count += 1;
             return None;
         }
 
@@ -185,58 +498,427 @@ impl SpanTracker {
             None
         }
     }
+
+    fn is_started(&self, id: u64) -> bool {
+        self.started_span_cache.contains_key(&id)
+    }
+
+    /// The number of spans which were started but haven't ended (yet).
+    fn orphan_count(&self) -> usize {
+        self.started_span_cache.len()
+    }
+
+    /// How long the oldest unended span has been open at `now_ns`, which is
+    /// a raw timestamp like the ones in the marker file.
+    fn oldest_orphan_age_ns(&self, now_ns: u64) -> Option<u64> {
+        self.started_span_cache
+            .values()
+            .filter_map(|json| json.get("timestamp")?.as_str()?.parse::<u64>().ok())
+            .min()
+            .map(|start| now_ns.saturating_sub(start))
+    }
+}
+
+/// How much of a marker file was turned into markers.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MarkerFileReport {
+    pub lines_read: usize,
+    /// Lines which completed at least one marker.
+    pub lines_with_markers: usize,
+    /// Span lines with the wrong keyword for the state of their span, e.g. a
+    /// "close" for a span which was never created.
+    pub dropped_keyword_mismatch: usize,
+    /// Lines which aren't of the form `id[,tid] {json}`.
+    pub invalid_json: usize,
+    /// Lines with valid JSON which isn't a valid marker line, e.g. with an
+    /// invalid timestamp or a missing `target`.
+    pub malformed: usize,
+}
+
+impl Display for MarkerFileReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} lines read, {} produced markers, {} dropped for a keyword mismatch, {} with invalid JSON, {} malformed",
+            self.lines_read,
+            self.lines_with_markers,
+            self.dropped_keyword_mismatch,
+            self.invalid_json,
+            self.malformed
+        )
+    }
+}
+
+/// `MarkerFile` warns about unended spans at the end of the file if there
+/// are more than this many of them. A few are normal, because spans which
+/// are still open when the profiled program is stopped never end.
+const DEFAULT_ORPHAN_WARNING_THRESHOLD: usize = 10;
+
+/// With type inference, the types of the extra fields are inferred from the
+/// markers completed by this many lines at the start of the file.
+const TYPE_INFERENCE_LINE_COUNT: usize = 100;
+
+/// The extra field which holds the source line of a marker, if raw lines
+/// are kept, see `MarkerLineProcessor::with_raw_lines`.
+pub const RAW_LINE_FIELD: &str = "__raw";
+
+/// The field of events and spans which holds their W3C trace context, see
+/// `TraceContext`.
+const TRACEPARENT_FIELD: &str = "traceparent";
+
+/// The field which the trace id of a marker's `TraceContext` is shown in.
+pub const TRACE_ID_FIELD: &str = "trace_id";
+
+/// Raw lines are truncated to this many bytes.
+const RAW_LINE_MAX_LEN: usize = 1024;
+
+/// The lines quoted in warnings and errors are truncated to this many bytes.
+const QUOTED_LINE_MAX_LEN: usize = 200;
+
+/// The first `max_len` bytes of `line`, or fewer to end on a char boundary.
+fn truncate_line(line: &str, max_len: usize) -> &str {
+    let mut end = line.len().min(max_len);
+    while !line.is_char_boundary(end) {
+        end -= 1;
+    }
+    &line[..end]
 }
 
-pub struct MarkerFile {
-    lines: Lines<BufReader<File>>,
+/// Turns marker lines into markers, independent of where the lines come
+/// from. `MarkerFile` uses this for the lines of a file; lines can also be
+/// fed in one by one as they arrive, e.g. from a socket.
+pub struct MarkerLineProcessor {
     timestamp_converter: TimestampConverter,
     new_close_tracker: SpanTracker,
     enter_exit_tracker: SpanTracker,
+    /// For each entered Running span, the raw timestamp of the first line
+    /// emitted inside it on each thread.
+    first_line_on_thread: HashMap<(u64, i32), u64>,
+    /// Markers which were completed by the processed lines, but not yet
+    /// taken out.
+    pending_markers: VecDeque<(EventOrSpanMarker, Option<u64>)>,
+    /// The latest raw timestamp seen on any line.
+    last_timestamp: Option<u64>,
+    invalid_json_lines: usize,
+    /// Lines with valid JSON which couldn't be turned into markers.
+    malformed_lines: usize,
+    /// Lines which are a JSON object without the `id[,tid]` prefix, which
+    /// are treated as events.
+    missing_id_lines: usize,
+    raw_lines: bool,
+    strict: bool,
+    /// The number of lines processed so far, i.e. the 1-based number of the
+    /// current line while it's processed.
+    line_number: usize,
+    /// The warnings about the current line, which are emitted, or with
+    /// `strict`, turned into an error, once the line has been processed.
+    line_warnings: Vec<String>,
 }
 
-impl MarkerFile {
-    pub fn parse(file: File, timestamp_converter: TimestampConverter) -> Self {
+pub struct MarkerFile<R = File> {
+    path: PathBuf,
+    lines: Lines<BufReader<R>>,
+    processor: MarkerLineProcessor,
+    orphan_warning_threshold: usize,
+    reached_end: bool,
+    lines_with_markers: usize,
+    type_inference: bool,
+    /// The types inferred so far, if `type_inference` is on.
+    inferred_field_types: HashMap<String, FieldType>,
+}
+
+impl<R: Read> MarkerFile<R> {
+    /// `path` is only used in errors.
+    pub fn parse(reader: R, path: &Path, timestamp_converter: TimestampConverter) -> Self {
+        Self {
+            path: path.to_owned(),
+            lines: BufReader::new(reader).lines(),
+            processor: MarkerLineProcessor::new(timestamp_converter),
+            orphan_warning_threshold: DEFAULT_ORPHAN_WARNING_THRESHOLD,
+            reached_end: false,
+            lines_with_markers: 0,
+            type_inference: false,
+            inferred_field_types: HashMap::new(),
+        }
+    }
+
+    /// Infer the types of the extra fields from the first
+    /// `TYPE_INFERENCE_LINE_COUNT` lines, so that numeric fields can be shown
+    /// as numbers. The inferred types then apply to the whole file, see
+    /// `field_types`.
+    pub fn with_type_inference(mut self, type_inference: bool) -> Self {
+        self.type_inference = type_inference;
+        self
+    }
+
+    /// Keep the source line of each marker, see
+    /// `MarkerLineProcessor::with_raw_lines`.
+    pub fn with_raw_lines(mut self, raw_lines: bool) -> Self {
+        self.processor = self.processor.with_raw_lines(raw_lines);
+        self
+    }
+
+    /// Fail on the first invalid line, see `MarkerLineProcessor::with_strict`.
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.processor = self.processor.with_strict(strict);
+        self
+    }
+
+    /// Warn about unended spans at the end of the file only if there are
+    /// more than `threshold` of them.
+    pub fn with_orphan_warning_threshold(mut self, threshold: usize) -> Self {
+        self.orphan_warning_threshold = threshold;
+        self
+    }
+
+    /// The inferred type of each extra field. Fields which only appear after
+    /// the first `TYPE_INFERENCE_LINE_COUNT` lines are strings.
+    pub fn field_types(&self) -> HashMap<String, FieldType> {
+        self.inferred_field_types.clone()
+    }
+
+    fn infer_field_types(&mut self, markers: impl IntoIterator<Item = usize>) {
+        for index in markers {
+            let (marker, _) = &self.processor.pending_markers[index];
+            add_field_types(&mut self.inferred_field_types, marker);
+        }
+    }
+
+    /// The number of new/close and enter/exit spans which haven't ended.
+    /// Once iteration has finished, these are the spans which never ended.
+    pub fn orphan_stats(&self) -> (usize, usize) {
+        self.processor.orphan_stats()
+    }
+
+    /// Counts of how the lines read so far were used.
+    pub fn report(&self) -> MarkerFileReport {
+        MarkerFileReport {
+            lines_read: self.processor.line_number,
+            lines_with_markers: self.lines_with_markers,
+            dropped_keyword_mismatch: self.processor.new_close_tracker.dropped_count
+                + self.processor.enter_exit_tracker.dropped_count,
+            invalid_json: self.processor.invalid_json_lines,
+            malformed: self.processor.malformed_lines,
+        }
+    }
+
+    fn warn_about_orphans(&self) {
+        let (new_close_orphans, enter_exit_orphans) = self.orphan_stats();
+        if new_close_orphans + enter_exit_orphans <= self.orphan_warning_threshold {
+            return;
+        }
+        let processor = &self.processor;
+        let oldest_orphan_age_ns = processor.last_timestamp.and_then(|now_ns| {
+            [&processor.new_close_tracker, &processor.enter_exit_tracker]
+                .iter()
+                .filter_map(|tracker| tracker.oldest_orphan_age_ns(now_ns))
+                .max()
+        });
+        warn!(
+            "{}: {new_close_orphans} spans were never closed and {enter_exit_orphans} were never exited{}",
+            self.path.display(),
+            match oldest_orphan_age_ns {
+                Some(age_ns) => format!(
+                    ", the oldest one was open for {:?} at the end of the file",
+                    Duration::from_nanos(age_ns)
+                ),
+                None => String::new(),
+            }
+        );
+    }
+}
+
+/// Returns the raw timestamp of a marker line's JSON, converted into the
+/// domain of `timestamp_converter`. Lines can opt into a different clock
+/// with a `"clock": "realtime"` field.
+pub fn read_raw_timestamp(
+    json: &serde_json::Value,
+    timestamp_converter: &TimestampConverter,
+) -> Result<u64, String> {
+    let timestamp = str_field(json, "timestamp")?;
+    let timestamp = timestamp
+        .parse::<u64>()
+        .map_err(|_| format!("invalid timestamp '{timestamp}'"))?;
+    let domain = json
+        .get("clock")
+        .and_then(serde_json::Value::as_str)
+        .and_then(ClockDomain::from_name)
+        .unwrap_or_default();
+    Ok(timestamp_converter.raw_from_domain(timestamp, domain))
+}
+
+/// Parses a span id, which is decimal, or hexadecimal with a `0x` prefix as
+/// written by some tracing formatters.
+pub fn parse_span_id(id: &str) -> Option<u64> {
+    match id.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => id.parse().ok(),
+    }
+}
+
+fn str_field<'a>(json: &'a serde_json::Value, field: &str) -> Result<&'a str, String> {
+    json.get(field)
+        .and_then(serde_json::Value::as_str)
+        .ok_or_else(|| format!("missing string field '{field}'"))
+}
+
+fn object_field<'a>(
+    json: &'a serde_json::Value,
+    field: &str,
+) -> Result<&'a serde_json::Value, String> {
+    json.get(field)
+        .filter(|value| value.is_object())
+        .ok_or_else(|| format!("missing object field '{field}'"))
+}
+
+fn parse_timing_field(fields: &serde_json::Value, field: &str) -> Result<Option<Duration>, String> {
+    let Some(value) = fields.get(field) else {
+        return Ok(None);
+    };
+    let field_str = value
+        .as_str()
+        .ok_or_else(|| format!("field '{field}' is not a string"))?;
+    parse_duration(field_str).map(Some)
+}
+
+/// Parses a duration like `1.2ms`, with the unit `s`, `ms`, `us` or `ns`.
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    // Microseconds can be written with the micro sign (U+00B5) or with
+    // the Greek letter mu (U+03BC), which look the same.
+    let field_str = value.replace(['\u{00b5}', '\u{03bc}'], "u");
+
+    let (num, unit) = match field_str.rfind(|c: char| c.is_ascii_digit() || c == '.') {
+        Some(end_idx) => field_str.split_at(end_idx + 1),
+        None => return Err(format!("no number in field {field_str}")),
+    };
+    let num: f64 = num
+        .parse()
+        .map_err(|_| format!("invalid number in field {field_str}"))?;
+
+    let divisor = match unit {
+        "s" => 1.0,
+        "ms" => 1_000.0,
+        "us" => 1_000_000.0,
+        "ns" => 1_000_000_000.0,
+        _ => return Err(format!("unknown unit '{unit}' in field {field_str}")),
+    };
+    let duration = Duration::try_from_secs_f64(num / divisor)
+        .map_err(|_| format!("duration out of range in field {field_str}"))?;
+    Ok(duration)
+}
+
+impl MemoryUsage for MarkerLineProcessor {
+    /// The lines of spans which haven't ended yet are only counted by their
+    /// table entries.
+    fn heap_size(&self) -> usize {
+        vec_deque_heap_size(&self.pending_markers)
+            + self
+                .pending_markers
+                .iter()
+                .map(|(marker, _)| marker.heap_size())
+                .sum::<usize>()
+            + hash_map_heap_size(&self.new_close_tracker.started_span_cache)
+            + hash_map_heap_size(&self.enter_exit_tracker.started_span_cache)
+            + hash_map_heap_size(&self.first_line_on_thread)
+    }
+}
+
+impl MarkerLineProcessor {
+    pub fn new(timestamp_converter: TimestampConverter) -> Self {
         Self {
-            lines: BufReader::new(file).lines(),
             timestamp_converter,
             new_close_tracker: SpanTracker::new("new", "close"),
             enter_exit_tracker: SpanTracker::new("enter", "exit"),
+            first_line_on_thread: HashMap::new(),
+            pending_markers: VecDeque::new(),
+            last_timestamp: None,
+            invalid_json_lines: 0,
+            malformed_lines: 0,
+            missing_id_lines: 0,
+            raw_lines: false,
+            strict: false,
+            line_number: 0,
+            line_warnings: Vec::new(),
         }
     }
-}
 
-fn parse_timing_field(fields: &serde_json::Value, field: &str) -> Option<Duration> {
-    let field_str = fields.get(field)?.as_str().unwrap().replace('µ', "u");
+    /// Put the line which completed each marker, truncated to
+    /// `RAW_LINE_MAX_LEN` bytes, into its `RAW_LINE_FIELD` extra field, for
+    /// debugging markers which look wrong. For spans, this is the closing
+    /// line.
+    pub fn with_raw_lines(mut self, raw_lines: bool) -> Self {
+        self.raw_lines = raw_lines;
+        self
+    }
 
-    let end_idx = field_str
-        .rfind(|c| char::is_numeric(c) || c == '.')
-        .unwrap();
-    let (num, unit) = field_str.split_at(end_idx + 1);
-
-    Some(match unit {
-        "s" => Duration::from_secs_f64(num.parse().unwrap()),
-        "ms" => Duration::from_secs_f64(num.parse::<f64>().unwrap() / 1_000.0),
-        "us" => Duration::from_secs_f64(num.parse::<f64>().unwrap() / 1_000_000.0),
-        "ns" => Duration::from_secs_f64(num.parse::<f64>().unwrap() / 1_000_000_000.0),
-        _ => panic!("unknown unit '{}' in field {}", unit, field_str),
-    })
-}
+    /// Return an error for the first line which would otherwise be skipped
+    /// or warned about, e.g. a line which isn't JSON or a "close" line for a
+    /// span which was never created, to validate the program which writes
+    /// the markers.
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
 
-impl MarkerFile {
-    fn read_timestamp_from_event(&self, json: &serde_json::Value) -> u64 {
-        json.get("timestamp")
-            .unwrap()
-            .as_str()
-            .unwrap()
-            .parse::<u64>()
-            .unwrap()
+    /// A warning about `line`, the current line, with its line number and
+    /// the start of the line.
+    fn line_warning(&self, warning: &str, line: &str) -> String {
+        format!(
+            "line {}: {}",
+            self.line_number,
+            self.line_error(warning.to_string(), line)
+        )
+    }
+
+    /// An error about `line`, with the start of the line. The line number is
+    /// left to the caller, e.g. to `Error::Parse`.
+    fn line_error(&self, reason: String, line: &str) -> String {
+        format!("{reason} in {:?}", truncate_line(line, QUOTED_LINE_MAX_LEN))
+    }
+
+    /// Skips an invalid line, or fails with `reason` if `strict` is on.
+    fn skip_invalid_line(&self, reason: impl FnOnce() -> String) -> Result<(), String> {
+        if self.strict {
+            Err(reason())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The number of new/close and enter/exit spans which haven't ended.
+    pub fn orphan_stats(&self) -> (usize, usize) {
+        (
+            self.new_close_tracker.orphan_count(),
+            self.enter_exit_tracker.orphan_count(),
+        )
+    }
+
+    /// Takes out the oldest marker which was completed by the processed
+    /// lines, together with the id of the span it was emitted in, if any.
+    pub fn pop_marker(&mut self) -> Option<(EventOrSpanMarker, Option<u64>)> {
+        self.pending_markers.pop_front()
+    }
+
+    /// All markers completed by the processed lines, nested and sorted like
+    /// the markers returned by `get_markers`. Spans which were never ended
+    /// are dropped.
+    pub fn into_markers(mut self) -> Vec<EventOrSpanMarker> {
+        let markers = std::mem::take(&mut self.pending_markers);
+        let mut markers = build_marker_tree(markers);
+        markers.sort_by_key(|m| m.start_time);
+        markers
+    }
+
+    fn read_timestamp_from_event(&self, json: &serde_json::Value) -> Result<u64, String> {
+        read_raw_timestamp(json, &self.timestamp_converter)
     }
 
     fn value_to_hashmap(value: &serde_json::Value) -> HashMap<String, String> {
         value
             .as_object()
-            .unwrap()
-            .iter()
+            .into_iter()
+            .flatten()
             .map(|(k, v)| {
                 (
                     k.clone(),
@@ -249,31 +931,58 @@ impl MarkerFile {
             .collect::<HashMap<String, String>>()
     }
 
+    /// Takes a valid `traceparent` field out of `extra_fields`. An invalid
+    /// one stays an extra field.
+    fn take_trace_context(extra_fields: &mut HashMap<String, String>) -> Option<TraceContext> {
+        let trace_context = TraceContext::from_traceparent(extra_fields.get(TRACEPARENT_FIELD)?)?;
+        extra_fields.remove(TRACEPARENT_FIELD);
+        Some(trace_context)
+    }
+
+    /// Returns the id of the span that this line was emitted in, from an
+    /// optional `"parent_id"` field.
+    fn read_parent_id(json: &serde_json::Value) -> Option<u64> {
+        match json.get("parent_id")? {
+            serde_json::Value::Number(n) => n.as_u64(),
+            serde_json::Value::String(s) => parse_span_id(s),
+            _ => None,
+        }
+    }
+
     fn process_complete_span(
         &mut self,
+        id: u64,
         span_type: SpanType,
         start: serde_json::Value,
         end: serde_json::Value,
-    ) -> Option<EventOrSpanMarker> {
-        let fields = end.get("fields").unwrap();
+    ) -> Result<EventOrSpanMarker, String> {
+        let fields = object_field(&end, "fields")?;
 
-        let start_time = self.read_timestamp_from_event(&start);
-        let end_time = self.read_timestamp_from_event(&end);
+        let start_time = self.read_timestamp_from_event(&start)?;
+        let end_time = self.read_timestamp_from_event(&end)?;
 
-        let mut extra_fields = Self::value_to_hashmap(end.get("span").unwrap());
+        let mut extra_fields = Self::value_to_hashmap(object_field(&end, "span")?);
 
-        let message = extra_fields.remove("name").unwrap();
+        let message = extra_fields.remove("name").ok_or("missing span name")?;
         let action = extra_fields.get("action").map_or("-", String::as_str);
 
         // TODO: get label+category from sampled program?
         // Expected format: AtomType[-AtomId]/CollectionType-CollectionID
+        // An action without a valid collection is used as the category as a
+        // whole, like an action without a slash.
+        let collection = action.split_once('/').and_then(|(atom, collection)| {
+            let parsed = collection.split_once('-');
+            if parsed.is_none() {
+                self.line_warnings.push(format!(
+                    "Invalid collection in span action {action:?}, using it as the category"
+                ));
+            }
+            Some((atom, parsed?))
+        });
         let (category, profiler_label, stats_label) =
-            if let Some((atom, collection)) = action.split_once('/') {
-                let (collection_type, mut id) = collection
-                    .split_once('-')
-                    .unwrap_or_else(|| panic!("Invalid collection: {}", collection));
-                if id.len() > 8 {
-                    id = &id[..8];
+            if let Some((atom, (collection_type, mut id))) = collection {
+                if let Some((end, _)) = id.char_indices().nth(8) {
+                    id = &id[..end];
                 }
 
                 let profiler_label = format!("{}-{} {}", collection_type, &id, span_type);
@@ -283,18 +992,37 @@ impl MarkerFile {
                 (action.to_string(), None, None)
             };
 
-        let target = end.get("target").unwrap().as_str().unwrap().to_string();
+        let target = str_field(&end, "target")?.to_string();
+        // The start and end lines can be emitted from different modules.
+        // Keep the start's target too in that case, so that the crossing
+        // shows up in the marker.
+        if let Some(start_target) = start.get("target").and_then(serde_json::Value::as_str) {
+            if start_target != target {
+                extra_fields.insert("start_target".to_string(), start_target.to_string());
+            }
+        }
 
-        let time_busy = parse_timing_field(fields, "time.busy")
-            .unwrap_or(Duration::from_nanos(end_time - start_time));
-        let time_idle = parse_timing_field(fields, "time.idle").unwrap_or_default();
+        // Single-line spans call it "busy".
+        let time_busy = match parse_timing_field(fields, "time.busy")? {
+            Some(time_busy) => Some(time_busy),
+            None => parse_timing_field(fields, "busy")?,
+        };
+        let time_busy =
+            time_busy.unwrap_or(Duration::from_nanos(end_time.saturating_sub(start_time)));
+        let time_idle = parse_timing_field(fields, "time.idle")?.unwrap_or_default();
+        let trace_context = Self::take_trace_context(&mut extra_fields);
 
-        Some(EventOrSpanMarker {
+        Ok(EventOrSpanMarker {
             start_time: self.timestamp_converter.convert_time(start_time),
             message,
             target,
             extra_fields,
+            level: None,
+            tid: None,
+            trace_context,
+            field_types: None,
             marker_data: MarkerData::Span(MarkerSpan {
+                id,
                 end_time: self.timestamp_converter.convert_time(end_time),
                 span_type,
                 category,
@@ -304,66 +1032,333 @@ impl MarkerFile {
                     time_busy,
                     time_idle,
                 },
+                child_markers: Vec::new(),
             }),
         })
     }
 
-    fn process_event(&mut self, event: serde_json::Value) -> Option<EventOrSpanMarker> {
+    /// Returns `None` for events without a message, which are skipped.
+    fn process_event(
+        &mut self,
+        event: serde_json::Value,
+    ) -> Result<Option<EventOrSpanMarker>, String> {
         let start_time = self
             .timestamp_converter
-            .convert_time(self.read_timestamp_from_event(&event));
-        let target = event.get("target").unwrap().as_str().unwrap().to_string();
+            .convert_time(self.read_timestamp_from_event(&event)?);
+        let target = str_field(&event, "target")?.to_string();
+
+        let mut extra_fields = Self::value_to_hashmap(object_field(&event, "fields")?);
+        let Some(message) = extra_fields.remove("message") else {
+            return Ok(None);
+        };
 
-        let mut extra_fields = Self::value_to_hashmap(event.get("fields").unwrap());
-        let message = extra_fields.remove("message")?;
+        // The tracing JSON format puts the level at the top level of the
+        // line, but accept it among the fields too.
+        let level = match event.get("level").and_then(serde_json::Value::as_str) {
+            Some(level) => EventLevel::from_name(level),
+            None => extra_fields
+                .get("level")
+                .and_then(|level| EventLevel::from_name(level)),
+        };
+        if level.is_some() {
+            extra_fields.remove("level");
+        }
+        let trace_context = Self::take_trace_context(&mut extra_fields);
 
-        Some(EventOrSpanMarker {
+        Ok(Some(EventOrSpanMarker {
             start_time,
             message,
             target,
             extra_fields,
+            level,
+            tid: None,
+            trace_context,
+            field_types: None,
             marker_data: MarkerData::Event,
-        })
+        }))
     }
 
-    fn process_line(&mut self, line: &str) -> Option<EventOrSpanMarker> {
-        let (ids, json) = line.split_once(' ')?;
-        let json: serde_json::Value = serde_json::from_str(json).ok()?;
+    /// Queues the markers completed by this line in `pending_markers`,
+    /// together with the id of the span they were emitted in.
+    ///
+    /// Lines which aren't of the form `id[,tid] {json}` are skipped rather
+    /// than treated as errors, because the last line can be cut off if the
+    /// profiled program was killed while writing it. Lines which are, but
+    /// aren't valid marker lines, e.g. with an invalid timestamp, are skipped
+    /// with a warning. Only with `strict` are either of them errors. Warnings
+    /// and errors mention the line number and the start of the line.
+    pub fn process_line(&mut self, line: &str) -> Result<(), String> {
+        self.line_number += 1;
+        let pending_before = self.pending_markers.len();
+        let result = self.process_line_impl(line);
+        if self.raw_lines {
+            let raw_line = truncate_line(line, RAW_LINE_MAX_LEN);
+            for (marker, _) in self.pending_markers.range_mut(pending_before..) {
+                marker
+                    .extra_fields
+                    .insert(RAW_LINE_FIELD.to_string(), raw_line.to_string());
+            }
+        }
+        let mut warnings = std::mem::take(&mut self.line_warnings);
+        if self.strict {
+            if let Some(warning) = warnings.into_iter().next() {
+                return Err(self.line_error(warning, line));
+            }
+            return result.map_err(|reason| self.line_error(reason, line));
+        }
+        if let Err(reason) = result {
+            self.malformed_lines += 1;
+            warnings.push(format!("Skipping malformed line: {reason}"));
+        }
+        for warning in &warnings {
+            warn!("{}", self.line_warning(warning, line));
+        }
+        Ok(())
+    }
+
+    /// Some producers write events as bare JSON objects, without the id
+    /// prefix. Such a line is parsed as an event, i.e. with id 0.
+    fn parse_line_without_id(line: &str) -> Option<serde_json::Value> {
+        if !line.trim_start().starts_with('{') {
+            return None;
+        }
+        serde_json::from_str::<serde_json::Value>(line)
+            .ok()
+            .filter(serde_json::Value::is_object)
+    }
+
+    fn process_line_impl(&mut self, line: &str) -> Result<(), String> {
+        let (ids, mut json) = if let Some(json) = Self::parse_line_without_id(line) {
+            // Only the first such line is warned about, because a producer
+            // which leaves out the prefix does so on every event.
+            if self.missing_id_lines == 0 {
+                self.line_warnings.push(
+                    "missing `id[,tid]` prefix, treating this and later such lines as events"
+                        .to_string(),
+                );
+            }
+            self.missing_id_lines += 1;
+            ("0", json)
+        } else {
+            let Some((ids, json)) = line.split_once(' ') else {
+                self.invalid_json_lines += 1;
+                return self.skip_invalid_line(|| "expected `id[,tid] {json}`".to_string());
+            };
+            match serde_json::from_str::<serde_json::Value>(json) {
+                Ok(json) => (ids, json),
+                Err(e) => {
+                    self.invalid_json_lines += 1;
+                    return self.skip_invalid_line(|| format!("invalid JSON: {e}"));
+                }
+            }
+        };
+        if let Some(timestamp) = json
+            .get("timestamp")
+            .and_then(serde_json::Value::as_str)
+            .and_then(|timestamp| timestamp.parse::<u64>().ok())
+        {
+            self.last_timestamp = Some(self.last_timestamp.unwrap_or(0).max(timestamp));
+        }
 
         let (id, tid) = if let Some((id, tid)) = ids.split_once(',') {
-            (id.parse::<u64>().ok()?, Some(tid.parse::<i32>().ok()?))
+            match (parse_span_id(id), tid.parse::<i32>()) {
+                (Some(id), Ok(tid)) => (id, Some(tid)),
+                _ => return self.skip_invalid_line(|| format!("invalid ids '{ids}'")),
+            }
         } else {
-            (ids.parse::<u64>().ok()?, None)
+            match parse_span_id(ids) {
+                Some(id) => (id, None),
+                None => return self.skip_invalid_line(|| format!("invalid id '{ids}'")),
+            }
         };
 
         if id != 0 {
-            if let Some((start, end)) = self.new_close_tracker.process_line(id, json.clone()) {
-                self.process_complete_span(SpanType::Total, start, end)
-            } else if let Some((start, mut end)) = self.enter_exit_tracker.process_line(id, json) {
-                // tid only makes sense for running spans
-                if let Some(tid) = tid {
-                    end["span"]["tid"] = serde_json::Value::from(tid);
+            if let Some(start) = Self::single_line_span_start(&json)? {
+                let parent_id = Self::read_parent_id(&json);
+                let marker = self.process_complete_span(id, SpanType::Total, start, json)?;
+                self.pending_markers.push_back((marker, parent_id));
+                return Ok(());
+            }
+            if let Some((start, end)) =
+                self.new_close_tracker
+                    .process_line(id, json.clone(), &mut self.line_warnings)
+            {
+                let parent_id = Self::read_parent_id(&start);
+                let marker = self.process_complete_span(id, SpanType::Total, start, end)?;
+                self.pending_markers.push_back((marker, parent_id));
+                return Ok(());
+            }
+            // tid only makes sense for running spans. Keep it on both the
+            // enter and the exit line, to detect spans which migrated.
+            if let (Some(tid), Some(span)) = (tid, json.get_mut("span")) {
+                if let Some(span) = span.as_object_mut() {
+                    span.insert("tid".to_string(), serde_json::Value::from(tid));
+                }
+            }
+            if let Some((start, end)) =
+                self.enter_exit_tracker
+                    .process_line(id, json, &mut self.line_warnings)
+            {
+                for marker in self.process_running_span(id, start, end)? {
+                    self.pending_markers.push_back((marker, None));
                 }
-                self.process_complete_span(SpanType::Running, start, end)
-            } else {
-                None
             }
         } else {
-            self.process_event(json)
+            let parent_id = Self::read_parent_id(&json);
+            if let (Some(parent_id), Some(tid)) = (parent_id, tid) {
+                if self.enter_exit_tracker.is_started(parent_id) {
+                    let timestamp = self.read_timestamp_from_event(&json)?;
+                    self.first_line_on_thread
+                        .entry((parent_id, tid))
+                        .or_insert(timestamp);
+                }
+            }
+            if let Some(marker) = self.process_event(json)? {
+                self.pending_markers.push_back((marker, parent_id));
+            }
+        }
+        Ok(())
+    }
+
+    /// A line whose fields contain a `started_at` timestamp is a complete
+    /// span on its own, which ends at the line's timestamp. For such lines,
+    /// this returns the line with its timestamp replaced by `started_at`,
+    /// to serve as the span's start line.
+    pub fn single_line_span_start(
+        json: &serde_json::Value,
+    ) -> Result<Option<serde_json::Value>, String> {
+        let Some(started_at) = json
+            .get("fields")
+            .and_then(|fields| fields.get("started_at"))
+        else {
+            return Ok(None);
+        };
+        let started_at = match started_at {
+            serde_json::Value::Number(n) => n.as_u64(),
+            serde_json::Value::String(s) => s.parse().ok(),
+            _ => None,
+        }
+        .ok_or_else(|| format!("invalid started_at {started_at}"))?;
+        let mut start = json.clone();
+        start["timestamp"] = serde_json::Value::from(started_at.to_string());
+        Ok(Some(start))
+    }
+
+    fn span_tid(json: &serde_json::Value) -> Option<i32> {
+        let tid = json.get("span")?.get("tid")?.as_i64()?;
+        i32::try_from(tid).ok()
+    }
+
+    /// Completes a Running span. A span which was entered on one thread and
+    /// exited on another is split into one marker per thread, with a
+    /// `migrated` field which is `out` on the first and `in` on the second
+    /// marker. The split is at the first line emitted inside the span on the
+    /// exiting thread, or at the midpoint if there is no such line.
+    fn process_running_span(
+        &mut self,
+        id: u64,
+        start: serde_json::Value,
+        end: serde_json::Value,
+    ) -> Result<Vec<EventOrSpanMarker>, String> {
+        let enter_tid = Self::span_tid(&start);
+        let exit_tid = Self::span_tid(&end);
+        let start_time = self.read_timestamp_from_event(&start)?;
+        let end_time = self.read_timestamp_from_event(&end)?;
+        let first_line_on_exit_thread =
+            exit_tid.and_then(|exit_tid| self.first_line_on_thread.get(&(id, exit_tid)).copied());
+        self.first_line_on_thread
+            .retain(|&(span_id, _), _| span_id != id);
+
+        let mut marker = self.process_complete_span(id, SpanType::Running, start, end)?;
+        marker.tid = exit_tid;
+        let (Some(enter_tid), Some(exit_tid)) = (enter_tid, exit_tid) else {
+            return Ok(vec![marker]);
+        };
+        if enter_tid == exit_tid {
+            return Ok(vec![marker]);
+        }
+
+        let split_time = match first_line_on_exit_thread {
+            Some(time) if start_time < time && time < end_time => time,
+            _ => start_time + (end_time.saturating_sub(start_time)) / 2,
+        };
+        let split_timestamp = self.timestamp_converter.convert_time(split_time);
+        let split = |marker: EventOrSpanMarker, tid: i32, migrated: &str, duration: u64| {
+            let mut marker = marker
+                .with_thread_id(tid)
+                .with_extra_field("tid", &tid.to_string())
+                .with_extra_field("migrated", migrated);
+            if let MarkerData::Span(span) = &mut marker.marker_data {
+                span.timings = TracingTimings {
+                    time_busy: Duration::from_nanos(duration),
+                    time_idle: Duration::ZERO,
+                };
+            }
+            marker
+        };
+        let mut before = split(
+            marker.clone(),
+            enter_tid,
+            "out",
+            split_time.saturating_sub(start_time),
+        );
+        let mut after = split(marker, exit_tid, "in", end_time.saturating_sub(split_time));
+        if let MarkerData::Span(span) = &mut before.marker_data {
+            span.end_time = split_timestamp;
         }
+        after.start_time = split_timestamp;
+        Ok(vec![before, after])
     }
 }
 
-impl Iterator for MarkerFile {
-    type Item = EventOrSpanMarker;
+/// Yields markers in the order in which they complete, each with the id of
+/// the span it was emitted in, if any.
+impl<R: Read> Iterator for MarkerFile<R> {
+    type Item = Result<(EventOrSpanMarker, Option<u64>), Error>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        while let Ok(line) = self.lines.next()? {
-            if let Some(marker) = self.process_line(&line) {
-                return Some(marker);
+        loop {
+            if let Some(marker) = self.processor.pop_marker() {
+                return Some(Ok(marker));
+            }
+            let Some(line) = self.lines.next() else {
+                if !self.reached_end {
+                    self.reached_end = true;
+                    self.warn_about_orphans();
+                }
+                return None;
+            };
+            let line = match line {
+                Ok(line) => line,
+                Err(source) => {
+                    return Some(Err(Error::Io {
+                        path: self.path.clone(),
+                        source,
+                    }))
+                }
+            };
+            let pending_before = self.processor.pending_markers.len();
+            match self.processor.process_line(&line) {
+                Ok(()) => {
+                    let pending_after = self.processor.pending_markers.len();
+                    if pending_after > pending_before {
+                        self.lines_with_markers += 1;
+                    }
+                    if self.type_inference
+                        && self.processor.line_number <= TYPE_INFERENCE_LINE_COUNT
+                    {
+                        self.infer_field_types(pending_before..pending_after);
+                    }
+                }
+                Err(reason) => {
+                    return Some(Err(Error::Parse {
+                        path: self.path.clone(),
+                        line: Some(self.processor.line_number),
+                        reason,
+                    }))
+                }
             }
         }
-        None
     }
 }
 
@@ -376,26 +1371,2203 @@ pub struct MarkerFileInfo {
     pub tid: Option<u32>,
 }
 
+/// Parses a marker file name of the form `prefix-pid[-tid].txt`. Returns
+/// `None` if the name doesn't have that form.
 #[allow(unused)]
-pub fn parse_marker_file_path(path: &Path) -> MarkerFileInfo {
-    let filename = path.file_name().unwrap().to_str().unwrap();
-    // strip .txt extension
-    let filename = &filename[..filename.len() - 4];
+pub fn parse_marker_file_path(path: &Path) -> Option<MarkerFileInfo> {
+    let filename = path.file_name()?.to_str()?.strip_suffix(".txt")?;
     let mut parts = filename.splitn(3, '-');
-    let prefix = parts.next().unwrap().to_owned();
-    let pid = parts.next().unwrap().parse().unwrap();
-    let tid = parts.next().map(|tid| tid.parse().unwrap());
-    MarkerFileInfo { prefix, pid, tid }
+    let prefix = parts.next()?.to_owned();
+    let pid = parts.next()?.parse().ok()?;
+    let tid = match parts.next() {
+        Some(tid) => Some(tid.parse().ok()?),
+        None => None,
+    };
+    Some(MarkerFileInfo { prefix, pid, tid })
 }
 
-pub fn get_markers(
-    marker_file: &Path,
-    lookup_dirs: &[PathBuf],
-    timestamp_converter: TimestampConverter,
-) -> Result<Vec<EventOrSpanMarker>, std::io::Error> {
-    let (f, _true_path) = open_file_with_fallback(marker_file, lookup_dirs)?;
-    let marker_file = MarkerFile::parse(f, timestamp_converter);
-    let mut marker_spans: Vec<EventOrSpanMarker> = marker_file.collect();
-    marker_spans.sort_by_key(|m| m.start_time);
-    Ok(marker_spans)
+/// Merges the types of the extra fields of `marker` into `field_types`.
+fn add_field_types(field_types: &mut HashMap<String, FieldType>, marker: &EventOrSpanMarker) {
+    for (name, value) in &marker.extra_fields {
+        let value_type = FieldType::of_value(value);
+        field_types
+            .entry(name.clone())
+            .and_modify(|field_type| *field_type = field_type.merge(value_type))
+            .or_insert(value_type);
+    }
+}
+
+/// How `get_markers` reads a marker file.
+///
+/// Files in the other formats of `MarkerFileFormat` have no source lines
+/// or invalid lines of samply's kind, so `raw_lines` and `strict` are an
+/// error for them. Their field types are inferred from their first
+/// `TYPE_INFERENCE_LINE_COUNT` markers.
+#[derive(Debug, Clone, Copy)]
+pub struct MarkerFileOptions {
+    /// Whether each marker carries the field types inferred for the file.
+    pub type_inference: bool,
+    /// Whether each marker carries its source line, see
+    /// `MarkerLineProcessor::with_raw_lines`.
+    pub raw_lines: bool,
+    /// Whether the first invalid line is an error, see
+    /// `MarkerLineProcessor::with_strict`.
+    pub strict: bool,
+    /// See `MarkerFile::with_orphan_warning_threshold`.
+    pub orphan_warning_threshold: usize,
+}
+
+impl Default for MarkerFileOptions {
+    fn default() -> Self {
+        Self {
+            type_inference: false,
+            raw_lines: false,
+            strict: false,
+            orphan_warning_threshold: DEFAULT_ORPHAN_WARNING_THRESHOLD,
+        }
+    }
+}
+
+/// What `get_markers` read from a marker file.
+#[derive(Debug)]
+pub struct MarkerFileContents {
+    pub markers: Vec<EventOrSpanMarker>,
+    /// The path the file was actually found at.
+    pub true_path: PathBuf,
+    /// How the lines of the file were used.
+    pub report: MarkerFileReport,
+    /// The counters of the file. Only atrace output has counters.
+    pub counters: Vec<Counter>,
+}
+
+/// Reads all markers from the marker file.
+///
+/// Files which start with an ftrace header are read as atrace output, see
+/// `parse_atrace`. Files whose first line is in one of the other formats of
+/// `MarkerFileFormat` are read with `MarkerFile::auto_detect`; the `options`
+/// don't apply to them, and their report stays empty.
+pub fn get_markers(
+    marker_file: &Path,
+    lookup_dirs: &[PathBuf],
+    timestamp_converter: TimestampConverter,
+    options: MarkerFileOptions,
+) -> Result<MarkerFileContents, Error> {
+    let (f, true_path) = open_file_with_fallback(marker_file, lookup_dirs)?;
+    let io_error = |source| Error::Io {
+        path: true_path.clone(),
+        source,
+    };
+    let mut reader = BufReader::new(f);
+    let mut first_line = Vec::new();
+    reader
+        .read_until(b'\n', &mut first_line)
+        .map_err(io_error)?;
+    let is_atrace = is_ftrace_header(&first_line);
+    let format = MarkerFileFormat::detect(&String::from_utf8_lossy(&first_line));
+    let reader = Cursor::new(first_line).chain(reader);
+    if is_atrace {
+        let (markers, counters, report) =
+            parse_atrace(BufReader::new(reader), timestamp_converter).map_err(io_error)?;
+        return Ok(MarkerFileContents {
+            markers,
+            true_path,
+            report,
+            counters,
+        });
+    }
+
+    if let Some(format) = format.filter(|format| *format != MarkerFileFormat::Samply) {
+        let unsupported_option = if options.raw_lines {
+            Some("raw marker lines")
+        } else if options.strict {
+            Some("strict marker parsing")
+        } else {
+            None
+        };
+        if let Some(option) = unsupported_option {
+            return Err(Error::Parse {
+                path: true_path,
+                line: None,
+                reason: format!("{option} only works for samply's format, not for {format}"),
+            });
+        }
+        let reader = LineCountingReader::new(reader);
+        let line_count = reader.line_count();
+        let markers = MarkerFile::auto_detect(BufReader::new(reader), timestamp_converter)
+            .map_err(|e| match e {
+                FormatError::Io(source) => io_error(source),
+                FormatError::Unrecognized(_) => Error::Parse {
+                    path: true_path.clone(),
+                    line: Some(1),
+                    reason: e.to_string(),
+                },
+            })?;
+        let mut markers: Vec<_> = markers.collect();
+        if options.type_inference {
+            let mut field_types = HashMap::new();
+            for marker in markers.iter().take(TYPE_INFERENCE_LINE_COUNT) {
+                add_field_types(&mut field_types, marker);
+            }
+            let field_types = Arc::new(field_types);
+            for marker in &mut markers {
+                marker.field_types = Some(field_types.clone());
+            }
+        }
+        let mut markers = nest_markers_by_time(markers);
+        markers.sort_by_key(|m| m.start_time);
+        return Ok(MarkerFileContents {
+            markers,
+            true_path,
+            report: MarkerFileReport {
+                lines_read: line_count.get(),
+                ..Default::default()
+            },
+            counters: Vec::new(),
+        });
+    }
+
+    let mut marker_file = MarkerFile::parse(reader, &true_path, timestamp_converter)
+        .with_type_inference(options.type_inference)
+        .with_raw_lines(options.raw_lines)
+        .with_strict(options.strict)
+        .with_orphan_warning_threshold(options.orphan_warning_threshold);
+    let mut markers = marker_file.by_ref().collect::<Result<Vec<_>, _>>()?;
+    let report = marker_file.report();
+    if options.type_inference {
+        let field_types = Arc::new(marker_file.field_types());
+        for (marker, _) in &mut markers {
+            marker.field_types = Some(field_types.clone());
+        }
+    }
+    let mut marker_spans = build_marker_tree(markers);
+    marker_spans.sort_by_key(|m| m.start_time);
+    Ok(MarkerFileContents {
+        markers: marker_spans,
+        true_path,
+        report,
+        counters: Vec::new(),
+    })
+}
+
+/// Counts the lines which are read through it, for the report of a marker
+/// file which isn't read line by line. A last line without a newline counts
+/// too.
+struct LineCountingReader<R> {
+    inner: R,
+    line_count: LineCount,
+}
+
+/// The number of lines which a `LineCountingReader` has read so far.
+#[derive(Clone, Default)]
+struct LineCount {
+    complete_lines: Rc<Cell<usize>>,
+    in_line: Rc<Cell<bool>>,
+}
+
+impl LineCount {
+    fn get(&self) -> usize {
+        self.complete_lines.get() + usize::from(self.in_line.get())
+    }
+}
+
+impl<R: Read> LineCountingReader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            line_count: LineCount::default(),
+        }
+    }
+
+    /// The count, which keeps up with the reader after it has been moved.
+    fn line_count(&self) -> LineCount {
+        self.line_count.clone()
+    }
+}
+
+impl<R: Read> Read for LineCountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        if let Some(&last_byte) = buf[..len].last() {
+            let newlines = buf[..len].iter().filter(|&&b| b == b'\n').count();
+            let complete_lines = &self.line_count.complete_lines;
+            complete_lines.set(complete_lines.get() + newlines);
+            self.line_count.in_line.set(last_byte != b'\n');
+        }
+        Ok(len)
+    }
+}
+
+/// Nests each marker into the `child_markers` of the span it was emitted in,
+/// and returns the top-level markers.
+///
+/// `markers` must be in completion order, as yielded by `MarkerFile`. Since
+/// a child always completes before its parent closes, the children of a span
+/// are exactly the pending markers with its id at the time it closes. Markers
+/// whose parent never closed are returned as top-level markers.
+///
+/// If none of the markers has a `parent_id`, the file's writer doesn't record
+/// parentage, and the markers are nested by their timestamps instead, see
+/// `nest_markers_by_time`.
+fn build_marker_tree(
+    markers: impl IntoIterator<Item = (EventOrSpanMarker, Option<u64>)>,
+) -> Vec<EventOrSpanMarker> {
+    let markers: Vec<_> = markers.into_iter().collect();
+    if markers.iter().all(|(_, parent_id)| parent_id.is_none()) {
+        return nest_markers_by_time(markers.into_iter().map(|(marker, _)| marker).collect());
+    }
+    let mut roots = Vec::new();
+    let mut pending_children: HashMap<u64, Vec<EventOrSpanMarker>> = HashMap::new();
+    for (mut marker, parent_id) in markers {
+        if let MarkerData::Span(span) = &mut marker.marker_data {
+            if span.span_type == SpanType::Total {
+                if let Some(mut children) = pending_children.remove(&span.id) {
+                    children.sort_by_key(|m| m.start_time);
+                    span.child_markers = children;
+                }
+            }
+        }
+        match parent_id {
+            Some(parent_id) => pending_children.entry(parent_id).or_default().push(marker),
+            None => roots.push(marker),
+        }
+    }
+    roots.extend(pending_children.into_values().flatten());
+    roots
+}
+
+/// Nests each marker into the `child_markers` of the innermost `Total` span
+/// of the same thread whose time range contains it, and returns the
+/// top-level markers. This is the fallback of `build_marker_tree` for marker
+/// files without `parent_id`s.
+fn nest_markers_by_time(mut markers: Vec<EventOrSpanMarker>) -> Vec<EventOrSpanMarker> {
+    let end_time = |marker: &EventOrSpanMarker| match &marker.marker_data {
+        MarkerData::Span(span) => span.end_time,
+        MarkerData::Event => marker.start_time,
+    };
+    // Outer spans come before the markers they contain.
+    markers.sort_by(|a, b| {
+        a.start_time
+            .cmp(&b.start_time)
+            .then_with(|| end_time(b).cmp(&end_time(a)))
+    });
+
+    // The index of each marker's parent. Parents come before their children.
+    let mut parents: Vec<Option<usize>> = Vec::with_capacity(markers.len());
+    // The indexes of the spans which contain the current marker's start, by
+    // thread, innermost last.
+    let mut open_spans: HashMap<Option<i32>, Vec<usize>> = HashMap::new();
+    for (index, marker) in markers.iter().enumerate() {
+        let open = open_spans.entry(marker.tid).or_default();
+        while let Some(&innermost) = open.last() {
+            if end_time(&markers[innermost]) >= end_time(marker) {
+                break;
+            }
+            open.pop();
+        }
+        parents.push(open.last().copied());
+        if let MarkerData::Span(span) = &marker.marker_data {
+            if span.span_type == SpanType::Total {
+                open.push(index);
+            }
+        }
+    }
+
+    // Move the children into their parents, innermost first.
+    let mut markers: Vec<Option<EventOrSpanMarker>> = markers.into_iter().map(Some).collect();
+    for index in (0..markers.len()).rev() {
+        let Some(parent) = parents[index] else {
+            continue;
+        };
+        let child = markers[index].take().unwrap();
+        if let Some(MarkerData::Span(span)) = markers[parent].as_mut().map(|m| &mut m.marker_data) {
+            span.child_markers.push(child);
+        }
+    }
+    let mut roots: Vec<_> = markers.into_iter().flatten().collect();
+    for marker in &mut roots {
+        sort_child_markers(marker);
+    }
+    roots
+}
+
+/// Sorts the children of the span, which `nest_markers_by_time` adds in
+/// reverse, by start time, recursively.
+fn sort_child_markers(marker: &mut EventOrSpanMarker) {
+    if let MarkerData::Span(span) = &mut marker.marker_data {
+        span.child_markers.sort_by_key(|m| m.start_time);
+        for child in &mut span.child_markers {
+            sort_child_markers(child);
+        }
+    }
+}
+
+/// The target and category of the markers from atrace files.
+const ATRACE_TARGET: &str = "atrace";
+
+/// Whether `first_line` is the header of an ftrace text dump, such as the
+/// output of Android's `atrace`, which starts with `# tracer: <name>`.
+fn is_ftrace_header(first_line: &[u8]) -> bool {
+    first_line.starts_with(b"# tracer:")
+}
+
+/// Parses an ftrace timestamp in seconds, e.g. `1234.567890`, into
+/// nanoseconds, without going through a float.
+fn parse_ftrace_seconds(seconds: &str) -> Option<u64> {
+    let (secs, fraction) = seconds.split_once('.').unwrap_or((seconds, ""));
+    if fraction.len() > 9 || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let fraction_ns = format!("{fraction:0<9}").parse::<u64>().ok()?;
+    secs.parse::<u64>()
+        .ok()?
+        .checked_mul(1_000_000_000)?
+        .checked_add(fraction_ns)
+}
+
+/// Splits an ftrace line with a `tracing_mark_write` payload, e.g.
+/// `  surfaceflinger-612  [003] ...1  1234.567890: tracing_mark_write: B|612|draw`,
+/// into the thread id, the raw timestamp in nanoseconds and the payload.
+fn parse_ftrace_mark_line(line: &str) -> Option<(i32, u64, &str)> {
+    let (prefix, payload) = line.split_once(": tracing_mark_write: ")?;
+    let (task_pid, rest) = prefix.split_once(" [")?;
+    // Newer kernels can put the process id in parentheses after the thread.
+    let task_pid = task_pid.split(" (").next()?.trim();
+    let tid = task_pid.rsplit_once('-')?.1.parse().ok()?;
+    let timestamp = parse_ftrace_seconds(rest.split_whitespace().last()?)?;
+    Some((tid, timestamp, payload.trim_end()))
+}
+
+/// Reads an ftrace text dump with the `tracing_mark_write` lines written by
+/// Android's atrace / `ATrace_*` APIs, and returns its markers, its counters
+/// and a report of how its lines were used.
+///
+/// `B|pid|name` begins a span on the writing thread, which the next
+/// `E|pid` on the same thread ends, so spans nest like a call stack.
+/// `C|pid|name|value` is a sample of the counter `name`. Other lines,
+/// including the ftrace header, are skipped. The ftrace timestamps are in
+/// seconds of the trace clock, which is `CLOCK_MONOTONIC` for atrace.
+pub fn parse_atrace(
+    reader: impl BufRead,
+    converter: TimestampConverter,
+) -> std::io::Result<(Vec<EventOrSpanMarker>, Vec<Counter>, MarkerFileReport)> {
+    let mut report = MarkerFileReport::default();
+    let mut markers = Vec::new();
+    let mut counters: Vec<Counter> = Vec::new();
+    // The begun but not yet ended spans of each thread, innermost last.
+    let mut open_spans: HashMap<i32, Vec<(u64, String)>> = HashMap::new();
+    let mut next_span_id = 1;
+    for line in reader.lines() {
+        let line = line?;
+        report.lines_read += 1;
+        let Some((tid, timestamp, payload)) = parse_ftrace_mark_line(&line) else {
+            continue;
+        };
+        let mut parts = payload.split('|');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some("B"), Some(_pid), Some(name)) => {
+                open_spans
+                    .entry(tid)
+                    .or_default()
+                    .push((timestamp, name.to_string()));
+            }
+            (Some("E"), ..) => {
+                let Some((start, name)) = open_spans.get_mut(&tid).and_then(Vec::pop) else {
+                    report.dropped_keyword_mismatch += 1;
+                    continue;
+                };
+                markers.push(EventOrSpanMarker {
+                    start_time: converter.convert_time(start),
+                    message: name,
+                    target: ATRACE_TARGET.to_string(),
+                    extra_fields: HashMap::new(),
+                    level: None,
+                    tid: Some(tid),
+                    trace_context: None,
+                    field_types: None,
+                    marker_data: MarkerData::Span(MarkerSpan {
+                        id: next_span_id,
+                        span_type: SpanType::Total,
+                        end_time: converter.convert_time(timestamp),
+                        timings: TracingTimings {
+                            time_busy: Duration::from_nanos(timestamp.saturating_sub(start)),
+                            time_idle: Duration::ZERO,
+                        },
+                        category: ATRACE_TARGET.to_string(),
+                        profiler_label: None,
+                        stats_label: None,
+                        child_markers: Vec::new(),
+                    }),
+                });
+                next_span_id += 1;
+                report.lines_with_markers += 1;
+            }
+            (Some("C"), Some(_pid), Some(name)) => {
+                let Some(value) = parts.next().and_then(|value| value.parse::<f64>().ok()) else {
+                    continue;
+                };
+                let index = match counters.iter().position(|counter| counter.name == name) {
+                    Some(index) => index,
+                    None => {
+                        counters.push(Counter {
+                            name: name.to_string(),
+                            category: CounterCategory::Custom,
+                            description: format!("atrace counter {name}"),
+                            color: None,
+                            graph_type: MarkerGraphType::Line,
+                            is_cumulative: false,
+                            samples: Vec::new(),
+                        });
+                        counters.len() - 1
+                    }
+                };
+                counters[index].samples.push(CounterSample {
+                    timestamp: converter.convert_time(timestamp),
+                    value,
+                    modification_count: 1,
+                });
+                report.lines_with_markers += 1;
+            }
+            _ => {}
+        }
+    }
+    let mut markers = nest_markers_by_time(markers);
+    markers.sort_by_key(|m| m.start_time);
+    Ok((markers, counters, report))
+}
+
+/// The marker file formats which `MarkerFile::auto_detect` tells apart by
+/// their first line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MarkerFileFormat {
+    /// samply's `id[,tid] {json}` lines, see `MarkerLineProcessor`.
+    Samply,
+    /// The Chrome trace event format, either a JSON array of events or an
+    /// object with a `traceEvents` array.
+    ChromeTrace,
+    /// OpenTelemetry spans in the OTLP/JSON encoding, e.g. one export
+    /// request per line as written by the OTLP file exporter.
+    Otlp,
+    /// The records of slog-json, one JSON object with `msg`, `level` and
+    /// `ts` per line.
+    Slog,
+    /// Lines in env_logger's default format, e.g.
+    /// `[2024-05-01T12:00:00Z INFO  my_crate::module] message`.
+    EnvLogger,
+}
+
+impl Display for MarkerFileFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MarkerFileFormat::Samply => write!(f, "samply"),
+            MarkerFileFormat::ChromeTrace => write!(f, "Chrome trace"),
+            MarkerFileFormat::Otlp => write!(f, "OTLP"),
+            MarkerFileFormat::Slog => write!(f, "slog"),
+            MarkerFileFormat::EnvLogger => write!(f, "env_logger"),
+        }
+    }
+}
+
+impl MarkerFileFormat {
+    /// The format of a marker file which starts with `first_line`, or `None`
+    /// if it's none of the known formats.
+    pub fn detect(first_line: &str) -> Option<Self> {
+        let line = first_line.trim_start_matches('\u{feff}').trim();
+        if let Some(rest) = line.strip_prefix('[') {
+            let rest = rest.trim_start();
+            if rest.is_empty() || rest.starts_with('{') {
+                return Some(MarkerFileFormat::ChromeTrace);
+            }
+            return parse_env_logger_line(line).map(|_| MarkerFileFormat::EnvLogger);
+        }
+        if line.starts_with('{') {
+            if line.contains("\"traceEvents\"") {
+                return Some(MarkerFileFormat::ChromeTrace);
+            }
+            if line.contains("\"resourceSpans\"") {
+                return Some(MarkerFileFormat::Otlp);
+            }
+            let json = serde_json::from_str::<serde_json::Value>(line).ok()?;
+            if ["msg", "level", "ts"]
+                .iter()
+                .all(|field| json.get(field).is_some())
+            {
+                return Some(MarkerFileFormat::Slog);
+            }
+            // Events without the `id[,tid]` prefix, see
+            // `MarkerLineProcessor::parse_line_without_id`.
+            return json.get("timestamp").map(|_| MarkerFileFormat::Samply);
+        }
+        let (ids, json) = line.split_once(' ')?;
+        let (id, tid) = ids.split_once(',').unwrap_or((ids, "0"));
+        let is_samply = parse_span_id(id).is_some()
+            && tid.parse::<i32>().is_ok()
+            && json.trim_start().starts_with('{');
+        is_samply.then_some(MarkerFileFormat::Samply)
+    }
+}
+
+/// The diagnostic which `MarkerFile::auto_detect` logs at the info level,
+/// i.e. with `RUST_LOG=info`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DetectedFormat {
+    pub format: MarkerFileFormat,
+    /// The first line, truncated to `QUOTED_LINE_MAX_LEN` bytes.
+    pub first_line: String,
+}
+
+impl Display for DetectedFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Detected the {} marker file format from the first line {:?}",
+            self.format, self.first_line
+        )
+    }
+}
+
+impl MarkerFile {
+    /// Reads the first line of `reader` to detect which `MarkerFileFormat`
+    /// it's in, and returns an iterator over its markers in that format, in
+    /// file order and without nesting them into `child_markers`. Invalid
+    /// lines and records are skipped with a warning.
+    pub fn auto_detect(
+        mut reader: impl BufRead + 'static,
+        converter: TimestampConverter,
+    ) -> Result<Box<dyn Iterator<Item = EventOrSpanMarker>>, FormatError> {
+        let mut first_line = String::new();
+        reader.read_line(&mut first_line)?;
+        let quoted_line = truncate_line(first_line.trim_end(), QUOTED_LINE_MAX_LEN).to_string();
+        let Some(format) = MarkerFileFormat::detect(&first_line) else {
+            return Err(FormatError::Unrecognized(quoted_line));
+        };
+        log::info!(
+            "{}",
+            DetectedFormat {
+                format,
+                first_line: quoted_line,
+            }
+        );
+        let reader = BufReader::new(Cursor::new(first_line).chain(reader));
+        let markers: Box<dyn Iterator<Item = EventOrSpanMarker>> = match format {
+            MarkerFileFormat::Samply => Box::new(
+                MarkerFile::parse(reader, Path::new("marker file"), converter).filter_map(
+                    |marker| match marker {
+                        Ok((marker, _parent_id)) => Some(marker),
+                        Err(e) => {
+                            e.report();
+                            None
+                        }
+                    },
+                ),
+            ),
+            MarkerFileFormat::ChromeTrace => {
+                let mut chrome_trace = ChromeTraceReader::new(converter);
+                Box::new(
+                    json_values(reader)
+                        .flat_map(chrome_trace_events)
+                        .filter_map(move |event| chrome_trace.read_event(&event)),
+                )
+            }
+            MarkerFileFormat::Otlp => {
+                let mut otlp = OtlpReader::new(converter);
+                Box::new(json_values(reader).flat_map(move |request| otlp.read_request(&request)))
+            }
+            MarkerFileFormat::Slog => Box::new(
+                json_values(reader).filter_map(move |record| slog_marker(&record, &converter)),
+            ),
+            MarkerFileFormat::EnvLogger => Box::new(
+                reader
+                    .lines()
+                    .map_while(Result::ok)
+                    .filter_map(move |line| env_logger_marker(&line, &converter)),
+            ),
+        };
+        Ok(markers)
+    }
+}
+
+/// The JSON values in `reader`, which can be a single document or one value
+/// per line. Reading stops at the first invalid value, with a warning.
+fn json_values(reader: impl Read) -> impl Iterator<Item = serde_json::Value> {
+    serde_json::Deserializer::from_reader(reader)
+        .into_iter::<serde_json::Value>()
+        .map_while(|value| {
+            value
+                .map_err(|e| warn!("Stopped reading the marker file at invalid JSON: {e}"))
+                .ok()
+        })
+}
+
+/// An event or span of one of the formats which `MarkerFile::auto_detect`
+/// reads, which have no span ids of their own.
+fn foreign_marker(
+    start_time: Timestamp,
+    message: String,
+    target: String,
+    extra_fields: HashMap<String, String>,
+    level: Option<EventLevel>,
+) -> EventOrSpanMarker {
+    EventOrSpanMarker {
+        start_time,
+        message,
+        target,
+        extra_fields,
+        level,
+        tid: None,
+        trace_context: None,
+        field_types: None,
+        marker_data: MarkerData::Event,
+    }
+}
+
+/// A `SpanType::Total` span from `start_ns` to `end_ns`, raw timestamps.
+fn foreign_span(
+    id: u64,
+    category: String,
+    start_ns: u64,
+    end_ns: u64,
+    converter: &TimestampConverter,
+) -> MarkerData {
+    MarkerData::Span(MarkerSpan {
+        id,
+        span_type: SpanType::Total,
+        end_time: converter.convert_time(end_ns),
+        timings: TracingTimings {
+            time_busy: Duration::from_nanos(end_ns.saturating_sub(start_ns)),
+            time_idle: Duration::ZERO,
+        },
+        category,
+        profiler_label: None,
+        stats_label: None,
+        child_markers: Vec::new(),
+    })
+}
+
+/// The events of a Chrome trace document, which is either an array of
+/// events or an object with a `traceEvents` array.
+fn chrome_trace_events(document: serde_json::Value) -> Vec<serde_json::Value> {
+    match document {
+        serde_json::Value::Array(events) => events,
+        serde_json::Value::Object(mut object) => match object.remove("traceEvents") {
+            Some(serde_json::Value::Array(events)) => events,
+            _ => Vec::new(),
+        },
+        _ => Vec::new(),
+    }
+}
+
+/// Turns Chrome trace events into markers. Complete ("X") events, and
+/// begin ("B") and end ("E") events on the same thread, which nest like a
+/// call stack, become spans. Instant ("i", "I") and mark ("R") events become
+/// events. Other events are skipped. The `ts` timestamps are microseconds
+/// of the monotonic clock.
+struct ChromeTraceReader {
+    converter: TimestampConverter,
+    /// The begun but not yet ended spans of each (pid, tid), innermost last.
+    open_spans: HashMap<(i64, i64), Vec<(u64, serde_json::Value)>>,
+    next_span_id: u64,
+}
+
+impl ChromeTraceReader {
+    fn new(converter: TimestampConverter) -> Self {
+        Self {
+            converter,
+            open_spans: HashMap::new(),
+            next_span_id: 1,
+        }
+    }
+
+    fn timestamp_ns(value: Option<&serde_json::Value>) -> Option<u64> {
+        let us = value?.as_f64()?;
+        (us >= 0.0).then(|| (us * 1000.0).round() as u64)
+    }
+
+    fn read_event(&mut self, event: &serde_json::Value) -> Option<EventOrSpanMarker> {
+        let phase = event.get("ph")?.as_str()?;
+        let timestamp = Self::timestamp_ns(event.get("ts"))?;
+        let id = |field| event.get(field).and_then(serde_json::Value::as_i64);
+        let thread = (id("pid").unwrap_or(0), id("tid").unwrap_or(0));
+        match phase {
+            "B" => {
+                self.open_spans
+                    .entry(thread)
+                    .or_default()
+                    .push((timestamp, event.clone()));
+                None
+            }
+            "E" => {
+                let (start, begin) = self.open_spans.get_mut(&thread)?.pop()?;
+                Some(self.span(&begin, start, timestamp))
+            }
+            "X" => {
+                let duration = Self::timestamp_ns(event.get("dur")).unwrap_or(0);
+                Some(self.span(event, timestamp, timestamp + duration))
+            }
+            "i" | "I" | "R" => Some(self.marker(event, timestamp)),
+            _ => None,
+        }
+    }
+
+    fn marker(&self, event: &serde_json::Value, timestamp: u64) -> EventOrSpanMarker {
+        let string = |field| event.get(field).and_then(serde_json::Value::as_str);
+        let mut marker = foreign_marker(
+            self.converter
+                .convert_time_from_domain(timestamp, ClockDomain::Monotonic),
+            string("name").unwrap_or_default().to_string(),
+            string("cat").unwrap_or("chrome").to_string(),
+            event
+                .get("args")
+                .map(MarkerLineProcessor::value_to_hashmap)
+                .unwrap_or_default(),
+            None,
+        );
+        marker.tid = event
+            .get("tid")
+            .and_then(serde_json::Value::as_i64)
+            .and_then(|tid| i32::try_from(tid).ok());
+        marker
+    }
+
+    fn span(&mut self, event: &serde_json::Value, start: u64, end: u64) -> EventOrSpanMarker {
+        let mut marker = self.marker(event, start);
+        let end = self.converter.raw_from_domain(end, ClockDomain::Monotonic);
+        let start = self
+            .converter
+            .raw_from_domain(start, ClockDomain::Monotonic);
+        marker.marker_data = foreign_span(
+            self.next_span_id,
+            marker.target.clone(),
+            start,
+            end,
+            &self.converter,
+        );
+        self.next_span_id += 1;
+        marker
+    }
+}
+
+/// Parses a timestamp field which holds nanoseconds as a number or as a
+/// string, as OTLP/JSON does for 64-bit integers.
+fn u64_field(json: &serde_json::Value, field: &str) -> Option<u64> {
+    match json.get(field)? {
+        serde_json::Value::Number(n) => n.as_u64(),
+        serde_json::Value::String(s) => s.parse().ok(),
+        _ => None,
+    }
+}
+
+/// The value of an OTLP `AnyValue`, e.g. `{"stringValue": "GET"}`.
+fn otlp_value_to_string(value: &serde_json::Value) -> Option<String> {
+    let (_, value) = value.as_object()?.iter().next()?;
+    Some(match value.as_str() {
+        Some(s) => s.to_string(),
+        None => value.to_string(),
+    })
+}
+
+/// Reads the spans of OTLP/JSON export requests. The scope name is the
+/// target and category of its spans. The attributes, and the trace and span
+/// ids, become extra fields. The timestamps are nanoseconds of the realtime
+/// clock.
+struct OtlpReader {
+    converter: TimestampConverter,
+    /// The marker span id of each (trace id, span id) seen so far. The OTLP
+    /// span ids are only unique within their trace, and spans without one
+    /// get an id of their own, so the marker span ids are counted instead.
+    span_ids: HashMap<(String, String), u64>,
+    next_span_id: u64,
+}
+
+impl OtlpReader {
+    fn new(converter: TimestampConverter) -> Self {
+        Self {
+            converter,
+            span_ids: HashMap::new(),
+            next_span_id: 1,
+        }
+    }
+
+    /// The marker span id for the span with these ids.
+    fn span_id(&mut self, trace_id: Option<&str>, span_id: Option<&str>) -> u64 {
+        let next_span_id = &mut self.next_span_id;
+        let mut new_span_id = || {
+            let id = *next_span_id;
+            *next_span_id += 1;
+            id
+        };
+        match span_id {
+            Some(span_id) => *self
+                .span_ids
+                .entry((
+                    trace_id.unwrap_or_default().to_string(),
+                    span_id.to_string(),
+                ))
+                .or_insert_with(new_span_id),
+            None => new_span_id(),
+        }
+    }
+
+    fn read_request(&mut self, request: &serde_json::Value) -> Vec<EventOrSpanMarker> {
+        let array = |json: &serde_json::Value, field: &str| -> Vec<serde_json::Value> {
+            json.get(field)
+                .and_then(serde_json::Value::as_array)
+                .cloned()
+                .unwrap_or_default()
+        };
+        let mut markers = Vec::new();
+        for resource_spans in array(request, "resourceSpans") {
+            for scope_spans in array(&resource_spans, "scopeSpans") {
+                let scope = scope_spans
+                    .pointer("/scope/name")
+                    .and_then(serde_json::Value::as_str)
+                    .filter(|name| !name.is_empty())
+                    .unwrap_or("otlp");
+                for span in array(&scope_spans, "spans") {
+                    let (Some(start), Some(end)) = (
+                        u64_field(&span, "startTimeUnixNano"),
+                        u64_field(&span, "endTimeUnixNano"),
+                    ) else {
+                        continue;
+                    };
+                    let mut extra_fields: HashMap<String, String> = array(&span, "attributes")
+                        .iter()
+                        .filter_map(|attribute| {
+                            let key = attribute.get("key")?.as_str()?;
+                            let value = otlp_value_to_string(attribute.get("value")?)?;
+                            Some((key.to_string(), value))
+                        })
+                        .collect();
+                    let id = |field| {
+                        span.get(field)
+                            .and_then(serde_json::Value::as_str)
+                            .filter(|id| !id.is_empty())
+                    };
+                    for (field, extra_field) in [
+                        ("traceId", TRACE_ID_FIELD),
+                        ("spanId", "span_id"),
+                        ("parentSpanId", "parent_span_id"),
+                    ] {
+                        if let Some(id) = id(field) {
+                            extra_fields.insert(extra_field.to_string(), id.to_string());
+                        }
+                    }
+                    let span_id = self.span_id(id("traceId"), id("spanId"));
+                    let converter = &self.converter;
+                    let start = converter.raw_from_domain(start, ClockDomain::Realtime);
+                    let end = converter.raw_from_domain(end, ClockDomain::Realtime);
+                    let mut marker = foreign_marker(
+                        converter.convert_time(start),
+                        str_field(&span, "name").unwrap_or_default().to_string(),
+                        scope.to_string(),
+                        extra_fields,
+                        None,
+                    );
+                    marker.marker_data =
+                        foreign_span(span_id, scope.to_string(), start, end, converter);
+                    markers.push(marker);
+                }
+            }
+        }
+        markers
+    }
+}
+
+/// Parses an RFC 3339 timestamp, e.g. `2024-05-01T12:00:00.123Z` or
+/// `2024-05-01T14:00:00.123+02:00`, into nanoseconds since the UNIX epoch.
+fn parse_rfc3339_ns(timestamp: &str) -> Option<u64> {
+    let timestamp = timestamp.trim();
+    // humantime only reads UTC timestamps, so other offsets are applied here.
+    let split = timestamp.len().checked_sub(6);
+    let (utc, offset_s) = match split.and_then(|split| timestamp.get(split..)) {
+        Some(offset) if offset.starts_with(['+', '-']) && offset.as_bytes()[3] == b':' => {
+            let hours: i64 = offset[1..3].parse().ok()?;
+            let minutes: i64 = offset[4..6].parse().ok()?;
+            let sign = if offset.starts_with('-') { -1 } else { 1 };
+            (&timestamp[..split?], sign * (hours * 3600 + minutes * 60))
+        }
+        _ => (timestamp, 0),
+    };
+    let since_epoch = humantime::parse_rfc3339_weak(utc)
+        .ok()?
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .ok()?;
+    let ns = i128::try_from(since_epoch.as_nanos()).ok()? - i128::from(offset_s) * 1_000_000_000;
+    u64::try_from(ns).ok()
+}
+
+/// The level names of slog, e.g. `DEBG`, or the full names.
+fn slog_level(level: &str) -> Option<EventLevel> {
+    match level {
+        "CRIT" | "CRITICAL" | "ERRO" => Some(EventLevel::Error),
+        "DEBG" => Some(EventLevel::Debug),
+        "TRCE" => Some(EventLevel::Trace),
+        _ => EventLevel::from_name(level),
+    }
+}
+
+/// An event for a slog-json record. Its `module` field, if it has one, is
+/// the target, and the other key-value pairs become extra fields.
+fn slog_marker(
+    record: &serde_json::Value,
+    converter: &TimestampConverter,
+) -> Option<EventOrSpanMarker> {
+    let timestamp = parse_rfc3339_ns(record.get("ts")?.as_str()?)?;
+    let mut extra_fields = MarkerLineProcessor::value_to_hashmap(record);
+    let message = extra_fields.remove("msg")?;
+    let level = extra_fields.remove("level").as_deref().and_then(slog_level);
+    extra_fields.remove("ts");
+    let target = extra_fields
+        .remove("module")
+        .unwrap_or_else(|| "slog".to_string());
+    Some(foreign_marker(
+        converter.convert_time_from_domain(timestamp, ClockDomain::Realtime),
+        message,
+        target,
+        extra_fields,
+        level,
+    ))
+}
+
+/// Splits a line of env_logger's default format,
+/// `[<timestamp> <level> <target>] <message>`, into the timestamp in
+/// nanoseconds since the UNIX epoch, the level, the target and the message.
+fn parse_env_logger_line(line: &str) -> Option<(u64, EventLevel, &str, &str)> {
+    let (header, message) = line.strip_prefix('[')?.split_once(']')?;
+    let mut parts = header.split_whitespace();
+    let timestamp = parse_rfc3339_ns(parts.next()?)?;
+    let level = EventLevel::from_name(parts.next()?)?;
+    let target = parts.next().unwrap_or("env_logger");
+    Some((timestamp, level, target, message.trim()))
+}
+
+/// An event for an env_logger line. Lines which don't start with the
+/// bracketed header, e.g. the continuation lines of multi-line messages, are
+/// skipped.
+fn env_logger_marker(line: &str, converter: &TimestampConverter) -> Option<EventOrSpanMarker> {
+    let (timestamp, level, target, message) = parse_env_logger_line(line)?;
+    Some(foreign_marker(
+        converter.convert_time_from_domain(timestamp, ClockDomain::Realtime),
+        message.to_string(),
+        target.to_string(),
+        HashMap::new(),
+        Some(level),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::shared::test_support::identity_converter;
+
+    fn try_parse_markers(lines: &[&str]) -> Result<Vec<EventOrSpanMarker>, Error> {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        for line in lines {
+            writeln!(file, "{line}").unwrap();
+        }
+        Ok(get_markers(
+            file.path(),
+            &[],
+            identity_converter(),
+            MarkerFileOptions::default(),
+        )?
+        .markers)
+    }
+
+    fn parse_markers(lines: &[&str]) -> Vec<EventOrSpanMarker> {
+        try_parse_markers(lines).unwrap()
+    }
+
+    fn span_line(id: u64, message: &str, timestamp: u64, parent_id: Option<u64>) -> String {
+        let parent = parent_id.map_or(String::new(), |p| format!(r#""parent_id": {p}, "#));
+        format!(
+            r#"{id} {{{parent}"timestamp": "{timestamp}", "target": "t", "fields": {{"message": "{message}"}}, "span": {{"name": "span{id}", "action": "a"}}}}"#
+        )
+    }
+
+    fn event_line(timestamp: u64, parent_id: u64) -> String {
+        format!(
+            r#"0 {{"parent_id": "{parent_id}", "timestamp": "{timestamp}", "target": "t", "fields": {{"message": "event"}}}}"#
+        )
+    }
+
+    fn span(marker: &EventOrSpanMarker) -> &MarkerSpan {
+        match &marker.marker_data {
+            MarkerData::Span(span) => span,
+            MarkerData::Event => panic!("expected a span"),
+        }
+    }
+
+    #[test]
+    fn markers_from_log_records() {
+        let converter = TimestampConverter {
+            domain: ClockDomain::Realtime,
+            ..identity_converter()
+        };
+        let marker = EventOrSpanMarker::from_log_record(
+            &log::Record::builder()
+                .args(format_args!("cache miss for {}", "user:1"))
+                .level(log::Level::Warn)
+                .target("app::cache")
+                .file(Some("src/cache.rs"))
+                .line(Some(42))
+                .build(),
+            &converter,
+        )
+        .with_thread_id(7)
+        .with_extra_field("shard", "3");
+        assert_eq!(marker.message, "cache miss for user:1");
+        assert_eq!(marker.target, "app::cache");
+        assert_eq!(marker.level, Some(EventLevel::Warn));
+        assert_eq!(marker.tid, Some(7));
+        assert!(matches!(marker.marker_data, MarkerData::Event));
+        let mut fields: Vec<_> = marker.extra_fields.into_iter().collect();
+        fields.sort();
+        assert_eq!(
+            fields,
+            [
+                ("file".to_string(), "src/cache.rs".to_string()),
+                ("line".to_string(), "42".to_string()),
+                ("shard".to_string(), "3".to_string()),
+            ]
+        );
+        // The converter's reference is the UNIX epoch.
+        assert!(marker.start_time > Timestamp::from_millis_since_reference(1.7e12));
+    }
+
+    #[test]
+    fn child_markers_are_nested_into_their_parent_span() {
+        let markers = parse_markers(&[
+            &span_line(1, "new", 10, None),
+            &span_line(2, "new", 20, Some(1)),
+            &event_line(25, 2),
+            &span_line(2, "close", 30, None),
+            &event_line(35, 1),
+            &span_line(1, "close", 40, None),
+            // Span id 2 is reused for an unrelated top-level span.
+            &span_line(2, "new", 50, None),
+            &span_line(2, "close", 60, None),
+        ]);
+
+        assert_eq!(markers.len(), 2);
+        let outer = span(&markers[0]);
+        assert_eq!(outer.id, 1);
+        assert_eq!(outer.child_markers.len(), 2);
+        let inner = span(&outer.child_markers[0]);
+        assert_eq!(inner.id, 2);
+        assert_eq!(inner.child_markers.len(), 1);
+        assert!(matches!(
+            inner.child_markers[0].marker_data,
+            MarkerData::Event
+        ));
+        assert!(matches!(
+            outer.child_markers[1].marker_data,
+            MarkerData::Event
+        ));
+        assert!(span(&markers[1]).child_markers.is_empty());
+    }
+
+    #[test]
+    fn explicit_parent_ids_win_over_time_nesting() {
+        let markers = parse_markers(&[
+            &span_line(1, "new", 10, None),
+            // Span 2 is within span 1's time range, but is a root span.
+            &span_line(2, "new", 20, None),
+            &span_line(2, "close", 30, None),
+            &span_line(3, "new", 40, Some(1)),
+            &event_line(45, 3),
+            &span_line(3, "close", 50, None),
+            &span_line(1, "close", 100, None),
+        ]);
+
+        let ids: Vec<u64> = markers.iter().map(|m| span(m).id).collect();
+        assert_eq!(ids, [1, 2]);
+        let outer = span(&markers[0]);
+        assert_eq!(outer.child_markers.len(), 1);
+        let inner = span(&outer.child_markers[0]);
+        assert_eq!(inner.id, 3);
+        assert_eq!(
+            inner.child_markers[0].start_time.as_nanos_since_reference(),
+            45
+        );
+        assert!(span(&markers[1]).child_markers.is_empty());
+    }
+
+    #[test]
+    fn markers_without_parent_ids_are_nested_by_time() {
+        let event = |timestamp: u64| {
+            format!(
+                r#"0 {{"timestamp": "{timestamp}", "target": "t", "fields": {{"message": "event"}}}}"#
+            )
+        };
+        let markers = parse_markers(&[
+            &span_line(1, "new", 10, None),
+            &span_line(2, "new", 20, None),
+            &event(25),
+            &span_line(2, "close", 30, None),
+            &event(60),
+            &span_line(1, "close", 100, None),
+            &span_line(3, "new", 200, None),
+            &span_line(3, "close", 300, None),
+            &event(400),
+        ]);
+
+        assert_eq!(markers.len(), 3);
+        let outer = span(&markers[0]);
+        assert_eq!(outer.id, 1);
+        let child_times: Vec<u64> = outer
+            .child_markers
+            .iter()
+            .map(|m| m.start_time.as_nanos_since_reference())
+            .collect();
+        assert_eq!(child_times, [20, 60]);
+        let inner = span(&outer.child_markers[0]);
+        assert_eq!(inner.id, 2);
+        assert_eq!(inner.child_markers.len(), 1);
+        assert!(span(&markers[1]).child_markers.is_empty());
+        assert!(matches!(markers[2].marker_data, MarkerData::Event));
+    }
+
+    #[test]
+    fn span_keeps_start_target_if_it_differs() {
+        let markers = parse_markers(&[
+            &span_line(1, "new", 10, None).replace(r#""target": "t""#, r#""target": "db::open""#),
+            &span_line(1, "close", 20, None).replace(r#""target": "t""#, r#""target": "db::io""#),
+            &span_line(2, "new", 30, None),
+            &span_line(2, "close", 40, None),
+        ]);
+        assert_eq!(markers[0].target, "db::io");
+        assert_eq!(markers[0].extra_fields["start_target"], "db::open");
+        assert_eq!(markers[1].target, "t");
+        assert!(!markers[1].extra_fields.contains_key("start_target"));
+    }
+
+    #[test]
+    fn markers_with_unclosed_parent_stay_top_level() {
+        let markers = parse_markers(&[&span_line(1, "new", 10, None), &event_line(15, 1)]);
+        assert_eq!(markers.len(), 1);
+        assert!(matches!(markers[0].marker_data, MarkerData::Event));
+    }
+
+    #[test]
+    fn missing_marker_file_is_not_found() {
+        let err = get_markers(
+            Path::new("/nonexistent/marker-1.txt"),
+            &[],
+            identity_converter(),
+            MarkerFileOptions::default(),
+        )
+        .unwrap_err();
+        assert!(matches!(err, Error::NotFound(_)), "{err:?}");
+        assert!(!err.is_parse_error());
+    }
+
+    #[test]
+    fn malformed_lines_are_skipped() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        for line in [
+            event_line(5, 0),
+            r#"0 {"timestamp": "soon", "target": "t", "fields": {"message": "event"}}"#.to_string(),
+            r#"0 {"timestamp": "6", "fields": {"message": "no target"}}"#.to_string(),
+            span_line(1, "new", 10, None),
+            r#"1 {"timestamp": "20", "target": "t", "fields": {"message": "close", "time.busy": "3fortnights"}, "span": {"name": "span1"}}"#.to_string(),
+            r#"2 {"timestamp": "30", "target": "t", "fields": {"message": "m", "started_at": "later"}, "span": {"name": "s"}}"#.to_string(),
+            event_line(40, 0),
+        ] {
+            writeln!(file, "{line}").unwrap();
+        }
+        let MarkerFileContents {
+            markers, report, ..
+        } = get_markers(
+            file.path(),
+            &[],
+            identity_converter(),
+            MarkerFileOptions::default(),
+        )
+        .unwrap();
+        let times: Vec<Timestamp> = markers.iter().map(|m| m.start_time).collect();
+        assert_eq!(times, [ts(5), ts(40)]);
+        assert_eq!(report.lines_read, 7);
+        assert_eq!(report.malformed, 4);
+        assert_eq!(report.invalid_json, 0);
+    }
+
+    #[test]
+    fn invalid_marker_line_is_parse_error() {
+        let err = try_parse_markers_strict(&[
+            &event_line(5, 1),
+            r#"0 {"timestamp": "soon", "target": "t", "fields": {"message": "event"}}"#,
+        ])
+        .unwrap_err();
+        match err {
+            Error::Parse { line, reason, .. } => {
+                assert_eq!(line, Some(2));
+                assert!(reason.contains("soon"), "{reason}");
+            }
+            err => panic!("expected a parse error, got {err:?}"),
+        }
+    }
+
+    #[test]
+    fn unknown_timing_unit_is_parse_error() {
+        let err = try_parse_markers_strict(&[
+            &span_line(1, "new", 10, None),
+            r#"1 {"timestamp": "20", "target": "t", "fields": {"message": "close", "time.busy": "3fortnights"}, "span": {"name": "span1"}}"#,
+        ])
+        .unwrap_err();
+        assert!(matches!(err, Error::Parse { line: Some(2), .. }), "{err:?}");
+    }
+
+    fn try_parse_markers_strict(lines: &[&str]) -> Result<Vec<EventOrSpanMarker>, Error> {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        for line in lines {
+            writeln!(file, "{line}").unwrap();
+        }
+        let options = MarkerFileOptions {
+            strict: true,
+            ..Default::default()
+        };
+        Ok(get_markers(file.path(), &[], identity_converter(), options)?.markers)
+    }
+
+    #[test]
+    fn warnings_have_the_line_number_and_the_start_of_the_line() {
+        let mut processor = MarkerLineProcessor::new(identity_converter());
+        let close = span_line(1, "close", 10, None);
+        processor.process_line(&event_line(5, 0)).unwrap();
+        processor.process_line(&close).unwrap();
+        let warning = processor.line_warning("Dropping span", &close);
+        assert!(
+            warning.starts_with("line 2: Dropping span in "),
+            "{warning}"
+        );
+        assert!(warning.contains(r#"\"timestamp\": \"10\""#), "{warning}");
+
+        let long_line = format!("0 {}", "x".repeat(1000));
+        let warning = processor.line_warning("invalid", &long_line);
+        assert!(warning.len() < 300, "{warning}");
+    }
+
+    #[test]
+    fn strict_markers_fail_at_the_first_skipped_line() {
+        let lines = [
+            event_line(5, 0),
+            span_line(1, "new", 10, None),
+            "0 {not json".to_string(),
+        ];
+        let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+        assert_eq!(parse_markers(&lines).len(), 1);
+        match try_parse_markers_strict(&lines).unwrap_err() {
+            Error::Parse { line, reason, .. } => {
+                assert_eq!(line, Some(3));
+                assert!(reason.contains("invalid JSON"), "{reason}");
+                assert!(reason.contains("0 {not json"), "{reason}");
+            }
+            err => panic!("expected a parse error, got {err:?}"),
+        }
+    }
+
+    #[test]
+    fn strict_markers_fail_at_a_keyword_mismatch() {
+        let lines = [
+            span_line(1, "new", 10, None),
+            span_line(1, "close", 20, None),
+            span_line(1, "close", 30, None),
+        ];
+        let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+        assert_eq!(parse_markers(&lines).len(), 1);
+        match try_parse_markers_strict(&lines).unwrap_err() {
+            Error::Parse { line, reason, .. } => {
+                assert_eq!(line, Some(3));
+                assert!(reason.contains("expected 'new', got 'close'"), "{reason}");
+            }
+            err => panic!("expected a parse error, got {err:?}"),
+        }
+    }
+
+    #[test]
+    fn lines_without_an_id_are_events() {
+        let bare_event = r#"{"timestamp": "15", "target": "t", "fields": {"message": "bare"}}"#;
+        let lines = [event_line(5, 0), bare_event.to_string()];
+        let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+        let markers = parse_markers(&lines);
+        assert_eq!(markers.len(), 2);
+        assert!(matches!(markers[1].marker_data, MarkerData::Event));
+        assert_eq!(markers[1].message, "bare");
+
+        // It's a warning, so it's an error in strict mode.
+        match try_parse_markers_strict(&lines).unwrap_err() {
+            Error::Parse { line, reason, .. } => {
+                assert_eq!(line, Some(2));
+                assert!(reason.contains("missing `id[,tid]` prefix"), "{reason}");
+            }
+            err => panic!("expected a parse error, got {err:?}"),
+        }
+    }
+
+    #[test]
+    fn traceparent_is_parsed() {
+        let traceparent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        assert_eq!(
+            TraceContext::from_traceparent(traceparent),
+            Some(TraceContext {
+                trace_id: 0x4bf92f3577b34da6a3ce929d0e0e4736,
+                parent_id: 0x00f067aa0ba902b7,
+                flags: 1,
+            })
+        );
+        // Later versions may append parts, version 00 may not.
+        let future = "cc-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-what";
+        assert!(TraceContext::from_traceparent(future).is_some());
+        for invalid in [
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-what",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e473-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e473g-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+        ] {
+            assert_eq!(TraceContext::from_traceparent(invalid), None, "{invalid}");
+        }
+
+        let markers = parse_markers(&[
+            &format!(
+                r#"0 {{"timestamp": "5", "target": "t", "fields": {{"message": "event", "traceparent": "{traceparent}"}}}}"#
+            ),
+            r#"0 {"timestamp": "6", "target": "t", "fields": {"message": "event", "traceparent": "bogus"}}"#,
+            &format!(
+                r#"1 {{"timestamp": "20", "target": "t", "fields": {{"message": "close", "started_at": "10"}}, "span": {{"name": "span1", "traceparent": "{traceparent}"}}}}"#
+            ),
+        ]);
+        assert_eq!(markers.len(), 3);
+        let trace_id = Some(0x4bf92f3577b34da6a3ce929d0e0e4736);
+        assert_eq!(markers[0].trace_context.map(|c| c.trace_id), trace_id);
+        assert!(!markers[0].extra_fields.contains_key("traceparent"));
+        assert_eq!(markers[1].trace_context, None);
+        assert_eq!(markers[1].extra_fields["traceparent"], "bogus");
+        assert_eq!(markers[2].trace_context.map(|c| c.trace_id), trace_id);
+        assert_eq!(
+            markers[2].trace_context.unwrap().trace_id_hex(),
+            "4bf92f3577b34da6a3ce929d0e0e4736"
+        );
+    }
+
+    #[test]
+    fn hex_span_ids_are_parsed() {
+        assert_eq!(parse_span_id("0x1a2b"), Some(0x1a2b));
+        assert_eq!(parse_span_id("6699"), Some(6699));
+        assert_eq!(parse_span_id("0xzz"), None);
+
+        let markers = parse_markers(&[
+            &span_line(1, "new", 10, None).replacen("1 ", "0x1a2b ", 1),
+            &event_line(15, 0).replace(r#""parent_id": "0""#, r#""parent_id": "0x1a2b""#),
+            &span_line(1, "close", 20, None).replacen("1 ", "0x1a2b,7 ", 1),
+        ]);
+        assert_eq!(markers.len(), 1);
+        let span = span(&markers[0]);
+        assert_eq!(span.id, 0x1a2b);
+        assert_eq!(span.end_time, Timestamp::from_nanos_since_reference(20));
+        assert_eq!(span.child_markers.len(), 1);
+    }
+
+    #[test]
+    fn truncated_last_line_is_skipped() {
+        let markers = parse_markers(&[&event_line(5, 1), r#"0 {"timestamp": "6", "tar"#]);
+        assert_eq!(markers.len(), 1);
+    }
+
+    fn running_line(id: u64, tid: Option<i32>, message: &str, timestamp: u64) -> String {
+        let ids = tid.map_or(id.to_string(), |tid| format!("{id},{tid}"));
+        format!(
+            r#"{ids} {{"timestamp": "{timestamp}", "target": "t", "fields": {{"message": "{message}"}}, "span": {{"name": "s"}}}}"#
+        )
+    }
+
+    fn running_spans(lines: &[String]) -> Vec<(Option<i32>, Timestamp, Timestamp, Option<String>)> {
+        let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+        parse_markers(&lines)
+            .into_iter()
+            .map(|marker| {
+                let end_time = span(&marker).end_time;
+                let migrated = marker.extra_fields.get("migrated").cloned();
+                (marker.tid, marker.start_time, end_time, migrated)
+            })
+            .collect()
+    }
+
+    fn ts(ns: u64) -> Timestamp {
+        Timestamp::from_nanos_since_reference(ns)
+    }
+
+    #[test]
+    fn running_span_on_one_thread_is_not_split() {
+        let spans = running_spans(&[
+            running_line(1, Some(10), "enter", 100),
+            running_line(1, Some(10), "exit", 200),
+        ]);
+        assert_eq!(spans, [(Some(10), ts(100), ts(200), None)]);
+    }
+
+    #[test]
+    fn migrated_running_span_is_split_at_the_midpoint() {
+        let spans = running_spans(&[
+            running_line(1, Some(10), "enter", 100),
+            running_line(1, Some(11), "exit", 200),
+        ]);
+        assert_eq!(
+            spans,
+            [
+                (Some(10), ts(100), ts(150), Some("out".to_string())),
+                (Some(11), ts(150), ts(200), Some("in".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    fn migrated_running_span_is_split_at_the_first_line_on_the_new_thread() {
+        let event = |tid: i32, timestamp: u64| {
+            format!(
+                r#"0,{tid} {{"parent_id": 1, "timestamp": "{timestamp}", "target": "t", "fields": {{"message": "event"}}}}"#
+            )
+        };
+        let lines = [
+            running_line(1, Some(10), "enter", 100),
+            event(10, 110),
+            event(11, 180),
+            event(11, 190),
+            running_line(1, Some(11), "exit", 200),
+        ];
+        let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+        let spans: Vec<_> = parse_markers(&lines)
+            .into_iter()
+            .filter(|marker| matches!(marker.marker_data, MarkerData::Span(_)))
+            .map(|marker| (marker.tid, marker.start_time, span(&marker).end_time))
+            .collect();
+        assert_eq!(
+            spans,
+            [(Some(10), ts(100), ts(180)), (Some(11), ts(180), ts(200))]
+        );
+    }
+
+    #[test]
+    fn running_span_without_tid_is_not_split() {
+        let spans = running_spans(&[
+            running_line(1, Some(10), "enter", 100),
+            running_line(1, None, "exit", 200),
+        ]);
+        assert_eq!(spans, [(None, ts(100), ts(200), None)]);
+    }
+
+    #[test]
+    fn unended_spans_are_counted_as_orphans() {
+        let contents = [
+            span_line(1, "new", 10, None),
+            span_line(2, "new", 20, None),
+            span_line(2, "close", 30, None),
+            // Span 3 is never closed, and span 1 is never entered again.
+            span_line(3, "new", 40, None),
+            running_line(1, Some(5), "enter", 50),
+            running_line(1, Some(5), "exit", 60),
+            running_line(4, Some(5), "enter", 70),
+        ]
+        .join("\n");
+        let mut marker_file =
+            MarkerFile::parse(contents.as_bytes(), Path::new("m"), identity_converter())
+                .with_orphan_warning_threshold(1);
+        assert_eq!(marker_file.by_ref().filter(Result::is_ok).count(), 2);
+        assert_eq!(marker_file.orphan_stats(), (2, 1));
+        assert_eq!(
+            marker_file
+                .processor
+                .new_close_tracker
+                .oldest_orphan_age_ns(70),
+            Some(60)
+        );
+        assert_eq!(
+            marker_file
+                .processor
+                .enter_exit_tracker
+                .oldest_orphan_age_ns(70),
+            Some(0)
+        );
+    }
+
+    #[test]
+    fn single_line_spans_mix_with_paired_spans() {
+        let contents = include_str!("../../../fixtures/other/markers/mixed-spans.txt");
+        let markers = parse_markers(&contents.lines().collect::<Vec<_>>());
+        assert_eq!(markers.len(), 2);
+
+        let scan = span(&markers[0]);
+        assert_eq!(markers[0].message, "scan");
+        assert_eq!(markers[0].start_time, ts(100));
+        assert_eq!(scan.end_time, ts(300));
+        assert_eq!(scan.profiler_label.as_deref(), Some("Scan-0001 Total"));
+        assert_eq!(scan.child_markers.len(), 2);
+
+        let lookup = &scan.child_markers[0];
+        assert_eq!(lookup.message, "lookup");
+        assert_eq!(lookup.start_time, ts(120));
+        let lookup = span(lookup);
+        assert_eq!(lookup.end_time, ts(150));
+        assert_eq!(lookup.category, "Read");
+        assert_eq!(lookup.timings.time_busy, Duration::from_nanos(20));
+
+        let flush = span(&markers[1]);
+        assert_eq!(markers[1].start_time, ts(350));
+        assert_eq!(flush.end_time, ts(400));
+        assert_eq!(flush.span_type, SpanType::Total);
+        assert_eq!(flush.timings.time_busy, Duration::from_nanos(50));
+    }
+
+    #[test]
+    fn raw_lines_keep_the_completing_source_line() {
+        let lines = [
+            span_line(1, "new", 10, None),
+            span_line(1, "close", 20, None),
+            event_line(30, 0),
+        ];
+        let contents = lines.join("\n");
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        write!(file, "{contents}").unwrap();
+        let markers = get_markers(
+            file.path(),
+            &[],
+            identity_converter(),
+            MarkerFileOptions {
+                raw_lines: true,
+                ..Default::default()
+            },
+        )
+        .unwrap()
+        .markers;
+        assert_eq!(markers.len(), 2);
+        assert_eq!(markers[0].extra_fields[RAW_LINE_FIELD], lines[1]);
+        assert_eq!(markers[1].extra_fields[RAW_LINE_FIELD], lines[2]);
+
+        let markers = get_markers(
+            file.path(),
+            &[],
+            identity_converter(),
+            MarkerFileOptions::default(),
+        )
+        .unwrap()
+        .markers;
+        assert!(markers
+            .iter()
+            .all(|marker| !marker.extra_fields.contains_key(RAW_LINE_FIELD)));
+    }
+
+    #[test]
+    fn report_counts_dropped_and_invalid_lines() {
+        let contents = [
+            span_line(1, "new", 10, None),
+            span_line(1, "close", 20, None),
+            // Closed without being created.
+            span_line(2, "close", 30, None),
+            // Exited without being entered.
+            running_line(3, None, "exit", 40),
+            event_line(50, 0),
+            "0 {not json".to_string(),
+            "0{\"timestamp\": \"60\"}".to_string(),
+        ]
+        .join("\n");
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        write!(file, "{contents}").unwrap();
+        let MarkerFileContents {
+            markers, report, ..
+        } = get_markers(
+            file.path(),
+            &[],
+            identity_converter(),
+            MarkerFileOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(markers.len(), 2);
+        assert_eq!(
+            report,
+            MarkerFileReport {
+                lines_read: 7,
+                lines_with_markers: 2,
+                dropped_keyword_mismatch: 2,
+                invalid_json: 2,
+                malformed: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn field_types_are_inferred_from_the_first_lines() {
+        let event = |timestamp: usize, fields: &str| {
+            format!(
+                r#"0 {{"timestamp": "{timestamp}", "target": "t", "fields": {{"message": "m", {fields}}}}}"#
+            )
+        };
+        let mut lines = vec![
+            event(
+                1,
+                r#""count": 1, "ratio": 1, "ok": true, "mixed": 3, "latency": "1.2ms""#,
+            ),
+            event(
+                2,
+                r#""count": -7, "ratio": 2.5, "ok": false, "mixed": "abc", "latency": "3µs""#,
+            ),
+        ];
+        for timestamp in 3..=TYPE_INFERENCE_LINE_COUNT {
+            lines.push(event(timestamp, r#""count": 2"#));
+        }
+        // Past the inference lines, values don't change the types anymore.
+        lines.push(event(101, r#""count": "many", "late": 5"#));
+
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        for line in &lines {
+            writeln!(file, "{line}").unwrap();
+        }
+        let markers = get_markers(
+            file.path(),
+            &[],
+            identity_converter(),
+            MarkerFileOptions {
+                type_inference: true,
+                ..Default::default()
+            },
+        )
+        .unwrap()
+        .markers;
+        let last = markers.last().unwrap();
+        assert_eq!(last.field_type("count"), FieldType::Integer);
+        assert_eq!(last.field_type("ratio"), FieldType::Float);
+        assert_eq!(last.field_type("ok"), FieldType::Bool);
+        assert_eq!(last.field_type("mixed"), FieldType::String);
+        assert_eq!(last.field_type("latency"), FieldType::Duration);
+        assert_eq!(last.field_type("late"), FieldType::String);
+        assert_eq!(markers[0].field_type("count"), FieldType::Integer);
+
+        let markers = get_markers(
+            file.path(),
+            &[],
+            identity_converter(),
+            MarkerFileOptions::default(),
+        )
+        .unwrap()
+        .markers;
+        assert!(markers[0].field_types.is_none());
+        assert_eq!(markers[0].field_type("count"), FieldType::String);
+    }
+
+    #[test]
+    fn foreign_marker_files_have_a_report_and_inferred_field_types() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(
+            file,
+            r#"{{"msg": "a", "level": "INFO", "ts": "2024-05-01T12:00:00Z", "port": 80}}"#
+        )
+        .unwrap();
+        write!(
+            file,
+            r#"{{"msg": "b", "level": "INFO", "ts": "2024-05-01T12:00:01Z", "port": 81}}"#
+        )
+        .unwrap();
+        let options = |type_inference, raw_lines, strict| MarkerFileOptions {
+            type_inference,
+            raw_lines,
+            strict,
+            ..Default::default()
+        };
+
+        let contents = get_markers(
+            file.path(),
+            &[],
+            identity_converter(),
+            options(true, false, false),
+        )
+        .unwrap();
+        assert_eq!(contents.report.lines_read, 2);
+        assert_eq!(contents.markers.len(), 2);
+        assert_eq!(contents.markers[1].field_type("port"), FieldType::Integer);
+
+        for options in [options(false, true, false), options(false, false, true)] {
+            let err = get_markers(file.path(), &[], identity_converter(), options).unwrap_err();
+            assert!(matches!(err, Error::Parse { line: None, .. }), "{err:?}");
+        }
+    }
+
+    #[test]
+    fn category_matches_span_category_and_event_target() {
+        let markers = parse_markers(&[
+            r#"1 {"timestamp": "1", "target": "db::open", "fields": {"message": "new"}, "span": {"name": "s", "action": "Compaction"}}"#,
+            r#"0 {"timestamp": "2", "target": "database::open", "fields": {"message": "e"}}"#,
+            r#"1 {"timestamp": "3", "target": "db::open", "fields": {"message": "close"}, "span": {"name": "s", "action": "Compaction"}}"#,
+        ]);
+        let span = markers.iter().find(|m| m.message == "s").unwrap();
+        assert!(span.category_matches("Compaction"));
+        assert!(!span.category_matches("Flush"));
+        // Without parent ids, the event is nested into the span by its time.
+        let MarkerData::Span(span) = &span.marker_data else {
+            panic!()
+        };
+        let event = &span.child_markers[0];
+        assert_eq!(event.message, "e");
+        assert!(event.category_matches("database"));
+        assert!(event.category_matches("database::open"));
+        assert!(!event.category_matches("db"));
+        assert!(!event.category_matches("Compaction"));
+    }
+
+    #[test]
+    fn event_level_is_parsed_and_stripped() {
+        let markers = parse_markers(&[
+            r#"0 {"timestamp": "1", "level": "ERROR", "target": "t", "fields": {"message": "a"}}"#,
+            r#"0 {"timestamp": "2", "target": "t", "fields": {"message": "b", "level": "warn"}}"#,
+            r#"0 {"timestamp": "3", "target": "t", "fields": {"message": "c", "level": "loud"}}"#,
+        ]);
+        let levels: Vec<_> = markers.iter().map(|m| m.level).collect();
+        assert_eq!(
+            levels,
+            [Some(EventLevel::Error), Some(EventLevel::Warn), None]
+        );
+        assert!(!markers[1].extra_fields.contains_key("level"));
+        // Unknown levels are kept as a regular field.
+        assert_eq!(markers[2].extra_fields["level"], "loud");
+    }
+
+    #[test]
+    fn severity_colors() {
+        let markers = parse_markers(&[
+            r#"0 {"timestamp": "1", "level": "ERROR", "target": "t", "fields": {"message": "a"}}"#,
+            r#"0 {"timestamp": "2", "level": "WARN", "target": "t", "fields": {"message": "b"}}"#,
+            r#"0 {"timestamp": "3", "level": "INFO", "target": "t", "fields": {"message": "c"}}"#,
+            r#"0 {"timestamp": "4", "level": "DEBUG", "target": "t", "fields": {"message": "d"}}"#,
+            r#"0 {"timestamp": "5", "level": "TRACE", "target": "t", "fields": {"message": "e"}}"#,
+            r#"0 {"timestamp": "6", "target": "t", "fields": {"message": "f", "level": "loud"}}"#,
+            r#"0 {"timestamp": "7", "target": "t", "fields": {"message": "g"}}"#,
+        ]);
+        let colors: Vec<_> = markers.iter().map(|m| m.severity_color()).collect();
+        assert_eq!(
+            colors,
+            [
+                CategoryColor::Red,
+                CategoryColor::Orange,
+                CategoryColor::Green,
+                CategoryColor::Blue,
+                CategoryColor::Gray,
+                CategoryColor::Gray,
+                CategoryColor::Green,
+            ]
+        );
+    }
+
+    #[test]
+    fn all_microsecond_spellings_parse() {
+        let json = r#"[{"busy": "12.5\u00b5s"}, {"busy": "12.5\u03bcs"}, {"busy": "12.5us"}]"#;
+        let fields: Vec<serde_json::Value> = serde_json::from_str(json).unwrap();
+        let fields = fields.into_iter().chain(
+            ["12.5\u{00b5}s", "12.5µs", "12.5μs"].map(|busy| serde_json::json!({ "busy": busy })),
+        );
+        for fields in fields {
+            assert_eq!(
+                parse_timing_field(&fields, "busy"),
+                Ok(Some(Duration::from_nanos(12_500))),
+                "{fields}"
+            );
+        }
+    }
+
+    #[test]
+    fn malformed_timing_is_parse_error() {
+        for busy in ["-1s", "1e30s", "NaNs", "1²s"] {
+            let fields = serde_json::json!({ "time.busy": busy });
+            assert!(parse_timing_field(&fields, "time.busy").is_err(), "{busy}");
+        }
+    }
+
+    #[test]
+    fn long_collection_id_is_truncated_on_a_char_boundary() {
+        let markers = parse_markers(&[
+            r#"1 {"timestamp": "10", "target": "t", "fields": {"message": "new"}, "span": {"name": "s", "action": "a/c-ééééééééé"}}"#,
+            r#"1 {"timestamp": "20", "target": "t", "fields": {"message": "close"}, "span": {"name": "s", "action": "a/c-ééééééééé"}}"#,
+        ]);
+        assert_eq!(
+            span(&markers[0]).profiler_label.as_deref(),
+            Some("c-éééééééé Total")
+        );
+    }
+
+    #[test]
+    fn action_without_collection_id_becomes_the_category() {
+        let markers = parse_markers(&[
+            r#"1 {"timestamp": "10", "target": "t", "fields": {"message": "new"}, "span": {"name": "s", "action": "atom/nodash"}}"#,
+            r#"1 {"timestamp": "20", "target": "t", "fields": {"message": "close"}, "span": {"name": "s", "action": "atom/nodash"}}"#,
+        ]);
+        assert_eq!(markers.len(), 1);
+        let span = span(&markers[0]);
+        assert_eq!(span.category, "atom/nodash");
+        assert_eq!(span.profiler_label, None);
+    }
+
+    #[test]
+    fn reset_returns_the_stats_and_starts_over() {
+        let markers = parse_markers(&[
+            r#"1 {"timestamp": "10", "target": "t", "fields": {"message": "new"}, "span": {"name": "s", "action": "a/c-1"}}"#,
+            r#"1 {"timestamp": "20", "target": "t", "fields": {"message": "close"}, "span": {"name": "s", "action": "a/c-1"}}"#,
+        ]);
+        let mut stats = MarkerStats::new();
+        stats.process_span(&markers[0]);
+        assert!(!stats.is_empty());
+        let old = stats.reset();
+        assert!(stats.is_empty());
+        assert!(!old.is_empty());
+        assert_eq!(old.per_collection_map.len(), 1);
+    }
+
+    #[test]
+    fn marker_file_path() {
+        let info = parse_marker_file_path(Path::new("/tmp/marker-12-34.txt")).unwrap();
+        assert_eq!(
+            (info.prefix.as_str(), info.pid, info.tid),
+            ("marker", 12, Some(34))
+        );
+        let info = parse_marker_file_path(Path::new("marker-12.txt")).unwrap();
+        assert_eq!((info.pid, info.tid), (12, None));
+        for path in [
+            "marker-12",
+            "marker.txt",
+            "marker-x.txt",
+            "marker-1-x.txt",
+            ".txt",
+            "/",
+        ] {
+            assert!(parse_marker_file_path(Path::new(path)).is_none(), "{path}");
+        }
+    }
+
+    /// Lines which are close enough to real marker lines to get past the
+    /// line splitting and span tracking, and into the field parsing.
+    #[test]
+    fn atrace_file_has_spans_and_counters() {
+        let lines = [
+            "# tracer: nop",
+            "#",
+            "#           TASK-PID     CPU#  ||||    TIMESTAMP  FUNCTION",
+            "#              | |         |   ||||       |         |",
+            "  surfaceflinger-612   [003] ...1  12.000001: tracing_mark_write: B|612|onMessageReceived",
+            "  surfaceflinger-612   [003] ...1  12.000002: tracing_mark_write: C|612|BufferQueue|3",
+            "  surfaceflinger-612   [003] ...1  12.000005: tracing_mark_write: E|612",
+            "          <idle>-0     [001] d..2  12.000006: sched_switch: prev_comm=swapper",
+        ];
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        for line in lines {
+            writeln!(file, "{line}").unwrap();
+        }
+        let MarkerFileContents {
+            markers,
+            report,
+            counters,
+            ..
+        } = get_markers(
+            file.path(),
+            &[],
+            identity_converter(),
+            MarkerFileOptions::default(),
+        )
+        .unwrap();
+
+        assert_eq!(markers.len(), 1);
+        assert_eq!(markers[0].message, "onMessageReceived");
+        assert_eq!(markers[0].tid, Some(612));
+        assert_eq!(markers[0].start_time, ts(12_000_001_000));
+        let draw = span(&markers[0]);
+        assert_eq!(draw.end_time, ts(12_000_005_000));
+        assert_eq!(draw.timings.time_busy, Duration::from_micros(4));
+
+        assert_eq!(counters.len(), 1);
+        assert_eq!(counters[0].name, "BufferQueue");
+        assert_eq!(counters[0].samples.len(), 1);
+        assert_eq!(counters[0].samples[0].timestamp, ts(12_000_002_000));
+        assert_eq!(counters[0].samples[0].value, 3.0);
+
+        assert_eq!(report.lines_read, lines.len());
+        assert_eq!(report.lines_with_markers, 2);
+    }
+
+    fn auto_detect(text: &str) -> Vec<EventOrSpanMarker> {
+        MarkerFile::auto_detect(Cursor::new(text.to_string()), identity_converter())
+            .unwrap()
+            .collect()
+    }
+
+    #[test]
+    fn marker_file_formats_are_detected_from_the_first_line() {
+        let detect = MarkerFileFormat::detect;
+        assert_eq!(
+            detect(r#"1,7 {"timestamp": "5", "target": "t", "fields": {}}"#),
+            Some(MarkerFileFormat::Samply)
+        );
+        assert_eq!(
+            detect(r#"{"timestamp": "5", "target": "t", "fields": {}}"#),
+            Some(MarkerFileFormat::Samply)
+        );
+        assert_eq!(detect("["), Some(MarkerFileFormat::ChromeTrace));
+        assert_eq!(
+            detect(r#"[{"name": "a", "ph": "X", "ts": 1, "dur": 2},"#),
+            Some(MarkerFileFormat::ChromeTrace)
+        );
+        assert_eq!(
+            detect(r#"{"traceEvents": [], "displayTimeUnit": "ms"}"#),
+            Some(MarkerFileFormat::ChromeTrace)
+        );
+        assert_eq!(
+            detect(r#"{"resourceSpans": []}"#),
+            Some(MarkerFileFormat::Otlp)
+        );
+        assert_eq!(
+            detect(r#"{"msg": "hi", "level": "INFO", "ts": "2024-05-01T12:00:00Z"}"#),
+            Some(MarkerFileFormat::Slog)
+        );
+        assert_eq!(
+            detect("[2024-05-01T12:00:00Z INFO  my_crate] hi"),
+            Some(MarkerFileFormat::EnvLogger)
+        );
+        assert_eq!(detect(""), None);
+        assert_eq!(detect("hello world"), None);
+        assert_eq!(detect("[not a log line] hi"), None);
+    }
+
+    #[test]
+    fn auto_detect_rejects_unrecognized_formats() {
+        let result = MarkerFile::auto_detect(Cursor::new("hello world\n"), identity_converter());
+        assert!(matches!(
+            result,
+            Err(FormatError::Unrecognized(line)) if line == "hello world"
+        ));
+    }
+
+    #[test]
+    fn auto_detect_reads_samply_lines() {
+        let markers = auto_detect(&format!(
+            "{}\ngarbage\n{}\n",
+            span_line(1, "new", 5, None),
+            span_line(1, "close", 9, None)
+        ));
+        assert_eq!(markers.len(), 1);
+        assert_eq!(markers[0].message, "span1");
+        assert_eq!(markers[0].start_time, ts(5));
+        assert_eq!(span(&markers[0]).end_time, ts(9));
+    }
+
+    #[test]
+    fn auto_detect_reads_chrome_traces() {
+        let markers = auto_detect(
+            r#"[
+{"name": "outer", "cat": "io", "ph": "B", "ts": 1, "pid": 1, "tid": 2},
+{"name": "inner", "ph": "X", "ts": 2, "dur": 1.5, "pid": 1, "tid": 2, "args": {"n": 3}},
+{"name": "outer", "ph": "E", "ts": 5, "pid": 1, "tid": 2},
+{"name": "mark", "ph": "i", "ts": 6, "pid": 1, "tid": 3},
+{"name": "meta", "ph": "M", "ts": 0, "pid": 1, "tid": 2}
+]"#,
+        );
+        assert_eq!(markers.len(), 3);
+
+        assert_eq!(markers[0].message, "inner");
+        assert_eq!(markers[0].target, "chrome");
+        assert_eq!(markers[0].tid, Some(2));
+        assert_eq!(markers[0].extra_fields["n"], "3");
+        assert_eq!(markers[0].start_time, ts(2_000));
+        assert_eq!(span(&markers[0]).end_time, ts(3_500));
+
+        assert_eq!(markers[1].message, "outer");
+        assert_eq!(markers[1].target, "io");
+        assert_eq!(markers[1].start_time, ts(1_000));
+        let outer = span(&markers[1]);
+        assert_eq!(outer.end_time, ts(5_000));
+        assert_eq!(outer.category, "io");
+        assert_eq!(outer.timings.time_busy, Duration::from_micros(4));
+        assert_ne!(outer.id, span(&markers[0]).id);
+
+        assert_eq!(markers[2].message, "mark");
+        assert_eq!(markers[2].tid, Some(3));
+        assert!(matches!(markers[2].marker_data, MarkerData::Event));
+    }
+
+    #[test]
+    fn get_markers_reads_chrome_traces() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        write!(
+            file,
+            r#"[
+{{"name": "outer", "ph": "B", "ts": 1, "pid": 1, "tid": 2}},
+{{"name": "inner", "ph": "X", "ts": 2, "dur": 1.5, "pid": 1, "tid": 2}},
+{{"name": "outer", "ph": "E", "ts": 5, "pid": 1, "tid": 2}},
+{{"name": "mark", "ph": "i", "ts": 6, "pid": 1, "tid": 3}}
+]"#
+        )
+        .unwrap();
+        let MarkerFileContents {
+            markers, report, ..
+        } = get_markers(
+            file.path(),
+            &[],
+            identity_converter(),
+            MarkerFileOptions::default(),
+        )
+        .unwrap();
+
+        // The markers are nested by their time, like those of samply marker
+        // files without parent ids.
+        let messages: Vec<_> = markers.iter().map(|m| m.message.as_str()).collect();
+        assert_eq!(messages, ["outer", "mark"]);
+        let children = &span(&markers[0]).child_markers;
+        assert_eq!(children.len(), 1);
+        assert_eq!(children[0].message, "inner");
+        assert_eq!(
+            report,
+            MarkerFileReport {
+                lines_read: 6,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn auto_detect_reads_otlp_spans() {
+        let markers = auto_detect(
+            r#"{"resourceSpans": [{"scopeSpans": [{"scope": {"name": "http"}, "spans": [{"traceId": "5b8efff798038103d269b633813fc60c", "spanId": "eee19b7ec3c1b174", "name": "GET /", "startTimeUnixNano": "1000", "endTimeUnixNano": "4000", "attributes": [{"key": "http.method", "value": {"stringValue": "GET"}}, {"key": "http.status", "value": {"intValue": "200"}}]}]}]}]}"#,
+        );
+        assert_eq!(markers.len(), 1);
+        assert_eq!(markers[0].message, "GET /");
+        assert_eq!(markers[0].target, "http");
+        assert_eq!(markers[0].start_time, ts(1_000));
+        assert_eq!(markers[0].extra_fields["http.method"], "GET");
+        assert_eq!(markers[0].extra_fields["http.status"], "200");
+        assert_eq!(
+            markers[0].extra_fields[TRACE_ID_FIELD],
+            "5b8efff798038103d269b633813fc60c"
+        );
+        let span = span(&markers[0]);
+        assert_eq!(span.id, 1);
+        assert_eq!(span.end_time, ts(4_000));
+        assert_eq!(span.category, "http");
+    }
+
+    #[test]
+    fn otlp_span_ids_are_unique_per_trace_and_span() {
+        let request = |spans: &str| {
+            format!(r#"{{"resourceSpans": [{{"scopeSpans": [{{"spans": [{spans}]}}]}}]}}"#)
+        };
+        let markers = auto_detect(&[
+            request(concat!(
+                r#"{"traceId": "a1", "spanId": "01", "name": "a", "startTimeUnixNano": "1", "endTimeUnixNano": "2"}, "#,
+                r#"{"name": "no id", "startTimeUnixNano": "3", "endTimeUnixNano": "4"}, "#,
+                r#"{"traceId": "b2", "spanId": "01", "name": "b", "startTimeUnixNano": "5", "endTimeUnixNano": "6"}"#,
+            )),
+            request(concat!(
+                r#"{"spanId": "zz", "name": "non-hex", "startTimeUnixNano": "7", "endTimeUnixNano": "8"}, "#,
+                r#"{"name": "no id", "startTimeUnixNano": "9", "endTimeUnixNano": "10"}"#,
+            )),
+        ]
+        .join("\n"));
+        let ids: Vec<u64> = markers.iter().map(|marker| span(marker).id).collect();
+        assert_eq!(ids, [1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn auto_detect_reads_slog_records() {
+        let markers = auto_detect(concat!(
+            r#"{"msg": "started", "level": "INFO", "ts": "2024-05-01T12:00:00.5Z", "module": "app::server", "port": 80}"#,
+            "\n",
+            r#"{"msg": "slow", "level": "WARN", "ts": "2024-05-01T14:00:01+02:00"}"#,
+            "\n",
+        ));
+        assert_eq!(markers.len(), 2);
+        assert_eq!(markers[0].message, "started");
+        assert_eq!(markers[0].target, "app::server");
+        assert_eq!(markers[0].level, Some(EventLevel::Info));
+        assert_eq!(markers[0].extra_fields["port"], "80");
+        assert_eq!(markers[0].start_time, ts(1_714_564_800_500_000_000));
+        assert_eq!(markers[1].target, "slog");
+        assert_eq!(markers[1].level, Some(EventLevel::Warn));
+        assert_eq!(markers[1].start_time, ts(1_714_564_801_000_000_000));
+    }
+
+    #[test]
+    fn auto_detect_reads_env_logger_lines() {
+        let markers = auto_detect(
+            "[2024-05-01T12:00:00Z INFO  my_crate::module] first\n\
+             a continuation line\n\
+             [2024-05-01T12:00:02.25Z ERROR my_crate] second\n",
+        );
+        assert_eq!(markers.len(), 2);
+        assert_eq!(markers[0].message, "first");
+        assert_eq!(markers[0].target, "my_crate::module");
+        assert_eq!(markers[0].level, Some(EventLevel::Info));
+        assert_eq!(markers[0].start_time, ts(1_714_564_800_000_000_000));
+        assert_eq!(markers[1].message, "second");
+        assert_eq!(markers[1].level, Some(EventLevel::Error));
+        assert_eq!(markers[1].start_time, ts(1_714_564_802_250_000_000));
+    }
+
+    fn marker_line() -> impl Strategy<Value = String> {
+        (
+            0u64..4,
+            proptest::option::of(any::<i32>()),
+            prop::sample::select(vec!["new", "close", "enter", "exit", "event"]),
+            prop_oneof!["[0-9]{1,20}", "\\PC{0,8}"],
+            "\\PC{0,24}",
+            prop_oneof!["-?[0-9.e]{0,6}(s|ms|µs|us|ns)?", "\\PC{0,8}"],
+            prop_oneof![
+                Just(serde_json::Value::Null),
+                any::<u64>().prop_map(Into::into)
+            ],
+        )
+            .prop_map(|(id, tid, message, timestamp, action, busy, parent_id)| {
+                let ids = match tid {
+                    Some(tid) => format!("{id},{tid}"),
+                    None => id.to_string(),
+                };
+                let json = serde_json::json!({
+                    "parent_id": parent_id,
+                    "timestamp": timestamp,
+                    "target": "t",
+                    "fields": { "message": message, "time.busy": busy },
+                    "span": { "name": "s", "action": action },
+                });
+                format!("{ids} {json}")
+            })
+    }
+
+    proptest! {
+        #[test]
+        fn arbitrary_lines_never_panic(lines in prop::collection::vec("\\PC*", 0..8)) {
+            let mut processor = MarkerLineProcessor::new(identity_converter());
+            for line in &lines {
+                let _ = processor.process_line(line);
+            }
+        }
+
+        #[test]
+        fn marker_like_files_never_panic(lines in prop::collection::vec(marker_line(), 0..16)) {
+            let contents = lines.join("\n");
+            let marker_file = MarkerFile::parse(contents.as_bytes(), Path::new("fuzz"), identity_converter());
+            if let Ok(markers) = marker_file.collect::<Result<Vec<_>, _>>() {
+                build_marker_tree(markers);
+            }
+        }
+
+        #[test]
+        fn arbitrary_timing_field_never_panics(
+            value in prop_oneof!["-?[0-9.e]{0,6}(s|ms|µs|us|ns)?", "\\PC*"],
+        ) {
+            let fields = serde_json::json!({ "time.busy": value });
+            let _ = parse_timing_field(&fields, "time.busy");
+        }
+
+        #[test]
+        fn arbitrary_marker_file_path_never_panics(path in "\\PC*") {
+            let _ = parse_marker_file_path(Path::new(&path));
+        }
+    }
 }
@@ -0,0 +1,224 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::mem::size_of;
+use std::time::{Duration, Instant};
+
+/// How often the recording loop takes a `MemoryReport`.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// An approximation of the heap memory which a buffer holds on to, for
+/// finding out which of samply's buffers grew when a recording uses a lot of
+/// memory. The estimates go by capacities rather than lengths, because the
+/// capacity is what's allocated.
+pub trait MemoryUsage {
+    /// The approximate number of heap bytes owned by `self`.
+    fn heap_size(&self) -> usize;
+}
+
+pub fn vec_heap_size<T>(vec: &Vec<T>) -> usize {
+    vec.capacity() * size_of::<T>()
+}
+
+pub fn vec_deque_heap_size<T>(deque: &VecDeque<T>) -> usize {
+    deque.capacity() * size_of::<T>()
+}
+
+/// The size of a hash map's table: a power of two of buckets with one
+/// control byte each, kept at most 7/8 full. The heap data of the keys and
+/// values isn't included.
+pub fn hash_map_heap_size<K, V, S>(map: &HashMap<K, V, S>) -> usize {
+    if map.capacity() == 0 {
+        return 0;
+    }
+    let buckets = (map.capacity() * 8 / 7).next_power_of_two();
+    buckets * (size_of::<(K, V)>() + 1)
+}
+
+impl MemoryUsage for String {
+    fn heap_size(&self) -> usize {
+        self.capacity()
+    }
+}
+
+/// The approximate heap bytes of each kind of buffer, summed up over all
+/// processes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryReport {
+    bytes_by_buffer: BTreeMap<&'static str, usize>,
+}
+
+impl MemoryReport {
+    pub fn add(&mut self, buffer: &'static str, bytes: usize) {
+        *self.bytes_by_buffer.entry(buffer).or_default() += bytes;
+    }
+
+    pub fn total(&self) -> usize {
+        self.bytes_by_buffer.values().sum()
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        self.bytes_by_buffer
+            .iter()
+            .map(|(buffer, bytes)| (buffer.to_string(), (*bytes).into()))
+            .collect::<serde_json::Map<_, _>>()
+            .into()
+    }
+}
+
+impl fmt::Display for MemoryReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Memory usage of samply's buffers:")?;
+        for (buffer, bytes) in &self.bytes_by_buffer {
+            writeln!(f, "  {buffer:<24} {:>10.1} MB", *bytes as f64 / 1_000_000.0)?;
+        }
+        write!(
+            f,
+            "  {:<24} {:>10.1} MB",
+            "Total",
+            self.total() as f64 / 1_000_000.0
+        )
+    }
+}
+
+/// Keeps the latest `MemoryReport` of a recording, and the one with the
+/// highest total.
+#[derive(Debug)]
+pub struct MemoryUsageMonitor {
+    last_poll: Option<Instant>,
+    latest: MemoryReport,
+    peak: MemoryReport,
+}
+
+impl MemoryUsageMonitor {
+    pub fn new() -> Self {
+        Self {
+            last_poll: None,
+            latest: MemoryReport::default(),
+            peak: MemoryReport::default(),
+        }
+    }
+
+    /// Whether the next report is due.
+    pub fn is_due(&self) -> bool {
+        match self.last_poll {
+            Some(last_poll) => last_poll.elapsed() >= POLL_INTERVAL,
+            None => true,
+        }
+    }
+
+    pub fn record(&mut self, report: MemoryReport) {
+        self.last_poll = Some(Instant::now());
+        if report.total() >= self.peak.total() {
+            self.peak = report.clone();
+        }
+        self.latest = report;
+    }
+
+    pub fn latest(&self) -> &MemoryReport {
+        &self.latest
+    }
+
+    pub fn peak(&self) -> &MemoryReport {
+        &self.peak
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use fxprof_processed_profile::{CpuDelta, Timestamp};
+
+    use super::*;
+    use crate::shared::lib_mappings::{
+        LibMappingAdd, LibMappingInfo, LibMappingOp, LibMappingOpQueue,
+    };
+    use crate::shared::marker_file::MarkerLineProcessor;
+    use crate::shared::test_support::identity_converter;
+    use crate::shared::test_support::{add_process_with_threads, add_synthetic_lib, new_profile};
+    use crate::shared::types::{StackFrame, StackMode};
+    use crate::shared::unresolved_samples::{
+        UnresolvedSamples, UnresolvedStackHandle, UnresolvedStacks,
+    };
+
+    /// Calls `add` until the estimate grows, which may take a few calls when
+    /// there's spare capacity, and returns the estimates before and after.
+    fn grows<T: MemoryUsage>(value: &mut T, mut add: impl FnMut(&mut T, usize)) -> (usize, usize) {
+        let before = value.heap_size();
+        for i in 0..1000 {
+            add(value, i);
+            if value.heap_size() != before {
+                break;
+            }
+        }
+        (before, value.heap_size())
+    }
+
+    #[test]
+    fn estimates_grow_with_their_buffers() {
+        let mut profile = new_profile();
+        let (_process, threads) = add_process_with_threads(&mut profile, 1);
+        let lib = add_synthetic_lib(&mut profile, 0);
+
+        let (before, after) = grows(&mut UnresolvedStacks::default(), |stacks, i| {
+            stacks.convert([StackFrame::InstructionPointer(i as u64, StackMode::User)].into_iter());
+        });
+        assert!(before < after, "{before} {after}");
+
+        let (before, after) = grows(&mut UnresolvedSamples::default(), |samples, i| {
+            samples.add_sample(
+                threads[0],
+                Timestamp::from_nanos_since_reference(i as u64),
+                i as u64,
+                UnresolvedStackHandle::EMPTY,
+                CpuDelta::ZERO,
+                1,
+                None,
+            );
+        });
+        assert!(before < after, "{before} {after}");
+
+        let (before, after) = grows(&mut LibMappingOpQueue::default(), |ops, i| {
+            ops.push(
+                i as u64,
+                LibMappingOp::Add(LibMappingAdd {
+                    start_avma: 0,
+                    end_avma: 1,
+                    relative_address_at_start: 0,
+                    info: LibMappingInfo::new_lib(lib),
+                }),
+            );
+        });
+        assert!(before < after, "{before} {after}");
+
+        let mut processor = MarkerLineProcessor::new(identity_converter());
+        let (before, after) = grows(&mut processor, |processor, i| {
+            processor
+                .process_line(&format!(
+                    r#"0 {{"timestamp":"{i}","target":"app","fields":{{"message":"event {i}"}}}}"#
+                ))
+                .unwrap();
+        });
+        assert!(before < after, "{before} {after}");
+    }
+
+    #[test]
+    fn the_monitor_keeps_the_peak() {
+        let mut monitor = MemoryUsageMonitor::new();
+        assert!(monitor.is_due());
+        let report = |samples, stacks| {
+            let mut report = MemoryReport::default();
+            report.add("Unresolved samples", samples);
+            report.add("Unresolved stacks", stacks);
+            report
+        };
+        monitor.record(report(100, 50));
+        assert!(!monitor.is_due());
+        monitor.record(report(300, 50));
+        monitor.record(report(0, 60));
+        assert_eq!(monitor.latest().total(), 60);
+        assert_eq!(monitor.peak(), &report(300, 50));
+        assert_eq!(
+            monitor.peak().to_json(),
+            serde_json::json!({"Unresolved samples": 300, "Unresolved stacks": 50})
+        );
+    }
+}
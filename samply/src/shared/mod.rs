@@ -1,26 +1,43 @@
+pub mod address_lookup;
 pub mod context_switch;
+#[cfg(test)]
+mod conversion_benchmarks;
 pub mod counter_file;
 pub mod ctrl_c;
+pub mod custom_markers;
+pub mod error;
+pub mod file_inspection;
 pub mod included_processes;
 pub mod jit_category_manager;
 pub mod jit_function_add_marker;
 pub mod jit_function_recycler;
 pub mod jitdump_manager;
 pub mod lib_mappings;
+pub mod log_markers;
 pub mod marker_file;
+pub mod memory_usage;
 pub mod per_cpu;
 pub mod perf_map;
 pub mod process_name;
 pub mod process_sample_data;
+pub mod profile_merge;
 pub mod recording_props;
 pub mod recycling;
+pub mod sample_spill;
 pub mod save_profile;
+pub mod span_interval_index;
+pub mod span_recording_gate;
+pub mod spill_recovery;
 pub mod stack_converter;
 pub mod stack_depth_limiting_frame_iter;
 pub mod symbol_manager_observer;
+pub mod symbol_overrides;
 pub mod symbol_precog;
 pub mod symbol_props;
 pub mod synthetic_jit_library;
+#[cfg(test)]
+pub mod test_support;
+pub mod time_report;
 pub mod timestamp_converter;
 pub mod types;
 pub mod unresolved_samples;
@@ -1,11 +1,243 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
 use fxprof_processed_profile::{
-    CategoryHandle, Frame, FrameFlags, FrameInfo, MarkerFieldFormat, MarkerFieldSchema,
-    MarkerLocation, MarkerSchema, MarkerTiming, ProcessHandle, Profile, StaticSchemaMarker,
-    StringHandle, ThreadHandle, Timestamp,
+    CategoryHandle, CounterHandle, Frame, FrameFlags, FrameInfo, MarkerFieldFormat,
+    MarkerFieldSchema, MarkerLocation, MarkerSchema, MarkerStaticField, MarkerTiming,
+    ProcessHandle, Profile, StaticSchemaMarker, StringHandle, SymbolTable, ThreadHandle, Timestamp,
 };
 
 use crate::shared::context_switch::ThreadContextSwitchData;
+use crate::shared::counter_file::CounterCategory;
+use crate::shared::recording_props::SyntheticTrackNaming;
 use crate::shared::timestamp_converter::TimestampConverter;
+use crate::shared::types::{StackFrame, StackMode};
+
+/// The physical location of a logical CPU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct CpuCoreId {
+    pub socket: u32,
+    pub physical_core: u32,
+}
+
+/// Maps logical CPUs to the physical cores they run on, so that hyper-thread
+/// siblings can be told apart from independent cores.
+#[derive(Debug, Clone, Default)]
+pub struct CpuTopology {
+    cores: BTreeMap<u32, CpuCoreId>,
+}
+
+impl CpuTopology {
+    /// Read the topology of the running machine from `/sys/devices/system/cpu`.
+    #[allow(dead_code)]
+    pub fn from_sysfs() -> Result<Self, io::Error> {
+        Self::from_sysfs_dir(Path::new("/sys/devices/system/cpu"))
+    }
+
+    /// Read the topology from `<dir>/cpu*/topology/{core_id,physical_package_id}`.
+    pub fn from_sysfs_dir(dir: &Path) -> Result<Self, io::Error> {
+        let read_u32 = |path: &Path| -> Result<u32, io::Error> {
+            std::fs::read_to_string(path)?
+                .trim()
+                .parse()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        };
+
+        let mut cores = BTreeMap::new();
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let file_name = entry.file_name();
+            let Some(cpu) = file_name
+                .to_str()
+                .and_then(|name| name.strip_prefix("cpu"))
+                .and_then(|index| index.parse::<u32>().ok())
+            else {
+                // Skip cpufreq, cpuidle etc.
+                continue;
+            };
+            let topology_dir = entry.path().join("topology");
+            if !topology_dir.exists() {
+                // Offline CPUs don't have a topology directory.
+                continue;
+            }
+            let physical_core = read_u32(&topology_dir.join("core_id"))?;
+            let socket = read_u32(&topology_dir.join("physical_package_id"))?;
+            cores.insert(
+                cpu,
+                CpuCoreId {
+                    socket,
+                    physical_core,
+                },
+            );
+        }
+        Ok(Self { cores })
+    }
+
+    pub fn core_id(&self, cpu: u32) -> Option<CpuCoreId> {
+        self.cores.get(&cpu).copied()
+    }
+
+    /// All logical CPUs on the same physical core as `cpu`, including `cpu` itself, in order.
+    pub fn siblings(&self, cpu: u32) -> Vec<u32> {
+        let Some(core_id) = self.core_id(cpu) else {
+            return vec![cpu];
+        };
+        self.cores
+            .iter()
+            .filter(|(_, other)| **other == core_id)
+            .map(|(cpu, _)| *cpu)
+            .collect()
+    }
+
+    fn has_multiple_sockets(&self) -> bool {
+        let mut sockets = self.cores.values().map(|core_id| core_id.socket);
+        let first = sockets.next();
+        sockets.any(|socket| Some(socket) != first)
+    }
+
+    /// The track name for `cpu`, e.g. `"CPU 2/3 (Core 1)"` if CPUs 2 and 3
+    /// are hyper-thread siblings on physical core 1.
+    /// The name of the track of `cpu`: after its core if it's known, and
+    /// from `naming`'s CPU format otherwise.
+    pub fn track_name(&self, cpu: u32, naming: &SyntheticTrackNaming) -> String {
+        let Some(core_id) = self.core_id(cpu) else {
+            return naming.cpu_track_name(cpu);
+        };
+        let siblings: Vec<String> = self.siblings(cpu).iter().map(u32::to_string).collect();
+        let siblings = siblings.join("/");
+        let name = if self.has_multiple_sockets() {
+            format!(
+                "CPU {siblings} (Socket {}, Core {})",
+                core_id.socket, core_id.physical_core
+            )
+        } else {
+            format!("CPU {siblings} (Core {})", core_id.physical_core)
+        };
+        naming.prefixed(&name)
+    }
+}
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+/// A contiguous stretch of time during which one thread ran on a CPU.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CpuRun {
+    pub pid: i32,
+    pub tid: i32,
+    pub thread_name: StringHandle,
+    pub start: u64,
+    pub end: u64,
+}
+
+/// The runs on a CPU which were shorter than the threshold and which started
+/// in the same second, combined into one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShortCpuRuns {
+    /// The start of the first of the runs.
+    pub start: u64,
+    /// The end of the last of the runs.
+    pub end: u64,
+    pub count: u32,
+    pub total_duration: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CpuRunOutput {
+    Run(CpuRun),
+    ShortRuns(ShortCpuRuns),
+}
+
+/// Pairs the switch-ins on a CPU with the following switch-outs, to get the
+/// runs of threads on that CPU. Timestamps are raw (unconverted) nanoseconds.
+#[derive(Debug, Clone)]
+pub struct CpuRunTracker {
+    /// Runs shorter than this are combined into `ShortCpuRuns`.
+    min_run_duration: u64,
+    /// The run which is in progress, with `end` not set yet.
+    current_run: Option<CpuRun>,
+    short_runs: Option<ShortCpuRuns>,
+}
+
+impl CpuRunTracker {
+    pub fn new(min_run_duration: u64) -> Self {
+        Self {
+            min_run_duration,
+            current_run: None,
+            short_runs: None,
+        }
+    }
+
+    pub fn switch_in(
+        &mut self,
+        pid: i32,
+        tid: i32,
+        thread_name: StringHandle,
+        timestamp: u64,
+        emit: &mut impl FnMut(CpuRunOutput),
+    ) {
+        if let Some(run) = self.current_run.take() {
+            // We missed the switch-out of the previous thread. It must have
+            // stopped running by now.
+            self.end_run(run, timestamp, emit);
+        }
+        self.current_run = Some(CpuRun {
+            pid,
+            tid,
+            thread_name,
+            start: timestamp,
+            end: timestamp,
+        });
+    }
+
+    /// `tid` is the thread which is switched out. If it's not the thread we
+    /// saw switching in, we missed a switch-out and a switch-in, and the best
+    /// we can do is to end the current run here.
+    pub fn switch_out(&mut self, _tid: i32, timestamp: u64, emit: &mut impl FnMut(CpuRunOutput)) {
+        if let Some(run) = self.current_run.take() {
+            self.end_run(run, timestamp, emit);
+        }
+    }
+
+    /// Emits the pending short runs. A run which is still in progress is
+    /// discarded, because its end is unknown.
+    pub fn finish(&mut self, emit: &mut impl FnMut(CpuRunOutput)) {
+        self.current_run = None;
+        if let Some(short_runs) = self.short_runs.take() {
+            emit(CpuRunOutput::ShortRuns(short_runs));
+        }
+    }
+
+    fn end_run(&mut self, mut run: CpuRun, end: u64, emit: &mut impl FnMut(CpuRunOutput)) {
+        run.end = end.max(run.start);
+        let duration = run.end - run.start;
+        if duration >= self.min_run_duration {
+            emit(CpuRunOutput::Run(run));
+            return;
+        }
+
+        let second = run.start / NANOS_PER_SECOND;
+        match &mut self.short_runs {
+            Some(short_runs) if short_runs.start / NANOS_PER_SECOND == second => {
+                short_runs.end = short_runs.end.max(run.end);
+                short_runs.count += 1;
+                short_runs.total_duration += duration;
+            }
+            _ => {
+                let previous = self.short_runs.replace(ShortCpuRuns {
+                    start: run.start,
+                    end: run.end,
+                    count: 1,
+                    total_duration: duration,
+                });
+                if let Some(previous) = previous {
+                    emit(CpuRunOutput::ShortRuns(previous));
+                }
+            }
+        }
+    }
+}
 
 pub struct Cpus {
     start_time: Timestamp,
@@ -13,6 +245,9 @@ pub struct Cpus {
     combined_thread_handle: ThreadHandle,
     cpus: Vec<Cpu>,
     idle_frame_label: FrameInfo,
+    topology: Option<CpuTopology>,
+    track_naming: SyntheticTrackNaming,
+    min_run_duration: u64,
 }
 
 pub struct Cpu {
@@ -20,18 +255,52 @@ pub struct Cpu {
     pub thread_handle: ThreadHandle,
     pub context_switch_data: ThreadContextSwitchData,
     current_tid: Option<(i32, StringHandle, u64)>,
+    run_tracker: CpuRunTracker,
 }
 
 impl Cpu {
-    pub fn new(name: StringHandle, thread_handle: ThreadHandle) -> Self {
+    pub fn new(name: StringHandle, thread_handle: ThreadHandle, min_run_duration: u64) -> Self {
         Self {
             name,
             thread_handle,
             context_switch_data: Default::default(),
             current_tid: None,
+            run_tracker: CpuRunTracker::new(min_run_duration),
         }
     }
 
+    /// Like `notify_switch_in_for_marker`, but for `CpuRunMarker`s.
+    #[allow(clippy::too_many_arguments)]
+    pub fn notify_switch_in_for_run(
+        &mut self,
+        pid: i32,
+        tid: i32,
+        thread_name: StringHandle,
+        timestamp: u64,
+        converter: &TimestampConverter,
+        thread_handles: &[ThreadHandle],
+        profile: &mut Profile,
+    ) {
+        self.run_tracker
+            .switch_in(pid, tid, thread_name, timestamp, &mut |output| {
+                add_cpu_run_marker(output, converter, thread_handles, profile)
+            });
+    }
+
+    /// Like `notify_switch_out_for_marker`, but for `CpuRunMarker`s.
+    pub fn notify_switch_out_for_run(
+        &mut self,
+        tid: i32,
+        timestamp: u64,
+        converter: &TimestampConverter,
+        thread_handles: &[ThreadHandle],
+        profile: &mut Profile,
+    ) {
+        self.run_tracker.switch_out(tid, timestamp, &mut |output| {
+            add_cpu_run_marker(output, converter, thread_handles, profile)
+        });
+    }
+
     #[allow(clippy::too_many_arguments)]
     pub fn notify_switch_in_for_marker(
         &mut self,
@@ -126,13 +395,66 @@ impl Cpus {
             combined_thread_handle,
             cpus: Vec::new(),
             idle_frame_label,
+            topology: None,
+            track_naming: SyntheticTrackNaming::default(),
+            min_run_duration: 0,
         }
     }
 
+    /// Set the duration below which runs are combined into "(short runs)"
+    /// markers, when CPU run markers are emitted.
+    /// Must be called before the first CPU track is created.
+    #[allow(dead_code)]
+    pub fn set_min_run_duration(&mut self, min_run_duration_ns: u64) {
+        self.min_run_duration = min_run_duration_ns;
+    }
+
+    /// Emit the CPU run markers which are still pending.
+    #[allow(dead_code)]
+    pub fn finish_runs(&mut self, converter: &TimestampConverter, profile: &mut Profile) {
+        let combined_thread_handle = self.combined_thread_handle;
+        for cpu in &mut self.cpus {
+            let thread_handles = [cpu.thread_handle, combined_thread_handle];
+            cpu.run_tracker.finish(&mut |output| {
+                add_cpu_run_marker(output, converter, &thread_handles, profile)
+            });
+        }
+    }
+
+    /// Use `topology` to name the CPU tracks after their physical cores.
+    /// Must be called before the first CPU track is created.
+    #[allow(dead_code)]
+    pub fn set_topology(&mut self, topology: CpuTopology) {
+        self.topology = Some(topology);
+    }
+
+    /// Name the CPU tracks with `track_naming`.
+    /// Must be called before the first CPU track is created.
+    #[allow(dead_code)]
+    pub fn set_track_naming(&mut self, track_naming: SyntheticTrackNaming) {
+        self.track_naming = track_naming;
+    }
+
+    /// The number of CPU tracks created so far.
+    pub fn cpu_count(&self) -> usize {
+        self.cpus.len()
+    }
+
     pub fn combined_thread_handle(&self) -> ThreadHandle {
         self.combined_thread_handle
     }
 
+    /// The combined track and the tracks of the CPUs seen so far.
+    pub fn thread_handles(&self) -> Vec<ThreadHandle> {
+        std::iter::once(self.combined_thread_handle)
+            .chain(self.cpus.iter().map(|cpu| cpu.thread_handle))
+            .collect()
+    }
+
+    pub fn process_handle(&self) -> ProcessHandle {
+        self.process_handle
+    }
+
     pub fn idle_frame_label(&self) -> FrameInfo {
         self.idle_frame_label.clone()
     }
@@ -141,15 +463,301 @@ impl Cpus {
         while self.cpus.len() <= cpu {
             let i = self.cpus.len();
             let thread = profile.add_thread(self.process_handle, i as u32, self.start_time, false);
-            let name = format!("CPU {i}");
+            let name = match &self.topology {
+                Some(topology) => topology.track_name(i as u32, &self.track_naming),
+                None => self.track_naming.cpu_track_name(i as u32),
+            };
             profile.set_thread_name(thread, &name);
-            self.cpus
-                .push(Cpu::new(profile.intern_string(&name), thread));
+            self.cpus.push(Cpu::new(
+                profile.intern_string(&name),
+                thread,
+                self.min_run_duration,
+            ));
         }
         &mut self.cpus[cpu]
     }
 }
 
+/// The kernel functions which are entered when a CPU starts handling an
+/// interrupt. The outermost of them on a kernel stack names the interrupt,
+/// e.g. `asm_sysvec_apic_timer_interrupt` for the x86 APIC timer.
+const INTERRUPT_ENTRY_PREFIXES: &[&str] = &[
+    // x86 NMIs
+    "asm_exc_nmi",
+    "exc_nmi",
+    // x86 system vector interrupts and device interrupts
+    "asm_sysvec_",
+    "sysvec_",
+    "asm_common_interrupt",
+    "common_interrupt",
+    // arm64
+    "el1_interrupt",
+    "el0_interrupt",
+    "gic_handle_irq",
+];
+
+/// Counts the samples on each CPU which were taken while the CPU was
+/// handling an interrupt, and adds an "IRQ samples" counter and an
+/// `IrqMarker` per sample to the CPU tracks.
+///
+/// The misc field of a sample record only says that the sample is in the
+/// kernel, not whether it's in interrupt context, so interrupt samples are
+/// detected by the interrupt entry functions on their kernel stack. This
+/// needs the symbols of the kernel image.
+/// The profile metadata key for the per-CPU interrupt sample counts, see
+/// `InterruptTracker::embed_sample_counts`.
+const IRQ_SAMPLE_COUNTS_META_KEY: &str = "samplyIrqSampleCounts";
+
+#[derive(Debug)]
+pub struct InterruptTracker {
+    kernel_base_avma: u64,
+    kernel_symbols: Arc<SymbolTable>,
+    counter: Option<CounterHandle>,
+    sample_counts: BTreeMap<u32, u64>,
+}
+
+impl InterruptTracker {
+    pub fn new(kernel_base_avma: u64, kernel_symbols: Arc<SymbolTable>) -> Self {
+        Self {
+            kernel_base_avma,
+            kernel_symbols,
+            counter: None,
+            sample_counts: BTreeMap::new(),
+        }
+    }
+
+    /// The interrupt entry function on `stack`, which is ordered from the
+    /// leaf to the root, if the sample was taken while handling an interrupt.
+    pub fn interrupt_entry(&self, stack: &[StackFrame]) -> Option<&str> {
+        stack.iter().rev().find_map(|frame| {
+            let address = match *frame {
+                StackFrame::InstructionPointer(address, StackMode::Kernel)
+                | StackFrame::AdjustedReturnAddress(address, StackMode::Kernel) => address,
+                StackFrame::ReturnAddress(address, StackMode::Kernel) => address.checked_sub(1)?,
+                _ => return None,
+            };
+            let relative_address = address.checked_sub(self.kernel_base_avma)?;
+            let symbol = self
+                .kernel_symbols
+                .lookup(u32::try_from(relative_address).ok()?)?;
+            INTERRUPT_ENTRY_PREFIXES
+                .iter()
+                .any(|prefix| symbol.name.starts_with(prefix))
+                .then_some(symbol.name.as_str())
+        })
+    }
+
+    /// Looks at a sample in the kernel on CPU `cpu_index`, and records it if
+    /// it was taken while handling an interrupt. Returns whether it was.
+    pub fn handle_kernel_sample(
+        &mut self,
+        cpu_index: u32,
+        stack: &[StackFrame],
+        timestamp: Timestamp,
+        cpus: &mut Cpus,
+        profile: &mut Profile,
+    ) -> bool {
+        let Some(entry) = self.interrupt_entry(stack) else {
+            return false;
+        };
+        let kind = if entry.contains("nmi") { "NMI" } else { "IRQ" };
+        let marker = IrqMarker {
+            handler: profile.intern_string(entry),
+            kind: profile.intern_string(kind),
+        };
+        *self.sample_counts.entry(cpu_index).or_default() += 1;
+
+        let process = cpus.process_handle();
+        let counter = *self.counter.get_or_insert_with(|| {
+            let category = CounterCategory::Cpu;
+            let color = category.default_color();
+            profile.add_counter(
+                process,
+                "IRQ samples",
+                category.into(),
+                "Samples taken while a CPU was handling an interrupt",
+                color,
+            )
+        });
+        profile.add_counter_sample(counter, timestamp, 1.0, 1);
+
+        let thread_handle = cpus.get_mut(cpu_index as usize, profile).thread_handle;
+        profile.add_marker(thread_handle, MarkerTiming::Instant(timestamp), marker);
+        true
+    }
+
+    /// The number of interrupt samples on CPU `cpu_index` so far.
+    pub fn sample_count(&self, cpu_index: u32) -> u64 {
+        self.sample_counts.get(&cpu_index).copied().unwrap_or(0)
+    }
+
+    /// Record the number of interrupt samples on each of the first
+    /// `cpu_count` CPUs in the profile's metadata, indexed by CPU.
+    pub fn embed_sample_counts(&self, cpu_count: usize, profile: &mut Profile) {
+        let counts: Vec<u64> = (0..cpu_count as u32)
+            .map(|cpu_index| self.sample_count(cpu_index))
+            .collect();
+        profile.set_extra_meta_field(IRQ_SAMPLE_COUNTS_META_KEY, counts.into());
+    }
+}
+
+fn add_cpu_run_marker(
+    output: CpuRunOutput,
+    converter: &TimestampConverter,
+    thread_handles: &[ThreadHandle],
+    profile: &mut Profile,
+) {
+    let (start, end) = match &output {
+        CpuRunOutput::Run(run) => (run.start, run.end),
+        CpuRunOutput::ShortRuns(short_runs) => (short_runs.start, short_runs.end),
+    };
+    let timing = MarkerTiming::Interval(converter.convert_time(start), converter.convert_time(end));
+    for thread_handle in thread_handles {
+        match &output {
+            CpuRunOutput::Run(run) => {
+                profile.add_marker(*thread_handle, timing.clone(), CpuRunMarker(run.clone()))
+            }
+            CpuRunOutput::ShortRuns(short_runs) => profile.add_marker(
+                *thread_handle,
+                timing.clone(),
+                ShortCpuRunsMarker(short_runs.clone()),
+            ),
+        };
+    }
+}
+
+fn nanos_to_millis(nanos: u64) -> f64 {
+    nanos as f64 / 1_000_000.0
+}
+
+/// One run of a thread on a CPU, for the CPU tracks.
+#[derive(Debug, Clone)]
+pub struct CpuRunMarker(pub CpuRun);
+
+impl StaticSchemaMarker for CpuRunMarker {
+    const UNIQUE_MARKER_TYPE_NAME: &'static str = "CpuRun";
+
+    fn schema() -> MarkerSchema {
+        MarkerSchema {
+            type_name: Self::UNIQUE_MARKER_TYPE_NAME.into(),
+            locations: vec![MarkerLocation::MarkerChart, MarkerLocation::MarkerTable],
+            chart_label: Some("{marker.data.comm}".into()),
+            tooltip_label: Some("{marker.data.comm} ({marker.data.tid})".into()),
+            table_label: Some(
+                "{marker.data.comm}, pid {marker.data.pid}, tid {marker.data.tid}".into(),
+            ),
+            fields: vec![
+                MarkerFieldSchema {
+                    key: "pid".into(),
+                    label: "Process ID".into(),
+                    format: MarkerFieldFormat::Integer,
+                    searchable: true,
+                },
+                MarkerFieldSchema {
+                    key: "tid".into(),
+                    label: "Thread ID".into(),
+                    format: MarkerFieldFormat::Integer,
+                    searchable: true,
+                },
+                MarkerFieldSchema {
+                    key: "comm".into(),
+                    label: "Thread".into(),
+                    format: MarkerFieldFormat::String,
+                    searchable: true,
+                },
+                MarkerFieldSchema {
+                    key: "duration".into(),
+                    label: "Duration".into(),
+                    format: MarkerFieldFormat::Duration,
+                    searchable: false,
+                },
+            ],
+            static_fields: vec![],
+            graphs: vec![],
+        }
+    }
+
+    fn name(&self, _profile: &mut Profile) -> StringHandle {
+        self.0.thread_name
+    }
+
+    fn category(&self, _profile: &mut Profile) -> CategoryHandle {
+        CategoryHandle::OTHER
+    }
+
+    fn string_field_value(&self, _field_index: u32) -> StringHandle {
+        self.0.thread_name
+    }
+
+    fn number_field_value(&self, field_index: u32) -> f64 {
+        match field_index {
+            0 => self.0.pid.into(),
+            1 => self.0.tid.into(),
+            3 => nanos_to_millis(self.0.end - self.0.start),
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// The short runs on a CPU within one second, for the CPU tracks.
+#[derive(Debug, Clone)]
+pub struct ShortCpuRunsMarker(pub ShortCpuRuns);
+
+impl StaticSchemaMarker for ShortCpuRunsMarker {
+    const UNIQUE_MARKER_TYPE_NAME: &'static str = "ShortCpuRuns";
+
+    fn schema() -> MarkerSchema {
+        MarkerSchema {
+            type_name: Self::UNIQUE_MARKER_TYPE_NAME.into(),
+            locations: vec![MarkerLocation::MarkerChart, MarkerLocation::MarkerTable],
+            chart_label: Some("{marker.data.count} short runs".into()),
+            tooltip_label: Some("{marker.data.count} short runs".into()),
+            table_label: Some(
+                "{marker.data.count} short runs, {marker.data.totalDuration} in total".into(),
+            ),
+            fields: vec![
+                MarkerFieldSchema {
+                    key: "count".into(),
+                    label: "Number of runs".into(),
+                    format: MarkerFieldFormat::Integer,
+                    searchable: false,
+                },
+                MarkerFieldSchema {
+                    key: "totalDuration".into(),
+                    label: "Total duration".into(),
+                    format: MarkerFieldFormat::Duration,
+                    searchable: false,
+                },
+            ],
+            static_fields: vec![MarkerStaticField {
+                label: "Description".into(),
+                value: "Runs shorter than the --min-cpu-run-us threshold which started in the same second.".into(),
+            }],
+            graphs: vec![],
+        }
+    }
+
+    fn name(&self, profile: &mut Profile) -> StringHandle {
+        profile.intern_string("(short runs)")
+    }
+
+    fn category(&self, _profile: &mut Profile) -> CategoryHandle {
+        CategoryHandle::OTHER
+    }
+
+    fn string_field_value(&self, _field_index: u32) -> StringHandle {
+        unreachable!()
+    }
+
+    fn number_field_value(&self, field_index: u32) -> f64 {
+        match field_index {
+            0 => self.0.count.into(),
+            1 => nanos_to_millis(self.0.total_duration),
+            _ => unreachable!(),
+        }
+    }
+}
+
 /// An example marker type with some text content.
 #[derive(Debug, Clone)]
 pub struct ThreadNameMarkerForCpuTrack(pub StringHandle, pub StringHandle);
@@ -250,3 +858,350 @@ impl StaticSchemaMarker for OnCpuMarkerForThreadTrack {
         unreachable!()
     }
 }
+
+/// A sample which was taken while a CPU was handling an interrupt, for the
+/// CPU tracks.
+#[derive(Debug, Clone)]
+pub struct IrqMarker {
+    handler: StringHandle,
+    kind: StringHandle,
+}
+
+impl StaticSchemaMarker for IrqMarker {
+    const UNIQUE_MARKER_TYPE_NAME: &'static str = "Irq";
+
+    fn schema() -> MarkerSchema {
+        MarkerSchema {
+            type_name: Self::UNIQUE_MARKER_TYPE_NAME.into(),
+            locations: vec![MarkerLocation::MarkerChart, MarkerLocation::MarkerTable],
+            chart_label: Some("{marker.data.handler}".into()),
+            tooltip_label: Some("{marker.data.kind}: {marker.data.handler}".into()),
+            table_label: Some("{marker.data.kind}: {marker.data.handler}".into()),
+            fields: vec![
+                MarkerFieldSchema {
+                    key: "handler".into(),
+                    label: "Handler".into(),
+                    format: MarkerFieldFormat::String,
+                    searchable: true,
+                },
+                MarkerFieldSchema {
+                    key: "kind".into(),
+                    label: "Kind".into(),
+                    format: MarkerFieldFormat::String,
+                    searchable: true,
+                },
+            ],
+            static_fields: vec![],
+            graphs: vec![],
+        }
+    }
+
+    fn name(&self, _profile: &mut Profile) -> StringHandle {
+        self.kind
+    }
+
+    fn category(&self, _profile: &mut Profile) -> CategoryHandle {
+        CategoryHandle::OTHER
+    }
+
+    fn string_field_value(&self, field_index: u32) -> StringHandle {
+        match field_index {
+            0 => self.handler,
+            1 => self.kind,
+            _ => unreachable!(),
+        }
+    }
+
+    fn number_field_value(&self, _field_index: u32) -> f64 {
+        unreachable!()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use fxprof_processed_profile::Symbol;
+
+    use super::*;
+    use crate::shared::test_support::new_profile;
+
+    const MS: u64 = 1_000_000;
+    const US: u64 = 1_000;
+
+    enum Switch {
+        In(i32, u64),
+        Out(i32, u64),
+    }
+
+    /// Feeds `switches` for one CPU into a tracker and returns what it emitted,
+    /// including at the end.
+    fn track(min_run_duration: u64, switches: &[Switch]) -> Vec<CpuRunOutput> {
+        let mut profile = new_profile();
+        let mut tracker = CpuRunTracker::new(min_run_duration);
+        let mut outputs = Vec::new();
+        let mut emit = |output| outputs.push(output);
+        for switch in switches {
+            match *switch {
+                Switch::In(tid, timestamp) => {
+                    let name = profile.intern_string(&format!("thread {tid}"));
+                    tracker.switch_in(1, tid, name, timestamp, &mut emit);
+                }
+                Switch::Out(tid, timestamp) => tracker.switch_out(tid, timestamp, &mut emit),
+            }
+        }
+        tracker.finish(&mut emit);
+        outputs
+    }
+
+    fn runs(outputs: &[CpuRunOutput]) -> Vec<(i32, u64, u64)> {
+        outputs
+            .iter()
+            .filter_map(|output| match output {
+                CpuRunOutput::Run(run) => Some((run.tid, run.start, run.end)),
+                CpuRunOutput::ShortRuns(_) => None,
+            })
+            .collect()
+    }
+
+    fn short_runs(outputs: &[CpuRunOutput]) -> Vec<ShortCpuRuns> {
+        outputs
+            .iter()
+            .filter_map(|output| match output {
+                CpuRunOutput::ShortRuns(short_runs) => Some(short_runs.clone()),
+                CpuRunOutput::Run(_) => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn switch_ins_are_paired_with_switch_outs() {
+        use Switch::*;
+        let outputs = track(
+            0,
+            &[
+                In(10, 0),
+                Out(10, 2 * MS),
+                In(11, 3 * MS),
+                // Missing switch-out of 11: its run ends at the next switch-in.
+                In(12, 5 * MS),
+                Out(12, 6 * MS),
+                // A switch-out without a switch-in is ignored.
+                Out(13, 7 * MS),
+                // The run of 14 is still in progress at the end.
+                In(14, 8 * MS),
+            ],
+        );
+        assert_eq!(
+            runs(&outputs),
+            [(10, 0, 2 * MS), (11, 3 * MS, 5 * MS), (12, 5 * MS, 6 * MS)]
+        );
+        assert!(short_runs(&outputs).is_empty());
+    }
+
+    #[test]
+    fn short_runs_are_combined_per_second() {
+        use Switch::*;
+        // A burst of 1000 runs of 5us each, one every 10us, in the first
+        // second, one long run, and another burst straddling the second boundary.
+        let mut switches = Vec::new();
+        for i in 0..1000 {
+            let start = 100 * MS + i * 10 * US;
+            switches.push(In(20 + (i % 2) as i32, start));
+            switches.push(Out(20 + (i % 2) as i32, start + 5 * US));
+        }
+        switches.push(In(30, 500 * MS));
+        switches.push(Out(30, 600 * MS));
+        for i in 0..4 {
+            let start = 999 * MS + 400 * US + i * 200 * US;
+            switches.push(In(40, start));
+            switches.push(Out(40, start + 50 * US));
+        }
+        let outputs = track(100 * US, &switches);
+
+        assert_eq!(runs(&outputs), [(30, 500 * MS, 600 * MS)]);
+        assert_eq!(
+            short_runs(&outputs),
+            [
+                ShortCpuRuns {
+                    start: 100 * MS,
+                    end: 999 * MS + 850 * US,
+                    count: 1003,
+                    total_duration: 1000 * 5 * US + 3 * 50 * US,
+                },
+                ShortCpuRuns {
+                    start: 1000 * MS,
+                    end: 1000 * MS + 50 * US,
+                    count: 1,
+                    total_duration: 50 * US,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn runs_at_the_threshold_are_kept() {
+        use Switch::*;
+        let outputs = track(
+            100 * US,
+            &[In(1, 0), Out(1, 100 * US), In(2, MS), Out(2, MS + 99 * US)],
+        );
+        assert_eq!(runs(&outputs), [(1, 0, 100 * US)]);
+        assert_eq!(short_runs(&outputs).len(), 1);
+        assert_eq!(short_runs(&outputs)[0].count, 1);
+    }
+
+    fn mock_sysfs(cpus: &[(u32, u32, u32)]) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        for (cpu, socket, core) in cpus {
+            let topology_dir: PathBuf = dir.path().join(format!("cpu{cpu}/topology"));
+            std::fs::create_dir_all(&topology_dir).unwrap();
+            std::fs::write(topology_dir.join("core_id"), format!("{core}\n")).unwrap();
+            std::fs::write(
+                topology_dir.join("physical_package_id"),
+                format!("{socket}\n"),
+            )
+            .unwrap();
+        }
+        // Entries which are not CPUs, and an offline CPU without topology.
+        std::fs::create_dir_all(dir.path().join("cpufreq")).unwrap();
+        std::fs::create_dir_all(dir.path().join("cpu9")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn hyperthread_siblings() {
+        let dir = mock_sysfs(&[(0, 0, 0), (1, 0, 0), (2, 0, 1), (3, 0, 1)]);
+        let topology = CpuTopology::from_sysfs_dir(dir.path()).unwrap();
+        let naming = SyntheticTrackNaming::default();
+        assert_eq!(
+            topology.core_id(3),
+            Some(CpuCoreId {
+                socket: 0,
+                physical_core: 1
+            })
+        );
+        assert_eq!(topology.siblings(2), vec![2, 3]);
+        assert_eq!(topology.track_name(2, &naming), "CPU 2/3 (Core 1)");
+        assert_eq!(topology.track_name(3, &naming), "CPU 2/3 (Core 1)");
+        assert_eq!(topology.track_name(0, &naming), "CPU 0/1 (Core 0)");
+        assert_eq!(topology.track_name(9, &naming), "CPU 9");
+    }
+
+    const KERNEL_BASE: u64 = 0xffff_ffff_8100_0000;
+
+    fn kernel_symbols() -> Arc<SymbolTable> {
+        let symbol = |address, name: &str| Symbol {
+            address,
+            size: Some(0x100),
+            name: name.into(),
+        };
+        Arc::new(SymbolTable::new(vec![
+            symbol(0x1000, "entry_SYSCALL_64"),
+            symbol(0x2000, "do_sys_read"),
+            symbol(0x3000, "asm_sysvec_apic_timer_interrupt"),
+            symbol(0x4000, "hrtimer_interrupt"),
+            symbol(0x5000, "asm_exc_nmi"),
+            symbol(0x6000, "perf_event_nmi_handler"),
+        ]))
+    }
+
+    /// A kernel stack through the functions at these symbol offsets, leaf first.
+    fn kernel_stack(offsets: &[u64]) -> Vec<StackFrame> {
+        let mut stack = vec![StackFrame::InstructionPointer(
+            KERNEL_BASE + offsets[0] + 0x10,
+            StackMode::Kernel,
+        )];
+        stack.extend(offsets[1..].iter().map(|offset| {
+            StackFrame::ReturnAddress(KERNEL_BASE + offset + 0x20, StackMode::Kernel)
+        }));
+        stack.push(StackFrame::ReturnAddress(0x5555_0000, StackMode::User));
+        stack
+    }
+
+    #[test]
+    fn interrupt_samples_are_counted_per_cpu() {
+        let mut profile = new_profile();
+        let mut cpus = Cpus::new(Timestamp::from_millis_since_reference(0.0), &mut profile);
+        let mut tracker = InterruptTracker::new(KERNEL_BASE, kernel_symbols());
+
+        // A timer interrupt during a syscall, an NMI, and a plain syscall.
+        let timer = kernel_stack(&[0x4000, 0x3000, 0x2000, 0x1000]);
+        let nmi = kernel_stack(&[0x6000, 0x5000]);
+        let syscall = kernel_stack(&[0x2000, 0x1000]);
+        assert_eq!(
+            tracker.interrupt_entry(&timer),
+            Some("asm_sysvec_apic_timer_interrupt")
+        );
+        assert_eq!(tracker.interrupt_entry(&nmi), Some("asm_exc_nmi"));
+        assert_eq!(tracker.interrupt_entry(&syscall), None);
+
+        let samples = [(0, &timer), (1, &nmi), (1, &syscall), (1, &timer)];
+        for (i, (cpu, stack)) in samples.into_iter().enumerate() {
+            let timestamp = Timestamp::from_millis_since_reference(i as f64);
+            tracker.handle_kernel_sample(cpu, stack, timestamp, &mut cpus, &mut profile);
+        }
+        assert_eq!(tracker.sample_count(0), 1);
+        assert_eq!(tracker.sample_count(1), 2);
+        assert_eq!(tracker.sample_count(2), 0);
+        tracker.embed_sample_counts(cpus.cpu_count(), &mut profile);
+        assert_eq!(
+            profile.extra_meta_field(IRQ_SAMPLE_COUNTS_META_KEY),
+            Some(&serde_json::json!([1, 2]))
+        );
+
+        let json = serde_json::to_value(&profile).unwrap();
+        let counter = &json["counters"][0];
+        assert_eq!(counter["name"], "IRQ samples");
+        assert_eq!(counter["category"], "CPU");
+        assert_eq!(counter["samples"]["length"], 3);
+        let threads = json["threads"].as_array().unwrap();
+        let cpu1 = threads.iter().find(|t| t["name"] == "CPU 1").unwrap();
+        assert_eq!(cpu1["markers"]["length"], 2);
+        let kinds: Vec<&str> = cpu1["markers"]["data"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|data| {
+                let kind = data["kind"].as_u64().unwrap() as usize;
+                cpu1["stringArray"][kind].as_str().unwrap()
+            })
+            .collect();
+        assert_eq!(kinds, ["NMI", "IRQ"]);
+    }
+
+    #[test]
+    fn multiple_sockets() {
+        let dir = mock_sysfs(&[(0, 0, 0), (1, 1, 0)]);
+        let topology = CpuTopology::from_sysfs_dir(dir.path()).unwrap();
+        assert_eq!(topology.siblings(0), vec![0]);
+        let naming = SyntheticTrackNaming::default();
+        assert_eq!(topology.track_name(1, &naming), "CPU 1 (Socket 1, Core 0)");
+    }
+
+    #[test]
+    fn cpu_tracks_use_the_configured_names() {
+        let naming = SyntheticTrackNaming {
+            prefix: "[samply] ".to_string(),
+            cpu_format: "core #{cpu}".to_string(),
+            ..Default::default()
+        };
+        let mut profile = new_profile();
+        let mut cpus = Cpus::new(Timestamp::from_millis_since_reference(0.0), &mut profile);
+        cpus.set_track_naming(naming.clone());
+        cpus.get_mut(1, &mut profile);
+        let json = serde_json::to_value(&profile).unwrap();
+        let names: Vec<&str> = json["threads"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(|thread| thread["name"].as_str())
+            .collect();
+        assert!(names.contains(&"[samply] core #0"), "{names:?}");
+        assert!(names.contains(&"[samply] core #1"), "{names:?}");
+
+        let dir = mock_sysfs(&[(0, 0, 0), (1, 0, 0)]);
+        let topology = CpuTopology::from_sysfs_dir(dir.path()).unwrap();
+        assert_eq!(topology.track_name(1, &naming), "[samply] CPU 0/1 (Core 0)");
+    }
+}
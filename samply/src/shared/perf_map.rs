@@ -90,7 +90,7 @@ pub fn try_load_perf_map(
         });
 
         let (lib_handle, relative_address) = if let Some(recycler) = recycler.as_deref_mut() {
-            recycler.recycle(symbol_name, code_size, lib_handle, relative_address)
+            recycler.recycle(symbol_name, code_size, None, lib_handle, relative_address)
         } else {
             (lib_handle, relative_address)
         };
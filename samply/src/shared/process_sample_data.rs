@@ -1,22 +1,107 @@
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use fxprof_processed_profile::{
-    CategoryColor, CategoryHandle, CategoryPairHandle, LibMappings, Marker, MarkerFieldFormat,
-    MarkerFieldSchema, MarkerGraph, MarkerGraphType, MarkerLocation, MarkerSchema,
-    MarkerStaticField, MarkerTiming, MarkerTypeHandle, ProcessHandle, Profile, StaticSchemaMarker,
-    StringHandle, ThreadHandle,
+    CategoryColor, CategoryHandle, CategoryPairHandle, CounterHandle, Frame, LibMappings,
+    LibraryHandle, Marker, MarkerFieldFormat, MarkerFieldSchema, MarkerGraph, MarkerGraphType,
+    MarkerLocation, MarkerSchema, MarkerStaticField, MarkerTiming, MarkerTypeHandle, ProcessHandle,
+    Profile, StaticSchemaMarker, StringHandle, ThreadHandle, Timestamp,
 };
+use log::warn;
 
-use super::counter_file::{Counter, CounterCategory};
+use super::counter_file::{
+    distinguish_counter_colors, Counter, CounterCategory, CounterColorChange, CounterSample,
+};
 use super::lib_mappings::{LibMappingInfo, LibMappingOpQueue, LibMappingsHierarchy};
-use super::marker_file::{EventOrSpanMarker, MarkerData, MarkerSpan, MarkerStats, TracingTimings};
+use super::marker_file::{
+    EventLevel, EventOrSpanMarker, FieldType, MarkerData, MarkerSpan, MarkerStats, SpanType,
+    TracingTimings, RAW_LINE_FIELD, TRACE_ID_FIELD,
+};
+use super::memory_usage::{vec_heap_size, MemoryReport, MemoryUsage};
+use super::recording_props::SyntheticTrackNaming;
+use super::span_interval_index::SpanIntervalIndex;
 use super::stack_converter::StackConverter;
 use super::stack_depth_limiting_frame_iter::StackDepthLimitingFrameIter;
 use super::types::StackFrame;
 use super::unresolved_samples::{
-    SampleData, SampleOrMarker, UnresolvedSampleOrMarker, UnresolvedSamples, UnresolvedStacks,
+    SampleChunks, SampleData, SampleOrMarker, UnresolvedSampleOrMarker, UnresolvedSamples,
+    UnresolvedStackHandle, UnresolvedStacks,
 };
 
+/// The `meta` property which maps span category names to their colors.
+const SPAN_CATEGORY_COLORS_META_KEY: &str = "samplySpanCategoryColors";
+
+/// The colors which span categories are picked from. This leaves out the
+/// colors of the built-in "Other", "User" and "Kernel" categories.
+const SPAN_CATEGORY_PALETTE: [CategoryColor; 9] = [
+    CategoryColor::Green,
+    CategoryColor::Blue,
+    CategoryColor::Purple,
+    CategoryColor::Red,
+    CategoryColor::Magenta,
+    CategoryColor::Brown,
+    CategoryColor::LightBlue,
+    CategoryColor::LightGreen,
+    CategoryColor::LightRed,
+];
+
+/// Picks a color for a span category based on its name.
+fn span_category_color(name: &str) -> CategoryColor {
+    // FNV-1a, because unlike the std hasher its output is guaranteed to be stable.
+    let hash = name.bytes().fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    });
+    SPAN_CATEGORY_PALETTE[(hash % SPAN_CATEGORY_PALETTE.len() as u64) as usize]
+}
+
+/// The color with the given name, as used in the profile JSON.
+fn category_color_from_name(name: &str) -> Option<CategoryColor> {
+    let color = match name {
+        "transparent" => CategoryColor::Transparent,
+        "lightblue" => CategoryColor::LightBlue,
+        "red" => CategoryColor::Red,
+        "lightred" => CategoryColor::LightRed,
+        "orange" => CategoryColor::Orange,
+        "blue" => CategoryColor::Blue,
+        "green" => CategoryColor::Green,
+        "purple" => CategoryColor::Purple,
+        "yellow" => CategoryColor::Yellow,
+        "brown" => CategoryColor::Brown,
+        "magenta" => CategoryColor::Magenta,
+        "lightgreen" => CategoryColor::LightGreen,
+        "grey" | "gray" => CategoryColor::Gray,
+        "darkgray" | "darkgrey" => CategoryColor::DarkGray,
+        _ => return None,
+    };
+    Some(color)
+}
+
+/// Parses a span category color file: a JSON object which maps span category
+/// names to color names, like `{"Compaction": "blue", "Scan": "lightred"}`.
+/// This is the same format as the `samplySpanCategoryColors` metadata of a
+/// profile, so the colors of an earlier profile can be reused.
+pub fn parse_span_category_colors(json: &str) -> Result<HashMap<String, CategoryColor>, String> {
+    let json: serde_json::Value =
+        serde_json::from_str(json).map_err(|e| format!("invalid JSON: {e}"))?;
+    let serde_json::Value::Object(map) = json else {
+        return Err("expected a JSON object".to_string());
+    };
+    map.into_iter()
+        .map(|(name, color)| {
+            let color_name = color.as_str().unwrap_or_default();
+            let color = category_color_from_name(color_name)
+                .ok_or_else(|| format!("unknown color {color} for category '{name}'"))?;
+            Ok((name, color))
+        })
+        .collect()
+}
+
 #[derive(Debug, Clone)]
 pub struct MarkerOnThread {
     pub thread_handle: ThreadHandle,
@@ -29,6 +114,20 @@ pub struct CounterOnThread {
     pub counter: Counter,
 }
 
+/// A causal link between a stack in one process and a stack in another,
+/// e.g. a request which was sent over a socket and the stack which handled
+/// it. Each end becomes a marker with the end's stack on the end's thread.
+#[derive(Debug, Clone)]
+pub struct CrossProcessEdge {
+    pub from_pid: u32,
+    pub from_thread: ThreadHandle,
+    pub from_stack: UnresolvedStackHandle,
+    pub to_pid: u32,
+    pub to_thread: ThreadHandle,
+    pub to_stack: UnresolvedStackHandle,
+    pub timestamp: Timestamp,
+}
+
 #[derive(Debug, Clone)]
 pub enum RssStatMember {
     ResidentFileMappingPages,
@@ -37,7 +136,73 @@ pub enum RssStatMember {
     ResidentSharedMemoryPages,
 }
 
+/// How the data of a process is turned into profile data, see
+/// `ProcessSampleData::flush_samples_to_profile`. The default options don't
+/// add anything beyond the data itself, and don't truncate marker strings.
 #[derive(Debug, Clone)]
+pub struct FlushOptions {
+    /// Span categories whose samples get the span's category.
+    pub span_sample_categories: Vec<String>,
+    /// Whether to put all marker file events under a single "Log" marker type.
+    pub simple_event_markers: bool,
+    /// The length in bytes beyond which marker string fields are truncated.
+    pub max_marker_string_len: usize,
+    /// What the marker table shows for spans from marker files.
+    pub span_table_label: SpanTableLabel,
+    /// Colors for span categories, instead of the colors picked by name.
+    pub span_category_colors: HashMap<String, CategoryColor>,
+    /// Whether markers from marker files go on one track per category,
+    /// instead of on the thread they were emitted on.
+    pub category_marker_tracks: bool,
+    /// If set, the window length of the "Samples" counter, see
+    /// `ProcessSampleData::add_sample_rate_counter`.
+    pub sample_rate_counter_window: Option<Duration>,
+    /// Whether to add a cumulative busy time counter per span category.
+    pub span_busy_counters: bool,
+    /// If set, the number of counter tracks per category beyond which the
+    /// counters are folded, see `ProcessSampleData::cap_counter_tracks`.
+    pub max_counter_tracks: Option<usize>,
+    /// If set, the span stats are printed and reset for each window of
+    /// this length, instead of once for the whole recording.
+    pub stats_flush_interval: Option<Duration>,
+    /// See `LibMappingsHierarchy::set_aslr_tolerance`.
+    pub aslr_tolerance: Option<u64>,
+    /// The names of the marker category tracks.
+    pub track_naming: SyntheticTrackNaming,
+    /// If set, the folded stacks of each process are appended to this file,
+    /// see `ProcessSampleData::to_folded_stacks`.
+    pub folded_stacks_file: Option<Arc<Mutex<File>>>,
+    /// If set, only every n-th sample of each thread is kept, see
+    /// `ProcessSampleData::downsample`.
+    pub downsample_factor: Option<u32>,
+    /// If set, this many functions with the highest self weight are printed
+    /// for each process, see `ProcessSampleData::top_functions`.
+    pub top_functions: Option<usize>,
+}
+
+impl Default for FlushOptions {
+    fn default() -> Self {
+        Self {
+            span_sample_categories: Vec::new(),
+            simple_event_markers: false,
+            max_marker_string_len: usize::MAX,
+            span_table_label: SpanTableLabel::default(),
+            span_category_colors: HashMap::new(),
+            category_marker_tracks: false,
+            sample_rate_counter_window: None,
+            span_busy_counters: false,
+            max_counter_tracks: None,
+            stats_flush_interval: None,
+            aslr_tolerance: None,
+            track_naming: SyntheticTrackNaming::default(),
+            folded_stacks_file: None,
+            downsample_factor: None,
+            top_functions: None,
+        }
+    }
+}
+
+#[derive(Debug)]
 pub struct ProcessSampleData {
     unresolved_samples: UnresolvedSamples,
     regular_lib_mapping_op_queue: LibMappingOpQueue,
@@ -45,10 +210,17 @@ pub struct ProcessSampleData {
     perf_map_mappings: Option<LibMappings<LibMappingInfo>>,
     markers: Vec<MarkerOnThread>,
     counters: Vec<CounterOnThread>,
+    cross_process_edges: Vec<CrossProcessEdge>,
     process: ProcessHandle,
+    /// The marker and counter files which the markers and counters were read from.
+    source_files: Vec<PathBuf>,
+    /// The process's main thread, if known, which gets the global phase
+    /// markers, see `add_global_phase_markers`.
+    main_thread: Option<ThreadHandle>,
 }
 
 impl ProcessSampleData {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         unresolved_samples: UnresolvedSamples,
         regular_lib_mapping_op_queue: LibMappingOpQueue,
@@ -57,6 +229,7 @@ impl ProcessSampleData {
         markers: Vec<MarkerOnThread>,
         counters: Vec<CounterOnThread>,
         process: ProcessHandle,
+        source_files: Vec<PathBuf>,
     ) -> Self {
         Self {
             unresolved_samples,
@@ -65,7 +238,10 @@ impl ProcessSampleData {
             perf_map_mappings,
             markers,
             counters,
+            cross_process_edges: Vec::new(),
             process,
+            source_files,
+            main_thread: None,
         }
     }
 
@@ -73,363 +249,2317 @@ impl ProcessSampleData {
         self.unresolved_samples.is_empty()
     }
 
-    #[allow(clippy::too_many_arguments)]
-    pub fn flush_samples_to_profile(
-        self,
-        profile: &mut Profile,
-        user_category: CategoryPairHandle,
-        kernel_category: CategoryPairHandle,
-        stack_frame_scratch_buf: &mut Vec<StackFrame>,
-        stacks: &UnresolvedStacks,
-    ) {
-        let ProcessSampleData {
-            unresolved_samples,
-            regular_lib_mapping_op_queue,
-            jitdump_lib_mapping_op_queues,
-            perf_map_mappings,
-            markers,
-            counters,
-            process,
-        } = self;
-        let mut lib_mappings_hierarchy = LibMappingsHierarchy::new(regular_lib_mapping_op_queue);
-        for jitdump_lib_mapping_ops in jitdump_lib_mapping_op_queues {
-            lib_mappings_hierarchy.add_jitdump_lib_mappings_ops(jitdump_lib_mapping_ops);
+    /// The number of samples, without the stacks of markers.
+    pub fn sample_count(&self) -> usize {
+        let mut count = 0;
+        self.unresolved_samples.for_each_chunk(|chunk| {
+            count += chunk
+                .iter()
+                .filter(|sample| matches!(sample.sample_or_marker, SampleOrMarker::Sample(_)))
+                .count();
+        });
+        count
+    }
+
+    /// The number of markers from marker files, without their children.
+    pub fn marker_count(&self) -> usize {
+        self.markers.len()
+    }
+
+    /// Adds the samples, lib mappings, markers and counters of `other`, which
+    /// must be data of the same process, e.g. from another file of the same
+    /// recording. Samples, lib mapping ops and markers stay in timestamp
+    /// order. The perf map mappings of `other` are only used if `self` has
+    /// none.
+    pub fn merge(&mut self, other: ProcessSampleData) -> Result<(), String> {
+        if other.process != self.process {
+            return Err(format!(
+                "can't merge the data of {:?} into the data of {:?}",
+                other.process, self.process
+            ));
         }
-        if let Some(perf_map_mappings) = perf_map_mappings {
-            lib_mappings_hierarchy.add_perf_map_mappings(perf_map_mappings);
+        self.unresolved_samples.merge(other.unresolved_samples);
+        self.regular_lib_mapping_op_queue
+            .merge(other.regular_lib_mapping_op_queue);
+        self.jitdump_lib_mapping_op_queues
+            .extend(other.jitdump_lib_mapping_op_queues);
+        if self.perf_map_mappings.is_none() {
+            self.perf_map_mappings = other.perf_map_mappings;
         }
-        let mut stack_converter = StackConverter::new(user_category, kernel_category);
-        let samples = unresolved_samples.into_inner();
-        for sample in samples {
-            lib_mappings_hierarchy.process_ops(sample.timestamp_mono);
-            let UnresolvedSampleOrMarker {
-                thread_handle,
-                timestamp,
-                stack,
-                sample_or_marker,
-                extra_label_frame,
-                ..
-            } = sample;
-
-            stack_frame_scratch_buf.clear();
-            stacks.convert_back(stack, stack_frame_scratch_buf);
-            let frames = stack_converter.convert_stack(
-                stack_frame_scratch_buf,
-                &lib_mappings_hierarchy,
-                extra_label_frame,
-            );
-            let frames = StackDepthLimitingFrameIter::new(profile, frames, user_category);
-            match sample_or_marker {
-                SampleOrMarker::Sample(SampleData { cpu_delta, weight }) => {
-                    profile.add_sample(thread_handle, timestamp, frames, cpu_delta, weight);
-                }
-                SampleOrMarker::MarkerHandle(mh) => {
-                    profile.set_marker_stack(thread_handle, mh, frames);
-                }
+        self.markers.extend(other.markers);
+        self.markers
+            .sort_by_key(|marker| marker.event_or_span.start_time);
+        self.counters.extend(other.counters);
+        self.cross_process_edges.extend(other.cross_process_edges);
+        for source_file in other.source_files {
+            if !self.source_files.contains(&source_file) {
+                self.source_files.push(source_file);
             }
         }
+        Ok(())
+    }
 
-        let mut category_handles = HashMap::<String, CategoryHandle>::new();
-        let logging_category = profile.add_category("(Logging)", CategoryColor::Green);
-
-        let mut span_marker_types: HashMap<String, MarkerTypeHandle> = HashMap::new();
-        let mut event_marker_types: HashMap<String, MarkerTypeHandle> = HashMap::new();
+    pub fn source_files(&self) -> &[PathBuf] {
+        &self.source_files
+    }
 
-        let mut stats = MarkerStats::new();
-        for marker in markers {
-            stats.process_span(&marker.event_or_span);
-            let mut extra_fields: Vec<_> = marker
-                .event_or_span
-                .extra_fields
-                .clone()
-                .into_iter()
-                .collect();
-            extra_fields.sort_by_key(|(k, _)| k.clone());
+    /// The earliest and latest timestamp of any sample, marker or counter
+    /// sample in this process, or `None` if there is no timed data at all.
+    pub fn time_bounds(&self) -> Option<(Timestamp, Timestamp)> {
+        let mut sample_times = Vec::new();
+        self.unresolved_samples.for_each_chunk(|chunk| {
+            let chunk_times = chunk.iter().map(|sample| sample.timestamp);
+            sample_times.extend(chunk_times.clone().min());
+            sample_times.extend(chunk_times.max());
+        });
+        let marker_times = self.markers.iter().flat_map(|marker| {
+            let start_time = marker.event_or_span.start_time;
+            let end_time = match &marker.event_or_span.marker_data {
+                MarkerData::Span(span) => span.end_time,
+                MarkerData::Event => start_time,
+            };
+            [start_time, end_time]
+        });
+        let counter_times = self.counters.iter().flat_map(|counter| {
+            counter
+                .counter
+                .samples
+                .iter()
+                .map(|sample| sample.timestamp)
+        });
 
-            let (field_names, field_values): (Vec<_>, Vec<_>) = extra_fields.into_iter().unzip();
-            let marker_typename = field_names.join("_");
+        sample_times
+            .into_iter()
+            .chain(marker_times)
+            .chain(counter_times)
+            .fold(None, |bounds, t| match bounds {
+                None => Some((t, t)),
+                Some((min, max)) => Some((min.min(t), max.max(t))),
+            })
+    }
 
-            match &marker.event_or_span.marker_data {
-                MarkerData::Event => {
-                    let marker_type = event_marker_types
-                        .entry(marker_typename.clone())
-                        .or_insert_with(|| EventMarker::create_marker_type(profile, &field_names));
+    /// Reduces the size of the profile by keeping only every `factor`-th
+    /// sample of each thread. The weight of the kept samples is multiplied by
+    /// `factor`, so this loses detail but keeps the aggregate timing, e.g. the
+    /// share of each function in the call tree. The CPU deltas of the dropped
+    /// samples are lost. Markers and counters are kept unmodified.
+    pub fn downsample(self, factor: u32) -> Self {
+        Self {
+            unresolved_samples: self.unresolved_samples.downsample(factor),
+            ..self
+        }
+    }
 
-                    let span_marker = EventMarker::new(
-                        profile,
-                        &logging_category,
-                        &marker,
-                        marker_type,
-                        &field_values,
-                    );
-                    profile.add_marker(
-                        marker.thread_handle,
-                        MarkerTiming::Instant(marker.event_or_span.start_time),
-                        span_marker,
-                    );
+    /// Add a "Samples" counter with the number of samples in each `window` of
+    /// the recording, to show where the sampler sped up or slowed down. The
+    /// counter sample for a window is placed at the window's first sample, so
+    /// windows without any samples don't get one.
+    fn add_sample_rate_counter(&mut self, window: Duration) {
+        let window_ns = (window.as_nanos() as u64).max(1);
+        let mut thread_handle = None;
+        let mut windows: BTreeMap<u64, (Timestamp, u32)> = BTreeMap::new();
+        self.unresolved_samples.for_each_chunk(|chunk| {
+            for sample in chunk {
+                if !matches!(sample.sample_or_marker, SampleOrMarker::Sample(_)) {
+                    continue;
                 }
-                MarkerData::Span(span) => {
-                    let marker_type = span_marker_types
-                        .entry(marker_typename.clone())
-                        .or_insert_with(|| {
-                            SpanMarkerWithTimings::create_marker_type(profile, &field_names)
-                        });
+                thread_handle.get_or_insert(sample.thread_handle);
+                let (first_timestamp, count) = windows
+                    .entry(sample.timestamp_mono / window_ns)
+                    .or_insert((sample.timestamp, 0));
+                *first_timestamp = (*first_timestamp).min(sample.timestamp);
+                *count += 1;
+            }
+        });
+        let Some(thread_handle) = thread_handle else {
+            return;
+        };
+        let samples = windows
+            .into_values()
+            .map(|(timestamp, count)| CounterSample {
+                timestamp,
+                value: count as f64,
+                modification_count: count,
+            })
+            .collect();
+        self.counters.push(CounterOnThread {
+            thread_handle,
+            counter: Counter {
+                name: "Samples".to_string(),
+                category: CounterCategory::Cpu,
+                description: format!("Samples per {} ms", window.as_secs_f64() * 1000.0),
+                color: None,
+                graph_type: MarkerGraphType::Line,
+                is_cumulative: false,
+                samples,
+            },
+        });
+    }
 
-                    let span_marker = SpanMarkerWithTimings::new(
-                        profile,
-                        &marker,
-                        span,
-                        &mut category_handles,
-                        marker_type,
-                        &field_values,
-                    );
-                    profile.add_marker(
-                        marker.thread_handle,
-                        MarkerTiming::Interval(marker.event_or_span.start_time, span.end_time),
-                        span_marker,
-                    );
-                }
+    /// The categories of this process's markers, including the ones nested
+    /// in spans: the span categories, and the targets of events.
+    pub fn categories(&self) -> BTreeSet<String> {
+        let mut categories = BTreeSet::new();
+        let mut pending: Vec<&EventOrSpanMarker> = self
+            .markers
+            .iter()
+            .map(|marker| &marker.event_or_span)
+            .collect();
+        while let Some(marker) = pending.pop() {
+            if !categories.contains(marker.category()) {
+                categories.insert(marker.category().to_string());
+            }
+            if let MarkerData::Span(span) = &marker.marker_data {
+                pending.extend(&span.child_markers);
             }
         }
-        if !stats.is_empty() {
-            stats.dump();
-        }
+        categories
+    }
 
-        for CounterOnThread {
-            counter,
-            thread_handle,
-        } in counters
-        {
-            match counter.category {
-                CounterCategory::Custom => {
-                    let marker_type = CustomGraphMarker::create_marker_type(profile, &counter);
+    /// Adds the approximate sizes of this process's buffers to `report`.
+    pub fn add_memory_usage(&self, report: &mut MemoryReport) {
+        report.add("Unresolved samples", self.unresolved_samples.heap_size());
+        report.add(
+            "Lib mapping ops",
+            self.regular_lib_mapping_op_queue.heap_size()
+                + self
+                    .jitdump_lib_mapping_op_queues
+                    .iter()
+                    .map(LibMappingOpQueue::heap_size)
+                    .sum::<usize>(),
+        );
+        report.add(
+            "Markers",
+            vec_heap_size(&self.markers)
+                + self
+                    .markers
+                    .iter()
+                    .map(|marker| marker.event_or_span.heap_size())
+                    .sum::<usize>(),
+        );
+        report.add(
+            "Counters",
+            vec_heap_size(&self.counters)
+                + self
+                    .counters
+                    .iter()
+                    .map(|counter| counter.counter.heap_size())
+                    .sum::<usize>(),
+        );
+    }
 
-                    for sample in counter.samples {
-                        let marker = CustomGraphMarker::new(
-                            profile.intern_string(&counter.name),
-                            CategoryHandle::OTHER,
-                            marker_type,
-                            sample.value,
-                        );
+    /// The approximate number of heap bytes held by the samples, markers,
+    /// counters and lib mapping ops which haven't been flushed yet.
+    pub fn estimated_memory_bytes(&self) -> usize {
+        let mut report = MemoryReport::default();
+        self.add_memory_usage(&mut report);
+        report.total()
+    }
 
-                        profile.add_marker(
-                            thread_handle,
-                            MarkerTiming::Instant(sample.timestamp),
-                            marker,
-                        );
-                    }
-                }
-                _ => {
-                    let counter_handle = profile.add_counter(
-                        process,
-                        &counter.name,
-                        counter.category.into(),
-                        &counter.description,
-                        counter.color,
-                    );
+    /// Give only `max_tracks` tracks to the counters of each category, so
+    /// that files with thousands of counters don't overwhelm the UI. If a
+    /// category has more counters, the `max_tracks - 1` with the highest peak
+    /// value keep their tracks, and the others are folded into one "Other
+    /// <category>" counter with their sum. Counters of different categories
+    /// are never summed up, because their units differ. Custom counters are
+    /// left alone, because they are shown as marker graphs on their threads,
+    /// not as tracks. With a `max_tracks` of 0, the counters of the capped
+    /// categories are dropped. Returns the number of folded or dropped
+    /// counters.
+    fn cap_counter_tracks(&mut self, max_tracks: usize) -> usize {
+        let mut by_category: Vec<(&'static str, Vec<usize>)> = Vec::new();
+        for (index, counter) in self.counters.iter().enumerate() {
+            let category = &counter.counter.category;
+            if matches!(category, CounterCategory::Custom) {
+                continue;
+            }
+            let name: &str = category.clone().into();
+            match by_category.iter_mut().find(|(n, _)| *n == name) {
+                Some((_, indexes)) => indexes.push(index),
+                None => by_category.push((name, vec![index])),
+            }
+        }
 
-                    for sample in counter.samples {
-                        profile.add_counter_sample(
-                            counter_handle,
-                            sample.timestamp,
-                            sample.value,
-                            sample.modification_count,
-                        );
-                    }
-                }
+        let peak = |counter: &CounterOnThread| {
+            counter
+                .counter
+                .samples
+                .iter()
+                .map(|sample| sample.value.abs())
+                .fold(0.0, f64::max)
+        };
+        let mut folded: HashSet<usize> = HashSet::new();
+        let mut others = Vec::new();
+        for (category, mut indexes) in by_category {
+            if indexes.len() <= max_tracks {
+                continue;
             }
+            indexes.sort_by(|a, b| peak(&self.counters[*b]).total_cmp(&peak(&self.counters[*a])));
+            let folded_indexes = &indexes[max_tracks.saturating_sub(1)..];
+            folded.extend(folded_indexes);
+            if max_tracks == 0 {
+                continue;
+            }
+            let folded_counters: Vec<Counter> = folded_indexes
+                .iter()
+                .map(|index| self.counters[*index].counter.clone())
+                .collect();
+            let Some(mut other) = Counter::sum(&folded_counters) else {
+                continue;
+            };
+            other.name = format!("Other {category}");
+            other.description = format!(
+                "The sum of {} {category} counters with lower peaks",
+                folded_counters.len()
+            );
+            others.push(CounterOnThread {
+                thread_handle: self.counters[folded_indexes[0]].thread_handle,
+                counter: other,
+            });
         }
-    }
-}
 
-#[derive(Debug, Clone)]
-pub struct RssStatMarker {
-    pub name: StringHandle,
-    pub total_bytes: i64,
-    pub delta_bytes: i64,
-}
+        let folded_count = folded.len();
+        self.counters = std::mem::take(&mut self.counters)
+            .into_iter()
+            .enumerate()
+            .filter(|(index, _)| !folded.contains(index))
+            .map(|(_, counter)| counter)
+            .chain(others)
+            .collect();
+        folded_count
+    }
 
-impl RssStatMarker {
-    pub fn new(name: StringHandle, total_bytes: i64, delta_bytes: i64) -> Self {
-        Self {
-            name,
-            total_bytes,
-            delta_bytes,
+    /// Add the sample rate and span busy counters if `options` ask for them,
+    /// cap the number of counter tracks, and give colliding counter colors
+    /// distinct ones.
+    fn finish_counters(&mut self, options: &FlushOptions) {
+        if let Some(window) = options.sample_rate_counter_window {
+            self.add_sample_rate_counter(window);
+        }
+        if options.span_busy_counters {
+            self.add_span_busy_counters();
+        }
+        if let Some(max_tracks) = options.max_counter_tracks {
+            let folded = self.cap_counter_tracks(max_tracks);
+            if folded > 0 {
+                log::info!("Folded {folded} counters into \"Other\" tracks");
+            }
+        }
+        for change in self.distinguish_counter_colors() {
+            log::info!("{change}");
         }
     }
-}
 
-impl StaticSchemaMarker for RssStatMarker {
-    const UNIQUE_MARKER_TYPE_NAME: &'static str = "RSS Anon";
+    /// Give counters whose track colors collide distinct colors, see
+    /// `distinguish_counter_colors`. Returns the changes.
+    fn distinguish_counter_colors(&mut self) -> Vec<CounterColorChange> {
+        distinguish_counter_colors(self.counters.iter_mut().map(|counter| &mut counter.counter))
+    }
 
-    fn schema() -> MarkerSchema {
-        MarkerSchema {
-            type_name: Self::UNIQUE_MARKER_TYPE_NAME.into(),
-            locations: vec![MarkerLocation::MarkerChart, MarkerLocation::MarkerTable],
-            chart_label: Some("{marker.data.totalBytes}".into()),
-            tooltip_label: Some("{marker.data.totalBytes}".into()),
-            table_label: Some(
-                "Total: {marker.data.totalBytes}, delta: {marker.data.deltaBytes}".into(),
-            ),
-            fields: vec![
-                MarkerFieldSchema {
-                    key: "totalBytes".into(),
-                    label: "Total bytes".into(),
-                    format: MarkerFieldFormat::Bytes,
-                    searchable: true,
-                },
-                MarkerFieldSchema {
-                    key: "deltaBytes".into(),
-                    label: "Delta".into(),
-                    format: MarkerFieldFormat::Bytes,
-                    searchable: true,
+    /// Add a "<category> busy" counter for each span category, whose value
+    /// at each span end is the busy time in milliseconds of all Total spans
+    /// of the category which have ended by then, to show when the cost of a
+    /// subsystem ramps up. The counters go on the thread of the first span
+    /// of their category.
+    fn add_span_busy_counters(&mut self) {
+        let mut spans = Vec::new();
+        let mut pending: Vec<_> = self
+            .markers
+            .iter()
+            .map(|marker| (marker.thread_handle, &marker.event_or_span))
+            .collect();
+        while let Some((thread_handle, marker)) = pending.pop() {
+            if let MarkerData::Span(span) = &marker.marker_data {
+                if span.span_type == SpanType::Total {
+                    spans.push((
+                        span.end_time,
+                        span.category.as_str(),
+                        span.timings.time_busy,
+                        thread_handle,
+                    ));
+                }
+                pending.extend(
+                    span.child_markers
+                        .iter()
+                        .map(|child| (thread_handle, child)),
+                );
+            }
+        }
+        spans.sort_by_key(|(end_time, category, ..)| (*end_time, *category));
+
+        let mut counters: BTreeMap<&str, (ThreadHandle, Duration, Vec<CounterSample>)> =
+            BTreeMap::new();
+        for (end_time, category, time_busy, thread_handle) in spans {
+            let (_, total_busy, samples) = counters
+                .entry(category)
+                .or_insert_with(|| (thread_handle, Duration::ZERO, Vec::new()));
+            *total_busy += time_busy;
+            samples.push(CounterSample {
+                timestamp: end_time,
+                value: total_busy.as_secs_f64() * 1000.0,
+                modification_count: 1,
+            });
+        }
+        for (category, (thread_handle, _, samples)) in counters {
+            self.counters.push(CounterOnThread {
+                thread_handle,
+                counter: Counter {
+                    name: format!("{category} busy"),
+                    category: CounterCategory::Custom,
+                    description: format!("Cumulative busy milliseconds of the {category} spans"),
+                    color: None,
+                    graph_type: MarkerGraphType::Line,
+                    is_cumulative: true,
+                    samples,
                 },
-            ],
-            static_fields: vec![MarkerStaticField {
-                label: "Description".into(),
-                value: "Emitted when the kmem:rss_stat tracepoint is hit.".into(),
-            }],
-            graphs: vec![],
+            });
         }
     }
 
-    fn name(&self, _profile: &mut Profile) -> StringHandle {
-        self.name
+    /// Sets the process's main thread, which gets the global phase markers.
+    pub fn set_main_thread(&mut self, main_thread: ThreadHandle) {
+        self.main_thread = Some(main_thread);
     }
 
-    fn category(&self, _profile: &mut Profile) -> CategoryHandle {
-        CategoryHandle::OTHER
+    pub fn main_thread(&self) -> Option<ThreadHandle> {
+        self.main_thread
     }
 
-    fn string_field_value(&self, _field_index: u32) -> StringHandle {
-        unreachable!()
+    pub fn process(&self) -> ProcessHandle {
+        self.process
     }
 
-    fn number_field_value(&self, field_index: u32) -> f64 {
-        match field_index {
-            0 => self.total_bytes as f64,
-            1 => self.delta_bytes as f64,
-            _ => unreachable!(),
+    /// The Total spans of `category`, including nested ones, as phases.
+    pub fn global_phases(&self, category: &str) -> Vec<GlobalPhase> {
+        let mut phases = Vec::new();
+        let mut pending: Vec<&EventOrSpanMarker> = self
+            .markers
+            .iter()
+            .map(|marker| &marker.event_or_span)
+            .collect();
+        while let Some(marker) = pending.pop() {
+            if let MarkerData::Span(span) = &marker.marker_data {
+                if span.span_type == SpanType::Total && marker.category_matches(category) {
+                    phases.push(GlobalPhase {
+                        name: marker.message.clone(),
+                        start_time: marker.start_time,
+                        end_time: span.end_time,
+                    });
+                }
+                pending.extend(&span.child_markers);
+            }
         }
+        phases
     }
-}
-
-#[derive(Debug, Clone)]
-pub struct OtherEventMarker(pub StringHandle);
-
-impl StaticSchemaMarker for OtherEventMarker {
-    const UNIQUE_MARKER_TYPE_NAME: &'static str = "Other event";
 
-    fn schema() -> MarkerSchema {
-        MarkerSchema {
-            type_name: Self::UNIQUE_MARKER_TYPE_NAME.into(),
-            locations: vec![MarkerLocation::MarkerChart, MarkerLocation::MarkerTable],
-            chart_label: None,
-            tooltip_label: None,
-            table_label: None,
-            fields: vec![],
-            static_fields: vec![MarkerStaticField {
-                label: "Description".into(),
-                value:
-                    "Emitted for any records in a perf.data file which don't map to a known event."
-                        .into(),
-            }],
-            graphs: vec![],
-        }
+    /// The samples of this process and the stacks of its cross-process
+    /// edges, which are all the stacks it refers to, see
+    /// `UnresolvedStacks::gc`.
+    pub fn stack_refs_mut(
+        &mut self,
+    ) -> (
+        &mut UnresolvedSamples,
+        impl Iterator<Item = &mut UnresolvedStackHandle>,
+    ) {
+        let edge_stacks = self
+            .cross_process_edges
+            .iter_mut()
+            .flat_map(|edge| [&mut edge.from_stack, &mut edge.to_stack]);
+        (&mut self.unresolved_samples, edge_stacks)
     }
 
-    fn name(&self, _profile: &mut Profile) -> StringHandle {
-        self.0
+    /// Add an edge which has at least one end in this process. Only the
+    /// ends in this process get a marker, because the stacks are resolved
+    /// with this process's libraries; for an edge between two processes,
+    /// add it to the data of both.
+    pub fn add_cross_process_edge(&mut self, edge: CrossProcessEdge) {
+        self.cross_process_edges.push(edge);
     }
 
-    fn category(&self, _profile: &mut Profile) -> CategoryHandle {
-        CategoryHandle::OTHER
-    }
+    /// Add the counters which `options` ask for, then add all data of this
+    /// process to `profile`.
+    ///
+    /// This is for data which is flushed in one go. Data of a process which
+    /// is flushed in several steps must go through one `ProcessProfileBuilder`,
+    /// after `prepare_for_flush`.
+    pub fn flush_samples_to_profile(
+        self,
+        profile: &mut Profile,
+        user_category: CategoryPairHandle,
+        kernel_category: CategoryPairHandle,
+        stack_frame_scratch_buf: &mut Vec<StackFrame>,
+        stacks: &UnresolvedStacks,
+        options: &FlushOptions,
+    ) {
+        let data = self.prepare_for_flush(profile, stacks, options);
+        let mut builder =
+            ProcessProfileBuilder::new(data.process, user_category, kernel_category, options);
+        builder.add_pending(data);
+        builder.flush_pending(profile, stacks, stack_frame_scratch_buf);
+    }
+
+    /// Apply the parts of `options` which work on the data as a whole: the
+    /// downsampling, the folded stacks and top functions output, and the
+    /// counters which are computed from the samples.
+    pub fn prepare_for_flush(
+        mut self,
+        profile: &Profile,
+        stacks: &UnresolvedStacks,
+        options: &FlushOptions,
+    ) -> Self {
+        if let Some(factor) = options.downsample_factor {
+            self = self.downsample(factor);
+        }
+        if let Some(folded_stacks_file) = &options.folded_stacks_file {
+            let folded: String = self
+                .to_folded_stacks(stacks, options.aslr_tolerance, |lib| {
+                    profile.library_info(lib).name.clone()
+                })
+                .into_iter()
+                .map(|(stack, weight)| format!("{stack} {weight}\n"))
+                .collect();
+            let mut file = folded_stacks_file.lock().unwrap();
+            if let Err(e) = file.write_all(folded.as_bytes()) {
+                eprintln!("Couldn't write the folded stacks: {e}");
+            }
+        }
+        if let Some(n) = options.top_functions {
+            let top = self.top_functions(
+                stacks,
+                options.aslr_tolerance,
+                |lib| profile.library_info(lib).name.clone(),
+                n,
+            );
+            if !top.is_empty() {
+                println!("Top functions by self weight:");
+                for (name, weight) in top {
+                    println!("  {weight:>10}  {name}");
+                }
+            }
+        }
+        self.finish_counters(options);
+        self
+    }
+
+    /// Resolve the samples' stacks without a `Profile`, and return them in the
+    /// "collapsed" format used by flamegraph tools: one entry per distinct
+    /// stack, with the frames from root to leaf joined by `;`, and the summed
+    /// sample weight. Entries are sorted by stack.
+    ///
+    /// Since there are no symbols, frames are addresses: `name+0x1234` for an
+    /// address inside a library, with the relative address and the name which
+    /// `lib_name` gives the library, and `0x7f001234` for an address which
+    /// isn't in any library. `aslr_tolerance` is as in `FlushOptions`.
+    pub fn to_folded_stacks(
+        &self,
+        stacks: &UnresolvedStacks,
+        aslr_tolerance: Option<u64>,
+        lib_name: impl Fn(LibraryHandle) -> String,
+    ) -> Vec<(String, u64)> {
+        let mut folded: Vec<(String, u64)> = self
+            .named_stack_weights(stacks, aslr_tolerance, lib_name)
+            .into_iter()
+            .map(|(names, weight)| (names.join(";"), weight))
+            .collect();
+        folded.sort();
+        folded
+    }
+
+    /// Return the `n` functions with the highest self weight, i.e. the summed
+    /// weight of the samples whose leaf frame is that function, hottest first.
+    /// Ties are broken by name. Frames are named as in `to_folded_stacks`.
+    pub fn top_functions(
+        &self,
+        stacks: &UnresolvedStacks,
+        aslr_tolerance: Option<u64>,
+        lib_name: impl Fn(LibraryHandle) -> String,
+        n: usize,
+    ) -> Vec<(String, u64)> {
+        let mut self_weights: HashMap<String, u64> = HashMap::new();
+        for (mut names, weight) in self.named_stack_weights(stacks, aslr_tolerance, lib_name) {
+            if let Some(leaf) = names.pop() {
+                *self_weights.entry(leaf).or_default() += weight;
+            }
+        }
+        let mut top: Vec<(String, u64)> = self_weights.into_iter().collect();
+        top.sort_by(|(name_a, weight_a), (name_b, weight_b)| {
+            weight_b.cmp(weight_a).then_with(|| name_a.cmp(name_b))
+        });
+        top.truncate(n);
+        top
+    }
+
+    /// Resolve each sample's stack against the library mappings, and return
+    /// the distinct stacks, as frame names from root to leaf, with their
+    /// summed weight. `aslr_tolerance` and `lib_name` are as in
+    /// `to_folded_stacks`.
+    fn named_stack_weights(
+        &self,
+        stacks: &UnresolvedStacks,
+        aslr_tolerance: Option<u64>,
+        lib_name: impl Fn(LibraryHandle) -> String,
+    ) -> Vec<(Vec<String>, u64)> {
+        let mut lib_mappings_hierarchy = Self::make_lib_mappings_hierarchy(
+            self.regular_lib_mapping_op_queue.clone(),
+            self.jitdump_lib_mapping_op_queues.clone(),
+            self.perf_map_mappings.clone(),
+        );
+        lib_mappings_hierarchy.set_aslr_tolerance(aslr_tolerance);
+        let category = CategoryHandle::OTHER.into();
+        let mut stack_converter = StackConverter::new(category, category);
+        let mut stack_frame_scratch_buf = Vec::new();
+        let mut weights: BTreeMap<Vec<Frame>, u64> = BTreeMap::new();
+        self.unresolved_samples.for_each_chunk(|chunk| {
+            for sample in chunk {
+                let SampleOrMarker::Sample(SampleData { weight, .. }) = sample.sample_or_marker
+                else {
+                    continue;
+                };
+                lib_mappings_hierarchy.process_ops(sample.timestamp_mono);
+                stack_frame_scratch_buf.clear();
+                stacks.convert_back(sample.stack, &mut stack_frame_scratch_buf);
+                let frames = stack_converter
+                    .convert_stack(
+                        &stack_frame_scratch_buf,
+                        &lib_mappings_hierarchy,
+                        None,
+                        None,
+                    )
+                    .map(|frame_info| frame_info.frame)
+                    .collect();
+                *weights.entry(frames).or_default() += weight.max(0) as u64;
+            }
+        });
+
+        let mut lib_names: HashMap<LibraryHandle, String> = HashMap::new();
+        let mut frame_name = |frame: &Frame| match frame {
+            Frame::InstructionPointer(address)
+            | Frame::ReturnAddress(address)
+            | Frame::AdjustedReturnAddress(address) => format!("{address:#x}"),
+            Frame::RelativeAddressFromInstructionPointer(lib, address)
+            | Frame::RelativeAddressFromReturnAddress(lib, address)
+            | Frame::RelativeAddressFromAdjustedReturnAddress(lib, address) => {
+                let name = lib_names.entry(*lib).or_insert_with(|| lib_name(*lib));
+                format!("{name}+{address:#x}")
+            }
+            Frame::Label(_) => "[label]".to_string(),
+        };
+
+        weights
+            .into_iter()
+            .map(|(frames, weight)| (frames.iter().map(&mut frame_name).collect(), weight))
+            .collect()
+    }
+
+    fn make_lib_mappings_hierarchy(
+        regular_lib_mapping_op_queue: LibMappingOpQueue,
+        jitdump_lib_mapping_op_queues: Vec<LibMappingOpQueue>,
+        perf_map_mappings: Option<LibMappings<LibMappingInfo>>,
+    ) -> LibMappingsHierarchy {
+        let mut lib_mappings_hierarchy = LibMappingsHierarchy::new(regular_lib_mapping_op_queue);
+        for jitdump_lib_mapping_ops in jitdump_lib_mapping_op_queues {
+            lib_mappings_hierarchy.add_jitdump_lib_mappings_ops(jitdump_lib_mapping_ops);
+        }
+        if let Some(perf_map_mappings) = perf_map_mappings {
+            lib_mappings_hierarchy.add_perf_map_mappings(perf_map_mappings);
+        }
+        lib_mappings_hierarchy
+    }
+
+    /// Move nested `child_markers` out of their parent spans, so that every
+    /// marker is added to the profile. Each child gets a `parent_id` field, and
+    /// each span with children gets a `span_id` field with the same value.
+    fn flatten_marker_tree(markers: Vec<MarkerOnThread>) -> Vec<MarkerOnThread> {
+        let mut flattened = Vec::with_capacity(markers.len());
+        let mut stack: Vec<_> = markers.into_iter().rev().collect();
+        while let Some(mut marker) = stack.pop() {
+            if let MarkerData::Span(span) = &mut marker.event_or_span.marker_data {
+                if !span.child_markers.is_empty() {
+                    let span_id = span.id.to_string();
+                    let children = std::mem::take(&mut span.child_markers);
+                    marker
+                        .event_or_span
+                        .extra_fields
+                        .insert("span_id".to_string(), span_id.clone());
+                    stack.extend(children.into_iter().rev().map(|mut child| {
+                        child
+                            .extra_fields
+                            .insert("parent_id".to_string(), span_id.clone());
+                        MarkerOnThread {
+                            thread_handle: marker.thread_handle,
+                            event_or_span: child,
+                        }
+                    }));
+                }
+            }
+            flattened.push(marker);
+        }
+        flattened
+    }
+
+    /// Rename counters whose name is already taken by an earlier counter of
+    /// this process, by appending " (2)", " (3)" etc., so that their tracks
+    /// can be told apart.
+    fn disambiguate_counter_names(counters: &mut [CounterOnThread]) {
+        let mut used_names: HashSet<String> = HashSet::new();
+        for CounterOnThread { counter, .. } in counters {
+            if used_names.insert(counter.name.clone()) {
+                continue;
+            }
+            let unique_name = (2..)
+                .map(|n| format!("{} ({n})", counter.name))
+                .find(|name| !used_names.contains(name))
+                .unwrap();
+            warn!(
+                "There are multiple counters named \"{}\", renaming one of them to \"{unique_name}\"",
+                counter.name
+            );
+            used_names.insert(unique_name.clone());
+            counter.name = unique_name;
+        }
+    }
+
+    /// Add a profile category for each span category used by `markers` which
+    /// isn't in `category_handles` yet.
+    ///
+    /// Categories are added in name order and colored by a hash of their name,
+    /// unless `colors` has a color for them, so that the same workload produces
+    /// the same categories and colors on every run. The name to color
+    /// assignment is also recorded in the profile metadata.
+    fn add_span_categories(
+        profile: &mut Profile,
+        markers: &[MarkerOnThread],
+        colors: &HashMap<String, CategoryColor>,
+        category_handles: &mut HashMap<String, CategoryHandle>,
+    ) {
+        let names: BTreeSet<&str> = markers
+            .iter()
+            .filter_map(|marker| match &marker.event_or_span.marker_data {
+                MarkerData::Span(span) => Some(span.category.as_str()),
+                MarkerData::Event => None,
+            })
+            .filter(|name| !category_handles.contains_key(*name))
+            .collect();
+        if names.is_empty() {
+            return;
+        }
+
+        let mut colors_meta = match profile.extra_meta_field(SPAN_CATEGORY_COLORS_META_KEY) {
+            Some(serde_json::Value::Object(map)) => map.clone(),
+            _ => serde_json::Map::new(),
+        };
+        for name in names {
+            let color = colors
+                .get(name)
+                .copied()
+                .unwrap_or_else(|| span_category_color(name));
+            category_handles.insert(name.to_string(), profile.add_category(name, color));
+            colors_meta.insert(name.to_string(), serde_json::to_value(color).unwrap());
+        }
+        profile.set_extra_meta_field(SPAN_CATEGORY_COLORS_META_KEY, colors_meta.into());
+    }
+
+    /// For each thread, collect the spans whose category is one of `span_categories`,
+    /// so that samples taken during those spans can be given the span's category.
+    fn collect_span_intervals(
+        markers: &[MarkerOnThread],
+        span_categories: &[String],
+        category_handles: &HashMap<String, CategoryHandle>,
+        intervals_per_thread: &mut HashMap<ThreadHandle, Vec<SpanInterval>>,
+    ) {
+        for marker in markers {
+            let MarkerData::Span(span) = &marker.event_or_span.marker_data else {
+                continue;
+            };
+            if !span_categories
+                .iter()
+                .any(|category| marker.event_or_span.category_matches(category))
+            {
+                continue;
+            }
+            let category = category_handles[&span.category];
+            intervals_per_thread
+                .entry(marker.thread_handle)
+                .or_default()
+                .push((
+                    marker.event_or_span.start_time,
+                    span.end_time,
+                    category.into(),
+                ));
+        }
+    }
+}
+
+type SpanInterval = (Timestamp, Timestamp, CategoryPairHandle);
+
+/// The most tracks which the global phases are copied onto, so that
+/// recordings of many processes don't get a marker per phase and process.
+const MAX_GLOBAL_PHASE_TRACKS: usize = 64;
+
+/// A phase of the whole recording, e.g. a GC or a compaction, which is shown
+/// on every track, see `--global-phase-category`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GlobalPhase {
+    pub name: String,
+    pub start_time: Timestamp,
+    pub end_time: Timestamp,
+}
+
+/// The global phases in the `category` spans of the first process which has
+/// any, the designated process. Phases with the same name which overlap,
+/// e.g. because the process reported a phase twice, become one phase.
+pub fn collect_global_phases(
+    process_sample_datas: &[ProcessSampleData],
+    category: &str,
+) -> Vec<GlobalPhase> {
+    let mut phases = process_sample_datas
+        .iter()
+        .map(|data| data.global_phases(category))
+        .find(|phases| !phases.is_empty())
+        .unwrap_or_default();
+    phases.sort_by(|a, b| (a.name.as_str(), a.start_time).cmp(&(b.name.as_str(), b.start_time)));
+    let mut deduplicated: Vec<GlobalPhase> = Vec::with_capacity(phases.len());
+    for phase in phases {
+        match deduplicated.last_mut() {
+            Some(last) if last.name == phase.name && phase.start_time <= last.end_time => {
+                last.end_time = last.end_time.max(phase.end_time);
+            }
+            _ => deduplicated.push(phase),
+        }
+    }
+    deduplicated.sort_by_key(|phase| phase.start_time);
+    deduplicated
+}
+
+/// Warns about each of `requested`, the categories which options like
+/// `--categorize-samples-by-span` refer to, which none of the markers of
+/// `process_sample_datas` belongs to, e.g. because of a typo. Categories
+/// match as in `EventOrSpanMarker::category_matches`.
+pub fn warn_about_missing_categories<'a>(
+    process_sample_datas: &[ProcessSampleData],
+    requested: impl IntoIterator<Item = &'a str>,
+) {
+    let present: BTreeSet<String> = process_sample_datas
+        .iter()
+        .flat_map(ProcessSampleData::categories)
+        .collect();
+    for category in requested {
+        let is_present = present.iter().any(|present| {
+            present == category
+                || present
+                    .strip_prefix(category)
+                    .is_some_and(|rest| rest.starts_with("::"))
+        });
+        if !is_present {
+            warn!("None of the markers has the category {category:?}");
+        }
+    }
+}
+
+/// Adds a "Global phase" marker for each phase to each of `threads`, e.g.
+/// the main thread of every process and the per-CPU tracks, because the
+/// profiler has no bands which span all tracks. Only the first
+/// `MAX_GLOBAL_PHASE_TRACKS` threads get the markers.
+pub fn add_global_phase_markers(
+    profile: &mut Profile,
+    phases: &[GlobalPhase],
+    threads: &[ThreadHandle],
+) {
+    if phases.is_empty() {
+        return;
+    }
+    let mut target_threads: Vec<ThreadHandle> = Vec::new();
+    for thread in threads {
+        if !target_threads.contains(thread) {
+            target_threads.push(*thread);
+        }
+    }
+    if target_threads.len() > MAX_GLOBAL_PHASE_TRACKS {
+        warn!(
+            "Only showing the global phases on {MAX_GLOBAL_PHASE_TRACKS} of {} tracks",
+            target_threads.len()
+        );
+        target_threads.truncate(MAX_GLOBAL_PHASE_TRACKS);
+    }
+    let category = profile.add_category("Global phase", CategoryColor::DarkGray);
+    for phase in phases {
+        let name = profile.intern_string(&phase.name);
+        for thread in &target_threads {
+            profile.add_marker(
+                *thread,
+                MarkerTiming::Interval(phase.start_time, phase.end_time),
+                GlobalPhaseMarker { name, category },
+            );
+        }
+    }
+}
+
+/// Converts the data of one process into the profile, in one or more steps:
+/// data is added with [`add_pending`](Self::add_pending), and
+/// [`flush_pending`](Self::flush_pending) moves everything which was added
+/// since the last flush into the profile.
+///
+/// The library mappings, and everything which must only be created once per
+/// profile, such as marker types, categories and counter tracks, are kept
+/// across flushes, so flushing in several steps gives the same profile as
+/// flushing all data at once.
+pub struct ProcessProfileBuilder {
+    process: ProcessHandle,
+    user_category: CategoryPairHandle,
+    options: FlushOptions,
+    /// The track of each marker category, if `category_marker_tracks` is on.
+    category_tracks: HashMap<String, ThreadHandle>,
+    lib_mappings_hierarchy: LibMappingsHierarchy,
+    stack_converter: StackConverter,
+    /// The span intervals which later samples can still fall into. Intervals
+    /// which end before the last flushed sample are removed after each
+    /// flush, so that the index doesn't grow with the recording.
+    span_intervals_per_thread: HashMap<ThreadHandle, SpanIntervalIndex<CategoryPairHandle>>,
+    span_categories: HashMap<String, CategoryHandle>,
+    logging_category: Option<CategoryHandle>,
+    level_categories: BTreeMap<EventLevel, CategoryHandle>,
+    unknown_level_category: Option<CategoryHandle>,
+    span_marker_types: HashMap<String, MarkerTypeHandle>,
+    event_marker_types: HashMap<String, MarkerTypeHandle>,
+    custom_counter_marker_types: HashMap<String, MarkerTypeHandle>,
+    counter_handles: HashMap<String, CounterHandle>,
+    pending_samples: Vec<SampleChunks>,
+    pending_markers: Vec<MarkerOnThread>,
+    pending_counters: Vec<CounterOnThread>,
+    pending_cross_process_edges: Vec<CrossProcessEdge>,
+}
+
+impl ProcessProfileBuilder {
+    pub fn new(
+        process: ProcessHandle,
+        user_category: CategoryPairHandle,
+        kernel_category: CategoryPairHandle,
+        options: &FlushOptions,
+    ) -> Self {
+        let mut lib_mappings_hierarchy = LibMappingsHierarchy::new(LibMappingOpQueue::default());
+        lib_mappings_hierarchy.set_aslr_tolerance(options.aslr_tolerance);
+        Self {
+            process,
+            user_category,
+            options: options.clone(),
+            category_tracks: HashMap::new(),
+            lib_mappings_hierarchy,
+            stack_converter: StackConverter::new(user_category, kernel_category),
+            span_intervals_per_thread: HashMap::new(),
+            span_categories: HashMap::new(),
+            logging_category: None,
+            level_categories: BTreeMap::new(),
+            unknown_level_category: None,
+            span_marker_types: HashMap::new(),
+            event_marker_types: HashMap::new(),
+            custom_counter_marker_types: HashMap::new(),
+            counter_handles: HashMap::new(),
+            pending_samples: Vec::new(),
+            pending_markers: Vec::new(),
+            pending_counters: Vec::new(),
+            pending_cross_process_edges: Vec::new(),
+        }
+    }
+
+    /// Queue the data in `data` for the next flush. The data must be from
+    /// this builder's process, and not be earlier than previously added data.
+    /// The library mapping ops of `data` continue the previously added ones;
+    /// its i-th jitdump queue continues the i-th jitdump.
+    pub fn add_pending(&mut self, data: ProcessSampleData) {
+        let ProcessSampleData {
+            unresolved_samples,
+            regular_lib_mapping_op_queue,
+            jitdump_lib_mapping_op_queues,
+            perf_map_mappings,
+            markers,
+            counters,
+            cross_process_edges,
+            process,
+            ..
+        } = data;
+        debug_assert_eq!(process, self.process);
+        self.lib_mappings_hierarchy
+            .extend_ops(regular_lib_mapping_op_queue, jitdump_lib_mapping_op_queues);
+        if let Some(perf_map_mappings) = perf_map_mappings {
+            self.lib_mappings_hierarchy
+                .add_perf_map_mappings(perf_map_mappings);
+        }
+        self.pending_samples.push(unresolved_samples.into_chunks());
+        self.pending_markers.extend(markers);
+        self.pending_counters.extend(counters);
+        self.pending_cross_process_edges.extend(cross_process_edges);
+    }
+
+    /// Add the samples, markers and counters which were added since the
+    /// last flush to the profile.
+    pub fn flush_pending(
+        &mut self,
+        profile: &mut Profile,
+        stacks: &UnresolvedStacks,
+        stack_frame_scratch_buf: &mut Vec<StackFrame>,
+    ) {
+        let markers =
+            ProcessSampleData::flatten_marker_tree(std::mem::take(&mut self.pending_markers));
+        ProcessSampleData::add_span_categories(
+            profile,
+            &markers,
+            &self.options.span_category_colors,
+            &mut self.span_categories,
+        );
+        if !self.options.span_sample_categories.is_empty() {
+            let mut new_intervals = HashMap::new();
+            ProcessSampleData::collect_span_intervals(
+                &markers,
+                &self.options.span_sample_categories,
+                &self.span_categories,
+                &mut new_intervals,
+            );
+            for (thread, intervals) in new_intervals {
+                self.span_intervals_per_thread
+                    .entry(thread)
+                    .or_insert_with(|| SpanIntervalIndex::new(Vec::new()))
+                    .extend(intervals);
+            }
+        }
+        let mut edge_markers = self
+            .add_cross_process_edge_markers(profile)
+            .into_iter()
+            .peekable();
+
+        // The samples are read back one spilled chunk at a time, and the edge
+        // markers are slotted in after the samples which aren't later than
+        // them. They stand in for their raw timestamp with the one of the
+        // latest sample before them, for the library lookups.
+        let mut last_sample_time = None;
+        let mut prev_timestamp_mono = 0;
+        let samples = std::mem::take(&mut self.pending_samples)
+            .into_iter()
+            .flatten()
+            .flatten();
+        for sample in samples {
+            while let Some(mut edge_marker) =
+                edge_markers.next_if(|edge_marker| edge_marker.timestamp < sample.timestamp)
+            {
+                edge_marker.timestamp_mono = prev_timestamp_mono;
+                self.flush_sample(profile, stacks, stack_frame_scratch_buf, edge_marker);
+            }
+            prev_timestamp_mono = sample.timestamp_mono;
+            last_sample_time = last_sample_time.max(Some(sample.timestamp));
+            self.flush_sample(profile, stacks, stack_frame_scratch_buf, sample);
+        }
+        for mut edge_marker in edge_markers {
+            edge_marker.timestamp_mono = prev_timestamp_mono;
+            last_sample_time = last_sample_time.max(Some(edge_marker.timestamp));
+            self.flush_sample(profile, stacks, stack_frame_scratch_buf, edge_marker);
+        }
+
+        // Later samples aren't earlier than the flushed ones.
+        if let Some(last_sample_time) = last_sample_time {
+            self.span_intervals_per_thread.retain(|_, index| {
+                index.remove_ended_before(last_sample_time);
+                !index.is_empty()
+            });
+        }
+
+        self.flush_markers(profile, markers);
+        self.flush_counters(profile);
+    }
+
+    fn flush_sample(
+        &mut self,
+        profile: &mut Profile,
+        stacks: &UnresolvedStacks,
+        stack_frame_scratch_buf: &mut Vec<StackFrame>,
+        sample: UnresolvedSampleOrMarker,
+    ) {
+        self.lib_mappings_hierarchy
+            .process_ops(sample.timestamp_mono);
+        let UnresolvedSampleOrMarker {
+            thread_handle,
+            timestamp,
+            stack,
+            sample_or_marker,
+            extra_label_frame,
+            ..
+        } = sample;
+
+        let user_category_override = self
+            .span_intervals_per_thread
+            .get(&thread_handle)
+            .and_then(|index| index.lookup(timestamp));
+
+        stack_frame_scratch_buf.clear();
+        stacks.convert_back(stack, stack_frame_scratch_buf);
+        let frames = self.stack_converter.convert_stack(
+            stack_frame_scratch_buf,
+            &self.lib_mappings_hierarchy,
+            extra_label_frame,
+            user_category_override,
+        );
+        let frames = StackDepthLimitingFrameIter::new(profile, frames, self.user_category);
+        match sample_or_marker {
+            SampleOrMarker::Sample(SampleData { cpu_delta, weight }) => {
+                profile.add_sample(thread_handle, timestamp, frames, cpu_delta, weight);
+            }
+            SampleOrMarker::MarkerHandle(mh) => {
+                profile.set_marker_stack(thread_handle, mh, frames);
+            }
+        }
+    }
+
+    /// Adds a marker for each end of the pending edges which is in this
+    /// process, and returns its stack as a marker stack sorted by time, to be
+    /// flushed among the pending samples, so that it's resolved with the
+    /// libraries which were loaded at the edge's time. The raw timestamps are
+    /// left for `flush_pending` to fill in.
+    fn add_cross_process_edge_markers(
+        &mut self,
+        profile: &mut Profile,
+    ) -> Vec<UnresolvedSampleOrMarker> {
+        let mut edge_markers = Vec::new();
+        for edge in std::mem::take(&mut self.pending_cross_process_edges) {
+            let ends = [
+                (edge.from_thread, edge.from_stack, "IPC send", edge.to_pid),
+                (edge.to_thread, edge.to_stack, "IPC receive", edge.from_pid),
+            ];
+            for (thread_handle, stack, name, other_pid) in ends {
+                if profile.thread_process(thread_handle) != self.process {
+                    continue;
+                }
+                let name = profile.intern_string(name);
+                let marker_handle = profile.add_marker(
+                    thread_handle,
+                    MarkerTiming::Instant(edge.timestamp),
+                    CrossProcessEdgeMarker { name, other_pid },
+                );
+                edge_markers.push(UnresolvedSampleOrMarker {
+                    thread_handle,
+                    timestamp: edge.timestamp,
+                    timestamp_mono: 0,
+                    stack,
+                    extra_label_frame: None,
+                    sample_or_marker: SampleOrMarker::MarkerHandle(marker_handle),
+                });
+            }
+        }
+        edge_markers.sort_by_key(|edge_marker| edge_marker.timestamp);
+        edge_markers
+    }
+
+    fn flush_markers(&mut self, profile: &mut Profile, markers: Vec<MarkerOnThread>) {
+        let logging_category = *self
+            .logging_category
+            .get_or_insert_with(|| profile.add_category("(Logging)", CategoryColor::Green));
+
+        let first_marker_time = markers.iter().map(|m| m.event_or_span.start_time).min();
+        let mut stats = MarkerStats::new();
+        let stats_flush_interval_ns = self
+            .options
+            .stats_flush_interval
+            .map(|interval| (interval.as_nanos() as u64).max(1));
+        let mut stats_window_start = first_marker_time.map_or(0, |t| t.as_nanos_since_reference());
+        for mut marker in markers {
+            if let Some(interval_ns) = stats_flush_interval_ns {
+                let start_ns = marker.event_or_span.start_time.as_nanos_since_reference();
+                if start_ns >= stats_window_start + interval_ns {
+                    let window_count = (start_ns - stats_window_start) / interval_ns;
+                    let window_end = stats_window_start + window_count * interval_ns;
+                    let flushed = stats.reset();
+                    if !flushed.is_empty() {
+                        println!(
+                            "Span stats from {:.3}s to {:.3}s:",
+                            stats_window_start as f64 / 1e9,
+                            window_end as f64 / 1e9
+                        );
+                        flushed.dump();
+                    }
+                    stats_window_start = window_end;
+                }
+            }
+            if self.options.category_marker_tracks {
+                marker.thread_handle = self.category_track(
+                    profile,
+                    &marker.event_or_span,
+                    first_marker_time.unwrap_or(marker.event_or_span.start_time),
+                );
+            }
+            stats.process_span(&marker.event_or_span);
+            let mut extra_fields = marker.event_or_span.extra_fields.clone();
+            if let Some(trace_context) = &marker.event_or_span.trace_context {
+                extra_fields.insert(TRACE_ID_FIELD.to_string(), trace_context.trace_id_hex());
+            }
+            let mut extra_fields: Vec<_> = extra_fields.into_iter().collect();
+            extra_fields.sort_by_key(|(k, _)| k.clone());
+
+            let (field_names, field_values): (Vec<_>, Vec<_>) = extra_fields.into_iter().unzip();
+            let field_types: Vec<_> = field_names
+                .iter()
+                .map(|name| marker.event_or_span.field_type(name))
+                .collect();
+            let marker_typename = extra_fields_type_name(&field_names, &field_types);
+
+            match &marker.event_or_span.marker_data {
+                MarkerData::Event => {
+                    let severity_color = marker.event_or_span.severity_color();
+                    let category = match marker.event_or_span.level {
+                        Some(level) => *self.level_categories.entry(level).or_insert_with(|| {
+                            profile.add_category(
+                                &format!("(Logging) {}", level.as_str()),
+                                severity_color,
+                            )
+                        }),
+                        None if marker.event_or_span.extra_fields.contains_key("level") => {
+                            *self.unknown_level_category.get_or_insert_with(|| {
+                                profile.add_category("(Logging) other", severity_color)
+                            })
+                        }
+                        None => logging_category,
+                    };
+                    let timing = MarkerTiming::Instant(marker.event_or_span.start_time);
+
+                    if self.options.simple_event_markers {
+                        let log_marker = LogMarker::new(profile, category, &marker);
+                        profile.add_marker(marker.thread_handle, timing, log_marker);
+                        continue;
+                    }
+
+                    let marker_type = self
+                        .event_marker_types
+                        .entry(marker_typename.clone())
+                        .or_insert_with(|| {
+                            EventMarker::create_marker_type(profile, (&field_names, &field_types))
+                        });
+                    let span_marker = EventMarker::new(
+                        profile,
+                        &category,
+                        &marker,
+                        marker_type,
+                        (&field_names, &field_types, &field_values),
+                        self.options.max_marker_string_len,
+                    );
+                    profile.add_marker(marker.thread_handle, timing, span_marker);
+                }
+                MarkerData::Span(span) => {
+                    let marker_type = self
+                        .span_marker_types
+                        .entry(marker_typename.clone())
+                        .or_insert_with(|| {
+                            SpanMarkerWithTimings::create_marker_type(
+                                profile,
+                                (&field_names, &field_types),
+                                &self.options.span_table_label,
+                            )
+                        });
+
+                    let span_marker = SpanMarkerWithTimings::new(
+                        profile,
+                        &marker,
+                        span,
+                        &self.span_categories,
+                        marker_type,
+                        (&field_names, &field_types, &field_values),
+                        self.options.max_marker_string_len,
+                    );
+                    profile.add_marker(
+                        marker.thread_handle,
+                        MarkerTiming::Interval(marker.event_or_span.start_time, span.end_time),
+                        span_marker,
+                    );
+                }
+            }
+        }
+        if !stats.is_empty() {
+            if stats_flush_interval_ns.is_some() {
+                println!("Span stats from {:.3}s:", stats_window_start as f64 / 1e9);
+            }
+            stats.dump();
+        }
+    }
+
+    /// The track for the markers of `marker`'s category: the span category
+    /// for spans, and the target for events. The track is a thread of its own
+    /// in this builder's process, named after the category, and is created
+    /// when the first marker of the category is flushed.
+    fn category_track(
+        &mut self,
+        profile: &mut Profile,
+        marker: &EventOrSpanMarker,
+        start_time: Timestamp,
+    ) -> ThreadHandle {
+        let category = marker.category();
+        if let Some(thread) = self.category_tracks.get(category) {
+            return *thread;
+        }
+        let thread = profile.add_thread(self.process, 0, start_time, false);
+        profile.set_thread_name(
+            thread,
+            &self.options.track_naming.category_track_name(category),
+        );
+        self.category_tracks.insert(category.to_string(), thread);
+        thread
+    }
+
+    /// Counters which have the same name after disambiguation as a counter
+    /// from an earlier flush continue that counter's track.
+    fn flush_counters(&mut self, profile: &mut Profile) {
+        let mut counters = std::mem::take(&mut self.pending_counters);
+        ProcessSampleData::disambiguate_counter_names(&mut counters);
+        for CounterOnThread {
+            counter,
+            thread_handle,
+        } in counters
+        {
+            match counter.category {
+                CounterCategory::Custom => {
+                    let marker_type = *self
+                        .custom_counter_marker_types
+                        .entry(counter.name.clone())
+                        .or_insert_with(|| {
+                            CustomGraphMarker::create_marker_type(profile, &counter)
+                        });
+
+                    for sample in counter.samples {
+                        let marker = CustomGraphMarker::new(
+                            profile.intern_string(&counter.name),
+                            CategoryHandle::OTHER,
+                            marker_type,
+                            sample.value,
+                        );
+
+                        profile.add_marker(
+                            thread_handle,
+                            MarkerTiming::Instant(sample.timestamp),
+                            marker,
+                        );
+                    }
+                }
+                _ => {
+                    let process = self.process;
+                    let color = counter.track_color();
+                    let counter_handle = *self
+                        .counter_handles
+                        .entry(counter.name.clone())
+                        .or_insert_with(|| {
+                            profile.add_counter(
+                                process,
+                                &counter.name,
+                                counter.category.into(),
+                                &counter.description,
+                                color,
+                            )
+                        });
+
+                    for sample in counter.samples {
+                        profile.add_counter_sample(
+                            counter_handle,
+                            sample.timestamp,
+                            sample.value,
+                            sample.modification_count,
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RssStatMarker {
+    pub name: StringHandle,
+    pub total_bytes: i64,
+    pub delta_bytes: i64,
+}
+
+impl RssStatMarker {
+    pub fn new(name: StringHandle, total_bytes: i64, delta_bytes: i64) -> Self {
+        Self {
+            name,
+            total_bytes,
+            delta_bytes,
+        }
+    }
+}
+
+impl StaticSchemaMarker for RssStatMarker {
+    const UNIQUE_MARKER_TYPE_NAME: &'static str = "RSS Anon";
+
+    fn schema() -> MarkerSchema {
+        MarkerSchema {
+            type_name: Self::UNIQUE_MARKER_TYPE_NAME.into(),
+            locations: vec![MarkerLocation::MarkerChart, MarkerLocation::MarkerTable],
+            chart_label: Some("{marker.data.totalBytes}".into()),
+            tooltip_label: Some("{marker.data.totalBytes}".into()),
+            table_label: Some(
+                "Total: {marker.data.totalBytes}, delta: {marker.data.deltaBytes}".into(),
+            ),
+            fields: vec![
+                MarkerFieldSchema {
+                    key: "totalBytes".into(),
+                    label: "Total bytes".into(),
+                    format: MarkerFieldFormat::Bytes,
+                    searchable: true,
+                },
+                MarkerFieldSchema {
+                    key: "deltaBytes".into(),
+                    label: "Delta".into(),
+                    format: MarkerFieldFormat::Bytes,
+                    searchable: true,
+                },
+            ],
+            static_fields: vec![MarkerStaticField {
+                label: "Description".into(),
+                value: "Emitted when the kmem:rss_stat tracepoint is hit.".into(),
+            }],
+            graphs: vec![],
+        }
+    }
+
+    fn name(&self, _profile: &mut Profile) -> StringHandle {
+        self.name
+    }
+
+    fn category(&self, _profile: &mut Profile) -> CategoryHandle {
+        CategoryHandle::OTHER
+    }
+
+    fn string_field_value(&self, _field_index: u32) -> StringHandle {
+        unreachable!()
+    }
+
+    fn number_field_value(&self, field_index: u32) -> f64 {
+        match field_index {
+            0 => self.total_bytes as f64,
+            1 => self.delta_bytes as f64,
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// A line which was written to the annotation FIFO during recording, see
+/// `--annotation-fifo`.
+#[derive(Debug, Clone)]
+pub struct AnnotationMarker(pub StringHandle);
+
+impl StaticSchemaMarker for AnnotationMarker {
+    const UNIQUE_MARKER_TYPE_NAME: &'static str = "Annotation";
+
+    fn schema() -> MarkerSchema {
+        MarkerSchema {
+            type_name: Self::UNIQUE_MARKER_TYPE_NAME.into(),
+            locations: vec![
+                MarkerLocation::MarkerChart,
+                MarkerLocation::MarkerTable,
+                MarkerLocation::TimelineOverview,
+            ],
+            chart_label: Some("{marker.data.message}".into()),
+            tooltip_label: Some("{marker.data.message}".into()),
+            table_label: Some("{marker.data.message}".into()),
+            fields: vec![MarkerFieldSchema {
+                key: "message".into(),
+                label: "Message".into(),
+                format: MarkerFieldFormat::String,
+                searchable: true,
+            }],
+            static_fields: vec![],
+            graphs: vec![],
+        }
+    }
+
+    fn name(&self, profile: &mut Profile) -> StringHandle {
+        profile.intern_string("Annotation")
+    }
+
+    fn category(&self, _profile: &mut Profile) -> CategoryHandle {
+        CategoryHandle::OTHER
+    }
+
+    fn string_field_value(&self, _field_index: u32) -> StringHandle {
+        self.0
+    }
+
+    fn number_field_value(&self, _field_index: u32) -> f64 {
+        unreachable!()
+    }
+}
+
+/// One end of a `CrossProcessEdge`, with the pid of the other end.
+#[derive(Debug, Clone)]
+pub struct CrossProcessEdgeMarker {
+    pub name: StringHandle,
+    pub other_pid: u32,
+}
+
+impl StaticSchemaMarker for CrossProcessEdgeMarker {
+    const UNIQUE_MARKER_TYPE_NAME: &'static str = "CrossProcessEdge";
+
+    fn schema() -> MarkerSchema {
+        MarkerSchema {
+            type_name: Self::UNIQUE_MARKER_TYPE_NAME.into(),
+            locations: vec![MarkerLocation::MarkerChart, MarkerLocation::MarkerTable],
+            chart_label: Some("{marker.name}".into()),
+            tooltip_label: Some("{marker.name}, other process: {marker.data.otherPid}".into()),
+            table_label: Some("{marker.name}, other process: {marker.data.otherPid}".into()),
+            fields: vec![MarkerFieldSchema {
+                key: "otherPid".into(),
+                label: "Other process".into(),
+                format: MarkerFieldFormat::Integer,
+                searchable: true,
+            }],
+            static_fields: vec![MarkerStaticField {
+                label: "Description".into(),
+                value: "One end of a link between the stacks of two processes.".into(),
+            }],
+            graphs: vec![],
+        }
+    }
+
+    fn name(&self, _profile: &mut Profile) -> StringHandle {
+        self.name
+    }
+
+    fn category(&self, _profile: &mut Profile) -> CategoryHandle {
+        CategoryHandle::OTHER
+    }
+
+    fn string_field_value(&self, _field_index: u32) -> StringHandle {
+        unreachable!()
+    }
+
+    fn number_field_value(&self, _field_index: u32) -> f64 {
+        self.other_pid as f64
+    }
+}
+
+/// A copy of a global phase on one track, see `add_global_phase_markers`.
+#[derive(Debug, Clone)]
+pub struct GlobalPhaseMarker {
+    pub name: StringHandle,
+    pub category: CategoryHandle,
+}
+
+impl StaticSchemaMarker for GlobalPhaseMarker {
+    const UNIQUE_MARKER_TYPE_NAME: &'static str = "GlobalPhase";
+
+    fn schema() -> MarkerSchema {
+        MarkerSchema {
+            type_name: Self::UNIQUE_MARKER_TYPE_NAME.into(),
+            locations: vec![
+                MarkerLocation::MarkerChart,
+                MarkerLocation::MarkerTable,
+                MarkerLocation::TimelineOverview,
+            ],
+            chart_label: Some("{marker.name}".into()),
+            tooltip_label: Some("Global phase: {marker.name}".into()),
+            table_label: Some("{marker.name}".into()),
+            fields: vec![],
+            static_fields: vec![MarkerStaticField {
+                label: "Description".into(),
+                value: "A phase of the whole recording, copied onto every track.".into(),
+            }],
+            graphs: vec![],
+        }
+    }
+
+    fn name(&self, _profile: &mut Profile) -> StringHandle {
+        self.name
+    }
+
+    fn category(&self, _profile: &mut Profile) -> CategoryHandle {
+        self.category
+    }
+
+    fn string_field_value(&self, _field_index: u32) -> StringHandle {
+        unreachable!()
+    }
+
+    fn number_field_value(&self, _field_index: u32) -> f64 {
+        unreachable!()
+    }
+}
+
+/// A sample of a `--hw-counter` event, with the number of events since the
+/// previous sample.
+#[derive(Debug, Clone)]
+pub struct HwCounterMarker {
+    pub name: StringHandle,
+    pub count: u64,
+}
+
+impl StaticSchemaMarker for HwCounterMarker {
+    const UNIQUE_MARKER_TYPE_NAME: &'static str = "HwCounter";
+
+    fn schema() -> MarkerSchema {
+        MarkerSchema {
+            type_name: Self::UNIQUE_MARKER_TYPE_NAME.into(),
+            locations: vec![MarkerLocation::MarkerChart, MarkerLocation::MarkerTable],
+            chart_label: Some("{marker.data.count}".into()),
+            tooltip_label: Some("{marker.name}: {marker.data.count}".into()),
+            table_label: Some("{marker.name}: {marker.data.count}".into()),
+            fields: vec![MarkerFieldSchema {
+                key: "count".into(),
+                label: "Events".into(),
+                format: MarkerFieldFormat::Integer,
+                searchable: false,
+            }],
+            static_fields: vec![MarkerStaticField {
+                label: "Description".into(),
+                value: "A hardware counter sample. Its stack is the stack of the thread's latest CPU sample.".into(),
+            }],
+            graphs: vec![],
+        }
+    }
+
+    fn name(&self, _profile: &mut Profile) -> StringHandle {
+        self.name
+    }
+
+    fn category(&self, _profile: &mut Profile) -> CategoryHandle {
+        CategoryHandle::OTHER
+    }
+
+    fn string_field_value(&self, _field_index: u32) -> StringHandle {
+        unreachable!()
+    }
+
+    fn number_field_value(&self, _field_index: u32) -> f64 {
+        self.count as f64
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct OtherEventMarker(pub StringHandle);
+
+impl StaticSchemaMarker for OtherEventMarker {
+    const UNIQUE_MARKER_TYPE_NAME: &'static str = "Other event";
+
+    fn schema() -> MarkerSchema {
+        MarkerSchema {
+            type_name: Self::UNIQUE_MARKER_TYPE_NAME.into(),
+            locations: vec![MarkerLocation::MarkerChart, MarkerLocation::MarkerTable],
+            chart_label: None,
+            tooltip_label: None,
+            table_label: None,
+            fields: vec![],
+            static_fields: vec![MarkerStaticField {
+                label: "Description".into(),
+                value:
+                    "Emitted for any records in a perf.data file which don't map to a known event."
+                        .into(),
+            }],
+            graphs: vec![],
+        }
+    }
+
+    fn name(&self, _profile: &mut Profile) -> StringHandle {
+        self.0
+    }
+
+    fn category(&self, _profile: &mut Profile) -> CategoryHandle {
+        CategoryHandle::OTHER
+    }
+
+    fn string_field_value(&self, _field_index: u32) -> StringHandle {
+        unreachable!()
+    }
+
+    fn number_field_value(&self, _field_index: u32) -> f64 {
+        unreachable!()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct UserTimingMarker(pub StringHandle);
+
+impl StaticSchemaMarker for UserTimingMarker {
+    const UNIQUE_MARKER_TYPE_NAME: &'static str = "UserTiming";
+
+    fn schema() -> MarkerSchema {
+        MarkerSchema {
+            type_name: Self::UNIQUE_MARKER_TYPE_NAME.into(),
+            locations: vec![MarkerLocation::MarkerChart, MarkerLocation::MarkerTable],
+            chart_label: Some("{marker.data.name}".into()),
+            tooltip_label: Some("{marker.data.name}".into()),
+            table_label: Some("{marker.data.name}".into()),
+            fields: vec![MarkerFieldSchema {
+                key: "name".into(),
+                label: "Name".into(),
+                format: MarkerFieldFormat::String,
+                searchable: true,
+            }],
+            static_fields: vec![MarkerStaticField {
+                label: "Description".into(),
+                value: "Emitted for performance.mark and performance.measure.".into(),
+            }],
+            graphs: vec![],
+        }
+    }
+
+    fn name(&self, profile: &mut Profile) -> StringHandle {
+        profile.intern_string("UserTiming")
+    }
+
+    fn category(&self, _profile: &mut Profile) -> CategoryHandle {
+        CategoryHandle::OTHER
+    }
+
+    fn string_field_value(&self, _field_index: u32) -> StringHandle {
+        self.0
+    }
+
+    fn number_field_value(&self, _field_index: u32) -> f64 {
+        unreachable!()
+    }
+}
+
+/// The fields which both sched_switch markers get from the tracepoint.
+#[derive(Debug, Clone)]
+pub struct SchedSwitchDetails {
+    /// "preempted", "sleeping", "uninterruptible", "stopped" etc., or "unknown"
+    /// if the tracepoint payload couldn't be parsed.
+    pub reason: StringHandle,
+    pub prev_prio: Option<i32>,
+    pub next_prio: Option<i32>,
+}
+
+impl SchedSwitchDetails {
+    fn field_schemas() -> Vec<MarkerFieldSchema> {
+        vec![
+            MarkerFieldSchema {
+                key: "reason".into(),
+                label: "Reason".into(),
+                format: MarkerFieldFormat::String,
+                searchable: true,
+            },
+            MarkerFieldSchema {
+                key: "prevPrio".into(),
+                label: "Previous task priority".into(),
+                format: MarkerFieldFormat::Integer,
+                searchable: false,
+            },
+            MarkerFieldSchema {
+                key: "nextPrio".into(),
+                label: "Next task priority".into(),
+                format: MarkerFieldFormat::Integer,
+                searchable: false,
+            },
+        ]
+    }
+
+    /// The value of the number field at `field_index` among `field_schemas()`.
+    /// Missing priorities are NaN, which ends up as `null` in the profile.
+    fn number_field_value(&self, field_index: u32) -> f64 {
+        let prio = match field_index {
+            1 => self.prev_prio,
+            2 => self.next_prio,
+            _ => unreachable!(),
+        };
+        prio.map_or(f64::NAN, f64::from)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SchedSwitchMarkerOnCpuTrack(pub SchedSwitchDetails);
+
+impl StaticSchemaMarker for SchedSwitchMarkerOnCpuTrack {
+    const UNIQUE_MARKER_TYPE_NAME: &'static str = "sched_switch";
+
+    fn schema() -> MarkerSchema {
+        MarkerSchema {
+            type_name: Self::UNIQUE_MARKER_TYPE_NAME.into(),
+            locations: vec![MarkerLocation::MarkerChart, MarkerLocation::MarkerTable],
+            chart_label: Some("{marker.data.reason}".into()),
+            tooltip_label: Some("sched_switch ({marker.data.reason})".into()),
+            table_label: Some("{marker.data.reason}".into()),
+            fields: SchedSwitchDetails::field_schemas(),
+            static_fields: vec![MarkerStaticField {
+                label: "Description".into(),
+                value: "Emitted just before a running thread gets moved off-cpu.".into(),
+            }],
+            graphs: vec![],
+        }
+    }
+
+    fn name(&self, profile: &mut Profile) -> StringHandle {
+        profile.intern_string("sched_switch")
+    }
+
+    fn category(&self, _profile: &mut Profile) -> CategoryHandle {
+        CategoryHandle::OTHER
+    }
+
+    fn string_field_value(&self, _field_index: u32) -> StringHandle {
+        self.0.reason
+    }
+
+    fn number_field_value(&self, field_index: u32) -> f64 {
+        self.0.number_field_value(field_index)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SchedSwitchMarkerOnThreadTrack {
+    pub cpu: u32,
+    pub details: SchedSwitchDetails,
+}
+
+impl StaticSchemaMarker for SchedSwitchMarkerOnThreadTrack {
+    const UNIQUE_MARKER_TYPE_NAME: &'static str = "sched_switch";
+
+    fn schema() -> MarkerSchema {
+        let mut fields = SchedSwitchDetails::field_schemas();
+        fields.push(MarkerFieldSchema {
+            key: "cpu".into(),
+            label: "cpu".into(),
+            format: MarkerFieldFormat::Integer,
+            searchable: true,
+        });
+        MarkerSchema {
+            type_name: Self::UNIQUE_MARKER_TYPE_NAME.into(),
+            locations: vec![MarkerLocation::MarkerChart, MarkerLocation::MarkerTable],
+            chart_label: Some("{marker.data.reason}".into()),
+            tooltip_label: Some("sched_switch ({marker.data.reason})".into()),
+            table_label: Some("{marker.data.reason}".into()),
+            fields,
+            static_fields: vec![MarkerStaticField {
+                label: "Description".into(),
+                value: "Emitted just before a running thread gets moved off-cpu.".into(),
+            }],
+            graphs: vec![],
+        }
+    }
+
+    fn name(&self, profile: &mut Profile) -> StringHandle {
+        profile.intern_string("sched_switch")
+    }
+
+    fn category(&self, _profile: &mut Profile) -> CategoryHandle {
+        CategoryHandle::OTHER
+    }
+
+    fn string_field_value(&self, _field_index: u32) -> StringHandle {
+        self.details.reason
+    }
+
+    fn number_field_value(&self, field_index: u32) -> f64 {
+        match field_index {
+            3 => self.cpu.into(),
+            i => self.details.number_field_value(i),
+        }
+    }
+}
 
-    fn string_field_value(&self, _field_index: u32) -> StringHandle {
-        unreachable!()
+/// The schema of the field which holds the full values of truncated string
+/// fields. It's not searchable, so that searching doesn't have to go through
+/// the long values.
+fn untruncated_field_schema() -> MarkerFieldSchema {
+    MarkerFieldSchema {
+        key: "untruncated".into(),
+        label: "Untruncated fields".into(),
+        format: MarkerFieldFormat::String,
+        searchable: false,
+    }
+}
+
+/// Truncates the string field values of a marker which are longer than a
+/// maximum number of bytes, and collects their full values.
+struct StringFieldCapper {
+    max_len: usize,
+    untruncated: BTreeMap<String, String>,
+}
+
+impl StringFieldCapper {
+    fn new(max_len: usize) -> Self {
+        Self {
+            max_len,
+            untruncated: BTreeMap::new(),
+        }
     }
 
-    fn number_field_value(&self, _field_index: u32) -> f64 {
-        unreachable!()
+    /// Returns `value`, or its first `max_len` bytes followed by an ellipsis,
+    /// cut at a char boundary.
+    fn cap<'a>(&mut self, key: &str, value: &'a str) -> Cow<'a, str> {
+        if value.len() <= self.max_len {
+            return Cow::Borrowed(value);
+        }
+        let mut end = self.max_len;
+        while !value.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.untruncated.insert(key.to_owned(), value.to_owned());
+        Cow::Owned(format!("{}…", &value[..end]))
+    }
+
+    /// The full values of the truncated fields, by field key, as a JSON
+    /// object, or an empty string if nothing was truncated.
+    fn untruncated_json(&self) -> String {
+        if self.untruncated.is_empty() {
+            return String::new();
+        }
+        serde_json::to_string(&self.untruncated).expect("string maps always serialize")
+    }
+
+    /// Interns the values of the extra fields of a marker, and parses the
+    /// values of numeric fields. Values which don't parse as their field's
+    /// type are left empty, and kept in the untruncated fields instead.
+    fn extra_field_values(
+        &mut self,
+        profile: &mut Profile,
+        (field_names, field_types, field_values): ExtraFields,
+    ) -> Vec<ExtraFieldValue> {
+        field_names
+            .iter()
+            .zip(field_types)
+            .zip(field_values)
+            .map(|((name, field_type), value)| match field_type {
+                FieldType::Integer | FieldType::Float | FieldType::Duration => {
+                    let number = field_type.number_value(value).unwrap_or_else(|| {
+                        self.untruncated.insert(name.clone(), value.clone());
+                        f64::NAN
+                    });
+                    ExtraFieldValue::Number(number)
+                }
+                FieldType::Bool | FieldType::String => {
+                    ExtraFieldValue::String(profile.intern_string(&self.cap(name, value)))
+                }
+            })
+            .collect()
+    }
+}
+
+/// The names, types and values of the extra fields of a marker from a marker
+/// file.
+type ExtraFields<'a> = (&'a [String], &'a [FieldType], &'a [String]);
+
+/// The value of an extra field of a marker from a marker file.
+#[derive(Debug, Clone, Copy)]
+enum ExtraFieldValue {
+    String(StringHandle),
+    Number(f64),
+}
+
+impl ExtraFieldValue {
+    fn string(&self) -> StringHandle {
+        match self {
+            ExtraFieldValue::String(value) => *value,
+            ExtraFieldValue::Number(_) => unreachable!(),
+        }
+    }
+
+    fn number(&self) -> f64 {
+        match self {
+            ExtraFieldValue::Number(value) => *value,
+            ExtraFieldValue::String(_) => unreachable!(),
+        }
     }
 }
 
+/// The schemas of the extra fields of a marker type. Bools stay strings,
+/// because there is no marker field format for them. The raw source line
+/// isn't searchable, because it repeats the other fields.
+fn extra_field_schemas(
+    field_names: &[String],
+    field_types: &[FieldType],
+) -> Vec<MarkerFieldSchema> {
+    field_names
+        .iter()
+        .zip(field_types)
+        .map(|(name, field_type)| MarkerFieldSchema {
+            key: name.into(),
+            label: name.into(),
+            format: match field_type {
+                FieldType::Integer => MarkerFieldFormat::Integer,
+                FieldType::Float => MarkerFieldFormat::Decimal,
+                FieldType::Duration => MarkerFieldFormat::Duration,
+                FieldType::Bool | FieldType::String => MarkerFieldFormat::String,
+            },
+            searchable: name != RAW_LINE_FIELD,
+        })
+        .collect()
+}
+
+/// The part of a marker type name which identifies its extra fields: their
+/// names, each followed by its type unless it's a string.
+fn extra_fields_type_name(field_names: &[String], field_types: &[FieldType]) -> String {
+    field_names
+        .iter()
+        .zip(field_types)
+        .map(|(name, field_type)| match field_type {
+            FieldType::String => name.clone(),
+            field_type => format!("{name}:{}", field_type.as_str()),
+        })
+        .collect::<Vec<_>>()
+        .join("_")
+}
+
 #[derive(Debug, Clone)]
-pub struct UserTimingMarker(pub StringHandle);
+pub struct SpanMarkerWithTimings {
+    name: StringHandle,
+    label: StringHandle,
+    category: CategoryHandle,
+    marker_type: MarkerTypeHandle,
+    timings: TracingTimings,
+    /// The full values of the fields which were truncated, as a JSON object.
+    untruncated: StringHandle,
+    extra_fields: Vec<ExtraFieldValue>,
+}
 
-impl StaticSchemaMarker for UserTimingMarker {
-    const UNIQUE_MARKER_TYPE_NAME: &'static str = "UserTiming";
+/// What the marker table shows for spans from marker files.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum SpanTableLabel {
+    /// Just the span's name.
+    #[default]
+    NameOnly,
+    /// The name, followed by the busy and idle times.
+    WithTimings,
+    /// A label template, see [`validate_label_template`].
+    Custom(String),
+}
 
-    fn schema() -> MarkerSchema {
-        MarkerSchema {
-            type_name: Self::UNIQUE_MARKER_TYPE_NAME.into(),
+impl SpanTableLabel {
+    pub fn template(&self) -> &str {
+        match self {
+            SpanTableLabel::NameOnly => "{marker.data.name}",
+            SpanTableLabel::WithTimings => {
+                "{marker.data.name} — busy {marker.data.time_busy}, idle {marker.data.time_idle}"
+            }
+            SpanTableLabel::Custom(template) => template,
+        }
+    }
+}
+
+impl FromStr for SpanTableLabel {
+    type Err = String;
+
+    /// Parses "name", "timings", or a custom label template.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "name" => Ok(SpanTableLabel::NameOnly),
+            "timings" => Ok(SpanTableLabel::WithTimings),
+            template => {
+                validate_label_template(template)?;
+                Ok(SpanTableLabel::Custom(template.to_string()))
+            }
+        }
+    }
+}
+
+/// Checks that `template` is a valid marker label template: every `{...}`
+/// placeholder must be closed, and refer to `marker.name` or to a field,
+/// as `marker.data.<key>`.
+pub fn validate_label_template(template: &str) -> Result<(), String> {
+    let mut rest = template;
+    while let Some(start) = rest.find(['{', '}']) {
+        if rest[start..].starts_with('}') {
+            return Err(format!("unmatched '}}' in label template '{template}'"));
+        }
+        let Some(len) = rest[start + 1..].find('}') else {
+            return Err(format!("unclosed '{{' in label template '{template}'"));
+        };
+        let placeholder = &rest[start + 1..start + 1 + len];
+        let is_field = placeholder
+            .strip_prefix("marker.data.")
+            .is_some_and(|key| !key.is_empty() && !key.contains(['{', '.']));
+        if placeholder != "marker.name" && !is_field {
+            return Err(format!(
+                "unknown placeholder '{{{placeholder}}}' in label template '{template}', \
+                 expected {{marker.name}} or {{marker.data.<field>}}"
+            ));
+        }
+        rest = &rest[start + 1 + len + 1..];
+    }
+    Ok(())
+}
+
+impl SpanMarkerWithTimings {
+    pub fn create_marker_type(
+        profile: &mut Profile,
+        (extra_field_names, extra_field_types): (&[String], &[FieldType]),
+        table_label: &SpanTableLabel,
+    ) -> MarkerTypeHandle {
+        let mut all_fields = vec![
+            MarkerFieldSchema {
+                key: "time_idle".into(),
+                label: "time_idle".into(),
+                format: MarkerFieldFormat::Duration,
+                searchable: true,
+            },
+            MarkerFieldSchema {
+                key: "time_busy".into(),
+                label: "time_busy".into(),
+                format: MarkerFieldFormat::Duration,
+                searchable: true,
+            },
+            MarkerFieldSchema {
+                key: "name".into(),
+                label: "name".into(),
+                format: MarkerFieldFormat::String,
+                searchable: true,
+            },
+            untruncated_field_schema(),
+        ];
+
+        all_fields.extend(extra_field_schemas(extra_field_names, extra_field_types));
+
+        profile.register_marker_type(MarkerSchema {
+            type_name: format!(
+                "Span-{}",
+                extra_fields_type_name(extra_field_names, extra_field_types)
+            ),
             locations: vec![MarkerLocation::MarkerChart, MarkerLocation::MarkerTable],
             chart_label: Some("{marker.data.name}".into()),
             tooltip_label: Some("{marker.data.name}".into()),
-            table_label: Some("{marker.data.name}".into()),
-            fields: vec![MarkerFieldSchema {
-                key: "name".into(),
-                label: "Name".into(),
+            table_label: Some(table_label.template().into()),
+            fields: all_fields,
+            static_fields: vec![],
+            graphs: vec![],
+        })
+    }
+
+    pub fn new(
+        profile: &mut Profile,
+        marker: &MarkerOnThread,
+        span: &MarkerSpan,
+        category_handles: &HashMap<String, CategoryHandle>,
+        marker_type: &MarkerTypeHandle,
+        extra_fields: ExtraFields,
+        max_string_len: usize,
+    ) -> Self {
+        let marker = &marker.event_or_span;
+        let mut capper = StringFieldCapper::new(max_string_len);
+
+        let label = if let Some(ref label) = span.profiler_label {
+            profile.intern_string(label)
+        } else {
+            profile.intern_string(&span.span_type.to_string())
+        };
+
+        let category = category_handles[&span.category];
+
+        let name = profile.intern_string(&capper.cap("name", &marker.message));
+        let extra_fields = capper.extra_field_values(profile, extra_fields);
+
+        Self {
+            category,
+            label,
+            timings: span.timings.clone(),
+            name,
+            marker_type: *marker_type,
+            untruncated: profile.intern_string(&capper.untruncated_json()),
+            extra_fields,
+        }
+    }
+}
+
+impl Marker for SpanMarkerWithTimings {
+    fn marker_type(&self, _profile: &mut Profile) -> MarkerTypeHandle {
+        self.marker_type
+    }
+
+    fn name(&self, _profile: &mut Profile) -> StringHandle {
+        self.label
+    }
+
+    fn category(&self, _profile: &mut Profile) -> CategoryHandle {
+        self.category
+    }
+
+    fn string_field_value(&self, field_index: u32) -> StringHandle {
+        match field_index {
+            2 => self.name,
+            3 => self.untruncated,
+            i => self.extra_fields[i as usize - 4].string(),
+        }
+    }
+
+    fn number_field_value(&self, field_index: u32) -> f64 {
+        match field_index {
+            // Duration fields are in milliseconds.
+            0 => self.timings.time_idle.as_nanos() as f64 / 1_000_000.0,
+            1 => self.timings.time_busy.as_nanos() as f64 / 1_000_000.0,
+            i => self.extra_fields[i as usize - 4].number(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct EventMarker {
+    message: StringHandle,
+    category: CategoryHandle,
+    target: StringHandle,
+    /// The event's level, or an empty string.
+    level: StringHandle,
+    /// The full values of the fields which were truncated, as a JSON object.
+    untruncated: StringHandle,
+    extra_fields: Vec<ExtraFieldValue>,
+    marker_type: MarkerTypeHandle,
+}
+
+impl EventMarker {
+    pub fn new(
+        profile: &mut Profile,
+        category: &CategoryHandle,
+        marker: &MarkerOnThread,
+        marker_type: &MarkerTypeHandle,
+        extra_fields: ExtraFields,
+        max_string_len: usize,
+    ) -> Self {
+        let marker = &marker.event_or_span;
+        let mut capper = StringFieldCapper::new(max_string_len);
+
+        let message = profile.intern_string(&capper.cap("message", &marker.message));
+        let extra_fields = capper.extra_field_values(profile, extra_fields);
+
+        Self {
+            category: *category,
+            message,
+            target: profile.intern_string(&marker.target),
+            level: profile.intern_string(marker.level.map_or("", |level| level.as_str())),
+            untruncated: profile.intern_string(&capper.untruncated_json()),
+            marker_type: *marker_type,
+            extra_fields,
+        }
+    }
+
+    pub fn create_marker_type(
+        profile: &mut Profile,
+        (extra_field_names, extra_field_types): (&[String], &[FieldType]),
+    ) -> MarkerTypeHandle {
+        let mut all_fields = vec![
+            MarkerFieldSchema {
+                key: "message".into(),
+                label: "Message".into(),
                 format: MarkerFieldFormat::String,
                 searchable: true,
-            }],
-            static_fields: vec![MarkerStaticField {
-                label: "Description".into(),
-                value: "Emitted for performance.mark and performance.measure.".into(),
-            }],
+            },
+            MarkerFieldSchema {
+                key: "level".into(),
+                label: "Level".into(),
+                format: MarkerFieldFormat::String,
+                searchable: true,
+            },
+            untruncated_field_schema(),
+        ];
+
+        all_fields.extend(extra_field_schemas(extra_field_names, extra_field_types));
+
+        profile.register_marker_type(MarkerSchema {
+            type_name: format!(
+                "Event-{}",
+                extra_fields_type_name(extra_field_names, extra_field_types)
+            ),
+            locations: vec![MarkerLocation::MarkerChart, MarkerLocation::MarkerTable],
+            chart_label: Some("{marker.data.message}".into()),
+            tooltip_label: Some("{marker.data.message}".into()),
+            table_label: Some("{marker.data.message}".into()),
+            fields: all_fields,
+            static_fields: vec![],
             graphs: vec![],
-        }
+        })
     }
+}
 
-    fn name(&self, profile: &mut Profile) -> StringHandle {
-        profile.intern_string("UserTiming")
+impl Marker for EventMarker {
+    fn marker_type(&self, _profile: &mut Profile) -> MarkerTypeHandle {
+        self.marker_type
+    }
+
+    fn name(&self, _profile: &mut Profile) -> StringHandle {
+        self.target
     }
 
     fn category(&self, _profile: &mut Profile) -> CategoryHandle {
-        CategoryHandle::OTHER
+        self.category
     }
 
-    fn string_field_value(&self, _field_index: u32) -> StringHandle {
-        self.0
+    fn string_field_value(&self, field_index: u32) -> StringHandle {
+        match field_index {
+            0 => self.message,
+            1 => self.level,
+            2 => self.untruncated,
+            i => self.extra_fields[i as usize - 3].string(),
+        }
     }
 
-    fn number_field_value(&self, _field_index: u32) -> f64 {
-        unreachable!()
+    fn number_field_value(&self, field_index: u32) -> f64 {
+        self.extra_fields[field_index as usize - 3].number()
+    }
+}
+
+/// An event from a marker file, with all its extra fields in one JSON
+/// object. Unlike `EventMarker`, which registers a marker type per combination
+/// of extra fields, all events share this one type.
+#[derive(Debug, Clone)]
+pub struct LogMarker {
+    message: StringHandle,
+    target: StringHandle,
+    /// The event's level, or an empty string.
+    level: StringHandle,
+    /// The extra fields as a JSON object with sorted keys.
+    fields: StringHandle,
+    category: CategoryHandle,
+}
+
+impl LogMarker {
+    pub fn new(profile: &mut Profile, category: CategoryHandle, marker: &MarkerOnThread) -> Self {
+        let marker = &marker.event_or_span;
+        let fields: BTreeMap<&String, &String> = marker.extra_fields.iter().collect();
+        let fields = serde_json::to_string(&fields).expect("string maps always serialize");
+        Self {
+            message: profile.intern_string(&marker.message),
+            target: profile.intern_string(&marker.target),
+            level: profile.intern_string(marker.level.map_or("", |level| level.as_str())),
+            fields: profile.intern_string(&fields),
+            category,
+        }
     }
 }
 
-pub struct SchedSwitchMarkerOnCpuTrack;
-
-impl StaticSchemaMarker for SchedSwitchMarkerOnCpuTrack {
-    const UNIQUE_MARKER_TYPE_NAME: &'static str = "sched_switch";
+impl StaticSchemaMarker for LogMarker {
+    const UNIQUE_MARKER_TYPE_NAME: &'static str = "Log";
 
     fn schema() -> MarkerSchema {
+        let field = |key: &str, label: &str| MarkerFieldSchema {
+            key: key.into(),
+            label: label.into(),
+            format: MarkerFieldFormat::String,
+            searchable: true,
+        };
         MarkerSchema {
             type_name: Self::UNIQUE_MARKER_TYPE_NAME.into(),
             locations: vec![MarkerLocation::MarkerChart, MarkerLocation::MarkerTable],
-            chart_label: None,
-            tooltip_label: None,
-            table_label: None,
-            fields: vec![],
+            chart_label: Some("{marker.data.message}".into()),
+            tooltip_label: Some("{marker.data.message}".into()),
+            table_label: Some("{marker.data.message} {marker.data.fields}".into()),
+            fields: vec![
+                field("message", "Message"),
+                field("target", "Target"),
+                field("level", "Level"),
+                field("fields", "Fields"),
+            ],
             static_fields: vec![MarkerStaticField {
                 label: "Description".into(),
-                value: "Emitted just before a running thread gets moved off-cpu.".into(),
+                value: "Emitted for events in marker files, with --simple-event-markers.".into(),
             }],
             graphs: vec![],
         }
     }
 
-    fn name(&self, profile: &mut Profile) -> StringHandle {
-        profile.intern_string("sched_switch")
+    fn name(&self, _profile: &mut Profile) -> StringHandle {
+        self.target
     }
 
     fn category(&self, _profile: &mut Profile) -> CategoryHandle {
-        CategoryHandle::OTHER
+        self.category
     }
 
-    fn string_field_value(&self, _field_index: u32) -> StringHandle {
-        unreachable!()
+    fn string_field_value(&self, field_index: u32) -> StringHandle {
+        match field_index {
+            0 => self.message,
+            1 => self.target,
+            2 => self.level,
+            3 => self.fields,
+            _ => unreachable!(),
+        }
     }
 
     fn number_field_value(&self, _field_index: u32) -> f64 {
@@ -437,327 +2567,2022 @@ impl StaticSchemaMarker for SchedSwitchMarkerOnCpuTrack {
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct SchedSwitchMarkerOnThreadTrack {
-    pub cpu: u32,
+struct CustomGraphMarker {
+    marker_type: MarkerTypeHandle,
+    category: CategoryHandle,
+    name: StringHandle,
+    value: f64,
 }
 
-impl StaticSchemaMarker for SchedSwitchMarkerOnThreadTrack {
-    const UNIQUE_MARKER_TYPE_NAME: &'static str = "sched_switch";
-
-    fn schema() -> MarkerSchema {
-        MarkerSchema {
-            type_name: Self::UNIQUE_MARKER_TYPE_NAME.into(),
-            locations: vec![MarkerLocation::MarkerChart, MarkerLocation::MarkerTable],
+impl CustomGraphMarker {
+    pub fn create_marker_type(profile: &mut Profile, counter: &Counter) -> MarkerTypeHandle {
+        profile.register_marker_type(MarkerSchema {
+            type_name: format!("CustomGraph-{}", counter.name),
+            locations: vec![],
             chart_label: None,
             tooltip_label: None,
             table_label: None,
             fields: vec![MarkerFieldSchema {
-                key: "cpu".into(),
-                label: "cpu".into(),
-                format: MarkerFieldFormat::Integer,
-                searchable: true,
+                key: "value".into(),
+                label: "Value".into(),
+                format: MarkerFieldFormat::Decimal,
+                searchable: false,
             }],
-            static_fields: vec![MarkerStaticField {
-                label: "Description".into(),
-                value: "Emitted just before a running thread gets moved off-cpu.".into(),
+            static_fields: vec![],
+            graphs: vec![MarkerGraph {
+                key: "value".into(),
+                graph_type: counter.graph_type.clone(),
+                color: counter.track_color(),
             }],
-            graphs: vec![],
+        })
+    }
+
+    pub fn new(
+        name: StringHandle,
+        category: CategoryHandle,
+        marker_type: MarkerTypeHandle,
+        value: f64,
+    ) -> Self {
+        Self {
+            marker_type,
+            category,
+            name,
+            value,
         }
     }
+}
 
-    fn name(&self, profile: &mut Profile) -> StringHandle {
-        profile.intern_string("sched_switch")
+impl Marker for CustomGraphMarker {
+    fn marker_type(&self, _profile: &mut Profile) -> MarkerTypeHandle {
+        self.marker_type
+    }
+
+    fn name(&self, _profile: &mut Profile) -> StringHandle {
+        self.name
     }
 
     fn category(&self, _profile: &mut Profile) -> CategoryHandle {
-        CategoryHandle::OTHER
+        self.category
     }
 
     fn string_field_value(&self, _field_index: u32) -> StringHandle {
         unreachable!()
     }
 
-    fn number_field_value(&self, _field_index: u32) -> f64 {
-        self.cpu.into()
+    fn number_field_value(&self, field_index: u32) -> f64 {
+        match field_index {
+            0 => self.value,
+            _ => unreachable!(),
+        }
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct SpanMarkerWithTimings {
-    name: StringHandle,
-    label: StringHandle,
-    category: CategoryHandle,
-    marker_type: MarkerTypeHandle,
-    timings: TracingTimings,
-    extra_fields: Vec<StringHandle>,
-}
+#[cfg(test)]
+mod test {
+    use std::ops::Range;
+    use std::sync::Arc;
 
-impl SpanMarkerWithTimings {
-    pub fn create_marker_type(
-        profile: &mut Profile,
-        extra_field_names: &[String],
-    ) -> MarkerTypeHandle {
-        let mut all_fields = vec![
-            MarkerFieldSchema {
-                key: "time_idle".into(),
-                label: "time_idle".into(),
-                format: MarkerFieldFormat::Duration,
-                searchable: true,
+    use fxprof_processed_profile::{CpuDelta, GraphColor};
+
+    use super::*;
+    use crate::shared::counter_file::CounterSample;
+    use crate::shared::marker_file::TraceContext;
+    use crate::shared::sample_spill::SampleSpill;
+    use crate::shared::test_support::{
+        add_process_with_threads, add_synthetic_lib, new_profile, synthetic_lib_mapping,
+        synthetic_markers, synthetic_samples, synthetic_stacks,
+    };
+    use crate::shared::unresolved_samples::UnresolvedStackHandle;
+
+    fn span_marker(thread_handle: ThreadHandle, category: &str, start_ms: f64) -> MarkerOnThread {
+        MarkerOnThread {
+            thread_handle,
+            event_or_span: EventOrSpanMarker {
+                start_time: Timestamp::from_millis_since_reference(start_ms),
+                message: "span".to_string(),
+                target: "target".to_string(),
+                extra_fields: HashMap::new(),
+                level: None,
+                tid: None,
+                trace_context: None,
+                field_types: None,
+                marker_data: MarkerData::Span(MarkerSpan {
+                    id: 1,
+                    span_type: SpanType::Total,
+                    end_time: Timestamp::from_millis_since_reference(start_ms + 1.0),
+                    timings: TracingTimings::default(),
+                    category: category.to_string(),
+                    profiler_label: None,
+                    stats_label: None,
+                    child_markers: Vec::new(),
+                }),
             },
-            MarkerFieldSchema {
-                key: "time_busy".into(),
-                label: "time_busy".into(),
-                format: MarkerFieldFormat::Duration,
-                searchable: true,
+        }
+    }
+
+    fn event_marker(
+        thread_handle: ThreadHandle,
+        level: Option<EventLevel>,
+        start_ms: f64,
+    ) -> MarkerOnThread {
+        MarkerOnThread {
+            thread_handle,
+            event_or_span: EventOrSpanMarker {
+                start_time: Timestamp::from_millis_since_reference(start_ms),
+                message: "event".to_string(),
+                target: "target".to_string(),
+                extra_fields: HashMap::new(),
+                level,
+                tid: None,
+                trace_context: None,
+                field_types: None,
+                marker_data: MarkerData::Event,
             },
-            MarkerFieldSchema {
-                key: "name".into(),
-                label: "name".into(),
-                format: MarkerFieldFormat::String,
-                searchable: true,
+        }
+    }
+
+    #[test]
+    fn memory_estimate_covers_the_samples() {
+        let mut profile = new_profile();
+        let (process, threads) = add_process_with_threads(&mut profile, 2);
+        let mut stacks = UnresolvedStacks::default();
+        let stack_handles = synthetic_stacks(&mut stacks, 10, 4);
+        let sample_count = 1000;
+        let new_process_sample_data = |samples, markers| {
+            ProcessSampleData::new(
+                samples,
+                LibMappingOpQueue::default(),
+                Vec::new(),
+                None,
+                markers,
+                Vec::new(),
+                process,
+                Vec::new(),
+            )
+        };
+
+        let samples_only = new_process_sample_data(
+            synthetic_samples(&threads, &stack_handles, sample_count),
+            Vec::new(),
+        );
+        let samples_estimate = samples_only.estimated_memory_bytes();
+        assert!(
+            samples_estimate >= sample_count * std::mem::size_of::<UnresolvedSampleOrMarker>(),
+            "{samples_estimate}"
+        );
+
+        let with_markers = new_process_sample_data(
+            synthetic_samples(&threads, &stack_handles, sample_count),
+            synthetic_markers(&threads, 100, 4),
+        );
+        assert!(with_markers.estimated_memory_bytes() > samples_estimate);
+    }
+
+    #[test]
+    fn merged_data_stays_in_timestamp_order() {
+        let mut profile = new_profile();
+        let (process, threads) = add_process_with_threads(&mut profile, 2);
+        let mut stacks = UnresolvedStacks::default();
+        let stack_handles = synthetic_stacks(&mut stacks, 4, 3);
+        let new_process_sample_data =
+            |process, sample_times_ms: &[u64], marker_times_ms: &[f64]| {
+                let mut samples = UnresolvedSamples::default();
+                for (i, ms) in sample_times_ms.iter().enumerate() {
+                    samples.add_sample(
+                        threads[i % 2],
+                        Timestamp::from_millis_since_reference(*ms as f64),
+                        ms * 1_000_000,
+                        stack_handles[i % 4],
+                        CpuDelta::ZERO,
+                        1,
+                        None,
+                    );
+                }
+                let markers = marker_times_ms
+                    .iter()
+                    .map(|ms| event_marker(threads[0], None, *ms))
+                    .collect();
+                ProcessSampleData::new(
+                    samples,
+                    LibMappingOpQueue::default(),
+                    Vec::new(),
+                    None,
+                    markers,
+                    Vec::new(),
+                    process,
+                    vec![PathBuf::from("marker-1.txt")],
+                )
+            };
+
+        let mut data = new_process_sample_data(process, &[0, 2, 4, 6], &[1.0, 5.0]);
+        let other = new_process_sample_data(process, &[1, 3, 4, 8], &[3.0]);
+        data.merge(other).unwrap();
+        assert_eq!(data.sample_count(), 8);
+        assert_eq!(data.marker_count(), 3);
+        let sample_times: Vec<u64> = data
+            .unresolved_samples
+            .samples_and_markers()
+            .iter()
+            .map(|sample| sample.timestamp_mono / 1_000_000)
+            .collect();
+        assert_eq!(sample_times, [0, 1, 2, 3, 4, 4, 6, 8]);
+        let marker_times: Vec<_> = data
+            .markers
+            .iter()
+            .map(|marker| marker.event_or_span.start_time)
+            .collect();
+        assert_eq!(
+            marker_times,
+            [1.0, 3.0, 5.0].map(Timestamp::from_millis_since_reference)
+        );
+        assert_eq!(data.source_files().len(), 1);
+
+        let other_process =
+            profile.add_process("other", 2, Timestamp::from_millis_since_reference(0.0));
+        let other = new_process_sample_data(other_process, &[10], &[]);
+        assert!(data.merge(other).is_err());
+        assert_eq!(data.sample_count(), 8);
+    }
+
+    #[test]
+    fn flush_samples_with_empty_stacks() {
+        let mut profile = new_profile();
+        let process = profile.add_process("test", 1, Timestamp::from_millis_since_reference(0.0));
+        let thread = profile.add_thread(
+            process,
+            1,
+            Timestamp::from_millis_since_reference(0.0),
+            true,
+        );
+        let user_category = profile.add_category("User", CategoryColor::Yellow).into();
+        let kernel_category = profile.add_category("Kernel", CategoryColor::Orange).into();
+
+        // This is what recording with --sample-stacks-only=false produces.
+        let mut stacks = UnresolvedStacks::default();
+        let empty_stack = stacks.convert(std::iter::empty());
+        let mut samples = UnresolvedSamples::default();
+        for i in 0..3 {
+            samples.add_sample(
+                thread,
+                Timestamp::from_millis_since_reference(i as f64),
+                i,
+                empty_stack,
+                CpuDelta::from_millis(1.0),
+                1,
+                None,
+            );
+        }
+
+        let process_sample_data = ProcessSampleData::new(
+            samples,
+            LibMappingOpQueue::default(),
+            Vec::new(),
+            None,
+            Vec::new(),
+            Vec::new(),
+            process,
+            Vec::new(),
+        );
+        process_sample_data.flush_samples_to_profile(
+            &mut profile,
+            user_category,
+            kernel_category,
+            &mut Vec::new(),
+            &stacks,
+            &FlushOptions::default(),
+        );
+
+        let json = serde_json::to_value(&profile).unwrap();
+        let samples = &json["threads"][0]["samples"];
+        assert_eq!(samples["length"], 3);
+        assert!(samples["stack"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .all(|stack| stack.is_null()));
+    }
+
+    #[test]
+    fn flatten_marker_tree_links_children_to_parent() {
+        let mut profile = new_profile();
+        let process = profile.add_process("test", 1, Timestamp::from_millis_since_reference(0.0));
+        let thread = profile.add_thread(
+            process,
+            1,
+            Timestamp::from_millis_since_reference(0.0),
+            true,
+        );
+        let mut parent = span_marker(thread, "Outer", 0.0);
+        let child = span_marker(thread, "Inner", 0.5).event_or_span;
+        let MarkerData::Span(span) = &mut parent.event_or_span.marker_data else {
+            unreachable!()
+        };
+        span.id = 7;
+        span.child_markers.push(child);
+
+        let flattened = ProcessSampleData::flatten_marker_tree(vec![parent]);
+        assert_eq!(flattened.len(), 2);
+        assert_eq!(flattened[0].event_or_span.extra_fields["span_id"], "7");
+        assert_eq!(flattened[1].event_or_span.extra_fields["parent_id"], "7");
+        assert_eq!(flattened[1].thread_handle, thread);
+        assert!(flattened
+            .iter()
+            .all(|m| match &m.event_or_span.marker_data {
+                MarkerData::Span(span) => span.child_markers.is_empty(),
+                MarkerData::Event => true,
+            }));
+    }
+
+    #[test]
+    fn span_categories_are_deterministic() {
+        let flush_with_category_order =
+            |categories: &[&str], colors: &HashMap<String, CategoryColor>| {
+                let mut profile = new_profile();
+                let process =
+                    profile.add_process("test", 1, Timestamp::from_millis_since_reference(0.0));
+                let thread = profile.add_thread(
+                    process,
+                    1,
+                    Timestamp::from_millis_since_reference(0.0),
+                    true,
+                );
+                let user_category = profile.add_category("User", CategoryColor::Yellow).into();
+                let kernel_category = profile.add_category("Kernel", CategoryColor::Orange).into();
+                let markers = categories
+                    .iter()
+                    .enumerate()
+                    .map(|(i, category)| span_marker(thread, category, i as f64))
+                    .collect();
+                let process_sample_data = ProcessSampleData::new(
+                    UnresolvedSamples::default(),
+                    LibMappingOpQueue::default(),
+                    Vec::new(),
+                    None,
+                    markers,
+                    Vec::new(),
+                    process,
+                    Vec::new(),
+                );
+                process_sample_data.flush_samples_to_profile(
+                    &mut profile,
+                    user_category,
+                    kernel_category,
+                    &mut Vec::new(),
+                    &UnresolvedStacks::default(),
+                    &FlushOptions {
+                        span_category_colors: colors.clone(),
+                        ..Default::default()
+                    },
+                );
+                serde_json::to_value(&profile).unwrap()["meta"].clone()
+            };
+
+        let no_overrides = HashMap::new();
+        let first = flush_with_category_order(
+            &["Compaction", "Flush", "Compaction", "Scan"],
+            &no_overrides,
+        );
+        let second = flush_with_category_order(&["Scan", "Flush", "Compaction"], &no_overrides);
+        assert_eq!(first["categories"], second["categories"]);
+        assert_eq!(
+            first[SPAN_CATEGORY_COLORS_META_KEY],
+            second[SPAN_CATEGORY_COLORS_META_KEY]
+        );
+
+        let category_names: Vec<_> = first["categories"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|category| category["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            category_names,
+            [
+                "Other",
+                "User",
+                "Kernel",
+                "Compaction",
+                "Flush",
+                "Scan",
+                "(Logging)"
+            ]
+        );
+        assert_eq!(
+            first[SPAN_CATEGORY_COLORS_META_KEY]["Scan"],
+            serde_json::to_value(span_category_color("Scan")).unwrap()
+        );
+
+        let overrides = parse_span_category_colors(r#"{"Scan": "purple"}"#).unwrap();
+        let overridden = flush_with_category_order(&["Scan", "Flush"], &overrides);
+        assert_eq!(overridden[SPAN_CATEGORY_COLORS_META_KEY]["Scan"], "purple");
+        assert_eq!(
+            overridden[SPAN_CATEGORY_COLORS_META_KEY]["Flush"],
+            first[SPAN_CATEGORY_COLORS_META_KEY]["Flush"]
+        );
+        let scan_category = overridden["categories"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|category| category["name"] == "Scan")
+            .unwrap();
+        assert_eq!(scan_category["color"], "purple");
+    }
+
+    #[test]
+    fn span_category_palette_is_stable() {
+        let colors = ["Compaction", "Flush", "Scan", "Query", "Network"].map(span_category_color);
+        assert_eq!(
+            colors,
+            [
+                CategoryColor::Blue,
+                CategoryColor::LightGreen,
+                CategoryColor::Magenta,
+                CategoryColor::LightRed,
+                CategoryColor::LightRed,
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_span_category_colors_file() {
+        let colors =
+            parse_span_category_colors(r#"{"Compaction": "green", "Flush": "lightblue"}"#).unwrap();
+        assert_eq!(colors.len(), 2);
+        assert_eq!(colors["Compaction"], CategoryColor::Green);
+        assert_eq!(colors["Flush"], CategoryColor::LightBlue);
+        assert!(parse_span_category_colors(r#"{"Scan": "chartreuse"}"#).is_err());
+        assert!(parse_span_category_colors(r#"["green"]"#).is_err());
+    }
+
+    #[test]
+    fn time_bounds_over_mixed_data() {
+        let mut profile = new_profile();
+        let process = profile.add_process("test", 1, Timestamp::from_millis_since_reference(0.0));
+        let thread = profile.add_thread(
+            process,
+            1,
+            Timestamp::from_millis_since_reference(0.0),
+            true,
+        );
+
+        let empty = ProcessSampleData::new(
+            UnresolvedSamples::default(),
+            LibMappingOpQueue::default(),
+            Vec::new(),
+            None,
+            Vec::new(),
+            Vec::new(),
+            process,
+            Vec::new(),
+        );
+        assert_eq!(empty.time_bounds(), None);
+
+        let mut samples = UnresolvedSamples::default();
+        for ms in [5.0, 7.0] {
+            samples.add_sample(
+                thread,
+                Timestamp::from_millis_since_reference(ms),
+                0,
+                UnresolvedStackHandle::EMPTY,
+                CpuDelta::ZERO,
+                1,
+                None,
+            );
+        }
+        // The span starts before the first sample, the counter outlives everything.
+        let markers = vec![span_marker(thread, "Scan", 3.0)];
+        let counter = Counter {
+            name: "c".to_string(),
+            category: CounterCategory::Memory,
+            description: String::new(),
+            color: None,
+            graph_type: MarkerGraphType::Line,
+            is_cumulative: false,
+            samples: [6.0, 12.0]
+                .into_iter()
+                .map(|ms| CounterSample {
+                    timestamp: Timestamp::from_millis_since_reference(ms),
+                    value: 1.0,
+                    modification_count: 1,
+                })
+                .collect(),
+        };
+        let process_sample_data = ProcessSampleData::new(
+            samples,
+            LibMappingOpQueue::default(),
+            Vec::new(),
+            None,
+            markers,
+            vec![CounterOnThread {
+                thread_handle: thread,
+                counter,
+            }],
+            process,
+            Vec::new(),
+        );
+        assert_eq!(
+            process_sample_data.time_bounds(),
+            Some((
+                Timestamp::from_millis_since_reference(3.0),
+                Timestamp::from_millis_since_reference(12.0)
+            ))
+        );
+    }
+
+    #[test]
+    fn category_marker_tracks_collect_markers_from_all_threads() {
+        let mut profile = new_profile();
+        let (process, threads) =
+            crate::shared::test_support::add_process_with_threads(&mut profile, 2);
+        let user_category = profile.add_category("User", CategoryColor::Yellow).into();
+        let kernel_category = profile.add_category("Kernel", CategoryColor::Orange).into();
+        let markers = vec![
+            span_marker(threads[0], "Compaction", 1.0),
+            span_marker(threads[1], "Compaction", 2.0),
+            span_marker(threads[1], "Scan", 3.0),
+        ];
+        let process_sample_data = ProcessSampleData::new(
+            UnresolvedSamples::default(),
+            LibMappingOpQueue::default(),
+            Vec::new(),
+            None,
+            markers,
+            Vec::new(),
+            process,
+            Vec::new(),
+        );
+        process_sample_data.flush_samples_to_profile(
+            &mut profile,
+            user_category,
+            kernel_category,
+            &mut Vec::new(),
+            &UnresolvedStacks::default(),
+            &FlushOptions {
+                category_marker_tracks: true,
+                ..Default::default()
+            },
+        );
+
+        let json = serde_json::to_value(&profile).unwrap();
+        let threads = json["threads"].as_array().unwrap();
+        let marker_count = |name: &str| {
+            let thread = threads.iter().find(|t| t["name"] == name).unwrap();
+            thread["markers"]["length"].as_u64().unwrap()
+        };
+        assert_eq!(threads.len(), 4);
+        assert_eq!(marker_count("Compaction markers"), 2);
+        assert_eq!(marker_count("Scan markers"), 1);
+        assert_eq!(threads[0]["markers"]["length"], 0);
+        assert_eq!(threads[1]["markers"]["length"], 0);
+    }
+
+    #[test]
+    fn trace_ids_are_searchable_fields() {
+        let mut profile = new_profile();
+        let (process, threads) = add_process_with_threads(&mut profile, 1);
+        let user_category = profile.add_category("User", CategoryColor::Yellow).into();
+        let kernel_category = profile.add_category("Kernel", CategoryColor::Orange).into();
+        let mut event = event_marker(threads[0], None, 1.0);
+        event.event_or_span.trace_context = Some(TraceContext {
+            trace_id: 0x4bf92f3577b34da6a3ce929d0e0e4736,
+            parent_id: 0x00f067aa0ba902b7,
+            flags: 1,
+        });
+        let process_sample_data = ProcessSampleData::new(
+            UnresolvedSamples::default(),
+            LibMappingOpQueue::default(),
+            Vec::new(),
+            None,
+            vec![event],
+            Vec::new(),
+            process,
+            Vec::new(),
+        );
+        process_sample_data.flush_samples_to_profile(
+            &mut profile,
+            user_category,
+            kernel_category,
+            &mut Vec::new(),
+            &UnresolvedStacks::default(),
+            &FlushOptions {
+                span_table_label: SpanTableLabel::NameOnly,
+                ..Default::default()
             },
+        );
+
+        let json = serde_json::to_value(&profile).unwrap();
+        let schema = json["meta"]["markerSchema"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|schema| schema["name"] == "Event-trace_id")
+            .unwrap();
+        let field = schema["data"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|field| field["key"] == "trace_id")
+            .unwrap();
+        assert_eq!(field["searchable"], true);
+        let thread = &json["threads"][0];
+        let trace_id = thread["markers"]["data"][0]["trace_id"].as_u64().unwrap() as usize;
+        assert_eq!(
+            thread["stringArray"][trace_id],
+            "4bf92f3577b34da6a3ce929d0e0e4736"
+        );
+    }
+
+    #[test]
+    fn generated_marker_schemas_are_valid() {
+        let mut profile = new_profile();
+        let (process, threads) =
+            crate::shared::test_support::add_process_with_threads(&mut profile, 1);
+        let user_category = profile.add_category("User", CategoryColor::Yellow).into();
+        let kernel_category = profile.add_category("Kernel", CategoryColor::Orange).into();
+        let mut event_with_fields = event_marker(threads[0], Some(EventLevel::Warn), 2.0);
+        event_with_fields
+            .event_or_span
+            .extra_fields
+            .insert("size".to_string(), "1024".to_string());
+        let markers = vec![
+            span_marker(threads[0], "Compaction", 1.0),
+            event_with_fields,
+            event_marker(threads[0], None, 3.0),
         ];
+        let process_sample_data = ProcessSampleData::new(
+            UnresolvedSamples::default(),
+            LibMappingOpQueue::default(),
+            Vec::new(),
+            None,
+            markers,
+            Vec::new(),
+            process,
+            Vec::new(),
+        );
+        process_sample_data.flush_samples_to_profile(
+            &mut profile,
+            user_category,
+            kernel_category,
+            &mut Vec::new(),
+            &UnresolvedStacks::default(),
+            &FlushOptions {
+                span_table_label: SpanTableLabel::WithTimings,
+                ..Default::default()
+            },
+        );
+        assert_eq!(profile.validate_marker_schemas(), vec![]);
+    }
 
-        all_fields.extend(extra_field_names.iter().map(|name| MarkerFieldSchema {
-            key: name.into(),
-            label: name.into(),
-            format: MarkerFieldFormat::String,
-            searchable: true,
-        }));
+    #[test]
+    fn bar_counter_registers_bar_graph() {
+        let mut profile = new_profile();
+        let process = profile.add_process("test", 1, Timestamp::from_millis_since_reference(0.0));
+        let thread = profile.add_thread(
+            process,
+            1,
+            Timestamp::from_millis_since_reference(0.0),
+            true,
+        );
+        let user_category = profile.add_category("User", CategoryColor::Yellow).into();
+        let kernel_category = profile.add_category("Kernel", CategoryColor::Orange).into();
+        let counter = Counter {
+            name: "events".to_string(),
+            category: CounterCategory::Custom,
+            description: String::new(),
+            color: None,
+            graph_type: MarkerGraphType::Bar,
+            is_cumulative: false,
+            samples: vec![CounterSample {
+                timestamp: Timestamp::from_millis_since_reference(1.0),
+                value: 3.0,
+                modification_count: 1,
+            }],
+        };
+        let process_sample_data = ProcessSampleData::new(
+            UnresolvedSamples::default(),
+            LibMappingOpQueue::default(),
+            Vec::new(),
+            None,
+            Vec::new(),
+            vec![CounterOnThread {
+                thread_handle: thread,
+                counter,
+            }],
+            process,
+            Vec::new(),
+        );
+        process_sample_data.flush_samples_to_profile(
+            &mut profile,
+            user_category,
+            kernel_category,
+            &mut Vec::new(),
+            &UnresolvedStacks::default(),
+            &FlushOptions::default(),
+        );
 
-        profile.register_marker_type(MarkerSchema {
-            type_name: format!("Span-{}", extra_field_names.join("_")),
-            locations: vec![MarkerLocation::MarkerChart, MarkerLocation::MarkerTable],
-            chart_label: Some("{marker.data.name}".into()),
-            tooltip_label: Some("{marker.data.name}".into()),
-            table_label: Some("{marker.data.name}".into()),
-            fields: all_fields,
-            static_fields: vec![],
-            graphs: vec![],
-        })
+        let meta = serde_json::to_value(&profile).unwrap()["meta"].clone();
+        let schema = meta["markerSchema"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|schema| schema["name"] == "CustomGraph-events")
+            .unwrap();
+        assert_eq!(schema["graphs"][0]["type"], "bar");
     }
 
-    pub fn new(
-        profile: &mut Profile,
-        marker: &MarkerOnThread,
-        span: &MarkerSpan,
-        category_handles: &mut HashMap<String, CategoryHandle>,
-        marker_type: &MarkerTypeHandle,
-        field_values: &[String],
-    ) -> Self {
-        let marker = &marker.event_or_span;
+    #[test]
+    fn counter_tracks_use_category_color() {
+        let mut profile = new_profile();
+        let process = profile.add_process("test", 1, Timestamp::from_millis_since_reference(0.0));
+        let thread = profile.add_thread(
+            process,
+            1,
+            Timestamp::from_millis_since_reference(0.0),
+            true,
+        );
+        let user_category = profile.add_category("User", CategoryColor::Yellow).into();
+        let kernel_category = profile.add_category("Kernel", CategoryColor::Orange).into();
+        let counter = |name: &str, color| CounterOnThread {
+            thread_handle: thread,
+            counter: Counter {
+                name: name.to_string(),
+                category: CounterCategory::Memory,
+                description: String::new(),
+                color,
+                graph_type: MarkerGraphType::Line,
+                is_cumulative: false,
+                samples: Vec::new(),
+            },
+        };
+        let process_sample_data = ProcessSampleData::new(
+            UnresolvedSamples::default(),
+            LibMappingOpQueue::default(),
+            Vec::new(),
+            None,
+            Vec::new(),
+            vec![
+                counter("heap", None),
+                counter("arena", Some(GraphColor::Purple)),
+            ],
+            process,
+            Vec::new(),
+        );
+        process_sample_data.flush_samples_to_profile(
+            &mut profile,
+            user_category,
+            kernel_category,
+            &mut Vec::new(),
+            &UnresolvedStacks::default(),
+            &FlushOptions::default(),
+        );
 
-        let label = if let Some(ref label) = span.profiler_label {
-            profile.intern_string(label)
-        } else {
-            profile.intern_string(&span.span_type.to_string())
+        let counters = serde_json::to_value(&profile).unwrap()["counters"].clone();
+        assert_eq!(counters[0]["name"], "heap");
+        assert_eq!(counters[0]["category"], "Memory");
+        assert_eq!(counters[0]["color"], "orange");
+        // A color in the counter file overrides the category's color.
+        assert_eq!(counters[1]["name"], "arena");
+        assert_eq!(counters[1]["color"], "purple");
+    }
+
+    #[test]
+    fn colliding_counter_colors_are_distinguished() {
+        let mut profile = new_profile();
+        let process = profile.add_process("test", 1, Timestamp::from_millis_since_reference(0.0));
+        let thread = profile.add_thread(
+            process,
+            1,
+            Timestamp::from_millis_since_reference(0.0),
+            true,
+        );
+        let counter = |name: &str| CounterOnThread {
+            thread_handle: thread,
+            counter: Counter {
+                name: name.to_string(),
+                category: CounterCategory::Custom,
+                description: String::new(),
+                color: None,
+                graph_type: MarkerGraphType::Line,
+                is_cumulative: false,
+                samples: Vec::new(),
+            },
+        };
+        let mut process_sample_data = ProcessSampleData::new(
+            UnresolvedSamples::default(),
+            LibMappingOpQueue::default(),
+            Vec::new(),
+            None,
+            Vec::new(),
+            vec![counter("a"), counter("b"), counter("c")],
+            process,
+            Vec::new(),
+        );
+
+        let changes = process_sample_data.distinguish_counter_colors();
+        assert_eq!(changes.len(), 3);
+        assert_eq!(changes[0].to_string(), "Counter \"a\": unspec -> blue");
+        let colors: BTreeSet<GraphColor> = process_sample_data
+            .counters
+            .iter()
+            .map(|counter| counter.counter.track_color().unwrap())
+            .collect();
+        assert_eq!(colors.len(), 3);
+        // Distinct colors are left alone.
+        assert!(process_sample_data.distinguish_counter_colors().is_empty());
+    }
+
+    #[test]
+    fn incremental_flushes_match_single_flush() {
+        let mut stacks = UnresolvedStacks::default();
+        let stack_handles = synthetic_stacks(&mut stacks, 20, 8);
+        let make_profile = || {
+            let mut profile = new_profile();
+            let (process, threads) = add_process_with_threads(&mut profile, 2);
+            let lib = add_synthetic_lib(&mut profile, 0);
+            let user_category = profile.add_category("User", CategoryColor::Yellow).into();
+            let kernel_category = profile.add_category("Kernel", CategoryColor::Orange).into();
+            (
+                profile,
+                process,
+                threads,
+                lib,
+                user_category,
+                kernel_category,
+            )
+        };
+        let (mut single_profile, process, threads, lib, user_category, kernel_category) =
+            make_profile();
+        let markers = synthetic_markers(&threads, 40, 5);
+        let data = |range: Range<usize>, lib_mapping_ops: LibMappingOpQueue| {
+            let mut samples = UnresolvedSamples::default();
+            for i in range.clone() {
+                samples.add_sample(
+                    threads[i % threads.len()],
+                    Timestamp::from_millis_since_reference(i as f64),
+                    i as u64 * 1_000_000,
+                    stack_handles[i % stack_handles.len()],
+                    CpuDelta::from_micros(1000),
+                    1,
+                    None,
+                );
+            }
+            let counter = Counter {
+                name: "heap".to_string(),
+                category: CounterCategory::Memory,
+                description: String::new(),
+                color: None,
+                graph_type: MarkerGraphType::Line,
+                is_cumulative: false,
+                samples: range
+                    .clone()
+                    .map(|i| CounterSample {
+                        timestamp: Timestamp::from_millis_since_reference(i as f64),
+                        value: i as f64,
+                        modification_count: 1,
+                    })
+                    .collect(),
+            };
+            ProcessSampleData::new(
+                samples,
+                lib_mapping_ops,
+                Vec::new(),
+                None,
+                markers[range].to_vec(),
+                vec![CounterOnThread {
+                    thread_handle: threads[0],
+                    counter,
+                }],
+                process,
+                Vec::new(),
+            )
+        };
+        let options = FlushOptions {
+            span_sample_categories: vec!["Category0".to_string()],
+            ..Default::default()
         };
 
-        let category = *category_handles
-            .entry(span.category.clone())
-            .or_insert_with(|| profile.add_category(&span.category, CategoryColor::Green));
-
-        let extra_fields = field_values
-            .iter()
-            .map(|value| profile.intern_string(value))
-            .collect();
+        data(0..40, synthetic_lib_mapping(lib)).flush_samples_to_profile(
+            &mut single_profile,
+            user_category,
+            kernel_category,
+            &mut Vec::new(),
+            &stacks,
+            &options,
+        );
 
-        Self {
-            category,
-            label,
-            timings: span.timings.clone(),
-            name: profile.intern_string(&marker.message),
-            marker_type: *marker_type,
-            extra_fields,
-        }
-    }
-}
+        let (mut incremental_profile, ..) = make_profile();
+        let mut builder =
+            ProcessProfileBuilder::new(process, user_category, kernel_category, &options);
+        builder.add_pending(data(0..20, synthetic_lib_mapping(lib)));
+        builder.flush_pending(&mut incremental_profile, &stacks, &mut Vec::new());
+        builder.add_pending(data(20..40, LibMappingOpQueue::default()));
+        builder.flush_pending(&mut incremental_profile, &stacks, &mut Vec::new());
 
-impl Marker for SpanMarkerWithTimings {
-    fn marker_type(&self, _profile: &mut Profile) -> MarkerTypeHandle {
-        self.marker_type
+        let single = serde_json::to_value(&single_profile).unwrap();
+        let incremental = serde_json::to_value(&incremental_profile).unwrap();
+        assert_eq!(incremental, single);
+        let schemas = incremental["meta"]["markerSchema"].as_array().unwrap();
+        let names: HashSet<_> = schemas.iter().map(|schema| &schema["name"]).collect();
+        assert_eq!(names.len(), schemas.len());
     }
 
-    fn name(&self, _profile: &mut Profile) -> StringHandle {
-        self.label
+    #[test]
+    fn span_table_label_variants() {
+        let table_label = |label: &str| {
+            let mut profile = new_profile();
+            let label: SpanTableLabel = label.parse().unwrap();
+            SpanMarkerWithTimings::create_marker_type(&mut profile, (&[], &[]), &label);
+            serde_json::to_value(&profile).unwrap()["meta"]["markerSchema"][0]["tableLabel"].clone()
+        };
+        assert_eq!(table_label("name"), "{marker.data.name}");
+        assert_eq!(
+            table_label("timings"),
+            "{marker.data.name} — busy {marker.data.time_busy}, idle {marker.data.time_idle}"
+        );
+        assert_eq!(
+            table_label("{marker.name}: {marker.data.time_busy}"),
+            "{marker.name}: {marker.data.time_busy}"
+        );
     }
 
-    fn category(&self, _profile: &mut Profile) -> CategoryHandle {
-        self.category
+    #[test]
+    fn sub_microsecond_span_durations_are_kept() {
+        let mut profile = new_profile();
+        let process = profile.add_process("test", 1, Timestamp::from_millis_since_reference(0.0));
+        let thread = profile.add_thread(
+            process,
+            1,
+            Timestamp::from_millis_since_reference(0.0),
+            true,
+        );
+        let category = profile.add_category("Flush", CategoryColor::Blue);
+        let mut marker = span_marker(thread, "Flush", 0.0);
+        let MarkerData::Span(span) = &mut marker.event_or_span.marker_data else {
+            unreachable!()
+        };
+        span.timings = TracingTimings {
+            time_busy: Duration::from_nanos(500),
+            time_idle: Duration::from_micros(1500),
+        };
+        let span = span.clone();
+        let marker_type = SpanMarkerWithTimings::create_marker_type(
+            &mut profile,
+            (&[], &[]),
+            &SpanTableLabel::default(),
+        );
+        let span_marker = SpanMarkerWithTimings::new(
+            &mut profile,
+            &marker,
+            &span,
+            &HashMap::from([("Flush".to_string(), category)]),
+            &marker_type,
+            (&[], &[], &[]),
+            usize::MAX,
+        );
+        assert_eq!(span_marker.number_field_value(0), 1.5);
+        assert_eq!(span_marker.number_field_value(1), 0.0005);
     }
 
-    fn string_field_value(&self, field_index: u32) -> StringHandle {
-        match field_index {
-            2 => self.name,
-            i => *self.extra_fields.get(i as usize - 3).unwrap(),
+    #[test]
+    fn invalid_label_templates_are_rejected() {
+        assert!(validate_label_template("plain text").is_ok());
+        assert!(validate_label_template("{marker.data.name} ({marker.data.x})").is_ok());
+        for template in [
+            "{marker.data.name",
+            "marker.data.name}",
+            "{name}",
+            "{marker.data.}",
+            "{marker.data.a.b}",
+        ] {
+            assert!(validate_label_template(template).is_err(), "{template}");
+            assert!(template.parse::<SpanTableLabel>().is_err(), "{template}");
         }
     }
 
-    fn number_field_value(&self, field_index: u32) -> f64 {
-        match field_index {
-            0 => self.timings.time_idle.as_micros() as f64 / 1000.0,
-            1 => self.timings.time_busy.as_micros() as f64 / 1000.0,
-            _ => unreachable!(),
+    #[test]
+    fn sample_burst_shows_in_sample_rate_counter() {
+        let mut profile = new_profile();
+        let process = profile.add_process("test", 1, Timestamp::from_millis_since_reference(0.0));
+        let thread = profile.add_thread(
+            process,
+            1,
+            Timestamp::from_millis_since_reference(0.0),
+            true,
+        );
+        let user_category = profile.add_category("User", CategoryColor::Yellow).into();
+        let kernel_category = profile.add_category("Kernel", CategoryColor::Orange).into();
+
+        // One sample per millisecond for 50 ms, with ten extra samples
+        // between 20 and 21 ms.
+        let mut sample_times_us: Vec<u64> = (0..50).map(|ms| ms * 1000).collect();
+        sample_times_us.extend((0..10).map(|i| 20_050 + i * 50));
+        sample_times_us.sort();
+        let mut unresolved_samples = UnresolvedSamples::default();
+        for time_us in sample_times_us {
+            unresolved_samples.add_sample(
+                thread,
+                Timestamp::from_nanos_since_reference(time_us * 1000),
+                time_us * 1000,
+                UnresolvedStackHandle::EMPTY,
+                CpuDelta::ZERO,
+                1,
+                None,
+            );
         }
+        let process_sample_data = ProcessSampleData::new(
+            unresolved_samples,
+            LibMappingOpQueue::default(),
+            Vec::new(),
+            None,
+            Vec::new(),
+            Vec::new(),
+            process,
+            Vec::new(),
+        );
+        process_sample_data.flush_samples_to_profile(
+            &mut profile,
+            user_category,
+            kernel_category,
+            &mut Vec::new(),
+            &UnresolvedStacks::default(),
+            &FlushOptions {
+                sample_rate_counter_window: Some(Duration::from_millis(5)),
+                ..Default::default()
+            },
+        );
+
+        let counters = serde_json::to_value(&profile).unwrap()["counters"].clone();
+        assert_eq!(counters[0]["name"], "Samples");
+        assert_eq!(counters[0]["category"], "CPU");
+        let samples = &counters[0]["samples"];
+        assert_eq!(
+            samples["time"],
+            serde_json::json!([0.0, 5.0, 10.0, 15.0, 20.0, 25.0, 30.0, 35.0, 40.0, 45.0])
+        );
+        assert_eq!(
+            samples["count"],
+            serde_json::json!([5.0, 5.0, 5.0, 5.0, 15.0, 5.0, 5.0, 5.0, 5.0, 5.0])
+        );
     }
-}
 
-#[derive(Debug, Clone)]
-pub struct EventMarker {
-    message: StringHandle,
-    category: CategoryHandle,
-    target: StringHandle,
-    extra_fields: Vec<StringHandle>,
-    marker_type: MarkerTypeHandle,
-}
+    #[test]
+    fn same_named_counters_get_distinct_tracks() {
+        let mut profile = new_profile();
+        let process = profile.add_process("test", 1, Timestamp::from_millis_since_reference(0.0));
+        let thread = profile.add_thread(
+            process,
+            1,
+            Timestamp::from_millis_since_reference(0.0),
+            true,
+        );
+        let user_category = profile.add_category("User", CategoryColor::Yellow).into();
+        let kernel_category = profile.add_category("Kernel", CategoryColor::Orange).into();
+        let counter = |name: &str| CounterOnThread {
+            thread_handle: thread,
+            counter: Counter {
+                name: name.to_string(),
+                category: CounterCategory::Memory,
+                description: String::new(),
+                color: None,
+                graph_type: MarkerGraphType::Line,
+                is_cumulative: false,
+                samples: Vec::new(),
+            },
+        };
+        let process_sample_data = ProcessSampleData::new(
+            UnresolvedSamples::default(),
+            LibMappingOpQueue::default(),
+            Vec::new(),
+            None,
+            Vec::new(),
+            vec![counter("queue"), counter("heap"), counter("queue")],
+            process,
+            Vec::new(),
+        );
+        process_sample_data.flush_samples_to_profile(
+            &mut profile,
+            user_category,
+            kernel_category,
+            &mut Vec::new(),
+            &UnresolvedStacks::default(),
+            &FlushOptions::default(),
+        );
 
-impl EventMarker {
-    pub fn new(
-        profile: &mut Profile,
-        category: &CategoryHandle,
-        marker: &MarkerOnThread,
-        marker_type: &MarkerTypeHandle,
-        field_values: &[String],
-    ) -> Self {
-        let marker = &marker.event_or_span;
+        let counters = serde_json::to_value(&profile).unwrap()["counters"].clone();
+        let names: Vec<_> = counters
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|counter| counter["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, ["queue", "heap", "queue (2)"]);
+    }
+
+    #[test]
+    fn global_phases_are_copied_onto_every_main_thread() {
+        let mut profile = new_profile();
+        let start = Timestamp::from_millis_since_reference(0.0);
+        let new_process = |profile: &mut Profile, pid: u32, markers: Vec<MarkerOnThread>| {
+            let process = profile.add_process("process", pid, start);
+            let main_thread = profile.add_thread(process, pid, start, true);
+            let markers = markers
+                .into_iter()
+                .map(|marker| MarkerOnThread {
+                    thread_handle: main_thread,
+                    ..marker
+                })
+                .collect();
+            let mut data = ProcessSampleData::new(
+                UnresolvedSamples::default(),
+                LibMappingOpQueue::default(),
+                Vec::new(),
+                None,
+                markers,
+                Vec::new(),
+                process,
+                Vec::new(),
+            );
+            data.set_main_thread(main_thread);
+            data
+        };
+        let (_, placeholder_threads) = add_process_with_threads(&mut profile, 1);
+        // The coordinator reports the same phase twice.
+        let coordinator = new_process(
+            &mut profile,
+            10,
+            vec![
+                span_marker(placeholder_threads[0], "Phase", 1.0),
+                span_marker(placeholder_threads[0], "Phase", 1.5),
+                span_marker(placeholder_threads[0], "Scan", 3.0),
+            ],
+        );
+        let worker = new_process(&mut profile, 20, Vec::new());
+        let datas = vec![coordinator, worker];
 
-        let extra_fields = field_values
+        let phases = collect_global_phases(&datas, "Phase");
+        assert_eq!(
+            phases,
+            vec![GlobalPhase {
+                name: "span".to_string(),
+                start_time: Timestamp::from_millis_since_reference(1.0),
+                end_time: Timestamp::from_millis_since_reference(2.5),
+            }]
+        );
+        let main_threads: Vec<ThreadHandle> = datas
             .iter()
-            .map(|value| profile.intern_string(value))
+            .filter_map(ProcessSampleData::main_thread)
             .collect();
+        add_global_phase_markers(&mut profile, &phases, &main_threads);
 
-        Self {
-            category: *category,
-            message: profile.intern_string(&marker.message),
-            target: profile.intern_string(&marker.target),
-            marker_type: *marker_type,
-            extra_fields,
+        let json = serde_json::to_value(&profile).unwrap();
+        let threads = json["threads"].as_array().unwrap();
+        assert_eq!(threads[0]["markers"]["length"], 0);
+        for thread in &threads[1..] {
+            assert_eq!(thread["markers"]["length"], 1);
+            assert_eq!(thread["markers"]["data"][0]["type"], "GlobalPhase");
         }
     }
 
-    pub fn create_marker_type(
-        profile: &mut Profile,
-        extra_field_names: &[String],
-    ) -> MarkerTypeHandle {
-        let mut all_fields = vec![MarkerFieldSchema {
-            key: "message".into(),
-            label: "Message".into(),
-            format: MarkerFieldFormat::String,
-            searchable: true,
-        }];
-
-        all_fields.extend(extra_field_names.iter().map(|name| MarkerFieldSchema {
-            key: name.into(),
-            label: name.into(),
-            format: MarkerFieldFormat::String,
-            searchable: true,
-        }));
+    #[test]
+    fn span_busy_counters_accumulate_per_category() {
+        let mut profile = new_profile();
+        let (process, threads) = add_process_with_threads(&mut profile, 1);
+        let span = |category: &str, start_ms: f64, busy_us: u64| {
+            let mut marker = span_marker(threads[0], category, start_ms);
+            if let MarkerData::Span(span) = &mut marker.event_or_span.marker_data {
+                span.timings.time_busy = Duration::from_micros(busy_us);
+            }
+            marker
+        };
+        let mut parent = span("Scan", 2.0, 250);
+        if let MarkerData::Span(parent_span) = &mut parent.event_or_span.marker_data {
+            parent_span
+                .child_markers
+                .push(span("Scan", 1.5, 100).event_or_span);
+        }
+        let mut running = span("Scan", 0.0, 1000);
+        if let MarkerData::Span(running_span) = &mut running.event_or_span.marker_data {
+            running_span.span_type = SpanType::Running;
+        }
+        let mut process_sample_data = ProcessSampleData::new(
+            UnresolvedSamples::default(),
+            LibMappingOpQueue::default(),
+            Vec::new(),
+            None,
+            vec![
+                parent,
+                span("Compaction", 1.0, 750),
+                span("Scan", 0.0, 500),
+                running,
+            ],
+            Vec::new(),
+            process,
+            Vec::new(),
+        );
+        process_sample_data.add_span_busy_counters();
 
-        profile.register_marker_type(MarkerSchema {
-            type_name: format!("Event-{}", extra_field_names.join("_")),
-            locations: vec![MarkerLocation::MarkerChart, MarkerLocation::MarkerTable],
-            chart_label: Some("{marker.data.message}".into()),
-            tooltip_label: Some("{marker.data.message}".into()),
-            table_label: Some("{marker.data.message}".into()),
-            fields: all_fields,
-            static_fields: vec![],
-            graphs: vec![],
-        })
+        let counters: Vec<_> = process_sample_data
+            .counters
+            .iter()
+            .map(|counter| {
+                assert!(counter.counter.is_cumulative);
+                let samples: Vec<_> = counter
+                    .counter
+                    .samples
+                    .iter()
+                    .map(|sample| {
+                        (
+                            sample.timestamp.as_nanos_since_reference() / 1000,
+                            (sample.value * 1000.0).round() as u64,
+                        )
+                    })
+                    .collect();
+                (counter.counter.name.as_str(), samples)
+            })
+            .collect();
+        // (end time in µs, cumulative busy time in µs)
+        assert_eq!(
+            counters,
+            [
+                ("Compaction busy", vec![(2000, 750)]),
+                ("Scan busy", vec![(1000, 500), (2500, 600), (3000, 850)]),
+            ]
+        );
     }
-}
 
-impl Marker for EventMarker {
-    fn marker_type(&self, _profile: &mut Profile) -> MarkerTypeHandle {
-        self.marker_type
+    #[test]
+    fn categories_of_spans_and_events() {
+        let mut profile = new_profile();
+        let (process, threads) = add_process_with_threads(&mut profile, 1);
+        let event = |target: &str| {
+            let mut marker = event_marker(threads[0], None, 0.0);
+            marker.event_or_span.target = target.to_string();
+            marker
+        };
+        let mut scan = span_marker(threads[0], "Scan", 0.0);
+        if let MarkerData::Span(span) = &mut scan.event_or_span.marker_data {
+            span.child_markers.push(event("net").event_or_span);
+        }
+        let process_sample_data = ProcessSampleData::new(
+            UnresolvedSamples::default(),
+            LibMappingOpQueue::default(),
+            Vec::new(),
+            None,
+            vec![
+                scan,
+                span_marker(threads[0], "Compaction", 1.0),
+                span_marker(threads[0], "Scan", 2.0),
+                event("db::open"),
+                event("db::open"),
+            ],
+            Vec::new(),
+            process,
+            Vec::new(),
+        );
+        let categories: Vec<_> = process_sample_data.categories().into_iter().collect();
+        assert_eq!(categories, ["Compaction", "Scan", "db::open", "net"]);
     }
 
-    fn name(&self, _profile: &mut Profile) -> StringHandle {
-        self.target
+    #[test]
+    fn low_peak_counters_are_folded_per_category() {
+        let mut profile = new_profile();
+        let (process, threads) = add_process_with_threads(&mut profile, 1);
+        // Counter i peaks at i at 1ms, and is 1 at 0ms and 2ms. The order
+        // is shuffled so that the peaks don't follow the original order.
+        let counter = |category: CounterCategory, name: &str, peak: usize| CounterOnThread {
+            thread_handle: threads[0],
+            counter: Counter {
+                name: format!("{name} {peak}"),
+                category,
+                description: String::new(),
+                color: None,
+                graph_type: MarkerGraphType::Line,
+                is_cumulative: false,
+                samples: [1.0, peak as f64, 1.0]
+                    .into_iter()
+                    .enumerate()
+                    .map(|(ms, value)| CounterSample {
+                        timestamp: Timestamp::from_millis_since_reference(ms as f64),
+                        value,
+                        modification_count: 0,
+                    })
+                    .collect(),
+            },
+        };
+        let shuffled_peaks = |n: usize| (0..n).map(move |i| (i * 37) % n);
+        let counters = shuffled_peaks(100)
+            .map(|peak| counter(CounterCategory::Memory, "rss", peak))
+            .chain((0..3).map(|peak| counter(CounterCategory::Cpu, "cpu", peak)))
+            .chain(shuffled_peaks(20).map(|peak| counter(CounterCategory::Custom, "graph", peak)))
+            .collect();
+        let mut process_sample_data = ProcessSampleData::new(
+            UnresolvedSamples::default(),
+            LibMappingOpQueue::default(),
+            Vec::new(),
+            None,
+            Vec::new(),
+            counters,
+            process,
+            Vec::new(),
+        );
+        assert_eq!(process_sample_data.cap_counter_tracks(100), 0);
+        // 9 memory counters keep their tracks, and the 91 others share the
+        // 10th. The CPU counters fit, and custom counters are never folded.
+        assert_eq!(process_sample_data.cap_counter_tracks(10), 91);
+
+        let names: Vec<_> = process_sample_data
+            .counters
+            .iter()
+            .filter(|counter| !matches!(counter.counter.category, CounterCategory::Custom))
+            .map(|counter| counter.counter.name.as_str())
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "rss 96",
+                "rss 92",
+                "rss 99",
+                "rss 95",
+                "rss 91",
+                "rss 98",
+                "rss 94",
+                "rss 97",
+                "rss 93",
+                "cpu 0",
+                "cpu 1",
+                "cpu 2",
+                "Other Memory",
+            ]
+        );
+        let custom_count = process_sample_data
+            .counters
+            .iter()
+            .filter(|counter| matches!(counter.counter.category, CounterCategory::Custom))
+            .count();
+        assert_eq!(custom_count, 20);
+        let other = &process_sample_data.counters.last().unwrap().counter;
+        assert!(matches!(other.category, CounterCategory::Memory));
+        let values: Vec<f64> = other.samples.iter().map(|sample| sample.value).collect();
+        assert_eq!(values, [91.0, (0..91).sum::<u32>() as f64, 91.0]);
+
+        // Without any tracks, the capped categories' counters are dropped.
+        assert_eq!(process_sample_data.cap_counter_tracks(0), 13);
+        assert_eq!(process_sample_data.counters.len(), 20);
     }
 
-    fn category(&self, _profile: &mut Profile) -> CategoryHandle {
-        self.category
+    #[test]
+    fn cross_process_edges_become_markers_on_the_local_end() {
+        let mut profile = new_profile();
+        let (process, threads) = add_process_with_threads(&mut profile, 1);
+        let start = Timestamp::from_millis_since_reference(0.0);
+        let other_process = profile.add_process("other", 2, start);
+        let other_thread = profile.add_thread(other_process, 20, start, true);
+        let lib = add_synthetic_lib(&mut profile, 0);
+        let user_category = profile.add_category("User", CategoryColor::Yellow).into();
+        let kernel_category = profile.add_category("Kernel", CategoryColor::Orange).into();
+        let mut stacks = UnresolvedStacks::default();
+        let stack_handles = synthetic_stacks(&mut stacks, 2, 3);
+        let mut process_sample_data = ProcessSampleData::new(
+            synthetic_samples(&threads, &stack_handles, 10),
+            synthetic_lib_mapping(lib),
+            Vec::new(),
+            None,
+            Vec::new(),
+            Vec::new(),
+            process,
+            Vec::new(),
+        );
+        process_sample_data.add_cross_process_edge(CrossProcessEdge {
+            from_pid: 1,
+            from_thread: threads[0],
+            from_stack: stack_handles[1],
+            to_pid: 2,
+            to_thread: other_thread,
+            to_stack: stack_handles[0],
+            timestamp: Timestamp::from_millis_since_reference(4.5),
+        });
+        process_sample_data.flush_samples_to_profile(
+            &mut profile,
+            user_category,
+            kernel_category,
+            &mut Vec::new(),
+            &stacks,
+            &FlushOptions::default(),
+        );
+
+        let json = serde_json::to_value(&profile).unwrap();
+        let thread = &json["threads"][0];
+        let markers = &thread["markers"];
+        assert_eq!(markers["length"], 1);
+        let name = markers["name"][0].as_u64().unwrap() as usize;
+        assert_eq!(thread["stringArray"][name], "IPC send");
+        assert_eq!(markers["data"][0]["otherPid"], 2.0);
+        assert!(markers["data"][0]["cause"]["stack"].is_u64());
+        // The receiving end is in the other process, whose data would add it.
+        assert_eq!(json["threads"][1]["markers"]["length"], 0);
     }
 
-    fn string_field_value(&self, field_index: u32) -> StringHandle {
-        match field_index {
-            0 => self.message,
-            i => *self.extra_fields.get(i as usize - 1).unwrap(),
+    /// Three distinct stacks across two libraries, matching
+    /// `fixtures/other/folded-stacks/synthetic.folded`.
+    fn synthetic_folded_process() -> (Profile, ProcessSampleData, UnresolvedStacks) {
+        use crate::shared::lib_mappings::{LibMappingAdd, LibMappingOp};
+        use crate::shared::test_support::{add_process_with_threads, add_synthetic_lib};
+        use crate::shared::types::{StackFrame, StackMode};
+
+        let mut profile = new_profile();
+        let (process, threads) = add_process_with_threads(&mut profile, 1);
+        let mut lib_mapping_ops = LibMappingOpQueue::default();
+        for (lib_index, start_avma, relative_address_at_start) in
+            [(0, 0x1000, 0), (1, 0x5000, 0x100)]
+        {
+            let lib = add_synthetic_lib(&mut profile, lib_index);
+            lib_mapping_ops.push(
+                0,
+                LibMappingOp::Add(LibMappingAdd {
+                    start_avma,
+                    end_avma: start_avma + 0x1000,
+                    relative_address_at_start,
+                    info: LibMappingInfo::new_lib(lib),
+                }),
+            );
+        }
+
+        // Stacks go from the root caller to the leaf.
+        let mut stacks = UnresolvedStacks::default();
+        let mut stack = |addresses: &[u64]| {
+            let (leaf, callers) = addresses.split_last().unwrap();
+            let frames = callers
+                .iter()
+                .map(|address| StackFrame::ReturnAddress(*address, StackMode::User))
+                .chain(std::iter::once(StackFrame::InstructionPointer(
+                    *leaf,
+                    StackMode::User,
+                )));
+            stacks.convert(frames)
+        };
+        let in_lib0 = stack(&[0x1010, 0x1020, 0x1030]);
+        let into_lib1 = stack(&[0x1010, 0x5010, 0x5020]);
+        let unmapped = stack(&[0x1010, 0x9000]);
+
+        let mut samples = UnresolvedSamples::default();
+        for (i, (stack, weight)) in [(in_lib0, 1), (into_lib1, 3), (unmapped, 1), (in_lib0, 1)]
+            .into_iter()
+            .enumerate()
+        {
+            samples.add_sample(
+                threads[0],
+                Timestamp::from_millis_since_reference(i as f64),
+                i as u64,
+                stack,
+                CpuDelta::ZERO,
+                weight,
+                None,
+            );
         }
+        let process_sample_data = ProcessSampleData::new(
+            samples,
+            lib_mapping_ops,
+            Vec::new(),
+            None,
+            Vec::new(),
+            Vec::new(),
+            process,
+            Vec::new(),
+        );
+        (profile, process_sample_data, stacks)
     }
 
-    fn number_field_value(&self, _field_index: u32) -> f64 {
-        unreachable!()
+    #[test]
+    fn folded_stacks_match_fixture() {
+        let (mut profile, process_sample_data, stacks) = synthetic_folded_process();
+        let folded_stacks_file = tempfile::NamedTempFile::new().unwrap();
+        let options = FlushOptions {
+            folded_stacks_file: Some(Arc::new(Mutex::new(folded_stacks_file.reopen().unwrap()))),
+            ..Default::default()
+        };
+        let category = CategoryHandle::OTHER.into();
+        process_sample_data.flush_samples_to_profile(
+            &mut profile,
+            category,
+            category,
+            &mut Vec::new(),
+            &stacks,
+            &options,
+        );
+        assert_eq!(
+            std::fs::read_to_string(folded_stacks_file.path()).unwrap(),
+            include_str!("../../../fixtures/other/folded-stacks/synthetic.folded")
+        );
     }
-}
 
-struct CustomGraphMarker {
-    marker_type: MarkerTypeHandle,
-    category: CategoryHandle,
-    name: StringHandle,
-    value: f64,
-}
+    #[test]
+    fn top_functions_ranks_leaf_self_weight() {
+        let (profile, process_sample_data, stacks) = synthetic_folded_process();
+        let top = process_sample_data.top_functions(
+            &stacks,
+            None,
+            |lib| profile.library_info(lib).name.clone(),
+            2,
+        );
+        assert_eq!(
+            top,
+            vec![
+                ("libsynthetic1.so+0x120".to_string(), 3),
+                ("libsynthetic0.so+0x30".to_string(), 2)
+            ]
+        );
+    }
 
-impl CustomGraphMarker {
-    pub fn create_marker_type(profile: &mut Profile, counter: &Counter) -> MarkerTypeHandle {
-        profile.register_marker_type(MarkerSchema {
-            type_name: format!("CustomGraph-{}", counter.name),
-            locations: vec![],
-            chart_label: None,
-            tooltip_label: None,
-            table_label: None,
-            fields: vec![MarkerFieldSchema {
-                key: "value".into(),
-                label: "Value".into(),
-                format: MarkerFieldFormat::Decimal,
-                searchable: false,
-            }],
-            static_fields: vec![],
-            graphs: vec![MarkerGraph {
-                key: "value".into(),
-                graph_type: MarkerGraphType::Line,
-                color: counter.color,
-            }],
-        })
+    #[test]
+    fn error_events_get_a_red_category() {
+        let mut profile = new_profile();
+        let process = profile.add_process("test", 1, Timestamp::from_millis_since_reference(0.0));
+        let thread = profile.add_thread(
+            process,
+            1,
+            Timestamp::from_millis_since_reference(0.0),
+            true,
+        );
+        let user_category = profile.add_category("User", CategoryColor::Yellow).into();
+        let kernel_category = profile.add_category("Kernel", CategoryColor::Orange).into();
+        let mut unknown_level = event_marker(thread, None, 3.0);
+        unknown_level
+            .event_or_span
+            .extra_fields
+            .insert("level".to_string(), "loud".to_string());
+        let markers = vec![
+            event_marker(thread, None, 1.0),
+            event_marker(thread, Some(EventLevel::Error), 2.0),
+            unknown_level,
+        ];
+        let process_sample_data = ProcessSampleData::new(
+            UnresolvedSamples::default(),
+            LibMappingOpQueue::default(),
+            Vec::new(),
+            None,
+            markers,
+            Vec::new(),
+            process,
+            Vec::new(),
+        );
+        process_sample_data.flush_samples_to_profile(
+            &mut profile,
+            user_category,
+            kernel_category,
+            &mut Vec::new(),
+            &UnresolvedStacks::default(),
+            &FlushOptions::default(),
+        );
+
+        let json = serde_json::to_value(&profile).unwrap();
+        let categories = &json["meta"]["categories"];
+        let markers = &json["threads"][0]["markers"];
+        let marker_category =
+            |i: usize| &categories[markers["category"][i].as_u64().unwrap() as usize];
+        assert_eq!(marker_category(0)["name"], "(Logging)");
+        assert_eq!(marker_category(0)["color"], "green");
+        assert_eq!(marker_category(1)["name"], "(Logging) ERROR");
+        assert_eq!(marker_category(1)["color"], "red");
+        assert_eq!(marker_category(2)["name"], "(Logging) other");
+        assert_eq!(marker_category(2)["color"], "grey");
+        let level = markers["data"][1]["level"].as_u64().unwrap() as usize;
+        assert_eq!(json["threads"][0]["stringArray"][level], "ERROR");
     }
 
-    pub fn new(
-        name: StringHandle,
-        category: CategoryHandle,
-        marker_type: MarkerTypeHandle,
-        value: f64,
-    ) -> Self {
-        Self {
-            marker_type,
-            category,
-            name,
-            value,
+    #[test]
+    fn simple_event_markers_share_one_marker_type() {
+        let mut profile = new_profile();
+        let process = profile.add_process("test", 1, Timestamp::from_millis_since_reference(0.0));
+        let thread = profile.add_thread(
+            process,
+            1,
+            Timestamp::from_millis_since_reference(0.0),
+            true,
+        );
+        let user_category = profile.add_category("User", CategoryColor::Yellow).into();
+        let kernel_category = profile.add_category("Kernel", CategoryColor::Orange).into();
+        let field_sets: [&[(&str, &str)]; 3] = [
+            &[],
+            &[("path", "/tmp/a \"quoted\"")],
+            &[("rows", "12"), ("table", "users")],
+        ];
+        let markers = field_sets
+            .iter()
+            .enumerate()
+            .map(|(i, fields)| {
+                let mut marker = event_marker(thread, Some(EventLevel::Info), i as f64);
+                marker.event_or_span.extra_fields = fields
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect();
+                marker
+            })
+            .collect();
+        let process_sample_data = ProcessSampleData::new(
+            UnresolvedSamples::default(),
+            LibMappingOpQueue::default(),
+            Vec::new(),
+            None,
+            markers,
+            Vec::new(),
+            process,
+            Vec::new(),
+        );
+        process_sample_data.flush_samples_to_profile(
+            &mut profile,
+            user_category,
+            kernel_category,
+            &mut Vec::new(),
+            &UnresolvedStacks::default(),
+            &FlushOptions {
+                simple_event_markers: true,
+                ..Default::default()
+            },
+        );
+
+        let json = serde_json::to_value(&profile).unwrap();
+        let schemas = json["meta"]["markerSchema"].as_array().unwrap();
+        let schema_names: Vec<_> = schemas.iter().map(|schema| &schema["name"]).collect();
+        assert_eq!(schema_names, ["Log"]);
+
+        let thread = &json["threads"][0];
+        let string = |index: &serde_json::Value| {
+            thread["stringArray"][index.as_u64().unwrap() as usize]
+                .as_str()
+                .unwrap()
+        };
+        for (i, fields) in field_sets.iter().enumerate() {
+            let data = &thread["markers"]["data"][i];
+            assert_eq!(data["type"], "Log");
+            assert_eq!(string(&data["message"]), "event");
+            assert_eq!(string(&data["target"]), "target");
+            assert_eq!(string(&data["level"]), "INFO");
+            let parsed: HashMap<String, String> =
+                serde_json::from_str(string(&data["fields"])).unwrap();
+            let expected: HashMap<String, String> = fields
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+            assert_eq!(parsed, expected);
         }
     }
-}
 
-impl Marker for CustomGraphMarker {
-    fn marker_type(&self, _profile: &mut Profile) -> MarkerTypeHandle {
-        self.marker_type
+    #[test]
+    fn downsampling_scales_the_weight_of_kept_samples() {
+        let mut profile = new_profile();
+        let start = Timestamp::from_millis_since_reference(0.0);
+        let process = profile.add_process("test", 1, start);
+        let thread_a = profile.add_thread(process, 1, start, true);
+        let thread_b = profile.add_thread(process, 2, start, false);
+        let mut unresolved_samples = UnresolvedSamples::default();
+        for i in 0..10u64 {
+            unresolved_samples.add_sample(
+                thread_a,
+                Timestamp::from_millis_since_reference(i as f64),
+                i * 1_000_000,
+                UnresolvedStackHandle::EMPTY,
+                CpuDelta::from_micros(1000),
+                1,
+                None,
+            );
+            if i < 7 {
+                unresolved_samples.add_sample(
+                    thread_b,
+                    Timestamp::from_millis_since_reference(i as f64),
+                    i * 1_000_000,
+                    UnresolvedStackHandle::EMPTY,
+                    CpuDelta::from_micros(1000),
+                    2,
+                    None,
+                );
+            }
+        }
+        let name = profile.intern_string("marker");
+        let marker_handle = profile.add_marker(
+            thread_a,
+            MarkerTiming::Instant(Timestamp::from_millis_since_reference(4.5)),
+            OtherEventMarker(name),
+        );
+        unresolved_samples.attach_stack_to_marker(
+            thread_a,
+            Timestamp::from_millis_since_reference(4.5),
+            4_500_000,
+            UnresolvedStackHandle::EMPTY,
+            marker_handle,
+        );
+        let markers = vec![event_marker(thread_a, None, 1.0)];
+        let process_sample_data = ProcessSampleData::new(
+            unresolved_samples,
+            LibMappingOpQueue::default(),
+            Vec::new(),
+            None,
+            markers,
+            Vec::new(),
+            process,
+            Vec::new(),
+        )
+        .downsample(3);
+
+        let weights = |thread| -> Vec<i32> {
+            process_sample_data
+                .unresolved_samples
+                .samples_and_markers()
+                .iter()
+                .filter(|sample| sample.thread_handle == thread)
+                .filter_map(|sample| match &sample.sample_or_marker {
+                    SampleOrMarker::Sample(data) => Some(data.weight),
+                    SampleOrMarker::MarkerHandle(_) => None,
+                })
+                .collect()
+        };
+        // Every third sample is kept, and stands in for three samples.
+        assert_eq!(weights(thread_a), [3, 3, 3, 3]);
+        assert_eq!(weights(thread_b), [6, 6, 6]);
+        let sample_times: Vec<_> = process_sample_data
+            .unresolved_samples
+            .samples_and_markers()
+            .iter()
+            .filter(|sample| sample.thread_handle == thread_a)
+            .map(|sample| sample.timestamp_mono)
+            .collect();
+        assert_eq!(
+            sample_times,
+            [0, 3_000_000, 6_000_000, 9_000_000, 4_500_000]
+        );
+        assert_eq!(process_sample_data.markers.len(), 1);
     }
 
-    fn name(&self, _profile: &mut Profile) -> StringHandle {
-        self.name
+    #[test]
+    fn inferred_numeric_fields_become_number_fields() {
+        let mut profile = new_profile();
+        let process = profile.add_process("test", 1, Timestamp::from_millis_since_reference(0.0));
+        let thread = profile.add_thread(
+            process,
+            1,
+            Timestamp::from_millis_since_reference(0.0),
+            true,
+        );
+        let user_category = profile.add_category("User", CategoryColor::Yellow).into();
+        let kernel_category = profile.add_category("Kernel", CategoryColor::Orange).into();
+        let field_types = Arc::new(HashMap::from([
+            ("rows".to_string(), FieldType::Integer),
+            ("ratio".to_string(), FieldType::Float),
+            ("cached".to_string(), FieldType::Bool),
+            ("table".to_string(), FieldType::String),
+        ]));
+        let markers = [("12", "0.5"), ("n/a", "2")]
+            .iter()
+            .enumerate()
+            .map(|(i, (rows, ratio))| {
+                let mut marker = event_marker(thread, None, i as f64);
+                marker.event_or_span.extra_fields = HashMap::from([
+                    ("rows".to_string(), rows.to_string()),
+                    ("ratio".to_string(), ratio.to_string()),
+                    ("cached".to_string(), "true".to_string()),
+                    ("table".to_string(), "users".to_string()),
+                ]);
+                marker.event_or_span.field_types = Some(field_types.clone());
+                marker
+            })
+            .collect();
+        let process_sample_data = ProcessSampleData::new(
+            UnresolvedSamples::default(),
+            LibMappingOpQueue::default(),
+            Vec::new(),
+            None,
+            markers,
+            Vec::new(),
+            process,
+            Vec::new(),
+        );
+        process_sample_data.flush_samples_to_profile(
+            &mut profile,
+            user_category,
+            kernel_category,
+            &mut Vec::new(),
+            &UnresolvedStacks::default(),
+            &FlushOptions::default(),
+        );
+
+        let json = serde_json::to_value(&profile).unwrap();
+        let schemas = json["meta"]["markerSchema"].as_array().unwrap();
+        assert_eq!(schemas.len(), 1);
+        assert_eq!(
+            schemas[0]["name"],
+            "Event-cached:bool_ratio:float_rows:integer_table"
+        );
+        let formats: HashMap<_, _> = schemas[0]["data"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|field| (field["key"].as_str().unwrap(), field["format"].clone()))
+            .collect();
+        assert_eq!(formats["rows"], "integer");
+        assert_eq!(formats["ratio"], "decimal");
+        assert_eq!(formats["cached"], "unique-string");
+        assert_eq!(formats["table"], "unique-string");
+
+        let thread = &json["threads"][0];
+        let data = &thread["markers"]["data"];
+        assert_eq!(data[0]["rows"], 12.0);
+        assert_eq!(data[0]["ratio"], 0.5);
+        assert_eq!(data[1]["ratio"], 2.0);
+        // A value which doesn't match the inferred type is kept as an
+        // untruncated field.
+        assert!(data[1]["rows"].is_null());
+        let untruncated = thread["stringArray"][data[1]["untruncated"].as_u64().unwrap() as usize]
+            .as_str()
+            .unwrap();
+        assert_eq!(untruncated, r#"{"rows":"n/a"}"#);
     }
 
-    fn category(&self, _profile: &mut Profile) -> CategoryHandle {
-        self.category
+    #[test]
+    fn timing_like_fields_become_duration_fields() {
+        let mut profile = new_profile();
+        let (process, threads) = add_process_with_threads(&mut profile, 1);
+        let user_category = profile.add_category("User", CategoryColor::Yellow).into();
+        let kernel_category = profile.add_category("Kernel", CategoryColor::Orange).into();
+        let latency = "1.2ms".to_string();
+        assert_eq!(FieldType::of_value(&latency), FieldType::Duration);
+        let mut span = span_marker(threads[0], "db", 0.0);
+        span.event_or_span.extra_fields = HashMap::from([("latency".to_string(), latency)]);
+        span.event_or_span.field_types = Some(Arc::new(HashMap::from([(
+            "latency".to_string(),
+            FieldType::Duration,
+        )])));
+        let process_sample_data = ProcessSampleData::new(
+            UnresolvedSamples::default(),
+            LibMappingOpQueue::default(),
+            Vec::new(),
+            None,
+            vec![span],
+            Vec::new(),
+            process,
+            Vec::new(),
+        );
+        process_sample_data.flush_samples_to_profile(
+            &mut profile,
+            user_category,
+            kernel_category,
+            &mut Vec::new(),
+            &UnresolvedStacks::default(),
+            &FlushOptions::default(),
+        );
+
+        let json = serde_json::to_value(&profile).unwrap();
+        let latency_format = json["meta"]["markerSchema"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .flat_map(|schema| schema["data"].as_array().unwrap())
+            .find(|field| field["key"] == "latency")
+            .map(|field| field["format"].clone());
+        assert_eq!(latency_format, Some("duration".into()));
+        let data = &json["threads"][0]["markers"]["data"][0];
+        assert_eq!(data["latency"], 1.2);
     }
 
-    fn string_field_value(&self, _field_index: u32) -> StringHandle {
-        unreachable!()
+    #[test]
+    fn long_event_messages_are_truncated() {
+        let mut profile = new_profile();
+        let process = profile.add_process("test", 1, Timestamp::from_millis_since_reference(0.0));
+        let thread = profile.add_thread(
+            process,
+            1,
+            Timestamp::from_millis_since_reference(0.0),
+            true,
+        );
+        let user_category = profile.add_category("User", CategoryColor::Yellow).into();
+        let kernel_category = profile.add_category("Kernel", CategoryColor::Orange).into();
+        // 10 KB, with the cap falling into the middle of the two-byte "é".
+        let long_message = format!("{}é{}", "a".repeat(99), "b".repeat(10_000));
+        let mut long_event = event_marker(thread, None, 1.0);
+        long_event.event_or_span.message = long_message.clone();
+        let short_event = event_marker(thread, None, 2.0);
+        let process_sample_data = ProcessSampleData::new(
+            UnresolvedSamples::default(),
+            LibMappingOpQueue::default(),
+            Vec::new(),
+            None,
+            vec![long_event, short_event],
+            Vec::new(),
+            process,
+            Vec::new(),
+        );
+        process_sample_data.flush_samples_to_profile(
+            &mut profile,
+            user_category,
+            kernel_category,
+            &mut Vec::new(),
+            &UnresolvedStacks::default(),
+            &FlushOptions {
+                max_marker_string_len: 100,
+                ..Default::default()
+            },
+        );
+
+        let json = serde_json::to_value(&profile).unwrap();
+        let thread = &json["threads"][0];
+        let string = |index: &serde_json::Value| {
+            thread["stringArray"][index.as_u64().unwrap() as usize]
+                .as_str()
+                .unwrap()
+        };
+        let long_data = &thread["markers"]["data"][0];
+        assert_eq!(
+            string(&long_data["message"]),
+            format!("{}…", "a".repeat(99))
+        );
+        let untruncated: HashMap<String, String> =
+            serde_json::from_str(string(&long_data["untruncated"])).unwrap();
+        assert_eq!(untruncated["message"], long_message);
+
+        let short_data = &thread["markers"]["data"][1];
+        assert_eq!(string(&short_data["message"]), "event");
+        assert_eq!(string(&short_data["untruncated"]), "");
+
+        let schema = json["meta"]["markerSchema"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|schema| schema["name"] == "Event-")
+            .unwrap();
+        let untruncated_field = schema["data"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|field| field["key"] == "untruncated")
+            .unwrap();
+        assert_eq!(untruncated_field["searchable"], false);
     }
 
-    fn number_field_value(&self, field_index: u32) -> f64 {
-        match field_index {
-            0 => self.value,
-            _ => unreachable!(),
-        }
+    #[test]
+    fn spilled_samples_are_flushed_one_chunk_at_a_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut profile = new_profile();
+        let (process, threads) = add_process_with_threads(&mut profile, 2);
+        let lib = add_synthetic_lib(&mut profile, 0);
+        let mut stacks = UnresolvedStacks::default();
+        let stack_handles = synthetic_stacks(&mut stacks, 5, 4);
+        let max_in_memory = 10;
+        let new_process_sample_data = || {
+            let mut samples = UnresolvedSamples::default();
+            samples.set_spill(Some(
+                SampleSpill::create(dir.path(), 1, max_in_memory).unwrap(),
+            ));
+            for i in 0..100u64 {
+                samples.add_sample(
+                    threads[i as usize % 2],
+                    Timestamp::from_millis_since_reference(i as f64),
+                    i * 1_000_000,
+                    stack_handles[i as usize % 5],
+                    CpuDelta::ZERO,
+                    1,
+                    None,
+                );
+            }
+            ProcessSampleData::new(
+                samples,
+                synthetic_lib_mapping(lib),
+                Vec::new(),
+                None,
+                Vec::new(),
+                Vec::new(),
+                process,
+                Vec::new(),
+            )
+        };
+
+        let data = new_process_sample_data();
+        assert!(data.unresolved_samples.samples_and_markers().len() <= max_in_memory);
+        assert_eq!(data.sample_count(), 100);
+        let chunk_lens: Vec<_> = data
+            .unresolved_samples
+            .into_chunks()
+            .map(|chunk| chunk.len())
+            .collect();
+        assert!(chunk_lens.len() > 1);
+        assert!(chunk_lens.iter().all(|len| *len <= max_in_memory));
+        assert_eq!(chunk_lens.iter().sum::<usize>(), 100);
+
+        let category = CategoryHandle::OTHER.into();
+        new_process_sample_data().flush_samples_to_profile(
+            &mut profile,
+            category,
+            category,
+            &mut Vec::new(),
+            &stacks,
+            &FlushOptions::default(),
+        );
+        let json = serde_json::to_value(&profile).unwrap();
+        let sample_count: u64 = json["threads"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|thread| thread["samples"]["length"].as_u64().unwrap())
+            .sum();
+        assert_eq!(sample_count, 100);
+        // The spill files are deleted once they're read back.
+        assert!(std::fs::read_dir(dir.path()).unwrap().next().is_none());
     }
 }
@@ -0,0 +1,304 @@
+//! Appending a newly recorded profile to an earlier one, for
+//! `samply record --reuse-profile`.
+//!
+//! This works on the serialized JSON of the two profiles, because there is
+//! no way to load a saved profile back into a `Profile`. Threads are mostly
+//! self-contained in the processed profile format, with their own string
+//! and function tables; only their indexes into the global libraries,
+//! categories and marker schemas, and their timestamps, need fixing up.
+
+use serde_json::Value;
+
+/// The `meta` property with the number of runs which were merged into the
+/// profile.
+const RUN_COUNT_META_KEY: &str = "samplyRunCount";
+
+/// The gap between the end of one run and the start of the next one on the
+/// merged timeline.
+const RUN_GAP_MS: f64 = 100.0;
+
+/// Appends the threads and counters of `next` to `previous`, as a new run.
+///
+/// The processes of each run get a run-index suffix on their pid and name,
+/// so that the runs show up as separate processes. The timestamps of `next`
+/// are moved to start a short gap after the end of `previous`. Libraries,
+/// categories and marker schemas which both profiles have are only kept
+/// once.
+pub fn append_run(previous: &mut Value, mut next: Value) -> Result<(), String> {
+    if !previous["threads"].is_array() || !next["threads"].is_array() {
+        return Err("expected a processed profile with a threads array".to_string());
+    }
+
+    let run_count = previous["meta"][RUN_COUNT_META_KEY].as_u64().unwrap_or(1);
+    if run_count == 1 {
+        tag_run(previous, 1);
+    }
+    let run_index = run_count + 1;
+    tag_run(&mut next, run_index);
+
+    let previous_end = time_values_mut(previous)
+        .into_iter()
+        .filter_map(|time| time.as_f64())
+        .reduce(f64::max);
+    let next_start = time_values_mut(&mut next)
+        .into_iter()
+        .filter_map(|time| time.as_f64())
+        .reduce(f64::min);
+    if let (Some(previous_end), Some(next_start)) = (previous_end, next_start) {
+        let offset = previous_end + RUN_GAP_MS - next_start;
+        for time in time_values_mut(&mut next) {
+            if let Some(t) = time.as_f64() {
+                *time = (t + offset).into();
+            }
+        }
+    }
+
+    let lib_map = merge_by(&mut previous["libs"], &next["libs"], |a, b| a == b);
+    let category_map = merge_by(
+        &mut previous["meta"]["categories"],
+        &next["meta"]["categories"],
+        |a, b| a["name"] == b["name"],
+    );
+    merge_by(
+        &mut previous["meta"]["markerSchema"],
+        &next["meta"]["markerSchema"],
+        |a, b| a["name"] == b["name"],
+    );
+
+    let previous_thread_count = previous["threads"].as_array().map_or(0, Vec::len);
+    let mut threads = match next["threads"].take() {
+        Value::Array(threads) => threads,
+        _ => Vec::new(),
+    };
+    for thread in &mut threads {
+        remap_column(&mut thread["resourceTable"]["lib"], &lib_map);
+        remap_column(&mut thread["nativeSymbols"]["libIndex"], &lib_map);
+        remap_column(&mut thread["frameTable"]["category"], &category_map);
+        remap_column(&mut thread["stackTable"]["category"], &category_map);
+        remap_column(&mut thread["markers"]["category"], &category_map);
+    }
+    if let Some(previous_threads) = previous["threads"].as_array_mut() {
+        previous_threads.extend(threads);
+    }
+
+    let mut counters = match next["counters"].take() {
+        Value::Array(counters) => counters,
+        _ => Vec::new(),
+    };
+    for counter in &mut counters {
+        if let Some(index) = counter["mainThreadIndex"].as_u64() {
+            counter["mainThreadIndex"] = (index + previous_thread_count as u64).into();
+        }
+    }
+    match &mut previous["counters"] {
+        Value::Array(previous_counters) => previous_counters.extend(counters),
+        previous_counters => *previous_counters = counters.into(),
+    }
+
+    previous["meta"][RUN_COUNT_META_KEY] = run_index.into();
+    Ok(())
+}
+
+/// Adds the run index to the pid and process name of all threads and
+/// counters.
+fn tag_run(profile: &mut Value, run_index: u64) {
+    let threads = profile["threads"].as_array_mut().into_iter().flatten();
+    for thread in threads {
+        if let Some(pid) = thread["pid"].as_str() {
+            thread["pid"] = format!("{pid}.{run_index}").into();
+        }
+        if let Some(name) = thread["processName"].as_str() {
+            thread["processName"] = format!("{name} (run {run_index})").into();
+        }
+    }
+    let counters = profile["counters"].as_array_mut().into_iter().flatten();
+    for counter in counters {
+        if let Some(pid) = counter["pid"].as_str() {
+            counter["pid"] = format!("{pid}.{run_index}").into();
+        }
+    }
+}
+
+/// All timestamps in the threads and counters of the profile.
+fn time_values_mut(profile: &mut Value) -> Vec<&mut Value> {
+    let mut times = Vec::new();
+    let Value::Object(profile) = profile else {
+        return times;
+    };
+    for (key, value) in profile.iter_mut() {
+        let items = value.as_array_mut().into_iter().flatten();
+        match key.as_str() {
+            "threads" => {
+                for thread in items.filter_map(Value::as_object_mut) {
+                    for (key, value) in thread.iter_mut() {
+                        match key.as_str() {
+                            "registerTime"
+                            | "unregisterTime"
+                            | "processStartupTime"
+                            | "processShutdownTime" => times.push(value),
+                            "samples" | "nativeAllocations" => {
+                                times.extend(column_mut(value, &["time"]))
+                            }
+                            "markers" => times.extend(column_mut(value, &["startTime", "endTime"])),
+                            _ => {}
+                        }
+                    }
+                }
+            }
+            "counters" => {
+                for counter in items {
+                    times.extend(column_mut(&mut counter["samples"], &["time"]));
+                }
+            }
+            _ => {}
+        }
+    }
+    times
+}
+
+/// The values of the named columns of a table.
+fn column_mut<'a>(table: &'a mut Value, keys: &[&str]) -> Vec<&'a mut Value> {
+    let Value::Object(table) = table else {
+        return Vec::new();
+    };
+    table
+        .iter_mut()
+        .filter(|(key, _)| keys.contains(&key.as_str()))
+        .filter_map(|(_, column)| column.as_array_mut())
+        .flatten()
+        .collect()
+}
+
+/// Appends the items of `next` to `previous` unless `previous` already has
+/// an item which is the same by `is_same`, and returns the new index of each
+/// item of `next`.
+fn merge_by(
+    previous: &mut Value,
+    next: &Value,
+    is_same: impl Fn(&Value, &Value) -> bool,
+) -> Vec<u64> {
+    if !previous.is_array() {
+        *previous = Value::Array(Vec::new());
+    }
+    let Value::Array(previous) = previous else {
+        unreachable!()
+    };
+    let next = next.as_array().map_or(&[][..], Vec::as_slice);
+    next.iter()
+        .map(|item| {
+            let index = match previous.iter().position(|p| is_same(p, item)) {
+                Some(index) => index,
+                None => {
+                    previous.push(item.clone());
+                    previous.len() - 1
+                }
+            };
+            index as u64
+        })
+        .collect()
+}
+
+fn remap_column(column: &mut Value, map: &[u64]) {
+    let values = column.as_array_mut().into_iter().flatten();
+    for value in values {
+        if let Some(&new_index) = value.as_u64().and_then(|index| map.get(index as usize)) {
+            *value = new_index.into();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use fxprof_processed_profile::{
+        CategoryColor, CategoryHandle, CpuDelta, Frame, FrameFlags, FrameInfo, Profile,
+        ReferenceTimestamp, SamplingInterval, Timestamp,
+    };
+
+    use super::*;
+
+    /// A profile with one process which samples `function` at 1ms intervals
+    /// from `start_ms` on, with the categories in `categories`.
+    fn run(start_ms: f64, function: &str, categories: &[&str]) -> Value {
+        let mut profile = Profile::new(
+            "test",
+            ReferenceTimestamp::from_millis_since_unix_epoch(0.0),
+            SamplingInterval::from_millis(1),
+        );
+        let start = Timestamp::from_millis_since_reference(start_ms);
+        let process = profile.add_process("bench", 42, start);
+        let thread = profile.add_thread(process, 42, start, true);
+        let mut category = CategoryHandle::OTHER;
+        for name in categories {
+            category = profile.add_category(name, CategoryColor::Blue);
+        }
+        let function = profile.intern_string(function);
+        for i in 0..5 {
+            let frame = FrameInfo {
+                frame: Frame::Label(function),
+                category_pair: category.into(),
+                flags: FrameFlags::empty(),
+            };
+            profile.add_sample(
+                thread,
+                Timestamp::from_millis_since_reference(start_ms + i as f64),
+                std::iter::once(frame),
+                CpuDelta::ZERO,
+                1,
+            );
+        }
+        serde_json::to_value(&profile).unwrap()
+    }
+
+    #[test]
+    fn two_runs_become_two_processes_on_one_timeline() {
+        let mut merged = run(0.0, "warmup", &["Setup"]);
+        append_run(&mut merged, run(0.0, "measure", &["Measure", "Setup"])).unwrap();
+
+        let threads = merged["threads"].as_array().unwrap();
+        assert_eq!(threads.len(), 2);
+        assert_eq!(threads[0]["pid"], "42.1");
+        assert_eq!(threads[1]["pid"], "42.2");
+        assert_eq!(threads[1]["processName"], "bench (run 2)");
+        assert_eq!(merged["meta"][RUN_COUNT_META_KEY], 2);
+
+        let times: Vec<f64> = threads
+            .iter()
+            .flat_map(|thread| thread["samples"]["time"].as_array().unwrap())
+            .map(|time| time.as_f64().unwrap())
+            .collect();
+        assert!(times.windows(2).all(|pair| pair[0] < pair[1]), "{times:?}");
+        assert_eq!(times[5], 4.0 + RUN_GAP_MS);
+
+        let category_names: Vec<_> = merged["meta"]["categories"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|category| category["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(category_names, ["Other", "Setup", "Measure"]);
+        // The second run's "Setup" frame now points at the first run's
+        // "Setup" category.
+        assert_eq!(threads[1]["frameTable"]["category"][0], 1);
+    }
+
+    #[test]
+    fn third_run_continues_the_run_count() {
+        let mut merged = run(0.0, "a", &[]);
+        append_run(&mut merged, run(0.0, "b", &[])).unwrap();
+        append_run(&mut merged, run(0.0, "c", &[])).unwrap();
+        let pids: Vec<_> = merged["threads"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|thread| thread["pid"].as_str().unwrap())
+            .collect();
+        assert_eq!(pids, ["42.1", "42.2", "42.3"]);
+        assert_eq!(merged["meta"][RUN_COUNT_META_KEY], 3);
+    }
+
+    #[test]
+    fn non_profile_json_is_rejected() {
+        let mut previous = serde_json::json!({"hello": "world"});
+        assert!(append_run(&mut previous, run(0.0, "a", &[])).is_err());
+    }
+}
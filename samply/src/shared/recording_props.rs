@@ -1,9 +1,19 @@
+use std::collections::HashMap;
 use std::ffi::OsString;
+use std::fs::File;
 use std::path::PathBuf;
-use std::time::Duration;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 
+use fxprof_processed_profile::{CategoryColor, ReferenceTimestamp};
 use serde_derive::{Deserialize, Serialize};
 
+use super::custom_markers::CustomMarkerSources;
+use super::marker_file::MarkerFileOptions;
+use super::process_sample_data::{FlushOptions, SpanTableLabel};
+use super::unresolved_samples::SampleCallback;
+
 #[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
 pub struct CoreClrProfileProps {
     pub enabled: bool,
@@ -38,6 +48,276 @@ pub struct RecordingProps {
     pub browsers: bool,
     #[allow(dead_code)]
     pub keep_etl: bool,
+    /// Whether to sample the user stack and registers for unwinding. If false,
+    /// samples have empty stacks, but counters and markers are still collected.
+    #[allow(dead_code)]
+    pub sample_stacks: bool,
+    /// Whether to poll `/proc/<pid>/io` for disk read and write throughput
+    /// counters (Linux only).
+    #[allow(dead_code)]
+    pub io_counters: bool,
+    /// Whether to create a FIFO whose lines become annotation markers
+    /// (Linux only).
+    #[allow(dead_code)]
+    pub annotation_fifo: bool,
+    /// Where to bind a Unix datagram socket whose datagrams are marker lines
+    /// (Linux only).
+    #[allow(dead_code)]
+    pub marker_socket: Option<PathBuf>,
+    /// Whether samply's own warnings during the recording become markers
+    /// (Linux only).
+    #[allow(dead_code)]
+    pub log_markers: bool,
+    /// Whether the lines which the launched command writes to stderr become
+    /// markers (Linux only).
+    #[allow(dead_code)]
+    pub stderr_markers: bool,
+    /// The directory where the oldest samples of processes with too many
+    /// samples in memory are written to (Linux only).
+    #[allow(dead_code)]
+    pub spill_dir: Option<PathBuf>,
+    /// Only keep the samples taken while a span with this name was open, as
+    /// told by the marker socket lines (Linux only).
+    #[allow(dead_code)]
+    pub record_only_during_span: Option<String>,
+    /// How long samples are held back for `record_only_during_span`, in
+    /// case the span's marker lines arrive late (Linux only).
+    #[allow(dead_code)]
+    pub span_lookback: Duration,
+    /// The hardware performance counters which are sampled alongside the
+    /// CPU samples (Linux only).
+    #[allow(dead_code)]
+    pub hw_counters: Vec<HwCounter>,
+    /// The hardware performance counters which are read with each CPU
+    /// sample, for per-thread counter tracks (Linux only).
+    #[allow(dead_code)]
+    pub read_counters: Vec<HwCounter>,
+    /// The event which drives the CPU sampling (Linux only). If `None`,
+    /// hardware CPU cycles are used where they're available, and the
+    /// software CPU clock otherwise.
+    #[allow(dead_code)]
+    pub cpu_clock_source: Option<ClockSource>,
+    /// Whether processes and threads created by the profiled process are
+    /// profiled too (Linux only).
+    #[allow(dead_code)]
+    pub inherit: bool,
+    /// Called synchronously for every new sample, before it's added to the
+    /// profile (Linux only). This runs on the thread which reads the perf
+    /// events, so it must be fast; a slow callback makes the kernel drop
+    /// events once the ring buffers fill up.
+    #[allow(dead_code)]
+    pub sample_callback: Option<SampleCallback>,
+    /// Whether to wait for a newline on stdin before attaching to the
+    /// profiled process (Linux only). The process runs unprofiled until then.
+    #[allow(dead_code)]
+    pub stdin_trigger: bool,
+    /// Whether to append the recording to the profile which is already at
+    /// `output_file`, as a new run, instead of overwriting it.
+    pub reuse_profile: bool,
+    /// What the weights of the recorded samples measure.
+    pub sample_weight_unit: SampleWeightUnit,
+}
+
+/// What the weight of a sample measures, for labeling the value axis of the
+/// call tree and the track graphs.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SampleWeightUnit {
+    /// Every sample has a weight of one, or the number of samples it stands
+    /// for.
+    #[default]
+    Samples,
+    /// Sample weights are durations in nanoseconds, e.g. set by a sample
+    /// callback.
+    Nanoseconds,
+    /// Sample weights are byte counts.
+    Bytes,
+}
+
+impl SampleWeightUnit {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SampleWeightUnit::Samples => "samples",
+            SampleWeightUnit::Nanoseconds => "nanoseconds",
+            SampleWeightUnit::Bytes => "bytes",
+        }
+    }
+}
+
+impl FromStr for SampleWeightUnit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "samples" => Ok(SampleWeightUnit::Samples),
+            "nanoseconds" | "ns" => Ok(SampleWeightUnit::Nanoseconds),
+            "bytes" => Ok(SampleWeightUnit::Bytes),
+            other => Err(format!(
+                "unknown sample weight unit '{other}', expected samples, nanoseconds or bytes"
+            )),
+        }
+    }
+}
+
+/// What `--validate-schemas` does with the marker schemas which break the
+/// rules of the marker schema format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchemaValidation {
+    /// Print the problems and save the profile anyway.
+    Warn,
+    /// Print the problems and exit with an error instead of saving the
+    /// profile.
+    Strict,
+}
+
+impl FromStr for SchemaValidation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "warn" => Ok(SchemaValidation::Warn),
+            "strict" => Ok(SchemaValidation::Strict),
+            other => Err(format!(
+                "unknown schema validation mode '{other}', expected warn or strict"
+            )),
+        }
+    }
+}
+
+/// How the tracks which samply adds for things other than threads are named:
+/// the per-CPU tracks and the marker category tracks. By default they're
+/// named like "CPU 3" and "db markers".
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyntheticTrackNaming {
+    /// Put in front of every synthetic track name, to keep them apart from
+    /// the names of real threads.
+    pub prefix: String,
+    /// The name of a CPU track, with `{cpu}` for the CPU number. When the
+    /// CPU topology is known, the tracks are named after their cores
+    /// instead.
+    pub cpu_format: String,
+    /// The name of a marker category track, with `{category}` for the
+    /// category.
+    pub category_format: String,
+}
+
+impl Default for SyntheticTrackNaming {
+    fn default() -> Self {
+        Self {
+            prefix: String::new(),
+            cpu_format: "CPU {cpu}".to_string(),
+            category_format: "{category} markers".to_string(),
+        }
+    }
+}
+
+impl SyntheticTrackNaming {
+    pub const CPU_PLACEHOLDER: &'static str = "{cpu}";
+    pub const CATEGORY_PLACEHOLDER: &'static str = "{category}";
+
+    /// Checks that `format` has `placeholder`, without which all tracks of
+    /// a kind would get the same name.
+    pub fn validate_format(format: &str, placeholder: &str) -> Result<String, String> {
+        if format.contains(placeholder) {
+            Ok(format.to_string())
+        } else {
+            Err(format!(
+                "track name format '{format}' doesn't contain {placeholder}"
+            ))
+        }
+    }
+
+    pub fn cpu_track_name(&self, cpu: u32) -> String {
+        let name = self
+            .cpu_format
+            .replace(Self::CPU_PLACEHOLDER, &cpu.to_string());
+        self.prefixed(&name)
+    }
+
+    pub fn category_track_name(&self, category: &str) -> String {
+        let name = self
+            .category_format
+            .replace(Self::CATEGORY_PLACEHOLDER, category);
+        self.prefixed(&name)
+    }
+
+    /// `name` with the prefix, for synthetic tracks with a name of their
+    /// own, such as the CPU tracks named after their cores.
+    pub fn prefixed(&self, name: &str) -> String {
+        format!("{}{name}", self.prefix)
+    }
+}
+
+/// A hardware performance counter event, which can be sampled alongside the
+/// CPU samples with `--hw-counter`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HwCounter {
+    CacheMisses,
+    BranchMisses,
+    Instructions,
+    Cycles,
+}
+
+impl HwCounter {
+    #[allow(dead_code)]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HwCounter::CacheMisses => "cache-misses",
+            HwCounter::BranchMisses => "branch-misses",
+            HwCounter::Instructions => "instructions",
+            HwCounter::Cycles => "cycles",
+        }
+    }
+
+    /// The number of events between two samples. Misses are much rarer than
+    /// instructions and cycles, so they are sampled more finely.
+    #[allow(dead_code)]
+    pub fn sample_period(&self) -> u64 {
+        match self {
+            HwCounter::CacheMisses | HwCounter::BranchMisses => 10_000,
+            HwCounter::Instructions | HwCounter::Cycles => 10_000_000,
+        }
+    }
+}
+
+impl FromStr for HwCounter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cache-misses" => Ok(HwCounter::CacheMisses),
+            "branch-misses" => Ok(HwCounter::BranchMisses),
+            "instructions" => Ok(HwCounter::Instructions),
+            "cycles" => Ok(HwCounter::Cycles),
+            other => Err(format!(
+                "unknown hardware counter '{other}', expected cache-misses, branch-misses, instructions or cycles"
+            )),
+        }
+    }
+}
+
+/// The perf event which drives the CPU sampling on Linux, for
+/// `--cpu-clock-source`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockSource {
+    /// `PERF_COUNT_HW_CPU_CYCLES`, which is more precise, but needs a PMU
+    /// which the kernel exposes, so it's often unavailable in VMs.
+    HardwareCycles,
+    /// `PERF_COUNT_SW_CPU_CLOCK`, a timer which works everywhere.
+    SoftwareClock,
+}
+
+impl FromStr for ClockSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hardware" | "cycles" => Ok(ClockSource::HardwareCycles),
+            "software" | "cpu-clock" => Ok(ClockSource::SoftwareClock),
+            other => Err(format!(
+                "unknown CPU clock source '{other}', expected hardware or software"
+            )),
+        }
+    }
 }
 
 /// Which process(es) to record.
@@ -68,11 +348,18 @@ impl RecordingMode {
 pub struct ProfileCreationProps {
     pub profile_name: Option<String>,
     pub fallback_profile_name: String,
+    /// If set, the wall-clock time at which the profile is shown to start,
+    /// instead of the actual start of the recording.
+    pub profile_start_time: Option<SystemTime>,
     /// Only include the main thread of each process.
     #[allow(dead_code)]
     pub main_thread_only: bool,
     /// Merge non-overlapping threads of the same name.
     pub reuse_threads: bool,
+    /// When merging the JIT functions of reused processes, only merge the
+    /// ones with identical machine code.
+    #[allow(dead_code)]
+    pub exact_jit_function_reuse: bool,
     /// Fold repeated frames at the base of the stack.
     pub fold_recursive_prefix: bool,
     /// Unlink jitdump/marker files
@@ -101,12 +388,88 @@ pub struct ProfileCreationProps {
     /// Whether to emit context switch markers.
     #[allow(dead_code)]
     pub should_emit_cswitch_markers: bool,
+    /// Whether wakeups of threads in other processes become "IPC send" and
+    /// "IPC receive" markers.
+    #[allow(dead_code)]
+    pub ipc_edges: bool,
+    /// If set, the per-CPU tracks get one marker per run of a thread instead
+    /// of context switch markers, and runs shorter than this are combined.
+    #[allow(dead_code)]
+    pub cpu_run_intervals: Option<std::time::Duration>,
     /// Whether to emit markers for mmap events.
     #[allow(dead_code)]
     pub should_emit_mmap_markers: bool,
     /// Whether to attach markers from profiled program to the profiled thread.
     #[allow(dead_code)]
     pub attach_markers_to_profiled_thread: bool,
+    /// Whether to list the marker and counter files which were read in the profile metadata.
+    #[allow(dead_code)]
+    pub embed_source_files: bool,
+    /// Span categories whose samples should be given the span's category.
+    pub categorize_samples_by_span: Vec<String>,
+    /// Whether to put all events from marker files under one "Log" marker type.
+    pub simple_event_markers: bool,
+    /// Whether to infer the types of the extra fields of marker files, so
+    /// that numeric fields are shown as numbers.
+    pub infer_marker_field_types: bool,
+    /// Whether to keep the source line of each marker from a marker file in
+    /// a `__raw` field, for debugging.
+    pub raw_marker_lines: bool,
+    /// Whether the first invalid line of a marker file is a fatal error,
+    /// instead of being skipped with a warning.
+    pub strict_markers: bool,
+    /// The number of unended spans in a marker file above which a warning
+    /// is printed.
+    pub marker_orphan_warning_threshold: usize,
+    /// The length in bytes beyond which string fields of marker file events
+    /// and spans are truncated.
+    pub max_marker_string_len: usize,
+    /// What the marker table shows for spans from marker files.
+    pub span_table_label: SpanTableLabel,
+    /// Colors for span categories, instead of the colors picked by name.
+    pub span_category_colors: HashMap<String, CategoryColor>,
+    /// Whether markers from marker files go on one track per category, the
+    /// span category or the event target, instead of on the thread which
+    /// emitted them.
+    pub category_marker_tracks: bool,
+    /// If set, add a "Samples" counter with the number of samples in each
+    /// window of this length.
+    pub sample_rate_counter_window: Option<std::time::Duration>,
+    /// Whether to add a counter per span category with the cumulative busy
+    /// time of its spans.
+    pub span_busy_counters: bool,
+    /// If set, only this many counters with the highest peaks get a track
+    /// of their own in each process, and the rest are summed up into one.
+    pub max_counter_tracks: Option<usize>,
+    /// If set, the spans of this category from one process are shown as
+    /// phases on the main thread of every process and on the per-CPU tracks.
+    #[allow(dead_code)]
+    pub global_phase_category: Option<String>,
+    /// If set, sample addresses which aren't in any library mapping are
+    /// attributed to a mapping within this many bytes.
+    pub aslr_tolerance: Option<u64>,
+    /// If set, the span stats of the marker files are printed for each
+    /// window of this length, instead of once for the whole recording.
+    pub stats_flush_interval: Option<std::time::Duration>,
+    /// Sources of extra markers, for code which embeds samply's conversion,
+    /// and for `--stderr-markers` (Linux only). These are called for each
+    /// process when it is finished.
+    #[allow(dead_code)]
+    pub custom_marker_sources: CustomMarkerSources,
+    /// If set, the marker schemas of the profile are checked before it's
+    /// saved.
+    pub validate_schemas: Option<SchemaValidation>,
+    /// The names of the per-CPU tracks and the marker category tracks.
+    pub synthetic_track_naming: SyntheticTrackNaming,
+    /// Whether to print how long each phase of creating the profile took.
+    pub time_report: bool,
+    /// If set, the samples' stacks are also written to this file in the
+    /// folded format of flamegraph tools.
+    pub folded_stacks_file: Option<Arc<Mutex<File>>>,
+    /// If set, only every n-th sample of each thread is kept.
+    pub downsample_factor: Option<u32>,
+    /// If set, this many of the hottest functions of each process are printed.
+    pub top_functions: Option<usize>,
 }
 
 impl ProfileCreationProps {
@@ -115,6 +478,48 @@ impl ProfileCreationProps {
             .as_deref()
             .unwrap_or(&self.fallback_profile_name)
     }
+
+    /// The reference timestamp for a profile which was recorded with
+    /// `recorded`, or the `profile_start_time` override. All timestamps in the
+    /// profile are relative to the reference timestamp, so the override only
+    /// shifts the displayed wall-clock time.
+    pub fn reference_timestamp(&self, recorded: ReferenceTimestamp) -> ReferenceTimestamp {
+        match self.profile_start_time {
+            Some(start_time) => ReferenceTimestamp::from_system_time(start_time),
+            None => recorded,
+        }
+    }
+
+    /// The options for turning the data of each process into profile data.
+    pub fn flush_options(&self) -> FlushOptions {
+        FlushOptions {
+            span_sample_categories: self.categorize_samples_by_span.clone(),
+            simple_event_markers: self.simple_event_markers,
+            max_marker_string_len: self.max_marker_string_len,
+            span_table_label: self.span_table_label.clone(),
+            span_category_colors: self.span_category_colors.clone(),
+            category_marker_tracks: self.category_marker_tracks,
+            sample_rate_counter_window: self.sample_rate_counter_window,
+            span_busy_counters: self.span_busy_counters,
+            max_counter_tracks: self.max_counter_tracks,
+            stats_flush_interval: self.stats_flush_interval,
+            aslr_tolerance: self.aslr_tolerance,
+            track_naming: self.synthetic_track_naming.clone(),
+            folded_stacks_file: self.folded_stacks_file.clone(),
+            downsample_factor: self.downsample_factor,
+            top_functions: self.top_functions,
+        }
+    }
+
+    /// The options for reading the marker files of the profiled processes.
+    pub fn marker_file_options(&self) -> MarkerFileOptions {
+        MarkerFileOptions {
+            type_inference: self.infer_marker_field_types,
+            raw_lines: self.raw_marker_lines,
+            strict: self.strict_markers,
+            orphan_warning_threshold: self.marker_orphan_warning_threshold,
+        }
+    }
 }
 
 /// Properties which are meaningful for launching and recording a fresh process.
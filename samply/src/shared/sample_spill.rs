@@ -0,0 +1,530 @@
+//! Spilling the oldest unresolved samples of a process to disk during long
+//! recordings, for `samply record --spill-dir`.
+//!
+//! The spill file is a sequence of chunks. Each chunk is a little-endian
+//! `u32` record count followed by that many fixed-size records. The profile
+//! handles in a sample can't be serialized, so the records refer to them by
+//! their index in tables which stay in memory; these tables are small, since
+//! processes have few threads, label frames and stack-carrying markers
+//! compared to samples. Stack handles are written as they are, because
+//! `UnresolvedStacks` isn't spilled.
+//!
+//! Spilled samples are read back one chunk at a time when the profile is
+//! made, so that no more than about `max_in_memory` samples of a process are
+//! in memory at any time.
+//!
+//! Spill files are named `samply-<pid>-<random>.spill` and are deleted when
+//! the process's samples have been read back. If samply is killed instead,
+//! they stay behind for `samply recover`, see `spill_recovery`.
+
+use std::borrow::Borrow;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, ErrorKind, Read, Seek, SeekFrom, Take, Write};
+use std::path::Path;
+
+use fxprof_processed_profile::{CpuDelta, FrameInfo, MarkerHandle, ThreadHandle, Timestamp};
+use tempfile::NamedTempFile;
+
+use super::types::FastHashMap;
+use super::unresolved_samples::{
+    SampleData, SampleOrMarker, UnresolvedSampleOrMarker, UnresolvedStackHandle,
+};
+
+/// The number of in-memory samples per process above which the oldest half
+/// is spilled.
+pub const DEFAULT_MAX_IN_MEMORY_SAMPLES: usize = 1_000_000;
+
+const NO_LABEL_FRAME: u32 = u32::MAX;
+const KIND_SAMPLE: u8 = 0;
+const KIND_MARKER: u8 = 1;
+
+/// thread, timestamp, timestamp_mono, stack, label frame, kind, and the
+/// sample's CPU delta and weight or the marker index.
+const RECORD_SIZE: usize = 4 + 8 + 8 + 4 + 4 + 1 + 8 + 4;
+
+/// The spill file of one process's samples and markers.
+#[derive(Debug)]
+pub struct SampleSpill {
+    /// A file in the spill directory, which is deleted when this is dropped.
+    file: NamedTempFile,
+    pid: i32,
+    /// The length of the file up to the end of the last complete chunk.
+    len: u64,
+    max_in_memory: usize,
+    spilled_count: usize,
+    thread_handles: Vec<ThreadHandle>,
+    thread_indexes: FastHashMap<ThreadHandle, u32>,
+    label_frames: Vec<FrameInfo>,
+    label_frame_indexes: BTreeMap<FrameInfo, u32>,
+    marker_handles: Vec<MarkerHandle>,
+}
+
+impl SampleSpill {
+    /// Creates a spill file for the process `pid` in `dir`. Samples are
+    /// spilled once there are more than `max_in_memory` of them in memory.
+    pub fn create(dir: &Path, pid: i32, max_in_memory: usize) -> std::io::Result<Self> {
+        let file = tempfile::Builder::new()
+            .prefix(&format!("samply-{pid}-"))
+            .suffix(".spill")
+            .tempfile_in(dir)?;
+        Ok(Self {
+            file,
+            pid,
+            len: 0,
+            max_in_memory: max_in_memory.max(1),
+            spilled_count: 0,
+            thread_handles: Vec::new(),
+            thread_indexes: Default::default(),
+            label_frames: Vec::new(),
+            label_frame_indexes: BTreeMap::new(),
+            marker_handles: Vec::new(),
+        })
+    }
+
+    /// Creates another spill file in the same directory, with the same
+    /// limit, for samples which are derived from the ones in this one.
+    pub fn create_sibling(&self) -> std::io::Result<Self> {
+        let dir = self.file.path().parent().unwrap_or(Path::new("."));
+        Self::create(dir, self.pid, self.max_in_memory)
+    }
+
+    pub fn max_in_memory(&self) -> usize {
+        self.max_in_memory
+    }
+
+    /// Don't spill any more samples, e.g. because writing failed. The ones
+    /// which were spilled are still read back.
+    pub fn stop_spilling(&mut self) {
+        self.max_in_memory = usize::MAX;
+    }
+
+    /// The number of samples and markers in the spill file.
+    pub fn spilled_count(&self) -> usize {
+        self.spilled_count
+    }
+
+    pub fn path(&self) -> &Path {
+        self.file.path()
+    }
+
+    /// The threads which the thread indexes in the spill file refer to.
+    pub fn thread_handles(&self) -> &[ThreadHandle] {
+        &self.thread_handles
+    }
+
+    /// Appends `chunk` to the spill file. If writing fails, the file is
+    /// truncated to the previous chunk, so that it stays readable.
+    pub fn write_chunk(&mut self, chunk: &[UnresolvedSampleOrMarker]) -> std::io::Result<()> {
+        let mut buf = Vec::with_capacity(4 + chunk.len() * RECORD_SIZE);
+        buf.extend_from_slice(&(chunk.len() as u32).to_le_bytes());
+        for sample in chunk {
+            self.encode(sample, &mut buf);
+        }
+        let file = self.file.as_file_mut();
+        let result = file
+            .seek(SeekFrom::Start(self.len))
+            .and_then(|_| file.write_all(&buf));
+        if let Err(e) = result {
+            let _ = file.set_len(self.len);
+            return Err(e);
+        }
+        self.len += buf.len() as u64;
+        self.spilled_count += chunk.len();
+        Ok(())
+    }
+
+    /// Reads the spilled samples and markers back one chunk at a time, in
+    /// the order in which they were spilled.
+    pub fn chunks(&self) -> std::io::Result<SpillChunks<&Self>> {
+        SpillChunks::new(self)
+    }
+
+    /// Like `chunks`, but the spill file is deleted once the returned
+    /// iterator is dropped.
+    pub fn into_chunks(self) -> std::io::Result<SpillChunks<Self>> {
+        SpillChunks::new(self)
+    }
+
+    /// Replaces the stack of every spilled sample and marker by
+    /// `remap(stack)`. The samples are written to a new spill file, chunk by
+    /// chunk, which then replaces this one.
+    pub fn remap_stacks(
+        &mut self,
+        mut remap: impl FnMut(UnresolvedStackHandle) -> UnresolvedStackHandle,
+    ) -> std::io::Result<()> {
+        let mut remapped = self.create_sibling()?;
+        for chunk in self.chunks()? {
+            let mut chunk = chunk?;
+            for sample in &mut chunk {
+                sample.stack = remap(sample.stack);
+            }
+            remapped.write_chunk(&chunk)?;
+        }
+        remapped.max_in_memory = self.max_in_memory;
+        *self = remapped;
+        Ok(())
+    }
+
+    fn encode(&mut self, sample: &UnresolvedSampleOrMarker, buf: &mut Vec<u8>) {
+        let thread_index = *self
+            .thread_indexes
+            .entry(sample.thread_handle)
+            .or_insert_with(|| {
+                self.thread_handles.push(sample.thread_handle);
+                self.thread_handles.len() as u32 - 1
+            });
+        let label_frame_index = match &sample.extra_label_frame {
+            Some(frame) => match self.label_frame_indexes.get(frame) {
+                Some(index) => *index,
+                None => {
+                    let index = self.label_frames.len() as u32;
+                    self.label_frames.push(frame.clone());
+                    self.label_frame_indexes.insert(frame.clone(), index);
+                    index
+                }
+            },
+            None => NO_LABEL_FRAME,
+        };
+        buf.extend_from_slice(&thread_index.to_le_bytes());
+        buf.extend_from_slice(&sample.timestamp.as_nanos_since_reference().to_le_bytes());
+        buf.extend_from_slice(&sample.timestamp_mono.to_le_bytes());
+        buf.extend_from_slice(&sample.stack.0.to_le_bytes());
+        buf.extend_from_slice(&label_frame_index.to_le_bytes());
+        match &sample.sample_or_marker {
+            SampleOrMarker::Sample(data) => {
+                buf.push(KIND_SAMPLE);
+                buf.extend_from_slice(&data.cpu_delta.as_micros().to_le_bytes());
+                buf.extend_from_slice(&data.weight.to_le_bytes());
+            }
+            SampleOrMarker::MarkerHandle(marker_handle) => {
+                buf.push(KIND_MARKER);
+                let marker_index = self.marker_handles.len() as u64;
+                self.marker_handles.push(*marker_handle);
+                buf.extend_from_slice(&marker_index.to_le_bytes());
+                buf.extend_from_slice(&0i32.to_le_bytes());
+            }
+        }
+    }
+
+    fn resolve(&self, record: SpillRecord) -> UnresolvedSampleOrMarker {
+        let sample_or_marker = match record.data {
+            SpillRecordData::Sample(data) => SampleOrMarker::Sample(data),
+            SpillRecordData::Marker(index) => {
+                SampleOrMarker::MarkerHandle(self.marker_handles[index as usize])
+            }
+        };
+        UnresolvedSampleOrMarker {
+            thread_handle: self.thread_handles[record.thread_index as usize],
+            timestamp: record.timestamp,
+            timestamp_mono: record.timestamp_mono,
+            stack: record.stack,
+            extra_label_frame: record
+                .label_frame_index
+                .map(|index| self.label_frames[index as usize].clone()),
+            sample_or_marker,
+        }
+    }
+}
+
+/// The chunks of a spill file, as returned by `SampleSpill::chunks` and
+/// `SampleSpill::into_chunks`. Yields an error and stops if the file can't
+/// be read or has fewer samples than were spilled.
+#[derive(Debug)]
+pub struct SpillChunks<S: Borrow<SampleSpill>> {
+    spill: S,
+    reader: BufReader<Take<File>>,
+    read_count: usize,
+    done: bool,
+}
+
+impl<S: Borrow<SampleSpill>> SpillChunks<S> {
+    fn new(spill: S) -> std::io::Result<Self> {
+        let sample_spill = spill.borrow();
+        // A separate file handle, so that reading doesn't move the position
+        // which chunks are written at.
+        let file = sample_spill.file.reopen()?;
+        let reader = BufReader::new(file.take(sample_spill.len));
+        Ok(Self {
+            spill,
+            reader,
+            read_count: 0,
+            done: false,
+        })
+    }
+}
+
+impl<S: Borrow<SampleSpill>> Iterator for SpillChunks<S> {
+    type Item = std::io::Result<Vec<UnresolvedSampleOrMarker>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let spill = self.spill.borrow();
+        let result = match read_spill_chunk(&mut self.reader) {
+            Ok(Some(records)) => {
+                self.read_count += records.len();
+                return Some(Ok(records
+                    .into_iter()
+                    .map(|record| spill.resolve(record))
+                    .collect()));
+            }
+            Ok(None) if self.read_count == spill.spilled_count => None,
+            Ok(None) => Some(Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "the spill file is incomplete",
+            ))),
+            Err(e) => Some(Err(e)),
+        };
+        self.done = true;
+        result
+    }
+}
+
+/// A sample or marker as it is stored in a spill file, with indexes into the
+/// tables of the `SampleSpill` which wrote it.
+#[derive(Debug, Clone)]
+pub struct SpillRecord {
+    pub thread_index: u32,
+    pub timestamp: Timestamp,
+    pub timestamp_mono: u64,
+    pub stack: UnresolvedStackHandle,
+    pub label_frame_index: Option<u32>,
+    pub data: SpillRecordData,
+}
+
+#[derive(Debug, Clone)]
+pub enum SpillRecordData {
+    Sample(SampleData),
+    /// The index of a stack-carrying marker.
+    Marker(u32),
+}
+
+/// Reads the records of the next chunk of a spill file, or returns `None` at
+/// the end of the file. If the file ends within the chunk, the error is
+/// `UnexpectedEof`; this happens if samply was killed while writing it.
+pub fn read_spill_chunk(reader: &mut impl Read) -> std::io::Result<Option<Vec<SpillRecord>>> {
+    let mut count_buf = [0; 4];
+    match read_up_to(reader, &mut count_buf)? {
+        0 => return Ok(None),
+        4 => {}
+        _ => return Err(ErrorKind::UnexpectedEof.into()),
+    }
+    let chunk_len = u32::from_le_bytes(count_buf) as u64 * RECORD_SIZE as u64;
+    let mut chunk = Vec::new();
+    reader.take(chunk_len).read_to_end(&mut chunk)?;
+    if chunk.len() as u64 != chunk_len {
+        return Err(ErrorKind::UnexpectedEof.into());
+    }
+    Ok(Some(
+        chunk
+            .chunks_exact(RECORD_SIZE)
+            .map(|record| decode(record.try_into().unwrap()))
+            .collect(),
+    ))
+}
+
+/// Like `read_exact`, but returns how much was read if the reader ends
+/// before `buf` is full.
+fn read_up_to(reader: &mut impl Read, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(len)
+}
+
+fn decode(record: &[u8; RECORD_SIZE]) -> SpillRecord {
+    let u32_at = |offset: usize| u32::from_le_bytes(record[offset..offset + 4].try_into().unwrap());
+    let u64_at = |offset: usize| u64::from_le_bytes(record[offset..offset + 8].try_into().unwrap());
+    let label_frame_index = u32_at(24);
+    let payload = u64_at(29);
+    let data = match record[28] {
+        KIND_SAMPLE => SpillRecordData::Sample(SampleData {
+            cpu_delta: CpuDelta::from_micros(payload),
+            weight: u32_at(37) as i32,
+        }),
+        _ => SpillRecordData::Marker(payload as u32),
+    };
+    SpillRecord {
+        thread_index: u32_at(0),
+        timestamp: Timestamp::from_nanos_since_reference(u64_at(4)),
+        timestamp_mono: u64_at(12),
+        stack: UnresolvedStackHandle(u32_at(20)),
+        label_frame_index: (label_frame_index != NO_LABEL_FRAME).then_some(label_frame_index),
+        data,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use fxprof_processed_profile::{
+        CategoryColor, CategoryHandle, Frame, FrameFlags, MarkerTiming,
+    };
+
+    use super::*;
+    use crate::shared::process_sample_data::{FlushOptions, OtherEventMarker, ProcessSampleData};
+    use crate::shared::test_support::{
+        add_process_with_threads, add_synthetic_lib, new_profile, synthetic_lib_mapping,
+        synthetic_stacks,
+    };
+    use crate::shared::unresolved_samples::{UnresolvedSamples, UnresolvedStacks};
+
+    /// Records the same stream of samples, labeled samples and marker stacks
+    /// into a fresh profile, spilling with `spill`, and returns the profile
+    /// JSON.
+    fn record(spill: Option<SampleSpill>) -> serde_json::Value {
+        let mut profile = new_profile();
+        let (process, threads) = add_process_with_threads(&mut profile, 2);
+        let lib = add_synthetic_lib(&mut profile, 0);
+        let user_category = profile.add_category("User", CategoryColor::Yellow).into();
+        let kernel_category = profile.add_category("Kernel", CategoryColor::Orange).into();
+        let mut stacks = UnresolvedStacks::default();
+        let stack_handles = synthetic_stacks(&mut stacks, 5, 4);
+        let label = FrameInfo {
+            frame: Frame::Label(profile.intern_string("worker")),
+            category_pair: CategoryHandle::OTHER.into(),
+            flags: FrameFlags::empty(),
+        };
+        let marker_name = profile.intern_string("marker");
+
+        let mut samples = UnresolvedSamples::default();
+        samples.set_spill(spill);
+        for i in 0..40u64 {
+            let thread = threads[i as usize % 2];
+            let timestamp = Timestamp::from_millis_since_reference(i as f64);
+            samples.add_sample(
+                thread,
+                timestamp,
+                i * 1_000_000,
+                stack_handles[i as usize % 5],
+                CpuDelta::from_micros(100 * i),
+                1 + i as i32 % 3,
+                (i % 4 == 0).then(|| label.clone()),
+            );
+            if i % 7 == 0 {
+                let marker_handle = profile.add_marker(
+                    thread,
+                    MarkerTiming::Instant(timestamp),
+                    OtherEventMarker(marker_name),
+                );
+                samples.attach_stack_to_marker(
+                    thread,
+                    timestamp,
+                    i * 1_000_000,
+                    stack_handles[0],
+                    marker_handle,
+                );
+            }
+        }
+
+        ProcessSampleData::new(
+            samples,
+            synthetic_lib_mapping(lib),
+            Vec::new(),
+            None,
+            Vec::new(),
+            Vec::new(),
+            process,
+            Vec::new(),
+        )
+        .flush_samples_to_profile(
+            &mut profile,
+            user_category,
+            kernel_category,
+            &mut Vec::new(),
+            &stacks,
+            &FlushOptions::default(),
+        );
+        serde_json::to_value(&profile).unwrap()
+    }
+
+    #[test]
+    fn spilled_samples_give_the_same_profile() {
+        let dir = tempfile::tempdir().unwrap();
+        let spill = SampleSpill::create(dir.path(), 1, 8).unwrap();
+        let with_spill = record(Some(spill));
+        let without_spill = record(None);
+        assert_eq!(with_spill["threads"][0]["samples"]["length"], 20);
+        assert_eq!(with_spill, without_spill);
+    }
+
+    #[test]
+    fn chunks_are_read_back_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut spill = SampleSpill::create(dir.path(), 1, 2).unwrap();
+        let mut samples = UnresolvedSamples::default();
+        let mut profile = new_profile();
+        let (_process, threads) = add_process_with_threads(&mut profile, 1);
+        for i in 0..6 {
+            samples.add_sample(
+                threads[0],
+                Timestamp::from_nanos_since_reference(i),
+                i,
+                UnresolvedStackHandle::EMPTY,
+                CpuDelta::ZERO,
+                1,
+                None,
+            );
+        }
+        spill
+            .write_chunk(&samples.samples_and_markers()[..2])
+            .unwrap();
+        spill
+            .write_chunk(&samples.samples_and_markers()[2..5])
+            .unwrap();
+        assert_eq!(spill.spilled_count(), 5);
+        let chunk_times: Vec<Vec<_>> = spill
+            .chunks()
+            .unwrap()
+            .map(|chunk| {
+                chunk
+                    .unwrap()
+                    .iter()
+                    .map(|sample| sample.timestamp_mono)
+                    .collect()
+            })
+            .collect();
+        assert_eq!(chunk_times, [vec![0, 1], vec![2, 3, 4]]);
+    }
+
+    #[test]
+    fn remapped_stacks_are_read_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut spill = SampleSpill::create(dir.path(), 1, 2).unwrap();
+        let mut samples = UnresolvedSamples::default();
+        let mut profile = new_profile();
+        let (_process, threads) = add_process_with_threads(&mut profile, 1);
+        for i in 0..3 {
+            samples.add_sample(
+                threads[0],
+                Timestamp::from_nanos_since_reference(i),
+                i,
+                UnresolvedStackHandle(i as u32),
+                CpuDelta::ZERO,
+                1,
+                None,
+            );
+        }
+        spill.write_chunk(samples.samples_and_markers()).unwrap();
+        spill
+            .remap_stacks(|stack| UnresolvedStackHandle(stack.0 + 10))
+            .unwrap();
+        assert_eq!(spill.spilled_count(), 3);
+        // The file with the old stacks is gone.
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+        let stacks: Vec<_> = spill
+            .into_chunks()
+            .unwrap()
+            .flat_map(|chunk| chunk.unwrap())
+            .map(|sample| sample.stack.0)
+            .collect();
+        assert_eq!(stacks, [10, 11, 12]);
+    }
+}
@@ -1,16 +1,81 @@
+use std::collections::{BTreeSet, HashMap};
 use std::ffi::OsStr;
 use std::fs::File;
-use std::io::BufWriter;
-use std::path::Path;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
 
+use flate2::read::GzDecoder;
 use flate2::{Compression, GzBuilder};
 use fxprof_processed_profile::Profile;
+use serde::Serialize;
+
+use super::marker_file::MarkerFileReport;
+use super::memory_usage::MemoryReport;
+use super::profile_merge::append_run;
+use super::recording_props::{SampleWeightUnit, SchemaValidation};
+
+/// The `meta` property which lists the marker and counter files that fed the profile.
+const SOURCE_FILES_META_KEY: &str = "samplySourceFiles";
+
+/// The `meta` property with a `MarkerFileReport` for each marker file.
+const MARKER_FILE_REPORTS_META_KEY: &str = "samplyMarkerFileReports";
+
+/// The `meta` property with the unit of the sample weights.
+const SAMPLE_WEIGHT_UNIT_META_KEY: &str = "samplyWeightUnit";
+
+/// The meta key for the peak memory usage of samply's buffers during the
+/// recording, by buffer, in bytes.
+const MEMORY_USAGE_META_KEY: &str = "samplyMemoryUsage";
 
 // Level two has an acceptable trade-off between how long compression
 // takes and how much data it saves on the profile JSONs I tested with.
 const GZIP_COMPRESSION_LEVEL: u32 = 2;
 
+/// Write the profile as JSON, gzip-compressed if `output_path` ends in `.gz`.
+///
+/// The JSON is always written without any whitespace; pretty-printing would
+/// make large profiles considerably bigger and slower to write, and the
+/// Firefox Profiler doesn't care about the formatting.
 pub fn save_profile_to_file(profile: &Profile, output_path: &Path) -> std::io::Result<()> {
+    save_json_to_file(profile, output_path)
+}
+
+/// Append `profile` as a new run to the profile which was saved at
+/// `output_path` earlier, for `--reuse-profile`. If there is no profile at
+/// `output_path` yet, `profile` is saved as is.
+pub fn append_profile_to_file(profile: &Profile, output_path: &Path) -> std::io::Result<()> {
+    let file = match File::open(output_path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            return save_profile_to_file(profile, output_path)
+        }
+        Err(err) => return Err(err),
+    };
+    let mut previous = read_profile_json(file, output_path)?;
+    append_run(&mut previous, serde_json::to_value(profile)?).map_err(|reason| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("{}: {reason}", output_path.display()),
+        )
+    })?;
+    save_json_to_file(&previous, output_path)
+}
+
+/// Read a profile which was saved by `save_profile_to_file`, as JSON.
+pub fn load_profile_json(path: &Path) -> std::io::Result<serde_json::Value> {
+    read_profile_json(File::open(path)?, path)
+}
+
+fn read_profile_json(file: File, path: &Path) -> std::io::Result<serde_json::Value> {
+    let file = BufReader::new(file);
+    if path.extension() == Some(OsStr::new("gz")) {
+        Ok(serde_json::from_reader(GzDecoder::new(file))?)
+    } else {
+        Ok(serde_json::from_reader(file)?)
+    }
+}
+
+fn save_json_to_file(json: &impl Serialize, output_path: &Path) -> std::io::Result<()> {
     let output_file = match File::create(output_path) {
         Ok(output_file) => output_file,
         Err(err) => {
@@ -21,14 +86,498 @@ pub fn save_profile_to_file(profile: &Profile, output_path: &Path) -> std::io::R
 
     let writer = BufWriter::new(output_file);
     let is_gz = output_path.extension() == Some(OsStr::new("gz"));
-    if is_gz {
+    let mut writer = if is_gz {
         let name_without_gz = output_path.file_stem().unwrap().to_string_lossy();
-        let builder = GzBuilder::new().filename(name_without_gz.as_bytes());
-        let gz = builder.write(writer, Compression::new(GZIP_COMPRESSION_LEVEL));
-        let gz = BufWriter::new(gz);
-        serde_json::to_writer(gz, &profile)?;
+        write_profile_gz(json, writer, &name_without_gz)?
     } else {
-        serde_json::to_writer(writer, &profile)?;
+        let mut writer = writer;
+        serde_json::to_writer(&mut writer, json)?;
+        writer
+    };
+    writer.flush()
+}
+
+/// Serialize the profile as gzip-compressed JSON into `writer`.
+///
+/// The JSON is compressed as it is produced, so the uncompressed profile is
+/// never held in memory. The gzip stream is finished explicitly, so that
+/// errors from writing its trailer are returned rather than lost on drop.
+/// Returns the inner writer, which the caller still needs to flush.
+fn write_profile_gz<W: Write>(
+    profile: &impl Serialize,
+    writer: W,
+    filename: &str,
+) -> std::io::Result<W> {
+    let builder = GzBuilder::new().filename(filename.as_bytes());
+    let gz = builder.write(writer, Compression::new(GZIP_COMPRESSION_LEVEL));
+    let mut gz = BufWriter::new(gz);
+    serde_json::to_writer(&mut gz, profile)?;
+    gz.into_inner().map_err(|e| e.into_error())?.finish()
+}
+
+/// Record the marker and counter files which were read while creating the
+/// profile in its metadata, so that it's clear which inputs it was built from.
+pub fn embed_source_files(profile: &mut Profile, source_files: &[PathBuf]) {
+    let source_files: Vec<_> = source_files
+        .iter()
+        .map(|path| path.to_string_lossy().into_owned())
+        .collect();
+    profile.set_extra_meta_field(SOURCE_FILES_META_KEY, source_files.into());
+}
+
+/// Record how the lines of a marker file were used in the profile's
+/// metadata, keyed by the file's path.
+pub fn embed_marker_file_report(profile: &mut Profile, path: &Path, report: &MarkerFileReport) {
+    let mut reports = match profile.extra_meta_field(MARKER_FILE_REPORTS_META_KEY) {
+        Some(serde_json::Value::Object(reports)) => reports.clone(),
+        _ => serde_json::Map::new(),
+    };
+    reports.insert(
+        path.to_string_lossy().into_owned(),
+        serde_json::to_value(report).unwrap(),
+    );
+    profile.set_extra_meta_field(MARKER_FILE_REPORTS_META_KEY, reports.into());
+}
+
+/// Record what the sample weights measure in the profile's metadata. Nothing
+/// is recorded for plain sample counts, which is what a profile without the
+/// property means.
+pub fn embed_sample_weight_unit(profile: &mut Profile, unit: SampleWeightUnit) {
+    if unit != SampleWeightUnit::Samples {
+        profile.set_extra_meta_field(SAMPLE_WEIGHT_UNIT_META_KEY, unit.as_str().into());
+    }
+}
+
+/// Record the peak memory usage of samply's buffers in the profile's
+/// metadata, so that it's at hand when a recording used too much memory.
+pub fn embed_memory_report(profile: &mut Profile, report: &MemoryReport) {
+    profile.set_extra_meta_field(MEMORY_USAGE_META_KEY, report.to_json());
+}
+
+/// Check the marker schemas of the profile for `--validate-schemas`, and
+/// print the problems. Returns whether the profile should be saved, which it
+/// shouldn't in strict mode if there are any problems.
+pub fn check_marker_schemas(profile: &Profile, validation: SchemaValidation) -> bool {
+    let issues = profile.validate_marker_schemas();
+    for issue in &issues {
+        eprintln!("Invalid marker schema: {issue}");
+    }
+    issues.is_empty() || validation == SchemaValidation::Warn
+}
+
+/// How the self weight of one function changed between two profiles.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionDiff {
+    pub name: String,
+    pub before: i64,
+    pub after: i64,
+}
+
+impl FunctionDiff {
+    pub fn delta(&self) -> i64 {
+        self.after - self.before
+    }
+}
+
+/// The functions whose self weight changed between two profiles.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DiffReport {
+    /// The functions which got heavier, largest increase first.
+    pub regressions: Vec<FunctionDiff>,
+    /// The functions which got lighter, largest decrease first.
+    pub improvements: Vec<FunctionDiff>,
+}
+
+impl std::fmt::Display for DiffReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (title, diffs) in [
+            ("Regressions", &self.regressions),
+            ("Improvements", &self.improvements),
+        ] {
+            if diffs.is_empty() {
+                writeln!(f, "{title}: none")?;
+                continue;
+            }
+            writeln!(f, "{title}:")?;
+            for diff in diffs {
+                writeln!(
+                    f,
+                    "  {:+} {} ({} -> {})",
+                    diff.delta(),
+                    diff.name,
+                    diff.before,
+                    diff.after
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Sum up the sample weights of each function in a profile in the processed
+/// profile JSON format, attributing each sample to the function of its leaf
+/// frame. Functions are identified by name, across all threads.
+pub fn function_self_weights(profile: &serde_json::Value) -> HashMap<String, i64> {
+    let mut weights = HashMap::new();
+    for thread in profile["threads"].as_array().into_iter().flatten() {
+        let samples = &thread["samples"];
+        let stack_frames = &thread["stackTable"]["frame"];
+        let frame_funcs = &thread["frameTable"]["func"];
+        let func_names = &thread["funcTable"]["name"];
+        let strings = &thread["stringArray"];
+        for (i, stack) in samples["stack"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .enumerate()
+        {
+            let Some(stack) = stack.as_u64() else {
+                continue;
+            };
+            let name = stack_frames[stack as usize]
+                .as_u64()
+                .and_then(|frame| frame_funcs[frame as usize].as_u64())
+                .and_then(|func| func_names[func as usize].as_u64())
+                .and_then(|string| strings[string as usize].as_str());
+            let Some(name) = name else {
+                continue;
+            };
+            // A missing weight column means that every sample has weight 1.
+            let weight = samples["weight"][i].as_i64().unwrap_or(1);
+            *weights.entry(name.to_string()).or_default() += weight;
+        }
+    }
+    weights
+}
+
+/// Compare the per-function self weights of two profiles in the processed
+/// profile JSON format, `a` being the baseline and `b` the new profile, for
+/// example to catch performance regressions in CI.
+pub fn diff_summaries(a: &serde_json::Value, b: &serde_json::Value) -> DiffReport {
+    let before = function_self_weights(a);
+    let after = function_self_weights(b);
+    let names: BTreeSet<&String> = before.keys().chain(after.keys()).collect();
+    let mut report = DiffReport::default();
+    for name in names {
+        let diff = FunctionDiff {
+            name: name.clone(),
+            before: before.get(name).copied().unwrap_or(0),
+            after: after.get(name).copied().unwrap_or(0),
+        };
+        match diff.delta() {
+            0 => {}
+            delta if delta > 0 => report.regressions.push(diff),
+            _ => report.improvements.push(diff),
+        }
+    }
+    report
+        .regressions
+        .sort_by_key(|diff| std::cmp::Reverse(diff.delta()));
+    report.improvements.sort_by_key(FunctionDiff::delta);
+    report
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use fxprof_processed_profile::{
+        CategoryHandle, CpuDelta, Frame, FrameFlags, FrameInfo, MarkerFieldFormat,
+        MarkerFieldSchema, MarkerGraph, MarkerGraphType, MarkerLocation, MarkerSchema,
+        ReferenceTimestamp, SamplingInterval, Timestamp,
+    };
+
+    use super::*;
+    use crate::shared::counter_file::get_counter;
+    use crate::shared::marker_file::{get_markers, MarkerFileOptions};
+    use crate::shared::test_support::identity_converter;
+
+    #[test]
+    fn saved_profile_lists_source_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let timestamp_converter = identity_converter();
+
+        let marker_path = dir.path().join("marker-1-1.txt");
+        let mut marker_file = File::create(&marker_path).unwrap();
+        writeln!(
+            marker_file,
+            r#"0 {{"timestamp":"10","target":"t","fields":{{"message":"hello"}}}}"#
+        )
+        .unwrap();
+        let counter_path = dir.path().join("counter-1.json");
+        let mut counter_file = File::create(&counter_path).unwrap();
+        write!(
+            counter_file,
+            r#"{{"name":"c","category":"Memory","description":"","color":"blue","samples":[[10,1.0,1]]}}"#
+        )
+        .unwrap();
+
+        // Look the files up through the fallback directory, so that the
+        // resolved paths are the ones which end up in the profile.
+        let lookup_dirs = [dir.path().to_owned()];
+        let marker_true_path = get_markers(
+            Path::new("/nonexistent/marker-1-1.txt"),
+            &lookup_dirs,
+            timestamp_converter,
+            MarkerFileOptions::default(),
+        )
+        .unwrap()
+        .true_path;
+        let (_, counter_true_path) = get_counter(
+            Path::new("/nonexistent/counter-1.json"),
+            &lookup_dirs,
+            timestamp_converter,
+        )
+        .unwrap();
+
+        let mut profile = Profile::new(
+            "",
+            ReferenceTimestamp::from_millis_since_unix_epoch(0.0),
+            SamplingInterval::from_millis(1),
+        );
+        embed_source_files(&mut profile, &[marker_true_path, counter_true_path]);
+        let output_path = dir.path().join("profile.json");
+        save_profile_to_file(&profile, &output_path).unwrap();
+
+        let json: serde_json::Value =
+            serde_json::from_reader(File::open(&output_path).unwrap()).unwrap();
+        assert_eq!(
+            json["meta"][SOURCE_FILES_META_KEY],
+            serde_json::json!([
+                marker_path.to_string_lossy(),
+                counter_path.to_string_lossy()
+            ])
+        );
+    }
+
+    #[test]
+    fn strict_schema_validation_rejects_invalid_schemas() {
+        let mut profile = Profile::new(
+            "schemas",
+            ReferenceTimestamp::from_millis_since_unix_epoch(0.0),
+            SamplingInterval::from_millis(1),
+        );
+        let schema = |graph_format| MarkerSchema {
+            type_name: "Request".into(),
+            locations: vec![MarkerLocation::MarkerChart],
+            chart_label: None,
+            tooltip_label: None,
+            table_label: None,
+            fields: vec![MarkerFieldSchema {
+                key: "latency".into(),
+                label: "Latency".into(),
+                format: graph_format,
+                searchable: false,
+            }],
+            static_fields: vec![],
+            graphs: vec![MarkerGraph {
+                key: "latency".into(),
+                graph_type: MarkerGraphType::Line,
+                color: None,
+            }],
+        };
+        profile.register_marker_type(schema(MarkerFieldFormat::Milliseconds));
+        assert!(check_marker_schemas(&profile, SchemaValidation::Strict));
+
+        let mut profile = Profile::new(
+            "schemas",
+            ReferenceTimestamp::from_millis_since_unix_epoch(0.0),
+            SamplingInterval::from_millis(1),
+        );
+        profile.register_marker_type(schema(MarkerFieldFormat::Duration));
+        assert!(check_marker_schemas(&profile, SchemaValidation::Warn));
+        assert!(!check_marker_schemas(&profile, SchemaValidation::Strict));
+    }
+
+    #[test]
+    fn saved_profile_records_the_sample_weight_unit() {
+        let dir = tempfile::tempdir().unwrap();
+        let new_profile = || {
+            Profile::new(
+                "weights",
+                ReferenceTimestamp::from_millis_since_unix_epoch(0.0),
+                SamplingInterval::from_millis(1),
+            )
+        };
+
+        let mut profile = new_profile();
+        embed_sample_weight_unit(&mut profile, SampleWeightUnit::Nanoseconds);
+        let output_path = dir.path().join("profile.json");
+        save_profile_to_file(&profile, &output_path).unwrap();
+        let saved: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&output_path).unwrap()).unwrap();
+        assert_eq!(saved["meta"][SAMPLE_WEIGHT_UNIT_META_KEY], "nanoseconds");
+
+        let mut profile = new_profile();
+        embed_sample_weight_unit(&mut profile, SampleWeightUnit::default());
+        let saved = serde_json::to_value(&profile).unwrap();
+        assert!(saved["meta"].get(SAMPLE_WEIGHT_UNIT_META_KEY).is_none());
+    }
+
+    #[test]
+    fn streamed_gzip_matches_uncompressed_json() {
+        let mut profile = Profile::new(
+            "gzip",
+            ReferenceTimestamp::from_millis_since_unix_epoch(0.0),
+            SamplingInterval::from_millis(1),
+        );
+        embed_source_files(&mut profile, &[PathBuf::from("/tmp/marker-1.txt")]);
+
+        let compressed = write_profile_gz(&profile, Vec::new(), "profile.json").unwrap();
+        let mut decompressed = Vec::new();
+        let mut decoder = flate2::read::GzDecoder::new(compressed.as_slice());
+        std::io::Read::read_to_end(&mut decoder, &mut decompressed).unwrap();
+        assert_eq!(
+            decoder.header().unwrap().filename(),
+            Some(&b"profile.json"[..])
+        );
+        assert_eq!(decompressed, serde_json::to_vec(&profile).unwrap());
+    }
+
+    #[test]
+    fn saved_gzip_profile_matches_uncompressed_json() {
+        let dir = tempfile::tempdir().unwrap();
+        let profile = Profile::new(
+            "gzip",
+            ReferenceTimestamp::from_millis_since_unix_epoch(0.0),
+            SamplingInterval::from_millis(1),
+        );
+        let output_path = dir.path().join("profile.json.gz");
+        save_profile_to_file(&profile, &output_path).unwrap();
+
+        let mut decompressed = Vec::new();
+        let mut decoder = flate2::read::GzDecoder::new(File::open(&output_path).unwrap());
+        std::io::Read::read_to_end(&mut decoder, &mut decompressed).unwrap();
+        assert_eq!(decompressed, serde_json::to_vec(&profile).unwrap());
+        assert_eq!(
+            load_profile_json(&output_path).unwrap(),
+            serde_json::to_value(&profile).unwrap()
+        );
+    }
+
+    #[test]
+    fn product_and_start_time_overrides_are_saved() {
+        let mut profile = weighted_profile(&[("main", 2)]);
+        let recorded = serde_json::to_value(&profile).unwrap();
+        profile.set_product("bench on ci-7 (v1.2-3-gabc123)");
+        let start_time = humantime::parse_rfc3339("2024-05-01T12:00:00Z").unwrap();
+        profile.set_reference_timestamp(ReferenceTimestamp::from_system_time(start_time));
+
+        let dir = tempfile::tempdir().unwrap();
+        let output_path = dir.path().join("profile.json");
+        save_profile_to_file(&profile, &output_path).unwrap();
+        let saved: serde_json::Value =
+            serde_json::from_reader(File::open(&output_path).unwrap()).unwrap();
+        assert_eq!(saved["meta"]["product"], "bench on ci-7 (v1.2-3-gabc123)");
+        assert_eq!(saved["meta"]["startTime"], 1_714_564_800_000.0);
+        // The timeline stays the same.
+        assert_ne!(recorded["meta"]["startTime"], saved["meta"]["startTime"]);
+        assert_eq!(
+            recorded["threads"][0]["samples"]["time"],
+            saved["threads"][0]["samples"]["time"]
+        );
+    }
+
+    fn profile_with_self_weights(weights: &[(&str, usize)]) -> serde_json::Value {
+        serde_json::to_value(weighted_profile(weights)).unwrap()
+    }
+
+    fn weighted_profile(weights: &[(&str, usize)]) -> Profile {
+        let mut profile = Profile::new(
+            "diff",
+            ReferenceTimestamp::from_millis_since_unix_epoch(0.0),
+            SamplingInterval::from_millis(1),
+        );
+        let start = Timestamp::from_millis_since_reference(0.0);
+        let process = profile.add_process("p", 1, start);
+        let thread = profile.add_thread(process, 1, start, true);
+        let main = profile.intern_string("main");
+        let mut time = 0.0;
+        for &(name, count) in weights {
+            let leaf = profile.intern_string(name);
+            for _ in 0..count {
+                let frames = [main, leaf].map(|label| FrameInfo {
+                    frame: Frame::Label(label),
+                    category_pair: CategoryHandle::OTHER.into(),
+                    flags: FrameFlags::empty(),
+                });
+                let timestamp = Timestamp::from_millis_since_reference(time);
+                profile.add_sample(thread, timestamp, frames.into_iter(), CpuDelta::ZERO, 1);
+                time += 1.0;
+            }
+        }
+        profile
+    }
+
+    #[test]
+    fn diff_reports_heavier_function_as_top_regression() {
+        let before = profile_with_self_weights(&[("foo", 10), ("bar", 8), ("baz", 5)]);
+        let after = profile_with_self_weights(&[("foo", 20), ("bar", 11), ("baz", 2)]);
+        let report = diff_summaries(&before, &after);
+        assert_eq!(
+            report.regressions,
+            vec![
+                FunctionDiff {
+                    name: "foo".into(),
+                    before: 10,
+                    after: 20
+                },
+                FunctionDiff {
+                    name: "bar".into(),
+                    before: 8,
+                    after: 11
+                },
+            ]
+        );
+        assert_eq!(report.improvements.len(), 1);
+        assert_eq!(report.improvements[0].name, "baz");
+        assert_eq!(report.improvements[0].delta(), -3);
+        // "main" only ever appears as a caller, so it has no self weight.
+        assert!(!function_self_weights(&after).contains_key("main"));
+        assert_eq!(
+            report.to_string(),
+            "Regressions:\n  +10 foo (10 -> 20)\n  +3 bar (8 -> 11)\nImprovements:\n  -3 baz (5 -> 2)\n"
+        );
+    }
+
+    #[test]
+    fn saved_profile_is_minified() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut profile = Profile::new(
+            "minified",
+            ReferenceTimestamp::from_millis_since_unix_epoch(0.0),
+            SamplingInterval::from_millis(1),
+        );
+        embed_source_files(&mut profile, &[PathBuf::from("/tmp/marker-1.txt")]);
+        let output_path = dir.path().join("profile.json");
+        save_profile_to_file(&profile, &output_path).unwrap();
+
+        let saved = std::fs::read_to_string(&output_path).unwrap();
+        assert!(!saved.contains('\n'));
+        assert!(!saved.contains("\": "));
+        assert_eq!(saved, serde_json::to_string(&profile).unwrap());
+        assert!(saved.len() < serde_json::to_string_pretty(&profile).unwrap().len());
+    }
+
+    #[test]
+    fn reused_profile_gets_one_process_per_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let output_path = dir.path().join("profile.json.gz");
+        append_profile_to_file(&weighted_profile(&[("warmup", 3)]), &output_path).unwrap();
+        append_profile_to_file(&weighted_profile(&[("measure", 4)]), &output_path).unwrap();
+
+        let file = File::open(&output_path).unwrap();
+        let json: serde_json::Value = serde_json::from_reader(GzDecoder::new(file)).unwrap();
+        let threads = json["threads"].as_array().unwrap();
+        let pids: Vec<_> = threads.iter().map(|thread| &thread["pid"]).collect();
+        assert_eq!(pids, ["1.1", "1.2"]);
+        let times: Vec<f64> = threads
+            .iter()
+            .flat_map(|thread| thread["samples"]["time"].as_array().unwrap())
+            .map(|time| time.as_f64().unwrap())
+            .collect();
+        assert_eq!(times.len(), 7);
+        assert!(times.windows(2).all(|pair| pair[0] < pair[1]), "{times:?}");
+        assert_eq!(function_self_weights(&json)["measure"], 4);
     }
-    Ok(())
 }
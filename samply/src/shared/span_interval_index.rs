@@ -0,0 +1,134 @@
+use fxprof_processed_profile::Timestamp;
+
+/// A sorted set of `[start, end)` intervals which can be queried for the
+/// innermost interval containing a given timestamp.
+///
+/// Intervals may overlap. If several intervals contain the timestamp, the one
+/// which started last wins, which for properly nested spans is the innermost one.
+#[derive(Debug, Clone)]
+pub struct SpanIntervalIndex<T> {
+    /// Sorted by start time.
+    intervals: Vec<(Timestamp, Timestamp, T)>,
+    /// `max_end[i]` is the maximum end time of `intervals[..=i]`. This lets
+    /// the backwards scan in `lookup` stop as soon as no earlier interval
+    /// can reach the queried timestamp.
+    max_end: Vec<Timestamp>,
+}
+
+impl<T: Copy> SpanIntervalIndex<T> {
+    pub fn new(intervals: Vec<(Timestamp, Timestamp, T)>) -> Self {
+        let mut index = Self {
+            intervals,
+            max_end: Vec::new(),
+        };
+        index.sort();
+        index
+    }
+
+    /// Adds more intervals, e.g. the spans of the next incremental flush.
+    /// Intervals with the same start keep the order in which they were added.
+    pub fn extend(&mut self, intervals: impl IntoIterator<Item = (Timestamp, Timestamp, T)>) {
+        self.intervals.extend(intervals);
+        self.sort();
+    }
+
+    /// Removes the intervals which end at or before `timestamp`, which can't
+    /// contain any later timestamp. The results of lookups at `timestamp` or
+    /// later are unchanged.
+    pub fn remove_ended_before(&mut self, timestamp: Timestamp) {
+        self.intervals.retain(|(_, end, _)| *end > timestamp);
+        self.compute_max_end();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.intervals.is_empty()
+    }
+
+    fn sort(&mut self) {
+        self.intervals.sort_by_key(|(start, _, _)| *start);
+        self.compute_max_end();
+    }
+
+    fn compute_max_end(&mut self) {
+        self.max_end.clear();
+        for (_, end, _) in &self.intervals {
+            let prev = self.max_end.last().copied().unwrap_or(*end);
+            self.max_end.push(prev.max(*end));
+        }
+    }
+
+    /// Returns the value of the latest-starting interval which contains `timestamp`.
+    pub fn lookup(&self, timestamp: Timestamp) -> Option<T> {
+        let candidate_count = self
+            .intervals
+            .partition_point(|(start, _, _)| *start <= timestamp);
+        for i in (0..candidate_count).rev() {
+            if self.max_end[i] <= timestamp {
+                return None;
+            }
+            let (_, end, value) = &self.intervals[i];
+            if timestamp < *end {
+                return Some(*value);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ts(ns: u64) -> Timestamp {
+        Timestamp::from_nanos_since_reference(ns)
+    }
+
+    #[test]
+    fn inside_and_outside() {
+        let index = SpanIntervalIndex::new(vec![(ts(30), ts(40), 'b'), (ts(10), ts(20), 'a')]);
+        assert_eq!(index.lookup(ts(5)), None);
+        assert_eq!(index.lookup(ts(10)), Some('a'));
+        assert_eq!(index.lookup(ts(19)), Some('a'));
+        assert_eq!(index.lookup(ts(20)), None);
+        assert_eq!(index.lookup(ts(35)), Some('b'));
+        assert_eq!(index.lookup(ts(40)), None);
+    }
+
+    #[test]
+    fn overlapping_spans() {
+        // A long outer span with a nested span, followed by a span that
+        // starts inside the outer one and outlives it.
+        let index = SpanIntervalIndex::new(vec![
+            (ts(0), ts(100), 'o'),
+            (ts(10), ts(20), 'n'),
+            (ts(90), ts(150), 'x'),
+        ]);
+        assert_eq!(index.lookup(ts(5)), Some('o'));
+        assert_eq!(index.lookup(ts(15)), Some('n'));
+        assert_eq!(index.lookup(ts(50)), Some('o'));
+        assert_eq!(index.lookup(ts(95)), Some('x'));
+        assert_eq!(index.lookup(ts(120)), Some('x'));
+        assert_eq!(index.lookup(ts(150)), None);
+    }
+
+    #[test]
+    fn extended_and_pruned_index_matches_a_new_one() {
+        let all = vec![
+            (ts(0), ts(100), 'o'),
+            (ts(10), ts(20), 'n'),
+            (ts(90), ts(150), 'x'),
+            (ts(120), ts(130), 'y'),
+        ];
+        let new_index = SpanIntervalIndex::new(all.clone());
+        let mut index = SpanIntervalIndex::new(vec![all[1], all[2]]);
+        index.remove_ended_before(ts(50));
+        assert_eq!(index.intervals.len(), 1);
+        // The outer span only arrives once it ends, after the nested one.
+        index.extend([all[0], all[3]]);
+        for t in 50..160 {
+            assert_eq!(index.lookup(ts(t)), new_index.lookup(ts(t)), "at {t}");
+        }
+        index.remove_ended_before(ts(150));
+        assert!(index.is_empty());
+    }
+}
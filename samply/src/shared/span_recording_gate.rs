@@ -0,0 +1,227 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use super::marker_file::{parse_span_id, read_raw_timestamp, MarkerLineProcessor};
+use super::timestamp_converter::TimestampConverter;
+use super::unresolved_samples::UnresolvedSamples;
+
+/// Decides which samples are kept for `--record-only-during-span`: the ones
+/// taken while a span with a given name was open in any profiled process.
+/// The spans come from the live marker lines, e.g. from the marker socket.
+///
+/// Marker lines arrive some time after the samples they're about, so
+/// samples are held back until they're older than `lookback`, unless they
+/// fall into a span which has closed already. That way, a span's first
+/// samples survive even if its `new` line shows up late, and the samples
+/// after its end are dropped even if its `close` line shows up late.
+#[derive(Debug)]
+pub struct SpanRecordingGate {
+    span_name: String,
+    lookback_ns: u64,
+    /// The raw start timestamps of the open spans, by pid and span id.
+    open_spans: HashMap<(i32, u64), u64>,
+    /// The raw start and end timestamps of the spans which have closed, and
+    /// which samples that haven't been decided on can still fall into.
+    closed_spans: Vec<(u64, u64)>,
+}
+
+impl SpanRecordingGate {
+    pub fn new(span_name: String, lookback: Duration) -> Self {
+        Self {
+            span_name,
+            lookback_ns: lookback.as_nanos() as u64,
+            open_spans: HashMap::new(),
+            closed_spans: Vec::new(),
+        }
+    }
+
+    /// Looks at a marker line from process `pid`. Only the `new` and `close`
+    /// lines of spans with our name, and single-line spans with our name,
+    /// change anything.
+    pub fn process_line(&mut self, pid: i32, line: &str, timestamp_converter: &TimestampConverter) {
+        let Some((ids, json)) = line.split_once(' ') else {
+            return;
+        };
+        let id = ids.split(',').next().and_then(parse_span_id);
+        let (Some(id @ 1..), Ok(json)) = (id, serde_json::from_str::<serde_json::Value>(json))
+        else {
+            return;
+        };
+        let name = json
+            .get("span")
+            .and_then(|span| span.get("name"))
+            .and_then(serde_json::Value::as_str);
+        if name != Some(self.span_name.as_str()) {
+            return;
+        }
+        let Ok(timestamp) = read_raw_timestamp(&json, timestamp_converter) else {
+            return;
+        };
+
+        if let Ok(Some(start)) = MarkerLineProcessor::single_line_span_start(&json) {
+            if let Ok(start) = read_raw_timestamp(&start, timestamp_converter) {
+                self.closed_spans.push((start, timestamp));
+            }
+            return;
+        }
+        let message = json
+            .get("fields")
+            .and_then(|fields| fields.get("message"))
+            .and_then(serde_json::Value::as_str);
+        match message {
+            Some("new") => {
+                self.open_spans.insert((pid, id), timestamp);
+            }
+            Some("close") => {
+                if let Some(start) = self.open_spans.remove(&(pid, id)) {
+                    self.closed_spans.push((start, timestamp));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Whether a sample at `timestamp_mono` is inside one of the spans.
+    pub fn covers(&self, timestamp_mono: u64) -> bool {
+        self.open_spans
+            .values()
+            .any(|start| *start <= timestamp_mono)
+            || self
+                .closed_spans
+                .iter()
+                .any(|(start, end)| (*start..=*end).contains(&timestamp_mono))
+    }
+
+    /// Whether a sample at `timestamp_mono` is kept, as of the raw time
+    /// `now`, or `None` if it's too recent to tell.
+    pub fn decide(&self, timestamp_mono: u64, now: u64) -> Option<bool> {
+        if self
+            .closed_spans
+            .iter()
+            .any(|(start, end)| (*start..=*end).contains(&timestamp_mono))
+        {
+            Some(true)
+        } else if timestamp_mono.saturating_add(self.lookback_ns) < now {
+            Some(self.covers(timestamp_mono))
+        } else {
+            None
+        }
+    }
+
+    /// Drops the samples in `samples` which are known to be outside of the
+    /// spans as of the raw time `now`. Returns the number of dropped samples.
+    pub fn apply(&self, samples: &mut UnresolvedSamples, now: u64) -> usize {
+        samples.gate_samples(|timestamp_mono| self.decide(timestamp_mono, now))
+    }
+
+    /// Drops the samples in `samples` which are outside of the spans known
+    /// by now, including recent ones, because no more samples of this
+    /// process will be decided on.
+    pub fn apply_final(&self, samples: &mut UnresolvedSamples) {
+        samples.gate_samples(|timestamp_mono| Some(self.covers(timestamp_mono)));
+    }
+
+    /// Forgets the closed spans which can't matter for undecided samples
+    /// anymore, i.e. which ended long before the lookback window.
+    pub fn forget_old_spans(&mut self, now: u64) {
+        let horizon = now.saturating_sub(self.lookback_ns.saturating_mul(2));
+        self.closed_spans.retain(|(_, end)| *end >= horizon);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use fxprof_processed_profile::{CpuDelta, Timestamp};
+
+    use super::*;
+    use crate::shared::test_support::{add_process_with_threads, identity_converter, new_profile};
+    use crate::shared::unresolved_samples::UnresolvedStackHandle;
+
+    const MS: u64 = 1_000_000;
+
+    fn span_line(id: u64, name: &str, message: &str, time: u64) -> String {
+        format!(
+            r#"{id} {{"timestamp":"{time}","target":"app","span":{{"name":"{name}"}},"fields":{{"message":"{message}"}}}}"#
+        )
+    }
+
+    /// Adds a sample every 100ms from `from` to `to`, exclusive, and applies
+    /// the gate after each one, as of that sample's time.
+    fn add_samples(samples: &mut UnresolvedSamples, gate: &SpanRecordingGate, from: u64, to: u64) {
+        let mut profile = new_profile();
+        let (_process, threads) = add_process_with_threads(&mut profile, 1);
+        for time in (from..to).step_by(100 * MS as usize) {
+            samples.add_sample(
+                threads[0],
+                Timestamp::from_nanos_since_reference(time),
+                time,
+                UnresolvedStackHandle::EMPTY,
+                CpuDelta::ZERO,
+                1,
+                None,
+            );
+            gate.apply(samples, time);
+        }
+    }
+
+    fn sample_times_ms(samples: &UnresolvedSamples) -> Vec<u64> {
+        samples
+            .samples_and_markers()
+            .iter()
+            .map(|sample| sample.timestamp_mono / MS)
+            .collect()
+    }
+
+    #[test]
+    fn only_samples_during_the_span_survive() {
+        let mut gate = SpanRecordingGate::new("Benchmark".to_string(), Duration::from_secs(1));
+        let mut samples = UnresolvedSamples::default();
+        add_samples(&mut samples, &gate, 0, 3000 * MS);
+        // The span started at 2500ms, but its line arrives at 3000ms. The
+        // samples of the last second are still undecided, so its beginning
+        // is kept.
+        gate.process_line(
+            1,
+            &span_line(7, "Benchmark", "new", 2500 * MS),
+            &identity_converter(),
+        );
+        // Other spans don't matter.
+        gate.process_line(1, &span_line(8, "Warmup", "new", 0), &identity_converter());
+        add_samples(&mut samples, &gate, 3000 * MS, 4000 * MS);
+        gate.process_line(
+            1,
+            &span_line(7, "Benchmark", "close", 3500 * MS),
+            &identity_converter(),
+        );
+        add_samples(&mut samples, &gate, 4000 * MS, 6000 * MS);
+        gate.apply_final(&mut samples);
+        let during_span: Vec<u64> = (2500..=3500).step_by(100).collect();
+        assert_eq!(sample_times_ms(&samples), during_span);
+    }
+
+    #[test]
+    fn samples_stay_undecided_during_the_lookback() {
+        let gate = SpanRecordingGate::new("Benchmark".to_string(), Duration::from_secs(1));
+        let mut samples = UnresolvedSamples::default();
+        add_samples(&mut samples, &gate, 0, 2000 * MS);
+        let undecided: Vec<u64> = (900..2000).step_by(100).collect();
+        assert_eq!(sample_times_ms(&samples), undecided);
+        gate.apply_final(&mut samples);
+        assert!(samples.samples_and_markers().is_empty());
+    }
+
+    #[test]
+    fn single_line_spans_and_old_spans() {
+        let mut gate = SpanRecordingGate::new("Benchmark".to_string(), Duration::from_secs(1));
+        let line = format!(
+            r#"3 {{"timestamp":"{}","target":"app","span":{{"name":"Benchmark"}},"fields":{{"message":"close","started_at":"{}"}}}}"#,
+            20 * MS,
+            10 * MS
+        );
+        gate.process_line(1, &line, &identity_converter());
+        assert!(gate.covers(15 * MS));
+        assert!(!gate.covers(25 * MS));
+        gate.forget_old_spans(5000 * MS);
+        assert!(!gate.covers(15 * MS));
+    }
+}
@@ -0,0 +1,617 @@
+//! Recovering a profile from the spill directory of a recording which didn't
+//! finish, for `samply recover`.
+//!
+//! Spill files only contain samples, which refer to threads and stacks that
+//! are kept in memory during recording. So that the spill files can be read
+//! without the recording process, the converter also writes a manifest to
+//! the spill directory: a JSON record per line, with the processes and their
+//! spill files, the thread table of every spill file, the libraries which
+//! were mapped into these processes and the stack table. New records are
+//! appended at every checkpoint, and the manifest is compacted into a fresh
+//! file once many lines have been appended.
+//!
+//! A killed samply leaves a manifest whose last line may be incomplete, and
+//! spill files whose last chunk may be incomplete. Recovery skips these, as
+//! well as samples whose thread or stack wasn't checkpointed yet.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use debugid::DebugId;
+use fxprof_processed_profile::{
+    CategoryColor, CategoryPairHandle, Frame, FrameFlags, FrameInfo, LibraryInfo, ProcessHandle,
+    Profile, ReferenceTimestamp, SamplingInterval, ThreadHandle, Timestamp,
+};
+use serde_derive::{Deserialize, Serialize};
+
+use super::sample_spill::{read_spill_chunk, SpillRecordData};
+use super::types::{StackFrame, StackMode};
+use super::unresolved_samples::{UnresolvedStackHandle, UnresolvedStacks};
+
+/// How often the converter appends to the manifest.
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The number of lines which can be appended to the manifest before it's
+/// compacted.
+const COMPACTION_THRESHOLD: usize = 1000;
+
+/// A line of the manifest.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ManifestRecord {
+    /// The first line.
+    Header {
+        reference_ms_since_epoch: f64,
+        interval_ns: u64,
+    },
+    /// A process with a spill file, which is a file name in the spill
+    /// directory.
+    Process {
+        pid: i32,
+        name: Option<String>,
+        spill_file: String,
+    },
+    /// An entry of the thread table of the spill file of process `pid`.
+    Thread { pid: i32, index: u32, tid: i32 },
+    /// A library mapping of process `pid`.
+    Lib {
+        pid: i32,
+        start_avma: u64,
+        end_avma: u64,
+        relative_address_at_start: u32,
+        name: String,
+        debug_name: String,
+        path: String,
+        debug_path: String,
+        debug_id: String,
+        code_id: Option<String>,
+        arch: Option<String>,
+    },
+    /// The stacks from index `first` on.
+    Stacks {
+        first: u32,
+        stacks: Vec<ManifestStack>,
+    },
+}
+
+/// A stack table entry: the prefix, the frame kind, the address and whether
+/// it's a kernel frame. The kinds are instruction pointer (0), return address
+/// (1), adjusted return address (2) and the truncated stack marker (3).
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ManifestStack(u32, u8, u64, bool);
+
+impl ManifestStack {
+    fn new(prefix: UnresolvedStackHandle, frame: StackFrame) -> Self {
+        let (kind, address, mode) = match frame {
+            StackFrame::InstructionPointer(address, mode) => (0, address, mode),
+            StackFrame::ReturnAddress(address, mode) => (1, address, mode),
+            StackFrame::AdjustedReturnAddress(address, mode) => (2, address, mode),
+            StackFrame::TruncatedStackMarker => (3, 0, StackMode::User),
+        };
+        Self(prefix.0, kind, address, mode == StackMode::Kernel)
+    }
+}
+
+/// Which of a process's tables have been written to the manifest.
+#[derive(Debug, Clone, Default)]
+pub struct ManifestProgress {
+    pub process_written: bool,
+    pub thread_count: usize,
+    pub lib_op_count: usize,
+}
+
+/// The manifest of a recording's spill directory, which is deleted when this
+/// is dropped.
+#[derive(Debug)]
+pub struct SpillManifest {
+    path: PathBuf,
+    file: File,
+    header: ManifestRecord,
+    /// Every record but the header and the stacks, for compaction.
+    records: Vec<ManifestRecord>,
+    written_stack_count: usize,
+    appended_lines: usize,
+    last_checkpoint: Instant,
+}
+
+impl SpillManifest {
+    /// Creates `samply-<pid>.manifest` in `dir`, with `pid` being our own pid.
+    pub fn create(
+        dir: &Path,
+        reference_timestamp: ReferenceTimestamp,
+        interval: SamplingInterval,
+    ) -> std::io::Result<Self> {
+        let path = dir.join(format!("samply-{}.manifest", std::process::id()));
+        let header = ManifestRecord::Header {
+            reference_ms_since_epoch: reference_timestamp.as_millis_since_unix_epoch(),
+            interval_ns: interval.nanos(),
+        };
+        let file = write_manifest_file(&path, &[&header])?;
+        Ok(Self {
+            path,
+            file,
+            header,
+            records: Vec::new(),
+            written_stack_count: 0,
+            appended_lines: 0,
+            last_checkpoint: Instant::now(),
+        })
+    }
+
+    /// Whether the next checkpoint is due.
+    pub fn is_due(&self) -> bool {
+        self.last_checkpoint.elapsed() >= CHECKPOINT_INTERVAL
+    }
+
+    /// Appends `records`, and the stacks which were added to `stacks` since
+    /// the last checkpoint.
+    pub fn checkpoint(
+        &mut self,
+        records: Vec<ManifestRecord>,
+        stacks: &UnresolvedStacks,
+    ) -> std::io::Result<()> {
+        self.last_checkpoint = Instant::now();
+        let stacks_record = stacks_record(stacks, self.written_stack_count);
+        let new_line_count = records.len() + usize::from(stacks_record.is_some());
+        if new_line_count == 0 {
+            return Ok(());
+        }
+        self.records.extend(records.iter().cloned());
+        if self.appended_lines + new_line_count > COMPACTION_THRESHOLD {
+            return self.compact(stacks);
+        }
+
+        let mut text = String::new();
+        for record in records.iter().chain(&stacks_record) {
+            text.push_str(&serde_json::to_string(record).unwrap());
+            text.push('\n');
+        }
+        self.file.write_all(text.as_bytes())?;
+        self.written_stack_count = stacks.stacks.len();
+        self.appended_lines += new_line_count;
+        Ok(())
+    }
+
+    /// Replaces the manifest by one with a single stacks record. The new
+    /// manifest is written to a temporary file and renamed, so that there is
+    /// a complete manifest at any time.
+    fn compact(&mut self, stacks: &UnresolvedStacks) -> std::io::Result<()> {
+        let stacks_record = stacks_record(stacks, 0);
+        let records: Vec<&ManifestRecord> = std::iter::once(&self.header)
+            .chain(&self.records)
+            .chain(&stacks_record)
+            .collect();
+        let tmp_path = self.path.with_extension("manifest.tmp");
+        let file = write_manifest_file(&tmp_path, &records)?;
+        std::fs::rename(&tmp_path, &self.path)?;
+        self.file = file;
+        self.written_stack_count = stacks.stacks.len();
+        self.appended_lines = 0;
+        Ok(())
+    }
+}
+
+impl Drop for SpillManifest {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+fn stacks_record(stacks: &UnresolvedStacks, first: usize) -> Option<ManifestRecord> {
+    let new_stacks = stacks.stacks.get(first..).filter(|s| !s.is_empty())?;
+    Some(ManifestRecord::Stacks {
+        first: first as u32,
+        stacks: new_stacks
+            .iter()
+            .map(|(prefix, frame)| ManifestStack::new(*prefix, *frame))
+            .collect(),
+    })
+}
+
+/// Writes `records` to a new file at `path`, and returns the file for
+/// appending.
+fn write_manifest_file(path: &Path, records: &[&ManifestRecord]) -> std::io::Result<File> {
+    let mut file = File::create(path)?;
+    let mut text = String::new();
+    for record in records {
+        text.push_str(&serde_json::to_string(record).unwrap());
+        text.push('\n');
+    }
+    file.write_all(text.as_bytes())?;
+    file.sync_all()?;
+    Ok(file)
+}
+
+/// Parses the complete lines of a manifest. The second value is false if
+/// the last line is incomplete or some lines couldn't be parsed.
+pub fn parse_manifest(text: &str) -> (Vec<ManifestRecord>, bool) {
+    let complete_text = match text.rfind('\n') {
+        Some(end) => &text[..=end],
+        None => "",
+    };
+    let mut complete = complete_text.len() == text.len();
+    let mut records = Vec::new();
+    for line in complete_text.lines().filter(|line| !line.is_empty()) {
+        match serde_json::from_str(line) {
+            Ok(record) => records.push(record),
+            Err(_) => complete = false,
+        }
+    }
+    (records, complete)
+}
+
+/// A profile made from a spill directory, with what was left out.
+pub struct RecoveredProfile {
+    pub profile: Profile,
+    pub sample_count: usize,
+    /// Samples whose thread or stack wasn't in the manifest.
+    pub dropped_sample_count: usize,
+    /// Whether the manifest or a spill file ended in an incomplete record.
+    pub truncated: bool,
+}
+
+struct RecoveredProcess {
+    handle: ProcessHandle,
+    spill_file: String,
+    tids_by_index: HashMap<u32, i32>,
+    threads: BTreeMap<i32, ThreadHandle>,
+}
+
+/// Makes a profile from the newest manifest in `dir` and the spill files it
+/// lists. Markers and the label frames of samples aren't recovered.
+pub fn recover(dir: &Path) -> Result<RecoveredProfile, String> {
+    let manifest_path = newest_manifest(dir)?;
+    let text = std::fs::read_to_string(&manifest_path)
+        .map_err(|e| format!("Couldn't read {}: {e}", manifest_path.display()))?;
+    let (records, manifest_complete) = parse_manifest(&text);
+    let Some(ManifestRecord::Header {
+        reference_ms_since_epoch,
+        interval_ns,
+    }) = records.first()
+    else {
+        return Err(format!(
+            "{} doesn't start with a header",
+            manifest_path.display()
+        ));
+    };
+
+    let mut profile = Profile::new(
+        "samply (recovered)",
+        ReferenceTimestamp::from_millis_since_unix_epoch(*reference_ms_since_epoch),
+        SamplingInterval::from_nanos(*interval_ns),
+    );
+    let user_category: CategoryPairHandle =
+        profile.add_category("User", CategoryColor::Yellow).into();
+    let kernel_category: CategoryPairHandle =
+        profile.add_category("Kernel", CategoryColor::Orange).into();
+    let start = Timestamp::from_nanos_since_reference(0);
+
+    let mut processes: BTreeMap<i32, RecoveredProcess> = BTreeMap::new();
+    let mut stacks: Vec<ManifestStack> = Vec::new();
+    for record in &records[1..] {
+        match record {
+            ManifestRecord::Header { .. } => {}
+            ManifestRecord::Process {
+                pid,
+                name,
+                spill_file,
+            } => {
+                processes.entry(*pid).or_insert_with(|| {
+                    let name = name.clone().unwrap_or_else(|| format!("pid {pid}"));
+                    RecoveredProcess {
+                        handle: profile.add_process(&name, *pid as u32, start),
+                        spill_file: spill_file.clone(),
+                        tids_by_index: HashMap::new(),
+                        threads: BTreeMap::new(),
+                    }
+                });
+            }
+            ManifestRecord::Thread { pid, index, tid } => {
+                if let Some(process) = processes.get_mut(pid) {
+                    process.tids_by_index.insert(*index, *tid);
+                }
+            }
+            ManifestRecord::Lib {
+                pid,
+                start_avma,
+                end_avma,
+                relative_address_at_start,
+                name,
+                debug_name,
+                path,
+                debug_path,
+                debug_id,
+                code_id,
+                arch,
+            } => {
+                let Some(process) = processes.get(pid) else {
+                    continue;
+                };
+                let lib = profile.add_lib(LibraryInfo {
+                    name: name.clone(),
+                    debug_name: debug_name.clone(),
+                    path: path.clone(),
+                    debug_path: debug_path.clone(),
+                    debug_id: DebugId::from_str(debug_id).unwrap_or_default(),
+                    code_id: code_id.clone(),
+                    arch: arch.clone(),
+                    symbol_table: None,
+                });
+                profile.add_lib_mapping(
+                    process.handle,
+                    lib,
+                    *start_avma,
+                    *end_avma,
+                    *relative_address_at_start,
+                );
+            }
+            ManifestRecord::Stacks {
+                first,
+                stacks: new_stacks,
+            } => {
+                // Compaction rewrites the whole table, so a record may repeat
+                // stacks, but it never leaves a gap.
+                if *first as usize <= stacks.len() {
+                    stacks.truncate(*first as usize);
+                    stacks.extend_from_slice(new_stacks);
+                }
+            }
+        }
+    }
+
+    let mut sample_count = 0;
+    let mut dropped_sample_count = 0;
+    let mut spill_files_complete = true;
+    let mut seen_spill_files = HashSet::new();
+    for (pid, process) in &mut processes {
+        if !seen_spill_files.insert(process.spill_file.clone()) {
+            continue;
+        }
+        let spill_path = dir.join(&process.spill_file);
+        let Ok(file) = File::open(&spill_path) else {
+            // The process finished before samply was stopped, and its spill
+            // file was read back and deleted.
+            continue;
+        };
+        // Spill files can be much larger than memory, so they're read one
+        // chunk at a time. A partially written last chunk is skipped.
+        let mut reader = BufReader::new(file);
+        loop {
+            let spill_records = match read_spill_chunk(&mut reader) {
+                Ok(Some(spill_records)) => spill_records,
+                Ok(None) => break,
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                    spill_files_complete = false;
+                    break;
+                }
+                Err(e) => return Err(format!("Couldn't read {}: {e}", spill_path.display())),
+            };
+            for record in spill_records {
+                let SpillRecordData::Sample(data) = record.data else {
+                    continue;
+                };
+                let (Some(tid), Some(frames)) = (
+                    process.tids_by_index.get(&record.thread_index),
+                    stack_frames(&stacks, record.stack, user_category, kernel_category),
+                ) else {
+                    dropped_sample_count += 1;
+                    continue;
+                };
+                let thread = *process.threads.entry(*tid).or_insert_with(|| {
+                    profile.add_thread(process.handle, *tid as u32, start, *tid == *pid)
+                });
+                profile.add_sample(
+                    thread,
+                    record.timestamp,
+                    frames.into_iter(),
+                    data.cpu_delta,
+                    data.weight,
+                );
+                sample_count += 1;
+            }
+        }
+    }
+
+    Ok(RecoveredProfile {
+        profile,
+        sample_count,
+        dropped_sample_count,
+        truncated: !manifest_complete || !spill_files_complete,
+    })
+}
+
+fn newest_manifest(dir: &Path) -> Result<PathBuf, String> {
+    let entries =
+        std::fs::read_dir(dir).map_err(|e| format!("Couldn't read {}: {e}", dir.display()))?;
+    entries
+        .filter_map(Result::ok)
+        .filter(|entry| {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            name.starts_with("samply-") && name.ends_with(".manifest")
+        })
+        .filter_map(|entry| Some((entry.metadata().ok()?.modified().ok()?, entry.path())))
+        .max()
+        .map(|(_, path)| path)
+        .ok_or_else(|| format!("There is no samply manifest in {}", dir.display()))
+}
+
+/// The frames of `stack`, caller-most first, or `None` if the stack isn't in
+/// `stacks`.
+fn stack_frames(
+    stacks: &[ManifestStack],
+    mut stack: UnresolvedStackHandle,
+    user_category: CategoryPairHandle,
+    kernel_category: CategoryPairHandle,
+) -> Option<Vec<FrameInfo>> {
+    let mut frames = Vec::new();
+    while stack != UnresolvedStackHandle::EMPTY {
+        let ManifestStack(prefix, kind, address, is_kernel) = *stacks.get(stack.0 as usize)?;
+        let frame = match kind {
+            0 => Some(Frame::InstructionPointer(address)),
+            1 => Some(Frame::ReturnAddress(address)),
+            2 => Some(Frame::AdjustedReturnAddress(address)),
+            _ => None,
+        };
+        if let Some(frame) = frame {
+            frames.push(FrameInfo {
+                frame,
+                category_pair: if is_kernel {
+                    kernel_category
+                } else {
+                    user_category
+                },
+                flags: FrameFlags::empty(),
+            });
+        }
+        stack = UnresolvedStackHandle(prefix);
+    }
+    frames.reverse();
+    Some(frames)
+}
+
+#[cfg(test)]
+mod test {
+    use fxprof_processed_profile::CpuDelta;
+
+    use super::*;
+    use crate::shared::sample_spill::SampleSpill;
+    use crate::shared::test_support::{add_process_with_threads, new_profile};
+    use crate::shared::unresolved_samples::UnresolvedSamples;
+
+    /// Spills `count` samples with `stack` for process 10, all on the
+    /// spill's thread 0.
+    fn write_spill(dir: &Path, stack: UnresolvedStackHandle, count: u64) -> SampleSpill {
+        let mut profile = new_profile();
+        let (_process, threads) = add_process_with_threads(&mut profile, 1);
+        let mut samples = UnresolvedSamples::default();
+        for i in 0..count {
+            samples.add_sample(
+                threads[0],
+                Timestamp::from_millis_since_reference(i as f64),
+                i,
+                stack,
+                CpuDelta::from_micros(10),
+                1,
+                None,
+            );
+        }
+        let mut spill = SampleSpill::create(dir, 10, usize::MAX).unwrap();
+        spill.write_chunk(samples.samples_and_markers()).unwrap();
+        spill
+    }
+
+    #[test]
+    fn truncated_manifest_keeps_the_complete_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut stacks = UnresolvedStacks::default();
+        let stack = stacks.convert(
+            [
+                StackFrame::ReturnAddress(0x1000, StackMode::User),
+                StackFrame::InstructionPointer(0x2000, StackMode::User),
+            ]
+            .into_iter(),
+        );
+        let spill = write_spill(dir.path(), stack, 5);
+        let spill_file = spill.path().file_name().unwrap().to_string_lossy();
+
+        let mut manifest = SpillManifest::create(
+            dir.path(),
+            ReferenceTimestamp::from_millis_since_unix_epoch(1_000.0),
+            SamplingInterval::from_millis(1),
+        )
+        .unwrap();
+        manifest
+            .checkpoint(
+                vec![
+                    ManifestRecord::Process {
+                        pid: 10,
+                        name: Some("app".to_string()),
+                        spill_file: spill_file.to_string(),
+                    },
+                    ManifestRecord::Thread {
+                        pid: 10,
+                        index: 0,
+                        tid: 11,
+                    },
+                ],
+                &stacks,
+            )
+            .unwrap();
+        // A library record which samply was killed in the middle of.
+        manifest
+            .checkpoint(
+                vec![ManifestRecord::Lib {
+                    pid: 10,
+                    start_avma: 0,
+                    end_avma: 0x10000,
+                    relative_address_at_start: 0,
+                    name: "app".to_string(),
+                    debug_name: "app".to_string(),
+                    path: "/usr/bin/app".to_string(),
+                    debug_path: "/usr/bin/app".to_string(),
+                    debug_id: DebugId::nil().to_string(),
+                    code_id: None,
+                    arch: None,
+                }],
+                &stacks,
+            )
+            .unwrap();
+        let manifest_path = dir
+            .path()
+            .join(format!("samply-{}.manifest", std::process::id()));
+        let text = std::fs::read_to_string(&manifest_path).unwrap();
+        let torn_len = text.len() - 20;
+        std::fs::write(&manifest_path, &text[..torn_len]).unwrap();
+
+        let recovered = recover(dir.path()).unwrap();
+        assert!(recovered.truncated);
+        assert_eq!(recovered.sample_count, 5);
+        assert_eq!(recovered.dropped_sample_count, 0);
+        let json = serde_json::to_value(&recovered.profile).unwrap();
+        assert_eq!(json["libs"].as_array().unwrap().len(), 0);
+        let threads = json["threads"].as_array().unwrap();
+        assert_eq!(threads.len(), 1);
+        assert_eq!(threads[0]["tid"], "11");
+        assert_eq!(threads[0]["processName"], "app");
+        assert_eq!(threads[0]["samples"]["length"], 5);
+        assert_eq!(threads[0]["stackTable"]["length"], 2);
+
+        drop(manifest);
+        assert!(!manifest_path.exists());
+        drop(spill);
+        assert!(std::fs::read_dir(dir.path()).unwrap().next().is_none());
+    }
+
+    #[test]
+    fn truncated_spill_chunks_are_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let spill = write_spill(dir.path(), UnresolvedStackHandle::EMPTY, 3);
+        let mut bytes = std::fs::read(spill.path()).unwrap();
+        let first_chunk_len = bytes.len();
+        bytes.extend_from_within(..first_chunk_len - 1);
+        let mut reader = &bytes[..];
+        assert_eq!(read_spill_chunk(&mut reader).unwrap().unwrap().len(), 3);
+        let e = read_spill_chunk(&mut reader).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+        let mut reader = &bytes[..first_chunk_len];
+        assert_eq!(read_spill_chunk(&mut reader).unwrap().unwrap().len(), 3);
+        assert!(read_spill_chunk(&mut reader).unwrap().is_none());
+    }
+
+    #[test]
+    fn incomplete_last_line_is_ignored() {
+        let header = r#"{"type":"header","reference_ms_since_epoch":0.0,"interval_ns":1000000}"#;
+        let thread = r#"{"type":"thread","pid":1,"index":0,"tid":1}"#;
+        let (records, complete) = parse_manifest(&format!("{header}\n{thread}\n"));
+        assert!(complete);
+        assert_eq!(records.len(), 2);
+        let (records, complete) = parse_manifest(&format!("{header}\n{}", &thread[..10]));
+        assert!(!complete);
+        assert_eq!(records.len(), 1);
+    }
+}
@@ -265,17 +265,21 @@ impl StackConverter {
     /// Takes a stack going from callee to root caller.
     ///
     /// Returns an iterator going from root caller to callee.
+    ///
+    /// If `user_category_override` is set, it is used instead of the user
+    /// category for user frames which don't have a more specific category.
     pub fn convert_stack<'a>(
         &'a mut self,
         stack: &'a [StackFrame],
         lib_mappings: &'a LibMappingsHierarchy,
         extra_first_frame: Option<FrameInfo>,
+        user_category_override: Option<CategoryPairHandle>,
     ) -> impl Iterator<Item = FrameInfo> + 'a {
         let pass1 = FirstPassIter(stack.iter().cloned().rev());
         let pass2 = SecondPassIter {
             inner: pass1,
             lib_mappings,
-            user_category: self.user_category,
+            user_category: user_category_override.unwrap_or(self.user_category),
             kernel_category: self.kernel_category,
         };
         self.libart_frame_buffer.clear();
@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Names for addresses which the symbolicator can't resolve, read from a
+/// symbol override file.
+///
+/// Each non-empty line of the file has an address and a name, separated by
+/// whitespace. The address is either `<library name>+<offset>`, e.g.
+/// `libfoo.so+0x1a2b`, or a raw address like `0x7f001a2b` for code outside of
+/// any library. Lines starting with `#` are comments.
+///
+/// ```text
+/// # Hand-written assembly without symbols
+/// libcrypto.so+0x12f40 aes_gcm_encrypt_avx2
+/// 0x7f5e00001000 jit_trampoline
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct SymbolOverrides {
+    by_lib_offset: HashMap<(String, u64), String>,
+    by_address: HashMap<u64, String>,
+}
+
+fn parse_hex(s: &str) -> Option<u64> {
+    let digits = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X"))?;
+    u64::from_str_radix(digits, 16).ok()
+}
+
+impl SymbolOverrides {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("could not read {}: {e}", path.display()))?;
+        Self::parse(&contents)
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        let mut overrides = Self::default();
+        for (line_index, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let line_number = line_index + 1;
+            let Some((address, name)) = line.split_once(char::is_whitespace) else {
+                return Err(format!("line {line_number}: missing name after address"));
+            };
+            let name = name.trim().to_string();
+            match address.rsplit_once('+') {
+                Some((lib, offset)) => {
+                    let offset = parse_hex(offset)
+                        .ok_or_else(|| format!("line {line_number}: invalid offset '{offset}'"))?;
+                    overrides
+                        .by_lib_offset
+                        .insert((lib.to_string(), offset), name);
+                }
+                None => {
+                    let address = parse_hex(address).ok_or_else(|| {
+                        format!("line {line_number}: invalid address '{address}'")
+                    })?;
+                    overrides.by_address.insert(address, name);
+                }
+            }
+        }
+        Ok(overrides)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_lib_offset.is_empty() && self.by_address.is_empty()
+    }
+
+    /// The override for an offset in the library named `lib`, or, if `lib`
+    /// is `None`, for a raw address.
+    pub fn lookup(&self, lib: Option<&str>, offset: u64) -> Option<&str> {
+        match lib {
+            Some(lib) => self.by_lib_offset.get(&(lib.to_string(), offset)),
+            None => self.by_address.get(&offset),
+        }
+        .map(String::as_str)
+    }
+
+    /// Fill in the function names of the frames which the symbolicator
+    /// couldn't resolve in a response of the `/symbolicate/v5` API. Frames
+    /// without a module are looked up by their raw address.
+    pub fn apply_to_symbolication_response(&self, response_json: String) -> String {
+        if self.is_empty() {
+            return response_json;
+        }
+        let Ok(mut response) = serde_json::from_str::<serde_json::Value>(&response_json) else {
+            return response_json;
+        };
+        let frames = response["results"]
+            .as_array_mut()
+            .into_iter()
+            .flatten()
+            .filter_map(|result| result["stacks"].as_array_mut())
+            .flatten()
+            .filter_map(|stack| stack.as_array_mut())
+            .flatten()
+            .filter_map(|frame| frame.as_object_mut());
+        for frame in frames {
+            if frame.contains_key("function") {
+                continue;
+            }
+            let Some(offset) = frame
+                .get("module_offset")
+                .and_then(serde_json::Value::as_str)
+                .and_then(parse_hex)
+            else {
+                continue;
+            };
+            let lib = frame.get("module").and_then(serde_json::Value::as_str);
+            if let Some(name) = self.lookup(lib, offset) {
+                frame.insert("function".into(), name.into());
+                frame.insert("function_offset".into(), "0x0".into());
+            }
+        }
+        response.to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_overrides() {
+        let overrides = SymbolOverrides::parse(
+            "# comment\n\nlibfoo.so+0x1a2b  foo::bar()\n0x7f0010 trampoline\n",
+        )
+        .unwrap();
+        assert_eq!(
+            overrides.lookup(Some("libfoo.so"), 0x1a2b),
+            Some("foo::bar()")
+        );
+        assert_eq!(overrides.lookup(Some("libfoo.so"), 0x1a2c), None);
+        assert_eq!(overrides.lookup(None, 0x7f0010), Some("trampoline"));
+        assert!(SymbolOverrides::parse("libfoo.so+12 name").is_err());
+        assert!(SymbolOverrides::parse("0x1234").is_err());
+    }
+
+    #[test]
+    fn override_names_unresolved_frames() {
+        let overrides =
+            SymbolOverrides::parse("libfoo.so+0x20 from_override\n0x7f0010 trampoline").unwrap();
+        let response = serde_json::json!({
+            "results": [{
+                "stacks": [[
+                    {"frame": 0, "module": "libfoo.so", "module_offset": "0x10",
+                     "function": "resolved", "function_offset": "0x4"},
+                    {"frame": 1, "module": "libfoo.so", "module_offset": "0x20"},
+                    {"frame": 2, "module": "libfoo.so", "module_offset": "0x30"},
+                    {"frame": 3, "module_offset": "0x7f0010"},
+                ]],
+                "found_modules": {},
+            }]
+        });
+        let patched = overrides.apply_to_symbolication_response(response.to_string());
+        let patched: serde_json::Value = serde_json::from_str(&patched).unwrap();
+        let frames = &patched["results"][0]["stacks"][0];
+        assert_eq!(frames[0]["function"], "resolved");
+        assert_eq!(frames[1]["function"], "from_override");
+        assert_eq!(frames[1]["function_offset"], "0x0");
+        assert!(frames[2].get("function").is_none());
+        assert_eq!(frames[3]["function"], "trampoline");
+    }
+}
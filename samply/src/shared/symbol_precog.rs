@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fs::File;
 use std::io::BufWriter;
 use std::path::Path;
@@ -146,6 +146,136 @@ impl InternedSymbolInfo {
     }
 }
 
+/// The fewest equally spaced addresses which
+/// `SymbolPrecog::enqueue_with_trampolines` takes for trampolines.
+const MIN_TRAMPOLINE_RUN: usize = 4;
+
+/// The smallest distance between two trampolines, which rules out runs of
+/// addresses which are only aligned by chance.
+const MIN_TRAMPOLINE_STRIDE: u32 = 16;
+
+/// The largest distance between two used trampolines of a run, which bounds
+/// how many addresses a run can add.
+const MAX_TRAMPOLINE_GAP: u32 = 1024;
+
+fn gcd(a: u32, b: u32) -> u32 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+/// What presymbolication knows about an address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PrecogState {
+    /// The address still needs to be looked up.
+    Pending,
+    /// The address is in the symbol at this index of the library's symbol
+    /// table.
+    Resolved(usize),
+    /// The address was looked up, but no symbol contains it.
+    Unresolved,
+}
+
+/// The relative addresses of one library which presymbolication resolves,
+/// with what they resolved to, sorted by address for fast lookups.
+#[derive(Debug, Clone, Default)]
+pub struct SymbolPrecog {
+    addresses: BTreeMap<u32, PrecogState>,
+}
+
+impl SymbolPrecog {
+    pub fn from_known_addresses(known_addresses: &[(u32, usize)]) -> Self {
+        let addresses = known_addresses
+            .iter()
+            .map(|&(rva, index)| (rva, PrecogState::Resolved(index)))
+            .collect();
+        Self { addresses }
+    }
+
+    /// Queues `rva` to be looked up, unless it already is known.
+    pub fn enqueue(&mut self, rva: u32) {
+        self.addresses.entry(rva).or_insert(PrecogState::Pending);
+    }
+
+    /// Queues every `step`-th address from `start` up to, but not including,
+    /// `end` to be resolved speculatively, e.g. the regularly spaced
+    /// trampolines of a JIT.
+    pub fn prefetch_range(&mut self, start: u32, end: u32, step: u32) {
+        for rva in (start..end).step_by(step.max(1) as usize) {
+            self.enqueue(rva);
+        }
+    }
+
+    fn pending(&self) -> Vec<u32> {
+        self.addresses
+            .iter()
+            .filter(|(_, state)| **state == PrecogState::Pending)
+            .map(|(rva, _)| *rva)
+            .collect()
+    }
+
+    fn set_symbol_index(&mut self, rva: u32, symbol_index: Option<usize>) {
+        let state = match symbol_index {
+            Some(index) => PrecogState::Resolved(index),
+            None => PrecogState::Unresolved,
+        };
+        self.addresses.insert(rva, state);
+    }
+
+    /// The index of the symbol which `rva` was resolved to.
+    pub fn lookup(&self, rva: u32) -> Option<usize> {
+        match self.addresses.get(&rva)? {
+            PrecogState::Resolved(index) => Some(*index),
+            PrecogState::Pending | PrecogState::Unresolved => None,
+        }
+    }
+
+    /// Queues the used addresses `rvas`, and the addresses in between the
+    /// runs of them which look like the regularly spaced trampolines of a
+    /// JIT or a PLT: at least `MIN_TRAMPOLINE_RUN` addresses at most
+    /// `MAX_TRAMPOLINE_GAP` bytes apart, whose distances are multiples of a
+    /// stride of at least `MIN_TRAMPOLINE_STRIDE` bytes. The sidecar file
+    /// then also covers the trampolines which this profile didn't hit.
+    pub fn enqueue_with_trampolines(&mut self, rvas: &BTreeSet<u32>) {
+        let rvas: Vec<u32> = rvas.iter().copied().collect();
+        let mut run_start = 0;
+        let mut stride = 0;
+        for i in 1..=rvas.len() {
+            if let Some(&rva) = rvas.get(i) {
+                let gap = rva - rvas[i - 1];
+                let run_stride = gcd(stride, gap);
+                if gap <= MAX_TRAMPOLINE_GAP && run_stride >= MIN_TRAMPOLINE_STRIDE {
+                    stride = run_stride;
+                    continue;
+                }
+            }
+            let run = &rvas[run_start..i];
+            if run.len() >= MIN_TRAMPOLINE_RUN {
+                self.prefetch_range(run[0], run[run.len() - 1] + 1, stride);
+            } else {
+                for rva in run {
+                    self.enqueue(*rva);
+                }
+            }
+            run_start = i;
+            stride = 0;
+        }
+    }
+
+    /// The resolved addresses, as stored in the sidecar file.
+    fn known_addresses(&self) -> Vec<(u32, usize)> {
+        self.addresses
+            .iter()
+            .filter_map(|(rva, state)| match state {
+                PrecogState::Resolved(index) => Some((*rva, *index)),
+                PrecogState::Pending | PrecogState::Unresolved => None,
+            })
+            .collect()
+    }
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 struct PrecogLibrarySymbols {
     debug_name: String,
@@ -158,6 +288,10 @@ struct PrecogLibrarySymbols {
 
     #[serde(skip)]
     string_table: Option<Arc<StringTable>>,
+
+    /// The index for looking up `known_addresses`.
+    #[serde(skip)]
+    precog: SymbolPrecog,
 }
 
 pub struct PrecogSymbolInfo {
@@ -206,11 +340,13 @@ impl<'de> Deserialize<'de> for PrecogSymbolInfo {
                     }
                 }
 
-                // Give the shared string table to each PrecogLibrarySymbols
+                // Give the shared string table to each PrecogLibrarySymbols,
+                // and index its addresses.
                 let (string_table, mut data) = (string_table.unwrap(), data.unwrap());
                 let string_table = Arc::new(string_table);
                 for lib in &mut data {
                     lib.string_table = Some(string_table.clone());
+                    lib.precog = SymbolPrecog::from_known_addresses(&lib.known_addresses);
                 }
                 Ok(PrecogSymbolInfo { string_table, data })
             }
@@ -258,37 +394,30 @@ impl wholesym::samply_symbols::SymbolMapTrait for PrecogLibrarySymbols {
     fn lookup_sync(&self, address: wholesym::LookupAddress) -> Option<wholesym::SyncAddressInfo> {
         match address {
             wholesym::LookupAddress::Relative(rva) => {
-                for (known_rva, sym_index) in &self.known_addresses {
-                    if *known_rva == rva {
-                        //eprintln!("lookup_sync: 0x{:x} -> {}", rva, info.symbol.0);
-                        let info = &self.symbol_table[*sym_index];
-                        return Some(wholesym::SyncAddressInfo {
-                            symbol: wholesym::SymbolInfo {
-                                address: info.rva,
-                                size: info.size,
-                                name: self.get_owned_string(info.symbol),
-                            },
-                            frames: info.frames.as_ref().map(|frames| {
-                                wholesym::FramesLookupResult::Available(
-                                    frames
-                                        .iter()
-                                        .map(|frame| wholesym::FrameDebugInfo {
-                                            function: self.get_owned_opt_string(frame.function),
-                                            file_path: frame.file.map(|file| {
-                                                SourceFilePath::new(
-                                                    self.get_string(file).to_owned(),
-                                                    None,
-                                                )
-                                            }),
-                                            line_number: frame.line,
-                                        })
-                                        .collect(),
-                                )
-                            }),
-                        });
-                    }
-                }
-                None
+                let sym_index = self.precog.lookup(rva)?;
+                //eprintln!("lookup_sync: 0x{:x} -> {}", rva, info.symbol.0);
+                let info = &self.symbol_table[sym_index];
+                Some(wholesym::SyncAddressInfo {
+                    symbol: wholesym::SymbolInfo {
+                        address: info.rva,
+                        size: info.size,
+                        name: self.get_owned_string(info.symbol),
+                    },
+                    frames: info.frames.as_ref().map(|frames| {
+                        wholesym::FramesLookupResult::Available(
+                            frames
+                                .iter()
+                                .map(|frame| wholesym::FrameDebugInfo {
+                                    function: self.get_owned_opt_string(frame.function),
+                                    file_path: frame.file.map(|file| {
+                                        SourceFilePath::new(self.get_string(file).to_owned(), None)
+                                    }),
+                                    line_number: frame.line,
+                                })
+                                .collect(),
+                        )
+                    }),
+                })
             }
             wholesym::LookupAddress::Svma(_) => None,
             wholesym::LookupAddress::FileOffset(_) => None,
@@ -366,22 +495,24 @@ pub fn presymbolicate(profile: &fxprof_processed_profile::Profile, precog_output
             let mut symbol_table = Vec::new();
             let mut symbol_table_map = HashMap::new();
 
-            let mut known_addresses = Vec::new();
-            for rva in rvas {
-                if let Some(addr_info) = symbol_map
-                    .lookup(wholesym::LookupAddress::Relative(*rva))
+            let mut precog = SymbolPrecog::default();
+            precog.enqueue_with_trampolines(rvas);
+            for rva in precog.pending() {
+                let index = symbol_map
+                    .lookup(wholesym::LookupAddress::Relative(rva))
                     .await
-                {
-                    let index = symbol_table_map
-                        .entry(addr_info.symbol.address)
-                        .or_insert_with(|| {
-                            let info = InternedSymbolInfo::new(&addr_info, &mut string_table);
-                            symbol_table.push(info);
-                            symbol_table.len() - 1
-                        });
-                    known_addresses.push((*rva, *index));
-                }
+                    .map(|addr_info| {
+                        *symbol_table_map
+                            .entry(addr_info.symbol.address)
+                            .or_insert_with(|| {
+                                let info = InternedSymbolInfo::new(&addr_info, &mut string_table);
+                                symbol_table.push(info);
+                                symbol_table.len() - 1
+                            })
+                    });
+                precog.set_symbol_index(rva, index);
             }
+            let known_addresses = precog.known_addresses();
 
             Some(PrecogLibrarySymbols {
                 debug_name: lib.debug_name.clone(),
@@ -394,6 +525,7 @@ pub fn presymbolicate(profile: &fxprof_processed_profile::Profile, precog_output
                 symbol_table,
                 known_addresses,
                 string_table: None,
+                precog,
             })
         });
 
@@ -417,3 +549,44 @@ pub fn presymbolicate(profile: &fxprof_processed_profile::Profile, precog_output
         to_writer(writer, &info).expect("Couldn't write JSON for presymbolication");
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn precog_lookup_and_prefetch() {
+        let mut precog = SymbolPrecog::from_known_addresses(&[(0x40, 2), (0x10, 0)]);
+        assert_eq!(precog.lookup(0x10), Some(0));
+        assert_eq!(precog.lookup(0x40), Some(2));
+        assert_eq!(precog.lookup(0x20), None);
+
+        precog.prefetch_range(0x1000, 0x1040, 0x10);
+        assert_eq!(precog.pending(), [0x1000, 0x1010, 0x1020, 0x1030]);
+        precog.set_symbol_index(0x1000, Some(5));
+        precog.set_symbol_index(0x1010, None);
+        assert_eq!(precog.lookup(0x1000), Some(5));
+        assert_eq!(precog.lookup(0x1010), None);
+        assert_eq!(precog.pending(), [0x1020, 0x1030]);
+        assert_eq!(
+            precog.known_addresses(),
+            [(0x10, 0), (0x40, 2), (0x1000, 5)]
+        );
+    }
+
+    #[test]
+    fn runs_of_trampolines_are_filled_in() {
+        let mut precog = SymbolPrecog::default();
+        let trampolines = [0x2000, 0x2040, 0x2060, 0x20c0];
+        let code = [0x10, 0x33, 0x57, 0x1000];
+        precog.enqueue_with_trampolines(&trampolines.into_iter().chain(code).collect());
+        let mut expected = code.to_vec();
+        expected.extend((0x2000..=0x20c0).step_by(0x20));
+        assert_eq!(precog.pending(), expected);
+
+        // Too few equally spaced addresses aren't taken for trampolines.
+        let mut precog = SymbolPrecog::default();
+        precog.enqueue_with_trampolines(&[0x2000, 0x2040, 0x20c0].into_iter().collect());
+        assert_eq!(precog.pending(), [0x2000, 0x2040, 0x20c0]);
+    }
+}
@@ -16,4 +16,6 @@ pub struct SymbolProps {
     pub breakpad_symbol_cache: Option<PathBuf>,
     /// Extra directory containing symbol files, with the directory structure used by simpleperf's scripts
     pub simpleperf_binary_cache: Option<PathBuf>,
+    /// A file with names for addresses which can't be symbolicated otherwise
+    pub symbol_override_file: Option<PathBuf>,
 }
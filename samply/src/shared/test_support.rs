@@ -0,0 +1,232 @@
+//! Generators for synthetic profiling data, shared between the unit tests
+//! and the conversion benchmarks.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use debugid::DebugId;
+use fxprof_processed_profile::{
+    CpuDelta, LibraryHandle, LibraryInfo, ProcessHandle, Profile, ReferenceTimestamp,
+    SamplingInterval, ThreadHandle, Timestamp,
+};
+
+use super::lib_mappings::{
+    LibMappingAdd, LibMappingInfo, LibMappingMove, LibMappingOp, LibMappingOpQueue,
+};
+use super::marker_file::{EventOrSpanMarker, MarkerData, MarkerSpan, SpanType, TracingTimings};
+use super::process_sample_data::MarkerOnThread;
+use super::timestamp_converter::{ClockDomain, TimestampConverter};
+use super::types::{StackFrame, StackMode};
+use super::unresolved_samples::{UnresolvedSamples, UnresolvedStackHandle, UnresolvedStacks};
+
+/// The address at which the synthetic library is mapped.
+pub const LIB_START_AVMA: u64 = 0x10_0000;
+const LIB_SIZE: u64 = 0x100_0000;
+
+/// A converter whose raw timestamps are nanoseconds since the profile start.
+pub fn identity_converter() -> TimestampConverter {
+    TimestampConverter {
+        reference_raw: 0,
+        raw_to_ns_factor: 1,
+        domain: ClockDomain::Monotonic,
+        realtime_to_monotonic_offset_ns: None,
+    }
+}
+
+pub fn new_profile() -> Profile {
+    Profile::new(
+        "synthetic",
+        ReferenceTimestamp::from_millis_since_unix_epoch(0.0),
+        SamplingInterval::from_millis(1),
+    )
+}
+
+pub fn add_process_with_threads(
+    profile: &mut Profile,
+    thread_count: usize,
+) -> (ProcessHandle, Vec<ThreadHandle>) {
+    let start = Timestamp::from_millis_since_reference(0.0);
+    let process = profile.add_process("synthetic", 1, start);
+    let threads = (0..thread_count)
+        .map(|i| profile.add_thread(process, i as u32 + 1, start, i == 0))
+        .collect();
+    (process, threads)
+}
+
+pub fn add_synthetic_lib(profile: &mut Profile, index: usize) -> LibraryHandle {
+    let name = format!("libsynthetic{index}.so");
+    profile.add_lib(LibraryInfo {
+        name: name.clone(),
+        debug_name: name.clone(),
+        path: format!("/usr/lib/{name}"),
+        debug_path: format!("/usr/lib/{name}"),
+        debug_id: DebugId::nil(),
+        code_id: None,
+        arch: None,
+        symbol_table: None,
+    })
+}
+
+/// `count` distinct user stacks of depth `depth`, all inside the synthetic
+/// library. Stacks share prefixes like real call trees do: stack `i` differs
+/// from stack `i - 1` only in its innermost frames.
+pub fn synthetic_stacks(
+    stacks: &mut UnresolvedStacks,
+    count: usize,
+    depth: usize,
+) -> Vec<UnresolvedStackHandle> {
+    (0..count)
+        .map(|i| {
+            let frames = (0..depth).map(|level| {
+                // Deeper levels vary faster, so stacks fan out towards the leaf.
+                let shift = (depth - 1 - level).min(7) * 2;
+                let address = LIB_START_AVMA + 0x10 * level as u64 + ((i >> shift) as u64) * 0x1000;
+                if level + 1 == depth {
+                    StackFrame::InstructionPointer(address, StackMode::User)
+                } else {
+                    StackFrame::ReturnAddress(address, StackMode::User)
+                }
+            });
+            stacks.convert(frames)
+        })
+        .collect()
+}
+
+/// `sample_count` samples, round-robin across `threads`, one millisecond
+/// apart, cycling through `stack_handles`.
+pub fn synthetic_samples(
+    threads: &[ThreadHandle],
+    stack_handles: &[UnresolvedStackHandle],
+    sample_count: usize,
+) -> UnresolvedSamples {
+    let mut samples = UnresolvedSamples::default();
+    for i in 0..sample_count {
+        let thread = threads[i % threads.len()];
+        let stack = stack_handles[i % stack_handles.len()];
+        samples.add_sample(
+            thread,
+            Timestamp::from_millis_since_reference(i as f64),
+            i as u64 * 1_000_000,
+            stack,
+            CpuDelta::from_micros(1000),
+            1,
+            None,
+        );
+    }
+    samples
+}
+
+/// A single mapping of the synthetic library, which covers every address
+/// used by `synthetic_stacks`.
+pub fn synthetic_lib_mapping(lib: LibraryHandle) -> LibMappingOpQueue {
+    let mut ops = LibMappingOpQueue::default();
+    ops.push(
+        0,
+        LibMappingOp::Add(LibMappingAdd {
+            start_avma: LIB_START_AVMA,
+            end_avma: LIB_START_AVMA + LIB_SIZE,
+            relative_address_at_start: 0,
+            info: LibMappingInfo::new_lib(lib),
+        }),
+    );
+    ops
+}
+
+/// `count` operations which map, move and clear mappings of `libs`, the way a
+/// process that repeatedly loads and unloads libraries would.
+pub fn synthetic_lib_mapping_ops(libs: &[LibraryHandle], count: usize) -> LibMappingOpQueue {
+    let mut ops = LibMappingOpQueue::default();
+    for i in 0..count {
+        let lib = libs[i % libs.len()];
+        let start_avma = LIB_START_AVMA + (i % 4096) as u64 * LIB_SIZE;
+        let op = match i % 64 {
+            63 => LibMappingOp::Clear,
+            n if n % 8 == 7 => LibMappingOp::Move(LibMappingMove {
+                old_start_avma: start_avma - LIB_SIZE,
+                new_start_avma: start_avma,
+                new_end_avma: start_avma + LIB_SIZE,
+            }),
+            _ => LibMappingOp::Add(LibMappingAdd {
+                start_avma,
+                end_avma: start_avma + LIB_SIZE,
+                relative_address_at_start: 0,
+                info: LibMappingInfo::new_lib(lib),
+            }),
+        };
+        ops.push(i as u64, op);
+    }
+    ops
+}
+
+/// `count` markers round-robin across `threads`, alternating between spans
+/// and events, with `field_combinations` different sets of field names.
+pub fn synthetic_markers(
+    threads: &[ThreadHandle],
+    count: usize,
+    field_combinations: usize,
+) -> Vec<MarkerOnThread> {
+    (0..count)
+        .map(|i| {
+            let combination = i % field_combinations;
+            let extra_fields: HashMap<String, String> = (0..=combination % 4)
+                .map(|field| (format!("field{combination}_{field}"), i.to_string()))
+                .collect();
+            let start_time = Timestamp::from_millis_since_reference(i as f64);
+            let marker_data = if i % 2 == 0 {
+                MarkerData::Span(MarkerSpan {
+                    id: i as u64 + 1,
+                    span_type: SpanType::Total,
+                    end_time: Timestamp::from_millis_since_reference(i as f64 + 0.5),
+                    timings: TracingTimings::default(),
+                    category: format!("Category{}", combination % 3),
+                    profiler_label: None,
+                    stats_label: None,
+                    child_markers: Vec::new(),
+                })
+            } else {
+                MarkerData::Event
+            };
+            MarkerOnThread {
+                thread_handle: threads[i % threads.len()],
+                event_or_span: EventOrSpanMarker {
+                    start_time,
+                    message: format!("marker{combination}"),
+                    target: "synthetic".to_string(),
+                    extra_fields,
+                    level: None,
+                    tid: None,
+                    trace_context: None,
+                    field_types: None,
+                    marker_data,
+                },
+            }
+        })
+        .collect()
+}
+
+/// The contents of a marker file with `span_count` new/close span pairs,
+/// each containing one event.
+pub fn synthetic_marker_file(span_count: usize) -> String {
+    let mut contents = String::new();
+    for i in 0..span_count {
+        let id = i + 1;
+        let start = i * 1000;
+        for (message, timestamp) in [("new", start), ("close", start + 900)] {
+            if message == "close" {
+                writeln!(
+                    contents,
+                    r#"0 {{"parent_id": {id}, "timestamp": "{}", "target": "synthetic", "fields": {{"message": "event", "n": {i}}}}}"#,
+                    start + 500
+                )
+                .unwrap();
+            }
+            writeln!(
+                contents,
+                r#"{id} {{"timestamp": "{timestamp}", "target": "synthetic", "fields": {{"message": "{message}", "time.busy": "800ns", "time.idle": "100ns"}}, "span": {{"name": "span{}", "action": "Atom/Collection-{id}"}}}}"#,
+                i % 10
+            )
+            .unwrap();
+        }
+    }
+    contents
+}
@@ -0,0 +1,80 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// How long each phase of creating and saving a profile took. Printed at the
+/// end of profiling with `--time-report`, so that slow conversions can be
+/// narrowed down to a phase.
+#[derive(Debug, Default)]
+pub struct TimeReport {
+    phases: Vec<(&'static str, Duration)>,
+}
+
+impl TimeReport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Run `f` and record how long it took as `phase`.
+    pub fn time<T>(&mut self, phase: &'static str, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        self.record(phase, start.elapsed());
+        result
+    }
+
+    pub fn record(&mut self, phase: &'static str, duration: Duration) {
+        self.phases.push((phase, duration));
+    }
+
+    /// The recorded phases, in the order they ran.
+    pub fn phases(&self) -> &[(&'static str, Duration)] {
+        &self.phases
+    }
+
+    pub fn print(&self) {
+        eprint!("{self}");
+    }
+}
+
+impl fmt::Display for TimeReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Time report:")?;
+        for (phase, duration) in self.phases() {
+            writeln!(f, "  {phase:<24} {:>12.3?}", duration)?;
+        }
+        let total: Duration = self.phases().iter().map(|(_, duration)| *duration).sum();
+        writeln!(f, "  {:<24} {:>12.3?}", "Total", total)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn phases_are_recorded_in_order() {
+        let mut report = TimeReport::new();
+        let value = report.time("first", || 42);
+        report.record("second", Duration::from_millis(5));
+        assert_eq!(value, 42);
+        let names: Vec<_> = report.phases().iter().map(|(name, _)| *name).collect();
+        assert_eq!(names, ["first", "second"]);
+        assert_eq!(report.phases()[1].1, Duration::from_millis(5));
+    }
+
+    #[test]
+    fn printed_report_lists_phases_and_total() {
+        let mut report = TimeReport::new();
+        report.record("Processing events", Duration::from_millis(3));
+        report.record("Saving profile", Duration::from_millis(4));
+        let printed = report.to_string();
+        let lines: Vec<_> = printed.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0], "Time report:");
+        assert!(lines[1].trim_start().starts_with("Processing events"));
+        assert!(lines[1].ends_with("3.000ms"));
+        assert!(lines[2].trim_start().starts_with("Saving profile"));
+        assert!(lines[3].trim_start().starts_with("Total"));
+        assert!(lines[3].ends_with("7.000ms"));
+    }
+}
@@ -1,11 +1,37 @@
 use fxprof_processed_profile::{CpuDelta, Timestamp};
 
+/// The clock that a raw timestamp was taken from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ClockDomain {
+    /// CLOCK_MONOTONIC, which is what perf events and samples use.
+    #[default]
+    Monotonic,
+    /// CLOCK_REALTIME, i.e. wall-clock nanoseconds since the UNIX epoch.
+    Realtime,
+}
+
+impl ClockDomain {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "monotonic" => Some(ClockDomain::Monotonic),
+            "realtime" => Some(ClockDomain::Realtime),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct TimestampConverter {
     /// A reference timestamp, as a raw timestamp.
     pub reference_raw: u64,
     /// A "ticks per nanosecond" conversion factor. If raw values are in nanoseconds, this is 1.
     pub raw_to_ns_factor: u64,
+    /// The clock domain of `reference_raw`, and of all timestamps passed to `convert_time`.
+    pub domain: ClockDomain,
+    /// The offset, in nanoseconds, between CLOCK_REALTIME and CLOCK_MONOTONIC,
+    /// such that `monotonic_ns = realtime_ns + realtime_to_monotonic_offset_ns`.
+    /// `None` if no offset has been registered.
+    pub realtime_to_monotonic_offset_ns: Option<i64>,
 }
 
 impl TimestampConverter {
@@ -15,6 +41,46 @@ impl TimestampConverter {
         )
     }
 
+    /// Register the offset between CLOCK_REALTIME and CLOCK_MONOTONIC, so that
+    /// timestamps from either domain can be converted into this converter's domain.
+    pub fn register_realtime_offset(&mut self, realtime_to_monotonic_offset_ns: i64) {
+        self.realtime_to_monotonic_offset_ns = Some(realtime_to_monotonic_offset_ns);
+    }
+
+    /// Register the wall-clock time of `reference_raw`, in nanoseconds since
+    /// the UNIX epoch, as a fallback for when the offset between
+    /// CLOCK_REALTIME and CLOCK_MONOTONIC can't be measured, e.g. when
+    /// importing. A measured offset which is registered later replaces it.
+    pub fn register_realtime_reference(&mut self, reference_realtime_ns: u64) {
+        if self.domain == ClockDomain::Monotonic && self.realtime_to_monotonic_offset_ns.is_none() {
+            let reference_ns = i128::from(self.reference_raw) * i128::from(self.raw_to_ns_factor);
+            self.realtime_to_monotonic_offset_ns =
+                Some((reference_ns - i128::from(reference_realtime_ns)) as i64);
+        }
+    }
+
+    /// Convert a nanosecond timestamp from `domain` into a raw timestamp in
+    /// this converter's domain.
+    ///
+    /// If the domains differ and no offset has been registered, the timestamp
+    /// is treated as if it were from this converter's domain.
+    pub fn raw_from_domain(&self, timestamp_ns: u64, domain: ClockDomain) -> u64 {
+        let offset = match (domain == self.domain, self.realtime_to_monotonic_offset_ns) {
+            (false, Some(offset)) => match domain {
+                ClockDomain::Realtime => offset,
+                ClockDomain::Monotonic => -offset,
+            },
+            _ => 0,
+        };
+        let own_ns = (i128::from(timestamp_ns) + i128::from(offset)).max(0) as u64;
+        own_ns / self.raw_to_ns_factor
+    }
+
+    /// Convert a nanosecond timestamp from `domain` into a profile timestamp.
+    pub fn convert_time_from_domain(&self, timestamp_ns: u64, domain: ClockDomain) -> Timestamp {
+        self.convert_time(self.raw_from_domain(timestamp_ns, domain))
+    }
+
     #[allow(dead_code)]
     pub fn convert_cpu_delta(&self, delta_raw: u64) -> CpuDelta {
         CpuDelta::from_nanos(delta_raw * self.raw_to_ns_factor)
@@ -27,3 +93,82 @@ impl TimestampConverter {
         )
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::shared::test_support::identity_converter;
+
+    #[test]
+    fn sub_microsecond_timestamps_keep_their_precision() {
+        let converter = identity_converter();
+        let inputs = [1, 500, 999];
+        let converted: Vec<u64> = inputs
+            .iter()
+            .map(|raw| converter.convert_time(*raw).as_nanos_since_reference())
+            .collect();
+        assert_eq!(converted, inputs);
+        for (raws, timestamps) in inputs.windows(2).zip(converted.windows(2)) {
+            assert!(timestamps[0] < timestamps[1]);
+            assert!((timestamps[1] - timestamps[0]).abs_diff(raws[1] - raws[0]) <= 1);
+        }
+    }
+
+    #[test]
+    fn zero_and_large_timestamps() {
+        let converter = identity_converter();
+        assert_eq!(
+            converter.convert_time(0),
+            Timestamp::from_nanos_since_reference(0)
+        );
+        assert_eq!(
+            converter
+                .convert_time(u64::MAX / 2)
+                .as_nanos_since_reference(),
+            u64::MAX / 2
+        );
+    }
+
+    #[test]
+    fn realtime_marker_aligns_with_monotonic_sample() {
+        let mut converter = TimestampConverter {
+            reference_raw: 1_000_000,
+            raw_to_ns_factor: 1,
+            domain: ClockDomain::Monotonic,
+            realtime_to_monotonic_offset_ns: None,
+        };
+        let realtime_to_monotonic = -1_700_000_000_000_000_000i64;
+        converter.register_realtime_offset(realtime_to_monotonic);
+
+        let sample_mono = 1_500_000;
+        let marker_realtime = (sample_mono as i64 - realtime_to_monotonic) as u64;
+        assert_eq!(
+            converter.convert_time(sample_mono),
+            converter.convert_time_from_domain(marker_realtime, ClockDomain::Realtime)
+        );
+        assert_eq!(
+            converter.convert_time(sample_mono),
+            converter.convert_time_from_domain(sample_mono, ClockDomain::Monotonic)
+        );
+    }
+
+    #[test]
+    fn raw_from_domain_uses_raw_units_in_every_branch() {
+        // 100 ns per tick, like a 10 MHz QueryPerformanceCounter.
+        let mut converter = TimestampConverter {
+            reference_raw: 10,
+            raw_to_ns_factor: 100,
+            domain: ClockDomain::Monotonic,
+            realtime_to_monotonic_offset_ns: None,
+        };
+        assert_eq!(converter.raw_from_domain(5_000, ClockDomain::Monotonic), 50);
+        assert_eq!(converter.raw_from_domain(5_000, ClockDomain::Realtime), 50);
+        converter.register_realtime_offset(-1_000);
+        assert_eq!(converter.raw_from_domain(5_000, ClockDomain::Monotonic), 50);
+        assert_eq!(converter.raw_from_domain(5_000, ClockDomain::Realtime), 40);
+        assert_eq!(
+            converter.convert_time_from_domain(5_000, ClockDomain::Monotonic),
+            Timestamp::from_nanos_since_reference(4_000)
+        );
+    }
+}
@@ -1,13 +1,56 @@
 use std::collections::hash_map::Entry;
+use std::fmt;
+use std::sync::Arc;
 
 use fxprof_processed_profile::{CpuDelta, FrameInfo, MarkerHandle, ThreadHandle, Timestamp};
 
+use super::memory_usage::{hash_map_heap_size, vec_heap_size, MemoryUsage};
+use super::sample_spill::{SampleSpill, SpillChunks};
 use super::types::{FastHashMap, StackFrame, StackMode};
 
-#[derive(Debug, Clone, Default)]
+/// A function which is called for every new sample while recording, see
+/// `RecordingProps::sample_callback`.
+#[derive(Clone)]
+pub struct SampleCallback(pub Arc<dyn Fn(&UnresolvedSampleOrMarker) + Send + Sync>);
+
+impl fmt::Debug for SampleCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SampleCallback")
+    }
+}
+
+/// Hands a new sample to the sample callback, if there is one, and then
+/// appends it. Takes the fields separately so that it can be used while
+/// `prev_sample_info_per_thread` is borrowed.
+fn push_sample(
+    samples_and_markers: &mut Vec<UnresolvedSampleOrMarker>,
+    sample_callback: &Option<SampleCallback>,
+    sample: UnresolvedSampleOrMarker,
+) {
+    if let Some(SampleCallback(callback)) = sample_callback {
+        callback(&sample);
+    }
+    samples_and_markers.push(sample);
+}
+
+#[derive(Debug, Default)]
 pub struct UnresolvedSamples {
     samples_and_markers: Vec<UnresolvedSampleOrMarker>,
     prev_sample_info_per_thread: FastHashMap<ThreadHandle, PreviousSampleInfo>,
+    sample_callback: Option<SampleCallback>,
+    /// Where the oldest samples go when there are too many in memory.
+    spill: Option<SampleSpill>,
+    /// The number of in-memory samples and markers at the front which
+    /// `gate_samples` has made a decision about.
+    gated_len: usize,
+}
+
+impl MemoryUsage for UnresolvedSamples {
+    /// Spilled samples are on disk, and aren't included.
+    fn heap_size(&self) -> usize {
+        vec_heap_size(&self.samples_and_markers)
+            + hash_map_heap_size(&self.prev_sample_info_per_thread)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -17,12 +60,136 @@ struct PreviousSampleInfo {
 }
 
 impl UnresolvedSamples {
-    pub fn into_inner(self) -> Vec<UnresolvedSampleOrMarker> {
-        self.samples_and_markers
+    /// Returns the samples and markers, including spilled ones, in chunks of
+    /// at most about `max_in_memory` of the spill, see `SampleChunks`.
+    pub fn into_chunks(self) -> SampleChunks {
+        let spilled = self
+            .spill
+            .filter(|spill| spill.spilled_count() > 0)
+            .and_then(|spill| match spill.into_chunks() {
+                Ok(chunks) => Some(chunks),
+                Err(e) => {
+                    eprintln!("Couldn't read back the spilled samples: {e}");
+                    None
+                }
+            });
+        SampleChunks {
+            spilled,
+            in_memory: Some(self.samples_and_markers),
+        }
+    }
+
+    /// Calls `f` with the samples and markers, including spilled ones, in
+    /// chunks like `into_chunks`.
+    pub fn for_each_chunk(&self, mut f: impl FnMut(&[UnresolvedSampleOrMarker])) {
+        if let Some(spill) = self
+            .spill
+            .as_ref()
+            .filter(|spill| spill.spilled_count() > 0)
+        {
+            let chunks = spill.chunks().and_then(|chunks| {
+                for chunk in chunks {
+                    f(&chunk?);
+                }
+                Ok(())
+            });
+            if let Err(e) = chunks {
+                eprintln!("Couldn't read back the spilled samples: {e}");
+            }
+        }
+        f(&self.samples_and_markers);
     }
 
     pub fn is_empty(&self) -> bool {
         self.samples_and_markers.is_empty()
+            && self
+                .spill
+                .as_ref()
+                .map_or(true, |spill| spill.spilled_count() == 0)
+    }
+
+    /// The samples and markers which are in memory, without the spilled
+    /// ones.
+    #[cfg(test)]
+    pub fn samples_and_markers(&self) -> &[UnresolvedSampleOrMarker] {
+        &self.samples_and_markers
+    }
+
+    /// Write the oldest samples and markers to `spill` whenever there are
+    /// more than `spill.max_in_memory()` of them in memory.
+    pub fn set_spill(&mut self, spill: Option<SampleSpill>) {
+        self.spill = spill;
+    }
+
+    pub fn spill(&self) -> Option<&SampleSpill> {
+        self.spill.as_ref()
+    }
+
+    /// An empty `UnresolvedSamples` with the sample callback of this one,
+    /// which spills to a new file next to this one's spill file, if there is
+    /// one, for samples which are derived from these.
+    fn derived(&self) -> Self {
+        let spill = self
+            .spill
+            .as_ref()
+            .and_then(|spill| match spill.create_sibling() {
+                Ok(spill) => Some(spill),
+                Err(e) => {
+                    eprintln!("Couldn't create a spill file, keeping the samples in memory: {e}");
+                    None
+                }
+            });
+        Self {
+            sample_callback: self.sample_callback.clone(),
+            spill,
+            ..Default::default()
+        }
+    }
+
+    /// Appends a sample or marker without handing it to the sample callback,
+    /// because it isn't a new sample.
+    fn push_existing(&mut self, sample: UnresolvedSampleOrMarker) {
+        self.samples_and_markers.push(sample);
+        self.spill_if_needed();
+    }
+
+    /// Spills the oldest half of the in-memory samples and markers if there
+    /// are too many. Zero-CPU samples which were spilled can't be extended by
+    /// `add_sample_same_stack_zero_cpu` anymore; the next one becomes a new
+    /// sample.
+    fn spill_if_needed(&mut self) {
+        let Some(spill) = &mut self.spill else {
+            return;
+        };
+        if self.samples_and_markers.len() <= spill.max_in_memory() {
+            return;
+        }
+        let chunk_len = self.samples_and_markers.len() / 2;
+        if let Err(e) = spill.write_chunk(&self.samples_and_markers[..chunk_len]) {
+            eprintln!("Couldn't spill samples, keeping the rest in memory: {e}");
+            spill.stop_spilling();
+            return;
+        }
+        self.samples_and_markers.drain(..chunk_len);
+        self.gated_len = self.gated_len.saturating_sub(chunk_len);
+        for sample_info in self.prev_sample_info_per_thread.values_mut() {
+            sample_info.prev_sample_index_if_zero_cpu = sample_info
+                .prev_sample_index_if_zero_cpu
+                .and_then(|index| index.checked_sub(chunk_len));
+        }
+    }
+
+    /// The stack of the thread's most recent sample, if it has one.
+    pub fn last_sample_stack(&self, thread_handle: ThreadHandle) -> Option<UnresolvedStackHandle> {
+        self.prev_sample_info_per_thread
+            .get(&thread_handle)
+            .map(|sample_info| sample_info.stack)
+    }
+
+    /// Call `sample_callback` for every sample which is added from now on,
+    /// just before it is added.
+    pub fn set_sample_callback(&mut self, sample_callback: Option<SampleCallback>) {
+        self.sample_callback = sample_callback;
     }
 
     #[allow(clippy::too_many_arguments)]
@@ -37,14 +204,18 @@ impl UnresolvedSamples {
         extra_label_frame: Option<FrameInfo>,
     ) {
         let sample_index = self.samples_and_markers.len();
-        self.samples_and_markers.push(UnresolvedSampleOrMarker {
-            thread_handle,
-            timestamp,
-            timestamp_mono,
-            stack,
-            extra_label_frame,
-            sample_or_marker: SampleOrMarker::Sample(SampleData { weight, cpu_delta }),
-        });
+        push_sample(
+            &mut self.samples_and_markers,
+            &self.sample_callback,
+            UnresolvedSampleOrMarker {
+                thread_handle,
+                timestamp,
+                timestamp_mono,
+                stack,
+                extra_label_frame,
+                sample_or_marker: SampleOrMarker::Sample(SampleData { weight, cpu_delta }),
+            },
+        );
         self.prev_sample_info_per_thread.insert(
             thread_handle,
             PreviousSampleInfo {
@@ -53,6 +224,7 @@ impl UnresolvedSamples {
                     .then_some(sample_index),
             },
         );
+        self.spill_if_needed();
     }
 
     #[allow(unused)]
@@ -77,7 +249,31 @@ impl UnresolvedSamples {
                 } else {
                     let stack = sample_info.stack;
                     let sample_index = self.samples_and_markers.len();
-                    self.samples_and_markers.push(UnresolvedSampleOrMarker {
+                    push_sample(
+                        &mut self.samples_and_markers,
+                        &self.sample_callback,
+                        UnresolvedSampleOrMarker {
+                            thread_handle,
+                            timestamp,
+                            timestamp_mono,
+                            stack,
+                            extra_label_frame,
+                            sample_or_marker: SampleOrMarker::Sample(SampleData {
+                                weight,
+                                cpu_delta: CpuDelta::ZERO,
+                            }),
+                        },
+                    );
+                    sample_info.prev_sample_index_if_zero_cpu = Some(sample_index);
+                }
+            }
+            Entry::Vacant(entry) => {
+                let stack = UnresolvedStackHandle::EMPTY;
+                let sample_index = self.samples_and_markers.len();
+                push_sample(
+                    &mut self.samples_and_markers,
+                    &self.sample_callback,
+                    UnresolvedSampleOrMarker {
                         thread_handle,
                         timestamp,
                         timestamp_mono,
@@ -87,30 +283,115 @@ impl UnresolvedSamples {
                             weight,
                             cpu_delta: CpuDelta::ZERO,
                         }),
-                    });
-                    sample_info.prev_sample_index_if_zero_cpu = Some(sample_index);
-                }
-            }
-            Entry::Vacant(entry) => {
-                let stack = UnresolvedStackHandle::EMPTY;
-                let sample_index = self.samples_and_markers.len();
-                self.samples_and_markers.push(UnresolvedSampleOrMarker {
-                    thread_handle,
-                    timestamp,
-                    timestamp_mono,
-                    stack,
-                    extra_label_frame,
-                    sample_or_marker: SampleOrMarker::Sample(SampleData {
-                        weight,
-                        cpu_delta: CpuDelta::ZERO,
-                    }),
-                });
+                    },
+                );
                 entry.insert(PreviousSampleInfo {
                     stack,
                     prev_sample_index_if_zero_cpu: Some(sample_index),
                 });
             }
         }
+        self.spill_if_needed();
+    }
+
+    /// Keeps every `factor`-th sample of each thread, starting with the first
+    /// one, and multiplies the weight of the kept samples by `factor`. Marker
+    /// stacks are all kept. No more samples can be merged into the kept ones
+    /// by `add_sample_same_stack_zero_cpu`. Spilled samples are downsampled
+    /// one chunk at a time, into a new spill file.
+    pub fn downsample(self, factor: u32) -> Self {
+        let factor = factor.max(1);
+        let weight_factor = i32::try_from(factor).unwrap_or(i32::MAX);
+        let mut sample_count_per_thread: FastHashMap<ThreadHandle, u32> = Default::default();
+        let mut downsampled = self.derived();
+        for mut sample in self.into_chunks().flatten() {
+            if let SampleOrMarker::Sample(data) = &mut sample.sample_or_marker {
+                let count = sample_count_per_thread
+                    .entry(sample.thread_handle)
+                    .or_default();
+                let keep = *count % factor == 0;
+                *count += 1;
+                if !keep {
+                    continue;
+                }
+                data.weight = data.weight.saturating_mul(weight_factor);
+            }
+            downsampled.push_existing(sample);
+        }
+        downsampled.gated_len = 0;
+        downsampled
+    }
+
+    /// Adds the samples and markers of `other`, including spilled ones, so
+    /// that all of them are in timestamp order. Of two with the same
+    /// timestamp, the one from `self` comes first. No more samples can be
+    /// merged into the existing ones by `add_sample_same_stack_zero_cpu`.
+    /// Spilled samples are merged one chunk at a time, into a new spill file.
+    pub fn merge(&mut self, other: UnresolvedSamples) {
+        let mut merged = self.derived();
+        let mut ours = std::mem::take(self).into_chunks().flatten().peekable();
+        let mut theirs = other.into_chunks().flatten().peekable();
+        loop {
+            let next = match (ours.peek(), theirs.peek()) {
+                (Some(a), Some(b)) if b.timestamp_mono < a.timestamp_mono => theirs.next(),
+                (Some(_), _) => ours.next(),
+                (None, _) => theirs.next(),
+            };
+            let Some(sample) = next else {
+                break;
+            };
+            merged.push_existing(sample);
+        }
+        merged.gated_len = merged.samples_and_markers.len();
+        *self = merged;
+    }
+
+    /// Drops the samples which `keep` rejects, for
+    /// `--record-only-during-span`. `keep` gets the `timestamp_mono` of each
+    /// sample which it hasn't seen yet, in order, and returns `None` if it
+    /// can't decide yet; that sample and the ones after it are looked at
+    /// again in the next call. Marker stacks are always kept. Samples which
+    /// were spilled before they were decided on are kept too.
+    ///
+    /// Returns the number of dropped samples.
+    pub fn gate_samples(&mut self, mut keep: impl FnMut(u64) -> Option<bool>) -> usize {
+        let first = self.gated_len;
+        let mut decisions = Vec::new();
+        for sample in &self.samples_and_markers[first..] {
+            let keep_sample = match sample.sample_or_marker {
+                SampleOrMarker::Sample(_) => match keep(sample.timestamp_mono) {
+                    Some(keep_sample) => keep_sample,
+                    None => break,
+                },
+                SampleOrMarker::MarkerHandle(_) => true,
+            };
+            decisions.push(keep_sample);
+        }
+        if decisions.iter().all(|keep_sample| *keep_sample) {
+            self.gated_len += decisions.len();
+            return 0;
+        }
+
+        let end = first + decisions.len();
+        let kept: Vec<_> = self
+            .samples_and_markers
+            .drain(first..end)
+            .zip(decisions)
+            .filter_map(|(sample, keep_sample)| keep_sample.then_some(sample))
+            .collect();
+        let dropped_count = end - first - kept.len();
+        self.gated_len = first + kept.len();
+        self.samples_and_markers.splice(first..first, kept);
+        // The indexes of the samples after `first` have changed.
+        for sample_info in self.prev_sample_info_per_thread.values_mut() {
+            if sample_info
+                .prev_sample_index_if_zero_cpu
+                .is_some_and(|index| index >= first)
+            {
+                sample_info.prev_sample_index_if_zero_cpu = None;
+            }
+        }
+        dropped_count
     }
 
     pub fn attach_stack_to_marker(
@@ -129,6 +410,33 @@ impl UnresolvedSamples {
             extra_label_frame: None,
             sample_or_marker: SampleOrMarker::MarkerHandle(marker_handle),
         });
+        self.spill_if_needed();
+    }
+}
+
+/// The samples and markers of an `UnresolvedSamples`, in order: first the
+/// spilled ones, which are read back one spilled chunk at a time, then the
+/// ones which were in memory. If the spill file can't be read, the rest of
+/// the spilled samples are left out.
+#[derive(Debug)]
+pub struct SampleChunks {
+    spilled: Option<SpillChunks<SampleSpill>>,
+    in_memory: Option<Vec<UnresolvedSampleOrMarker>>,
+}
+
+impl Iterator for SampleChunks {
+    type Item = Vec<UnresolvedSampleOrMarker>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(spilled) = &mut self.spilled {
+            match spilled.next() {
+                Some(Ok(chunk)) => return Some(chunk),
+                Some(Err(e)) => eprintln!("Couldn't read back the spilled samples: {e}"),
+                None => {}
+            }
+            self.spilled = None;
+        }
+        self.in_memory.take()
     }
 }
 
@@ -155,7 +463,7 @@ pub struct SampleData {
 }
 
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
-pub struct UnresolvedStackHandle(u32);
+pub struct UnresolvedStackHandle(pub(super) u32);
 
 impl UnresolvedStackHandle {
     /// Represents the empty stack / the root stack node
@@ -168,6 +476,12 @@ pub struct UnresolvedStacks {
     pub stack_lookup: FastHashMap<(UnresolvedStackHandle, StackFrame), UnresolvedStackHandle>, // (prefix, frame) -> stack index
 }
 
+impl MemoryUsage for UnresolvedStacks {
+    fn heap_size(&self) -> usize {
+        vec_heap_size(&self.stacks) + hash_map_heap_size(&self.stack_lookup)
+    }
+}
+
 impl UnresolvedStacks {
     /// Get the `UnresolvedStackHandle` for a stack. The stack must be ordered from
     /// caller-most to callee-most ("outside to inside").
@@ -219,4 +533,223 @@ impl UnresolvedStacks {
             stack_index = prefix;
         }
     }
+
+    /// Drops the stacks which aren't referenced by any sample or marker of
+    /// `live_samples`, or by `live_handles`, anymore, e.g. after samples
+    /// were filtered out, and renumbers the remaining ones. The stack handles
+    /// in `live_samples` and `live_handles` are updated to the new numbering;
+    /// handles from anywhere else become invalid. The stacks of spilled
+    /// samples are kept and renumbered too; the spilled samples are read back
+    /// one chunk at a time, and rewritten to a new spill file.
+    ///
+    /// Returns the number of dropped stacks.
+    pub fn gc<'a>(
+        &mut self,
+        live_samples: impl IntoIterator<Item = &'a mut UnresolvedSamples>,
+        live_handles: impl IntoIterator<Item = &'a mut UnresolvedStackHandle>,
+    ) -> usize {
+        let mut live_samples: Vec<_> = live_samples.into_iter().collect();
+        let mut live_handles: Vec<_> = live_handles.into_iter().collect();
+
+        let mut is_live = vec![false; self.stacks.len()];
+        let mut mark_live = |mut stack: UnresolvedStackHandle| {
+            while stack != UnresolvedStackHandle::EMPTY && !is_live[stack.0 as usize] {
+                is_live[stack.0 as usize] = true;
+                stack = self.stacks[stack.0 as usize].0;
+            }
+        };
+        for samples in &live_samples {
+            samples.for_each_chunk(|chunk| {
+                for sample in chunk {
+                    mark_live(sample.stack);
+                }
+            });
+            for prev_sample_info in samples.prev_sample_info_per_thread.values() {
+                mark_live(prev_sample_info.stack);
+            }
+        }
+        for handle in &live_handles {
+            mark_live(**handle);
+        }
+
+        // A stack's prefix always has a lower index than the stack itself,
+        // so the prefix has already been renumbered when we get to a stack.
+        let old_stacks = std::mem::take(&mut self.stacks);
+        let dropped_count = old_stacks.len() - is_live.iter().filter(|&&live| live).count();
+        let mut new_handles = vec![UnresolvedStackHandle::EMPTY; old_stacks.len()];
+        self.stack_lookup.clear();
+        for (old_index, (prefix, frame)) in old_stacks.into_iter().enumerate() {
+            if !is_live[old_index] {
+                continue;
+            }
+            let prefix = remap_stack(&new_handles, prefix);
+            let new_handle = UnresolvedStackHandle(self.stacks.len() as u32);
+            self.stacks.push((prefix, frame));
+            self.stack_lookup.insert((prefix, frame), new_handle);
+            new_handles[old_index] = new_handle;
+        }
+
+        for samples in &mut live_samples {
+            if let Some(spill) = &mut samples.spill {
+                if let Err(e) = spill.remap_stacks(|stack| remap_stack(&new_handles, stack)) {
+                    eprintln!("Couldn't rewrite the spilled samples: {e}");
+                }
+            }
+            for sample in &mut samples.samples_and_markers {
+                sample.stack = remap_stack(&new_handles, sample.stack);
+            }
+            for prev_sample_info in samples.prev_sample_info_per_thread.values_mut() {
+                prev_sample_info.stack = remap_stack(&new_handles, prev_sample_info.stack);
+            }
+        }
+        for handle in &mut live_handles {
+            **handle = remap_stack(&new_handles, **handle);
+        }
+        dropped_count
+    }
+}
+
+fn remap_stack(
+    new_handles: &[UnresolvedStackHandle],
+    stack: UnresolvedStackHandle,
+) -> UnresolvedStackHandle {
+    if stack == UnresolvedStackHandle::EMPTY {
+        stack
+    } else {
+        new_handles[stack.0 as usize]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::shared::test_support::{add_process_with_threads, new_profile};
+
+    #[test]
+    fn sample_callback_sees_every_new_sample() {
+        let mut profile = new_profile();
+        let (_process, threads) = add_process_with_threads(&mut profile, 1);
+        let thread = threads[0];
+
+        let count = Arc::new(AtomicUsize::new(0));
+        let mut samples = UnresolvedSamples::default();
+        samples.set_sample_callback(Some(SampleCallback(Arc::new({
+            let count = count.clone();
+            move |sample| {
+                assert!(matches!(sample.sample_or_marker, SampleOrMarker::Sample(_)));
+                count.fetch_add(1, Ordering::Relaxed);
+            }
+        }))));
+
+        for i in 0..3 {
+            samples.add_sample(
+                thread,
+                Timestamp::from_millis_since_reference(i as f64),
+                i,
+                UnresolvedStackHandle::EMPTY,
+                CpuDelta::from_millis(1.0),
+                1,
+                None,
+            );
+        }
+        // The first zero-CPU sample is added, the second one is folded into it.
+        for i in 3..5 {
+            samples.add_sample_same_stack_zero_cpu(
+                thread,
+                Timestamp::from_millis_since_reference(i as f64),
+                i,
+                1,
+                None,
+            );
+        }
+        assert_eq!(count.load(Ordering::Relaxed), 4);
+        assert_eq!(samples.samples_and_markers().len(), 4);
+    }
+
+    #[test]
+    fn last_sample_stack_follows_each_thread() {
+        let mut profile = new_profile();
+        let (_process, threads) = add_process_with_threads(&mut profile, 2);
+        let frame = |address| StackFrame::InstructionPointer(address, StackMode::User);
+        let mut stacks = UnresolvedStacks::default();
+        let first = stacks.convert([frame(0x1000)].into_iter());
+        let second = stacks.convert([frame(0x2000)].into_iter());
+
+        let mut samples = UnresolvedSamples::default();
+        assert_eq!(samples.last_sample_stack(threads[0]), None);
+        for (i, stack) in [first, second].into_iter().enumerate() {
+            samples.add_sample(
+                threads[0],
+                Timestamp::from_millis_since_reference(i as f64),
+                i as u64,
+                stack,
+                CpuDelta::from_micros(1000),
+                1,
+                None,
+            );
+        }
+        assert_eq!(samples.last_sample_stack(threads[0]), Some(second));
+        assert_eq!(samples.last_sample_stack(threads[1]), None);
+    }
+
+    #[test]
+    fn gc_drops_stacks_of_filtered_samples() {
+        let mut profile = new_profile();
+        let (_process, threads) = add_process_with_threads(&mut profile, 1);
+        let thread = threads[0];
+
+        let frame = |address| StackFrame::InstructionPointer(address, StackMode::User);
+        let mut stacks = UnresolvedStacks::default();
+        let mut samples = UnresolvedSamples::default();
+        for i in 0..10 {
+            // All samples share the `main` frame, and each has its own leaf.
+            let stack = stacks.convert([frame(0x1000), frame(0x2000 + i)].into_iter());
+            samples.add_sample(
+                thread,
+                Timestamp::from_millis_since_reference(i as f64),
+                i,
+                stack,
+                CpuDelta::from_millis(1.0),
+                1,
+                None,
+            );
+        }
+        assert_eq!(stacks.stacks.len(), 11);
+        // A stack which no sample has yet, like the off-CPU stack of a thread.
+        let mut pending_stack = stacks.convert([frame(0x1000), frame(0x3000)].into_iter());
+        let mut expected_pending = Vec::new();
+        stacks.convert_back(pending_stack, &mut expected_pending);
+
+        samples
+            .samples_and_markers
+            .retain(|sample| sample.timestamp_mono % 2 == 0);
+        let mut expected = Vec::new();
+        for sample in samples.samples_and_markers() {
+            let mut buf = Vec::new();
+            stacks.convert_back(sample.stack, &mut buf);
+            expected.push(buf);
+        }
+
+        // The stack of the last sample stays alive too, because the next
+        // sample of the thread may be merged into it.
+        assert_eq!(stacks.gc([&mut samples], [&mut pending_stack]), 4);
+        assert_eq!(stacks.stacks.len(), 8);
+        assert_eq!(stacks.stack_lookup.len(), 8);
+        let mut actual_pending = Vec::new();
+        stacks.convert_back(pending_stack, &mut actual_pending);
+        assert_eq!(actual_pending, expected_pending);
+        let mut actual = Vec::new();
+        for sample in samples.samples_and_markers() {
+            let mut buf = Vec::new();
+            stacks.convert_back(sample.stack, &mut buf);
+            actual.push(buf);
+        }
+        assert_eq!(actual, expected);
+
+        // Converting a stack which survived finds it again.
+        let stack = stacks.convert([frame(0x1000), frame(0x2004)].into_iter());
+        assert_eq!(stack, samples.samples_and_markers()[2].stack);
+    }
 }
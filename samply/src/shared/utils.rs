@@ -5,25 +5,152 @@ use fxprof_processed_profile::{LibraryHandle, LibraryInfo, Profile};
 use linux_perf_data::jitdump::JitDumpHeader;
 use wholesym::samply_symbols::debug_id_and_code_id_for_jitdump;
 
+/// The error returned by [`open_file_with_fallback`] if none of the candidate
+/// paths could be opened.
+#[derive(Debug)]
+pub struct OpenFileWithFallbackError {
+    /// Every path that was tried, in order, with the error from opening it.
+    pub attempts: Vec<(PathBuf, std::io::Error)>,
+}
+
+impl OpenFileWithFallbackError {
+    /// The paths that were tried, in the order in which they were tried.
+    pub fn candidates(&self) -> impl Iterator<Item = &Path> {
+        self.attempts.iter().map(|(path, _)| path.as_path())
+    }
+}
+
+impl std::fmt::Display for OpenFileWithFallbackError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Some(path) = self.candidates().next() else {
+            return write!(f, "Could not open file: no candidate paths");
+        };
+        write!(f, "Could not open {path:?}")?;
+        if let [(_, err)] = self.attempts.as_slice() {
+            return write!(f, ": {err}");
+        }
+        write!(f, ", tried:")?;
+        for (path, (_, err)) in self.candidates().zip(&self.attempts) {
+            write!(f, "\n  {path:?}: {err}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for OpenFileWithFallbackError {}
+
+/// Opens `path`, or, if that fails, a file with the same file name in one of
+/// `extra_dirs`, trying the directories in order.
 pub fn open_file_with_fallback<P: AsRef<Path>>(
     path: &Path,
     extra_dirs: &[P],
-) -> std::io::Result<(std::fs::File, PathBuf)> {
-    let e = match std::fs::File::open(path) {
+) -> Result<(std::fs::File, PathBuf), OpenFileWithFallbackError> {
+    let mut attempts = Vec::new();
+    match std::fs::File::open(path) {
         Ok(file) => return Ok((file, path.to_owned())),
-        Err(e) => e,
-    };
+        Err(e) => attempts.push((path.to_owned(), e)),
+    }
 
     if let Some(filename) = path.file_name() {
         for dir in extra_dirs {
             let p: PathBuf = [dir.as_ref(), Path::new(filename)].iter().collect();
-            if let Ok(file) = std::fs::File::open(&p) {
-                return Ok((file, p));
+            match std::fs::File::open(&p) {
+                Ok(file) => return Ok((file, p)),
+                Err(e) => attempts.push((p, e)),
             }
         }
     }
 
-    Err(e)
+    Err(OpenFileWithFallbackError { attempts })
+}
+
+/// Reads a list of addresses from a text file with one hex address per line,
+/// such as the output of `awk` or `perf script`.
+///
+/// Addresses may have a `0x` prefix. Blank lines and lines starting with `#`
+/// are skipped, and surrounding whitespace is ignored. Any other line which
+/// isn't a hex number fails with an `InvalidData` error naming the line.
+pub fn read_hex_address_list(path: &Path) -> std::io::Result<Vec<u64>> {
+    parse_hex_address_list(&std::fs::read_to_string(path)?)
+}
+
+fn parse_hex_address_list(text: &str) -> std::io::Result<Vec<u64>> {
+    let mut addresses = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let digits = line
+            .strip_prefix("0x")
+            .or_else(|| line.strip_prefix("0X"))
+            .unwrap_or(line);
+        let address = u64::from_str_radix(digits, 16).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Invalid hex address {line:?} on line {}: {e}", index + 1),
+            )
+        })?;
+        addresses.push(address);
+    }
+    Ok(addresses)
+}
+
+/// The default profile name for a recording of a launched command:
+/// `<binary> on <hostname> (<git describe>)`, with the parts which aren't
+/// known left out. `git describe` is run in the current directory, so CI
+/// profiles say which commit they were recorded at.
+pub fn default_launch_profile_name(binary: &str) -> String {
+    format_launch_profile_name(binary, hostname().as_deref(), git_describe().as_deref())
+}
+
+fn format_launch_profile_name(
+    binary: &str,
+    hostname: Option<&str>,
+    git_describe: Option<&str>,
+) -> String {
+    let mut name = binary.to_string();
+    if let Some(hostname) = hostname {
+        name.push_str(&format!(" on {hostname}"));
+    }
+    if let Some(git_describe) = git_describe {
+        name.push_str(&format!(" ({git_describe})"));
+    }
+    name
+}
+
+#[cfg(unix)]
+fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    let result = unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) };
+    if result != 0 {
+        return None;
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    let hostname = String::from_utf8_lossy(&buf[..len]).into_owned();
+    (!hostname.is_empty()).then_some(hostname)
+}
+
+#[cfg(windows)]
+fn hostname() -> Option<String> {
+    std::env::var("COMPUTERNAME")
+        .ok()
+        .filter(|name| !name.is_empty())
+}
+
+/// The output of `git describe --always --dirty`, or `None` if the current
+/// directory isn't in a git repository or git isn't installed.
+fn git_describe() -> Option<String> {
+    let output = std::process::Command::new("git")
+        .args(["describe", "--always", "--dirty"])
+        .stderr(std::process::Stdio::null())
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let describe = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    (!describe.is_empty()).then_some(describe)
 }
 
 pub fn lib_handle_for_jitdump(
@@ -52,3 +179,85 @@ pub fn lib_handle_for_jitdump(
         symbol_table: None,
     })
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn launch_profile_name_leaves_out_unknown_parts() {
+        assert_eq!(
+            format_launch_profile_name("bench", Some("ci-7"), Some("v1.2-3-gabc123")),
+            "bench on ci-7 (v1.2-3-gabc123)"
+        );
+        assert_eq!(
+            format_launch_profile_name("bench", Some("ci-7"), None),
+            "bench on ci-7"
+        );
+        assert_eq!(format_launch_profile_name("bench", None, None), "bench");
+    }
+
+    #[test]
+    fn open_file_with_fallback_lists_candidates_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = dir.path().join("first");
+        let second = dir.path().join("second");
+        let missing = Path::new("/nonexistent/samply-marker.txt");
+
+        let err = open_file_with_fallback(missing, &[&first, &second]).unwrap_err();
+        let candidates: Vec<&Path> = err.candidates().collect();
+        assert_eq!(
+            candidates,
+            vec![
+                missing.to_path_buf(),
+                first.join("samply-marker.txt"),
+                second.join("samply-marker.txt"),
+            ]
+        );
+        assert!(err
+            .attempts
+            .iter()
+            .all(|(_, e)| e.kind() == std::io::ErrorKind::NotFound));
+
+        let message = err.to_string();
+        let positions: Vec<usize> = candidates
+            .iter()
+            .map(|p| message.find(&format!("{p:?}: ")).unwrap())
+            .collect();
+        assert!(positions.windows(2).all(|w| w[0] < w[1]), "{message}");
+    }
+
+    #[test]
+    fn open_file_with_fallback_finds_file_in_extra_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("samply-marker.txt"), b"").unwrap();
+        let missing = Path::new("/nonexistent/samply-marker.txt");
+
+        let (_, true_path) = open_file_with_fallback(missing, &[dir.path()]).unwrap();
+        assert_eq!(true_path, dir.path().join("samply-marker.txt"));
+    }
+
+    #[test]
+    fn hex_address_list() {
+        let text = "# addresses from perf script\n0x1000\n  7f00dead  \n\n0XBEEF\n#0x1\n";
+        assert_eq!(
+            parse_hex_address_list(text).unwrap(),
+            vec![0x1000, 0x7f00dead, 0xbeef]
+        );
+    }
+
+    #[test]
+    fn hex_address_list_rejects_invalid_lines() {
+        let err = parse_hex_address_list("0x10\nmain+0x4\n").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("line 2"), "{err}");
+    }
+
+    #[test]
+    fn read_hex_address_list_from_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("addresses.txt");
+        std::fs::write(&path, "0x2a\nff\n").unwrap();
+        assert_eq!(read_hex_address_list(&path).unwrap(), vec![0x2a, 0xff]);
+    }
+}
@@ -5,7 +5,8 @@ use fxprof_processed_profile::{Profile, ReferenceTimestamp, SamplingInterval};
 use super::etw_gecko;
 use crate::shared::included_processes::IncludedProcesses;
 use crate::shared::recording_props::ProfileCreationProps;
-use crate::shared::save_profile::save_profile_to_file;
+use crate::shared::save_profile::{check_marker_schemas, save_profile_to_file};
+use crate::shared::time_report::TimeReport;
 use crate::windows::profile_context::ProfileContext;
 
 pub fn convert_etl_file_to_profile(
@@ -16,7 +17,8 @@ pub fn convert_etl_file_to_profile(
     included_processes: Option<IncludedProcesses>,
 ) {
     let timebase = std::time::SystemTime::now();
-    let timebase = ReferenceTimestamp::from_system_time(timebase);
+    let timebase =
+        profile_creation_props.reference_timestamp(ReferenceTimestamp::from_system_time(timebase));
 
     let interval_8khz = SamplingInterval::from_nanos(122100); // 8192Hz // only with the higher recording rate?
     let profile = Profile::new(
@@ -29,13 +31,28 @@ pub fn convert_etl_file_to_profile(
 
     eprintln!("Processing ETL trace...");
 
+    let print_time_report = profile_creation_props.time_report;
+    let validate_schemas = profile_creation_props.validate_schemas;
     let mut context =
         ProfileContext::new(profile, arch, included_processes, profile_creation_props);
 
-    etw_gecko::process_etl_files(&mut context, filename, extra_etl_filenames);
+    let mut time_report = TimeReport::new();
+    time_report.time("Processing events", || {
+        etw_gecko::process_etl_files(&mut context, filename, extra_etl_filenames)
+    });
 
-    let profile = context.finish();
-    save_profile_to_file(&profile, output_file).expect("Couldn't write JSON");
+    let profile = time_report.time("Finishing profile", || context.finish());
+    if let Some(validation) = validate_schemas {
+        if !check_marker_schemas(&profile, validation) {
+            std::process::exit(1);
+        }
+    }
+    time_report.time("Saving profile", || {
+        save_profile_to_file(&profile, output_file).expect("Couldn't write JSON")
+    });
+    if print_time_report {
+        time_report.print();
+    }
 }
 
 #[cfg(target_arch = "x86")]
@@ -27,7 +27,7 @@ use crate::shared::process_sample_data::{ProcessSampleData, UserTimingMarker};
 use crate::shared::recording_props::ProfileCreationProps;
 use crate::shared::recycling::{ProcessRecycler, ProcessRecyclingData, ThreadRecycler};
 use crate::shared::synthetic_jit_library::SyntheticJitLibrary;
-use crate::shared::timestamp_converter::TimestampConverter;
+use crate::shared::timestamp_converter::{ClockDomain, TimestampConverter};
 use crate::shared::types::{StackFrame, StackMode};
 use crate::shared::unresolved_samples::{
     UnresolvedSamples, UnresolvedStackHandle, UnresolvedStacks,
@@ -564,10 +564,9 @@ impl ProfileContext {
         );
 
         let cpus = if profile_creation_props.create_per_cpu_threads {
-            Some(Cpus::new(
-                Timestamp::from_nanos_since_reference(0),
-                &mut profile,
-            ))
+            let mut cpus = Cpus::new(Timestamp::from_nanos_since_reference(0), &mut profile);
+            cpus.set_track_naming(profile_creation_props.synthetic_track_naming.clone());
+            Some(cpus)
         } else {
             None
         };
@@ -600,6 +599,8 @@ impl ProfileContext {
             timestamp_converter: TimestampConverter {
                 reference_raw: 0,
                 raw_to_ns_factor: 1,
+                domain: ClockDomain::Monotonic,
+                realtime_to_monotonic_offset_ns: None,
             },
             event_timestamps_are_qpc: false,
             main_thread_only,
@@ -713,6 +714,8 @@ impl ProfileContext {
             self.timestamp_converter = TimestampConverter {
                 reference_raw: timestamp_raw,
                 raw_to_ns_factor: 1000 * 1000 * 1000 / perf_freq,
+                domain: ClockDomain::Monotonic,
+                realtime_to_monotonic_offset_ns: None,
             };
             self.seen_header = true;
         } else {
@@ -772,7 +775,9 @@ impl ProfileContext {
         let (thread_recycler, jit_function_recycler) = if self.process_recycler.is_some() {
             (
                 Some(ThreadRecycler::new()),
-                Some(JitFunctionRecycler::default()),
+                Some(JitFunctionRecycler::new(
+                    self.profile_creation_props.exact_jit_function_reuse,
+                )),
             )
         } else {
             (None, None)
@@ -841,7 +846,9 @@ impl ProfileContext {
         } else if self.process_recycler.is_some() {
             (
                 Some(ThreadRecycler::new()),
-                Some(JitFunctionRecycler::default()),
+                Some(JitFunctionRecycler::new(
+                    self.profile_creation_props.exact_jit_function_reuse,
+                )),
             )
         } else {
             (None, None)
@@ -2066,6 +2073,7 @@ impl ProfileContext {
             .categories
             .get(KnownCategory::Kernel, &mut self.profile);
 
+        let flush_options = self.profile_creation_props.flush_options();
         for process_sample_data in process_sample_datas {
             process_sample_data.flush_samples_to_profile(
                 &mut self.profile,
@@ -2073,6 +2081,7 @@ impl ProfileContext {
                 kernel_category.into(),
                 &mut stack_frame_scratch_buf,
                 &self.unresolved_stacks,
+                &flush_options,
             )
         }
 
@@ -10,8 +10,11 @@ use crate::server::{start_server_main, ServerProps};
 use crate::shared::ctrl_c::CtrlC;
 use crate::shared::included_processes::IncludedProcesses;
 use crate::shared::recording_props::{ProfileCreationProps, RecordingMode, RecordingProps};
-use crate::shared::save_profile::save_profile_to_file;
+use crate::shared::save_profile::{
+    append_profile_to_file, check_marker_schemas, embed_sample_weight_unit, save_profile_to_file,
+};
 use crate::shared::symbol_props::SymbolProps;
+use crate::shared::time_report::TimeReport;
 use crate::windows::elevated_helper::ElevatedHelperSession;
 
 // Hello intrepid explorer! You may be in this code because you'd like to extend something,
@@ -48,7 +51,8 @@ pub fn start_recording(
     server_props: Option<ServerProps>,
 ) -> Result<ExitStatus, i32> {
     let timebase = std::time::SystemTime::now();
-    let timebase = ReferenceTimestamp::from_system_time(timebase);
+    let timebase =
+        profile_creation_props.reference_timestamp(ReferenceTimestamp::from_system_time(timebase));
 
     let profile = Profile::new(
         profile_creation_props.profile_name(),
@@ -146,6 +150,8 @@ pub fn start_recording(
         .unwrap_or(get_native_arch().to_string());
 
     let unstable_presymbolicate = profile_creation_props.unstable_presymbolicate;
+    let print_time_report = profile_creation_props.time_report;
+    let validate_schemas = profile_creation_props.validate_schemas;
     let mut context =
         ProfileContext::new(profile, &arch, included_processes, profile_creation_props);
     let extra_etls = match &user_output_file {
@@ -158,7 +164,9 @@ pub fn start_recording(
         context.set_os_name(&format!("Windows {win_version}"))
     }
 
-    let profile = context.finish();
+    let mut time_report = TimeReport::new();
+    let mut profile = time_report.time("Finishing profile", || context.finish());
+    embed_sample_weight_unit(&mut profile, recording_props.sample_weight_unit);
 
     if !recording_props.keep_etl {
         std::fs::remove_file(&kernel_output_file).unwrap_or_else(|_| {
@@ -182,7 +190,21 @@ pub fn start_recording(
         }
     }
 
-    save_profile_to_file(&profile, &output_file).expect("Couldn't write JSON");
+    if let Some(validation) = validate_schemas {
+        if !check_marker_schemas(&profile, validation) {
+            std::process::exit(1);
+        }
+    }
+    time_report.time("Saving profile", || {
+        if recording_props.reuse_profile {
+            append_profile_to_file(&profile, &output_file).expect("Couldn't write JSON")
+        } else {
+            save_profile_to_file(&profile, &output_file).expect("Couldn't write JSON")
+        }
+    });
+    if print_time_report {
+        time_report.print();
+    }
 
     if unstable_presymbolicate {
         crate::shared::symbol_precog::presymbolicate(